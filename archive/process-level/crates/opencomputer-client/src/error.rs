@@ -0,0 +1,51 @@
+use std::fmt;
+
+#[derive(Debug)]
+pub enum Error {
+    /// The server answered with a non-2xx status
+    Api { status: u16, message: String },
+    /// The request could not be sent or the response body not read
+    Request(reqwest::Error),
+    /// The response body was not what the API returns
+    Decode(String),
+}
+
+impl Error {
+    /// HTTP status for [`Error::Api`] errors.
+    pub fn status(&self) -> Option<u16> {
+        match self {
+            Error::Api { status, .. } => Some(*status),
+            _ => None,
+        }
+    }
+
+    pub fn is_not_found(&self) -> bool {
+        self.status() == Some(404)
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Api { status, message } if message.is_empty() => write!(f, "HTTP {}", status),
+            Error::Api { status, message } => write!(f, "HTTP {}: {}", status, message),
+            Error::Request(e) => write!(f, "request failed: {}", e),
+            Error::Decode(e) => write!(f, "invalid response: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Request(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<reqwest::Error> for Error {
+    fn from(e: reqwest::Error) -> Self {
+        Error::Request(e)
+    }
+}
@@ -0,0 +1,1164 @@
+//! Typed async client for the OpenSandbox HTTP API.
+//!
+//! ```no_run
+//! # async fn demo() -> Result<(), opencomputer_client::Error> {
+//! use opencomputer_client::{Client, CreateSessionRequest, RunRequest};
+//!
+//! let client = Client::new("http://localhost:8080").with_api_key("sk-...");
+//! let session = client.create_session(&CreateSessionRequest::default()).await?;
+//! let result = client.run(&session.session_id, &RunRequest::shell("echo hello")).await?;
+//! assert_eq!(result.stdout, "hello\n");
+//! client.delete_session(&session.session_id).await?;
+//! # Ok(())
+//! # }
+//! ```
+
+mod error;
+mod types;
+
+pub use error::Error;
+pub use types::*;
+
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use futures_util::{Stream, StreamExt};
+use reqwest::{Method, RequestBuilder, Response, StatusCode};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::time::Duration;
+
+/// Response header carrying the cursor for the next page of sessions.
+const NEXT_CURSOR_HEADER: &str = "x-next-cursor";
+
+/// Request header the server recognizes a retried request by.
+const IDEMPOTENCY_KEY_HEADER: &str = "idempotency-key";
+
+/// Retry behaviour for transient failures.
+///
+/// Connection failures are retried for every request, since the server never
+/// saw them. 502/503/504 responses are only retried for idempotent methods
+/// (GET, PUT, DELETE) so a non-idempotent POST is never applied twice, and for
+/// session creates, runs and background starts, which are sent with an
+/// `Idempotency-Key` the server replays the first response for.
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    pub max_retries: u32,
+    /// Delay before the first retry; doubled after each attempt
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_retries: 3,
+            initial_backoff: Duration::from_millis(200),
+            max_backoff: Duration::from_secs(5),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Never retry.
+    pub fn none() -> Self {
+        Self {
+            max_retries: 0,
+            ..Default::default()
+        }
+    }
+}
+
+/// Client for one OpenSandbox server.
+#[derive(Debug, Clone)]
+pub struct Client {
+    http: reqwest::Client,
+    base_url: String,
+    api_key: Option<String>,
+    retry: RetryPolicy,
+}
+
+impl Client {
+    /// `base_url` is the server root, e.g. `http://localhost:8080`.
+    pub fn new(base_url: impl Into<String>) -> Self {
+        Self {
+            http: reqwest::Client::new(),
+            base_url: base_url.into().trim_end_matches('/').to_string(),
+            api_key: None,
+            retry: RetryPolicy::default(),
+        }
+    }
+
+    /// Send `Authorization: Bearer <key>` on every request.
+    pub fn with_api_key(mut self, key: impl Into<String>) -> Self {
+        self.api_key = Some(key.into());
+        self
+    }
+
+    pub fn with_retry(mut self, retry: RetryPolicy) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    /// Use a preconfigured `reqwest::Client` (timeouts, proxies, TLS roots).
+    pub fn with_http_client(mut self, http: reqwest::Client) -> Self {
+        self.http = http;
+        self
+    }
+
+    pub fn base_url(&self) -> &str {
+        &self.base_url
+    }
+
+    // Server
+
+    pub async fn health(&self) -> Result<String, Error> {
+        let resp = self.send(Method::GET, "/health", |r| r).await?;
+        resp.text().await.map_err(Error::from)
+    }
+
+    /// `GET /healthz`. A failing report is returned rather than an error.
+    pub async fn liveness(&self) -> Result<HealthReport, Error> {
+        self.health_report("/healthz").await
+    }
+
+    /// `GET /readyz`. A failing report is returned rather than an error.
+    pub async fn readiness(&self) -> Result<HealthReport, Error> {
+        self.health_report("/readyz").await
+    }
+
+    pub async fn pool_stats(&self) -> Result<PoolStats, Error> {
+        self.get_json("/pool").await
+    }
+
+    pub async fn capacity(&self) -> Result<Capacity, Error> {
+        self.get_json("/capacity").await
+    }
+
+    // Sessions
+
+    pub async fn create_session(&self, req: &CreateSessionRequest) -> Result<CreateSessionResponse, Error> {
+        self.post_json_once("/sessions", req).await
+    }
+
+    pub async fn list_sessions(&self, query: &ListSessionsQuery) -> Result<SessionPage, Error> {
+        let pairs = query.to_pairs();
+        let resp = self.send(Method::GET, "/sessions", |r| r.query(&pairs)).await?;
+        let next_cursor = resp
+            .headers()
+            .get(NEXT_CURSOR_HEADER)
+            .and_then(|v| v.to_str().ok())
+            .map(str::to_string);
+        Ok(SessionPage {
+            sessions: decode(resp).await?,
+            next_cursor,
+        })
+    }
+
+    pub async fn get_session(&self, id: &str) -> Result<SessionInfo, Error> {
+        self.get_json(&format!("/sessions/{}", id)).await
+    }
+
+    pub async fn delete_session(&self, id: &str) -> Result<(), Error> {
+        self.send(Method::DELETE, &format!("/sessions/{}", id), |r| r).await?;
+        Ok(())
+    }
+
+    /// Reset the idle timer, optionally replacing the session's TTL.
+    pub async fn keepalive(&self, id: &str, ttl_secs: Option<u64>) -> Result<KeepaliveResponse, Error> {
+        self.post_json(&format!("/sessions/{}/keepalive", id), &KeepaliveRequest { ttl: ttl_secs })
+            .await
+    }
+
+    /// Mint a token limited to this session and `req.scopes`, e.g. for a
+    /// dashboard that should only read logs.
+    pub async fn create_session_token(
+        &self,
+        id: &str,
+        req: &CreateSessionTokenRequest,
+    ) -> Result<CreateSessionTokenResponse, Error> {
+        self.post_json(&format!("/sessions/{}/tokens", id), req).await
+    }
+
+    /// Mint an expiring token giving someone else `req.access` to this
+    /// session. The token is returned only this once.
+    pub async fn share_session(&self, id: &str, req: &CreateShareRequest) -> Result<CreateShareResponse, Error> {
+        self.post_json(&format!("/sessions/{}/share", id), req).await
+    }
+
+    pub async fn shares(&self, id: &str) -> Result<Vec<Share>, Error> {
+        self.get_json(&format!("/sessions/{}/shares", id)).await
+    }
+
+    pub async fn revoke_share(&self, id: &str, share_id: &str) -> Result<(), Error> {
+        self.send(Method::DELETE, &format!("/sessions/{}/shares/{}", id, share_id), |r| r).await?;
+        Ok(())
+    }
+
+    /// Mint a key for `ssh -i key -p <port> <session id>@<host>`. The
+    /// private key is returned only this once.
+    pub async fn create_ssh_key(&self, id: &str, req: &CreateSshKeyRequest) -> Result<CreateSshKeyResponse, Error> {
+        self.post_json(&format!("/sessions/{}/ssh-keys", id), req).await
+    }
+
+    pub async fn ssh_keys(&self, id: &str) -> Result<Vec<SshKey>, Error> {
+        self.get_json(&format!("/sessions/{}/ssh-keys", id)).await
+    }
+
+    pub async fn delete_ssh_key(&self, id: &str, key_id: &str) -> Result<(), Error> {
+        self.send(Method::DELETE, &format!("/sessions/{}/ssh-keys/{}", id, key_id), |r| r).await?;
+        Ok(())
+    }
+
+    pub async fn pause_session(&self, id: &str) -> Result<PauseResponse, Error> {
+        self.post_empty(&format!("/sessions/{}/pause", id)).await
+    }
+
+    pub async fn resume_session(&self, id: &str) -> Result<PauseResponse, Error> {
+        self.post_empty(&format!("/sessions/{}/resume", id)).await
+    }
+
+    /// Dump the session's background processes to disk (needs CRIU on the
+    /// server) and kill everything else in it.
+    pub async fn hibernate_session(&self, id: &str) -> Result<HibernateResponse, Error> {
+        self.post_empty(&format!("/sessions/{}/hibernate", id)).await
+    }
+
+    /// Restore the background processes of a hibernated session.
+    pub async fn wake_session(&self, id: &str) -> Result<HibernateResponse, Error> {
+        self.post_empty(&format!("/sessions/{}/wake", id)).await
+    }
+
+    /// Resource use of the session's processes, with the last few minutes
+    /// of samples.
+    pub async fn session_stats(&self, id: &str) -> Result<SessionStatsHistory, Error> {
+        self.get_json(&format!("/sessions/{}/stats", id)).await
+    }
+
+    /// A sample of the session's resource use every second, starting with
+    /// the latest. The stream ends with the session.
+    pub async fn stats_stream(&self, id: &str) -> Result<impl Stream<Item = Result<StatsSample, Error>>, Error> {
+        let resp = self.send(Method::GET, &format!("/sessions/{}/stats/stream", id), |r| r).await?;
+        Ok(sse_events(resp))
+    }
+
+    /// The session's recent events (runs, background processes, ports,
+    /// pauses, TTL warnings), oldest first.
+    pub async fn session_events(&self, id: &str, query: &SessionEventsQuery) -> Result<Vec<SessionEvent>, Error> {
+        let url = format!("/sessions/{}/events", id);
+        let resp = self.send(Method::GET, &url, |r| r.query(query)).await?;
+        decode(resp).await
+    }
+
+    /// The session's kept events after `query.after`, then new ones as they
+    /// happen. The stream ends after the session's `expired` or `deleted`
+    /// event.
+    pub async fn session_events_stream(
+        &self,
+        id: &str,
+        query: &SessionEventsQuery,
+    ) -> Result<impl Stream<Item = Result<SessionEvent, Error>>, Error> {
+        let url = format!("/sessions/{}/events/stream", id);
+        let resp = self.send(Method::GET, &url, |r| r.query(query)).await?;
+        Ok(sse_events(resp))
+    }
+
+    /// A page of the commands the session ran, oldest first. Pass the
+    /// page's `next_after` as `query.after` for the next one.
+    pub async fn history(&self, id: &str, query: &HistoryQuery) -> Result<HistoryPage, Error> {
+        let url = format!("/sessions/{}/history", id);
+        let resp = self.send(Method::GET, &url, |r| r.query(query)).await?;
+        decode(resp).await
+    }
+
+    /// What command `seq` of the history printed; only the last commands'
+    /// output is kept.
+    pub async fn history_output(&self, id: &str, seq: u64) -> Result<HistoryOutput, Error> {
+        self.get_json(&format!("/sessions/{}/history/{}/output", id, seq)).await
+    }
+
+    /// What the session's files take on disk, and its largest directories.
+    pub async fn disk_usage(&self, id: &str, query: &DiskUsageQuery) -> Result<DiskUsage, Error> {
+        let url = format!("/sessions/{}/usage", id);
+        let resp = self.send(Method::GET, &url, |r| r.query(query)).await?;
+        decode(resp).await
+    }
+
+    /// Merge `env` into the session env.
+    pub async fn set_env(&self, id: &str, env: &HashMap<String, String>) -> Result<(), Error> {
+        self.put_env(id, env, false).await
+    }
+
+    /// Make `env` the whole session env, dropping every other variable.
+    pub async fn replace_env(&self, id: &str, env: &HashMap<String, String>) -> Result<(), Error> {
+        self.put_env(id, env, true).await
+    }
+
+    async fn put_env(&self, id: &str, env: &HashMap<String, String>, replace: bool) -> Result<(), Error> {
+        let path = format!("/sessions/{}/env", id);
+        let body = SetEnvRequest {
+            env: env.clone(),
+            replace,
+        };
+        self.send(Method::POST, &path, |r| r.json(&body)).await?;
+        Ok(())
+    }
+
+    /// Remove one variable from the session env.
+    pub async fn unset_env(&self, id: &str, name: &str) -> Result<(), Error> {
+        self.send(Method::DELETE, &format!("/sessions/{}/env/{}", id, name), |r| r).await?;
+        Ok(())
+    }
+
+    /// Add or replace named tasks, or with `req.replace` make them all of
+    /// the session's tasks.
+    pub async fn set_tasks(&self, id: &str, req: &SetTasksRequest) -> Result<(), Error> {
+        self.send(Method::POST, &format!("/sessions/{}/tasks", id), |r| r.json(req)).await?;
+        Ok(())
+    }
+
+    pub async fn list_tasks(&self, id: &str) -> Result<Vec<TaskInfo>, Error> {
+        self.get_json(&format!("/sessions/{}/tasks", id)).await
+    }
+
+    pub async fn delete_task(&self, id: &str, name: &str) -> Result<(), Error> {
+        self.send(Method::DELETE, &format!("/sessions/{}/tasks/{}", id, name), |r| r).await?;
+        Ok(())
+    }
+
+    /// Run a task to completion, or start it in the background if it's a
+    /// background task (or `req.background` says so).
+    pub async fn run_task(&self, id: &str, name: &str, req: &RunTaskRequest) -> Result<TaskRunResponse, Error> {
+        self.post_json(&format!("/sessions/{}/tasks/{}/run", id, name), req).await
+    }
+
+    /// Have the server run a command in the session on a cron schedule.
+    pub async fn create_schedule(&self, id: &str, schedule: &ScheduleDefinition) -> Result<ScheduleInfo, Error> {
+        self.post_json(&format!("/sessions/{}/schedules", id), schedule).await
+    }
+
+    pub async fn list_schedules(&self, id: &str) -> Result<Vec<ScheduleInfo>, Error> {
+        self.get_json(&format!("/sessions/{}/schedules", id)).await
+    }
+
+    /// A schedule with its next fire time and last runs.
+    pub async fn get_schedule(&self, id: &str, schedule_id: &str) -> Result<ScheduleInfo, Error> {
+        self.get_json(&format!("/sessions/{}/schedules/{}", id, schedule_id)).await
+    }
+
+    pub async fn delete_schedule(&self, id: &str, schedule_id: &str) -> Result<(), Error> {
+        self.send(Method::DELETE, &format!("/sessions/{}/schedules/{}", id, schedule_id), |r| r).await?;
+        Ok(())
+    }
+
+    /// Add or replace session secrets, e.g. a token for [`Client::git_clone`].
+    pub async fn set_secrets(&self, id: &str, secrets: &HashMap<String, String>) -> Result<(), Error> {
+        let path = format!("/sessions/{}/secrets", id);
+        let body = SetSecretsRequest {
+            secrets: secrets.clone(),
+        };
+        self.send(Method::POST, &path, |r| r.json(&body)).await?;
+        Ok(())
+    }
+
+    /// The session's egress policy and recent attempts through its proxy.
+    pub async fn egress(&self, id: &str) -> Result<EgressReport, Error> {
+        self.get_json(&format!("/sessions/{}/egress", id)).await
+    }
+
+    /// Replace the egress policy of a session created with one.
+    pub async fn set_egress(&self, id: &str, policy: &EgressPolicy) -> Result<EgressReport, Error> {
+        let path = format!("/sessions/{}/egress", id);
+        let resp = self.send(Method::PUT, &path, |r| r.json(policy)).await?;
+        decode(resp).await
+    }
+
+    /// Change a session's CPU weight, CPU cap or process limit; fields left
+    /// unset keep their value. Returns the limits now in force.
+    pub async fn set_limits(&self, id: &str, limits: &SessionLimits) -> Result<SessionLimits, Error> {
+        let path = format!("/sessions/{}/limits", id);
+        let resp = self.send(Method::PATCH, &path, |r| r.json(limits)).await?;
+        decode(resp).await
+    }
+
+    pub async fn set_cwd(&self, id: &str, cwd: &str) -> Result<(), Error> {
+        let path = format!("/sessions/{}/cwd", id);
+        self.send(Method::POST, &path, |r| r.json(&SetCwdRequest { cwd: cwd.to_string() })).await?;
+        Ok(())
+    }
+
+    /// Serve a directory at the session's preview URL instead of a port.
+    pub async fn set_static_site(&self, id: &str, site: &StaticSite) -> Result<StaticSite, Error> {
+        self.post_json(&format!("/sessions/{}/static", id), site).await
+    }
+
+    pub async fn delete_static_site(&self, id: &str) -> Result<(), Error> {
+        self.send(Method::DELETE, &format!("/sessions/{}/static", id), |r| r).await?;
+        Ok(())
+    }
+
+    // Commands
+
+    pub async fn run(&self, id: &str, req: &RunRequest) -> Result<RunResult, Error> {
+        self.post_json_once(&format!("/sessions/{}/run", id), req).await
+    }
+
+    /// Run in a fresh sandbox that is discarded afterwards.
+    pub async fn run_oneshot(&self, req: &RunRequest) -> Result<RunResult, Error> {
+        self.post_json("/run", req).await
+    }
+
+    pub async fn run_background(&self, id: &str, req: &BackgroundRunRequest) -> Result<BackgroundRunResponse, Error> {
+        self.post_json_once(&format!("/sessions/{}/background", id), req).await
+    }
+
+    /// Create a session, unpack `req.archive` into it and start the dev
+    /// server; returns once the preview answers. The server deletes the
+    /// session if any step fails.
+    pub async fn run_preview(&self, req: &RunPreviewRequest) -> Result<RunPreviewResponse, Error> {
+        self.post_json("/run-preview", req).await
+    }
+
+    pub async fn kill_background(&self, id: &str) -> Result<KillBackgroundResponse, Error> {
+        let resp = self
+            .send(Method::DELETE, &format!("/sessions/{}/background", id), |r| r)
+            .await?;
+        decode(resp).await
+    }
+
+    /// Process status plus the background log from byte `offset`, at most
+    /// `limit_bytes` of it.
+    pub async fn background_status(
+        &self,
+        id: &str,
+        offset: u64,
+        limit_bytes: Option<u64>,
+    ) -> Result<BackgroundStatusResponse, Error> {
+        let url = format!("/sessions/{}/background/status", id);
+        let resp = self
+            .send(Method::GET, &url, |r| {
+                let r = r.query(&[("offset", offset)]);
+                match limit_bytes {
+                    Some(limit) => r.query(&[("limit_bytes", limit)]),
+                    None => r,
+                }
+            })
+            .await?;
+        decode(resp).await
+    }
+
+    /// Background process `pid`'s log from byte `offset`, at most
+    /// `limit_bytes` of it, with the session's process status.
+    pub async fn process_log(
+        &self,
+        id: &str,
+        pid: u32,
+        offset: u64,
+        limit_bytes: Option<u64>,
+    ) -> Result<BackgroundStatusResponse, Error> {
+        let url = format!("/sessions/{}/background/status", id);
+        let resp = self
+            .send(Method::GET, &url, |r| {
+                let r = r.query(&[("pid", u64::from(pid)), ("offset", offset)]);
+                match limit_bytes {
+                    Some(limit) => r.query(&[("limit_bytes", limit)]),
+                    None => r,
+                }
+            })
+            .await?;
+        decode(resp).await
+    }
+
+    /// Start `req` in the background and stream its output as it is written.
+    ///
+    /// Returns the started process together with a stream that ends once it
+    /// has exited; see [`Client::follow_background_log`].
+    pub async fn run_streaming(
+        &self,
+        id: &str,
+        req: &BackgroundRunRequest,
+        interval: Duration,
+    ) -> Result<(BackgroundRunResponse, impl Stream<Item = Result<String, Error>> + '_), Error> {
+        let started = self.run_background(id, req).await?;
+        Ok((started, self.follow_background_log(id, interval)))
+    }
+
+    /// Stream new background log output as it appears, polling every `interval`.
+    ///
+    /// Each item is the text appended since the previous poll; only new bytes
+    /// are fetched. The log followed is that of the last process started when
+    /// the stream begins. The stream ends once every background process has
+    /// exited and the log has been drained, or after the first error.
+    pub fn follow_background_log(
+        &self,
+        id: &str,
+        interval: Duration,
+    ) -> impl Stream<Item = Result<String, Error>> + '_ {
+        struct Follow {
+            pid: Option<u32>,
+            offset: u64,
+            done: bool,
+            first: bool,
+        }
+        let id = id.to_string();
+        let start = Follow { pid: None, offset: 0, done: false, first: true };
+        futures_util::stream::unfold(start, move |mut st| {
+            let id = id.clone();
+            async move {
+                loop {
+                    if st.done {
+                        return None;
+                    }
+                    if !st.first {
+                        tokio::time::sleep(interval).await;
+                    }
+                    st.first = false;
+                    let status = match st.pid {
+                        Some(pid) => self.process_log(&id, pid, st.offset, None).await,
+                        None => self.background_status(&id, st.offset, None).await,
+                    };
+                    let status = match status {
+                        Ok(status) => status,
+                        Err(e) => {
+                            st.done = true;
+                            return Some((Err(e), st));
+                        }
+                    };
+                    st.done = status.pids.iter().all(|p| !p.alive);
+                    st.pid = st.pid.or(status.log_pid);
+                    st.offset = status.next_offset;
+                    if !status.log.is_empty() {
+                        return Some((Ok(status.log), st));
+                    }
+                }
+            }
+        })
+    }
+
+    // Jobs
+
+    /// Start `req` in the session and return without waiting for it; follow
+    /// it with [`Client::get_job`] or [`Client::wait_job`].
+    pub async fn start_job(&self, id: &str, req: &RunRequest) -> Result<Job, Error> {
+        self.post_json(&format!("/sessions/{}/jobs", id), req).await
+    }
+
+    /// The job's status and its output from the query's offsets on.
+    pub async fn get_job(&self, job_id: &str, query: &JobQuery) -> Result<Job, Error> {
+        let resp = self
+            .send(Method::GET, &format!("/jobs/{}", job_id), |r| r.query(query))
+            .await?;
+        decode(resp).await
+    }
+
+    /// Kill a running job; it's reported `cancelled` once it's gone.
+    pub async fn cancel_job(&self, job_id: &str) -> Result<(), Error> {
+        self.send(Method::DELETE, &format!("/jobs/{}", job_id), |r| r).await?;
+        Ok(())
+    }
+
+    /// Poll a job every `interval` until it's done, fetching only new
+    /// output, and return it with all of its output.
+    pub async fn wait_job(&self, job_id: &str, interval: Duration) -> Result<Job, Error> {
+        let (mut stdout, mut stderr) = (String::new(), String::new());
+        let mut query = JobQuery::default();
+        loop {
+            let mut job = self.get_job(job_id, &query).await?;
+            stdout.push_str(&job.stdout);
+            stderr.push_str(&job.stderr);
+            query.stdout_offset = job.stdout_offset;
+            query.stderr_offset = job.stderr_offset;
+            if job.is_done() {
+                (job.stdout, job.stderr) = (stdout, stderr);
+                return Ok(job);
+            }
+            tokio::time::sleep(interval).await;
+        }
+    }
+
+    // Kernels
+
+    /// Run a cell in the session's Python kernel, starting it if needed.
+    /// Variables persist between calls until the kernel is shut down.
+    pub async fn kernel_execute(&self, id: &str, req: &KernelExecuteRequest) -> Result<KernelExecuteResult, Error> {
+        self.post_json(&format!("/sessions/{}/kernel/python/execute", id), req).await
+    }
+
+    /// Stop the session's Python kernel, dropping its state.
+    pub async fn kernel_shutdown(&self, id: &str) -> Result<(), Error> {
+        self.send(Method::DELETE, &format!("/sessions/{}/kernel/python", id), |r| r)
+            .await?;
+        Ok(())
+    }
+
+    // Packages
+
+    /// Install pip or npm packages into the session from the server's shared
+    /// cache, downloading into it only what's missing.
+    pub async fn install_packages(&self, id: &str, req: &InstallPackagesRequest) -> Result<InstallPackagesResult, Error> {
+        self.post_json(&format!("/sessions/{}/packages", id), req).await
+    }
+
+    // Build cache
+
+    /// Mount the cached directory for the project's lockfile, if the server
+    /// has one; `hit` says whether it did.
+    pub async fn restore_build_cache(&self, id: &str, req: &BuildCacheRequest) -> Result<BuildCacheResult, Error> {
+        self.post_json(&format!("/sessions/{}/build-cache/restore", id), req).await
+    }
+
+    /// Copy the project's directory into the build cache for later sessions.
+    pub async fn save_build_cache(&self, id: &str, req: &BuildCacheRequest) -> Result<BuildCacheResult, Error> {
+        self.post_json(&format!("/sessions/{}/build-cache/save", id), req).await
+    }
+
+    // Git
+
+    pub async fn git_clone(&self, id: &str, req: &GitCloneRequest) -> Result<GitResult, Error> {
+        self.post_json(&format!("/sessions/{}/git/clone", id), req).await
+    }
+
+    pub async fn git_pull(&self, id: &str, req: &GitPullRequest) -> Result<GitResult, Error> {
+        self.post_json(&format!("/sessions/{}/git/pull", id), req).await
+    }
+
+    pub async fn git_commit(&self, id: &str, req: &GitCommitRequest) -> Result<GitResult, Error> {
+        self.post_json(&format!("/sessions/{}/git/commit", id), req).await
+    }
+
+    /// Patch of the working tree against a commit, new files included.
+    pub async fn git_diff(&self, id: &str, query: &GitDiffQuery) -> Result<GitDiffResult, Error> {
+        let url = format!("/sessions/{}/git/diff", id);
+        let resp = self.send(Method::GET, &url, |r| r.query(query)).await?;
+        decode(resp).await
+    }
+
+    /// What the session changed, as a patch for `git apply`, or a git bundle
+    /// with `PatchFormat::Bundle`.
+    pub async fn export_patch(&self, id: &str, query: &PatchExportQuery) -> Result<Vec<u8>, Error> {
+        let url = format!("/sessions/{}/export/patch", id);
+        let resp = self.send(Method::GET, &url, |r| r.query(query)).await?;
+        Ok(resp.bytes().await?.to_vec())
+    }
+
+    /// Have the server fetch an archive URL or a git ref into the session.
+    pub async fn import_project(&self, id: &str, req: &ImportRequest) -> Result<ImportResult, Error> {
+        self.post_json(&format!("/sessions/{}/import", id), req).await
+    }
+
+    // Files
+
+    pub async fn write_file(&self, id: &str, path: &str, content: &[u8]) -> Result<(), Error> {
+        self.write_file_with(id, path, content, WriteMode::Overwrite, None).await
+    }
+
+    /// Add `content` to the end of a file, creating it if it's missing.
+    pub async fn append_file(&self, id: &str, path: &str, content: &[u8]) -> Result<(), Error> {
+        self.write_file_with(id, path, content, WriteMode::Append, None).await
+    }
+
+    /// Write `content` at byte `offset` of a file, keeping the rest of it,
+    /// e.g. to upload a large file in chunks.
+    pub async fn write_file_at(&self, id: &str, path: &str, content: &[u8], offset: u64) -> Result<(), Error> {
+        self.write_file_with(id, path, content, WriteMode::Overwrite, Some(offset)).await
+    }
+
+    async fn write_file_with(
+        &self,
+        id: &str,
+        path: &str,
+        content: &[u8],
+        mode: WriteMode,
+        offset: Option<u64>,
+    ) -> Result<(), Error> {
+        let body = WriteFileRequest {
+            path: path.to_string(),
+            content: BASE64.encode(content),
+            mode,
+            offset,
+        };
+        let url = format!("/sessions/{}/files/write", id);
+        self.send(Method::POST, &url, |r| r.json(&body)).await?;
+        Ok(())
+    }
+
+    /// Write several files in one request. Per-file failures are reported in
+    /// the response rather than as an error.
+    pub async fn write_files(&self, id: &str, files: &[(&str, &[u8])]) -> Result<WriteFilesResponse, Error> {
+        let body = WriteFilesRequest {
+            files: files
+                .iter()
+                .map(|(path, content)| WriteFileRequest {
+                    path: path.to_string(),
+                    content: BASE64.encode(content),
+                    mode: WriteMode::Overwrite,
+                    offset: None,
+                })
+                .collect(),
+        };
+        self.post_json(&format!("/sessions/{}/files/write-bulk", id), &body).await
+    }
+
+    pub async fn read_file(&self, id: &str, path: &str) -> Result<Vec<u8>, Error> {
+        let url = format!("/sessions/{}/files/read", id);
+        let resp = self.send(Method::GET, &url, |r| r.query(&[("path", path)])).await?;
+        let body: ReadFileResponse = decode(resp).await?;
+        BASE64
+            .decode(body.content)
+            .map_err(|e| Error::Decode(format!("file content: {}", e)))
+    }
+
+    /// Stream a file's bytes from `offset` on, without holding it in memory.
+    /// A nonzero `offset` resumes an interrupted download.
+    pub async fn download_file(
+        &self,
+        id: &str,
+        path: &str,
+        offset: u64,
+    ) -> Result<impl Stream<Item = Result<Vec<u8>, Error>>, Error> {
+        let url = format!("/sessions/{}/files/download", id);
+        let resp = self
+            .send(Method::GET, &url, |r| {
+                let r = r.query(&[("path", path)]);
+                if offset > 0 {
+                    r.header(reqwest::header::RANGE, format!("bytes={}-", offset))
+                } else {
+                    r
+                }
+            })
+            .await?;
+        Ok(resp.bytes_stream().map(|chunk| chunk.map(|b| b.to_vec()).map_err(Error::from)))
+    }
+
+    /// Checksum of a file, to check a transfer without fetching the file.
+    pub async fn file_hash(&self, id: &str, path: &str, algo: HashAlgo) -> Result<FileHash, Error> {
+        let query = FileHashQuery {
+            path: path.to_string(),
+            algo,
+        };
+        let url = format!("/sessions/{}/files/hash", id);
+        let resp = self.send(Method::GET, &url, |r| r.query(&query)).await?;
+        decode(resp).await
+    }
+
+    /// Checksums of several files. Files that can't be read are listed in
+    /// `errors` rather than failing the call.
+    pub async fn file_hashes(&self, id: &str, paths: &[&str], algo: HashAlgo) -> Result<FileHashesResponse, Error> {
+        let body = FileHashesRequest {
+            paths: paths.iter().map(|p| p.to_string()).collect(),
+            algo,
+        };
+        self.post_json(&format!("/sessions/{}/files/hash", id), &body).await
+    }
+
+    /// What differs between `req.to` and `req.from`, or the copy of `req.to`
+    /// in checkpoint `req.checkpoint`.
+    pub async fn diff_files(&self, id: &str, req: &FilesDiffRequest) -> Result<FilesDiff, Error> {
+        self.post_json(&format!("/sessions/{}/files/diff", id), req).await
+    }
+
+    /// Files added, removed or modified since the session was created.
+    pub async fn session_changes(&self, id: &str, query: &SessionChangesQuery) -> Result<SessionChanges, Error> {
+        let url = format!("/sessions/{}/changes", id);
+        let resp = self.send(Method::GET, &url, |r| r.query(query)).await?;
+        decode(resp).await
+    }
+
+    pub async fn list_files(&self, id: &str, path: &str) -> Result<Vec<FileEntry>, Error> {
+        let url = format!("/sessions/{}/files/list", id);
+        let resp = self.send(Method::GET, &url, |r| r.query(&[("path", path)])).await?;
+        let body: ListFilesResponse = decode(resp).await?;
+        Ok(body.files)
+    }
+
+    /// Delete a file or directory. Sessions created with `trash_ttl` move it
+    /// to their trash unless `req.permanent` is set.
+    pub async fn delete_file(&self, id: &str, req: &DeleteFileRequest) -> Result<DeleteFileResponse, Error> {
+        self.post_json(&format!("/sessions/{}/files/delete", id), req).await
+    }
+
+    pub async fn list_trash(&self, id: &str) -> Result<Vec<TrashEntry>, Error> {
+        self.get_json(&format!("/sessions/{}/files/trash", id)).await
+    }
+
+    /// Move a trashed entry back, to where it was deleted from unless
+    /// `req.to` says otherwise.
+    pub async fn restore_file(&self, id: &str, req: &RestoreFileRequest) -> Result<RestoreFileResponse, Error> {
+        self.post_json(&format!("/sessions/{}/files/restore", id), req).await
+    }
+
+    /// Push a directory: send the manifest of `req.path` without content
+    /// first, then again with content for the files in `need`. Only files
+    /// that differ are written.
+    pub async fn sync_files(&self, id: &str, req: &SyncRequest) -> Result<SyncPushResponse, Error> {
+        self.post_json(&format!("/sessions/{}/files/sync", id), req).await
+    }
+
+    /// Pull a directory: the session's files that differ from the manifest,
+    /// with content up to a budget. Pull again with the updated manifest
+    /// while `truncated`.
+    pub async fn pull_files(&self, id: &str, req: &SyncRequest) -> Result<SyncPullResponse, Error> {
+        self.post_json(&format!("/sessions/{}/files/sync/pull", id), req).await
+    }
+
+    // Resumable uploads
+
+    /// Start a resumable upload of `req.size` bytes to `req.path`.
+    pub async fn create_upload(&self, id: &str, req: &CreateUploadRequest) -> Result<UploadStatus, Error> {
+        self.post_json(&format!("/sessions/{}/uploads", id), req).await
+    }
+
+    /// Where an upload stands; `offset` is where the next chunk starts.
+    pub async fn upload_status(&self, id: &str, upload_id: &str) -> Result<UploadStatus, Error> {
+        self.get_json(&format!("/sessions/{}/uploads/{}", id, upload_id)).await
+    }
+
+    /// Send the chunk starting at `offset`, returning the offset after it.
+    /// A chunk at the wrong offset fails with `409`.
+    pub async fn upload_chunk(&self, id: &str, upload_id: &str, offset: u64, chunk: Vec<u8>) -> Result<u64, Error> {
+        let url = format!("/sessions/{}/uploads/{}", id, upload_id);
+        // Safe to repeat: a chunk that already landed gets 409, not written twice
+        let resp = self
+            .send_with(Method::PATCH, &url, true, |r| {
+                r.header("upload-offset", offset).body(chunk.clone())
+            })
+            .await?;
+        Ok(resp
+            .headers()
+            .get("upload-offset")
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(offset + chunk.len() as u64))
+    }
+
+    /// Move a fully received upload to its path.
+    pub async fn finalize_upload(&self, id: &str, upload_id: &str) -> Result<FinalizeUploadResponse, Error> {
+        self.post_empty(&format!("/sessions/{}/uploads/{}/finalize", id, upload_id)).await
+    }
+
+    pub async fn cancel_upload(&self, id: &str, upload_id: &str) -> Result<(), Error> {
+        let url = format!("/sessions/{}/uploads/{}", id, upload_id);
+        self.send(Method::DELETE, &url, |r| r).await?;
+        Ok(())
+    }
+
+    /// Upload `data` to `path` in chunks of `chunk_size` bytes. A chunk that
+    /// fails is picked up from the offset the server reports, up to the
+    /// retry policy's `max_retries` times in a row.
+    pub async fn upload_file(
+        &self,
+        id: &str,
+        path: &str,
+        data: &[u8],
+        chunk_size: usize,
+    ) -> Result<FinalizeUploadResponse, Error> {
+        let req = CreateUploadRequest {
+            path: path.to_string(),
+            size: data.len() as u64,
+            sha256: None,
+        };
+        let upload = self.create_upload(id, &req).await?;
+        let mut offset = 0u64;
+        let mut failures = 0;
+        while offset < req.size {
+            let end = (offset as usize).saturating_add(chunk_size.max(1)).min(data.len());
+            let chunk = data[offset as usize..end].to_vec();
+            match self.upload_chunk(id, &upload.id, offset, chunk).await {
+                Ok(next) => {
+                    offset = next;
+                    failures = 0;
+                }
+                Err(e) if failures >= self.retry.max_retries => return Err(e),
+                Err(_) => {
+                    failures += 1;
+                    offset = self.upload_status(id, &upload.id).await?.offset;
+                }
+            }
+        }
+        self.finalize_upload(id, &upload.id).await
+    }
+
+    // Checkpoints
+
+    /// Save a copy of the session's files to diff against later.
+    pub async fn create_checkpoint(&self, id: &str, label: Option<&str>) -> Result<CheckpointInfo, Error> {
+        let body = CreateCheckpointRequest {
+            label: label.map(str::to_string),
+        };
+        self.post_json(&format!("/sessions/{}/checkpoints", id), &body).await
+    }
+
+    /// Checkpoints of a session, oldest first.
+    pub async fn list_checkpoints(&self, id: &str) -> Result<Vec<CheckpointInfo>, Error> {
+        self.get_json(&format!("/sessions/{}/checkpoints", id)).await
+    }
+
+    pub async fn delete_checkpoint(&self, id: &str, checkpoint_id: &str) -> Result<(), Error> {
+        let url = format!("/sessions/{}/checkpoints/{}", id, checkpoint_id);
+        self.send(Method::DELETE, &url, |r| r).await?;
+        Ok(())
+    }
+
+    /// What changed in the session's files from checkpoint `from` to `to`.
+    pub async fn diff_checkpoints(
+        &self,
+        id: &str,
+        from: &str,
+        to: &str,
+        query: &CheckpointDiffQuery,
+    ) -> Result<CheckpointDiff, Error> {
+        let url = format!("/sessions/{}/checkpoints/{}/diff/{}", id, from, to);
+        let resp = self.send(Method::GET, &url, |r| r.query(query)).await?;
+        decode(resp).await
+    }
+
+    // Templates
+
+    pub async fn list_templates(&self) -> Result<Vec<TemplateInfo>, Error> {
+        self.get_json("/templates").await
+    }
+
+    /// Register a template from a directory on the server's filesystem, inside
+    /// one of its template source dirs.
+    pub async fn register_template(&self, name: &str, server_path: &str) -> Result<TemplateInfo, Error> {
+        self.post_json("/templates", &RegisterTemplateRequest {
+                name: name.to_string(),
+                path: server_path.to_string(),
+            })
+            .await
+    }
+
+    /// Register a template from a tar or tar.gz archive.
+    pub async fn upload_template(&self, name: &str, tarball: Vec<u8>) -> Result<TemplateInfo, Error> {
+        let url = format!("/templates/{}", name);
+        let resp = self.send(Method::PUT, &url, |r| r.body(tarball.clone())).await?;
+        decode(resp).await
+    }
+
+    pub async fn delete_template(&self, name: &str) -> Result<(), Error> {
+        self.send(Method::DELETE, &format!("/templates/{}", name), |r| r).await?;
+        Ok(())
+    }
+
+    // Progress events
+
+    /// Subscribe to progress of template registration/materialization and
+    /// replay restores. The stream ends when the server closes it.
+    pub async fn progress_events(
+        &self,
+        query: &ProgressEventsQuery,
+    ) -> Result<impl Stream<Item = Result<ProgressEvent, Error>>, Error> {
+        let resp = self.send(Method::GET, "/events", |r| r.query(&query.to_pairs())).await?;
+        Ok(sse_events(resp))
+    }
+
+    // Schedules
+
+    /// Check a cron expression and preview its next fire times. A bad
+    /// expression comes back with `valid: false` rather than as an error.
+    pub async fn validate_schedule(&self, req: &ValidateScheduleRequest) -> Result<ValidateScheduleResponse, Error> {
+        self.post_json("/schedules/validate", req).await
+    }
+
+    // Record/replay
+
+    /// Download the bundle (tar.gz) of a run made with `record: true`.
+    pub async fn download_replay(&self, replay_id: &str) -> Result<Vec<u8>, Error> {
+        let resp = self.send(Method::GET, &format!("/replays/{}", replay_id), |r| r).await?;
+        Ok(resp.bytes().await?.to_vec())
+    }
+
+    /// Replay a bundle in a fresh sandbox on this server.
+    pub async fn replay(&self, bundle: Vec<u8>) -> Result<ReplayOutcome, Error> {
+        let resp = self.send(Method::POST, "/replays", |r| r.body(bundle.clone())).await?;
+        decode(resp).await
+    }
+
+    pub async fn delete_replay(&self, replay_id: &str) -> Result<(), Error> {
+        self.send(Method::DELETE, &format!("/replays/{}", replay_id), |r| r).await?;
+        Ok(())
+    }
+
+    // Admin
+    //
+    // When the server gives the operator API a listener of its own, point a
+    // client at that address (with the admin token as its key) for these.
+
+    /// Host-wide usage of every session, top consumers, ports, warm pool and
+    /// queues. Needs the `admin.read` scope.
+    pub async fn overview(&self, query: &OverviewQuery) -> Result<Overview, Error> {
+        let resp = self.send(Method::GET, "/admin/overview", |r| r.query(query)).await?;
+        decode(resp).await
+    }
+
+    /// Sessions of every org, or of `query.org`. Needs `admin.read`.
+    pub async fn admin_sessions(&self, query: &AdminSessionsQuery) -> Result<Vec<AdminSessionInfo>, Error> {
+        let resp = self.send(Method::GET, "/admin/sessions", |r| r.query(query)).await?;
+        decode(resp).await
+    }
+
+    /// Delete a session whichever org owns it. Needs `admin.write`.
+    pub async fn force_delete_session(&self, id: &str) -> Result<(), Error> {
+        self.send(Method::DELETE, &format!("/admin/sessions/{}", id), |r| r).await?;
+        Ok(())
+    }
+
+    /// Every org's quotas and usage. Needs `admin.read`.
+    pub async fn org_usage(&self) -> Result<Vec<OrgUsage>, Error> {
+        self.get_json("/admin/orgs").await
+    }
+
+    /// Server metrics in the Prometheus text format. Needs `admin.read`.
+    pub async fn metrics(&self) -> Result<String, Error> {
+        let resp = self.send(Method::GET, "/admin/metrics", |r| r).await?;
+        resp.text().await.map_err(Error::from)
+    }
+
+    /// Have the server read its API key file again. Needs `admin.write`.
+    pub async fn reload_api_keys(&self) -> Result<ReloadResponse, Error> {
+        let resp = self.send(Method::POST, "/admin/reload", |r| r).await?;
+        decode(resp).await
+    }
+
+    /// Stop the server taking new sessions, ahead of a shutdown. Needs
+    /// `admin.write`.
+    pub async fn drain(&self) -> Result<DrainResponse, Error> {
+        let resp = self.send(Method::POST, "/admin/drain", |r| r).await?;
+        decode(resp).await
+    }
+
+    // Fault injection
+
+    /// Current fault settings of a server built with the `chaos` feature
+    /// (404 otherwise).
+    pub async fn faults(&self) -> Result<FaultsResponse, Error> {
+        self.get_json("/admin/faults").await
+    }
+
+    /// Replace the server's fault settings; an all-zero config turns
+    /// injection off.
+    pub async fn set_faults(&self, config: &FaultConfig) -> Result<FaultsResponse, Error> {
+        let resp = self.send(Method::PUT, "/admin/faults", |r| r.json(config)).await?;
+        decode(resp).await
+    }
+
+    // Transport
+
+    async fn get_json<T: DeserializeOwned>(&self, path: &str) -> Result<T, Error> {
+        let resp = self.send(Method::GET, path, |r| r).await?;
+        decode(resp).await
+    }
+
+    async fn post_json<B: Serialize, T: DeserializeOwned>(&self, path: &str, body: &B) -> Result<T, Error> {
+        let resp = self.send(Method::POST, path, |r| r.json(body)).await?;
+        decode(resp).await
+    }
+
+    /// [`Client::post_json`] under a fresh `Idempotency-Key`, so the server
+    /// applies it once however often it's retried.
+    async fn post_json_once<B: Serialize, T: DeserializeOwned>(&self, path: &str, body: &B) -> Result<T, Error> {
+        let key = uuid::Uuid::new_v4().to_string();
+        let resp = self
+            .send_with(Method::POST, path, true, |r| r.header(IDEMPOTENCY_KEY_HEADER, &key).json(body))
+            .await?;
+        decode(resp).await
+    }
+
+    async fn post_empty<T: DeserializeOwned>(&self, path: &str) -> Result<T, Error> {
+        let resp = self.send(Method::POST, path, |r| r).await?;
+        decode(resp).await
+    }
+
+    /// A health report, which comes with `503` when a check fails. Not
+    /// retried: a probe wants the answer as it is now.
+    async fn health_report(&self, path: &str) -> Result<HealthReport, Error> {
+        let resp = self.http.get(format!("{}{}", self.base_url, path)).send().await?;
+        let status = resp.status();
+        if status.is_success() || status == StatusCode::SERVICE_UNAVAILABLE {
+            return resp.json().await.map_err(Error::from);
+        }
+        let message = resp.text().await.unwrap_or_default();
+        Err(Error::Api { status: status.as_u16(), message })
+    }
+
+    /// Send a request with retries; non-2xx responses become [`Error::Api`].
+    async fn send<F>(&self, method: Method, path: &str, build: F) -> Result<Response, Error>
+    where
+        F: Fn(RequestBuilder) -> RequestBuilder,
+    {
+        let idempotent = matches!(method, Method::GET | Method::PUT | Method::DELETE);
+        self.send_with(method, path, idempotent, build).await
+    }
+
+    /// [`Client::send`], retrying error responses too if `idempotent`.
+    async fn send_with<F>(&self, method: Method, path: &str, idempotent: bool, build: F) -> Result<Response, Error>
+    where
+        F: Fn(RequestBuilder) -> RequestBuilder,
+    {
+        let url = format!("{}{}", self.base_url, path);
+        let mut backoff = self.retry.initial_backoff;
+        let mut attempt = 0;
+        loop {
+            let mut req = build(self.http.request(method.clone(), &url));
+            if let Some(ref key) = self.api_key {
+                req = req.bearer_auth(key);
+            }
+            match req.send().await {
+                Ok(resp) if resp.status().is_success() => return Ok(resp),
+                Ok(resp) => {
+                    let status = resp.status();
+                    if !(idempotent && is_transient(status)) || attempt >= self.retry.max_retries {
+                        let message = resp.text().await.unwrap_or_default();
+                        return Err(Error::Api { status: status.as_u16(), message });
+                    }
+                }
+                Err(e) if e.is_connect() && attempt < self.retry.max_retries => {}
+                Err(e) => return Err(Error::Request(e)),
+            }
+            attempt += 1;
+            tokio::time::sleep(backoff).await;
+            backoff = (backoff * 2).min(self.retry.max_backoff);
+        }
+    }
+}
+
+fn is_transient(status: StatusCode) -> bool {
+    matches!(
+        status,
+        StatusCode::BAD_GATEWAY | StatusCode::SERVICE_UNAVAILABLE | StatusCode::GATEWAY_TIMEOUT
+    )
+}
+
+async fn decode<T: DeserializeOwned>(resp: Response) -> Result<T, Error> {
+    let bytes = resp.bytes().await?;
+    serde_json::from_slice(&bytes).map_err(|e| Error::Decode(e.to_string()))
+}
+
+/// The `data` of each server-sent event in `resp`, decoded as JSON. Ends when
+/// the server closes the stream.
+fn sse_events<T: DeserializeOwned>(resp: Response) -> impl Stream<Item = Result<T, Error>> {
+    let start = (resp.bytes_stream(), Vec::new());
+    futures_util::stream::unfold(start, |(mut body, mut buf)| async move {
+        loop {
+            // Events are separated by a blank line
+            if let Some(end) = buf.windows(2).position(|w| w == b"\n\n") {
+                let block: Vec<u8> = buf.drain(..end + 2).collect();
+                let data: Vec<&str> = std::str::from_utf8(&block)
+                    .unwrap_or_default()
+                    .lines()
+                    .filter_map(|l| l.strip_prefix("data:"))
+                    .map(|d| d.strip_prefix(' ').unwrap_or(d))
+                    .collect();
+                // Keep-alive comments carry no data
+                if data.is_empty() {
+                    continue;
+                }
+                let event = serde_json::from_str(&data.join("\n")).map_err(|e| Error::Decode(e.to_string()));
+                return Some((event, (body, buf)));
+            }
+            match body.next().await? {
+                Ok(chunk) => buf.extend_from_slice(&chunk),
+                Err(e) => return Some((Err(Error::Request(e)), (body, buf))),
+            }
+        }
+    })
+}
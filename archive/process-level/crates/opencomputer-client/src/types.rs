@@ -0,0 +1,87 @@
+//! Request/response types for the HTTP API.
+//!
+//! The wire types come from `opencomputer-types`, which the server uses
+//! too, so the two cannot drift apart. Only client-side query builders
+//! live here.
+
+pub use opencomputer_types::*;
+
+/// Filters, sorting and pagination for `GET /sessions`.
+#[derive(Debug, Clone, Default)]
+pub struct ListSessionsQuery {
+    /// `key=value` for an exact match, or `key` to require the label exists
+    pub labels: Vec<String>,
+    /// running, idle, paused, hibernated, or terminating
+    pub status: Option<String>,
+    /// "age" (default) or "idle"
+    pub sort: Option<String>,
+    /// "desc" (default, oldest/most idle first) or "asc"
+    pub order: Option<String>,
+    pub limit: Option<usize>,
+    /// `next_cursor` from the previous page
+    pub cursor: Option<String>,
+    /// Fields to include, e.g. `["status", "labels"]`; `id` is always included
+    pub fields: Option<Vec<String>>,
+}
+
+/// One page of sessions.
+#[derive(Debug, Clone)]
+pub struct SessionPage {
+    pub sessions: Vec<SessionInfo>,
+    /// Cursor for the next page, if more sessions remain
+    pub next_cursor: Option<String>,
+}
+
+impl ListSessionsQuery {
+    pub fn label(mut self, key: &str, value: &str) -> Self {
+        self.labels.push(format!("{}={}", key, value));
+        self
+    }
+
+    pub fn status(mut self, status: impl Into<String>) -> Self {
+        self.status = Some(status.into());
+        self
+    }
+
+    pub fn limit(mut self, limit: usize) -> Self {
+        self.limit = Some(limit);
+        self
+    }
+
+    pub(crate) fn to_pairs(&self) -> Vec<(&'static str, String)> {
+        let mut pairs: Vec<(&'static str, String)> =
+            self.labels.iter().map(|l| ("label", l.clone())).collect();
+        let optional = [
+            ("status", self.status.clone()),
+            ("sort", self.sort.clone()),
+            ("order", self.order.clone()),
+            ("limit", self.limit.map(|l| l.to_string())),
+            ("cursor", self.cursor.clone()),
+            ("fields", self.fields.as_ref().map(|f| f.join(","))),
+        ];
+        pairs.extend(optional.into_iter().filter_map(|(k, v)| v.map(|v| (k, v))));
+        pairs
+    }
+}
+
+/// Filters for [`Client::progress_events`](crate::Client::progress_events).
+#[derive(Debug, Clone, Default)]
+pub struct ProgressEventsQuery {
+    /// `template.register`, `replay.restore` or `packages.install`
+    pub operation: Option<String>,
+    pub session_id: Option<String>,
+    pub template: Option<String>,
+}
+
+impl ProgressEventsQuery {
+    pub(crate) fn to_pairs(&self) -> Vec<(&'static str, String)> {
+        [
+            ("operation", self.operation.clone()),
+            ("session_id", self.session_id.clone()),
+            ("template", self.template.clone()),
+        ]
+        .into_iter()
+        .filter_map(|(k, v)| v.map(|v| (k, v)))
+        .collect()
+    }
+}
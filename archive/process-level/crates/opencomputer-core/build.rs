@@ -0,0 +1,5 @@
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    // The proto lives at the top of the repo so the Python SDK can generate from it too
+    tonic_build::compile_protos("../../proto/sandbox.proto")?;
+    Ok(())
+}
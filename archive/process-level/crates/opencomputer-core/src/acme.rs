@@ -0,0 +1,457 @@
+//! Certificates from an ACME CA (Let's Encrypt by default) via DNS-01.
+//!
+//! DNS-01 is the only challenge that can prove control of a wildcard name,
+//! which the preview domain needs. Publishing the TXT records is left to a
+//! hook command, so any DNS provider works:
+//!
+//! ```text
+//! <dns_hook> present _acme-challenge.preview.example.com <value>
+//! <dns_hook> cleanup _acme-challenge.preview.example.com <value>
+//! ```
+//!
+//! `present` must add the record without replacing other TXT values under
+//! the same name: a wildcard and its apex share one record name. A non-zero
+//! exit from `present` fails the order.
+//!
+//! The account key, certificate and certificate key are cached in
+//! `cache_dir`; a cached certificate is reused until it is within
+//! [`RENEW_BEFORE`] of expiring or no longer covers the configured names.
+
+use crate::tls::CertStore;
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD as B64, Engine};
+use reqwest::header::{HeaderMap, CONTENT_TYPE, LOCATION};
+use ring::rand::SystemRandom;
+use ring::signature::{EcdsaKeyPair, KeyPair, ECDSA_P256_SHA256_FIXED_SIGNING};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use tracing::{info, warn};
+
+/// Let's Encrypt's production directory.
+pub const LETS_ENCRYPT_DIRECTORY: &str = "https://acme-v02.api.letsencrypt.org/directory";
+
+/// Renew certificates expiring within this long.
+pub const RENEW_BEFORE: Duration = Duration::from_secs(30 * 24 * 3600);
+
+/// How often the renewal task checks the expiry date.
+const RENEW_CHECK_INTERVAL: Duration = Duration::from_secs(12 * 3600);
+
+/// Retry delay after a failed renewal.
+const RENEW_RETRY_INTERVAL: Duration = Duration::from_secs(3600);
+
+/// How long to wait for the CA to validate challenges or issue the certificate.
+const POLL_TIMEOUT: Duration = Duration::from_secs(180);
+
+const ACCOUNT_KEY_FILE: &str = "account.key";
+const CERT_FILE: &str = "cert.pem";
+const KEY_FILE: &str = "key.pem";
+
+#[derive(Debug, Clone)]
+pub struct AcmeConfig {
+    /// ACME directory URL
+    pub directory: String,
+    /// Contact address for expiry notices from the CA
+    pub email: Option<String>,
+    /// Names to certify, e.g. `["api.example.com", "*.preview.example.com"]`
+    pub domains: Vec<String>,
+    /// Command publishing and removing the challenge TXT records
+    pub dns_hook: PathBuf,
+    pub cache_dir: PathBuf,
+    /// Wait after publishing records before asking the CA to check them
+    pub propagation_delay: Duration,
+}
+
+impl AcmeConfig {
+    fn cached(&self) -> Option<CertStore> {
+        let store = CertStore::load(&self.cache_dir.join(CERT_FILE), &self.cache_dir.join(KEY_FILE)).ok()?;
+        self.is_current(&store).then_some(store)
+    }
+
+    /// Whether `store` covers every configured name and isn't due for renewal.
+    fn is_current(&self, store: &CertStore) -> bool {
+        let names = store.names();
+        let covered = self.domains.iter().all(|d| names.iter().any(|n| n.eq_ignore_ascii_case(d)));
+        let fresh = store
+            .not_after()
+            .is_some_and(|at| at > SystemTime::now() + RENEW_BEFORE);
+        covered && fresh
+    }
+}
+
+/// The cached certificate if still good, otherwise a newly issued one.
+pub async fn load_or_issue(config: &AcmeConfig) -> Result<CertStore, String> {
+    if let Some(store) = config.cached() {
+        info!("Using cached ACME certificate for {}", config.domains.join(", "));
+        return Ok(store);
+    }
+    let (cert_pem, key_pem) = issue(config).await?;
+    CertStore::from_pem(cert_pem.as_bytes(), key_pem.as_bytes())
+}
+
+/// Renew the certificate in `store` before it expires.
+pub fn spawn_renewal(config: AcmeConfig, store: Arc<CertStore>) {
+    tokio::spawn(async move {
+        let mut wait = RENEW_CHECK_INTERVAL;
+        loop {
+            tokio::time::sleep(wait).await;
+            wait = RENEW_CHECK_INTERVAL;
+            if config.is_current(&store) {
+                continue;
+            }
+            let renewed = match issue(&config).await {
+                Ok((cert_pem, key_pem)) => store.replace(cert_pem.as_bytes(), key_pem.as_bytes()),
+                Err(e) => Err(e),
+            };
+            match renewed {
+                Ok(()) => info!("Renewed ACME certificate for {}", config.domains.join(", ")),
+                Err(e) => {
+                    warn!("ACME renewal failed, retrying in an hour: {}", e);
+                    wait = RENEW_RETRY_INTERVAL;
+                }
+            }
+        }
+    });
+}
+
+/// Order a certificate and cache it. Returns the PEM chain and PEM key.
+pub async fn issue(config: &AcmeConfig) -> Result<(String, String), String> {
+    std::fs::create_dir_all(&config.cache_dir)
+        .map_err(|e| format!("mkdir {}: {}", config.cache_dir.display(), e))?;
+    let mut client = Client::new(config).await?;
+    client.register(config.email.as_deref()).await?;
+
+    info!("Ordering ACME certificate for {}", config.domains.join(", "));
+    let identifiers: Vec<Value> = config
+        .domains
+        .iter()
+        .map(|d| json!({"type": "dns", "value": d}))
+        .collect();
+    let (headers, order) = client.post(&client.directory.new_order.clone(), Some(json!({"identifiers": identifiers}))).await?;
+    let order_url = location(&headers)?;
+    let order: Order = parse(&order)?;
+
+    // Publish every challenge first, then have the CA check them all
+    let mut challenges = Vec::new();
+    for authz_url in &order.authorizations {
+        let authz: Authorization = parse(&client.post(authz_url, None).await?.1)?;
+        if authz.status == "valid" {
+            continue;
+        }
+        let challenge = authz
+            .challenges
+            .iter()
+            .find(|c| c.kind == "dns-01")
+            .ok_or_else(|| format!("CA offers no dns-01 challenge for {}", authz.identifier.value))?;
+        let record = format!("_acme-challenge.{}", authz.identifier.value.trim_start_matches("*."));
+        let key_authorization = format!("{}.{}", challenge.token, client.thumbprint);
+        let value = B64.encode(Sha256::digest(key_authorization.as_bytes()));
+        challenges.push((record, value, challenge.url.clone(), authz_url.clone()));
+    }
+    let validated = async {
+        for (record, value, _, _) in &challenges {
+            run_hook(&config.dns_hook, "present", record, value).await?;
+        }
+        if !challenges.is_empty() {
+            tokio::time::sleep(config.propagation_delay).await;
+        }
+        for (_, _, challenge_url, _) in &challenges {
+            client.post(challenge_url, Some(json!({}))).await?;
+        }
+        for (record, _, _, authz_url) in &challenges {
+            let authz: Authorization = client.poll(authz_url, &["pending"]).await?;
+            if authz.status != "valid" {
+                return Err(format!("{} validation {}: {}", record, authz.status, authz.error_detail()));
+            }
+        }
+        Ok(())
+    }
+    .await;
+    for (record, value, _, _) in &challenges {
+        if let Err(e) = run_hook(&config.dns_hook, "cleanup", record, value).await {
+            warn!("ACME DNS cleanup failed: {}", e);
+        }
+    }
+    validated?;
+
+    let key = rcgen::KeyPair::generate().map_err(|e| format!("generate key: {}", e))?;
+    let csr = rcgen::CertificateParams::new(config.domains.clone())
+        .and_then(|params| params.serialize_request(&key))
+        .map_err(|e| format!("build CSR: {}", e))?;
+    client
+        .post(&order.finalize, Some(json!({"csr": B64.encode(csr.der())})))
+        .await?;
+    let order: Order = client.poll(&order_url, &["pending", "ready", "processing"]).await?;
+    let cert_url = match (order.status.as_str(), order.certificate) {
+        ("valid", Some(url)) => url,
+        (status, _) => return Err(format!("order {}", status)),
+    };
+    let (_, cert) = client.post(&cert_url, None).await?;
+    let cert_pem = String::from_utf8(cert).map_err(|_| "certificate is not PEM".to_string())?;
+    let key_pem = key.serialize_pem();
+
+    write_private(&config.cache_dir.join(KEY_FILE), key_pem.as_bytes())?;
+    std::fs::write(config.cache_dir.join(CERT_FILE), &cert_pem).map_err(|e| format!("cache certificate: {}", e))?;
+    info!("Issued ACME certificate for {}", config.domains.join(", "));
+    Ok((cert_pem, key_pem))
+}
+
+async fn run_hook(hook: &Path, action: &str, record: &str, value: &str) -> Result<(), String> {
+    let output = tokio::process::Command::new(hook)
+        .args([action, record, value])
+        .output()
+        .await
+        .map_err(|e| format!("run {}: {}", hook.display(), e))?;
+    if !output.status.success() {
+        return Err(format!(
+            "{} {} {} failed ({}): {}",
+            hook.display(),
+            action,
+            record,
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    Ok(())
+}
+
+fn write_private(path: &Path, data: &[u8]) -> Result<(), String> {
+    std::fs::write(path, data).map_err(|e| format!("write {}: {}", path.display(), e))?;
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))
+        .map_err(|e| format!("chmod {}: {}", path.display(), e))
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Directory {
+    new_nonce: String,
+    new_account: String,
+    new_order: String,
+}
+
+#[derive(Deserialize)]
+struct Order {
+    status: String,
+    #[serde(default)]
+    authorizations: Vec<String>,
+    #[serde(default)]
+    finalize: String,
+    certificate: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct Authorization {
+    status: String,
+    identifier: Identifier,
+    #[serde(default)]
+    challenges: Vec<Challenge>,
+}
+
+impl Authorization {
+    fn error_detail(&self) -> String {
+        self.challenges
+            .iter()
+            .filter_map(|c| c.error.as_ref()?.get("detail")?.as_str())
+            .collect::<Vec<_>>()
+            .join("; ")
+    }
+}
+
+#[derive(Deserialize)]
+struct Identifier {
+    value: String,
+}
+
+#[derive(Deserialize)]
+struct Challenge {
+    #[serde(rename = "type")]
+    kind: String,
+    url: String,
+    #[serde(default)]
+    token: String,
+    error: Option<Value>,
+}
+
+fn parse<T: serde::de::DeserializeOwned>(body: &[u8]) -> Result<T, String> {
+    serde_json::from_slice(body).map_err(|e| format!("unexpected ACME response: {}", e))
+}
+
+fn location(headers: &HeaderMap) -> Result<String, String> {
+    headers
+        .get(LOCATION)
+        .and_then(|v| v.to_str().ok())
+        .map(str::to_string)
+        .ok_or_else(|| "ACME response has no Location".to_string())
+}
+
+/// An ACME account session: signs requests with the account key (RFC 8555).
+struct Client {
+    http: reqwest::Client,
+    directory: Directory,
+    key: EcdsaKeyPair,
+    rng: SystemRandom,
+    jwk: Value,
+    /// base64url SHA-256 of the account JWK, part of every key authorization
+    thumbprint: String,
+    kid: Option<String>,
+    nonce: Option<String>,
+}
+
+impl Client {
+    async fn new(config: &AcmeConfig) -> Result<Self, String> {
+        let http = reqwest::Client::builder()
+            .timeout(Duration::from_secs(30))
+            .build()
+            .map_err(|e| e.to_string())?;
+        let directory: Directory = parse(
+            &http
+                .get(&config.directory)
+                .send()
+                .await
+                .and_then(|r| r.error_for_status())
+                .map_err(|e| format!("fetch ACME directory: {}", e))?
+                .bytes()
+                .await
+                .map_err(|e| e.to_string())?,
+        )?;
+
+        let rng = SystemRandom::new();
+        let key_path = config.cache_dir.join(ACCOUNT_KEY_FILE);
+        let pkcs8 = match std::fs::read(&key_path) {
+            Ok(pkcs8) => pkcs8,
+            Err(_) => {
+                let pkcs8 = EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &rng)
+                    .map_err(|_| "generate account key".to_string())?;
+                write_private(&key_path, pkcs8.as_ref())?;
+                pkcs8.as_ref().to_vec()
+            }
+        };
+        let key = EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &pkcs8, &rng)
+            .map_err(|e| format!("{}: {}", key_path.display(), e))?;
+
+        // Uncompressed point: 0x04 || x || y
+        let point = key.public_key().as_ref();
+        let (x, y) = (B64.encode(&point[1..33]), B64.encode(&point[33..65]));
+        // The thumbprint hashes the members in lexicographic order, no spaces
+        let canonical = format!(r#"{{"crv":"P-256","kty":"EC","x":"{}","y":"{}"}}"#, x, y);
+        let thumbprint = B64.encode(Sha256::digest(canonical.as_bytes()));
+        Ok(Self {
+            http,
+            directory,
+            key,
+            rng,
+            jwk: json!({"crv": "P-256", "kty": "EC", "x": x, "y": y}),
+            thumbprint,
+            kid: None,
+            nonce: None,
+        })
+    }
+
+    /// Create the account, or look up the existing one for this key.
+    async fn register(&mut self, email: Option<&str>) -> Result<(), String> {
+        let mut payload = json!({"termsOfServiceAgreed": true});
+        if let Some(email) = email {
+            payload["contact"] = json!([format!("mailto:{}", email)]);
+        }
+        let (headers, _) = self.post(&self.directory.new_account.clone(), Some(payload)).await?;
+        self.kid = Some(location(&headers)?);
+        Ok(())
+    }
+
+    async fn nonce(&mut self) -> Result<String, String> {
+        if let Some(nonce) = self.nonce.take() {
+            return Ok(nonce);
+        }
+        let resp = self
+            .http
+            .head(&self.directory.new_nonce)
+            .send()
+            .await
+            .map_err(|e| format!("fetch ACME nonce: {}", e))?;
+        replay_nonce(resp.headers()).ok_or_else(|| "ACME server sent no nonce".to_string())
+    }
+
+    /// Signed POST; no payload makes it a POST-as-GET. Retries a rejected nonce.
+    async fn post(&mut self, url: &str, payload: Option<Value>) -> Result<(HeaderMap, Vec<u8>), String> {
+        let payload = match payload {
+            Some(ref payload) => B64.encode(payload.to_string()),
+            None => String::new(),
+        };
+        for _ in 0..3 {
+            let mut protected = json!({"alg": "ES256", "nonce": self.nonce().await?, "url": url});
+            match self.kid {
+                Some(ref kid) => protected["kid"] = json!(kid),
+                None => protected["jwk"] = self.jwk.clone(),
+            }
+            let protected = B64.encode(protected.to_string());
+            let signature = self
+                .key
+                .sign(&self.rng, format!("{}.{}", protected, payload).as_bytes())
+                .map_err(|_| "sign ACME request".to_string())?;
+            let body = json!({"protected": protected, "payload": payload, "signature": B64.encode(signature)});
+            let resp = self
+                .http
+                .post(url)
+                .header(CONTENT_TYPE, "application/jose+json")
+                .body(body.to_string())
+                .send()
+                .await
+                .map_err(|e| format!("ACME request to {}: {}", url, e))?;
+            self.nonce = replay_nonce(resp.headers());
+            let (status, headers) = (resp.status(), resp.headers().clone());
+            let body = resp.bytes().await.map_err(|e| e.to_string())?.to_vec();
+            if status.is_success() {
+                return Ok((headers, body));
+            }
+            let problem: Value = serde_json::from_slice(&body).unwrap_or_default();
+            if problem["type"] == "urn:ietf:params:acme:error:badNonce" {
+                continue;
+            }
+            return Err(format!(
+                "ACME request to {} failed ({}): {}",
+                url,
+                status,
+                problem["detail"].as_str().unwrap_or_default()
+            ));
+        }
+        Err(format!("ACME request to {}: nonce rejected repeatedly", url))
+    }
+
+    /// Re-fetch `url` while its status is one of `waiting`.
+    async fn poll<T: serde::de::DeserializeOwned + HasStatus>(&mut self, url: &str, waiting: &[&str]) -> Result<T, String> {
+        let deadline = tokio::time::Instant::now() + POLL_TIMEOUT;
+        loop {
+            let resource: T = parse(&self.post(url, None).await?.1)?;
+            if !waiting.contains(&resource.status()) {
+                return Ok(resource);
+            }
+            if tokio::time::Instant::now() >= deadline {
+                return Err(format!("timed out waiting on {} ({})", url, resource.status()));
+            }
+            tokio::time::sleep(Duration::from_secs(2)).await;
+        }
+    }
+}
+
+trait HasStatus {
+    fn status(&self) -> &str;
+}
+
+impl HasStatus for Order {
+    fn status(&self) -> &str {
+        &self.status
+    }
+}
+
+impl HasStatus for Authorization {
+    fn status(&self) -> &str {
+        &self.status
+    }
+}
+
+fn replay_nonce(headers: &HeaderMap) -> Option<String> {
+    headers.get("replay-nonce")?.to_str().ok().map(str::to_string)
+}
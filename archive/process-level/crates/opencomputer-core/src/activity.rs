@@ -0,0 +1,94 @@
+//! Activity-based idle detection.
+//!
+//! A session's `last_used` only moves on API calls, so a long build writing
+//! to a background log or a preview tab holding a websocket open would look
+//! idle and be reaped mid-work. Every stats sample (see [`crate::stats`]),
+//! the cleanup task also asks whether each session is busy: a run, job,
+//! package install or git operation in progress, an open preview or tunnel
+//! connection, or its processes using at least [`ACTIVE_CPU_PERCENT`] of a
+//! core. A busy session gets `last_activity` set, and its idle TTL counts
+//! from whichever of that and `last_used` is later.
+
+use opencomputer_types::{IdleReason, SessionStats};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+/// CPU use over one sample that counts as activity; 100 is one full core.
+/// Above what an idle dev server's file watcher uses.
+pub const ACTIVE_CPU_PERCENT: f64 = 5.0;
+
+/// Runs and connections in progress, by session.
+#[derive(Default)]
+pub struct ActivityTracker {
+    by_session: Mutex<HashMap<String, InProgress>>,
+}
+
+#[derive(Default)]
+struct InProgress {
+    runs: usize,
+    connections: usize,
+}
+
+impl InProgress {
+    fn count(&mut self, reason: IdleReason) -> &mut usize {
+        match reason {
+            IdleReason::Connection => &mut self.connections,
+            _ => &mut self.runs,
+        }
+    }
+}
+
+/// Counts a run or connection as in progress until dropped.
+pub struct ActivityGuard {
+    tracker: Arc<ActivityTracker>,
+    session_id: String,
+    reason: IdleReason,
+}
+
+impl ActivityTracker {
+    /// Count a run in `session_id` while the guard lives.
+    pub fn run(self: &Arc<Self>, session_id: &str) -> ActivityGuard {
+        self.start(session_id, IdleReason::Run)
+    }
+
+    /// Count a preview or tunnel connection to `session_id` while the guard
+    /// lives.
+    pub fn connection(self: &Arc<Self>, session_id: &str) -> ActivityGuard {
+        self.start(session_id, IdleReason::Connection)
+    }
+
+    fn start(self: &Arc<Self>, session_id: &str, reason: IdleReason) -> ActivityGuard {
+        let mut by_session = self.by_session.lock().unwrap_or_else(|e| e.into_inner());
+        *by_session.entry(session_id.to_string()).or_default().count(reason) += 1;
+        ActivityGuard {
+            tracker: self.clone(),
+            session_id: session_id.to_string(),
+            reason,
+        }
+    }
+
+    /// Why the session counts as busy right now, given its latest stats, if
+    /// it does.
+    pub fn busy(&self, session_id: &str, stats: &SessionStats) -> Option<IdleReason> {
+        let by_session = self.by_session.lock().unwrap_or_else(|e| e.into_inner());
+        match by_session.get(session_id) {
+            Some(in_progress) if in_progress.runs > 0 => Some(IdleReason::Run),
+            Some(in_progress) if in_progress.connections > 0 => Some(IdleReason::Connection),
+            _ if stats.cpu_percent >= ACTIVE_CPU_PERCENT => Some(IdleReason::Cpu),
+            _ => None,
+        }
+    }
+}
+
+impl Drop for ActivityGuard {
+    fn drop(&mut self) {
+        let mut by_session = self.tracker.by_session.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(in_progress) = by_session.get_mut(&self.session_id) {
+            let count = in_progress.count(self.reason);
+            *count = count.saturating_sub(1);
+            if in_progress.runs == 0 && in_progress.connections == 0 {
+                by_session.remove(&self.session_id);
+            }
+        }
+    }
+}
@@ -0,0 +1,179 @@
+//! Operator API: the `/admin` routes.
+//!
+//! These see every org: the host overview, all sessions (and deleting any of
+//! them), org quotas and usage, Prometheus metrics, reloading API keys and
+//! draining the server. They need the `admin.read` or `admin.write` scope.
+//! The event stream, `GET /events/stream` (see [`crate::events`]), is served
+//! with them.
+//!
+//! They're only served when asked for: on a listener of their own
+//! ([`run_admin_server`](crate::http_server::run_admin_server), or
+//! `[admin] listen` in the server config), so they can be bound to a private
+//! interface, or with the tenant API behind an admin token. The token
+//! replaces API keys, so no tenant key, whatever its scopes, reaches them.
+//! Without one, only keys explicitly granted the operator scopes do; with no
+//! keys at all nobody does.
+//!
+//! Orgs are shown by [`ApiKeys::org_label`](crate::auth::ApiKeys::org_label),
+//! so a key that is its own org doesn't appear in responses or metrics.
+
+use crate::auth;
+use crate::preview_auth::constant_time_eq;
+use crate::sandbox;
+use crate::state::{AppState, SessionStatus};
+use axum::{
+    extract::{Request, State},
+    http::StatusCode,
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Write;
+use std::sync::Arc;
+use std::time::Duration;
+
+pub use opencomputer_types::{AdminSessionInfo, AdminSessionsQuery, DrainResponse, OrgUsage, ReloadResponse};
+
+/// Content type of `GET /admin/metrics`.
+pub const METRICS_CONTENT_TYPE: &str = "text/plain; version=0.0.4; charset=utf-8";
+
+const STATUSES: [SessionStatus; 5] = [
+    SessionStatus::Running,
+    SessionStatus::Idle,
+    SessionStatus::Paused,
+    SessionStatus::Hibernated,
+    SessionStatus::Terminating,
+];
+
+/// Marks a request that presented the admin token, which holds the
+/// operator scopes no caller gets by default.
+#[derive(Debug, Clone, Copy)]
+pub struct AdminTokenCaller;
+
+/// Axum route layer for the admin routes: require the admin token, in
+/// `Authorization: Bearer` or `X-API-Key`.
+pub async fn require_admin_token(State(token): State<Arc<str>>, mut req: Request, next: Next) -> Response {
+    let valid = auth::presented_key(req.headers()).is_some_and(|p| constant_time_eq(p.as_bytes(), token.as_bytes()));
+    if !valid {
+        return (StatusCode::UNAUTHORIZED, "Missing or invalid admin token").into_response();
+    }
+    req.extensions_mut().insert(AdminTokenCaller);
+    next.run(req).await
+}
+
+/// Every org with a quota, a live session or CPU time charged, by ID.
+pub async fn org_usage(state: &AppState) -> Vec<OrgUsage> {
+    let quotas = state.api_keys.orgs();
+    let cpu = state.cpu_usage.all();
+    let mut roots: BTreeMap<String, Vec<_>> = BTreeMap::new();
+    for session in state.sessions.read().await.values() {
+        if let Some(ref org) = session.org_id {
+            roots.entry(org.clone()).or_default().push(session.sandbox_root.clone());
+        }
+    }
+    let label = |org: &str| state.api_keys.org_label(org);
+    let orgs: BTreeSet<&String> = quotas.keys().chain(cpu.keys()).chain(roots.keys()).collect();
+    orgs.into_iter()
+        .map(|org| {
+            let org_roots = roots.get(org).map(Vec::as_slice).unwrap_or_default();
+            let quota = quotas.get(org).cloned().unwrap_or_default();
+            OrgUsage {
+                org_id: label(org),
+                sessions: org_roots.len(),
+                disk_bytes: org_roots.iter().map(|root| sandbox::disk_usage(root)).sum(),
+                cpu_secs: cpu.get(org).copied().unwrap_or_default().as_secs_f64(),
+                max_sessions: quota.max_sessions,
+                max_disk_mb: quota.max_disk_mb,
+                max_cpu_secs: quota.max_cpu_secs,
+            }
+        })
+        .collect()
+}
+
+/// Server metrics in the Prometheus text format.
+pub async fn render_metrics(state: &AppState) -> String {
+    let mut out = String::new();
+    let (by_status, by_org) = {
+        let sessions = state.sessions.read().await;
+        let mut by_org: BTreeMap<String, usize> = BTreeMap::new();
+        for org in sessions.values().filter_map(|s| s.org_id.as_ref()) {
+            *by_org.entry(state.api_keys.org_label(org)).or_default() += 1;
+        }
+        let by_status: Vec<(SessionStatus, usize)> = STATUSES
+            .iter()
+            .map(|&status| (status, sessions.values().filter(|s| s.status == status).count()))
+            .collect();
+        (by_status, by_org)
+    };
+
+    metric(&mut out, "opensandbox_sessions", "gauge", "Live sessions by status");
+    for (status, count) in by_status {
+        let _ = writeln!(out, "opensandbox_sessions{{status=\"{}\"}} {}", status, count);
+    }
+    if let Some(max) = state.max_sessions {
+        gauge(&mut out, "opensandbox_max_sessions", "Most live sessions allowed", max);
+    }
+    metric(&mut out, "opensandbox_org_sessions", "gauge", "Live sessions by org");
+    for (org, count) in by_org {
+        let _ = writeln!(out, "opensandbox_org_sessions{{org=\"{}\"}} {}", escape(&org), count);
+    }
+    metric(
+        &mut out,
+        "opensandbox_org_cpu_seconds_total",
+        "counter",
+        "CPU time of each org's runs since the server started",
+    );
+    let cpu: BTreeMap<String, Duration> = state
+        .cpu_usage
+        .all()
+        .into_iter()
+        .map(|(org, used)| (state.api_keys.org_label(&org), used))
+        .collect();
+    for (org, used) in cpu {
+        let _ = writeln!(out, "opensandbox_org_cpu_seconds_total{{org=\"{}\"}} {}", escape(&org), used.as_secs_f64());
+    }
+    gauge(
+        &mut out,
+        "opensandbox_session_creates_in_progress",
+        "Session creates in progress",
+        state.create_stats.queue_depth(),
+    );
+
+    let pool = state.warm_pool.stats().await;
+    gauge(&mut out, "opensandbox_warm_pool_target", "Sandboxes the warm pool keeps ready", pool.target);
+    gauge(&mut out, "opensandbox_warm_pool_available", "Sandboxes ready in the warm pool", pool.available);
+    counter(&mut out, "opensandbox_warm_pool_hits_total", "Creates that got a pooled sandbox", pool.hits);
+    counter(&mut out, "opensandbox_warm_pool_misses_total", "Creates made while the pool was empty", pool.misses);
+    counter(&mut out, "opensandbox_warm_pool_created_total", "Sandboxes the pool created", pool.created);
+    counter(&mut out, "opensandbox_warm_pool_failures_total", "Sandboxes the pool failed to create", pool.failures);
+
+    gauge(
+        &mut out,
+        "opensandbox_draining",
+        "1 once POST /admin/drain was called",
+        state.is_draining() as u8,
+    );
+    metric(&mut out, "opensandbox_uptime_seconds", "gauge", "Time since the server started");
+    let _ = writeln!(out, "opensandbox_uptime_seconds {}", state.started_at.elapsed().as_secs_f64());
+    out
+}
+
+fn metric(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+fn gauge(out: &mut String, name: &str, help: &str, value: impl std::fmt::Display) {
+    metric(out, name, "gauge", help);
+    let _ = writeln!(out, "{} {}", name, value);
+}
+
+fn counter(out: &mut String, name: &str, help: &str, value: u64) {
+    metric(out, name, "counter", help);
+    let _ = writeln!(out, "{} {}", name, value);
+}
+
+/// Escape a label value: backslash, double quote and newline.
+fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
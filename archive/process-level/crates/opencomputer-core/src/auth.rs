@@ -0,0 +1,315 @@
+//! API key authentication.
+//!
+//! Keys are loaded from a JSON file passed via `--api-keys-file`:
+//!
+//! ```json
+//! {"keys": [{"key": "osb_...", "name": "ci", "env": {"PIP_INDEX_URL": "https://mirror/simple"},
+//!            "max_sessions": 20, "scopes": ["sessions.*", "files.*", "exec.run"]}]}
+//! ```
+//!
+//! When no keys are configured the API is open, matching the previous behavior.
+//!
+//! Keys with the same `org_id` share an org: they see only that org's sessions
+//! and share its quotas (see [`crate::quota`]). A key without `org_id` is an
+//! org of its own.
+//!
+//! A key's `scopes` limit which routes it may call (see [`crate::scope`]).
+//! Session tokens minted with `POST /sessions/:id/tokens` are accepted in the
+//! same headers; they act as their creator's key, restricted to one session
+//! and to the scopes they were given.
+//!
+//! With `[auth.oidc]` configured, JWTs from that issuer are accepted too (see
+//! [`crate::oidc`]).
+//!
+//! Keys and org quotas can be swapped while serving with
+//! [`ApiKeys::replace`], which `POST /admin/reload` uses.
+
+use crate::oidc::Oidc;
+use crate::quota::OrgQuota;
+use crate::scope::{self, Scope};
+use crate::state::AppState;
+use axum::{
+    extract::{FromRequestParts, MatchedPath, RawPathParams, Request, State},
+    http::{header, HeaderMap, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::{Arc, RwLock};
+
+/// Header accepted as an alternative to `Authorization: Bearer`.
+pub const API_KEY_HEADER: &str = "x-api-key";
+
+/// A configured API key and the defaults applied to its sessions.
+#[derive(Debug, Clone, Deserialize)]
+pub struct ApiKey {
+    pub key: String,
+    /// Human-readable label used in logs
+    #[serde(default)]
+    pub name: String,
+    /// Environment injected into every session created with this key.
+    /// User-provided env takes precedence.
+    #[serde(default)]
+    pub env: HashMap<String, String>,
+    /// Most live sessions this key may hold at once (unset = unlimited)
+    #[serde(default)]
+    pub max_sessions: Option<usize>,
+    /// Organization whose sessions and quotas this key shares
+    #[serde(default)]
+    pub org_id: Option<String>,
+    /// Scope patterns the key holds (unset = every scope)
+    #[serde(default)]
+    pub scopes: Option<Vec<String>>,
+    /// Session a session token is bound to; never set for configured keys
+    #[serde(skip)]
+    pub session_id: Option<String>,
+}
+
+impl ApiKey {
+    /// Org the key acts for. Keys without `org_id` are their own org.
+    pub fn org(&self) -> &str {
+        self.org_id.as_deref().unwrap_or(&self.key)
+    }
+
+    pub fn allows(&self, scope: Scope) -> bool {
+        scope::allows(self.scopes.as_deref(), scope)
+    }
+
+    /// Derive a session token from this key: same org, quotas and env, bound
+    /// to `session_id` and limited to `scopes`.
+    pub fn session_token(&self, token: String, session_id: &str, scopes: Vec<String>) -> ApiKey {
+        ApiKey {
+            key: token,
+            name: format!("{} (session token)", self.name),
+            scopes: Some(scopes),
+            session_id: Some(session_id.to_string()),
+            org_id: Some(self.org().to_string()),
+            ..self.clone()
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct ApiKeysFile {
+    keys: Vec<ApiKey>,
+    #[serde(default)]
+    orgs: HashMap<String, OrgQuota>,
+}
+
+/// Lookup table of configured API keys.
+#[derive(Debug, Default)]
+pub struct ApiKeys {
+    keys: RwLock<HashMap<String, Arc<ApiKey>>>,
+    orgs: RwLock<HashMap<String, OrgQuota>>,
+    oidc: Option<Arc<Oidc>>,
+}
+
+impl ApiKeys {
+    /// Load keys from a JSON file. A file without keys is refused rather
+    /// than turning authentication off, so reloading one emptied by mistake
+    /// keeps the keys in force.
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let data = std::fs::read_to_string(path)
+            .map_err(|e| format!("read api keys {}: {}", path.display(), e))?;
+        let file: ApiKeysFile = serde_json::from_str(&data)
+            .map_err(|e| format!("parse api keys {}: {}", path.display(), e))?;
+        if file.keys.is_empty() {
+            return Err(format!("api keys {}: no keys", path.display()));
+        }
+        Self::from_keys(file.keys, file.orgs).map_err(|e| format!("api keys {}: {}", path.display(), e))
+    }
+
+    /// Build the table from keys and org quotas defined elsewhere, e.g. inline
+    /// in a config file.
+    pub fn from_keys(list: Vec<ApiKey>, orgs: HashMap<String, OrgQuota>) -> Result<Self, String> {
+        let mut keys = HashMap::new();
+        for key in list {
+            if key.key.is_empty() {
+                return Err(format!("empty key for {:?}", key.name));
+            }
+            if key.org_id.as_deref() == Some("") {
+                return Err(format!("empty org_id for {:?}", key.name));
+            }
+            for pattern in key.scopes.iter().flatten() {
+                scope::validate_pattern(pattern).map_err(|e| format!("{} for {:?}", e, key.name))?;
+            }
+            keys.insert(key.key.clone(), Arc::new(key));
+        }
+        Ok(Self {
+            keys: RwLock::new(keys),
+            orgs: RwLock::new(orgs),
+            oidc: None,
+        })
+    }
+
+    /// Swap in the keys and org quotas of `other`, e.g. the key file read
+    /// again. The OIDC verifier stays. Sessions keep the key they were
+    /// created with; requests use the new table from now on.
+    pub fn replace(&self, other: ApiKeys) {
+        *self.keys.write().unwrap() = other.keys.into_inner().unwrap();
+        *self.orgs.write().unwrap() = other.orgs.into_inner().unwrap();
+    }
+
+    /// Also accept JWTs verified by `oidc`.
+    pub fn with_oidc(mut self, oidc: Oidc) -> Self {
+        self.oidc = Some(Arc::new(oidc));
+        self
+    }
+
+    /// The JWT verifier, if one is configured.
+    pub fn oidc(&self) -> Option<&Arc<Oidc>> {
+        self.oidc.as_ref()
+    }
+
+    /// Quotas configured for an org.
+    pub fn org_quota(&self, org: &str) -> Option<OrgQuota> {
+        self.orgs.read().unwrap().get(org).cloned()
+    }
+
+    /// Every org with quotas configured.
+    pub fn orgs(&self) -> HashMap<String, OrgQuota> {
+        self.orgs.read().unwrap().clone()
+    }
+
+    /// How `org` is shown to operators. Only org IDs named in the config (a
+    /// key's `org_id` or an org's quotas) are shown as is. Anything else may
+    /// be a key that is its own org, including one since removed, so it's
+    /// shown as `key:` and the start of its SHA-256 instead.
+    pub fn org_label(&self, org: &str) -> String {
+        let configured = self.orgs.read().unwrap().contains_key(org)
+            || self
+                .keys
+                .read()
+                .unwrap()
+                .values()
+                .any(|key| key.org_id.as_deref() == Some(org));
+        if configured {
+            org.to_string()
+        } else {
+            format!("key:{}", &hex::encode(Sha256::digest(org.as_bytes()))[..12])
+        }
+    }
+
+    /// Whether authentication is enforced.
+    pub fn is_enabled(&self) -> bool {
+        !self.keys.read().unwrap().is_empty() || self.oidc.is_some()
+    }
+
+    /// Look up a raw key string.
+    pub fn lookup(&self, key: &str) -> Option<Arc<ApiKey>> {
+        self.keys.read().unwrap().get(key).cloned()
+    }
+
+    /// Every configured key.
+    pub fn all(&self) -> Vec<Arc<ApiKey>> {
+        self.keys.read().unwrap().values().cloned().collect()
+    }
+
+    /// Resolve the key presented in request headers.
+    pub fn authenticate(&self, headers: &HeaderMap) -> Option<Arc<ApiKey>> {
+        self.lookup(presented_key(headers)?)
+    }
+}
+
+/// Extract a key from `Authorization: Bearer` or `X-API-Key`.
+pub fn presented_key(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+        .or_else(|| headers.get(API_KEY_HEADER).and_then(|v| v.to_str().ok()))
+        .map(str::trim)
+}
+
+/// Axum middleware: reject requests without a valid key and attach the
+/// resolved [`ApiKey`] as a request extension for handlers.
+pub async fn require_api_key(
+    State(state): State<AppState>,
+    mut req: Request,
+    next: Next,
+) -> Response {
+    if !state.api_keys.is_enabled() {
+        return next.run(req).await;
+    }
+    let key = match state.api_keys.authenticate(req.headers()) {
+        Some(key) => Ok(key),
+        None => match presented_key(req.headers()) {
+            Some(token) => state.lookup_token(token).await,
+            None => Err("Missing or invalid API key".to_string()),
+        },
+    };
+    match key {
+        Ok(key) => {
+            req.extensions_mut().insert(key);
+            next.run(req).await
+        }
+        Err(e) => (StatusCode::UNAUTHORIZED, [(header::WWW_AUTHENTICATE, "Bearer")], e).into_response(),
+    }
+}
+
+/// Axum layer for a single route: refuse callers whose key lacks `scope`.
+/// Runs after [`require_api_key`]; without API keys every scope is granted,
+/// except those of the `/admin` routes, which take the admin token.
+pub async fn require_scope(State(scope): State<Scope>, req: Request, next: Next) -> Response {
+    let Some(key) = req.extensions().get::<Arc<ApiKey>>() else {
+        if scope.is_admin() && req.extensions().get::<crate::admin::AdminTokenCaller>().is_none() {
+            let message = format!("{} needs the admin token or an API key granted it", scope);
+            return (StatusCode::FORBIDDEN, message).into_response();
+        }
+        return next.run(req).await;
+    };
+    if !key.allows(scope) {
+        return (StatusCode::FORBIDDEN, format!("API key lacks scope {}", scope)).into_response();
+    }
+    // Session tokens stay under /sessions, where other sessions are hidden,
+    // and /jobs, which hides other sessions' jobs
+    let outside_sessions = key.session_id.is_some()
+        && !req
+            .extensions()
+            .get::<MatchedPath>()
+            .is_some_and(|p| p.as_str().contains("/sessions") || p.as_str().contains("/jobs/"));
+    if outside_sessions {
+        return (StatusCode::FORBIDDEN, "Session tokens may only access their own session").into_response();
+    }
+    next.run(req).await
+}
+
+/// Axum route layer: hide sessions owned by other orgs, and from session
+/// tokens every session but their own. Requests naming a session
+/// (`/sessions/:id/...`) the caller can't see get the same 404 as a missing
+/// session. Runs after [`require_api_key`].
+pub async fn scope_session(State(state): State<AppState>, req: Request, next: Next) -> Response {
+    let Some(key) = req.extensions().get::<Arc<ApiKey>>().cloned() else {
+        return next.run(req).await;
+    };
+    let names_session = req
+        .extensions()
+        .get::<MatchedPath>()
+        .is_some_and(|p| p.as_str().contains("/sessions/:id"));
+    if !names_session {
+        return next.run(req).await;
+    }
+    let (mut parts, body) = req.into_parts();
+    let id = match RawPathParams::from_request_parts(&mut parts, &state).await {
+        Ok(params) => params
+            .iter()
+            .find(|(name, _)| *name == "id")
+            .map(|(_, value)| value.to_string()),
+        Err(_) => None,
+    };
+    if let Some(id) = id {
+        let foreign = state
+            .sessions
+            .read()
+            .await
+            .get(&id)
+            .is_some_and(|s| !s.visible_to(Some(&key)));
+        if foreign {
+            return (StatusCode::NOT_FOUND, "Session not found").into_response();
+        }
+    }
+    next.run(Request::from_parts(parts, body)).await
+}
@@ -0,0 +1,199 @@
+//! Isolation backends behind the session API.
+//!
+//! The HTTP and gRPC servers create, run in, and destroy sandboxes through
+//! [`AppState::backend`](crate::AppState::backend) rather than calling
+//! [`crate::sandbox`] directly, so another way of isolating sessions
+//! (bubblewrap, gVisor, a microVM) only has to implement [`SandboxBackend`]
+//! and be added to [`by_name`]. A sandbox is still named by a host path
+//! that the backend hands out from `create` and gets back on every other
+//! call. Features built on the root's mounts (images, checkpoints, egress,
+//! package installs, transactional runs) keep using [`crate::sandbox`] and
+//! need the chroot backend's layout.
+
+use crate::safe_path::WriteAt;
+use crate::sandbox::{self, LiveRun, RunConfig, RunResult, SandboxFileEntry};
+use crate::userns;
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::process::Child;
+use std::sync::Arc;
+
+/// Backend used unless configured otherwise.
+pub const DEFAULT_BACKEND: &str = "chroot";
+
+/// Names [`by_name`] accepts.
+pub const BACKENDS: &[&str] = &["chroot", "userns"];
+
+/// One way of isolating sessions. Every method blocks, so call them from
+/// `spawn_blocking` on async paths.
+pub trait SandboxBackend: Send + Sync {
+    /// Name the backend is selected by.
+    fn name(&self) -> &'static str;
+
+    /// Create a session's sandbox, layered on `template` and built on the
+    /// unpacked OCI image `image` if given. Returns the sandbox root.
+    fn create(&self, session_id: &str, template: Option<&Path>, image: Option<&Path>) -> Result<PathBuf, String>;
+
+    /// Whether `create` can build a sandbox on an OCI image.
+    fn supports_images(&self) -> bool {
+        true
+    }
+
+    /// Create a sandbox for the warm pool, not yet tied to a session.
+    fn create_pooled(&self) -> Result<PathBuf, String>;
+
+    /// Tear down a sandbox and everything in it.
+    fn destroy(&self, sandbox_root: &Path);
+
+    /// Run a command to completion in a session's sandbox, sharing its
+    /// output and a way to stop it through `live` if given.
+    fn run(&self, sandbox_root: &Path, config: &RunConfig, live: Option<&LiveRun>) -> Result<RunResult, String>;
+
+    /// Run a command in a throwaway sandbox.
+    fn run_oneshot(&self, config: &RunConfig) -> Result<RunResult, String>;
+
+    /// Start a command that outlives the call. The caller owns the child
+    /// and must wait on it.
+    fn run_background(&self, sandbox_root: &Path, config: &RunConfig) -> Result<Child, String>;
+
+    fn read_file(&self, sandbox_root: &Path, path: &str) -> Result<Vec<u8>, String>;
+
+    /// Open a regular file for reading, for downloads too large to read
+    /// into memory.
+    fn open_file(&self, sandbox_root: &Path, path: &str) -> Result<File, String>;
+
+    fn write_file(&self, sandbox_root: &Path, path: &str, content: &[u8]) -> Result<(), String>;
+
+    /// Write into a file at `at` instead of replacing it.
+    fn write_file_at(&self, sandbox_root: &Path, path: &str, content: &[u8], at: WriteAt) -> Result<(), String>;
+
+    fn list_files(&self, sandbox_root: &Path, path: &str) -> Result<Vec<SandboxFileEntry>, String>;
+}
+
+/// The backend called `name`, if there is one and it can run here.
+pub fn by_name(name: &str) -> Result<Arc<dyn SandboxBackend>, String> {
+    match name {
+        "chroot" => Ok(Arc::new(ChrootBackend)),
+        // Its commands would be root on the host's files
+        "userns" if nix::unistd::geteuid().is_root() => {
+            Err("userns is for servers that don't run as root; use chroot".to_string())
+        }
+        "userns" => Ok(Arc::new(UsernsBackend)),
+        _ => Err(format!("unknown backend {:?} (available: {})", name, BACKENDS.join(", "))),
+    }
+}
+
+/// Namespaces, an overlay root entered with chroot, and rlimits; see
+/// [`crate::sandbox`].
+pub struct ChrootBackend;
+
+impl SandboxBackend for ChrootBackend {
+    fn name(&self) -> &'static str {
+        "chroot"
+    }
+
+    fn create(&self, session_id: &str, template: Option<&Path>, image: Option<&Path>) -> Result<PathBuf, String> {
+        sandbox::create_session_sandbox(session_id, template, image)
+    }
+
+    fn create_pooled(&self) -> Result<PathBuf, String> {
+        sandbox::create_pooled_sandbox()
+    }
+
+    fn destroy(&self, sandbox_root: &Path) {
+        sandbox::destroy_session_sandbox(sandbox_root)
+    }
+
+    fn run(&self, sandbox_root: &Path, config: &RunConfig, live: Option<&LiveRun>) -> Result<RunResult, String> {
+        sandbox::run_in_session_live(sandbox_root, config, live)
+    }
+
+    fn run_oneshot(&self, config: &RunConfig) -> Result<RunResult, String> {
+        sandbox::run_oneshot(config)
+    }
+
+    fn run_background(&self, sandbox_root: &Path, config: &RunConfig) -> Result<Child, String> {
+        sandbox::run_background_in_session(sandbox_root, config)
+    }
+
+    fn read_file(&self, sandbox_root: &Path, path: &str) -> Result<Vec<u8>, String> {
+        sandbox::read_file_in_sandbox(sandbox_root, path)
+    }
+
+    fn open_file(&self, sandbox_root: &Path, path: &str) -> Result<File, String> {
+        sandbox::open_file_in_sandbox(sandbox_root, path)
+    }
+
+    fn write_file(&self, sandbox_root: &Path, path: &str, content: &[u8]) -> Result<(), String> {
+        sandbox::write_file_in_sandbox(sandbox_root, path, content)
+    }
+
+    fn write_file_at(&self, sandbox_root: &Path, path: &str, content: &[u8], at: WriteAt) -> Result<(), String> {
+        sandbox::write_file_in_sandbox_at(sandbox_root, path, content, at)
+    }
+
+    fn list_files(&self, sandbox_root: &Path, path: &str) -> Result<Vec<SandboxFileEntry>, String> {
+        sandbox::list_files_in_sandbox(sandbox_root, path)
+    }
+}
+
+/// Rootless sessions in user, mount and PID namespaces; see
+/// [`crate::userns`].
+pub struct UsernsBackend;
+
+impl SandboxBackend for UsernsBackend {
+    fn name(&self) -> &'static str {
+        "userns"
+    }
+
+    fn create(&self, session_id: &str, template: Option<&Path>, image: Option<&Path>) -> Result<PathBuf, String> {
+        if image.is_some() {
+            return Err("the userns backend can't build sessions on images".to_string());
+        }
+        userns::create_session_root(session_id, template)
+    }
+
+    fn supports_images(&self) -> bool {
+        false
+    }
+
+    fn create_pooled(&self) -> Result<PathBuf, String> {
+        userns::create_pooled_root()
+    }
+
+    fn destroy(&self, sandbox_root: &Path) {
+        sandbox::destroy_session_sandbox(sandbox_root)
+    }
+
+    fn run(&self, sandbox_root: &Path, config: &RunConfig, live: Option<&LiveRun>) -> Result<RunResult, String> {
+        sandbox::run_in_session_live(sandbox_root, config, live)
+    }
+
+    fn run_oneshot(&self, config: &RunConfig) -> Result<RunResult, String> {
+        userns::run_oneshot(config)
+    }
+
+    fn run_background(&self, sandbox_root: &Path, config: &RunConfig) -> Result<Child, String> {
+        sandbox::run_background_in_session(sandbox_root, config)
+    }
+
+    fn read_file(&self, sandbox_root: &Path, path: &str) -> Result<Vec<u8>, String> {
+        sandbox::read_file_in_sandbox(sandbox_root, path)
+    }
+
+    fn open_file(&self, sandbox_root: &Path, path: &str) -> Result<File, String> {
+        sandbox::open_file_in_sandbox(sandbox_root, path)
+    }
+
+    fn write_file(&self, sandbox_root: &Path, path: &str, content: &[u8]) -> Result<(), String> {
+        sandbox::write_file_in_sandbox(sandbox_root, path, content)
+    }
+
+    fn write_file_at(&self, sandbox_root: &Path, path: &str, content: &[u8], at: WriteAt) -> Result<(), String> {
+        sandbox::write_file_in_sandbox_at(sandbox_root, path, content, at)
+    }
+
+    fn list_files(&self, sandbox_root: &Path, path: &str) -> Result<Vec<SandboxFileEntry>, String> {
+        sandbox::list_files_in_sandbox(sandbox_root, path)
+    }
+}
@@ -0,0 +1,238 @@
+//! Logs of background processes, one per process, rotated by size.
+//!
+//! A background process writes its stdout and stderr straight to
+//! `/tmp/background/{pid}.log` in its sandbox, opened for appending, so the
+//! log outlives server restarts and hibernation like the process does. Every
+//! [`CHECK_INTERVAL`] the server rotates the logs of live processes that have
+//! grown past [`LogRotation::max_bytes`]: what a log holds is compressed into
+//! a segment named after the bytes it covers, `{pid}.{start}-{end}.log.gz`,
+//! and the log is truncated, leaving the process's appends to carry on at its
+//! new end. Output written between the copy and the truncate is lost, as with
+//! logrotate's `copytruncate`. Only the newest [`LogRotation::segments`]
+//! segments are kept.
+//!
+//! Offsets count every byte a process wrote, rotated ones included, so a
+//! reader following a log keeps its place across rotations. Reads from
+//! before the oldest kept segment start at it instead.
+
+use crate::safe_path::Root;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// Where background logs live in the sandbox.
+pub const LOG_DIR: &str = "/tmp/background";
+
+/// How often the logs of live processes are checked for rotation.
+pub const CHECK_INTERVAL: Duration = Duration::from_secs(2);
+
+/// When logs are rotated and how much of them is kept.
+#[derive(Debug, Clone, Copy)]
+pub struct LogRotation {
+    /// Size past which a log is rotated
+    pub max_bytes: u64,
+    /// Rotated segments kept per process, at least 1
+    pub segments: usize,
+}
+
+impl Default for LogRotation {
+    fn default() -> Self {
+        Self {
+            max_bytes: 10 * 1024 * 1024,
+            segments: 5,
+        }
+    }
+}
+
+/// A byte range of a log.
+#[derive(Debug, Clone, Default)]
+pub struct LogChunk {
+    pub data: String,
+    /// Offset `data` starts at, moved up to `start` or down to `size` if
+    /// the one asked for was out of range
+    pub offset: u64,
+    /// Offset to pass on the next read to continue after `data`
+    pub next_offset: u64,
+    /// Oldest offset still kept
+    pub start: u64,
+    /// Bytes the process has written, rotated ones included
+    pub size: u64,
+}
+
+/// A rotated part of a log.
+struct Segment {
+    name: String,
+    start: u64,
+    end: u64,
+}
+
+/// Background logs of every session.
+#[derive(Default)]
+pub struct BackgroundLogs {
+    rotation: LogRotation,
+    /// Held while a log is rotated or read, so a read never sees the new
+    /// segment without the truncate or the other way round
+    lock: Mutex<()>,
+}
+
+impl BackgroundLogs {
+    pub fn new(rotation: LogRotation) -> Self {
+        Self {
+            rotation,
+            lock: Mutex::new(()),
+        }
+    }
+
+    pub fn rotation(&self) -> LogRotation {
+        self.rotation
+    }
+
+    /// Rotate process `pid`'s log if it has grown too big. Returns whether
+    /// it was.
+    pub fn rotate(&self, sandbox_root: &Path, pid: u32) -> Result<bool, String> {
+        let root = Root::open(sandbox_root)?;
+        let log = log_path(pid);
+        if root.metadata(&log).map_or(true, |entry| entry.size <= self.rotation.max_bytes) {
+            return Ok(false);
+        }
+        let _held = self.lock.lock().unwrap_or_else(|e| e.into_inner());
+        // Truncated right after the copy, to lose as little as possible
+        let content = root.read(&log)?;
+        root.open_append(&log, None)?
+            .set_len(0)
+            .map_err(|e| format!("truncate log: {}", e))?;
+        let mut segments = segments(&root, pid)?;
+        let start = segments.last().map_or(0, |segment| segment.end);
+        let end = start + content.len() as u64;
+
+        let mut gz = GzEncoder::new(Vec::new(), Compression::default());
+        gz.write_all(&content).map_err(|e| format!("compress log: {}", e))?;
+        let gz = gz.finish().map_err(|e| format!("compress log: {}", e))?;
+        let name = format!("{}.{}-{}.log.gz", pid, start, end);
+        root.write(&Path::new(LOG_DIR).join(&name), &gz, None)?;
+
+        segments.push(Segment { name, start, end });
+        let excess = segments.len().saturating_sub(self.rotation.segments.max(1));
+        for segment in &segments[..excess] {
+            root.remove_all(&Path::new(LOG_DIR).join(&segment.name))?;
+        }
+        Ok(true)
+    }
+
+    /// Read process `pid`'s log from `offset`, at most `limit` bytes of it,
+    /// across segments and the live log. A UTF-8 character split by the
+    /// limit is left for the next read.
+    pub fn read(&self, sandbox_root: &Path, pid: u32, offset: u64, limit: Option<u64>) -> Result<LogChunk, String> {
+        let root = Root::open(sandbox_root)?;
+        let _held = self.lock.lock().unwrap_or_else(|e| e.into_inner());
+        let segments = segments(&root, pid)?;
+        let live_start = segments.last().map_or(0, |segment| segment.end);
+        let live_size = match root.metadata(&log_path(pid)) {
+            Ok(entry) => entry.size,
+            Err(_) if !segments.is_empty() => 0,
+            Err(e) => return Err(format!("read log: {}", e)),
+        };
+        let start = segments.first().map_or(live_start, |segment| segment.start);
+        let size = live_start + live_size;
+        let offset = offset.clamp(start, size);
+        let mut remaining = limit.unwrap_or(u64::MAX).min(size - offset);
+
+        let mut buf = Vec::new();
+        let mut at = offset;
+        for segment in segments.iter().filter(|segment| segment.end > offset) {
+            if remaining == 0 {
+                break;
+            }
+            let mut content = Vec::new();
+            GzDecoder::new(root.open_file(&Path::new(LOG_DIR).join(&segment.name))?)
+                .read_to_end(&mut content)
+                .map_err(|e| format!("read log: {}", e))?;
+            let from = (at.saturating_sub(segment.start) as usize).min(content.len());
+            let take = remaining.min((content.len() - from) as u64) as usize;
+            buf.extend_from_slice(&content[from..from + take]);
+            remaining -= take as u64;
+            at = segment.end;
+        }
+        if remaining > 0 && live_size > 0 {
+            let mut file = root.open_file(&log_path(pid))?;
+            file.seek(SeekFrom::Start(at - live_start)).map_err(|e| format!("read log: {}", e))?;
+            file.take(remaining)
+                .read_to_end(&mut buf)
+                .map_err(|e| format!("read log: {}", e))?;
+        }
+
+        if let Err(e) = std::str::from_utf8(&buf) {
+            // Don't stall on a limit smaller than one character
+            if e.error_len().is_none() && e.valid_up_to() > 0 {
+                buf.truncate(e.valid_up_to());
+            }
+        }
+        Ok(LogChunk {
+            data: String::from_utf8_lossy(&buf).into_owned(),
+            offset,
+            next_offset: offset + buf.len() as u64,
+            start,
+            size,
+        })
+    }
+}
+
+/// Open a log for a process about to start, under a temporary name until
+/// [`name_after`] gives it the process's.
+pub fn create(sandbox_root: &Path) -> Result<(File, PathBuf), String> {
+    let path = Path::new(LOG_DIR).join(format!("starting-{}.log", uuid::Uuid::new_v4()));
+    let file = Root::open(sandbox_root)?
+        .open_append(&path, None)
+        .map_err(|e| format!("open background log {}: {}", path.display(), e))?;
+    Ok((file, path))
+}
+
+/// Name the log made by [`create`] after the process writing to it,
+/// dropping what an earlier process with the same PID left. Returns its
+/// path in the sandbox.
+pub fn name_after(sandbox_root: &Path, path: &Path, pid: u32) -> Result<PathBuf, String> {
+    let root = Root::open(sandbox_root)?;
+    let log = log_path(pid);
+    for segment in segments(&root, pid)? {
+        root.remove_all(&Path::new(LOG_DIR).join(segment.name))?;
+    }
+    if root.symlink_metadata(&log).is_ok() {
+        root.remove_all(&log)?;
+    }
+    root.rename(path, &log)?;
+    Ok(log)
+}
+
+/// Path of process `pid`'s live log in the sandbox.
+pub fn log_path(pid: u32) -> PathBuf {
+    Path::new(LOG_DIR).join(format!("{}.log", pid))
+}
+
+/// Rotated segments of process `pid`'s log, oldest first.
+fn segments(root: &Root, pid: u32) -> Result<Vec<Segment>, String> {
+    let entries = match root.list(Path::new(LOG_DIR)) {
+        Ok(entries) => entries,
+        Err(_) => return Ok(Vec::new()),
+    };
+    let prefix = format!("{}.", pid);
+    let mut segments: Vec<Segment> = entries
+        .into_iter()
+        .filter_map(|entry| {
+            let range = entry.name.strip_prefix(&prefix)?.strip_suffix(".log.gz")?;
+            let (start, end) = range.split_once('-')?;
+            let (start, end) = (start.parse().ok()?, end.parse().ok()?);
+            (start <= end).then_some(Segment {
+                name: entry.name,
+                start,
+                end,
+            })
+        })
+        .collect();
+    segments.sort_by_key(|segment| segment.start);
+    Ok(segments)
+}
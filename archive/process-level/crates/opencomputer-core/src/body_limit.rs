@@ -0,0 +1,151 @@
+//! Request body limits, per kind of route.
+//!
+//! JSON control calls (creating sessions, runs, env) have small bodies; file
+//! writes carry file contents; template, replay and `/run-preview` uploads
+//! carry whole tarballs. Each kind has its own limit. A request whose
+//! `Content-Length` is over it gets `413` before anything is read, and a
+//! chunked body is cut off at it, which also ends in `413` from the handler.
+//!
+//! The preview proxy streams bodies to the session's server as they arrive
+//! and only has a limit if [`BodyLimits::preview`] is set.
+
+use axum::{
+    body::Body,
+    extract::{MatchedPath, Request, State},
+    http::{header, HeaderMap, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use http_body_util::{LengthLimitError, Limited};
+use std::fmt;
+
+const MIB: usize = 1024 * 1024;
+
+/// File write routes, sync manifests and upload chunks.
+const FILE_ROUTES: &[&str] = &[
+    "/sessions/:id/files/write",
+    "/sessions/:id/files/write-bulk",
+    "/sessions/:id/files/sync",
+    "/sessions/:id/files/sync/pull",
+    "/sessions/:id/uploads/:upload_id",
+];
+
+/// Tarball upload routes.
+const UPLOAD_ROUTES: &[&str] = &["/run-preview", "/templates/:name", "/replays"];
+
+/// Largest request bodies accepted.
+#[derive(Debug, Clone, Copy)]
+pub struct BodyLimits {
+    /// JSON control calls, and routes not listed under another kind
+    pub control: usize,
+    /// File writes, syncs and resumable upload chunks
+    pub files: usize,
+    /// Template, replay and `/run-preview` tarballs
+    pub uploads: usize,
+    /// Requests proxied to a session's preview (unset = unlimited)
+    pub preview: Option<usize>,
+}
+
+impl Default for BodyLimits {
+    fn default() -> Self {
+        Self {
+            control: 2 * MIB,
+            files: 64 * MIB,
+            uploads: 1024 * MIB,
+            preview: None,
+        }
+    }
+}
+
+/// Which of [`BodyLimits`] a route falls under.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RouteKind {
+    Control,
+    Files,
+    Uploads,
+}
+
+impl fmt::Display for RouteKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            RouteKind::Control => "API calls",
+            RouteKind::Files => "file writes",
+            RouteKind::Uploads => "uploads",
+        })
+    }
+}
+
+impl RouteKind {
+    /// Kind of the route matched as `path`, which may sit under a base path.
+    pub fn of(path: &str) -> Self {
+        if FILE_ROUTES.iter().any(|route| path.ends_with(route)) {
+            RouteKind::Files
+        } else if UPLOAD_ROUTES.iter().any(|route| path.ends_with(route)) {
+            RouteKind::Uploads
+        } else {
+            RouteKind::Control
+        }
+    }
+}
+
+impl BodyLimits {
+    pub fn for_kind(&self, kind: RouteKind) -> usize {
+        match kind {
+            RouteKind::Control => self.control,
+            RouteKind::Files => self.files,
+            RouteKind::Uploads => self.uploads,
+        }
+    }
+}
+
+/// `413` for a request that says it's longer than `limit`, if it does.
+pub fn check_length(headers: &HeaderMap, limit: usize, what: impl fmt::Display) -> Option<Response> {
+    let length = headers
+        .get(header::CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<u64>().ok());
+    match length {
+        Some(length) if length > limit as u64 => Some(too_large(limit, what)),
+        _ => None,
+    }
+}
+
+/// Cut `req`'s body off after `limit` bytes.
+pub fn limit_body(req: Request, limit: usize) -> Request {
+    req.map(|body| Body::new(Limited::new(body, limit)))
+}
+
+/// Whether reading a body failed because [`limit_body`] cut it off.
+pub fn is_too_large(e: &axum::Error) -> bool {
+    let mut source: Option<&(dyn std::error::Error + 'static)> = Some(e);
+    while let Some(e) = source {
+        if e.is::<LengthLimitError>() {
+            return true;
+        }
+        source = e.source();
+    }
+    false
+}
+
+fn too_large(limit: usize, what: impl fmt::Display) -> Response {
+    (
+        StatusCode::PAYLOAD_TOO_LARGE,
+        format!("Request body is over the {} byte limit for {}", limit, what),
+    )
+        .into_response()
+}
+
+/// Axum route layer for the API: hold each request to its route's limit.
+/// Axum's own default limit must be off (`DefaultBodyLimit::disable`) so it
+/// doesn't also apply.
+pub async fn enforce(State(limits): State<BodyLimits>, req: Request, next: Next) -> Response {
+    let kind = req
+        .extensions()
+        .get::<MatchedPath>()
+        .map_or(RouteKind::Control, |path| RouteKind::of(path.as_str()));
+    let limit = limits.for_kind(kind);
+    if let Some(response) = check_length(req.headers(), limit, kind) {
+        return response;
+    }
+    next.run(limit_body(req, limit)).await
+}
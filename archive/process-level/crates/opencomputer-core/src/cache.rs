@@ -0,0 +1,344 @@
+//! Build outputs shared across sessions: `node_modules`, Python venvs and
+//! cargo `target` dirs, keyed on a hash of the lockfile they were built from.
+//!
+//! `POST /sessions/:id/build-cache/save` copies a session's directory to
+//! `{dir}/{org}/{kind}/{key}/files` after an install or build, and `restore`
+//! brings it back in a later session with the same lockfile. The copy is
+//! made inside the sandbox, by the session's user, into a host directory
+//! mounted there for the purpose, so it reads nothing the session couldn't.
+//! A restore copies nothing: the entry is mounted as an overlay whose writes
+//! land on the session's layers tmpfs, so it's there at once however large,
+//! and sessions sharing an entry never see each other's changes.
+//!
+//! Entries are kept per org, since each holds code the next session runs.
+//! After every save, the least recently used entries that no live session
+//! has mounted are removed until the cache fits its size limit.
+
+use crate::backend::SandboxBackend;
+use crate::safe_path;
+use crate::sandbox::{self, RunConfig};
+use axum::http::StatusCode;
+use nix::mount::{mount, umount2, MntFlags, MsFlags};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fs;
+use std::os::fd::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Instant, SystemTime};
+
+pub use opencomputer_types::{BuildCacheKind, BuildCacheRequest, BuildCacheResult};
+
+pub const DEFAULT_BUILD_CACHE_DIR: &str = "/var/cache/opensandbox/build";
+
+/// Size limit unless configured otherwise.
+pub const DEFAULT_MAX_MB: u64 = 10 * 1024;
+
+/// Where a save's staging dir is mounted inside the sandbox, under the root.
+const SANDBOX_STAGING_DIR: &str = ".opensandbox/build-cache";
+
+/// Longest a save's copy may take; a cargo `target` dir can be large.
+const SAVE_TIME_MS: u64 = 600_000;
+
+/// Largest file a save may copy.
+const SAVE_FSIZE_KB: u64 = 8 * 1024 * 1024;
+
+/// An entry's size in bytes, written next to its files.
+const SIZE_FILE: &str = "size";
+
+/// Directory each kind caches, and its lockfiles in order of preference.
+fn layout(kind: BuildCacheKind) -> (&'static str, &'static [&'static str]) {
+    match kind {
+        BuildCacheKind::NodeModules => ("node_modules", &["package-lock.json", "yarn.lock", "pnpm-lock.yaml"]),
+        BuildCacheKind::Venv => (".venv", &["poetry.lock", "uv.lock", "Pipfile.lock", "requirements.txt"]),
+        BuildCacheKind::CargoTarget => ("target", &["Cargo.lock"]),
+    }
+}
+
+pub struct BuildCache {
+    dir: PathBuf,
+    max_bytes: u64,
+    /// Entries mounted in live sessions, by session; these are never evicted.
+    /// Also held while entries are added or removed.
+    mounted: Mutex<HashMap<String, Vec<PathBuf>>>,
+}
+
+/// Lockfile, key and the cached directory's path for a request.
+struct Target {
+    lockfile: String,
+    key: String,
+    path: PathBuf,
+}
+
+impl BuildCache {
+    pub fn new(dir: impl Into<PathBuf>, max_mb: u64) -> Self {
+        Self {
+            dir: dir.into(),
+            max_bytes: max_mb * 1024 * 1024,
+            mounted: Mutex::default(),
+        }
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Mount the entry for the lockfile in `project` at its directory there,
+    /// if the cache has one. The directory must be missing or empty.
+    pub fn restore(
+        &self,
+        session_id: &str,
+        org: &str,
+        sandbox_root: &Path,
+        kind: BuildCacheKind,
+        project: &str,
+    ) -> Result<BuildCacheResult, (StatusCode, String)> {
+        let started = Instant::now();
+        let root = safe_path::Root::open(sandbox_root).map_err(internal)?;
+        let target = target(&root, kind, project)?;
+        let entry = self.entry(org, kind, &target.key);
+        let mut result = BuildCacheResult {
+            kind,
+            lockfile: target.lockfile,
+            key: target.key,
+            hit: false,
+            path: target.path.display().to_string(),
+            size_bytes: 0,
+            duration_ms: 0,
+        };
+
+        let mut mounted = self.mounted.lock().unwrap_or_else(|e| e.into_inner());
+        if entry.join("files").is_dir() {
+            if root.list(&target.path).is_ok_and(|entries| !entries.is_empty()) {
+                return Err((StatusCode::CONFLICT, format!("{} already exists and isn't empty", result.path)));
+            }
+            root.create_dir_all(&target.path, sandbox::sandbox_owner(sandbox_root)).map_err(internal)?;
+            let dir = root.dir(&target.path).map_err(internal)?;
+            let mountpoint = PathBuf::from(format!("/proc/self/fd/{}", dir.as_raw_fd()));
+            // Stays until the sandbox is destroyed, which takes its scratch
+            // dir along with the layers tmpfs
+            sandbox::mount_scratch_overlay(sandbox_root, &entry.join("files"), &mountpoint).map_err(internal)?;
+            mounted.entry(session_id.to_string()).or_default().push(entry.clone());
+            touch(&entry);
+            result.hit = true;
+            result.size_bytes = entry_size(&entry);
+        }
+        drop(mounted);
+        result.duration_ms = started.elapsed().as_millis() as u64;
+        Ok(result)
+    }
+
+    /// Copy the directory for the lockfile in `project` into the cache,
+    /// unless it already has an entry for it. `config` supplies the env and
+    /// limits of the copy.
+    pub fn save(
+        &self,
+        backend: &dyn SandboxBackend,
+        org: &str,
+        sandbox_root: &Path,
+        config: &RunConfig,
+        kind: BuildCacheKind,
+        project: &str,
+    ) -> Result<BuildCacheResult, (StatusCode, String)> {
+        let started = Instant::now();
+        let root = safe_path::Root::open(sandbox_root).map_err(internal)?;
+        let target = target(&root, kind, project)?;
+        let path = target.path.display().to_string();
+        if root.dir(&target.path).is_err() {
+            return Err((StatusCode::BAD_REQUEST, format!("{} is not a directory", path)));
+        }
+        let entry = self.entry(org, kind, &target.key);
+        let mut result = BuildCacheResult {
+            kind,
+            lockfile: target.lockfile,
+            key: target.key,
+            hit: true,
+            path,
+            size_bytes: 0,
+            duration_ms: 0,
+        };
+
+        if entry.join("files").is_dir() {
+            touch(&entry);
+        } else {
+            let staging = self.dir.join(format!(".staging-{}", uuid::Uuid::new_v4()));
+            let copied = self.copy_out(backend, &root, sandbox_root, config, &target.path, &staging);
+            let added = copied.and_then(|size| {
+                let _adding = self.mounted.lock().unwrap_or_else(|e| e.into_inner());
+                if entry.join("files").is_dir() {
+                    // Another session saved the same key meanwhile
+                    return Ok(false);
+                }
+                fs::write(staging.join(SIZE_FILE), size.to_string()).map_err(|e| format!("write size: {}", e))?;
+                let parent = entry.parent().unwrap_or(&self.dir);
+                fs::create_dir_all(parent).map_err(|e| format!("mkdir {}: {}", parent.display(), e))?;
+                fs::rename(&staging, &entry).map_err(|e| format!("rename into {}: {}", entry.display(), e))?;
+                Ok(true)
+            });
+            let _ = fs::remove_dir_all(&staging);
+            result.hit = !added.map_err(internal)?;
+            self.evict();
+        }
+        result.size_bytes = entry_size(&entry);
+        result.duration_ms = started.elapsed().as_millis() as u64;
+        Ok(result)
+    }
+
+    /// Forget a destroyed session's mounts, so their entries can be evicted.
+    pub fn release(&self, session_id: &str) {
+        self.mounted.lock().unwrap_or_else(|e| e.into_inner()).remove(session_id);
+    }
+
+    /// Copy `path` in the sandbox to `{staging}/files` as the session's user.
+    /// Returns the bytes copied.
+    fn copy_out(
+        &self,
+        backend: &dyn SandboxBackend,
+        root: &safe_path::Root,
+        sandbox_root: &Path,
+        config: &RunConfig,
+        path: &Path,
+        staging: &Path,
+    ) -> Result<u64, String> {
+        let files = staging.join("files");
+        fs::create_dir_all(&files).map_err(|e| format!("mkdir {}: {}", files.display(), e))?;
+        if let Some((uid, gid)) = sandbox::sandbox_owner(sandbox_root) {
+            std::os::unix::fs::chown(&files, Some(uid), Some(gid)).map_err(|e| format!("chown staging: {}", e))?;
+        }
+
+        let inside = Path::new("/").join(SANDBOX_STAGING_DIR);
+        root.create_dir_all(&inside, None)?;
+        let dir = root.dir(&inside)?;
+        mount(
+            Some(&files),
+            format!("/proc/self/fd/{}", dir.as_raw_fd()).as_str(),
+            None::<&str>,
+            MsFlags::MS_BIND,
+            None::<&str>,
+        )
+        .map_err(|e| format!("bind mount staging dir: {}", e))?;
+        let config = RunConfig {
+            command: ["cp", "-a", "--"]
+                .map(String::from)
+                .into_iter()
+                .chain([format!("{}/.", path.display()), inside.display().to_string()])
+                .collect(),
+            cwd: "/".to_string(),
+            time_ms: SAVE_TIME_MS,
+            fsize_kb: SAVE_FSIZE_KB,
+            ..config.clone()
+        };
+        let run = backend.run(sandbox_root, &config, None);
+        // Opened after the mount, so it names the mount rather than what it covers
+        match root.dir(&inside) {
+            Ok(mounted) => {
+                let _ = umount2(format!("/proc/self/fd/{}", mounted.as_raw_fd()).as_str(), MntFlags::MNT_DETACH);
+            }
+            Err(e) => tracing::warn!("Failed to unmount the build cache staging dir: {}", e),
+        }
+        let run = run?;
+        if !run.success() {
+            return Err(format!("copying {} failed: {}", path.display(), run.stderr.trim()));
+        }
+        // Sandbox users make their writes to restored entries through the shared group
+        sandbox::share_with_sandboxes(&files)?;
+        Ok(tree_size(&files))
+    }
+
+    /// `{dir}/{org}/{kind}/{key}`, with the org hashed since it may be an API
+    /// key.
+    fn entry(&self, org: &str, kind: BuildCacheKind, key: &str) -> PathBuf {
+        let org = hex::encode(&Sha256::digest(org.as_bytes())[..8]);
+        self.dir.join(org).join(layout(kind).0.trim_start_matches('.')).join(key)
+    }
+
+    /// Remove the least recently used entries no session has mounted until
+    /// the cache fits its limit.
+    fn evict(&self) {
+        let mounted = self.mounted.lock().unwrap_or_else(|e| e.into_inner());
+        let in_use: Vec<&PathBuf> = mounted.values().flatten().collect();
+        let mut entries: Vec<(SystemTime, u64, PathBuf)> = Vec::new();
+        let children = |dir: &Path| -> Vec<PathBuf> {
+            fs::read_dir(dir)
+                .map(|e| e.flatten().map(|e| e.path()).filter(|p| p.is_dir()).collect())
+                .unwrap_or_default()
+        };
+        for org in children(&self.dir) {
+            if org.file_name().is_some_and(|n| n.to_string_lossy().starts_with(".staging-")) {
+                continue;
+            }
+            for entry in children(&org).iter().flat_map(|kind| children(kind)) {
+                let used = fs::metadata(&entry).and_then(|m| m.modified()).unwrap_or(SystemTime::UNIX_EPOCH);
+                entries.push((used, entry_size(&entry), entry));
+            }
+        }
+        let mut total: u64 = entries.iter().map(|(_, size, _)| size).sum();
+        entries.sort();
+        for (_, size, entry) in entries {
+            if total <= self.max_bytes {
+                break;
+            }
+            if in_use.contains(&&entry) {
+                continue;
+            }
+            match fs::remove_dir_all(&entry) {
+                Ok(()) => total -= size,
+                Err(e) => tracing::warn!("Failed to evict build cache entry {}: {}", entry.display(), e),
+            }
+        }
+    }
+}
+
+/// Find the lockfile for `kind` in `project` and hash it.
+fn target(root: &safe_path::Root, kind: BuildCacheKind, project: &str) -> Result<Target, (StatusCode, String)> {
+    let (dir, lockfiles) = layout(kind);
+    let project = Path::new(project);
+    for lockfile in lockfiles {
+        let Ok(content) = root.read(&project.join(lockfile)) else {
+            continue;
+        };
+        let mut hasher = Sha256::new();
+        for part in [dir.as_bytes(), b"\0", lockfile.as_bytes(), b"\0", &content] {
+            hasher.update(part);
+        }
+        return Ok(Target {
+            lockfile: lockfile.to_string(),
+            key: hex::encode(hasher.finalize()),
+            path: project.join(dir),
+        });
+    }
+    Err((
+        StatusCode::BAD_REQUEST,
+        format!("no lockfile in {} (looked for {})", project.display(), lockfiles.join(", ")),
+    ))
+}
+
+fn entry_size(entry: &Path) -> u64 {
+    fs::read_to_string(entry.join(SIZE_FILE))
+        .ok()
+        .and_then(|s| s.trim().parse().ok())
+        .unwrap_or(0)
+}
+
+/// Bytes in the regular files under `dir`, not following symlinks.
+fn tree_size(dir: &Path) -> u64 {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return 0;
+    };
+    entries
+        .flatten()
+        .filter_map(|e| Some((e.path(), e.metadata().ok()?)))
+        .map(|(path, meta)| if meta.is_dir() { tree_size(&path) } else { meta.len() })
+        .sum()
+}
+
+/// Mark an entry used now, for eviction order.
+fn touch(entry: &Path) {
+    if let Err(e) = fs::File::open(entry).and_then(|f| f.set_modified(SystemTime::now())) {
+        tracing::warn!("Failed to touch build cache entry {}: {}", entry.display(), e);
+    }
+}
+
+fn internal(e: String) -> (StatusCode, String) {
+    (StatusCode::INTERNAL_SERVER_ERROR, e)
+}
@@ -0,0 +1,84 @@
+//! Capacity hints for orchestrators placing sessions across instances.
+//!
+//! `POST /sessions` returns a [`Capacity`] snapshot with the new session and
+//! `GET /capacity` returns one on demand: how many more sessions the caller
+//! may create, how many creates are in flight, how many warm roots are ready,
+//! and how long a create is expected to take right now.
+
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+pub use opencomputer_types::Capacity;
+
+/// Estimate used until the first warm-pool create is measured.
+const DEFAULT_WARM_CREATE_MS: u64 = 20;
+/// Estimate used until the first cold create is measured.
+const DEFAULT_COLD_CREATE_MS: u64 = 250;
+
+/// Weight of the newest sample in the moving averages (percent).
+const EWMA_WEIGHT_PCT: u64 = 20;
+
+/// In-flight creates and moving averages of create latency.
+#[derive(Debug)]
+pub struct CreateStats {
+    pending: AtomicUsize,
+    warm_ms: AtomicU64,
+    cold_ms: AtomicU64,
+}
+
+impl Default for CreateStats {
+    fn default() -> Self {
+        Self {
+            pending: AtomicUsize::new(0),
+            warm_ms: AtomicU64::new(DEFAULT_WARM_CREATE_MS),
+            cold_ms: AtomicU64::new(DEFAULT_COLD_CREATE_MS),
+        }
+    }
+}
+
+impl CreateStats {
+    /// Count a create as in flight until the guard is dropped.
+    pub fn begin(self: &Arc<Self>) -> PendingCreate {
+        self.pending.fetch_add(1, Ordering::Relaxed);
+        PendingCreate(self.clone())
+    }
+
+    pub fn queue_depth(&self) -> usize {
+        self.pending.load(Ordering::Relaxed)
+    }
+
+    /// Fold a successful create's duration into the warm or cold average.
+    pub fn record(&self, warm: bool, elapsed: Duration) {
+        let avg = if warm { &self.warm_ms } else { &self.cold_ms };
+        let sample = elapsed.as_millis() as u64;
+        // Lost updates under contention only skew the estimate slightly
+        let old = avg.load(Ordering::Relaxed);
+        let new = (old * (100 - EWMA_WEIGHT_PCT) + sample * EWMA_WEIGHT_PCT) / 100;
+        avg.store(new, Ordering::Relaxed);
+    }
+
+    /// A create started now gets a warm root if there are more ready than
+    /// creates already waiting for one.
+    pub fn estimate_ms(&self, warm_available: usize, queue_depth: usize) -> u64 {
+        if warm_available > queue_depth {
+            self.warm_ms.load(Ordering::Relaxed)
+        } else {
+            self.cold_ms.load(Ordering::Relaxed)
+        }
+    }
+}
+
+/// Guard returned by [`CreateStats::begin`].
+pub struct PendingCreate(Arc<CreateStats>);
+
+impl Drop for PendingCreate {
+    fn drop(&mut self) {
+        self.0.pending.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Sessions left under an optional limit.
+pub fn remaining(limit: Option<usize>, used: usize) -> Option<usize> {
+    limit.map(|max| max.saturating_sub(used))
+}
@@ -0,0 +1,259 @@
+//! Per-session cgroups for CPU fairness and process limits (`limits` at
+//! session creation, `PATCH /sessions/:id/limits`).
+//!
+//! Without them the kernel shares CPU between processes, so a session
+//! running a parallel build with forty compilers gets forty times the CPU of
+//! one running a dev server. Once enabled, every session gets a cgroup v2
+//! group of its own under a parent the server manages, and every command it
+//! starts (runs, background processes, kernels, SSH) joins that group
+//! before it execs. CPU is then shared between sessions by their
+//! `cpu.weight`, however many processes each runs, and `cpu.max` caps a
+//! session outright. `pids.max` bounds the processes and threads the session
+//! may have, so a fork bomb exhausts its own group instead of the host's PID
+//! space; forks past it fail with `EAGAIN`, and the group's `pids.events`
+//! counts them, which is how runs report `limit_exceeded: "pids"`. All of
+//! them can be changed while the session runs, within the server's
+//! ceilings, so a tenant can't outweigh everyone else or lift a limit an
+//! operator set.
+//!
+//! Needs cgroup v2 and the `cpu` and `pids` controllers available to the
+//! parent: the server creates the parent if it's missing and enables them in
+//! it for the sessions' groups. Processes hibernated and restored with CRIU
+//! are left where CRIU puts them.
+
+use crate::sandbox;
+use std::fs::{self, OpenOptions};
+use std::io::ErrorKind;
+use std::os::fd::{AsRawFd, OwnedFd};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tracing::warn;
+
+pub use opencomputer_types::SessionLimits;
+
+/// `cpu.weight` of sessions that don't set one: an even share.
+pub const DEFAULT_CPU_WEIGHT: u32 = 100;
+
+/// `pids.max` of sessions unless the server sets another default: room for
+/// parallel builds, not for a fork bomb.
+pub const DEFAULT_PIDS_MAX: u64 = 1024;
+
+/// Controllers enabled for the sessions' groups.
+const CONTROLLERS: [&str; 2] = ["cpu", "pids"];
+
+/// Scheduling period `cpu_max` is applied over, in microseconds.
+const CPU_PERIOD_US: u64 = 100_000;
+
+/// How often removing a group is tried while its last processes exit.
+const REMOVE_ATTEMPTS: u32 = 20;
+const REMOVE_RETRY: Duration = Duration::from_millis(50);
+
+/// The parent of the sessions' groups, the limits sessions start with and
+/// the most they may ask for.
+#[derive(Debug)]
+pub struct Cgroups {
+    root: PathBuf,
+    defaults: SessionLimits,
+    max: SessionLimits,
+}
+
+impl Cgroups {
+    /// Manage session groups under `root`, creating it if needed and
+    /// enabling the `cpu` and `pids` controllers for its children. Limits
+    /// `defaults` leaves unset get [`DEFAULT_CPU_WEIGHT`] and
+    /// [`DEFAULT_PIDS_MAX`]; a zero `cpu_max` or `pids_max` there means none.
+    pub fn new(root: impl Into<PathBuf>, defaults: SessionLimits) -> Result<Self, String> {
+        let root = root.into();
+        defaults.validate()?;
+        let parent = root
+            .parent()
+            .ok_or_else(|| format!("{} has no parent cgroup", root.display()))?;
+        if !parent.join("cgroup.controllers").is_file() {
+            return Err(format!("{} is not in a cgroup v2 hierarchy", parent.display()));
+        }
+        fs::create_dir_all(&root).map_err(|e| format!("mkdir {}: {}", root.display(), e))?;
+        if !has_controllers(&root.join("cgroup.controllers")) {
+            // The parent hands controllers down only if asked to
+            enable_controllers(parent)?;
+        }
+        enable_controllers(&root)?;
+        let defaults = SessionLimits {
+            cpu_weight: Some(defaults.cpu_weight.unwrap_or(DEFAULT_CPU_WEIGHT)),
+            pids_max: Some(defaults.pids_max.unwrap_or(DEFAULT_PIDS_MAX)),
+            ..defaults
+        };
+        Ok(Self {
+            root,
+            defaults,
+            max: SessionLimits::default(),
+        })
+    }
+
+    /// Refuse limits above `max.cpu_weight`, and with `max.cpu_max` or
+    /// `max.pids_max` set, limits above it or without one.
+    pub fn with_max(mut self, max: SessionLimits) -> Result<Self, String> {
+        max.validate()?;
+        self.max = max;
+        self.check(&self.resolve(None)).map_err(|e| format!("defaults: {}", e))?;
+        Ok(self)
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// `requested` over the server's defaults: the limits a session runs with.
+    pub fn resolve(&self, requested: Option<SessionLimits>) -> SessionLimits {
+        self.defaults.merge(requested.unwrap_or_default())
+    }
+
+    /// Refuse resolved limits above the server's ceilings.
+    pub fn check(&self, limits: &SessionLimits) -> Result<(), String> {
+        if let (Some(weight), Some(max)) = (limits.cpu_weight, self.max.cpu_weight) {
+            if weight > max {
+                return Err(format!("cpu_weight {} is above this server's maximum of {}", weight, max));
+            }
+        }
+        if let Some(max) = self.max.cpu_max.filter(|&max| max > 0.0) {
+            match limits.cpu_max {
+                Some(cpus) if cpus <= max => {}
+                Some(cpus) => return Err(format!("cpu_max {} is above this server's maximum of {}", cpus, max)),
+                None => return Err(format!("cpu_max must be set, at most {}", max)),
+            }
+        }
+        if let Some(max) = self.max.pids_max.filter(|&max| max > 0) {
+            match limits.pids_max {
+                Some(pids) if pids <= max => {}
+                Some(pids) => return Err(format!("pids_max {} is above this server's maximum of {}", pids, max)),
+                None => return Err(format!("pids_max must be set, at most {}", max)),
+            }
+        }
+        Ok(())
+    }
+
+    /// Give the session at `sandbox_root` its group, with `limits`. Commands
+    /// it starts from now on join the group. Existing groups, e.g. of
+    /// sessions restored after a restart, are reused.
+    pub fn attach(&self, session_id: &str, sandbox_root: &Path, limits: &SessionLimits) -> Result<(), String> {
+        let dir = self.dir(session_id);
+        match fs::create_dir(&dir) {
+            Ok(()) => {}
+            Err(e) if e.kind() == ErrorKind::AlreadyExists => {}
+            Err(e) => return Err(format!("mkdir {}: {}", dir.display(), e)),
+        }
+        let joined = self.apply(session_id, limits).and_then(|()| {
+            let procs = dir.join("cgroup.procs");
+            OpenOptions::new()
+                .write(true)
+                .open(&procs)
+                .map_err(|e| format!("open {}: {}", procs.display(), e))
+        });
+        match joined {
+            Ok(procs) => {
+                let cgroup = SessionCgroup {
+                    procs: OwnedFd::from(procs),
+                    dir,
+                };
+                sandbox::set_cgroup(sandbox_root, Some(cgroup));
+                Ok(())
+            }
+            Err(e) => {
+                let _ = fs::remove_dir(&dir);
+                Err(e)
+            }
+        }
+    }
+
+    /// Change the limits of a session's group; running processes are
+    /// affected at once.
+    pub fn apply(&self, session_id: &str, limits: &SessionLimits) -> Result<(), String> {
+        let dir = self.dir(session_id);
+        let weight = limits.cpu_weight.unwrap_or(DEFAULT_CPU_WEIGHT);
+        write(&dir.join("cpu.weight"), &weight.to_string())?;
+        let max = match limits.cpu_max {
+            Some(cpus) if cpus > 0.0 => {
+                let quota = (cpus * CPU_PERIOD_US as f64) as u64;
+                format!("{} {}", quota.max(1000), CPU_PERIOD_US)
+            }
+            _ => format!("max {}", CPU_PERIOD_US),
+        };
+        write(&dir.join("cpu.max"), &max)?;
+        let pids = limits.pids_max.filter(|&max| max > 0).map(|max| max.to_string());
+        write(&dir.join("pids.max"), pids.as_deref().unwrap_or("max"))
+    }
+
+    /// Kill whatever is left in a session's group and remove it, once the
+    /// session is gone.
+    pub fn detach(&self, session_id: &str, sandbox_root: &Path) {
+        sandbox::set_cgroup(sandbox_root, None);
+        let dir = self.dir(session_id);
+        if !dir.exists() {
+            return;
+        }
+        // Linux 5.14+; older kernels rely on the session's processes having
+        // been killed already
+        let _ = fs::write(dir.join("cgroup.kill"), "1");
+        for _ in 0..REMOVE_ATTEMPTS {
+            match fs::remove_dir(&dir) {
+                Ok(()) => return,
+                // Processes still exiting
+                Err(e) if e.raw_os_error() == Some(libc::EBUSY) => std::thread::sleep(REMOVE_RETRY),
+                Err(e) => {
+                    warn!("Leaving cgroup {}: {}", dir.display(), e);
+                    return;
+                }
+            }
+        }
+        warn!("Leaving cgroup {}: processes still in it", dir.display());
+    }
+
+    fn dir(&self, session_id: &str) -> PathBuf {
+        self.root.join(session_id)
+    }
+}
+
+/// A session's group as the commands joining it see it.
+#[derive(Debug)]
+pub struct SessionCgroup {
+    /// Its `cgroup.procs`, opened for writing
+    procs: OwnedFd,
+    dir: PathBuf,
+}
+
+impl SessionCgroup {
+    /// Move the calling process into the group. Only makes a system call, so
+    /// it's safe between fork and exec.
+    pub(crate) fn join(&self) -> std::io::Result<()> {
+        // "0" is the writer itself
+        let written = unsafe { libc::write(self.procs.as_raw_fd(), b"0".as_ptr().cast(), 1) };
+        if written < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    /// Forks in the group that failed at `pids.max` so far.
+    pub fn pids_max_hits(&self) -> u64 {
+        fs::read_to_string(self.dir.join("pids.events"))
+            .ok()
+            .and_then(|events| {
+                let line = events.lines().find(|l| l.starts_with("max "))?;
+                line["max ".len()..].trim().parse().ok()
+            })
+            .unwrap_or(0)
+    }
+}
+
+fn has_controllers(controllers: &Path) -> bool {
+    fs::read_to_string(controllers)
+        .is_ok_and(|list| CONTROLLERS.iter().all(|name| list.split_whitespace().any(|c| c == *name)))
+}
+
+fn enable_controllers(group: &Path) -> Result<(), String> {
+    let enable: Vec<String> = CONTROLLERS.iter().map(|name| format!("+{}", name)).collect();
+    write(&group.join("cgroup.subtree_control"), &enable.join(" "))
+}
+
+fn write(path: &Path, value: &str) -> Result<(), String> {
+    fs::write(path, value).map_err(|e| format!("write {}: {}", path.display(), e))
+}
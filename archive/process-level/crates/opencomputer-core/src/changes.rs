@@ -0,0 +1,179 @@
+//! Files a session changed since it was created, for
+//! `GET /sessions/:id/changes`.
+//!
+//! Once a session's sandbox is built, its tree (template and image files
+//! included, the system mounts not) is recorded as a baseline of kinds,
+//! modes, sizes, mtimes and symlink targets. Changes are whatever differs
+//! from it, judged as git's index does: a file with the same size, mtime and
+//! mode wasn't modified. Nothing is hashed at creation, so a large template
+//! only costs a metadata walk; `hash=true` hashes just the changed files.
+
+use crate::file_diff::{self, below, normalize};
+use crate::safe_path::{Entry, Root};
+use axum::http::StatusCode;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+pub use opencomputer_types::{ChangeKind, ChangedFile, DiffSummary, SessionChanges, SessionChangesQuery};
+
+pub const CHANGES_DIR: &str = "/tmp/opensandbox-changes";
+
+/// Sessions with more entries than this aren't tracked.
+pub const MAX_TRACKED: usize = 250_000;
+
+/// An entry as it was (or is), keyed by its absolute path in the sandbox.
+#[derive(Serialize, Deserialize)]
+struct Recorded {
+    kind: String,
+    mode: u32,
+    size: u64,
+    mtime_ns: i64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    target: Option<String>,
+}
+
+impl Recorded {
+    fn new(entry: &Entry, target: Option<String>) -> Self {
+        let kind = file_diff::kind(entry);
+        Self {
+            kind: kind.to_string(),
+            mode: entry.mode & 0o7777,
+            size: if kind == "file" { entry.size } else { 0 },
+            mtime_ns: entry.mtime_ns,
+            target,
+        }
+    }
+}
+
+/// Baseline file of a session; the ID must be a UUID so it is a safe name.
+fn baseline_path(session_id: &str) -> Result<PathBuf, String> {
+    uuid::Uuid::parse_str(session_id).map_err(|_| format!("invalid session ID {:?}", session_id))?;
+    Ok(Path::new(CHANGES_DIR).join(format!("{}.json", session_id)))
+}
+
+/// Record the session's tree as it is now.
+pub fn record(sandbox_root: &Path, session_id: &str) -> Result<(), String> {
+    let root = Root::open(sandbox_root)?;
+    let entries = scan(&root, "/").map_err(|(_, e)| e)?;
+    let path = baseline_path(session_id)?;
+    fs::create_dir_all(CHANGES_DIR).map_err(|e| format!("mkdir {}: {}", CHANGES_DIR, e))?;
+    let data = serde_json::to_vec(&entries).map_err(|e| e.to_string())?;
+    // Written aside and renamed, so a crash never leaves half a baseline
+    let partial = path.with_extension("partial");
+    fs::write(&partial, data).map_err(|e| format!("write {}: {}", partial.display(), e))?;
+    fs::rename(&partial, &path).map_err(|e| format!("rename {}: {}", path.display(), e))
+}
+
+/// What differs under `query.path` from the session's baseline.
+pub fn changes(
+    sandbox_root: &Path,
+    session_id: &str,
+    query: &SessionChangesQuery,
+) -> Result<SessionChanges, (StatusCode, String)> {
+    let path = baseline_path(session_id).map_err(|e| (StatusCode::BAD_REQUEST, e))?;
+    let data = match fs::read(&path) {
+        Ok(data) => data,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            return Err((
+                StatusCode::CONFLICT,
+                "The session's files weren't recorded at creation (too many, or created by an older server)"
+                    .to_string(),
+            ))
+        }
+        Err(e) => return Err((StatusCode::INTERNAL_SERVER_ERROR, format!("read {}: {}", path.display(), e))),
+    };
+    let mut baseline: BTreeMap<String, Recorded> = serde_json::from_slice(&data)
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, format!("parse {}: {}", path.display(), e)))?;
+    let base = normalize(query.path.as_deref().unwrap_or("/"));
+    let root = Root::open(sandbox_root).map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e))?;
+    let current = scan(&root, &base)?;
+    // Like the scan, a directory `base` itself isn't an entry
+    baseline.retain(|path, was| match below(&base, path) {
+        Some("") => was.kind != "dir",
+        rest => rest.is_some(),
+    });
+
+    let file_bytes = |entries: &BTreeMap<String, Recorded>| -> u64 {
+        entries.values().filter(|e| e.kind == "file").map(|e| e.size).sum()
+    };
+    let mut summary = DiffSummary {
+        bytes_before: file_bytes(&baseline),
+        bytes_after: file_bytes(&current),
+        ..Default::default()
+    };
+    let mut changes = Vec::new();
+    let paths: BTreeSet<&String> = baseline.keys().chain(current.keys()).collect();
+    for path in paths {
+        let (was, now) = (baseline.get(path), current.get(path));
+        let (change, mode_only) = match (was, now) {
+            (None, Some(_)) => (ChangeKind::Added, false),
+            (Some(_), None) => (ChangeKind::Removed, false),
+            (Some(was), Some(now)) => {
+                let same_content = was.kind == now.kind
+                    && was.target == now.target
+                    // A directory's mtime moves with its entries, which are reported themselves
+                    && (was.kind != "file" || (was.size == now.size && was.mtime_ns == now.mtime_ns));
+                match (same_content, was.mode == now.mode) {
+                    (true, true) => continue,
+                    (true, false) => (ChangeKind::Modified, true),
+                    (false, _) => (ChangeKind::Modified, false),
+                }
+            }
+            (None, None) => continue,
+        };
+        match change {
+            ChangeKind::Added => summary.added += 1,
+            ChangeKind::Removed => summary.removed += 1,
+            ChangeKind::Modified => summary.modified += 1,
+        }
+        let sha256 = match now {
+            Some(now) if query.hash && now.kind == "file" && !mode_only => file_diff::sha256(&root, path),
+            _ => None,
+        };
+        changes.push(ChangedFile {
+            path: path.clone(),
+            change,
+            kind: now.or(was).map(|e| e.kind.clone()).unwrap_or_default(),
+            size: now.filter(|e| e.kind == "file").map(|e| e.size),
+            modified_at: now.map(|e| (e.mtime_ns / 1_000_000_000).max(0) as u64),
+            mode_only,
+            sha256,
+        });
+    }
+    Ok(SessionChanges { summary, changes })
+}
+
+/// Delete the baseline of a session that went away.
+pub fn remove(session_id: &str) {
+    if let Ok(path) = baseline_path(session_id) {
+        let _ = fs::remove_file(path);
+    }
+}
+
+/// Entries under `base`, keyed by absolute path, or `base` alone if it's a
+/// file. A missing `base` has none.
+fn scan(root: &Root, base: &str) -> Result<BTreeMap<String, Recorded>, (StatusCode, String)> {
+    let mut entries = BTreeMap::new();
+    let top = match root.metadata(Path::new(base)) {
+        Ok(top) => top,
+        Err(_) if base != "/" => return Ok(entries),
+        Err(e) => return Err((StatusCode::INTERNAL_SERVER_ERROR, e)),
+    };
+    if !top.is_directory {
+        entries.insert(base.to_string(), Recorded::new(&top, None));
+        return Ok(entries);
+    }
+    file_diff::walk(root, base, "", &mut |_, path, entry, target| {
+        if entries.len() >= MAX_TRACKED {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                format!("More than {} entries under {}", MAX_TRACKED, base),
+            ));
+        }
+        entries.insert(path, Recorded::new(entry, target));
+        Ok(())
+    })?;
+    Ok(entries)
+}
@@ -0,0 +1,151 @@
+//! Fault injection for resilience testing (`chaos` feature).
+//!
+//! Builds with the feature expose `GET`/`PUT /admin/faults`. Once configured,
+//! the server randomly:
+//!
+//! - delays API requests before handling them,
+//! - SIGKILLs a running background process every so often, which clients see
+//!   exactly like a crash (the exit is reported to lifecycle hooks),
+//! - fails file and template writes with `500`.
+//!
+//! Everything is off until a probability is set. Never enable the feature in
+//! production builds.
+
+use crate::state::AppState;
+use axum::extract::{Request, State};
+use axum::http::StatusCode;
+use axum::middleware::Next;
+use axum::response::Response;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::RwLock;
+use std::time::{Duration, Instant};
+use tracing::warn;
+
+pub use opencomputer_types::{FaultConfig, FaultCounts};
+
+/// Check interval for background kills when the config leaves it at 0.
+const DEFAULT_KILL_INTERVAL_SECS: u64 = 10;
+
+/// Current fault settings and how many faults were injected.
+#[derive(Default)]
+pub struct FaultInjector {
+    config: RwLock<FaultConfig>,
+    delays: AtomicU64,
+    background_kills: AtomicU64,
+    storage_failures: AtomicU64,
+}
+
+impl FaultInjector {
+    pub fn config(&self) -> FaultConfig {
+        self.config.read().unwrap().clone()
+    }
+
+    pub fn set_config(&self, config: FaultConfig) -> Result<(), String> {
+        for (name, p) in [
+            ("delay_probability", config.delay_probability),
+            ("kill_background_probability", config.kill_background_probability),
+            ("storage_failure_probability", config.storage_failure_probability),
+        ] {
+            if !(0.0..=1.0).contains(&p) {
+                return Err(format!("{} must be between 0 and 1", name));
+            }
+        }
+        warn!("Fault injection set to {:?}", config);
+        *self.config.write().unwrap() = config;
+        Ok(())
+    }
+
+    pub fn counts(&self) -> FaultCounts {
+        FaultCounts {
+            delays: self.delays.load(Ordering::Relaxed),
+            background_kills: self.background_kills.load(Ordering::Relaxed),
+            storage_failures: self.storage_failures.load(Ordering::Relaxed),
+        }
+    }
+
+    /// How long to hold the current request, if at all.
+    fn delay(&self) -> Option<Duration> {
+        let config = self.config();
+        if !chance(config.delay_probability) {
+            return None;
+        }
+        self.delays.fetch_add(1, Ordering::Relaxed);
+        let ms = (random() * (config.delay_ms_max + 1) as f64) as u64;
+        Some(Duration::from_millis(ms.min(config.delay_ms_max)))
+    }
+
+    /// Fail a storage write, sometimes.
+    pub fn check_storage_write(&self, what: &str) -> Result<(), (StatusCode, String)> {
+        if !chance(self.config().storage_failure_probability) {
+            return Ok(());
+        }
+        self.storage_failures.fetch_add(1, Ordering::Relaxed);
+        warn!("Injected fault: failing {}", what);
+        Err((
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("Injected fault: {} failed", what),
+        ))
+    }
+}
+
+/// Middleware delaying API requests according to the fault config.
+pub async fn inject_delay(State(state): State<AppState>, request: Request, next: Next) -> Response {
+    if let Some(delay) = state.faults.delay() {
+        tokio::time::sleep(delay).await;
+    }
+    next.run(request).await
+}
+
+/// Spawn the task that kills random background processes. Started by
+/// [`crate::http_server::spawn_cleanup_task`].
+pub fn spawn_background_killer(state: AppState) {
+    tokio::spawn(async move {
+        // Poll every second so a new interval takes effect right away
+        let mut last_check = Instant::now();
+        loop {
+            tokio::time::sleep(Duration::from_secs(1)).await;
+            let config = state.faults.config();
+            let interval = match config.kill_interval_secs {
+                0 => DEFAULT_KILL_INTERVAL_SECS,
+                secs => secs,
+            };
+            if last_check.elapsed() < Duration::from_secs(interval) {
+                continue;
+            }
+            last_check = Instant::now();
+            if !chance(config.kill_background_probability) {
+                continue;
+            }
+            let candidates: Vec<(String, u32)> = state
+                .sessions
+                .read()
+                .await
+                .values()
+                .flat_map(|s| s.background_pids.iter().map(move |&pid| (s.id.clone(), pid)))
+                .filter(|&(_, pid)| crate::sandbox::is_process_alive(pid))
+                .collect();
+            if candidates.is_empty() {
+                continue;
+            }
+            let (session_id, pid) = &candidates[(random() * candidates.len() as f64) as usize % candidates.len()];
+            warn!("Injected fault: killing background pid {} of session {}", pid, session_id);
+            let killed = nix::sys::signal::kill(
+                nix::unistd::Pid::from_raw(*pid as i32),
+                nix::sys::signal::Signal::SIGKILL,
+            );
+            if killed.is_ok() {
+                state.faults.background_kills.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    });
+}
+
+fn chance(probability: f64) -> bool {
+    probability > 0.0 && random() < probability
+}
+
+/// Uniform in `[0, 1)`, from the random bits of a v4 UUID.
+fn random() -> f64 {
+    let bits = uuid::Uuid::new_v4().as_u128() as u64 & ((1 << 53) - 1);
+    bits as f64 / (1u64 << 53) as f64
+}
@@ -0,0 +1,319 @@
+//! Checkpoints of a session's files and diffs between them.
+//!
+//! A checkpoint copies the sandbox's own files (not the read-only system
+//! mounts) to `{CHECKPOINT_DIR}/{session}/{checkpoint}/files`, next to a
+//! manifest of sizes, modes and SHA-256 hashes. Diffs compare two manifests,
+//! so they stay cheap however large the tree is; file contents are only read
+//! for the optional text diffs.
+
+use crate::progress::Progress;
+use crate::replay::{sha256_file, ManifestEntry};
+use crate::sandbox;
+use serde::{Deserialize, Serialize};
+use similar::TextDiff;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+
+pub use opencomputer_types::{
+    ChangeKind, CheckpointDiff, CheckpointDiffQuery, CheckpointInfo, DiffSummary, FileChange,
+};
+
+pub const CHECKPOINT_DIR: &str = "/tmp/opensandbox-checkpoints";
+
+/// Most checkpoints a session may keep.
+pub const MAX_CHECKPOINTS: usize = 20;
+
+/// Text diffs are only produced when both versions are at most this large.
+pub const MAX_CONTENT_DIFF_BYTES: u64 = 64 * 1024;
+
+const MANIFEST_JSON: &str = "checkpoint.json";
+pub(crate) const FILES_DIR: &str = "files";
+
+#[derive(Serialize, Deserialize)]
+pub struct Manifest {
+    pub info: CheckpointInfo,
+    pub files: Vec<ManifestEntry>,
+}
+
+fn session_dir(session_id: &str) -> PathBuf {
+    Path::new(CHECKPOINT_DIR).join(session_id)
+}
+
+/// Directory of a checkpoint; the ID must be a UUID so it is a safe file name.
+pub(crate) fn checkpoint_dir(session_id: &str, id: &str) -> Result<PathBuf, String> {
+    uuid::Uuid::parse_str(id).map_err(|_| format!("invalid checkpoint ID {:?}", id))?;
+    Ok(session_dir(session_id).join(id))
+}
+
+/// Copy the session's files into a new checkpoint.
+pub fn create(
+    sandbox_root: &Path,
+    session_id: &str,
+    label: Option<String>,
+    mut progress: Progress,
+) -> Result<CheckpointInfo, String> {
+    let id = uuid::Uuid::new_v4().to_string();
+    let dir = checkpoint_dir(session_id, &id)?;
+    let partial = session_dir(session_id).join(format!(".partial-{}", id));
+
+    progress.begin_stage("copy", None, None);
+    let created = (|| {
+        let files_dir = partial.join(FILES_DIR);
+        fs::create_dir_all(&files_dir).map_err(|e| format!("mkdir {}: {}", files_dir.display(), e))?;
+        let mut files = Vec::new();
+        snapshot_tree(sandbox_root, Path::new(""), &files_dir, &mut files, &mut progress)?;
+        let regular = files.iter().filter(|e| e.kind == "file");
+        let info = CheckpointInfo {
+            id: id.clone(),
+            label,
+            created_at: std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or(0),
+            files: regular.clone().count() as u64,
+            bytes: regular.map(|e| e.size).sum(),
+        };
+        let manifest = Manifest { info: info.clone(), files };
+        let json = serde_json::to_vec(&manifest).map_err(|e| e.to_string())?;
+        fs::write(partial.join(MANIFEST_JSON), json).map_err(|e| format!("write manifest: {}", e))?;
+        fs::rename(&partial, &dir).map_err(|e| format!("save checkpoint: {}", e))?;
+        Ok(info)
+    })();
+    if created.is_err() {
+        let _ = fs::remove_dir_all(&partial);
+    }
+    progress.finish(&created);
+    created
+}
+
+/// A checkpoint's manifest, or `None` if it doesn't exist.
+pub fn load(session_id: &str, id: &str) -> Result<Option<Manifest>, String> {
+    let path = checkpoint_dir(session_id, id)?.join(MANIFEST_JSON);
+    let data = match fs::read(&path) {
+        Ok(data) => data,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(format!("read {}: {}", path.display(), e)),
+    };
+    serde_json::from_slice(&data)
+        .map(Some)
+        .map_err(|e| format!("parse {}: {}", path.display(), e))
+}
+
+/// Checkpoints of a session, oldest first.
+pub fn list(session_id: &str) -> Result<Vec<CheckpointInfo>, String> {
+    let entries = match fs::read_dir(session_dir(session_id)) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(format!("read checkpoints: {}", e)),
+    };
+    let mut infos = Vec::new();
+    for entry in entries.flatten() {
+        let name = entry.file_name().to_string_lossy().to_string();
+        if uuid::Uuid::parse_str(&name).is_err() {
+            continue;
+        }
+        if let Some(manifest) = load(session_id, &name)? {
+            infos.push(manifest.info);
+        }
+    }
+    infos.sort_by(|a, b| a.created_at.cmp(&b.created_at).then_with(|| a.id.cmp(&b.id)));
+    Ok(infos)
+}
+
+/// Delete a checkpoint. Returns false if it didn't exist.
+pub fn delete(session_id: &str, id: &str) -> Result<bool, String> {
+    let dir = checkpoint_dir(session_id, id)?;
+    match fs::remove_dir_all(&dir) {
+        Ok(()) => Ok(true),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(false),
+        Err(e) => Err(format!("delete checkpoint: {}", e)),
+    }
+}
+
+/// Delete every checkpoint of a session that went away.
+pub fn remove_all(session_id: &str) {
+    let _ = fs::remove_dir_all(session_dir(session_id));
+}
+
+/// Compare checkpoint `from` with checkpoint `to`.
+pub fn diff(
+    session_id: &str,
+    from: &Manifest,
+    to: &Manifest,
+    query: &CheckpointDiffQuery,
+) -> Result<CheckpointDiff, String> {
+    let prefix = query
+        .path
+        .as_deref()
+        .map(|p| format!("/{}", p.trim_matches('/')))
+        .filter(|p| p != "/");
+    let within = |path: &str| match prefix {
+        Some(ref prefix) => path == prefix || path.strip_prefix(prefix.as_str()).is_some_and(|r| r.starts_with('/')),
+        None => true,
+    };
+    let index = |manifest: &'_ Manifest| -> BTreeMap<String, ManifestEntry> {
+        manifest
+            .files
+            .iter()
+            .filter(|e| within(&e.path))
+            .map(|e| (e.path.clone(), e.clone()))
+            .collect()
+    };
+    let (before, after) = (index(from), index(to));
+    let files_before = checkpoint_dir(session_id, &from.info.id)?.join(FILES_DIR);
+    let files_after = checkpoint_dir(session_id, &to.info.id)?.join(FILES_DIR);
+
+    let file_bytes = |entries: &BTreeMap<String, ManifestEntry>| -> u64 {
+        entries.values().filter(|e| e.kind == "file").map(|e| e.size).sum()
+    };
+    let mut summary = DiffSummary {
+        bytes_before: file_bytes(&before),
+        bytes_after: file_bytes(&after),
+        ..Default::default()
+    };
+    let mut changes = Vec::new();
+    let paths: BTreeSet<&String> = before.keys().chain(after.keys()).collect();
+    for path in paths {
+        let (old, new) = (before.get(path), after.get(path));
+        let (change, mode_only) = match (old, new) {
+            (None, Some(_)) => (ChangeKind::Added, false),
+            (Some(_), None) => (ChangeKind::Removed, false),
+            (Some(old), Some(new)) => {
+                let same_content = old.kind == new.kind && old.sha256 == new.sha256 && old.target == new.target;
+                match (same_content, old.mode == new.mode) {
+                    (true, true) => continue,
+                    (true, false) => (ChangeKind::Modified, true),
+                    (false, _) => (ChangeKind::Modified, false),
+                }
+            }
+            (None, None) => continue,
+        };
+        match change {
+            ChangeKind::Added => summary.added += 1,
+            ChangeKind::Removed => summary.removed += 1,
+            ChangeKind::Modified => summary.modified += 1,
+        }
+        let size = |e: Option<&ManifestEntry>| e.filter(|e| e.kind == "file").map(|e| e.size);
+        let diff = if query.content && !mode_only {
+            text_diff(path, old.map(|_| &files_before), old, new.map(|_| &files_after), new)
+        } else {
+            None
+        };
+        changes.push(FileChange {
+            path: path.clone(),
+            change,
+            kind: new.or(old).map(|e| e.kind.clone()).unwrap_or_default(),
+            size_before: size(old),
+            size_after: size(new),
+            mode_only,
+            diff,
+        });
+    }
+    Ok(CheckpointDiff {
+        from: from.info.id.clone(),
+        to: to.info.id.clone(),
+        summary,
+        changes,
+    })
+}
+
+/// Unified diff of a small text file; a missing side counts as empty.
+fn text_diff(
+    path: &str,
+    old_root: Option<&PathBuf>,
+    old: Option<&ManifestEntry>,
+    new_root: Option<&PathBuf>,
+    new: Option<&ManifestEntry>,
+) -> Option<String> {
+    let read = |root: Option<&PathBuf>, entry: Option<&ManifestEntry>| -> Option<String> {
+        let (Some(root), Some(entry)) = (root, entry) else {
+            return Some(String::new());
+        };
+        if entry.kind != "file" || entry.size > MAX_CONTENT_DIFF_BYTES {
+            return None;
+        }
+        as_text(fs::read(root.join(path.trim_start_matches('/'))).ok()?)
+    };
+    let (old_text, new_text) = (read(old_root, old)?, read(new_root, new)?);
+    Some(unified_diff(path, &old_text, &new_text))
+}
+
+/// `a{path}`/`b{path}` unified diff with three lines of context.
+pub(crate) fn unified_diff(path: &str, old: &str, new: &str) -> String {
+    TextDiff::from_lines(old, new)
+        .unified_diff()
+        .context_radius(3)
+        .header(&format!("a{}", path), &format!("b{}", path))
+        .to_string()
+}
+
+/// `data` as text, unless it's binary (has a NUL byte or isn't UTF-8).
+pub(crate) fn as_text(data: Vec<u8>) -> Option<String> {
+    if data.contains(&0) {
+        return None;
+    }
+    String::from_utf8(data).ok()
+}
+
+/// Copy a sandbox tree into `dest`, recording each entry.
+fn snapshot_tree(
+    root: &Path,
+    rel: &Path,
+    dest: &Path,
+    manifest: &mut Vec<ManifestEntry>,
+    progress: &mut Progress,
+) -> Result<(), String> {
+    let dir = root.join(rel);
+    let mut entries: Vec<_> = fs::read_dir(&dir)
+        .map_err(|e| format!("read {}: {}", dir.display(), e))?
+        .flatten()
+        .collect();
+    entries.sort_by_key(|e| e.file_name());
+
+    for entry in entries {
+        let name = entry.file_name();
+        if rel.as_os_str().is_empty() && sandbox::is_mounted_top_level(&name.to_string_lossy()) {
+            continue;
+        }
+        let rel_path = rel.join(&name);
+        let full = entry.path();
+        let target_path = dest.join(&rel_path);
+        let meta = fs::symlink_metadata(&full).map_err(|e| format!("stat {}: {}", full.display(), e))?;
+        let mode = meta.permissions().mode() & 0o7777;
+        let file_type = meta.file_type();
+        let (kind, sha256, target) = if file_type.is_dir() {
+            fs::create_dir(&target_path).map_err(|e| format!("mkdir {}: {}", target_path.display(), e))?;
+            ("dir", None, None)
+        } else if file_type.is_symlink() {
+            let target = fs::read_link(&full).map_err(|e| format!("readlink {}: {}", full.display(), e))?;
+            std::os::unix::fs::symlink(&target, &target_path)
+                .map_err(|e| format!("symlink {}: {}", target_path.display(), e))?;
+            ("symlink", None, Some(target.to_string_lossy().to_string()))
+        } else if file_type.is_file() {
+            fs::copy(&full, &target_path).map_err(|e| format!("copy {}: {}", full.display(), e))?;
+            let hash = sha256_file(&target_path).map_err(|e| format!("{}: {}", full.display(), e))?;
+            progress.add_file(meta.len());
+            ("file", Some(hash), None)
+        } else {
+            // Sockets, fifos and devices aren't files an agent changes
+            continue;
+        };
+
+        manifest.push(ManifestEntry {
+            path: format!("/{}", rel_path.display()),
+            kind: kind.to_string(),
+            mode,
+            size: if kind == "file" { meta.len() } else { 0 },
+            sha256,
+            target,
+        });
+        if kind == "dir" {
+            snapshot_tree(root, &rel_path, dest, manifest, progress)?;
+            // Apply the mode after filling the copy, which it may forbid
+            let _ = fs::set_permissions(&target_path, fs::Permissions::from_mode(mode));
+        }
+    }
+    Ok(())
+}
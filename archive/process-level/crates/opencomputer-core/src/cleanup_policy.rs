@@ -0,0 +1,127 @@
+//! Label-driven retention rules evaluated by the cleanup task.
+//!
+//! Rules are loaded from a JSON file:
+//!
+//! ```json
+//! {"rules": [
+//!   {"match": {"keep": "long"}, "ttl_secs": 86400},
+//!   {"match": {"ci": "true"}, "ttl_secs": 60, "max_age_secs": 1800}
+//! ]}
+//! ```
+//!
+//! The first rule whose `match` labels are all present on a session applies.
+//! `ttl_secs` replaces the session's idle TTL; `max_age_secs` removes the
+//! session that long after creation regardless of activity. Sessions no rule
+//! matches keep their own TTL.
+//!
+//! Expiry never archives: an expired session is torn down with its files
+//! like a deleted one, so a short rule such as the `ci` one above is the
+//! aggressive cleanup, with nothing kept.
+
+use crate::state::Session;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+/// Default interval between cleanup sweeps.
+pub const CLEANUP_INTERVAL_SECS: u64 = 60;
+
+/// Shortest sweep interval, however short a rule's limits are.
+const MIN_CLEANUP_INTERVAL_SECS: u64 = 5;
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct CleanupRule {
+    /// Labels a session must carry (exact values) for the rule to apply
+    #[serde(rename = "match")]
+    pub labels: HashMap<String, String>,
+    #[serde(default)]
+    pub ttl_secs: Option<u64>,
+    #[serde(default)]
+    pub max_age_secs: Option<u64>,
+}
+
+impl CleanupRule {
+    fn matches(&self, session: &Session) -> bool {
+        self.labels
+            .iter()
+            .all(|(k, v)| session.labels.get(k) == Some(v))
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct CleanupPolicy {
+    #[serde(default)]
+    pub rules: Vec<CleanupRule>,
+}
+
+impl CleanupPolicy {
+    /// Load rules from a JSON file.
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let data = std::fs::read_to_string(path)
+            .map_err(|e| format!("read cleanup policy {}: {}", path.display(), e))?;
+        let policy: CleanupPolicy = serde_json::from_str(&data)
+            .map_err(|e| format!("parse cleanup policy {}: {}", path.display(), e))?;
+        for (i, rule) in policy.rules.iter().enumerate() {
+            if rule.labels.is_empty() {
+                return Err(format!("cleanup policy {}: rule {} has an empty match", path.display(), i));
+            }
+            if rule.ttl_secs == Some(0) || rule.max_age_secs == Some(0) {
+                return Err(format!("cleanup policy {}: rule {} has a zero limit", path.display(), i));
+            }
+        }
+        Ok(policy)
+    }
+
+    pub fn rule_for(&self, session: &Session) -> Option<&CleanupRule> {
+        self.rules.iter().find(|r| r.matches(session))
+    }
+
+    /// Idle TTL in effect for a session.
+    pub fn idle_ttl(&self, session: &Session) -> Duration {
+        self.rule_for(session)
+            .and_then(|r| r.ttl_secs)
+            .map(Duration::from_secs)
+            .unwrap_or(session.ttl)
+    }
+
+    /// Time until the session is removed, whichever of idle TTL or max age
+    /// comes first.
+    pub fn expires_in(&self, session: &Session, now: Instant) -> Duration {
+        let idle_left = self
+            .idle_ttl(session)
+            .saturating_sub(now.duration_since(session.idle_since().0));
+        let age_left = self
+            .rule_for(session)
+            .and_then(|r| r.max_age_secs)
+            .map(|max| Duration::from_secs(max).saturating_sub(now.duration_since(session.created_at)));
+        match age_left {
+            Some(age_left) => idle_left.min(age_left),
+            None => idle_left,
+        }
+    }
+
+    pub fn is_expired(&self, session: &Session, now: Instant) -> bool {
+        let idle = now.duration_since(session.idle_since().0);
+        if idle > self.idle_ttl(session) {
+            return true;
+        }
+        self.rule_for(session)
+            .and_then(|r| r.max_age_secs)
+            .is_some_and(|max| now.duration_since(session.created_at) > Duration::from_secs(max))
+    }
+
+    /// Sweep often enough that the shortest rule limit is honoured within
+    /// about half its length.
+    pub fn cleanup_interval(&self) -> Duration {
+        let shortest = self
+            .rules
+            .iter()
+            .flat_map(|r| [r.ttl_secs, r.max_age_secs])
+            .flatten()
+            .min()
+            .map(|secs| (secs / 2).max(MIN_CLEANUP_INTERVAL_SECS))
+            .unwrap_or(CLEANUP_INTERVAL_SECS);
+        Duration::from_secs(shortest.min(CLEANUP_INTERVAL_SECS))
+    }
+}
@@ -0,0 +1,215 @@
+//! Per-session disk usage for `GET /sessions/:id/usage`.
+//!
+//! Used space comes from the session's tmpfs and is read on every request.
+//! Which files take it needs a walk of the session's own files (see
+//! [`sandbox::own_files_dir`]), which is kept for [`WALK_TTL`] so operators
+//! polling a busy host don't walk every tree on each call. The walk stays on one filesystem,
+//! counts allocated blocks rather than apparent sizes, counts hard-linked
+//! files once and skips overlay whiteouts. Directories are opened relative to
+//! their parent without following symlinks, so a session swapping a
+//! directory for a symlink mid-walk can't send it outside its files.
+
+use crate::sandbox;
+use nix::dir::Dir;
+use nix::fcntl::{AtFlags, OFlag};
+use nix::sys::stat::{fstat, fstatat, FileStat, Mode, SFlag};
+use std::collections::{HashMap, HashSet};
+use std::os::fd::AsRawFd;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+pub use opencomputer_types::{DirUsage, DiskUsage, DiskUsageQuery};
+
+pub const DEFAULT_TOP: usize = 10;
+pub const MAX_TOP: usize = 100;
+pub const DEFAULT_DEPTH: usize = 3;
+pub const MAX_DEPTH: usize = 16;
+
+/// How long a walk is reused before the next request walks again.
+pub const WALK_TTL: Duration = Duration::from_secs(60);
+
+/// Entries one walk visits before it stops and reports `truncated`.
+const MAX_ENTRIES: u64 = 2_000_000;
+
+/// Deepest directory the walk descends into; each level holds a descriptor.
+const MAX_NESTING: usize = 256;
+
+/// A session's last walk. Held while walking, so concurrent requests wait
+/// for the walk in progress instead of starting their own.
+type WalkSlot = Arc<Mutex<Option<Arc<Walk>>>>;
+
+/// Finished walks by session ID.
+#[derive(Default)]
+pub struct DiskUsageCache {
+    by_session: Mutex<HashMap<String, WalkSlot>>,
+}
+
+/// What one walk of a session's files found.
+struct Walk {
+    at: Instant,
+    took: Duration,
+    files: u64,
+    bytes: u64,
+    /// Every directory down to [`MAX_DEPTH`], with their totals
+    dirs: Vec<(usize, DirUsage)>,
+    truncated: bool,
+}
+
+impl DiskUsageCache {
+    /// Usage of the session at `sandbox_root`, walking its files if there's
+    /// no walk younger than [`WALK_TTL`] or `refresh` is set. Blocks for the
+    /// walk, so call it off the async runtime.
+    pub fn usage(
+        &self,
+        session_id: &str,
+        sandbox_root: &Path,
+        top: usize,
+        depth: usize,
+        refresh: bool,
+    ) -> Result<DiskUsage, String> {
+        let slot = self
+            .by_session
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .entry(session_id.to_string())
+            .or_default()
+            .clone();
+        let walk = {
+            let mut cached = slot.lock().unwrap_or_else(|e| e.into_inner());
+            match cached.as_ref() {
+                Some(walk) if !refresh && walk.at.elapsed() < WALK_TTL => walk.clone(),
+                _ => {
+                    let walk = Arc::new(walk(&sandbox::own_files_dir(sandbox_root))?);
+                    *cached = Some(walk.clone());
+                    walk
+                }
+            }
+        };
+
+        let mut largest_dirs: Vec<DirUsage> =
+            walk.dirs.iter().filter(|(d, _)| *d <= depth).map(|(_, dir)| dir.clone()).collect();
+        largest_dirs.sort_by(|a, b| b.bytes.cmp(&a.bytes).then_with(|| a.path.cmp(&b.path)));
+        largest_dirs.truncate(top);
+        Ok(DiskUsage {
+            disk_bytes: sandbox::disk_usage(sandbox_root),
+            disk_limit_bytes: sandbox::disk_capacity(sandbox_root),
+            files: walk.files,
+            file_bytes: walk.bytes,
+            largest_dirs,
+            truncated: walk.truncated,
+            walked_secs_ago: walk.at.elapsed().as_secs(),
+            walk_ms: walk.took.as_millis() as u64,
+        })
+    }
+
+    /// Drop a session's walk, e.g. once the session is gone.
+    pub fn forget(&self, session_id: &str) {
+        self.by_session.lock().unwrap_or_else(|e| e.into_inner()).remove(session_id);
+    }
+}
+
+fn walk(dir: &Path) -> Result<Walk, String> {
+    let started = Instant::now();
+    let top = Dir::open(dir, OFlag::O_RDONLY | OFlag::O_DIRECTORY | OFlag::O_NOFOLLOW, Mode::empty())
+        .map_err(|e| format!("open {}: {}", dir.display(), e))?;
+    let stat = fstat(top.as_raw_fd()).map_err(|e| format!("stat {}: {}", dir.display(), e))?;
+    let mut walker = Walker {
+        dev: stat.st_dev,
+        seen: HashSet::new(),
+        entries: 0,
+        dirs: Vec::new(),
+        truncated: false,
+    };
+    let (bytes, files) = walker.dir(top, "/", 0);
+    Ok(Walk {
+        at: Instant::now(),
+        took: started.elapsed(),
+        files,
+        bytes: bytes + allocated(&stat),
+        dirs: walker.dirs,
+        truncated: walker.truncated,
+    })
+}
+
+struct Walker {
+    /// Filesystem the walk stays on
+    dev: u64,
+    /// Hard-linked files already counted, by (device, inode)
+    seen: HashSet<(u64, u64)>,
+    entries: u64,
+    dirs: Vec<(usize, DirUsage)>,
+    truncated: bool,
+}
+
+impl Walker {
+    /// Bytes and entries beneath `dir`, which is at sandbox path `path`.
+    /// Directories down to [`MAX_DEPTH`] are recorded with their totals.
+    fn dir(&mut self, mut dir: Dir, path: &str, depth: usize) -> (u64, u64) {
+        let (mut bytes, mut files) = (0, 0);
+        let dir_fd = dir.as_raw_fd();
+        let mut subdirs = Vec::new();
+        for entry in dir.iter() {
+            let Ok(entry) = entry else { break };
+            let name = entry.file_name();
+            if name.to_bytes() == b"." || name.to_bytes() == b".." {
+                continue;
+            }
+            if self.entries >= MAX_ENTRIES {
+                self.truncated = true;
+                break;
+            }
+            // Gone since the listing: nothing to count
+            let Ok(stat) = fstatat(Some(dir_fd), name, AtFlags::AT_SYMLINK_NOFOLLOW) else {
+                continue;
+            };
+            let kind = SFlag::from_bits_truncate(stat.st_mode) & SFlag::S_IFMT;
+            // Whiteouts record deletions from lower layers, not files
+            if kind == SFlag::S_IFCHR && stat.st_rdev == 0 {
+                continue;
+            }
+            // Mountpoints: something else's files, e.g. the host's /usr
+            if stat.st_dev != self.dev {
+                continue;
+            }
+            self.entries += 1;
+            files += 1;
+            if kind == SFlag::S_IFDIR {
+                bytes += allocated(&stat);
+                subdirs.push(name.to_owned());
+            } else if stat.st_nlink <= 1 || self.seen.insert((stat.st_dev, stat.st_ino)) {
+                bytes += allocated(&stat);
+            }
+        }
+
+        for name in subdirs {
+            if depth + 1 >= MAX_NESTING {
+                self.truncated = true;
+                continue;
+            }
+            let flags = OFlag::O_RDONLY | OFlag::O_DIRECTORY | OFlag::O_NOFOLLOW;
+            let Ok(child) = Dir::openat(Some(dir_fd), name.as_c_str(), flags, Mode::empty()) else {
+                continue;
+            };
+            let (b, f) = self.dir(child, &format!("{}{}/", path, name.to_string_lossy()), depth + 1);
+            bytes += b;
+            files += f;
+        }
+
+        if depth <= MAX_DEPTH {
+            let path = if depth == 0 { path } else { path.trim_end_matches('/') };
+            let usage = DirUsage {
+                path: path.to_string(),
+                bytes,
+                files,
+            };
+            self.dirs.push((depth, usage));
+        }
+        (bytes, files)
+    }
+}
+
+/// Space a file takes on disk, rather than its apparent size.
+fn allocated(stat: &FileStat) -> u64 {
+    stat.st_blocks as u64 * 512
+}
@@ -0,0 +1,192 @@
+//! Conditional and range requests for `GET /sessions/:id/files/download`.
+//!
+//! A file's validators come from its metadata alone, so answering
+//! `If-None-Match` or `If-Modified-Since` never reads the file. The ETag
+//! covers inode, size and mtime to the nanosecond, which changes on any write
+//! through the file API or inside the sandbox. Only single byte ranges are
+//! served as `206`; a multi-range request gets the whole file, which RFC 9110
+//! allows and every client handles.
+
+use axum::body::Bytes;
+use axum::http::{header, HeaderMap};
+use chrono::{DateTime, TimeZone, Utc};
+use futures_util::Stream;
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+use tokio::io::AsyncReadExt;
+
+/// Bytes read from the file per body chunk.
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// What identifies one version of a file.
+pub struct Validators {
+    /// Strong entity tag, quotes included.
+    pub etag: String,
+    /// Whole seconds, as HTTP dates carry no more.
+    pub last_modified: DateTime<Utc>,
+}
+
+impl Validators {
+    pub fn of(meta: &std::fs::Metadata) -> Self {
+        Self {
+            etag: format!("\"{:x}-{:x}-{:x}.{:x}\"", meta.ino(), meta.size(), meta.mtime(), meta.mtime_nsec()),
+            last_modified: Utc.timestamp_opt(meta.mtime(), 0).single().unwrap_or_default(),
+        }
+    }
+
+    /// `Last-Modified` as an HTTP date.
+    pub fn last_modified_header(&self) -> String {
+        http_date(&self.last_modified)
+    }
+
+    /// Whether the client's cached copy is still current. `If-None-Match`
+    /// wins over `If-Modified-Since` when both are sent.
+    pub fn not_modified(&self, headers: &HeaderMap) -> bool {
+        if let Some(tags) = header_str(headers, header::IF_NONE_MATCH) {
+            return tags.trim() == "*" || tags.split(',').any(|tag| weak_eq(tag, &self.etag));
+        }
+        header_str(headers, header::IF_MODIFIED_SINCE)
+            .and_then(parse_http_date)
+            .is_some_and(|since| self.last_modified <= since)
+    }
+
+    /// Whether `If-Range` (if sent) names this version, so a range of it
+    /// may be served. Only a strong match counts.
+    fn range_applies(&self, headers: &HeaderMap) -> bool {
+        match header_str(headers, header::IF_RANGE) {
+            None => true,
+            Some(v) if v.trim().starts_with('"') => v.trim() == self.etag,
+            Some(v) => parse_http_date(v).is_some_and(|date| date == self.last_modified),
+        }
+    }
+}
+
+/// What part of the file to send.
+#[derive(Debug, PartialEq, Eq)]
+pub enum ByteRange {
+    /// The whole file: no `Range`, one we ignore, or a stale `If-Range`.
+    Full,
+    /// Bytes `start..=end`.
+    Partial { start: u64, end: u64 },
+    /// The range starts past the end of the file.
+    Unsatisfiable,
+}
+
+/// The range `headers` ask for out of a file of `size` bytes.
+pub fn requested_range(headers: &HeaderMap, validators: &Validators, size: u64) -> ByteRange {
+    let Some(spec) = header_str(headers, header::RANGE) else {
+        return ByteRange::Full;
+    };
+    if !validators.range_applies(headers) {
+        return ByteRange::Full;
+    }
+    let Some(spec) = spec.trim().strip_prefix("bytes=") else {
+        return ByteRange::Full;
+    };
+    if spec.contains(',') {
+        return ByteRange::Full;
+    }
+    let Some((first, last)) = spec.trim().split_once('-') else {
+        return ByteRange::Full;
+    };
+    let (first, last) = (first.trim(), last.trim());
+    match (first.parse::<u64>(), last.parse::<u64>()) {
+        // bytes=-N: the last N bytes
+        (Err(_), Ok(n)) if first.is_empty() => {
+            if n == 0 || size == 0 {
+                ByteRange::Unsatisfiable
+            } else {
+                ByteRange::Partial {
+                    start: size.saturating_sub(n),
+                    end: size - 1,
+                }
+            }
+        }
+        // bytes=N-: from N to the end
+        (Ok(start), Err(_)) if last.is_empty() => within(start, u64::MAX, size),
+        (Ok(start), Ok(end)) if start <= end => within(start, end, size),
+        _ => ByteRange::Full,
+    }
+}
+
+fn within(start: u64, end: u64, size: u64) -> ByteRange {
+    if start >= size {
+        ByteRange::Unsatisfiable
+    } else {
+        ByteRange::Partial {
+            start,
+            end: end.min(size - 1),
+        }
+    }
+}
+
+/// `len` bytes of `file` from its current offset, a chunk at a time.
+pub fn body(file: tokio::fs::File, len: u64) -> impl Stream<Item = std::io::Result<Bytes>> {
+    futures_util::stream::unfold(file.take(len), |mut file| async move {
+        let mut buf = vec![0; CHUNK_SIZE];
+        match file.read(&mut buf).await {
+            Ok(0) => None,
+            Ok(n) => {
+                buf.truncate(n);
+                Some((Ok(Bytes::from(buf)), file))
+            }
+            Err(e) => Some((Err(e), file)),
+        }
+    })
+}
+
+/// `Content-Type` by file extension, for the files a browser previews.
+pub fn content_type(path: &str) -> &'static str {
+    let ext = Path::new(path)
+        .extension()
+        .and_then(|e| e.to_str())
+        .map(str::to_ascii_lowercase)
+        .unwrap_or_default();
+    match ext.as_str() {
+        "html" | "htm" => "text/html; charset=utf-8",
+        "css" => "text/css; charset=utf-8",
+        "js" | "mjs" => "text/javascript; charset=utf-8",
+        "json" => "application/json",
+        "txt" | "log" | "md" => "text/plain; charset=utf-8",
+        "csv" => "text/csv; charset=utf-8",
+        "xml" => "application/xml",
+        "svg" => "image/svg+xml",
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "webp" => "image/webp",
+        "ico" => "image/x-icon",
+        "pdf" => "application/pdf",
+        "mp4" => "video/mp4",
+        "webm" => "video/webm",
+        "mov" => "video/quicktime",
+        "mp3" => "audio/mpeg",
+        "wav" => "audio/wav",
+        "ogg" => "audio/ogg",
+        "wasm" => "application/wasm",
+        "zip" => "application/zip",
+        "gz" | "tgz" => "application/gzip",
+        "tar" => "application/x-tar",
+        _ => "application/octet-stream",
+    }
+}
+
+fn header_str(headers: &HeaderMap, name: header::HeaderName) -> Option<&str> {
+    headers.get(name).and_then(|v| v.to_str().ok())
+}
+
+/// Weak comparison: `W/"x"` matches `"x"`.
+fn weak_eq(tag: &str, etag: &str) -> bool {
+    let tag = tag.trim();
+    tag.strip_prefix("W/").unwrap_or(tag) == etag
+}
+
+fn http_date(date: &DateTime<Utc>) -> String {
+    date.format("%a, %d %b %Y %H:%M:%S GMT").to_string()
+}
+
+/// An IMF-fixdate (`Sun, 06 Nov 1994 08:49:37 GMT`). The obsolete RFC 850 and
+/// asctime forms are ignored, which only costs a full response.
+fn parse_http_date(value: &str) -> Option<DateTime<Utc>> {
+    DateTime::parse_from_rfc2822(value.trim()).ok().map(|d| d.with_timezone(&Utc))
+}
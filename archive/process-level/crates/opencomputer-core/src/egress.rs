@@ -0,0 +1,563 @@
+//! Outbound HTTP(S) through a per-session forward proxy (`egress` at session
+//! creation, `GET` and `PUT /sessions/:id/egress`).
+//!
+//! A session created with an egress policy gets a network namespace of its
+//! own, holding nothing but a loopback interface. Its commands run in that
+//! namespace, where the only way out is the proxy listening on
+//! [`PROXY_PORT`]: the socket is bound inside the namespace, but served by
+//! the server, which makes the outbound connections from the host's. The
+//! proxy takes `CONNECT host:port` (HTTPS and other TLS) and absolute-form
+//! `http://` requests, checks the host against the session's policy, and
+//! keeps the last [`LOG_CAPACITY`] attempts. Hosts resolving to loopback or
+//! to one of the host's own addresses are refused, so a session can't reach
+//! the server's own listeners; link-local (cloud metadata included) and
+//! private addresses are refused unless the allow list names the host
+//! exactly (see [`address_refusal`]). IPv4-mapped IPv6 addresses are checked
+//! as the IPv4 address they stand for.
+
+use crate::sandbox;
+use std::collections::{HashMap, VecDeque};
+use std::fs::File;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::task::JoinHandle;
+use tracing::info;
+
+pub use opencomputer_types::{EgressAttempt, EgressPolicy, EgressReport};
+
+/// Port the proxy listens on inside each session's namespace.
+pub const PROXY_PORT: u16 = 3128;
+
+/// Attempts kept per session.
+pub const LOG_CAPACITY: usize = 256;
+
+/// Longest request head the proxy reads.
+const MAX_HEAD: usize = 64 * 1024;
+
+/// How long a client has to send its request head.
+const HEAD_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How long the proxy waits to connect upstream.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// URL of the proxy as seen from inside a session, for `HTTP_PROXY` and the
+/// like.
+pub fn proxy_url() -> String {
+    format!("http://127.0.0.1:{}", PROXY_PORT)
+}
+
+/// Proxies by session ID.
+#[derive(Default)]
+pub struct Egress {
+    by_session: Mutex<HashMap<String, Running>>,
+}
+
+struct Running {
+    proxy: Arc<Proxy>,
+    task: JoinHandle<()>,
+}
+
+struct Proxy {
+    session_id: String,
+    policy: Mutex<EgressPolicy>,
+    attempts: Mutex<VecDeque<EgressAttempt>>,
+}
+
+impl Egress {
+    /// Give the session at `sandbox_root` a network namespace of its own and
+    /// start its proxy. Commands started afterwards run in the namespace.
+    /// Must be called within the Tokio runtime.
+    pub fn enable(&self, session_id: &str, sandbox_root: &Path, policy: EgressPolicy) -> Result<(), String> {
+        // unshare moves the calling thread, so do it on one of our own
+        let (listener, ns) = std::thread::spawn(isolated_listener)
+            .join()
+            .map_err(|_| "egress setup thread panicked".to_string())??;
+        let listener = TcpListener::from_std(listener).map_err(|e| format!("egress listener: {}", e))?;
+        let proxy = Arc::new(Proxy {
+            session_id: session_id.to_string(),
+            policy: Mutex::new(policy),
+            attempts: Mutex::default(),
+        });
+        let task = tokio::spawn(serve(listener, proxy.clone()));
+        sandbox::set_network_namespace(sandbox_root, Some(ns));
+        let previous = self.lock().insert(session_id.to_string(), Running { proxy, task });
+        if let Some(previous) = previous {
+            previous.task.abort();
+        }
+        Ok(())
+    }
+
+    /// Replace a session's policy. Connections already open stay open. False
+    /// if the session has no proxy.
+    pub fn set_policy(&self, session_id: &str, policy: EgressPolicy) -> bool {
+        match self.lock().get(session_id) {
+            Some(running) => {
+                *running.proxy.policy.lock().unwrap_or_else(|e| e.into_inner()) = policy;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// A session's policy and recent attempts; `None` if it has no proxy.
+    pub fn report(&self, session_id: &str) -> Option<EgressReport> {
+        let by_session = self.lock();
+        let proxy = &by_session.get(session_id)?.proxy;
+        let policy = proxy.policy.lock().unwrap_or_else(|e| e.into_inner()).clone();
+        let attempts = proxy.attempts.lock().unwrap_or_else(|e| e.into_inner()).iter().cloned().collect();
+        Some(EgressReport { policy, attempts })
+    }
+
+    /// A session's attempts made at or after `since_ms` (Unix milliseconds)
+    /// that are still in its log.
+    pub fn attempts_since(&self, session_id: &str, since_ms: u64) -> Vec<EgressAttempt> {
+        let by_session = self.lock();
+        let Some(running) = by_session.get(session_id) else {
+            return Vec::new();
+        };
+        let attempts = running.proxy.attempts.lock().unwrap_or_else(|e| e.into_inner());
+        attempts.iter().filter(|a| a.at_ms >= since_ms).cloned().collect()
+    }
+
+    /// Stop a session's proxy once the session is gone.
+    pub fn disable(&self, session_id: &str, sandbox_root: &Path) {
+        if let Some(running) = self.lock().remove(session_id) {
+            running.task.abort();
+            sandbox::set_network_namespace(sandbox_root, None);
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<String, Running>> {
+        self.by_session.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// Check a policy's entries are domain names, optionally `*.`-prefixed.
+pub fn validate(policy: &EgressPolicy) -> Result<(), String> {
+    for entry in policy.allow.iter().chain(&policy.deny) {
+        let domain = entry.strip_prefix("*.").unwrap_or(entry);
+        let valid = !domain.is_empty()
+            && domain.len() <= 253
+            && domain.split('.').all(|label| {
+                !label.is_empty()
+                    && label.len() <= 63
+                    && label.bytes().all(|b| b.is_ascii_lowercase() || b.is_ascii_digit() || b == b'-')
+            });
+        if !valid {
+            return Err(format!(
+                "Invalid egress domain '{}': expected a lowercase domain name, optionally starting with '*.'",
+                entry
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Why `policy` refuses `host`, if it does. Denials win over the allow list.
+pub fn refusal(policy: &EgressPolicy, host: &str) -> Option<&'static str> {
+    if policy.deny.iter().any(|entry| matches(entry, host)) {
+        Some("denied by policy")
+    } else if !policy.allow.is_empty() && !policy.allow.iter().any(|entry| matches(entry, host)) {
+        Some("not in the allow list")
+    } else {
+        None
+    }
+}
+
+/// Whether the allow list names `host` itself, not through a wildcard, which
+/// lets it resolve to link-local and private addresses.
+pub fn explicitly_allows(policy: &EgressPolicy, host: &str) -> bool {
+    policy.allow.iter().any(|entry| entry == host)
+}
+
+/// Why a connection to `ip` is refused, if it is. Loopback and the host's
+/// own addresses always are, as they lead to the server's listeners and
+/// other sessions' ports. Link-local and private addresses are unless
+/// `named` (see [`explicitly_allows`]).
+pub fn address_refusal(ip: IpAddr, named: bool) -> Option<&'static str> {
+    let ip = ip.to_canonical();
+    if ip.is_loopback() || ip.is_unspecified() {
+        return Some("resolves to a loopback address");
+    }
+    if host_addresses().contains(&ip) {
+        return Some("resolves to one of the server's addresses");
+    }
+    if named {
+        return None;
+    }
+    match ip {
+        IpAddr::V4(v4) if v4.is_link_local() => Some("resolves to a link-local address"),
+        // 100.64.0.0/10 is carrier-grade NAT, private in practice
+        IpAddr::V4(v4) if v4.is_private() || (v4.octets()[0] == 100 && v4.octets()[1] & 0xc0 == 64) => {
+            Some("resolves to a private address")
+        }
+        IpAddr::V6(v6) if v6.segments()[0] & 0xffc0 == 0xfe80 => Some("resolves to a link-local address"),
+        IpAddr::V6(v6) if v6.segments()[0] & 0xfe00 == 0xfc00 => Some("resolves to a private address"),
+        _ => None,
+    }
+}
+
+/// Addresses of the host's own interfaces; empty if they can't be listed.
+fn host_addresses() -> Vec<IpAddr> {
+    let mut list: *mut libc::ifaddrs = std::ptr::null_mut();
+    // SAFETY: getifaddrs fills `list`, which is freed below
+    if unsafe { libc::getifaddrs(&mut list) } != 0 {
+        return Vec::new();
+    }
+    let mut addrs = Vec::new();
+    let mut next = list;
+    while !next.is_null() {
+        // SAFETY: entries of the list stay valid until freeifaddrs, and
+        // `ifa_addr` points to a sockaddr of the family it names
+        unsafe {
+            let entry = &*next;
+            if !entry.ifa_addr.is_null() {
+                match (*entry.ifa_addr).sa_family as i32 {
+                    libc::AF_INET => {
+                        let addr = &*(entry.ifa_addr as *const libc::sockaddr_in);
+                        addrs.push(IpAddr::V4(Ipv4Addr::from(u32::from_be(addr.sin_addr.s_addr))));
+                    }
+                    libc::AF_INET6 => {
+                        let addr = &*(entry.ifa_addr as *const libc::sockaddr_in6);
+                        addrs.push(IpAddr::V6(Ipv6Addr::from(addr.sin6_addr.s6_addr)));
+                    }
+                    _ => {}
+                }
+            }
+            next = entry.ifa_next;
+        }
+    }
+    // SAFETY: `list` came from getifaddrs and isn't used after this
+    unsafe { libc::freeifaddrs(list) };
+    addrs
+}
+
+/// Whether a policy entry covers `host`; `*.example.com` covers subdomains
+/// of `example.com` but not `example.com` itself.
+fn matches(entry: &str, host: &str) -> bool {
+    match entry.strip_prefix("*.") {
+        Some(domain) => host
+            .strip_suffix(domain)
+            .is_some_and(|sub| sub.len() > 1 && sub.ends_with('.')),
+        None => host == entry,
+    }
+}
+
+/// A listener on [`PROXY_PORT`] in a new network namespace with loopback
+/// up, and the namespace. Moves the calling thread into the namespace.
+fn isolated_listener() -> Result<(std::net::TcpListener, OwnedFd), String> {
+    nix::sched::unshare(nix::sched::CloneFlags::CLONE_NEWNET).map_err(|e| format!("unshare net: {}", e))?;
+    loopback_up().map_err(|e| format!("bring up loopback: {}", e))?;
+    let listener = std::net::TcpListener::bind((Ipv4Addr::LOCALHOST, PROXY_PORT))
+        .map_err(|e| format!("bind egress proxy: {}", e))?;
+    listener
+        .set_nonblocking(true)
+        .map_err(|e| format!("egress listener: {}", e))?;
+    let ns = File::open("/proc/thread-self/ns/net").map_err(|e| format!("open network namespace: {}", e))?;
+    Ok((listener, ns.into()))
+}
+
+/// Set `lo` up in the calling thread's network namespace.
+fn loopback_up() -> std::io::Result<()> {
+    let fd = unsafe { libc::socket(libc::AF_INET, libc::SOCK_DGRAM | libc::SOCK_CLOEXEC, 0) };
+    if fd < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    let socket = unsafe { OwnedFd::from_raw_fd(fd) };
+    let mut request: libc::ifreq = unsafe { std::mem::zeroed() };
+    for (dst, &src) in request.ifr_name.iter_mut().zip(b"lo") {
+        *dst = src as libc::c_char;
+    }
+    unsafe {
+        if libc::ioctl(socket.as_raw_fd(), libc::SIOCGIFFLAGS, &mut request) < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        request.ifr_ifru.ifru_flags |= libc::IFF_UP as libc::c_short;
+        if libc::ioctl(socket.as_raw_fd(), libc::SIOCSIFFLAGS, &request) < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+async fn serve(listener: TcpListener, proxy: Arc<Proxy>) {
+    loop {
+        match listener.accept().await {
+            Ok((client, _)) => {
+                let proxy = proxy.clone();
+                tokio::spawn(async move { proxy.handle(client).await });
+            }
+            // Out of descriptors, most likely; let some close
+            Err(_) => tokio::time::sleep(Duration::from_millis(100)).await,
+        }
+    }
+}
+
+/// What a client asked the proxy for.
+struct Request {
+    host: String,
+    port: u16,
+    /// The absolute-form target of a plain request
+    url: Option<String>,
+    /// Head to send upstream; `None` for a `CONNECT` tunnel
+    head: Option<Vec<u8>>,
+}
+
+impl Proxy {
+    async fn handle(&self, mut client: TcpStream) {
+        let (head, rest) = match tokio::time::timeout(HEAD_TIMEOUT, read_head(&mut client)).await {
+            Ok(Some(read)) => read,
+            _ => return respond(&mut client, "400 Bad Request", "Malformed proxy request").await,
+        };
+        let Some(request) = parse_request(&head) else {
+            return respond(&mut client, "400 Bad Request", "Malformed proxy request").await;
+        };
+
+        let (refused, named) = {
+            let policy = self.policy.lock().unwrap_or_else(|e| e.into_inner());
+            (refusal(&policy, &request.host), explicitly_allows(&policy, &request.host))
+        };
+        if let Some(reason) = refused {
+            self.record(&request, Some(reason));
+            let message = format!("Blocked by egress policy: {}", request.host);
+            return respond(&mut client, "403 Forbidden", &message).await;
+        }
+        let addrs: Vec<SocketAddr> = match tokio::net::lookup_host((request.host.as_str(), request.port)).await {
+            Ok(addrs) => addrs.collect(),
+            Err(_) => {
+                self.record(&request, Some("could not be resolved"));
+                return respond(&mut client, "502 Bad Gateway", "Could not resolve host").await;
+            }
+        };
+        if let Some(reason) = addrs.iter().find_map(|a| address_refusal(a.ip(), named)) {
+            self.record(&request, Some(reason));
+            let message = format!("Blocked by egress policy: {}", request.host);
+            return respond(&mut client, "403 Forbidden", &message).await;
+        }
+        self.record(&request, None);
+
+        let mut upstream = None;
+        for addr in addrs {
+            if let Ok(Ok(stream)) = tokio::time::timeout(CONNECT_TIMEOUT, TcpStream::connect(addr)).await {
+                upstream = Some(stream);
+                break;
+            }
+        }
+        let Some(mut upstream) = upstream else {
+            return respond(&mut client, "502 Bad Gateway", "Could not connect to host").await;
+        };
+        let opened = match request.head {
+            Some(ref head) => upstream.write_all(head).await,
+            None => client.write_all(b"HTTP/1.1 200 Connection established\r\n\r\n").await,
+        };
+        if opened.is_err() || upstream.write_all(&rest).await.is_err() {
+            return;
+        }
+        let _ = tokio::io::copy_bidirectional(&mut client, &mut upstream).await;
+    }
+
+    fn record(&self, request: &Request, reason: Option<&str>) {
+        let allowed = reason.is_none();
+        info!(
+            "Egress from session {} to {}:{} {}",
+            self.session_id,
+            request.host,
+            request.port,
+            reason.unwrap_or("allowed")
+        );
+        let attempt = EgressAttempt {
+            at_ms: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_millis() as u64)
+                .unwrap_or(0),
+            host: request.host.clone(),
+            port: request.port,
+            url: request.url.clone(),
+            allowed,
+            reason: reason.map(str::to_string),
+        };
+        let mut attempts = self.attempts.lock().unwrap_or_else(|e| e.into_inner());
+        attempts.push_back(attempt);
+        while attempts.len() > LOG_CAPACITY {
+            attempts.pop_front();
+        }
+    }
+}
+
+/// A request head, through its blank line, and whatever was read after it.
+/// `None` if the client closes first or the head is too long.
+async fn read_head(client: &mut TcpStream) -> Option<(Vec<u8>, Vec<u8>)> {
+    let mut buf = Vec::new();
+    let mut chunk = [0u8; 4096];
+    loop {
+        let n = client.read(&mut chunk).await.ok()?;
+        if n == 0 {
+            return None;
+        }
+        let searched = buf.len().saturating_sub(3);
+        buf.extend_from_slice(&chunk[..n]);
+        if let Some(end) = buf[searched..].windows(4).position(|w| w == b"\r\n\r\n") {
+            let rest = buf.split_off(searched + end + 4);
+            return Some((buf, rest));
+        }
+        if buf.len() > MAX_HEAD {
+            return None;
+        }
+    }
+}
+
+/// Parse a `CONNECT host:port` or absolute-form `http://` request. Plain
+/// requests are rewritten to origin form, without proxy headers, on a
+/// connection that closes after the response.
+fn parse_request(head: &[u8]) -> Option<Request> {
+    let head = std::str::from_utf8(head).ok()?;
+    let mut lines = head.split("\r\n");
+    let mut request_line = lines.next()?.split(' ');
+    let (method, target, version) = (request_line.next()?, request_line.next()?, request_line.next()?);
+    if request_line.next().is_some() || !version.starts_with("HTTP/") {
+        return None;
+    }
+
+    if method.eq_ignore_ascii_case("CONNECT") {
+        let (host, port) = split_authority(target, None)?;
+        return Some(Request {
+            host,
+            port,
+            url: None,
+            head: None,
+        });
+    }
+
+    let rest = target.get(..7).filter(|s| s.eq_ignore_ascii_case("http://")).map(|_| &target[7..])?;
+    let path_start = rest.find(['/', '?']).unwrap_or(rest.len());
+    let (authority, path) = rest.split_at(path_start);
+    let (host, port) = split_authority(authority, Some(80))?;
+    let path = if path.starts_with('/') { path.to_string() } else { format!("/{}", path) };
+
+    let mut rewritten = format!("{} {} {}\r\n", method, path, version);
+    let mut has_host = false;
+    for line in lines.take_while(|l| !l.is_empty()) {
+        let name = line.split(':').next().unwrap_or("").trim().to_ascii_lowercase();
+        if name.starts_with("proxy-") || name == "connection" || name == "keep-alive" {
+            continue;
+        }
+        has_host |= name == "host";
+        rewritten.push_str(line);
+        rewritten.push_str("\r\n");
+    }
+    if !has_host {
+        rewritten.push_str(&format!("Host: {}\r\n", authority));
+    }
+    rewritten.push_str("Connection: close\r\n\r\n");
+    Some(Request {
+        host,
+        port,
+        url: Some(target.to_string()),
+        head: Some(rewritten.into_bytes()),
+    })
+}
+
+/// Host (lowercase, without a trailing dot or IPv6 brackets) and port of
+/// `host:port`; the port may be left out if there's a default.
+fn split_authority(authority: &str, default_port: Option<u16>) -> Option<(String, u16)> {
+    if authority.contains('@') {
+        return None;
+    }
+    let (host, port) = match authority.strip_prefix('[') {
+        Some(bracketed) => {
+            let (host, after) = bracketed.split_once(']')?;
+            (host, after.strip_prefix(':'))
+        }
+        None => match authority.rsplit_once(':') {
+            Some((host, port)) => (host, Some(port)),
+            None => (authority, None),
+        },
+    };
+    let port = match port {
+        Some(port) => port.parse().ok()?,
+        None => default_port?,
+    };
+    let host = host.trim_end_matches('.').to_ascii_lowercase();
+    if host.is_empty() || port == 0 {
+        return None;
+    }
+    Some((host, port))
+}
+
+async fn respond(client: &mut TcpStream, status: &str, message: &str) {
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}\n",
+        status,
+        message.len() + 1,
+        message
+    );
+    let _ = client.write_all(response.as_bytes()).await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// What the proxy answers `head` with, under `policy`.
+    async fn proxy_response(policy: EgressPolicy, head: &str) -> String {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let (server, _) = listener.accept().await.unwrap();
+        let proxy = Proxy {
+            session_id: "test".to_string(),
+            policy: Mutex::new(policy),
+            attempts: Mutex::default(),
+        };
+        let handled = tokio::spawn(async move { proxy.handle(server).await });
+        client.write_all(head.as_bytes()).await.unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).await.unwrap();
+        handled.await.unwrap();
+        response
+    }
+
+    #[tokio::test]
+    async fn mapped_loopback_connect_is_refused() {
+        let head = "CONNECT [::ffff:127.0.0.1]:8080 HTTP/1.1\r\n\r\n";
+        let response = proxy_response(EgressPolicy::default(), head).await;
+        assert!(response.starts_with("HTTP/1.1 403"), "{}", response);
+    }
+
+    #[tokio::test]
+    async fn metadata_address_is_refused() {
+        let head = "GET http://169.254.169.254/latest HTTP/1.1\r\n\r\n";
+        let response = proxy_response(EgressPolicy::default(), head).await;
+        assert!(response.starts_with("HTTP/1.1 403"), "{}", response);
+    }
+
+    #[test]
+    fn local_addresses_are_refused() {
+        for ip in ["127.0.0.1", "::1", "::ffff:127.0.0.1", "0.0.0.0", "::"] {
+            assert!(address_refusal(ip.parse().unwrap(), true).is_some(), "{}", ip);
+        }
+        let internal = [
+            "169.254.169.254",
+            "10.0.0.1",
+            "172.16.5.4",
+            "192.168.1.1",
+            "100.64.0.1",
+            "fe80::1",
+            "fd00::1",
+            "::ffff:10.0.0.1",
+        ];
+        for ip in internal {
+            let ip: IpAddr = ip.parse().unwrap();
+            assert!(address_refusal(ip, false).is_some(), "{}", ip);
+            // Unless this host happens to have the address itself
+            if !host_addresses().contains(&ip.to_canonical()) {
+                assert!(address_refusal(ip, true).is_none(), "{}", ip);
+            }
+        }
+        assert!(address_refusal("93.184.216.34".parse().unwrap(), false).is_none());
+    }
+}
@@ -0,0 +1,108 @@
+//! Server-wide policy for environment variables handed to sandboxed code.
+//!
+//! Names are matched against a denylist of patterns (`*` matches any run of
+//! characters, e.g. `AWS_*`). An allowlist carves out exceptions
+//! (`AWS_REGION`). Matching variables are either rejected with an error or
+//! silently stripped, depending on the configured action.
+
+use std::collections::HashMap;
+use std::str::FromStr;
+use tracing::info;
+
+/// Variables denied when no policy is configured.
+pub const DEFAULT_FORBIDDEN_ENV: &[&str] = &["LD_PRELOAD", "LD_AUDIT"];
+
+/// What to do with a forbidden variable.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EnvPolicyAction {
+    /// Fail the request
+    Reject,
+    /// Drop the variable and carry on
+    Strip,
+}
+
+impl FromStr for EnvPolicyAction {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "reject" => Ok(EnvPolicyAction::Reject),
+            "strip" => Ok(EnvPolicyAction::Strip),
+            other => Err(format!("invalid env policy action {:?} (expected reject or strip)", other)),
+        }
+    }
+}
+
+/// Denylist/allowlist of environment variable names.
+#[derive(Debug, Clone)]
+pub struct EnvPolicy {
+    pub deny: Vec<String>,
+    pub allow: Vec<String>,
+    pub action: EnvPolicyAction,
+}
+
+impl Default for EnvPolicy {
+    fn default() -> Self {
+        Self {
+            deny: DEFAULT_FORBIDDEN_ENV.iter().map(|s| s.to_string()).collect(),
+            allow: Vec::new(),
+            action: EnvPolicyAction::Reject,
+        }
+    }
+}
+
+impl EnvPolicy {
+    /// Whether a variable name is forbidden by this policy.
+    pub fn is_forbidden(&self, name: &str) -> bool {
+        self.deny.iter().any(|p| pattern_matches(p, name))
+            && !self.allow.iter().any(|p| pattern_matches(p, name))
+    }
+
+    /// Enforce the policy on an env map: errors on forbidden names in reject
+    /// mode, removes them in strip mode.
+    pub fn apply(&self, env: &mut HashMap<String, String>) -> Result<(), String> {
+        let mut forbidden: Vec<String> = env
+            .keys()
+            .filter(|k| self.is_forbidden(k))
+            .cloned()
+            .collect();
+        if forbidden.is_empty() {
+            return Ok(());
+        }
+        forbidden.sort();
+        match self.action {
+            EnvPolicyAction::Reject => Err(format!(
+                "environment variables not allowed: {}",
+                forbidden.join(", ")
+            )),
+            EnvPolicyAction::Strip => {
+                info!(stripped = ?forbidden, "Stripping forbidden environment variables");
+                for name in &forbidden {
+                    env.remove(name);
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Glob-style match supporting `*` wildcards only.
+fn pattern_matches(pattern: &str, name: &str) -> bool {
+    let parts: Vec<&str> = pattern.split('*').collect();
+    if parts.len() == 1 {
+        return pattern == name;
+    }
+    let (first, last) = (parts[0], parts[parts.len() - 1]);
+    if !name.starts_with(first) || name.len() < first.len() + last.len() || !name.ends_with(last) {
+        return false;
+    }
+    // Middle segments must appear in order between the prefix and suffix
+    let mut rest = &name[first.len()..name.len() - last.len()];
+    for part in &parts[1..parts.len() - 1] {
+        match rest.find(part) {
+            Some(i) => rest = &rest[i + part.len()..],
+            None => return false,
+        }
+    }
+    true
+}
@@ -0,0 +1,110 @@
+//! Server-wide event stream for dashboards and autoscalers.
+//!
+//! Session lifecycle transitions, runs starting and finishing, background
+//! process exits and preview proxy requests are published to the
+//! [`EventBus`] in [`AppState`](crate::state::AppState). `GET /events/stream`
+//! (scope `admin.read`) relays them to WebSocket subscribers as JSON text
+//! messages, across every org. Nothing is kept: a subscriber sees what
+//! happens while it's connected, and one that falls [`CHANNEL_CAPACITY`]
+//! events behind gets `{"type": "lagged", "missed": n}` and carries on with
+//! newer ones.
+
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::sync::broadcast;
+
+pub use opencomputer_types::{ServerEvent, ServerEventKind, ServerEventsQuery};
+
+/// Events buffered per subscriber before slow ones start missing them.
+pub const CHANNEL_CAPACITY: usize = 4096;
+
+/// Fan-out of server events to `GET /events/stream` subscribers.
+#[derive(Clone)]
+pub struct EventBus {
+    tx: broadcast::Sender<ServerEvent>,
+}
+
+impl Default for EventBus {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl EventBus {
+    pub fn new() -> Self {
+        let (tx, _) = broadcast::channel(CHANNEL_CAPACITY);
+        Self { tx }
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<ServerEvent> {
+        self.tx.subscribe()
+    }
+
+    /// Whether anyone is subscribed. Publishers with costly events to build
+    /// check this first.
+    pub fn is_observed(&self) -> bool {
+        self.tx.receiver_count() > 0
+    }
+
+    pub fn publish(&self, session_id: Option<&str>, org_id: Option<&str>, kind: ServerEventKind) {
+        if !self.is_observed() {
+            return;
+        }
+        let at_ms = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_millis() as u64)
+            .unwrap_or(0);
+        let _ = self.tx.send(ServerEvent {
+            at_ms,
+            session_id: session_id.map(str::to_string),
+            org_id: org_id.map(str::to_string),
+            kind,
+        });
+    }
+}
+
+/// Which events a subscriber asked for.
+#[derive(Debug, Clone)]
+pub struct EventFilter {
+    /// Types (`run.finished`) or groups (`run`); `None` = all
+    types: Option<Vec<String>>,
+    session_id: Option<String>,
+    org: Option<String>,
+}
+
+impl EventFilter {
+    pub fn from_query(query: ServerEventsQuery) -> Result<Self, String> {
+        let types = match query.types {
+            Some(list) => {
+                let types: Vec<String> = list
+                    .split(',')
+                    .map(|t| t.trim().to_string())
+                    .filter(|t| !t.is_empty())
+                    .collect();
+                for t in &types {
+                    if !ServerEventKind::TYPES.iter().any(|known| matches_type(known, t)) {
+                        return Err(format!("unknown event type {:?}", t));
+                    }
+                }
+                Some(types)
+            }
+            None => None,
+        };
+        Ok(Self {
+            types,
+            session_id: query.session_id,
+            org: query.org,
+        })
+    }
+
+    pub fn matches(&self, event: &ServerEvent) -> bool {
+        let name = event.kind.type_name();
+        self.types.as_ref().is_none_or(|types| types.iter().any(|t| matches_type(name, t)))
+            && self.session_id.as_ref().is_none_or(|id| event.session_id.as_ref() == Some(id))
+            && self.org.as_ref().is_none_or(|org| event.org_id.as_ref() == Some(org))
+    }
+}
+
+/// Whether event type `name` is `pattern` or in its group.
+fn matches_type(name: &str, pattern: &str) -> bool {
+    name == pattern || name.split_once('.').is_some_and(|(group, _)| group == pattern)
+}
@@ -0,0 +1,262 @@
+//! Diffs of live sandbox paths for `POST /sessions/:id/files/diff`, against
+//! another path or a checkpoint's copy of the same one.
+//!
+//! Each side becomes a map of manifest entries keyed by their path below the
+//! compared root, and the two maps are compared as checkpoint diffs compare
+//! manifests. A checkpoint's hashes come from its manifest; a live file is
+//! only hashed when its size matches the other side's. Live trees are walked
+//! and read through `safe_path`, as sandboxed code may change them meanwhile,
+//! and so are checkpoint copies, whose symlinks are the sandbox's.
+
+use crate::checkpoint::{self, MAX_CONTENT_DIFF_BYTES};
+use crate::replay::ManifestEntry;
+use crate::safe_path::{Entry, Root};
+use crate::sandbox;
+use axum::http::StatusCode;
+use nix::sys::stat::SFlag;
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::{self, Read};
+use std::path::Path;
+
+pub use opencomputer_types::{ChangeKind, DiffSummary, FileChange, FilesDiff, FilesDiffRequest};
+
+/// Most entries either side of a diff may have.
+pub const MAX_ENTRIES: usize = 100_000;
+
+/// One side of a diff. Entries are keyed by their path below the compared
+/// root (`""` for a file compared itself) and their `path` is within `root`.
+struct Tree {
+    root: Root,
+    entries: BTreeMap<String, ManifestEntry>,
+}
+
+/// Compare `req.to` with `req.from` or with its copy in `req.checkpoint`.
+pub fn diff(sandbox_root: &Path, session_id: &str, req: &FilesDiffRequest) -> Result<FilesDiff, (StatusCode, String)> {
+    let to = normalize(&req.to);
+    let before = match (&req.from, &req.checkpoint) {
+        (Some(from), None) => Tree::live(sandbox_root, &normalize(from))?,
+        (None, Some(id)) => Tree::checkpoint(session_id, id, &to)?,
+        _ => {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                "Set either from or checkpoint".to_string(),
+            ))
+        }
+    };
+    let after = Tree::live(sandbox_root, &to)?;
+
+    let file_bytes = |tree: &Tree| -> u64 { tree.entries.values().filter(|e| e.kind == "file").map(|e| e.size).sum() };
+    let mut summary = DiffSummary {
+        bytes_before: file_bytes(&before),
+        bytes_after: file_bytes(&after),
+        ..Default::default()
+    };
+    let mut changes = Vec::new();
+    let paths: BTreeSet<&String> = before.entries.keys().chain(after.entries.keys()).collect();
+    for rel in paths {
+        let (old, new) = (before.entries.get(rel), after.entries.get(rel));
+        let (change, mode_only) = match (old, new) {
+            (None, Some(_)) => (ChangeKind::Added, false),
+            (Some(_), None) => (ChangeKind::Removed, false),
+            (Some(old), Some(new)) => match (same_content(&before, old, &after, new), old.mode == new.mode) {
+                (true, true) => continue,
+                (true, false) => (ChangeKind::Modified, true),
+                (false, _) => (ChangeKind::Modified, false),
+            },
+            (None, None) => continue,
+        };
+        match change {
+            ChangeKind::Added => summary.added += 1,
+            ChangeKind::Removed => summary.removed += 1,
+            ChangeKind::Modified => summary.modified += 1,
+        }
+        let path = if rel.is_empty() {
+            to.clone()
+        } else {
+            format!("{}{}", to.trim_end_matches('/'), rel)
+        };
+        let diff = if req.content && !mode_only {
+            text(&before, old)
+                .zip(text(&after, new))
+                .map(|(old, new)| checkpoint::unified_diff(&path, &old, &new))
+        } else {
+            None
+        };
+        let size = |e: Option<&ManifestEntry>| e.filter(|e| e.kind == "file").map(|e| e.size);
+        changes.push(FileChange {
+            path,
+            change,
+            kind: new.or(old).map(|e| e.kind.clone()).unwrap_or_default(),
+            size_before: size(old),
+            size_after: size(new),
+            mode_only,
+            diff,
+        });
+    }
+    Ok(FilesDiff { summary, changes })
+}
+
+impl Tree {
+    /// The file or directory tree at `base` in the sandbox.
+    fn live(sandbox_root: &Path, base: &str) -> Result<Self, (StatusCode, String)> {
+        let root = Root::open(sandbox_root).map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e))?;
+        let top = root.metadata(Path::new(base)).map_err(|e| (StatusCode::NOT_FOUND, e))?;
+        let mut entries = BTreeMap::new();
+        match top.file_type() {
+            SFlag::S_IFDIR => walk(&root, base, "", &mut |rel, path, entry, target| {
+                if entries.len() >= MAX_ENTRIES {
+                    return Err((
+                        StatusCode::BAD_REQUEST,
+                        format!("More than {} entries under {}; diff a narrower path", MAX_ENTRIES, base),
+                    ));
+                }
+                let entry = manifest_entry(path, kind(entry), entry.mode, entry.size, target);
+                entries.insert(rel.to_string(), entry);
+                Ok(())
+            })?,
+            SFlag::S_IFREG => {
+                entries.insert(String::new(), manifest_entry(base.to_string(), "file", top.mode, top.size, None));
+            }
+            _ => {
+                return Err((
+                    StatusCode::BAD_REQUEST,
+                    format!("{} is not a file or directory", base),
+                ))
+            }
+        }
+        Ok(Self { root, entries })
+    }
+
+    /// What checkpoint `id` holds at `base`.
+    fn checkpoint(session_id: &str, id: &str, base: &str) -> Result<Self, (StatusCode, String)> {
+        let manifest = checkpoint::load(session_id, id)
+            .map_err(|e| (StatusCode::BAD_REQUEST, e))?
+            .ok_or((StatusCode::NOT_FOUND, format!("Checkpoint {} not found", id)))?;
+        let files = checkpoint::checkpoint_dir(session_id, id)
+            .map_err(|e| (StatusCode::BAD_REQUEST, e))?
+            .join(checkpoint::FILES_DIR);
+        let root = Root::open(&files).map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e))?;
+        let entries = manifest
+            .files
+            .into_iter()
+            .filter_map(|e| {
+                let rel = below(base, &e.path)?.to_string();
+                // Like a live directory, the compared root itself isn't an entry
+                (!(rel.is_empty() && e.kind == "dir")).then_some((rel, e))
+            })
+            .collect();
+        Ok(Self { root, entries })
+    }
+
+    /// SHA-256 of a regular file, from the manifest if it has one. `None`
+    /// if the file can't be read, e.g. because it was just removed.
+    fn sha256(&self, entry: &ManifestEntry) -> Option<String> {
+        if let Some(ref hash) = entry.sha256 {
+            return Some(hash.clone());
+        }
+        sha256(&self.root, &entry.path)
+    }
+}
+
+/// SHA-256 of the regular file at `path`, or `None` if it can't be read.
+pub(crate) fn sha256(root: &Root, path: &str) -> Option<String> {
+    let mut file = root.open_file(Path::new(path)).ok()?;
+    let mut hasher = Sha256::new();
+    io::copy(&mut file, &mut hasher).ok()?;
+    Some(hex::encode(hasher.finalize()))
+}
+
+/// Visit every entry under directory `base{rel}`, parents before children,
+/// with its path below `base`, its path in `root` and, for symlinks, its
+/// target. The sandbox's own mounts are skipped when `base` is the root, and
+/// so are sockets, fifos and devices, which aren't files an agent changes.
+pub(crate) fn walk<F>(root: &Root, base: &str, rel: &str, visit: &mut F) -> Result<(), (StatusCode, String)>
+where
+    F: FnMut(&str, String, &Entry, Option<String>) -> Result<(), (StatusCode, String)>,
+{
+    let dir = format!("{}{}", base.trim_end_matches('/'), rel);
+    let internal = |e| (StatusCode::INTERNAL_SERVER_ERROR, e);
+    for entry in root.list(Path::new(&dir)).map_err(internal)? {
+        if base == "/" && rel.is_empty() && sandbox::is_mounted_top_level(&entry.name) {
+            continue;
+        }
+        let rel = format!("{}/{}", rel, entry.name);
+        let path = format!("{}/{}", dir, entry.name);
+        let target = match entry.file_type() {
+            SFlag::S_IFDIR | SFlag::S_IFREG => None,
+            SFlag::S_IFLNK => Some(root.read_link(Path::new(&path)).map_err(internal)?),
+            _ => continue,
+        };
+        visit(&rel, path, &entry, target)?;
+        if entry.is_directory {
+            walk(root, base, &rel, visit)?;
+        }
+    }
+    Ok(())
+}
+
+/// The kind a manifest records for `entry`.
+pub(crate) fn kind(entry: &Entry) -> &'static str {
+    match entry.file_type() {
+        SFlag::S_IFDIR => "dir",
+        SFlag::S_IFLNK => "symlink",
+        _ => "file",
+    }
+}
+
+fn manifest_entry(path: String, kind: &str, mode: u32, size: u64, target: Option<String>) -> ManifestEntry {
+    ManifestEntry {
+        path,
+        kind: kind.to_string(),
+        mode: mode & 0o7777,
+        size: if kind == "file" { size } else { 0 },
+        sha256: None,
+        target,
+    }
+}
+
+fn same_content(before: &Tree, old: &ManifestEntry, after: &Tree, new: &ManifestEntry) -> bool {
+    if old.kind != new.kind || old.target != new.target {
+        return false;
+    }
+    if old.kind != "file" {
+        return true;
+    }
+    old.size == new.size && before.sha256(old).is_some_and(|hash| after.sha256(new) == Some(hash))
+}
+
+/// A small text file's content, `""` for a missing side, or `None` for
+/// anything not to diff.
+fn text(tree: &Tree, entry: Option<&ManifestEntry>) -> Option<String> {
+    let Some(entry) = entry else {
+        return Some(String::new());
+    };
+    if entry.kind != "file" || entry.size > MAX_CONTENT_DIFF_BYTES {
+        return None;
+    }
+    let mut data = Vec::new();
+    let file = tree.root.open_file(Path::new(&entry.path)).ok()?;
+    file.take(MAX_CONTENT_DIFF_BYTES + 1).read_to_end(&mut data).ok()?;
+    if data.len() as u64 > MAX_CONTENT_DIFF_BYTES {
+        return None;
+    }
+    checkpoint::as_text(data)
+}
+
+/// `path` with a single leading `/` and no trailing one, `/` for the root.
+pub(crate) fn normalize(path: &str) -> String {
+    format!("/{}", path.trim_matches('/'))
+}
+
+/// The rest of `path` below `base` (`""` for `base` itself, otherwise with a
+/// leading `/`), or `None` if it isn't below it.
+pub(crate) fn below<'a>(base: &str, path: &'a str) -> Option<&'a str> {
+    if base == "/" {
+        return Some(path);
+    }
+    match path.strip_prefix(base)? {
+        rest if rest.is_empty() || rest.starts_with('/') => Some(rest),
+        _ => None,
+    }
+}
@@ -0,0 +1,45 @@
+//! Checksums of sandbox files for `/sessions/:id/files/hash`, so a client can
+//! verify a transfer or skip syncing an unchanged file without fetching it.
+//! Files are hashed as they're read, never held in memory whole.
+
+use crate::backend::SandboxBackend;
+use md5::Md5;
+use sha1::Sha1;
+use sha2::{Digest, Sha256, Sha512};
+use std::io::{self, Read, Write};
+use std::path::Path;
+
+pub use opencomputer_types::{FileHash, FileHashError, FileHashQuery, FileHashesRequest, FileHashesResponse, HashAlgo};
+
+/// Most paths one `POST /sessions/:id/files/hash` takes.
+pub const MAX_PATHS: usize = 1000;
+
+/// Hash the regular file at `path`.
+pub fn hash_file(
+    backend: &dyn SandboxBackend,
+    sandbox_root: &Path,
+    path: &str,
+    algo: HashAlgo,
+) -> Result<FileHash, String> {
+    let file = backend.open_file(sandbox_root, path)?;
+    let (hash, size) = match algo {
+        HashAlgo::Sha256 => digest::<Sha256>(file),
+        HashAlgo::Sha512 => digest::<Sha512>(file),
+        HashAlgo::Sha1 => digest::<Sha1>(file),
+        HashAlgo::Md5 => digest::<Md5>(file),
+    }
+    .map_err(|e| format!("read file: {}", e))?;
+    Ok(FileHash {
+        path: path.to_string(),
+        algo,
+        hash,
+        size,
+    })
+}
+
+/// Hex digest and length of everything `reader` yields.
+fn digest<D: Digest + Write>(mut reader: impl Read) -> io::Result<(String, u64)> {
+    let mut hasher = D::new();
+    let size = io::copy(&mut reader, &mut hasher)?;
+    Ok((hex::encode(hasher.finalize()), size))
+}
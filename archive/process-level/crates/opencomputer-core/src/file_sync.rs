@@ -0,0 +1,281 @@
+//! Incremental directory sync for `POST /sessions/:id/files/sync` (push)
+//! and `POST /sessions/:id/files/sync/pull`.
+//!
+//! The client sends a manifest of its copy of a directory: each regular
+//! file's path below it, size and SHA-256. A push answers with the files the
+//! sandbox has different content for, which the client sends again with
+//! their content, and only those are written. A pull answers with the
+//! sandbox's files the client lacks or has different content for, content
+//! included up to [`MAX_PULL_BYTES`] per response. Nothing is kept between
+//! calls: a sync cut off midway carries on by sending the manifest again.
+//!
+//! Sandbox files are only hashed when their size matches the client's, and
+//! the tree is walked and written through `safe_path`, as sandboxed code may
+//! change it meanwhile.
+
+use crate::file_diff::{self, below, normalize, walk, MAX_ENTRIES};
+use crate::safe_path::Root;
+use crate::sandbox;
+use crate::trash::TRASH_DIR;
+use crate::uploads::UPLOADS_DIR;
+use axum::http::StatusCode;
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use nix::sys::stat::SFlag;
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::Path;
+
+pub use opencomputer_types::{SyncFile, SyncPullResponse, SyncPushResponse, SyncRequest};
+
+/// Most content bytes in one pull response. Larger files never come with
+/// content; they're fetched with `GET /sessions/:id/files/download`.
+pub const MAX_PULL_BYTES: u64 = 16 * 1024 * 1024;
+
+/// An entry under the synced directory.
+struct Existing {
+    kind: SFlag,
+    size: u64,
+    mode: u32,
+}
+
+/// Bring the sandbox's copy of `req.path` up to the client's manifest,
+/// writing the files that came with content and listing the rest that
+/// differ.
+pub fn push(sandbox_root: &Path, req: &SyncRequest) -> Result<SyncPushResponse, (StatusCode, String)> {
+    let base = normalize(&req.path);
+    if base != "/" {
+        check_target(&base)?;
+    }
+    let internal = |e| (StatusCode::INTERNAL_SERVER_ERROR, e);
+    let root = Root::open(sandbox_root).map_err(internal)?;
+    let wanted = manifest(&req.files)?;
+    for rel in wanted.keys() {
+        check_target(&join(&base, rel))?;
+    }
+    let existing = scan(&root, &base)?;
+
+    // Everything is checked before anything is written
+    let mut response = SyncPushResponse::default();
+    let mut writes = Vec::new();
+    let mut chmods = Vec::new();
+    for (rel, file) in &wanted {
+        let path = join(&base, rel);
+        let current = existing.get(rel);
+        let up_to_date = current.is_some_and(|c| c.kind == SFlag::S_IFREG && c.size == file.size)
+            && file_diff::sha256(&root, &path).is_some_and(|hash| hash.eq_ignore_ascii_case(&file.sha256));
+        if up_to_date {
+            let mode = file.mode.filter(|mode| current.is_some_and(|c| c.mode & 0o777 != mode & 0o777));
+            if let Some(mode) = mode {
+                chmods.push((path, mode));
+            }
+            continue;
+        }
+        match &file.content {
+            Some(content) => writes.push((rel, path, *file, decode(file, content)?)),
+            None => response.need.push(file.path.clone()),
+        }
+    }
+
+    for (path, mode) in chmods {
+        root.set_mode(Path::new(&path), mode).map_err(internal)?;
+    }
+    let owner = sandbox::sandbox_owner(sandbox_root);
+    let mut cleared = BTreeSet::new();
+    for (rel, path, file, content) in writes {
+        clear_way(&root, &base, rel, &existing, &mut cleared)?;
+        root.write(Path::new(&path), &content, owner).map_err(internal)?;
+        if let Some(mode) = file.mode {
+            root.set_mode(Path::new(&path), mode).map_err(internal)?;
+        }
+        response.written.push(file.path.clone());
+    }
+
+    if req.delete && response.need.is_empty() {
+        let dirs: BTreeSet<&str> = wanted.keys().flat_map(|rel| ancestors(rel)).collect();
+        // What was cleared for a write is gone along with what was below it
+        let mut removed: Vec<String> = cleared.into_iter().collect();
+        for rel in existing.keys() {
+            if wanted.contains_key(rel)
+                || dirs.contains(rel.as_str())
+                || removed.iter().any(|r| below(r, rel).is_some())
+            {
+                continue;
+            }
+            let path = join(&base, rel);
+            root.remove_all(Path::new(&path)).map_err(internal)?;
+            response.deleted.push(path);
+            removed.push(rel.clone());
+        }
+    }
+    Ok(response)
+}
+
+/// The sandbox's files under `req.path` that the client's manifest lacks
+/// or has different content for, and the manifest's files the sandbox
+/// doesn't have.
+pub fn pull(sandbox_root: &Path, req: &SyncRequest) -> Result<SyncPullResponse, (StatusCode, String)> {
+    let base = normalize(&req.path);
+    let root = Root::open(sandbox_root).map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e))?;
+    let have = manifest(&req.files)?;
+    let existing = scan(&root, &base)?;
+
+    let mut response = SyncPullResponse::default();
+    let mut budget = MAX_PULL_BYTES;
+    for (rel, entry) in existing.iter().filter(|(_, e)| e.kind == SFlag::S_IFREG) {
+        let path = join(&base, rel);
+        let mut hash = None;
+        if let Some(theirs) = have.get(rel).filter(|f| f.size == entry.size) {
+            // Gone since the walk if it can't be hashed
+            let Some(ours) = file_diff::sha256(&root, &path) else {
+                continue;
+            };
+            if ours.eq_ignore_ascii_case(&theirs.sha256) {
+                continue;
+            }
+            hash = Some(ours);
+        }
+        let mut file = SyncFile {
+            path: rel.trim_start_matches('/').to_string(),
+            size: entry.size,
+            sha256: String::new(),
+            mode: Some(entry.mode & 0o777),
+            content: None,
+        };
+        if entry.size <= budget {
+            let Ok(data) = root.read(Path::new(&path)) else {
+                continue;
+            };
+            budget = budget.saturating_sub(data.len() as u64);
+            file.size = data.len() as u64;
+            file.sha256 = hex::encode(Sha256::digest(&data));
+            file.content = Some(BASE64.encode(&data));
+        } else {
+            let Some(hash) = hash.or_else(|| file_diff::sha256(&root, &path)) else {
+                continue;
+            };
+            file.sha256 = hash;
+            response.truncated |= entry.size <= MAX_PULL_BYTES;
+        }
+        response.files.push(file);
+    }
+    response.removed = have
+        .iter()
+        .filter(|(rel, _)| existing.get(*rel).is_none_or(|e| e.kind != SFlag::S_IFREG))
+        .map(|(_, file)| file.path.clone())
+        .collect();
+    Ok(response)
+}
+
+/// The manifest keyed by path below the synced directory, as `/src/main.rs`.
+fn manifest(files: &[SyncFile]) -> Result<BTreeMap<String, &SyncFile>, (StatusCode, String)> {
+    if files.len() > MAX_ENTRIES {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            format!("More than {} files; sync a narrower path", MAX_ENTRIES),
+        ));
+    }
+    let mut manifest = BTreeMap::new();
+    for file in files {
+        let rel = file.path.trim_start_matches('/');
+        if rel.is_empty() || rel.split('/').any(|c| c.is_empty() || c == "." || c == "..") {
+            return Err((StatusCode::BAD_REQUEST, format!("{:?} isn't a path below the synced directory", file.path)));
+        }
+        if manifest.insert(format!("/{}", rel), file).is_some() {
+            return Err((StatusCode::BAD_REQUEST, format!("{} is listed twice", file.path)));
+        }
+    }
+    let dirs: BTreeSet<&str> = manifest.keys().flat_map(|rel| ancestors(rel)).collect();
+    if let Some(rel) = manifest.keys().find(|rel| dirs.contains(rel.as_str())) {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            format!("{} is listed as a file and has files below it", rel.trim_start_matches('/')),
+        ));
+    }
+    Ok(manifest)
+}
+
+/// What's under `base`, keyed as [`manifest`] keys files; empty if `base`
+/// doesn't exist yet. Server-owned directories are left out.
+fn scan(root: &Root, base: &str) -> Result<BTreeMap<String, Existing>, (StatusCode, String)> {
+    let mut entries = BTreeMap::new();
+    match root.symlink_metadata(Path::new(base)) {
+        Err(_) => return Ok(entries),
+        Ok(entry) if entry.is_directory => {}
+        Ok(_) => return Err((StatusCode::BAD_REQUEST, format!("{} is not a directory", base))),
+    }
+    walk(root, base, "", &mut |rel, path, entry, _| {
+        if below(TRASH_DIR, &path).is_some() || below(UPLOADS_DIR, &path).is_some() {
+            return Ok(());
+        }
+        if entries.len() >= MAX_ENTRIES {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                format!("More than {} entries under {}; sync a narrower path", MAX_ENTRIES, base),
+            ));
+        }
+        let existing = Existing {
+            kind: entry.file_type(),
+            size: entry.size,
+            mode: entry.mode,
+        };
+        entries.insert(rel.to_string(), existing);
+        Ok(())
+    })?;
+    Ok(entries)
+}
+
+/// Remove what stands where `rel` is written: anything but a directory
+/// above it, and anything but a regular file at it.
+fn clear_way(
+    root: &Root,
+    base: &str,
+    rel: &str,
+    existing: &BTreeMap<String, Existing>,
+    cleared: &mut BTreeSet<String>,
+) -> Result<(), (StatusCode, String)> {
+    let above = ancestors(rel).map(|dir| (dir, SFlag::S_IFDIR));
+    for (at, keep) in above.chain([(rel, SFlag::S_IFREG)]) {
+        let blocked = existing.get(at).is_some_and(|e| e.kind != keep);
+        if blocked && cleared.insert(at.to_string()) {
+            root.remove_all(Path::new(&join(base, at)))
+                .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e))?;
+        }
+    }
+    Ok(())
+}
+
+/// The directories `rel` is below, outermost first: `/a` and `/a/b` for
+/// `/a/b/c`.
+fn ancestors(rel: &str) -> impl Iterator<Item = &str> {
+    rel.match_indices('/').skip(1).map(move |(i, _)| &rel[..i])
+}
+
+/// Content sent for `file`, checked against its size and hash.
+fn decode(file: &SyncFile, content: &str) -> Result<Vec<u8>, (StatusCode, String)> {
+    let data = BASE64
+        .decode(content)
+        .map_err(|e| (StatusCode::BAD_REQUEST, format!("Invalid base64 for {}: {}", file.path, e)))?;
+    if data.len() as u64 != file.size || !hex::encode(Sha256::digest(&data)).eq_ignore_ascii_case(&file.sha256) {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            format!("content of {} doesn't match its size and sha256", file.path),
+        ));
+    }
+    Ok(data)
+}
+
+/// Refuse to write into the sandbox's own mounts or server-owned directories.
+fn check_target(path: &str) -> Result<(), (StatusCode, String)> {
+    let top = path.trim_start_matches('/').split('/').next().unwrap_or_default();
+    if sandbox::is_mounted_top_level(top) {
+        return Err((StatusCode::BAD_REQUEST, format!("{} is part of the sandbox itself", path)));
+    }
+    if below(TRASH_DIR, path).is_some() || below(UPLOADS_DIR, path).is_some() {
+        return Err((StatusCode::BAD_REQUEST, format!("{} is managed by the server", path)));
+    }
+    Ok(())
+}
+
+fn join(base: &str, rel: &str) -> String {
+    format!("{}{}", base.trim_end_matches('/'), rel)
+}
@@ -0,0 +1,359 @@
+//! Git operations (`/sessions/:id/git/*`), run with the host's `git` inside
+//! the sandbox as the session's user.
+//!
+//! Remotes that ask for a username and password are answered from a session
+//! secret by a credential helper given on the command line. The secret
+//! reaches git only through that command's environment; it is never written
+//! to the repository's config or put in a remote URL.
+
+use crate::backend::SandboxBackend;
+use crate::sandbox::{RunConfig, RunResult};
+use std::collections::HashMap;
+use std::path::Path;
+use std::time::Instant;
+
+pub use opencomputer_types::{
+    GitCloneRequest, GitCommitRequest, GitCredential, GitDiffQuery, GitDiffResult, GitPullRequest, GitResult,
+};
+
+/// Username sent with a credential that doesn't name one; GitHub and most
+/// other hosts accept any username with a token.
+pub const DEFAULT_USERNAME: &str = "x-access-token";
+
+/// Identity of commits in repositories that have none configured.
+const DEFAULT_AUTHOR_NAME: &str = "OpenSandbox";
+const DEFAULT_AUTHOR_EMAIL: &str = "sandbox@opensandbox.invalid";
+
+/// Answers git's `get` requests with the credential in the environment.
+const CREDENTIAL_HELPER: &str =
+    r#"!f() { test "$1" = get && echo "username=$OC_GIT_USERNAME" && echo "password=$OC_GIT_PASSWORD"; }; f"#;
+
+/// The tree of a repository without commits, for diffs before the first one.
+const EMPTY_TREE: &str = "4b825dc642cb6eb9a060e54bf8d69288fbee4904";
+
+/// Stage everything into a scratch index, so untracked files show up in the
+/// diff while the repository's own index stays as it was. `$1` is the base.
+const DIFF_SCRIPT: &str = r#"index=$(mktemp) || exit 1
+trap 'rm -f "$index"' EXIT
+cp "$(git rev-parse --git-path index)" "$index" 2>/dev/null || rm -f "$index"
+export GIT_INDEX_FILE="$index"
+git add -A && git diff --cached --binary --no-color --no-ext-diff --end-of-options "$1" --"#;
+
+/// Env that lets git answer auth prompts from `credential`, a session secret.
+pub fn credential_env(
+    credential: Option<&GitCredential>,
+    secrets: &HashMap<String, String>,
+) -> Result<HashMap<String, String>, String> {
+    let Some(credential) = credential else {
+        return Ok(HashMap::new());
+    };
+    let password = secrets
+        .get(&credential.secret)
+        .ok_or_else(|| format!("session has no secret {:?}", credential.secret))?;
+    let username = credential.username.as_deref().unwrap_or(DEFAULT_USERNAME);
+    Ok(HashMap::from([
+        ("OC_GIT_USERNAME".to_string(), username.to_string()),
+        ("OC_GIT_PASSWORD".to_string(), password.clone()),
+    ]))
+}
+
+/// Reject arguments git would take for options.
+fn check_arg(what: &str, value: &str) -> Result<(), String> {
+    if value.is_empty() || value.starts_with('-') {
+        return Err(format!("invalid {} {:?}", what, value));
+    }
+    Ok(())
+}
+
+pub fn validate_clone(req: &GitCloneRequest) -> Result<(), String> {
+    check_arg("url", &req.url)?;
+    if let Some(ref branch) = req.branch {
+        check_arg("branch", branch)?;
+    }
+    if req.depth == Some(0) {
+        return Err("depth must be at least 1".to_string());
+    }
+    Ok(())
+}
+
+pub fn validate_pull(req: &GitPullRequest) -> Result<(), String> {
+    if let Some(ref remote) = req.remote {
+        check_arg("remote", remote)?;
+    }
+    match req.branch {
+        Some(_) if req.remote.is_none() => Err("branch needs remote".to_string()),
+        Some(ref branch) => check_arg("branch", branch),
+        None => Ok(()),
+    }
+}
+
+pub fn validate_commit(req: &GitCommitRequest) -> Result<(), String> {
+    if req.message.trim().is_empty() {
+        return Err("message must not be empty".to_string());
+    }
+    Ok(())
+}
+
+pub fn validate_diff(query: &GitDiffQuery) -> Result<(), String> {
+    match query.base {
+        Some(ref base) => check_arg("base", base),
+        None => Ok(()),
+    }
+}
+
+/// Clone into `req.dir` (relative to `config.cwd`), or a directory named
+/// after the repository.
+pub fn clone(
+    backend: &dyn SandboxBackend,
+    sandbox_root: &Path,
+    config: &RunConfig,
+    req: &GitCloneRequest,
+    credential: HashMap<String, String>,
+) -> Result<GitResult, String> {
+    let started = Instant::now();
+    let dir = req.dir.clone().unwrap_or_else(|| humanish(&req.url));
+    let mut args = vec!["clone".to_string()];
+    if let Some(ref branch) = req.branch {
+        args.extend(["--branch".to_string(), branch.clone()]);
+    }
+    if let Some(depth) = req.depth {
+        args.push(format!("--depth={}", depth));
+    }
+    args.extend(["--".to_string(), req.url.clone(), dir.clone()]);
+    let run = git(backend, sandbox_root, config, args, credential)?;
+    let repo_config = RunConfig {
+        cwd: Path::new(&config.cwd).join(&dir).display().to_string(),
+        ..config.clone()
+    };
+    Ok(finish(backend, sandbox_root, &repo_config, run, started))
+}
+
+/// Fast-forward the repository at `config.cwd`; diverged branches fail
+/// rather than get a merge commit.
+pub fn pull(
+    backend: &dyn SandboxBackend,
+    sandbox_root: &Path,
+    config: &RunConfig,
+    req: &GitPullRequest,
+    credential: HashMap<String, String>,
+) -> Result<GitResult, String> {
+    let started = Instant::now();
+    let mut args = vec!["pull".to_string(), "--ff-only".to_string()];
+    args.extend(req.remote.iter().chain(req.branch.iter()).cloned());
+    let run = git(backend, sandbox_root, config, args, credential)?;
+    Ok(finish(backend, sandbox_root, config, run, started))
+}
+
+/// Stage `req.paths` (or everything) and commit in the repository at
+/// `config.cwd`.
+pub fn commit(
+    backend: &dyn SandboxBackend,
+    sandbox_root: &Path,
+    config: &RunConfig,
+    req: &GitCommitRequest,
+) -> Result<GitResult, String> {
+    let started = Instant::now();
+    let mut add = vec!["add".to_string(), "-A".to_string(), "--".to_string()];
+    add.extend(req.paths.iter().cloned());
+    let staged = git(backend, sandbox_root, config, add, HashMap::new())?;
+    if !staged.success() {
+        return Ok(finish(backend, sandbox_root, config, staged, started));
+    }
+
+    let mut identity = HashMap::new();
+    let (name, email) = match (&req.author_name, &req.author_email) {
+        (None, None) => {
+            let args = vec!["config".into(), "user.email".into()];
+            let configured = git(backend, sandbox_root, config, args, HashMap::new())?;
+            let fallback = !configured.success();
+            (fallback.then_some(DEFAULT_AUTHOR_NAME), fallback.then_some(DEFAULT_AUTHOR_EMAIL))
+        }
+        (name, email) => (name.as_deref(), email.as_deref()),
+    };
+    for (role, value) in [("NAME", name), ("EMAIL", email)] {
+        if let Some(value) = value {
+            identity.insert(format!("GIT_AUTHOR_{}", role), value.to_string());
+            identity.insert(format!("GIT_COMMITTER_{}", role), value.to_string());
+        }
+    }
+    let args = vec!["commit".to_string(), "-m".to_string(), req.message.clone()];
+    let run = git(backend, sandbox_root, config, args, identity)?;
+    Ok(finish(backend, sandbox_root, config, run, started))
+}
+
+/// Patch of the working tree at `config.cwd` against `base`, cut at
+/// `config.max_output_bytes`. `Ok(Err(stderr))` if git fails, e.g. outside
+/// a repository.
+pub fn diff(
+    backend: &dyn SandboxBackend,
+    sandbox_root: &Path,
+    config: &RunConfig,
+    base: Option<&str>,
+) -> Result<Result<GitDiffResult, String>, String> {
+    let base = match base {
+        Some(base) => base.to_string(),
+        None => head(backend, sandbox_root, config)?.unwrap_or_else(|| EMPTY_TREE.to_string()),
+    };
+    let script = RunConfig {
+        command: vec!["/bin/sh".into(), "-c".into(), DIFF_SCRIPT.into(), "git-diff".into(), base],
+        env: git_env(config, HashMap::new()),
+        ..config.clone()
+    };
+    let run = backend.run(sandbox_root, &script, None)?;
+    if !run.success() {
+        return Ok(Err(run.stderr));
+    }
+    Ok(Ok(GitDiffResult {
+        diff: run.stdout,
+        diff_bytes: run.stdout_bytes,
+        truncated: run.stdout_truncated,
+    }))
+}
+
+/// Fetch `git_ref` of `url` (the remote's `HEAD` if unset) without history
+/// into the empty directory `config.cwd` and check it out, for imports.
+/// `Ok(Ok(commit))` with what was checked out, `Ok(Err(stderr))` if git
+/// fails.
+pub fn fetch(
+    backend: &dyn SandboxBackend,
+    sandbox_root: &Path,
+    config: &RunConfig,
+    url: &str,
+    git_ref: Option<&str>,
+    credential: HashMap<String, String>,
+) -> Result<Result<String, String>, String> {
+    check_arg("url", url)?;
+    let git_ref = git_ref.unwrap_or("HEAD");
+    check_arg("ref", git_ref)?;
+    let steps = [
+        (vec!["init", "-q"], HashMap::new()),
+        (vec!["fetch", "-q", "--depth=1", "--", url, git_ref], credential),
+        (vec!["checkout", "-q", "--detach", "FETCH_HEAD"], HashMap::new()),
+    ];
+    for (args, env) in steps {
+        let run = git(backend, sandbox_root, config, args.into_iter().map(String::from).collect(), env)?;
+        if !run.success() {
+            return Ok(Err(run.stderr));
+        }
+    }
+    head(backend, sandbox_root, config)?
+        .map(Ok)
+        .ok_or_else(|| "nothing was checked out".to_string())
+}
+
+/// Whether `config.cwd` is in a work tree.
+pub fn is_repository(backend: &dyn SandboxBackend, sandbox_root: &Path, config: &RunConfig) -> Result<bool, String> {
+    let args = ["rev-parse", "--is-inside-work-tree"].map(String::from).to_vec();
+    let run = git(backend, sandbox_root, config, args, HashMap::new())?;
+    Ok(run.success() && run.stdout.trim() == "true")
+}
+
+/// What a patch export is against: `base`, else the branch's upstream, so
+/// local commits are in it, else `HEAD`, else the empty tree.
+pub fn export_base(
+    backend: &dyn SandboxBackend,
+    sandbox_root: &Path,
+    config: &RunConfig,
+    base: Option<&str>,
+) -> Result<String, String> {
+    if let Some(base) = base {
+        check_arg("base", base)?;
+        return Ok(base.to_string());
+    }
+    let args = ["rev-parse", "--verify", "--quiet", "@{upstream}"].map(String::from).to_vec();
+    let run = git(backend, sandbox_root, config, args, HashMap::new())?;
+    let upstream = run.success().then(|| run.stdout.trim().to_string()).filter(|c| !c.is_empty());
+    Ok(match upstream {
+        Some(upstream) => upstream,
+        None => head(backend, sandbox_root, config)?.unwrap_or_else(|| EMPTY_TREE.to_string()),
+    })
+}
+
+/// Write a bundle of the commits from `base` to `HEAD` to `path` in the
+/// sandbox. `Ok(Err(stderr))` if git fails, e.g. with nothing to bundle.
+pub fn bundle(
+    backend: &dyn SandboxBackend,
+    sandbox_root: &Path,
+    config: &RunConfig,
+    base: &str,
+    path: &str,
+) -> Result<Result<(), String>, String> {
+    let range = if base == EMPTY_TREE {
+        "HEAD".to_string()
+    } else {
+        format!("{}..HEAD", base)
+    };
+    let args = ["bundle", "create", "-q", path, &range].map(String::from).to_vec();
+    let run = git(backend, sandbox_root, config, args, HashMap::new())?;
+    Ok(if run.success() { Ok(()) } else { Err(run.stderr) })
+}
+
+fn finish(
+    backend: &dyn SandboxBackend,
+    sandbox_root: &Path,
+    config: &RunConfig,
+    run: RunResult,
+    started: Instant,
+) -> GitResult {
+    GitResult {
+        success: run.success(),
+        commit: head(backend, sandbox_root, config).ok().flatten(),
+        stdout: run.stdout,
+        stderr: run.stderr,
+        exit_code: run.exit_code,
+        duration_ms: started.elapsed().as_millis() as u64,
+    }
+}
+
+/// The commit `HEAD` points at, if the directory is a repository with one.
+fn head(backend: &dyn SandboxBackend, sandbox_root: &Path, config: &RunConfig) -> Result<Option<String>, String> {
+    let args = ["rev-parse", "--verify", "--quiet", "HEAD"].map(String::from).to_vec();
+    let run = git(backend, sandbox_root, config, args, HashMap::new())?;
+    Ok(run.success().then(|| run.stdout.trim().to_string()).filter(|c| !c.is_empty()))
+}
+
+/// Run `git args` in the sandbox; `extra_env` holds credentials or identity.
+fn git(
+    backend: &dyn SandboxBackend,
+    sandbox_root: &Path,
+    config: &RunConfig,
+    args: Vec<String>,
+    extra_env: HashMap<String, String>,
+) -> Result<RunResult, String> {
+    let mut command = vec!["git".to_string()];
+    if extra_env.contains_key("OC_GIT_PASSWORD") {
+        // Drop configured helpers so only the session's credential is offered
+        command.extend(["-c", "credential.helper=", "-c"].map(String::from));
+        command.push(format!("credential.helper={}", CREDENTIAL_HELPER));
+    }
+    command.extend(args);
+    let config = RunConfig {
+        command,
+        env: git_env(config, extra_env),
+        ..config.clone()
+    };
+    backend.run(sandbox_root, &config, None)
+}
+
+/// The session env plus settings that keep git from waiting on a terminal
+/// or a stalled remote.
+fn git_env(config: &RunConfig, extra_env: HashMap<String, String>) -> HashMap<String, String> {
+    let mut env = config.env.clone();
+    env.insert("GIT_TERMINAL_PROMPT".to_string(), "0".to_string());
+    env.entry("GIT_HTTP_LOW_SPEED_LIMIT".to_string()).or_insert_with(|| "1000".to_string());
+    env.entry("GIT_HTTP_LOW_SPEED_TIME".to_string()).or_insert_with(|| "60".to_string());
+    env.extend(extra_env);
+    env
+}
+
+/// The directory `git clone` would pick for `url`: its last path component
+/// without `.git`.
+pub(crate) fn humanish(url: &str) -> String {
+    let path = url.trim_end_matches('/').trim_end_matches("/.git");
+    let last = path.rsplit(['/', ':']).next().unwrap_or(path);
+    let name = last.strip_suffix(".git").unwrap_or(last);
+    match name {
+        "" | "." | ".." => "repo".to_string(),
+        name => name.to_string(),
+    }
+}
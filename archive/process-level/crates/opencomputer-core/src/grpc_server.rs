@@ -0,0 +1,522 @@
+//! gRPC server implementation using Tonic.
+
+use crate::auth::{self, ApiKey, ApiKeys};
+use crate::history::{self, HistorySource};
+use crate::hooks::{FileWrite, RunFinish, RunStart};
+use crate::lifecycle::{RunCompletion, SessionLifecycleEvent};
+use crate::replay;
+use crate::sandbox::{self, RunConfig};
+use crate::scope::Scope;
+use crate::secrets;
+use crate::state::AppState;
+use crate::timeline::{self, SessionEventKind};
+use crate::tls::{self, CertStore};
+use crate::trace_context::{RunTrace, TRACEPARENT_HEADER, TRACESTATE_HEADER};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Instant;
+use tonic::service::Interceptor;
+use tonic::{Request, Response, Status};
+use tracing::info;
+
+// Import generated protobuf types
+pub mod proto {
+    tonic::include_proto!("sandbox");
+}
+
+use proto::sandbox_service_server::{SandboxService, SandboxServiceServer};
+use proto::{
+    ReadFileRequest, ReadFileResponse, RunCommandRequest, RunCommandResponse,
+    SetCwdRequest, SetCwdResponse, SetEnvRequest, SetEnvResponse,
+    WriteFileRequest, WriteFileResponse,
+    WriteFilesRequest, WriteFilesResponse, FileError,
+};
+
+/// gRPC service implementation.
+pub struct SandboxServiceImpl {
+    state: AppState,
+}
+
+impl SandboxServiceImpl {
+    pub fn new(state: AppState) -> Self {
+        Self { state }
+    }
+
+    /// Resolve the caller's key (an API key, or a session token the
+    /// interceptor couldn't check) and require `scope` of it.
+    async fn caller<T>(&self, request: &Request<T>, scope: Scope) -> Result<Option<Arc<ApiKey>>, Status> {
+        let key = match request.extensions().get::<PresentedToken>() {
+            Some(PresentedToken(token)) => {
+                Some(self.state.lookup_token(token).await.map_err(Status::unauthenticated)?)
+            }
+            None => request.extensions().get::<Arc<ApiKey>>().cloned(),
+        };
+        if let Some(ref key) = key {
+            if !key.allows(scope) {
+                return Err(Status::permission_denied(format!("API key lacks scope {}", scope)));
+            }
+        }
+        Ok(key)
+    }
+}
+
+#[tonic::async_trait]
+impl SandboxService for SandboxServiceImpl {
+    async fn run_command(
+        &self,
+        request: Request<RunCommandRequest>,
+    ) -> Result<Response<RunCommandResponse>, Status> {
+        let key = self.caller(&request, Scope::ExecRun).await?;
+        let metadata = |name| request.metadata().get(name).and_then(|v| v.to_str().ok());
+        let trace = RunTrace::from_traceparent(metadata(TRACEPARENT_HEADER), metadata(TRACESTATE_HEADER));
+        let mut req = request.into_inner();
+        // The command may hold session secrets; the sandbox logs it masked
+        info!("gRPC RunCommand: session={}", req.session_id);
+        self.state
+            .env_policy
+            .apply(&mut req.env)
+            .map_err(Status::invalid_argument)?;
+        if req.max_output_bytes > sandbox::MAX_OUTPUT_BYTES {
+            return Err(Status::invalid_argument(format!(
+                "max_output_bytes must be at most {}",
+                sandbox::MAX_OUTPUT_BYTES
+            )));
+        }
+        if req.kill_grace_ms > sandbox::MAX_KILL_GRACE_MS {
+            return Err(Status::invalid_argument(format!(
+                "kill_grace_ms must be at most {}",
+                sandbox::MAX_KILL_GRACE_MS
+            )));
+        }
+        self.state
+            .check_org_quota(key.as_deref())
+            .await
+            .map_err(Status::resource_exhausted)?;
+
+        // Get session info
+        let (sandbox_root, mut env, secret_values, cwd, determinism, event) = {
+            let mut sessions = self.state.sessions.write().await;
+            let session = sessions
+                .get_mut(&req.session_id)
+                .filter(|s| s.visible_to(key.as_deref()))
+                .ok_or_else(|| Status::not_found("Session not found"))?;
+            if session.status.is_suspended() {
+                return Err(Status::failed_precondition(format!("Session is {}", session.status)));
+            }
+            session.last_used = Instant::now();
+            (
+                session.sandbox_root.clone(),
+                session.process_env(),
+                session.secret_values(),
+                session.cwd.clone(),
+                Some(session.determinism.clone()),
+                SessionLifecycleEvent::from_session(session),
+            )
+        };
+
+        // Merge request env with session env
+        for name in &req.unset_env {
+            env.remove(name);
+        }
+        env.extend(req.env);
+        let cwd = if !req.cwd.is_empty() && req.cwd != "/" { req.cwd } else { cwd };
+        sandbox::confine_cwd(&sandbox_root, &cwd).map_err(Status::invalid_argument)?;
+        let run = RunStart {
+            session_id: Some(req.session_id.clone()),
+            command: secrets::redact_all(&req.command, &secret_values),
+            cwd: cwd.clone(),
+            background: false,
+        };
+        self.state
+            .check_run_start(key.as_deref(), &run)
+            .map_err(Status::permission_denied)?;
+        trace.inject(&mut env);
+        info!(trace_id = %trace.trace_id, span_id = %trace.span_id, "Run trace");
+
+        let config = RunConfig {
+            command: req.command,
+            time_ms: if req.time_ms > 0 { req.time_ms } else { 300000 },
+            mem_kb: if req.mem_kb > 0 { req.mem_kb } else { 2097152 },
+            fsize_kb: if req.fsize_kb > 0 { req.fsize_kb } else { 1048576 },
+            nofile: if req.nofile > 0 { req.nofile } else { 256 },
+            nproc: if req.pids > 0 { req.pids } else { sandbox::DEFAULT_NPROC },
+            env,
+            cwd,
+            commit_on_success: req.commit_on_success,
+            determinism,
+            stdin: (!req.stdin.is_empty()).then_some(req.stdin),
+            max_output_bytes: if req.max_output_bytes > 0 {
+                req.max_output_bytes
+            } else {
+                sandbox::DEFAULT_MAX_OUTPUT_BYTES
+            },
+            kill_grace_ms: if req.kill_grace_ms > 0 {
+                req.kill_grace_ms
+            } else {
+                sandbox::DEFAULT_KILL_GRACE_MS
+            },
+            secrets: secret_values,
+        };
+
+        let permit = self
+            .state
+            .run_limits
+            .acquire(Some(&req.session_id))
+            .await
+            .map_err(Status::resource_exhausted)?
+            .with_activity(self.state.activity.run(&req.session_id));
+        let record = req.record;
+        let command = secrets::redact_all(&config.command, &config.secrets);
+        let session_id = req.session_id.clone();
+        self.state.timeline.record(&session_id, SessionEventKind::RunStarted { command: command.clone() });
+        let started = Instant::now();
+        let cwd = config.cwd.clone();
+        let backend = self.state.backend.clone();
+        let egress = self.state.egress.clone();
+        let recorded_session = session_id.clone();
+        let org_id = key.as_ref().map(|key| key.org().to_string());
+        let mut result = tokio::task::spawn_blocking(move || {
+            let _permit = permit;
+            if record {
+                let (mut result, replay_id) = replay::record_run(
+                    &*backend,
+                    &sandbox_root,
+                    &config,
+                    &egress,
+                    &recorded_session,
+                    org_id.as_deref(),
+                )?;
+                result.replay_id = Some(replay_id);
+                Ok(result)
+            } else {
+                backend.run(&sandbox_root, &config, None)
+            }
+        })
+        .await
+        .map_err(|e| Status::internal(e.to_string()))?
+        .map_err(|e| {
+            if e.starts_with(sandbox::PIDS_LIMIT_HIT) {
+                Status::resource_exhausted(e)
+            } else {
+                Status::internal(e)
+            }
+        })?;
+        self.state.record_cpu(key.as_deref(), result.cpu_time);
+        result.trace_id = Some(trace.trace_id.clone());
+
+        let completion = RunCompletion {
+            command,
+            exit_code: result.exit_code,
+            signal: result.signal,
+            duration_ms: started.elapsed().as_millis() as u64,
+        };
+        self.state.timeline.record(&session_id, timeline::run_finished(&completion));
+        self.state.notify_run_complete(&event, &completion);
+        self.state
+            .notify_run_finish(key.as_deref(), &RunFinish::new(Some(&session_id), &completion, result.cpu_time));
+        self.state.record_history(
+            &session_id,
+            history::Command {
+                source: HistorySource::Grpc,
+                completion: &completion,
+                cwd: &cwd,
+                result: &result,
+            },
+        );
+        Ok(Response::new(RunCommandResponse {
+            stdout: result.stdout,
+            stderr: result.stderr,
+            stdout_bytes: result.stdout_bytes,
+            stderr_bytes: result.stderr_bytes,
+            stdout_truncated: result.stdout_truncated,
+            stderr_truncated: result.stderr_truncated,
+            wall_time_ms: result.wall_time_ms,
+            user_time_ms: result.user_time_ms,
+            sys_time_ms: result.sys_time_ms,
+            max_rss_kb: result.max_rss_kb,
+            limit_exceeded: result.limit_exceeded.map(|l| l.as_str().to_string()).unwrap_or_default(),
+            exit_code: result.exit_code.unwrap_or(0),
+            signal: result.signal.unwrap_or(0),
+            committed: result.committed.unwrap_or(false),
+            replay_id: result.replay_id.unwrap_or_default(),
+            trace_id: trace.trace_id,
+        }))
+    }
+
+    async fn write_file(
+        &self,
+        request: Request<WriteFileRequest>,
+    ) -> Result<Response<WriteFileResponse>, Status> {
+        let key = self.caller(&request, Scope::FilesWrite).await?;
+        let req = request.into_inner();
+        info!("gRPC WriteFile: session={}, path={}", req.session_id, req.path);
+
+        // Get sandbox root
+        let sandbox_root = {
+            let mut sessions = self.state.sessions.write().await;
+            let session = sessions
+                .get_mut(&req.session_id)
+                .filter(|s| s.visible_to(key.as_deref()))
+                .ok_or_else(|| Status::not_found("Session not found"))?;
+            session.last_used = Instant::now();
+            session.sandbox_root.clone()
+        };
+        let write = FileWrite {
+            session_id: &req.session_id,
+            path: &req.path,
+            content: Some(&req.content),
+        };
+        self.state
+            .check_file_write(key.as_deref(), &write)
+            .map_err(Status::permission_denied)?;
+
+        // Write file directly (no shell command needed)
+        let path = req.path;
+        let content = req.content;
+        let backend = self.state.backend.clone();
+        let result = tokio::task::spawn_blocking(move || {
+            backend.write_file(&sandbox_root, &path, &content)
+        })
+        .await
+        .map_err(|e| Status::internal(e.to_string()))?;
+
+        match result {
+            Ok(()) => Ok(Response::new(WriteFileResponse {
+                success: true,
+                error: String::new(),
+            })),
+            Err(e) => Ok(Response::new(WriteFileResponse {
+                success: false,
+                error: e,
+            })),
+        }
+    }
+
+    async fn write_files(
+        &self,
+        request: Request<WriteFilesRequest>,
+    ) -> Result<Response<WriteFilesResponse>, Status> {
+        let key = self.caller(&request, Scope::FilesWrite).await?;
+        let req = request.into_inner();
+        info!("gRPC WriteFiles: session={}, count={}", req.session_id, req.files.len());
+
+        // Get sandbox root
+        let sandbox_root = {
+            let mut sessions = self.state.sessions.write().await;
+            let session = sessions
+                .get_mut(&req.session_id)
+                .filter(|s| s.visible_to(key.as_deref()))
+                .ok_or_else(|| Status::not_found("Session not found"))?;
+            session.last_used = Instant::now();
+            session.sandbox_root.clone()
+        };
+        for file in &req.files {
+            let write = FileWrite {
+                session_id: &req.session_id,
+                path: &file.path,
+                content: Some(&file.content),
+            };
+            self.state
+                .check_file_write(key.as_deref(), &write)
+                .map_err(Status::permission_denied)?;
+        }
+
+        // Collect files for the blocking task
+        let files: Vec<(String, Vec<u8>)> = req
+            .files
+            .into_iter()
+            .map(|f| (f.path, f.content))
+            .collect();
+
+        let backend = self.state.backend.clone();
+        let errors = tokio::task::spawn_blocking(move || {
+            let mut errors = Vec::new();
+            for (path, content) in &files {
+                if let Err(e) = backend.write_file(&sandbox_root, path, content) {
+                    errors.push(FileError {
+                        path: path.clone(),
+                        error: e,
+                    });
+                }
+            }
+            errors
+        })
+        .await
+        .map_err(|e| Status::internal(e.to_string()))?;
+
+        Ok(Response::new(WriteFilesResponse {
+            success: errors.is_empty(),
+            errors,
+        }))
+    }
+
+    async fn read_file(
+        &self,
+        request: Request<ReadFileRequest>,
+    ) -> Result<Response<ReadFileResponse>, Status> {
+        let key = self.caller(&request, Scope::FilesRead).await?;
+        let req = request.into_inner();
+        info!("gRPC ReadFile: session={}, path={}", req.session_id, req.path);
+
+        // Get sandbox root
+        let sandbox_root = {
+            let mut sessions = self.state.sessions.write().await;
+            let session = sessions
+                .get_mut(&req.session_id)
+                .filter(|s| s.visible_to(key.as_deref()))
+                .ok_or_else(|| Status::not_found("Session not found"))?;
+            session.last_used = Instant::now();
+            session.sandbox_root.clone()
+        };
+
+        // Read file directly (no shell command needed)
+        let path = req.path;
+        let backend = self.state.backend.clone();
+        let result = tokio::task::spawn_blocking(move || {
+            backend.read_file(&sandbox_root, &path)
+        })
+        .await
+        .map_err(|e| Status::internal(e.to_string()))?;
+
+        match result {
+            Ok(content) => Ok(Response::new(ReadFileResponse {
+                content,
+                error: String::new(),
+            })),
+            Err(e) => Ok(Response::new(ReadFileResponse {
+                content: Vec::new(),
+                error: e,
+            })),
+        }
+    }
+
+    async fn set_env(
+        &self,
+        request: Request<SetEnvRequest>,
+    ) -> Result<Response<SetEnvResponse>, Status> {
+        let key = self.caller(&request, Scope::SessionsWrite).await?;
+        let mut req = request.into_inner();
+        info!("gRPC SetEnv: session={}", req.session_id);
+        self.state
+            .env_policy
+            .apply(&mut req.env)
+            .map_err(Status::invalid_argument)?;
+
+        let mut sessions = self.state.sessions.write().await;
+        let session = sessions
+            .get_mut(&req.session_id)
+            .filter(|s| s.visible_to(key.as_deref()))
+            .ok_or_else(|| Status::not_found("Session not found"))?;
+        if req.replace {
+            session.env = req.env;
+        } else {
+            session.env.extend(req.env);
+        }
+        for name in &req.unset {
+            session.env.remove(name);
+        }
+        session.last_used = Instant::now();
+        self.state.persist_session(session);
+
+        Ok(Response::new(SetEnvResponse { success: true }))
+    }
+
+    async fn set_cwd(
+        &self,
+        request: Request<SetCwdRequest>,
+    ) -> Result<Response<SetCwdResponse>, Status> {
+        let key = self.caller(&request, Scope::SessionsWrite).await?;
+        let req = request.into_inner();
+        info!("gRPC SetCwd: session={}, cwd={}", req.session_id, req.cwd);
+
+        let mut sessions = self.state.sessions.write().await;
+        let session = sessions
+            .get_mut(&req.session_id)
+            .filter(|s| s.visible_to(key.as_deref()))
+            .ok_or_else(|| Status::not_found("Session not found"))?;
+        sandbox::confine_cwd(&session.sandbox_root, &req.cwd).map_err(Status::invalid_argument)?;
+        session.cwd = req.cwd;
+        session.last_used = Instant::now();
+        self.state.persist_session(session);
+
+        Ok(Response::new(SetCwdResponse { success: true }))
+    }
+}
+
+/// Credential that isn't a configured API key, left for
+/// [`SandboxServiceImpl::caller`] to check as a JWT or session token.
+#[derive(Clone)]
+struct PresentedToken(String);
+
+/// Checks the same API keys as the HTTP API, read from `authorization: Bearer`
+/// or `x-api-key` metadata.
+#[derive(Clone)]
+struct ApiKeyInterceptor {
+    api_keys: Arc<ApiKeys>,
+}
+
+impl Interceptor for ApiKeyInterceptor {
+    fn call(&mut self, mut req: Request<()>) -> Result<Request<()>, Status> {
+        if !self.api_keys.is_enabled() {
+            return Ok(req);
+        }
+        let md = req.metadata();
+        let presented = md
+            .get("authorization")
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.strip_prefix("Bearer "))
+            .or_else(|| md.get(auth::API_KEY_HEADER).and_then(|v| v.to_str().ok()))
+            .map(str::trim);
+        let Some(presented) = presented else {
+            return Err(Status::unauthenticated("Missing or invalid API key"));
+        };
+        match self.api_keys.lookup(presented) {
+            Some(key) => {
+                // Handlers scope sessions to the key's org
+                req.extensions_mut().insert(key);
+                Ok(req)
+            }
+            // Session tokens live on their session; handlers resolve them
+            None => {
+                let token = PresentedToken(presented.to_string());
+                req.extensions_mut().insert(token);
+                Ok(req)
+            }
+        }
+    }
+}
+
+/// Run the gRPC server on the given port with the provided state.
+pub async fn run_server(addr: SocketAddr, state: AppState) {
+    info!("Starting gRPC server on {}", addr);
+
+    let api_keys = state.api_keys.clone();
+    let service = SandboxServiceImpl::new(state);
+
+    tonic::transport::Server::builder()
+        .add_service(SandboxServiceServer::with_interceptor(
+            service,
+            ApiKeyInterceptor { api_keys },
+        ))
+        .serve(addr)
+        .await
+        .unwrap();
+}
+
+/// Like [`run_server`], but over TLS with the certificate in `certs`.
+pub async fn run_server_tls(addr: SocketAddr, state: AppState, certs: Arc<CertStore>) {
+    info!("Starting gRPC server on {} (TLS)", addr);
+
+    let api_keys = state.api_keys.clone();
+    let service = SandboxServiceImpl::new(state);
+    let listener = tokio::net::TcpListener::bind(addr).await.unwrap();
+
+    tonic::transport::Server::builder()
+        .add_service(SandboxServiceServer::with_interceptor(
+            service,
+            ApiKeyInterceptor { api_keys },
+        ))
+        .serve_with_incoming(tls::incoming(listener, certs))
+        .await
+        .unwrap();
+}
@@ -0,0 +1,105 @@
+//! Dependency checks behind `GET /healthz` and `GET /readyz`.
+//!
+//! `/health` only shows the server answers. These check what it needs to do
+//! useful work: free space on the filesystem sandboxes live on, a blocking
+//! pool that still picks up work (every sandbox operation runs on it), room
+//! under `max_sessions`, and not draining (`POST /admin/drain`). Liveness fails only on a stuck blocking pool,
+//! which a restart fixes; readiness fails on any check, so an orchestrator
+//! stops sending new sessions here until it passes again.
+
+use crate::reservation::SANDBOX_FS;
+use crate::state::{AppState, FullPolicy};
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+pub use opencomputer_types::{HealthCheck, HealthReport};
+
+/// Less free space than this on the sandbox filesystem fails readiness.
+pub const MIN_FREE_DISK_MB: u64 = 512;
+
+/// A no-op blocking task that hasn't started after this long means the pool
+/// is saturated.
+pub const BLOCKING_PROBE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Checks a restart would fix.
+pub async fn liveness() -> HealthReport {
+    report("Liveness", vec![blocking_pool().await])
+}
+
+/// Every check.
+pub async fn readiness(state: &AppState) -> HealthReport {
+    report("Readiness", vec![disk(), blocking_pool().await, sessions(state).await, draining(state)])
+}
+
+fn report(probe: &str, checks: Vec<HealthCheck>) -> HealthReport {
+    let healthy = checks.iter().all(|c| c.ok);
+    if !healthy {
+        let failed: Vec<&str> = checks.iter().filter(|c| !c.ok).map(|c| c.detail.as_str()).collect();
+        tracing::warn!("{} check failed: {}", probe, failed.join("; "));
+    }
+    HealthReport { healthy, checks }
+}
+
+fn disk() -> HealthCheck {
+    let (ok, detail) = match nix::sys::statvfs::statvfs(Path::new(SANDBOX_FS)) {
+        Ok(st) => {
+            let free_mb = st.blocks_available() * st.fragment_size() / (1024 * 1024);
+            let total_mb = st.blocks() * st.fragment_size() / (1024 * 1024);
+            (
+                free_mb >= MIN_FREE_DISK_MB,
+                format!("{} MB free of {} MB on {}", free_mb, total_mb, SANDBOX_FS),
+            )
+        }
+        Err(e) => (false, format!("statvfs {}: {}", SANDBOX_FS, e)),
+    };
+    HealthCheck {
+        name: "disk".to_string(),
+        ok,
+        detail,
+    }
+}
+
+async fn blocking_pool() -> HealthCheck {
+    let queued = Instant::now();
+    let probe = tokio::task::spawn_blocking(move || queued.elapsed());
+    let started = tokio::time::timeout(BLOCKING_PROBE_TIMEOUT, probe).await;
+    let (ok, detail) = match started {
+        Ok(Ok(waited)) => (true, format!("a blocking task started after {} ms", waited.as_millis())),
+        Ok(Err(e)) => (false, format!("blocking probe failed: {}", e)),
+        Err(_) => (
+            false,
+            format!("a blocking task hasn't started after {} ms", BLOCKING_PROBE_TIMEOUT.as_millis()),
+        ),
+    };
+    HealthCheck {
+        name: "blocking_pool".to_string(),
+        ok,
+        detail,
+    }
+}
+
+fn draining(state: &AppState) -> HealthCheck {
+    let ok = !state.is_draining();
+    HealthCheck {
+        name: "draining".to_string(),
+        ok,
+        detail: if ok { "taking new sessions" } else { "draining; not taking new sessions" }.to_string(),
+    }
+}
+
+async fn sessions(state: &AppState) -> HealthCheck {
+    let live = state.sessions.read().await.len();
+    let (ok, detail) = match state.max_sessions {
+        // Evicting makes room, so a full server still takes creates
+        Some(max) if live >= max && state.full_policy == FullPolicy::Reject => {
+            (false, format!("{} of {} sessions live", live, max))
+        }
+        Some(max) => (true, format!("{} of {} sessions live", live, max)),
+        None => (true, format!("{} sessions live, no limit", live)),
+    };
+    HealthCheck {
+        name: "sessions".to_string(),
+        ok,
+        detail,
+    }
+}
@@ -0,0 +1,144 @@
+//! Hibernation of a session's background processes with CRIU.
+//!
+//! Hibernating dumps every background process tree the session tracks with
+//! `criu dump` to `{HIBERNATION_DIR}/{session}/{pid}`, and CRIU kills each
+//! tree once its memory, open files and sockets are on disk. Waking runs
+//! `criu restore` on every dump, which brings the trees back under their old
+//! PIDs, so a dev server resumes with its state instead of starting cold.
+//! The sandbox's files stay in its root the whole time, so they aren't part
+//! of the dump.
+//!
+//! Background processes share the server's session and process group and are
+//! chrooted in the host's mount and network namespaces, which is what CRIU's
+//! `--shell-job` dumps and restores without help. Sessions behind an egress
+//! proxy live in a network namespace of their own and can't be hibernated.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+/// Where dumps are kept until the session wakes or is deleted.
+pub const HIBERNATION_DIR: &str = "/tmp/opensandbox-hibernation";
+
+const CRIU: &str = "criu";
+
+/// CRIU's log in each dump directory, quoted when a dump or restore fails.
+const LOG_FILE: &str = "criu.log";
+
+/// How many lines of the log a failure quotes.
+const LOG_TAIL_LINES: usize = 20;
+
+/// Options dump and restore must agree on: the trees belong to the server's
+/// session, may hold established TCP connections (a dev server's open
+/// websockets) and unix sockets to processes outside the tree, and may lock
+/// files.
+const SHARED_OPTIONS: &[&str] = &["--shell-job", "--tcp-established", "--ext-unix-sk", "--file-locks"];
+
+/// Whether CRIU is installed.
+pub fn available() -> bool {
+    Command::new(CRIU)
+        .arg("--version")
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .is_ok_and(|status| status.success())
+}
+
+fn session_dir(session_id: &str) -> PathBuf {
+    Path::new(HIBERNATION_DIR).join(session_id)
+}
+
+/// Dump and kill the process trees rooted at `pids`, skipping those that
+/// have already exited. Returns the PIDs dumped. If any dump fails, the
+/// trees dumped before it are restored and nothing is kept.
+pub fn dump(session_id: &str, pids: &[u32]) -> Result<Vec<u32>, String> {
+    let dir = session_dir(session_id);
+    fs::create_dir_all(&dir).map_err(|e| format!("create {}: {}", dir.display(), e))?;
+    let mut dumped: Vec<u32> = Vec::new();
+    for &pid in pids {
+        if !crate::sandbox::is_process_alive(pid) {
+            continue;
+        }
+        let images = dir.join(pid.to_string());
+        let result = fs::create_dir_all(&images)
+            .map_err(|e| format!("create {}: {}", images.display(), e))
+            .and_then(|_| criu(&images, &["dump", "--tree", &pid.to_string()]))
+            .map_err(|e| format!("dump pid {}: {}", pid, e));
+        if let Err(e) = result {
+            let _ = fs::remove_dir_all(&images);
+            for &pid in &dumped {
+                if let Err(e) = restore_tree(&dir.join(pid.to_string())) {
+                    tracing::warn!("Failed to restore pid {} after a failed hibernation: {}", pid, e);
+                }
+            }
+            let _ = fs::remove_dir_all(&dir);
+            return Err(e);
+        }
+        dumped.push(pid);
+    }
+    Ok(dumped)
+}
+
+/// Restore every tree dumped for the session and remove the dumps. Returns
+/// the restored PIDs. A tree that fails to restore keeps its dump (and so
+/// do the ones after it), so waking can be retried.
+pub fn restore(session_id: &str) -> Result<Vec<u32>, String> {
+    let dir = session_dir(session_id);
+    let entries = match fs::read_dir(&dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(format!("read {}: {}", dir.display(), e)),
+    };
+    let mut trees: Vec<PathBuf> = entries.flatten().map(|e| e.path()).filter(|p| p.is_dir()).collect();
+    trees.sort();
+    let mut restored = Vec::new();
+    for images in trees {
+        restored.push(restore_tree(&images)?);
+        let _ = fs::remove_dir_all(&images);
+    }
+    let _ = fs::remove_dir_all(&dir);
+    Ok(restored)
+}
+
+/// Drop a session's dumps without restoring them.
+pub fn remove(session_id: &str) {
+    let _ = fs::remove_dir_all(session_dir(session_id));
+}
+
+/// Restore one tree, detached from CRIU, and return its root's PID.
+fn restore_tree(images: &Path) -> Result<u32, String> {
+    let pidfile = images.join("restored.pid");
+    let pidfile_arg = pidfile.to_string_lossy().into_owned();
+    criu(images, &["restore", "--restore-detached", "--pidfile", &pidfile_arg])
+        .map_err(|e| format!("restore {}: {}", images.display(), e))?;
+    let pid = fs::read_to_string(&pidfile).map_err(|e| format!("read {}: {}", pidfile.display(), e))?;
+    pid.trim()
+        .parse()
+        .map_err(|_| format!("{} holds {:?}, not a PID", pidfile.display(), pid.trim()))
+}
+
+/// Run a CRIU action against the image directory `images`, quoting the end
+/// of its log if it fails.
+fn criu(images: &Path, args: &[&str]) -> Result<(), String> {
+    let output = Command::new(CRIU)
+        .args(args)
+        .arg("--images-dir")
+        .arg(images)
+        .args(SHARED_OPTIONS)
+        .args(["--log-file", LOG_FILE, "-v2"])
+        .stdin(Stdio::null())
+        .output()
+        .map_err(|e| format!("run {}: {}", CRIU, e))?;
+    if output.status.success() {
+        return Ok(());
+    }
+    let log = fs::read_to_string(images.join(LOG_FILE)).unwrap_or_default();
+    let log_lines: Vec<&str> = log.lines().collect();
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    let mut message = format!("{} {}", CRIU, output.status);
+    for line in stderr.lines().chain(log_lines[log_lines.len().saturating_sub(LOG_TAIL_LINES)..].iter().copied()) {
+        message.push('\n');
+        message.push_str(line);
+    }
+    Err(message)
+}
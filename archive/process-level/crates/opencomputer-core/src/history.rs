@@ -0,0 +1,227 @@
+//! Every command a session ran, for `GET /sessions/:id/history`.
+//!
+//! Unlike the event timeline, which keeps a session's last events in memory,
+//! history is written to disk as commands finish and lasts as long as the
+//! session, server restarts included. Each session has a directory under
+//! [`HISTORY_DIR`] holding `history.jsonl`, one entry per line, and the
+//! output of its last [`OUTPUT_KEPT`] commands, the first
+//! [`MAX_OUTPUT_BYTES`] of each stream. Commands and output are stored with
+//! secrets already masked.
+
+use crate::lifecycle::RunCompletion;
+use crate::sandbox::RunResult;
+use std::collections::HashMap;
+use std::fs::{self, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+pub use opencomputer_types::{HistoryEntry, HistoryOutput, HistoryPage, HistoryQuery, HistorySource};
+
+pub const HISTORY_DIR: &str = "/tmp/opensandbox-history";
+
+/// Bytes of stdout and of stderr kept per command.
+pub const MAX_OUTPUT_BYTES: usize = 64 * 1024;
+
+/// Commands whose output is kept; older ones keep only their entry.
+pub const OUTPUT_KEPT: u64 = 1000;
+
+/// Entries returned per page by default, and at most.
+pub const DEFAULT_PAGE: usize = 100;
+pub const MAX_PAGE: usize = 1000;
+
+const LOG_FILE: &str = "history.jsonl";
+
+/// Histories of all sessions.
+#[derive(Default)]
+pub struct History {
+    /// Next sequence number by session, read from disk on first use
+    next_seq: Mutex<HashMap<String, u64>>,
+}
+
+/// A finished command, as its caller knows it.
+pub struct Command<'a> {
+    pub source: HistorySource,
+    pub completion: &'a RunCompletion,
+    pub cwd: &'a str,
+    pub result: &'a RunResult,
+}
+
+impl History {
+    /// Start a session's history, if it has none yet (e.g. a session
+    /// restored from an older server).
+    pub fn open(&self, session_id: &str) -> Result<(), String> {
+        let dir = session_dir(session_id)?;
+        fs::create_dir_all(&dir).map_err(|e| format!("mkdir {}: {}", dir.display(), e))
+    }
+
+    /// Append a finished command to a session's history. Ignored for
+    /// sessions without one, such as one deleted while a job ran.
+    pub fn record(&self, session_id: &str, command: Command) -> Result<(), String> {
+        let dir = session_dir(session_id)?;
+        // Held while appending, so entries are written in sequence order
+        let mut next_seq = self.next_seq.lock().unwrap_or_else(|e| e.into_inner());
+        if !dir.is_dir() {
+            return Ok(());
+        }
+        let seq = match next_seq.get(session_id) {
+            Some(&seq) => seq,
+            None => next_seq_on_disk(&dir)?,
+        };
+
+        let RunCompletion {
+            command: ref argv,
+            exit_code,
+            signal,
+            duration_ms,
+        } = *command.completion;
+        let result = command.result;
+        let (stdout, stdout_cut) = truncate(&result.stdout);
+        let (stderr, stderr_cut) = truncate(&result.stderr);
+        let output = HistoryOutput {
+            seq,
+            stdout: stdout.to_string(),
+            stderr: stderr.to_string(),
+            stdout_truncated: stdout_cut || result.stdout_truncated,
+            stderr_truncated: stderr_cut || result.stderr_truncated,
+        };
+        let output_path = dir.join(format!("{}.json", seq));
+        let output_json = serde_json::to_vec(&output).map_err(|e| e.to_string())?;
+        fs::write(&output_path, output_json).map_err(|e| format!("write {}: {}", output_path.display(), e))?;
+        if let Some(old) = seq.checked_sub(OUTPUT_KEPT) {
+            let _ = fs::remove_file(dir.join(format!("{}.json", old)));
+        }
+
+        let finished = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        let entry = HistoryEntry {
+            seq,
+            source: command.source,
+            command: argv.clone(),
+            cwd: command.cwd.to_string(),
+            exit_code,
+            signal,
+            started_at_ms: finished.saturating_sub(Duration::from_millis(duration_ms)).as_millis() as u64,
+            duration_ms,
+            stdout_bytes: result.stdout_bytes,
+            stderr_bytes: result.stderr_bytes,
+            output_truncated: output.stdout_truncated || output.stderr_truncated,
+            output: None,
+            trace_id: result.trace_id.clone(),
+        };
+        let mut line = serde_json::to_vec(&entry).map_err(|e| e.to_string())?;
+        line.push(b'\n');
+        let log = dir.join(LOG_FILE);
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&log)
+            .and_then(|mut f| f.write_all(&line))
+            .map_err(|e| format!("write {}: {}", log.display(), e))?;
+        next_seq.insert(session_id.to_string(), seq + 1);
+        Ok(())
+    }
+
+    /// A page of a session's history, oldest first.
+    pub fn page(&self, session_id: &str, query: &HistoryQuery) -> Result<HistoryPage, String> {
+        let dir = session_dir(session_id)?;
+        let limit = query.limit.unwrap_or(DEFAULT_PAGE).clamp(1, MAX_PAGE);
+        let next_seq = self.next_seq(session_id, &dir)?;
+        let mut entries = Vec::new();
+        let mut more = false;
+        for mut entry in read_entries(&dir)? {
+            if query.after.is_some_and(|after| entry.seq <= after) {
+                continue;
+            }
+            if entries.len() == limit {
+                more = true;
+                break;
+            }
+            if entry.seq + OUTPUT_KEPT >= next_seq {
+                entry.output = Some(format!("/sessions/{}/history/{}/output", session_id, entry.seq));
+            }
+            entries.push(entry);
+        }
+        let next_after = if more { entries.last().map(|e| e.seq) } else { None };
+        Ok(HistoryPage { entries, next_after })
+    }
+
+    /// What command `seq` printed, if it's still kept.
+    pub fn output(&self, session_id: &str, seq: u64) -> Result<Option<HistoryOutput>, String> {
+        let path = session_dir(session_id)?.join(format!("{}.json", seq));
+        match fs::read(&path) {
+            Ok(data) => serde_json::from_slice(&data)
+                .map(Some)
+                .map_err(|e| format!("parse {}: {}", path.display(), e)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(format!("read {}: {}", path.display(), e)),
+        }
+    }
+
+    /// Delete a session's history once the session is gone.
+    pub fn remove(&self, session_id: &str) {
+        let mut next_seq = self.next_seq.lock().unwrap_or_else(|e| e.into_inner());
+        next_seq.remove(session_id);
+        if let Ok(dir) = session_dir(session_id) {
+            let _ = fs::remove_dir_all(dir);
+        }
+    }
+
+    /// Sessions with a history on disk.
+    pub fn sessions(&self) -> Vec<String> {
+        let Ok(dir) = fs::read_dir(HISTORY_DIR) else {
+            return Vec::new();
+        };
+        dir.filter_map(|e| e.ok()?.file_name().into_string().ok()).collect()
+    }
+
+    fn next_seq(&self, session_id: &str, dir: &Path) -> Result<u64, String> {
+        let mut next_seq = self.next_seq.lock().unwrap_or_else(|e| e.into_inner());
+        match next_seq.get(session_id) {
+            Some(&seq) => Ok(seq),
+            None => {
+                let seq = next_seq_on_disk(dir)?;
+                next_seq.insert(session_id.to_string(), seq);
+                Ok(seq)
+            }
+        }
+    }
+}
+
+/// History directory of a session; the ID must be a UUID so it is a safe name.
+fn session_dir(session_id: &str) -> Result<PathBuf, String> {
+    uuid::Uuid::parse_str(session_id).map_err(|_| format!("invalid session ID {:?}", session_id))?;
+    Ok(PathBuf::from(HISTORY_DIR).join(session_id))
+}
+
+/// Entries of the history in `dir`, skipping any line a crash left torn.
+fn read_entries(dir: &Path) -> Result<impl Iterator<Item = HistoryEntry>, String> {
+    let path = dir.join(LOG_FILE);
+    let file = match fs::File::open(&path) {
+        Ok(file) => Some(file),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+        Err(e) => return Err(format!("read {}: {}", path.display(), e)),
+    };
+    Ok(file
+        .into_iter()
+        .flat_map(|file| BufReader::new(file).lines().map_while(Result::ok))
+        .filter_map(|line| serde_json::from_str(&line).ok()))
+}
+
+/// Sequence number of the next entry written to `dir`.
+fn next_seq_on_disk(dir: &Path) -> Result<u64, String> {
+    Ok(read_entries(dir)?.last().map_or(0, |e| e.seq + 1))
+}
+
+/// At most [`MAX_OUTPUT_BYTES`] of `s`, cut at a character boundary, and
+/// whether anything was cut.
+fn truncate(s: &str) -> (&str, bool) {
+    if s.len() <= MAX_OUTPUT_BYTES {
+        return (s, false);
+    }
+    let mut end = MAX_OUTPUT_BYTES;
+    while !s.is_char_boundary(end) {
+        end -= 1;
+    }
+    (&s[..end], true)
+}
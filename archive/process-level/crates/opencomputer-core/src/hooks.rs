@@ -0,0 +1,102 @@
+//! Policy, billing and scanning hooks for embedders.
+//!
+//! Implement [`Hooks`] and register it with
+//! [`AppState::add_hooks`](crate::state::AppState::add_hooks) to add custom
+//! logic without forking the HTTP or gRPC servers. Unlike
+//! [`SessionLifecycleHook`](crate::lifecycle::SessionLifecycleHook), which only
+//! observes, the checks that return a `Result` run before the action and can
+//! refuse it: the error becomes a `403` (gRPC `PERMISSION_DENIED`) carrying its
+//! message. Hooks are asked in registration order and the first refusal wins.
+//!
+//! Hooks run inline on the request task, so blocking work, such as calling a
+//! scanning service, belongs in `tokio::task::block_in_place`.
+//!
+//! What's covered:
+//!
+//! - session creation, over HTTP;
+//! - runs: `/run`, `/sessions/:id/run`, jobs, tasks, schedules, background
+//!   processes and gRPC `RunCommand`. Background processes don't report a
+//!   finish; their exits go to lifecycle hooks;
+//! - file writes through the files API, sync pushes, uploads (when created, so
+//!   without content) and gRPC `WriteFile(s)`. Archives, imports, git and
+//!   what runs write aren't;
+//! - session teardown, however it happens.
+
+use crate::auth::ApiKey;
+use crate::lifecycle::{LifecycleTransition, RunCompletion, SessionLifecycleEvent};
+use opencomputer_types::CreateSessionRequest;
+use std::time::Duration;
+
+/// A run about to start.
+#[derive(Debug, Clone)]
+pub struct RunStart {
+    /// `None` for stateless `/run`
+    pub session_id: Option<String>,
+    /// With session secrets masked
+    pub command: Vec<String>,
+    pub cwd: String,
+    /// Started with `/sessions/:id/background`, so it won't report a finish
+    pub background: bool,
+}
+
+/// A run that finished (successfully or not).
+#[derive(Debug, Clone)]
+pub struct RunFinish {
+    /// `None` for stateless `/run`
+    pub session_id: Option<String>,
+    /// With session secrets masked
+    pub command: Vec<String>,
+    pub exit_code: Option<i32>,
+    pub signal: Option<i32>,
+    pub duration_ms: u64,
+    pub cpu_time: Duration,
+}
+
+impl RunFinish {
+    pub fn new(session_id: Option<&str>, run: &RunCompletion, cpu_time: Duration) -> Self {
+        Self {
+            session_id: session_id.map(str::to_string),
+            command: run.command.clone(),
+            exit_code: run.exit_code,
+            signal: run.signal,
+            duration_ms: run.duration_ms,
+            cpu_time,
+        }
+    }
+}
+
+/// A file about to be written.
+#[derive(Debug, Clone, Copy)]
+pub struct FileWrite<'a> {
+    pub session_id: &'a str,
+    /// As the client gave it, relative to the sandbox root
+    pub path: &'a str,
+    /// What will be written, unless it's streamed in later (uploads)
+    pub content: Option<&'a [u8]>,
+}
+
+/// Checks and notifications around sessions, runs and file writes. All
+/// methods default to allowing everything and doing nothing. `key` is the
+/// caller's, when the API requires keys.
+pub trait Hooks: Send + Sync {
+    /// Allow or refuse creating a session, once the env policy is applied.
+    fn on_session_create(&self, _key: Option<&ApiKey>, _request: &CreateSessionRequest) -> Result<(), String> {
+        Ok(())
+    }
+
+    /// Allow or refuse starting a run.
+    fn on_run_start(&self, _key: Option<&ApiKey>, _run: &RunStart) -> Result<(), String> {
+        Ok(())
+    }
+
+    /// A run that [`on_run_start`](Self::on_run_start) allowed finished.
+    fn on_run_finish(&self, _key: Option<&ApiKey>, _run: &RunFinish) {}
+
+    /// Allow or refuse writing a file.
+    fn on_file_write(&self, _key: Option<&ApiKey>, _write: &FileWrite) -> Result<(), String> {
+        Ok(())
+    }
+
+    /// A session was torn down: deleted, expired or evicted.
+    fn on_session_destroy(&self, _event: &SessionLifecycleEvent, _transition: LifecycleTransition) {}
+}
@@ -0,0 +1,5845 @@
+//! HTTP server implementation using Axum.
+
+use crate::admin::{self, AdminSessionInfo, AdminSessionsQuery, DrainResponse, OrgUsage, ReloadResponse};
+use crate::auth::{self, ApiKey};
+use crate::backend::SandboxBackend;
+use crate::background_log;
+use crate::body_limit;
+use crate::cache::{BuildCacheRequest, BuildCacheResult};
+use crate::capacity::{self, Capacity};
+use crate::cgroups::{Cgroups, SessionLimits};
+use crate::changes::{self, SessionChanges, SessionChangesQuery};
+use crate::checkpoint;
+#[cfg(feature = "chaos")]
+use crate::chaos;
+use crate::cleanup_policy::CleanupPolicy;
+use crate::disk_usage::{self, DiskUsage, DiskUsageQuery};
+use crate::download::{self, ByteRange, Validators};
+use crate::egress::{self, EgressPolicy, EgressReport};
+use crate::events::{EventFilter, ServerEvent, ServerEventKind, ServerEventsQuery};
+use crate::file_diff::{self, FilesDiff, FilesDiffRequest};
+use crate::file_hash::{self, FileHash, FileHashError, FileHashQuery, FileHashesRequest, FileHashesResponse};
+use crate::file_sync::{self, SyncPullResponse, SyncPushResponse, SyncRequest};
+use crate::git::{self, GitCloneRequest, GitCommitRequest, GitDiffQuery, GitDiffResult, GitPullRequest, GitResult};
+use crate::health;
+use crate::history::{self, HistoryOutput, HistoryPage, HistoryQuery, HistorySource};
+use crate::hooks::{FileWrite, RunFinish, RunStart};
+use crate::hibernate;
+use crate::idempotency;
+use crate::import::{self, ImportRequest, ImportResult};
+use crate::jobs::{self, JobInfo, JobQuery};
+use crate::kernel::{self, KernelExecuteRequest, KernelExecuteResult};
+use crate::lifecycle::{BackgroundExit, LifecycleTransition, RunCompletion, SessionLifecycleEvent};
+use crate::overview::{self, Overview, OverviewQuery, QueueDepths, SessionUsage, SessionUsageTotals, TopConsumers};
+use crate::packages::{self, InstallPackagesRequest, InstallPackagesResult};
+use crate::patch::{self, ExportContent, PatchExportQuery};
+use crate::pool::PoolStats;
+use crate::port_scan;
+use crate::ports;
+use crate::progress::{ProgressEvent, ProgressUpdate};
+use crate::preview_auth::{self, PreviewAuth, PreviewAuthOutcome};
+use crate::preview_path;
+use crate::replay::{self, ReplayOutcome};
+use crate::request_id;
+use crate::recurring::{self, ScheduleDefinition, ScheduleInfo, ScheduledRun, SessionSchedule};
+use crate::reservation::{self, Resources};
+use crate::run_limits::RunPermit;
+use crate::safe_path::{self, WriteAt};
+use crate::schedule;
+use crate::scope::{self, Scope};
+use crate::secrets;
+use crate::ssh;
+use crate::static_site::{self, Lookup, StaticSite};
+use crate::tasks::{self, RunTaskRequest, SetTasksRequest, TaskInfo, TaskRun, TaskRunResponse};
+use crate::sandbox::{self, Determinism, RunConfig, RunResult};
+use crate::stats::{self, SessionStatsHistory, StatsCollector, StatsSample};
+use crate::state::{
+    validate_labels, validate_secrets, validate_slug, AppState, FullPolicy, IdleReason, PreviewHost, Session,
+    SessionSshKey, SessionStatus, SessionToken, ShareGrant, EVICT_MIN_IDLE_SECS,
+};
+use crate::templates::{self, validate_template_name, TemplateInfo};
+use crate::timeline::{self, SessionEvent, SessionEventKind, SessionEventsQuery};
+use crate::tls::CertStore;
+use crate::trace_context::RunTrace;
+use crate::trash::{self, DeleteFileRequest, DeleteFileResponse, RestoreFileRequest, RestoreFileResponse, TrashEntry};
+use crate::uploads::{self, CreateUploadRequest, FinalizeUploadResponse, UploadStatus};
+use axum_server::tls_rustls::RustlsConfig;
+use axum::{
+    body::{Body, Bytes},
+    extract::{ConnectInfo, DefaultBodyLimit, Extension, Host, OriginalUri, Path, Query, State},
+    extract::ws::{WebSocket, WebSocketUpgrade, Message as AxumWsMsg},
+    http::{header, HeaderMap, HeaderName, HeaderValue, Method, Request, StatusCode},
+    middleware,
+    response::sse::{Event as SseEvent, KeepAlive, Sse},
+    response::{IntoResponse, Redirect, Response},
+    routing::{any, delete, get, patch, post, put, MethodRouter},
+    Json, Router,
+};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use futures_util::{SinkExt, StreamExt};
+use opencomputer_types::{
+    BackgroundPidStatus, BackgroundRunRequest, BackgroundRunResponse, BackgroundStatusResponse,
+    CheckpointDiff, CheckpointDiffQuery, CheckpointInfo, CreateCheckpointRequest, CreateSessionRequest, CreateSessionResponse, CreateSessionTokenRequest, CreateSessionTokenResponse, CreateShareRequest, CreateShareResponse, CreateSshKeyRequest, CreateSshKeyResponse, FileEntry, KeepaliveRequest, KeepaliveResponse,
+    HibernateResponse, KillBackgroundResponse, ListFilesResponse, PauseResponse, ReadFileResponse,
+    RegisterTemplateRequest, RunPreviewRequest, RunPreviewResponse, RunRequest, SessionInfo,
+    SetCwdRequest, SetEnvRequest, SetSecretsRequest, Share, SshKey, ValidateScheduleRequest, ValidateScheduleResponse, WriteFileError,
+    WriteFileRequest, WriteFileResponse, WriteFilesRequest, WriteFilesResponse, WriteMode,
+};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::process::Child;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+use tokio::sync::broadcast;
+use tokio::time::interval;
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tokio_tungstenite::tungstenite::Message as TungsteniteMsg;
+use tracing::{info, warn};
+
+/// Prefix of tokens minted with `POST /sessions/:id/tokens` or `/share`.
+const SESSION_TOKEN_PREFIX: &str = "ost_";
+
+/// Lifetime of a share that doesn't set one, and the longest one may ask for.
+const DEFAULT_SHARE_TTL: Duration = Duration::from_secs(24 * 3600);
+const MAX_SHARE_TTL: Duration = Duration::from_secs(30 * 24 * 3600);
+
+// Defaults for optional request fields
+const DEFAULT_TIME_MS: u64 = 300000;
+const DEFAULT_MEM_KB: u64 = 2097152;
+const DEFAULT_NOFILE: u64 = 256;
+const DEFAULT_FSIZE_KB: u64 = 1048576;
+const DEFAULT_CWD: &str = "/";
+const DEFAULT_BACKGROUND_PORT: u16 = 5173;
+const DEFAULT_PREVIEW_DIR: &str = "/home/app";
+const DEFAULT_READY_TIMEOUT_SECS: u64 = 60;
+
+fn session_info(s: &Session, now: Instant, policy: &CleanupPolicy, stats: &StatsCollector) -> SessionInfo {
+    let (idle_since, idle_reason) = s.idle_since();
+    let idle = now.duration_since(idle_since);
+    SessionInfo {
+        id: s.id.clone(),
+        env: s.env.clone(),
+        cwd: s.cwd.clone(),
+        age_secs: now.duration_since(s.created_at).as_secs(),
+        idle_secs: idle.as_secs(),
+        idle_reason,
+        preview_url: s.preview_url.clone(),
+        ports: s.ports.clone(),
+        static_site: s.static_site.clone(),
+        status: s.status,
+        preview_auth: s.preview_auth.as_ref().map(|a| a.mode().to_string()),
+        slug: s.slug.clone(),
+        template: s.template.clone(),
+        image: s.image.clone(),
+        labels: s.labels.clone(),
+        determinism: s.determinism.clone(),
+        resources: s.resources,
+        limits: s.limits,
+        secrets: {
+            let mut names: Vec<String> = s.secrets.keys().cloned().collect();
+            names.sort();
+            names
+        },
+        ttl_secs: policy.idle_ttl(s).as_secs(),
+        expires_in_secs: policy.expires_in(s, now).as_secs(),
+        stats: stats.get(&s.id),
+    }
+}
+
+#[derive(Deserialize)]
+struct ReadFileQuery {
+    path: String,
+}
+
+#[derive(Deserialize)]
+struct ListFilesQuery {
+    path: String,
+}
+
+/// Connect timeout for proxied preview requests (no read timeout is applied).
+const PROXY_CONNECT_TIMEOUT_SECS: u64 = 10;
+
+/// Headers that describe one connection rather than the message (RFC 7230
+/// section 6.1), so the preview proxy forwards them in neither direction.
+/// Headers a message's `Connection` header names are dropped too.
+const HOP_BY_HOP_HEADERS: &[&str] = &[
+    "connection",
+    "keep-alive",
+    "proxy-authenticate",
+    "proxy-authorization",
+    "proxy-connection",
+    "te",
+    "trailer",
+    "transfer-encoding",
+    "upgrade",
+];
+
+/// WebSocket handshake headers the proxy's own handshake with the sandboxed
+/// app sets; `Sec-WebSocket-Protocol` is forwarded instead.
+const WS_HANDSHAKE_HEADERS: &[&str] = &["sec-websocket-key", "sec-websocket-version", "sec-websocket-extensions"];
+
+/// Response content types the preview proxy relays incrementally.
+const STREAMING_CONTENT_TYPES: &[&str] = &[
+    "text/event-stream",
+    "application/x-ndjson",
+    "multipart/x-mixed-replace",
+];
+
+/// Longest `ready_timeout` a `/run-preview` caller may ask for.
+const MAX_READY_TIMEOUT_SECS: u64 = 600;
+
+/// How often `/run-preview` checks whether the dev server is up.
+const READY_POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Background log bytes returned when a preview never became ready.
+const READY_FAILURE_LOG_BYTES: u64 = 2000;
+
+/// Run the HTTP server on the given port with the provided state.
+pub async fn run_server(addr: SocketAddr, state: AppState) {
+    run_server_with(addr, state, None, RouterOptions::default()).await
+}
+
+/// Like [`run_server`], but terminating TLS with the certificate in `certs`.
+pub async fn run_server_tls(addr: SocketAddr, state: AppState, certs: Arc<CertStore>) {
+    run_server_with(addr, state, Some(certs), RouterOptions::default()).await
+}
+
+/// Like [`run_server`], with TLS if `certs` is given and the router built
+/// with `options`.
+pub async fn run_server_with(
+    addr: SocketAddr,
+    state: AppState,
+    certs: Option<Arc<CertStore>>,
+    options: RouterOptions,
+) {
+    spawn_cleanup_task(state.clone());
+    #[cfg(feature = "chaos")]
+    tracing::warn!("Built with fault injection; configure it with PUT /admin/faults");
+
+    let preview_domain = state.preview_domain.clone();
+    let preview_region = state.preview_region.clone();
+    let app = build_router_with(state, options);
+
+    let scheme = if certs.is_some() { "HTTPS" } else { "HTTP" };
+    info!("Starting {} server on {}", scheme, addr);
+    if let Some(ref domain) = preview_domain {
+        match preview_region {
+            Some(ref region) => info!("Preview domain: {} (region {})", domain, region),
+            None => info!("Preview domain: {}", domain),
+        }
+    }
+
+    // Client addresses go to preview apps in X-Forwarded-For
+    match certs {
+        None => {
+            let listener = tokio::net::TcpListener::bind(addr).await.unwrap();
+            axum::serve(listener, app.into_make_service_with_connect_info::<SocketAddr>())
+                .await
+                .unwrap();
+        }
+        Some(certs) => {
+            let config = RustlsConfig::from_config(certs.server_config(&[b"h2", b"http/1.1"]));
+            axum_server::bind_rustls(addr, config)
+                .serve(app.layer(Extension(TlsTerminated)).into_make_service_with_connect_info::<SocketAddr>())
+                .await
+                .unwrap();
+        }
+    }
+}
+
+/// Serve [`build_admin_router`] on `addr`. It speaks plaintext, so bind it
+/// to a loopback or private interface.
+pub async fn run_admin_server(addr: SocketAddr, state: AppState, token: Option<String>) {
+    if token.is_none() && !state.api_keys.is_enabled() {
+        warn!("Admin API on {} has no token and no API keys: every request will be refused", addr);
+    }
+    let app = build_admin_router(state, token);
+    info!("Starting admin server on {}", addr);
+    let listener = tokio::net::TcpListener::bind(addr).await.unwrap();
+    axum::serve(listener, app).await.unwrap();
+}
+
+/// Marks requests that reached this server over TLS, for the preview proxy's
+/// `X-Forwarded-Proto`.
+#[derive(Clone, Copy)]
+struct TlsTerminated;
+
+/// Spawn the background tasks. Embedders serving [`build_router`]
+/// themselves must call this once.
+///
+/// - stats sampling, activity tracking and expiry warnings
+/// - port detection
+/// - session schedules
+/// - background log rotation
+/// - reaping expired sessions, trash and uploads, and saving sessions
+pub fn spawn_cleanup_task(state: AppState) {
+    #[cfg(feature = "chaos")]
+    chaos::spawn_background_killer(state.clone());
+    let sampled = state.clone();
+    tokio::spawn(async move {
+        let mut interval = interval(stats::SAMPLE_INTERVAL);
+        loop {
+            interval.tick().await;
+            let sessions: Vec<(String, PathBuf)> = {
+                let sessions = sampled.sessions.read().await;
+                let now = Instant::now();
+                for s in sessions.values() {
+                    let ttl = sampled.cleanup_policy.idle_ttl(s);
+                    sampled.timeline.check_ttl(&s.id, ttl, sampled.cleanup_policy.expires_in(s, now));
+                }
+                sessions.values().map(|s| (s.id.clone(), s.sandbox_root.clone())).collect()
+            };
+            let ids: Vec<String> = sessions.iter().map(|(id, _)| id.clone()).collect();
+            let collector = sampled.session_stats.clone();
+            let _ = tokio::task::spawn_blocking(move || collector.sample(&sessions)).await;
+            record_activity(&sampled, &ids).await;
+        }
+    });
+    let scanner = state.clone();
+    tokio::spawn(async move {
+        let mut interval = interval(port_scan::SCAN_INTERVAL);
+        loop {
+            interval.tick().await;
+            detect_ports(&scanner).await;
+        }
+    });
+    let scheduler = state.clone();
+    tokio::spawn(async move {
+        let mut interval = interval(recurring::CHECK_INTERVAL);
+        loop {
+            interval.tick().await;
+            run_due_schedules(&scheduler).await;
+        }
+    });
+    let rotator = state.clone();
+    tokio::spawn(async move {
+        let mut interval = interval(background_log::CHECK_INTERVAL);
+        loop {
+            interval.tick().await;
+            rotate_background_logs(&rotator).await;
+        }
+    });
+    tokio::spawn(async move {
+        let mut interval = interval(state.cleanup_policy.cleanup_interval());
+        loop {
+            interval.tick().await;
+            cleanup_expired_sessions(&state).await;
+            purge_trash(&state).await;
+            purge_uploads(&state).await;
+            state.sync_sessions().await;
+        }
+    });
+}
+
+/// Mark the sessions that are busy right now as active; see
+/// [`crate::activity`].
+async fn record_activity(state: &AppState, ids: &[String]) {
+    let busy: Vec<(&String, IdleReason)> = ids
+        .iter()
+        .filter_map(|id| state.activity.busy(id, &state.session_stats.get(id)).map(|reason| (id, reason)))
+        .collect();
+    if busy.is_empty() {
+        return;
+    }
+    let now = Instant::now();
+    let mut sessions = state.sessions.write().await;
+    for (id, reason) in busy {
+        if let Some(session) = sessions.get_mut(id) {
+            session.last_activity = Some((now, reason));
+        }
+    }
+}
+
+/// Attach the ports sessions have started listening on and detach those
+/// found earlier that nothing listens on anymore; see [`crate::port_scan`].
+async fn detect_ports(state: &AppState) {
+    let roots: Vec<PathBuf> = {
+        let sessions = state.sessions.read().await;
+        sessions
+            .values()
+            .filter(|s| !s.status.is_suspended())
+            .map(|s| s.sandbox_root.clone())
+            .collect()
+    };
+    if roots.is_empty() {
+        return;
+    }
+    let Ok(found) = tokio::task::spawn_blocking(move || port_scan::listening_ports(&roots)).await else {
+        return;
+    };
+    let mut sessions = state.sessions.write().await;
+    for session in sessions.values_mut() {
+        // Sessions created or suspended since the scan started
+        let Some(listening) = found.get(&session.sandbox_root) else {
+            continue;
+        };
+        if session.status.is_suspended() {
+            continue;
+        }
+        let new: Vec<u16> = listening.iter().copied().filter(|port| !session.ports.contains(port)).collect();
+        let gone: Vec<u16> = session
+            .detected_ports
+            .iter()
+            .copied()
+            .filter(|port| !listening.contains(port))
+            .collect();
+        if new.is_empty() && gone.is_empty() {
+            continue;
+        }
+        for port in new {
+            info!("Session {} listens on port {}; attaching it", session.id, port);
+            session.ports.push(port);
+            session.detected_ports.push(port);
+            state.ports.claim(port);
+            state.timeline.record(&session.id, SessionEventKind::PortRegistered { port, detected: true });
+        }
+        for port in gone {
+            session.ports.retain(|&p| p != port);
+            session.detected_ports.retain(|&p| p != port);
+            state.release_port(port);
+            state.timeline.record(&session.id, SessionEventKind::PortReleased { port });
+        }
+        state.persist_session(session);
+    }
+}
+
+/// Rotate the logs of live background processes that have grown too big.
+async fn rotate_background_logs(state: &AppState) {
+    let logs: Vec<(PathBuf, u32)> = {
+        let sessions = state.sessions.read().await;
+        sessions
+            .values()
+            .flat_map(|s| s.background_pids.iter().map(|&pid| (s.sandbox_root.clone(), pid)))
+            .collect()
+    };
+    if logs.is_empty() {
+        return;
+    }
+    let background_logs = state.background_logs.clone();
+    let _ = tokio::task::spawn_blocking(move || {
+        for (sandbox_root, pid) in logs {
+            if !sandbox::is_process_alive(pid) {
+                continue;
+            }
+            if let Err(e) = background_logs.rotate(&sandbox_root, pid) {
+                warn!("Failed to rotate the log of background process {}: {}", pid, e);
+            }
+        }
+    })
+    .await;
+}
+
+/// Start a run of every session schedule that is due.
+async fn run_due_schedules(state: &AppState) {
+    let now = chrono::Utc::now();
+    let any_due = {
+        let sessions = state.sessions.read().await;
+        sessions.values().any(|s| s.schedules.values().any(|schedule| schedule.is_due(now)))
+    };
+    if !any_due {
+        return;
+    }
+    let mut sessions = state.sessions.write().await;
+    for session in sessions.values_mut() {
+        for (schedule_id, schedule) in &mut session.schedules {
+            if schedule.take_due(now) {
+                tokio::spawn(run_schedule(
+                    state.clone(),
+                    session.id.clone(),
+                    schedule_id.clone(),
+                    schedule.definition.clone(),
+                    session.api_key.clone(),
+                ));
+            }
+        }
+    }
+}
+
+/// Run a schedule's command once, as its session's creator, and record how
+/// it went in the schedule.
+async fn run_schedule(
+    state: AppState,
+    session_id: String,
+    schedule_id: String,
+    definition: ScheduleDefinition,
+    api_key: Option<Arc<ApiKey>>,
+) {
+    let started_at = chrono::Utc::now();
+    let started = Instant::now();
+    let req = RunRequest {
+        command: definition.command,
+        env: definition.env,
+        cwd: definition.cwd,
+        time: definition.time,
+        ..Default::default()
+    };
+    let headers = HeaderMap::new();
+    let result = run_session_command(&state, &session_id, api_key, &headers, req, HistorySource::Schedule).await;
+    let mut run = ScheduledRun {
+        started_at: started_at.to_rfc3339_opts(chrono::SecondsFormat::Secs, true),
+        duration_ms: started.elapsed().as_millis() as u64,
+        exit_code: None,
+        signal: None,
+        error: None,
+        trace_id: None,
+    };
+    match result {
+        Ok(result) => {
+            run.exit_code = result.exit_code;
+            run.signal = result.signal;
+            run.trace_id = result.trace_id;
+        }
+        Err((_, e)) => {
+            warn!("Schedule {} of session {} didn't run: {}", schedule_id, session_id, e);
+            run.error = Some(e);
+        }
+    }
+    let mut sessions = state.sessions.write().await;
+    if let Some(schedule) = sessions
+        .get_mut(&session_id)
+        .and_then(|session| session.schedules.get_mut(&schedule_id))
+    {
+        schedule.finish(run);
+    }
+}
+
+type ApiRouterFn = Box<dyn FnOnce(Router<AppState>) -> Router<AppState> + Send>;
+
+/// Extension points for [`build_router_with`], for integrators mounting the
+/// sandbox API inside their own server.
+pub struct RouterOptions {
+    base_path: Option<String>,
+    extra_routes: Router<AppState>,
+    api_layers: Vec<ApiRouterFn>,
+    api_key_auth: bool,
+    preview_proxy: bool,
+    admin_routes: bool,
+    admin_token: Option<String>,
+}
+
+impl Default for RouterOptions {
+    fn default() -> Self {
+        Self {
+            base_path: None,
+            extra_routes: Router::new(),
+            api_layers: Vec::new(),
+            api_key_auth: true,
+            preview_proxy: true,
+            admin_routes: false,
+            admin_token: None,
+        }
+    }
+}
+
+impl RouterOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Serve the API routes under a prefix such as `/sandbox`. The health
+    /// checks and the preview proxy stay at the root.
+    pub fn base_path(mut self, path: impl Into<String>) -> Self {
+        self.base_path = Some(path.into());
+        self
+    }
+
+    /// Merge extra routes into the API router. They get the same API key
+    /// check, layers, and base path as the built-in routes.
+    pub fn routes(mut self, routes: Router<AppState>) -> Self {
+        self.extra_routes = self.extra_routes.merge(routes);
+        self
+    }
+
+    /// Wrap the API routes, e.g. `|r| r.layer(my_auth_layer)`. Layers run
+    /// before the built-in API key check, in the order they were added
+    /// (the last one added is outermost).
+    pub fn map_api<F>(mut self, f: F) -> Self
+    where
+        F: FnOnce(Router<AppState>) -> Router<AppState> + Send + 'static,
+    {
+        self.api_layers.push(Box::new(f));
+        self
+    }
+
+    /// Disable the built-in API key middleware, for deployments that
+    /// authenticate with their own layer.
+    pub fn api_key_auth(mut self, enabled: bool) -> Self {
+        self.api_key_auth = enabled;
+        self
+    }
+
+    /// Disable the preview proxy fallback. Needed when merging into a router
+    /// that has its own fallback.
+    pub fn preview_proxy(mut self, enabled: bool) -> Self {
+        self.preview_proxy = enabled;
+        self
+    }
+
+    /// Mount the `/admin` routes with the API. They're left out by default,
+    /// for servers that serve them on a listener of their own with
+    /// [`build_admin_router`] or not at all.
+    pub fn admin_routes(mut self, enabled: bool) -> Self {
+        self.admin_routes = enabled;
+        self
+    }
+
+    /// Have the mounted `/admin` routes require `token` instead of API keys,
+    /// as [`build_admin_router`] does.
+    pub fn admin_token(mut self, token: Option<String>) -> Self {
+        self.admin_token = token;
+        self
+    }
+}
+
+/// Build the full HTTP API router (session, file, run, health, and preview proxy).
+pub fn build_router(state: AppState) -> Router {
+    build_router_with(state, RouterOptions::default())
+}
+
+/// Build the HTTP API router with integrator-supplied routes, layers, and base path.
+pub fn build_router_with(state: AppState, options: RouterOptions) -> Router {
+    let mut api = api_routes().merge(options.extra_routes);
+    if options.admin_routes && options.admin_token.is_none() {
+        api = api.merge(admin_routes());
+    }
+    // Inside the API key check so the caller's key is known
+    let mut api = api
+        .route_layer(middleware::from_fn_with_state(state.body_limits, body_limit::enforce))
+        .route_layer(middleware::from_fn_with_state(state.clone(), auth::scope_session));
+    #[cfg(feature = "chaos")]
+    {
+        api = api.route_layer(middleware::from_fn_with_state(state.clone(), chaos::inject_delay));
+    }
+    if options.api_key_auth {
+        // API key check applies to matched API routes only; the preview
+        // fallback has its own per-session auth
+        api = api.route_layer(middleware::from_fn_with_state(
+            state.clone(),
+            auth::require_api_key,
+        ));
+    }
+    // Merged after the API key check, which the admin token replaces
+    if let (true, Some(token)) = (options.admin_routes, options.admin_token) {
+        api = api.merge(token_admin_routes(token));
+    }
+    for map in options.api_layers {
+        api = map(api);
+    }
+    api = api
+        .layer(Extension(state.idempotency.clone()))
+        // body_limit::enforce holds each route to its own limit instead
+        .layer(DefaultBodyLimit::disable())
+        .layer(middleware::from_fn(request_id::annotate_errors));
+
+    let base_path = options
+        .base_path
+        .map(|p| format!("/{}", p.trim_matches('/')))
+        .filter(|p| p != "/");
+    let mut app = match base_path {
+        Some(prefix) => Router::new().nest(&prefix, api),
+        None => Router::new().merge(api),
+    }
+    // Health checks
+    .route("/health", get(health))
+    .route("/healthz", get(healthz))
+    .route("/readyz", get(readyz));
+    if options.preview_proxy {
+        // Path-based previews, for hosts without a preview domain
+        app = app
+            .route("/preview/:label/:port", any(path_preview))
+            .route("/preview/:label/:port/", any(path_preview))
+            .route("/preview/:label/:port/*path", any(path_preview));
+        // Preview proxy: catches all unmatched requests and checks Host header
+        app = app.fallback(preview_proxy);
+    }
+    app.layer(middleware::from_fn(request_id::track_request))
+        .with_state(state)
+}
+
+/// Build the operator API router (the `/admin` routes and health checks)
+/// for a listener of its own. With `token`, callers must present it and API
+/// keys aren't accepted; without, only API keys granted `admin.*` scopes
+/// are, and with no keys configured every admin route is refused.
+pub fn build_admin_router(state: AppState, token: Option<String>) -> Router {
+    let admin = match token {
+        Some(token) => token_admin_routes(token),
+        None => admin_routes().route_layer(middleware::from_fn_with_state(state.clone(), auth::require_api_key)),
+    };
+    admin
+        .layer(middleware::from_fn(request_id::annotate_errors))
+        .route("/healthz", get(healthz))
+        .route("/readyz", get(readyz))
+        .layer(middleware::from_fn(request_id::track_request))
+        .with_state(state)
+}
+
+fn api_routes() -> Router<AppState> {
+    use Scope::*;
+    Router::new()
+        // Session management
+        .route("/sessions", scoped(SessionsWrite, idempotent(post(create_session))))
+        .route("/sessions", scoped(SessionsRead, get(list_sessions)))
+        .route("/sessions/:id", scoped(SessionsRead, get(get_session)))
+        .route("/sessions/:id", scoped(SessionsWrite, delete(delete_session)))
+        .route("/sessions/:id/run", scoped(ExecRun, idempotent(post(run_in_session))))
+        .route("/sessions/:id/jobs", scoped(ExecRun, post(start_job)))
+        .route("/sessions/:id/background", scoped(BackgroundManage, idempotent(post(run_background))))
+        .route("/sessions/:id/background", scoped(BackgroundManage, delete(kill_background)))
+        .route("/sessions/:id/env", scoped(SessionsWrite, post(set_env)))
+        .route("/sessions/:id/env/:name", scoped(SessionsWrite, delete(unset_env)))
+        .route("/sessions/:id/tasks", scoped(SessionsWrite, post(set_tasks)))
+        .route("/sessions/:id/tasks", scoped(SessionsRead, get(list_tasks)))
+        .route("/sessions/:id/tasks/:name", scoped(SessionsWrite, delete(delete_task)))
+        .route("/sessions/:id/tasks/:name/run", scoped(ExecRun, post(run_task)))
+        .route("/sessions/:id/schedules", scoped(ExecRun, post(create_schedule)))
+        .route("/sessions/:id/schedules", scoped(SessionsRead, get(list_schedules)))
+        .route("/sessions/:id/schedules/:schedule_id", scoped(SessionsRead, get(get_schedule)))
+        .route("/sessions/:id/schedules/:schedule_id", scoped(SessionsWrite, delete(delete_schedule)))
+        .route("/sessions/:id/cwd", scoped(SessionsWrite, post(set_cwd)))
+        .route("/sessions/:id/static", scoped(SessionsWrite, post(set_static_site)))
+        .route("/sessions/:id/static", scoped(SessionsWrite, delete(delete_static_site)))
+        .route("/sessions/:id/secrets", scoped(SessionsWrite, post(set_secrets)))
+        .route("/sessions/:id/egress", scoped(SessionsRead, get(session_egress)))
+        .route("/sessions/:id/egress", scoped(SessionsWrite, put(set_egress)))
+        .route("/sessions/:id/limits", scoped(SessionsWrite, patch(set_limits)))
+        .route("/sessions/:id/keepalive", scoped(SessionsWrite, post(keepalive)))
+        .route("/sessions/:id/pause", scoped(SessionsWrite, post(pause_session)))
+        .route("/sessions/:id/resume", scoped(SessionsWrite, post(resume_session)))
+        .route("/sessions/:id/hibernate", scoped(SessionsWrite, post(hibernate_session)))
+        .route("/sessions/:id/wake", scoped(SessionsWrite, post(wake_session)))
+        .route("/sessions/:id/tokens", scoped(SessionsWrite, post(create_session_token)))
+        .route("/sessions/:id/share", scoped(SessionsWrite, post(create_share)))
+        .route("/sessions/:id/shares", scoped(SessionsRead, get(list_shares)))
+        .route("/sessions/:id/shares/:share_id", scoped(SessionsWrite, delete(delete_share)))
+        .route("/sessions/:id/ssh-keys", scoped(SessionsWrite, post(create_ssh_key)))
+        .route("/sessions/:id/ssh-keys", scoped(SessionsRead, get(list_ssh_keys)))
+        .route("/sessions/:id/ssh-keys/:key_id", scoped(SessionsWrite, delete(delete_ssh_key)))
+        .route("/sessions/:id/usage", scoped(SessionsRead, get(session_usage)))
+        .route("/sessions/:id/stats", scoped(SessionsRead, get(session_stats)))
+        .route("/sessions/:id/stats/stream", scoped(SessionsRead, get(session_stats_stream)))
+        .route("/sessions/:id/events", scoped(SessionsRead, get(session_events)))
+        .route("/sessions/:id/events/stream", scoped(SessionsRead, get(session_events_stream)))
+        .route("/sessions/:id/history", scoped(SessionsRead, get(session_history)))
+        .route("/sessions/:id/history/:seq/output", scoped(ExecRun, get(session_history_output)))
+        // File operations
+        .route("/sessions/:id/files/write", scoped(FilesWrite, post(write_file)))
+        .route("/sessions/:id/files/write-bulk", scoped(FilesWrite, post(write_files_bulk)))
+        .route("/sessions/:id/files/read", scoped(FilesRead, get(read_file)))
+        .route("/sessions/:id/files/download", scoped(FilesRead, get(download_file)))
+        .route("/sessions/:id/files/hash", scoped(FilesRead, get(hash_file).post(hash_files)))
+        .route("/sessions/:id/files/diff", scoped(FilesRead, post(diff_files)))
+        .route("/sessions/:id/changes", scoped(FilesRead, get(session_changes)))
+        .route("/sessions/:id/files/list", scoped(FilesRead, get(list_files)))
+        .route("/sessions/:id/files/delete", scoped(FilesWrite, post(delete_file)))
+        .route("/sessions/:id/files/trash", scoped(FilesRead, get(list_trash)))
+        .route("/sessions/:id/files/restore", scoped(FilesWrite, post(restore_file)))
+        .route("/sessions/:id/files/sync", scoped(FilesWrite, post(sync_files)))
+        .route("/sessions/:id/files/sync/pull", scoped(FilesRead, post(pull_files)))
+        // Resumable uploads
+        .route("/sessions/:id/uploads", scoped(FilesWrite, post(create_upload)))
+        .route("/sessions/:id/uploads/:upload_id", scoped(FilesRead, get(upload_status)))
+        .route("/sessions/:id/uploads/:upload_id", scoped(FilesWrite, patch(upload_chunk).delete(cancel_upload)))
+        .route("/sessions/:id/uploads/:upload_id/finalize", scoped(FilesWrite, post(finalize_upload)))
+        // Checkpoints
+        .route("/sessions/:id/checkpoints", scoped(SessionsWrite, post(create_checkpoint)))
+        .route("/sessions/:id/checkpoints", scoped(SessionsRead, get(list_checkpoints)))
+        .route("/sessions/:id/checkpoints/:checkpoint", scoped(SessionsWrite, delete(delete_checkpoint)))
+        .route("/sessions/:id/checkpoints/:from/diff/:to", scoped(FilesRead, get(diff_checkpoints)))
+        // Background diagnostics
+        .route("/sessions/:id/background/status", scoped(BackgroundRead, get(background_status)))
+        // Raw TCP to session listeners, over a WebSocket
+        .route("/sessions/:id/tcp/:port", scoped(ExecRun, get(tcp_forward)))
+        // Code-interpreter kernel
+        .route("/sessions/:id/kernel/python/execute", scoped(ExecRun, post(kernel_execute)))
+        .route("/sessions/:id/kernel/python", scoped(ExecRun, delete(kernel_shutdown)))
+        // Packages
+        .route("/sessions/:id/packages", scoped(ExecRun, post(install_packages)))
+        .route("/sessions/:id/build-cache/restore", scoped(ExecRun, post(restore_build_cache)))
+        .route("/sessions/:id/build-cache/save", scoped(ExecRun, post(save_build_cache)))
+        // Git
+        .route("/sessions/:id/git/clone", scoped(ExecRun, post(git_clone)))
+        .route("/sessions/:id/git/pull", scoped(ExecRun, post(git_pull)))
+        .route("/sessions/:id/git/commit", scoped(ExecRun, post(git_commit)))
+        .route("/sessions/:id/git/diff", scoped(FilesRead, get(git_diff)))
+        .route("/sessions/:id/import", scoped(ExecRun, post(import_project)))
+        .route("/sessions/:id/export/patch", scoped(FilesRead, get(export_patch)))
+        // Stateless run
+        .route("/run", scoped(ExecRun, post(run_oneshot)))
+        // Session runs started by /sessions/:id/jobs
+        .route("/jobs/:id", scoped(ExecRun, get(get_job)))
+        .route("/jobs/:id", scoped(ExecRun, delete(cancel_job)))
+        // Session + upload + dev server in one call
+        .route("/run-preview", scoped(PreviewAdmin, post(run_preview)))
+        // Warm pool metrics
+        .route("/pool", scoped(SessionsRead, get(pool_stats)))
+        .route("/capacity", scoped(SessionsRead, get(capacity)))
+        .route("/events", scoped(SessionsRead, get(progress_events)))
+        // Cron expression checks
+        .route("/schedules/validate", scoped(SessionsRead, post(validate_schedule)))
+        // Templates
+        .route("/templates", scoped(TemplatesRead, get(list_templates)))
+        .route("/templates", scoped(TemplatesWrite, post(register_template)))
+        .route("/templates/:name", scoped(TemplatesWrite, put(upload_template)))
+        .route("/templates/:name", scoped(TemplatesWrite, delete(delete_template)))
+        // Record/replay
+        .route("/replays", scoped(ExecRun, post(replay_bundle)))
+        .route("/replays/:id", scoped(ReplaysRead, get(download_replay)))
+        .route("/replays/:id", scoped(ReplaysWrite, delete(delete_replay)))
+}
+
+/// Operator routes behind the admin token.
+fn token_admin_routes(token: String) -> Router<AppState> {
+    admin_routes().route_layer(middleware::from_fn_with_state(
+        Arc::<str>::from(token),
+        admin::require_admin_token,
+    ))
+}
+
+/// Operator routes; see [`crate::admin`].
+fn admin_routes() -> Router<AppState> {
+    use Scope::{AdminRead, AdminWrite};
+    Router::new()
+        .route("/admin/overview", scoped(AdminRead, get(admin_overview)))
+        .route("/admin/sessions", scoped(AdminRead, get(admin_sessions)))
+        // Not `:id`, which auth::scope_session limits to the caller's org
+        .route("/admin/sessions/:session_id", scoped(AdminWrite, delete(force_delete_session)))
+        .route("/admin/orgs", scoped(AdminRead, get(admin_orgs)))
+        .route("/admin/metrics", scoped(AdminRead, get(admin_metrics)))
+        .route("/admin/reload", scoped(AdminWrite, post(reload_api_keys)))
+        .route("/admin/drain", scoped(AdminWrite, post(drain_server)))
+        .route("/events/stream", scoped(AdminRead, get(event_stream)))
+        .merge(fault_routes())
+}
+
+#[cfg(feature = "chaos")]
+fn fault_routes() -> Router<AppState> {
+    Router::new()
+        .route("/admin/faults", scoped(Scope::FaultsAdmin, get(get_faults)))
+        .route("/admin/faults", scoped(Scope::FaultsAdmin, put(set_faults)))
+}
+
+#[cfg(not(feature = "chaos"))]
+fn fault_routes() -> Router<AppState> {
+    Router::new()
+}
+
+/// Require `scope` of the caller's key for this route.
+fn scoped(scope: Scope, route: MethodRouter<AppState>) -> MethodRouter<AppState> {
+    route.layer(middleware::from_fn_with_state(scope, auth::require_scope))
+}
+
+/// Replay this route's response to retries with the same `Idempotency-Key`;
+/// see [`idempotency`].
+fn idempotent(route: MethodRouter<AppState>) -> MethodRouter<AppState> {
+    route.layer(middleware::from_fn(idempotency::replay))
+}
+
+async fn health() -> &'static str {
+    "OK"
+}
+
+async fn healthz() -> (StatusCode, Json<health::HealthReport>) {
+    health_response(health::liveness().await)
+}
+
+async fn readyz(State(state): State<AppState>) -> (StatusCode, Json<health::HealthReport>) {
+    health_response(health::readiness(&state).await)
+}
+
+fn health_response(report: health::HealthReport) -> (StatusCode, Json<health::HealthReport>) {
+    let status = if report.healthy { StatusCode::OK } else { StatusCode::SERVICE_UNAVAILABLE };
+    (status, Json(report))
+}
+
+#[cfg(feature = "chaos")]
+async fn get_faults(State(state): State<AppState>) -> Json<opencomputer_types::FaultsResponse> {
+    Json(opencomputer_types::FaultsResponse {
+        config: state.faults.config(),
+        injected: state.faults.counts(),
+    })
+}
+
+#[cfg(feature = "chaos")]
+async fn set_faults(
+    State(state): State<AppState>,
+    Json(config): Json<chaos::FaultConfig>,
+) -> Result<Json<opencomputer_types::FaultsResponse>, (StatusCode, String)> {
+    state
+        .faults
+        .set_config(config)
+        .map_err(|e| (StatusCode::BAD_REQUEST, e))?;
+    Ok(get_faults(State(state)).await)
+}
+
+async fn pool_stats(State(state): State<AppState>) -> Json<PoolStats> {
+    Json(state.warm_pool.stats().await)
+}
+
+async fn capacity(
+    State(state): State<AppState>,
+    api_key: Option<Extension<Arc<ApiKey>>>,
+) -> Json<Capacity> {
+    let key = api_key.as_ref().map(|Extension(key)| key.as_ref());
+    Json(state.capacity(key).await)
+}
+
+/// Host-wide resource usage: every session's CPU, memory and disk, the top
+/// consumers of each, and the state of ports, the warm pool and queues.
+async fn admin_overview(
+    State(state): State<AppState>,
+    Query(query): Query<OverviewQuery>,
+) -> Result<Json<Overview>, (StatusCode, String)> {
+    let top = query.top.unwrap_or(overview::DEFAULT_TOP);
+    if top > overview::MAX_TOP {
+        return Err((StatusCode::BAD_REQUEST, format!("top must be at most {}", overview::MAX_TOP)));
+    }
+    let sample_ms = query.sample_ms.unwrap_or(overview::DEFAULT_SAMPLE_MS);
+    if sample_ms > overview::MAX_SAMPLE_MS {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            format!("sample_ms must be at most {}", overview::MAX_SAMPLE_MS),
+        ));
+    }
+
+    let (sessions, reserved) = {
+        let sessions = state.sessions.read().await;
+        let now = Instant::now();
+        let listed: Vec<(PathBuf, SessionUsage)> = sessions
+            .values()
+            .map(|s| {
+                let usage = SessionUsage {
+                    id: s.id.clone(),
+                    slug: s.slug.clone(),
+                    org_id: s.org_id.as_deref().map(|org| state.api_keys.org_label(org)),
+                    status: s.status,
+                    cpu_percent: 0.0,
+                    cpu_secs: 0.0,
+                    mem_bytes: 0,
+                    disk_bytes: 0,
+                    processes: 0,
+                    ports: s.ports.clone(),
+                    idle_secs: now.duration_since(s.idle_since().0).as_secs(),
+                };
+                (s.sandbox_root.clone(), usage)
+            })
+            .collect();
+        (listed, reservation::reserved(&sessions))
+    };
+    let roots: Vec<PathBuf> = sessions.iter().map(|(root, _)| root.clone()).collect();
+    let usage = tokio::task::spawn_blocking(move || overview::sample(&roots, Duration::from_millis(sample_ms)))
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+
+    let mut totals = SessionUsageTotals::default();
+    let mut sessions: Vec<SessionUsage> = sessions
+        .into_iter()
+        .map(|(root, mut s)| {
+            let u = usage.get(&root).copied().unwrap_or_default();
+            (s.cpu_percent, s.cpu_secs, s.mem_bytes, s.disk_bytes, s.processes) =
+                (u.cpu_percent, u.cpu_secs, u.mem_bytes, u.disk_bytes, u.processes);
+            totals.sessions += 1;
+            *totals.by_status.entry(s.status.as_str().to_string()).or_default() += 1;
+            totals.cpu_percent += s.cpu_percent;
+            totals.mem_bytes += s.mem_bytes;
+            totals.disk_bytes += s.disk_bytes;
+            totals.processes += s.processes;
+            s
+        })
+        .collect();
+    sessions.sort_by(|a, b| a.id.cmp(&b.id));
+
+    let (load_average, mem_total_bytes, mem_available_bytes) = overview::host_load();
+    Ok(Json(Overview {
+        generated_at: std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0),
+        host: overview::HostOverview {
+            cpus: std::thread::available_parallelism().map(|n| n.get()).unwrap_or(0),
+            load_average,
+            mem_total_bytes,
+            mem_available_bytes,
+            capacity: state.reservations.host,
+            reserved,
+            overcommit_ratio: state.reservations.overcommit_ratio,
+        },
+        totals,
+        top: TopConsumers {
+            cpu: overview::top_by(&sessions, top, |s| s.cpu_percent),
+            memory: overview::top_by(&sessions, top, |s| s.mem_bytes),
+            disk: overview::top_by(&sessions, top, |s| s.disk_bytes),
+        },
+        sessions,
+        ports: state.ports.stats(),
+        pool: state.warm_pool.stats().await,
+        queues: QueueDepths {
+            session_creates: state.create_stats.queue_depth(),
+            lifecycle_deliveries: state.lifecycle_hooks.iter().map(|hook| hook.pending()).sum(),
+        },
+    }))
+}
+
+/// Sessions of every org, oldest first.
+async fn admin_sessions(
+    State(state): State<AppState>,
+    Query(query): Query<AdminSessionsQuery>,
+) -> Json<Vec<AdminSessionInfo>> {
+    let sessions = state.sessions.read().await;
+    let now = Instant::now();
+    let label = |s: &Session| s.org_id.as_deref().map(|org| state.api_keys.org_label(org));
+    let mut listed: Vec<&Session> = sessions
+        .values()
+        .filter(|s| query.org.is_none() || label(s) == query.org)
+        .collect();
+    listed.sort_by(|a, b| (a.created_at, &a.id).cmp(&(b.created_at, &b.id)));
+    Json(
+        listed
+            .into_iter()
+            .map(|s| AdminSessionInfo {
+                org_id: label(s),
+                key_name: s.api_key.as_ref().map(|k| k.name.clone()).filter(|n| !n.is_empty()),
+                info: session_info(s, now, &state.cleanup_policy, &state.session_stats),
+            })
+            .collect(),
+    )
+}
+
+/// Delete a session, whichever org owns it.
+async fn force_delete_session(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+) -> Result<StatusCode, StatusCode> {
+    let session = state.sessions.write().await.remove(&id).ok_or(StatusCode::NOT_FOUND)?;
+    let org = session.org_id.clone();
+    teardown_session(&state, session, LifecycleTransition::Deleted).await;
+    let org = org.map(|org| state.api_keys.org_label(&org));
+    info!("Force-deleted session {} (org {})", id, org.as_deref().unwrap_or("none"));
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn admin_orgs(State(state): State<AppState>) -> Json<Vec<OrgUsage>> {
+    Json(admin::org_usage(&state).await)
+}
+
+async fn admin_metrics(State(state): State<AppState>) -> impl IntoResponse {
+    (
+        [(header::CONTENT_TYPE, admin::METRICS_CONTENT_TYPE)],
+        admin::render_metrics(&state).await,
+    )
+}
+
+/// Read the API key file again. Keys removed from it stop working at once;
+/// sessions they created keep running.
+async fn reload_api_keys(State(state): State<AppState>) -> Result<Json<ReloadResponse>, (StatusCode, String)> {
+    if state.key_source.is_none() {
+        return Err((
+            StatusCode::CONFLICT,
+            "API keys aren't loaded from a file, so there's nothing to reload".to_string(),
+        ));
+    }
+    let (keys, orgs) = tokio::task::spawn_blocking(move || state.reload_api_keys())
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, format!("Reload failed: {}", e)))?;
+    Ok(Json(ReloadResponse { keys, orgs }))
+}
+
+/// `GET /events/stream`: session, run and proxy events of every org as JSON
+/// over a WebSocket; see [`crate::events`].
+async fn event_stream(
+    State(state): State<AppState>,
+    Query(query): Query<ServerEventsQuery>,
+    ws: WebSocketUpgrade,
+) -> Result<Response, (StatusCode, String)> {
+    let filter = EventFilter::from_query(query).map_err(|e| (StatusCode::BAD_REQUEST, e))?;
+    // Subscribe now so events during the upgrade aren't lost
+    let rx = state.events.subscribe();
+    Ok(ws.on_upgrade(move |socket| relay_events(socket, rx, filter)))
+}
+
+/// Send matching events until the client goes away.
+async fn relay_events(socket: WebSocket, mut rx: broadcast::Receiver<ServerEvent>, filter: EventFilter) {
+    let (mut ws_tx, mut ws_rx) = socket.split();
+    loop {
+        tokio::select! {
+            event = rx.recv() => {
+                let text = match event {
+                    Ok(event) if filter.matches(&event) => serde_json::to_string(&event).unwrap_or_default(),
+                    Ok(_) => continue,
+                    Err(broadcast::error::RecvError::Lagged(missed)) => {
+                        serde_json::json!({"type": "lagged", "missed": missed}).to_string()
+                    }
+                    Err(broadcast::error::RecvError::Closed) => break,
+                };
+                if ws_tx.send(AxumWsMsg::Text(text)).await.is_err() {
+                    break;
+                }
+            }
+            msg = ws_rx.next() => match msg {
+                Some(Ok(AxumWsMsg::Close(_))) | Some(Err(_)) | None => break,
+                // Pings are answered by the WebSocket layer; nothing else is expected
+                Some(Ok(_)) => {}
+            },
+        }
+    }
+}
+
+/// Stop taking new sessions ahead of a shutdown; see [`AppState::drain`].
+async fn drain_server(State(state): State<AppState>) -> Json<DrainResponse> {
+    state.drain().await;
+    let sessions = state.sessions.read().await.len();
+    Json(DrainResponse { draining: true, sessions })
+}
+
+/// Parse a cron expression and preview its next fire times. An invalid
+/// expression or timezone is reported in the body, not as an error status.
+async fn validate_schedule(
+    State(state): State<AppState>,
+    api_key: Option<Extension<Arc<ApiKey>>>,
+    Json(req): Json<ValidateScheduleRequest>,
+) -> Result<Json<ValidateScheduleResponse>, (StatusCode, String)> {
+    let count = req.count.unwrap_or(schedule::DEFAULT_PREVIEW_COUNT);
+    if count == 0 || count > schedule::MAX_PREVIEW_COUNT {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            format!("count must be 1-{}", schedule::MAX_PREVIEW_COUNT),
+        ));
+    }
+    let session_tz = match req.session_id {
+        Some(ref id) => {
+            let key = api_key.as_ref().map(|Extension(key)| key.as_ref());
+            let sessions = state.sessions.read().await;
+            let session = sessions
+                .get(id)
+                .filter(|s| s.visible_to(key))
+                .ok_or((StatusCode::NOT_FOUND, "Session not found".to_string()))?;
+            session.env.get("TZ").cloned()
+        }
+        None => None,
+    };
+    let timezone = req
+        .timezone
+        .or(session_tz)
+        .unwrap_or_else(|| "UTC".to_string());
+
+    let parsed = schedule::parse_timezone(&timezone)
+        .and_then(|tz| schedule::parse(&req.expression).map(|s| (s, tz)));
+    let (next, error) = match parsed {
+        Ok((parsed, tz)) => (schedule::next_fire_times(&parsed, tz, chrono::Utc::now(), count), None),
+        Err(e) => (Vec::new(), Some(e)),
+    };
+    Ok(Json(ValidateScheduleResponse {
+        valid: error.is_none(),
+        error,
+        timezone,
+        next,
+    }))
+}
+
+async fn list_templates(
+    State(state): State<AppState>,
+) -> Result<Json<Vec<TemplateInfo>>, (StatusCode, String)> {
+    let templates = state.templates.clone();
+    tokio::task::spawn_blocking(move || templates.list())
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+        .map(Json)
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e))
+}
+
+async fn register_template(
+    State(state): State<AppState>,
+    api_key: Option<Extension<Arc<ApiKey>>>,
+    Json(req): Json<RegisterTemplateRequest>,
+) -> Result<Json<TemplateInfo>, (StatusCode, String)> {
+    validate_template_name(&req.name).map_err(|e| (StatusCode::BAD_REQUEST, e))?;
+    let source = state
+        .templates
+        .check_source(std::path::Path::new(&req.path))
+        .map_err(|e| (StatusCode::FORBIDDEN, e))?;
+    #[cfg(feature = "chaos")]
+    state.faults.check_storage_write("template write")?;
+    let templates = state.templates.clone();
+    let progress = state
+        .progress
+        .start("template.register")
+        .org(api_key.as_ref().map(|Extension(key)| key.org()))
+        .template(&req.name);
+    let info = tokio::task::spawn_blocking(move || templates.register_from_dir(&req.name, &source, progress))
+    .await
+    .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+    .map_err(|e| (StatusCode::BAD_REQUEST, e))?;
+    info!("Registered template {} ({} files)", info.name, info.files);
+    Ok(Json(info))
+}
+
+/// Register a template from a tar (or tar.gz) request body.
+async fn upload_template(
+    State(state): State<AppState>,
+    Path(name): Path<String>,
+    api_key: Option<Extension<Arc<ApiKey>>>,
+    body: Bytes,
+) -> Result<Json<TemplateInfo>, (StatusCode, String)> {
+    validate_template_name(&name).map_err(|e| (StatusCode::BAD_REQUEST, e))?;
+    #[cfg(feature = "chaos")]
+    state.faults.check_storage_write("template write")?;
+    let templates = state.templates.clone();
+    let progress = state
+        .progress
+        .start("template.register")
+        .org(api_key.as_ref().map(|Extension(key)| key.org()))
+        .template(&name);
+    let info = tokio::task::spawn_blocking(move || templates.register_from_tarball(&name, &body, progress))
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+        .map_err(|e| (StatusCode::BAD_REQUEST, e))?;
+    info!("Registered template {} ({} files)", info.name, info.files);
+    Ok(Json(info))
+}
+
+async fn delete_template(
+    State(state): State<AppState>,
+    Path(name): Path<String>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    let templates = state.templates.clone();
+    let removed = tokio::task::spawn_blocking(move || templates.remove(&name))
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+        .map_err(|e| (StatusCode::BAD_REQUEST, e))?;
+    if removed {
+        Ok(StatusCode::NO_CONTENT)
+    } else {
+        Err((StatusCode::NOT_FOUND, "Template not found".to_string()))
+    }
+}
+
+async fn create_session(
+    State(state): State<AppState>,
+    api_key: Option<Extension<Arc<ApiKey>>>,
+    Json(req): Json<CreateSessionRequest>,
+) -> Result<Json<CreateSessionResponse>, CreateSessionError> {
+    create_session_for(&state, api_key.map(|Extension(key)| key), req)
+        .await
+        .map(Json)
+}
+
+/// Why a create was refused, with how long to wait before trying again when
+/// the server is full.
+struct CreateSessionError {
+    status: StatusCode,
+    message: String,
+    retry_after: Option<Duration>,
+}
+
+impl From<(StatusCode, String)> for CreateSessionError {
+    fn from((status, message): (StatusCode, String)) -> Self {
+        Self {
+            status,
+            message,
+            retry_after: None,
+        }
+    }
+}
+
+impl From<CreateSessionError> for (StatusCode, String) {
+    fn from(e: CreateSessionError) -> Self {
+        (e.status, e.message)
+    }
+}
+
+impl IntoResponse for CreateSessionError {
+    fn into_response(self) -> Response {
+        match self.retry_after {
+            Some(after) => {
+                let secs = after.as_secs().max(1).to_string();
+                (self.status, [(header::RETRY_AFTER, secs)], self.message).into_response()
+            }
+            None => (self.status, self.message).into_response(),
+        }
+    }
+}
+
+/// Create a session on behalf of `api_key`.
+async fn create_session_for(
+    state: &AppState,
+    api_key: Option<Arc<ApiKey>>,
+    mut req: CreateSessionRequest,
+) -> Result<CreateSessionResponse, CreateSessionError> {
+    if api_key.as_ref().is_some_and(|key| key.session_id.is_some()) {
+        return Err((StatusCode::FORBIDDEN, "Session tokens can't create sessions".to_string()).into());
+    }
+    if state.is_draining() {
+        return Err((StatusCode::SERVICE_UNAVAILABLE, "Server is draining; not taking new sessions".to_string()).into());
+    }
+    let session_id = uuid::Uuid::new_v4().to_string();
+
+    let ttl = state
+        .resolve_session_ttl(req.ttl)
+        .map_err(|e| (StatusCode::BAD_REQUEST, e))?;
+    state
+        .env_policy
+        .apply(&mut req.env)
+        .map_err(|e| (StatusCode::BAD_REQUEST, e))?;
+    state
+        .env_policy
+        .apply(&mut req.secrets)
+        .map_err(|e| (StatusCode::BAD_REQUEST, e))?;
+    state
+        .check_session_create(api_key.as_deref(), &req)
+        .map_err(|e| (StatusCode::FORBIDDEN, e))?;
+
+    let preview_auth = req
+        .preview_auth
+        .map(PreviewAuth::from_request)
+        .transpose()
+        .map_err(|e| (StatusCode::BAD_REQUEST, e))?;
+    let preview_token = preview_auth.as_ref().and_then(|a| a.token()).map(str::to_string);
+
+    validate_labels(&req.labels).map_err(|e| (StatusCode::BAD_REQUEST, e))?;
+    validate_secrets(&req.secrets).map_err(|e| (StatusCode::BAD_REQUEST, e))?;
+    let mut determinism = req.determinism.unwrap_or_default();
+    determinism.validate().map_err(|e| (StatusCode::BAD_REQUEST, e))?;
+    determinism
+        .hostname
+        .get_or_insert_with(|| sandbox::default_hostname(&session_id));
+    if let Some(ref name) = req.template {
+        if !state.templates.exists(name) {
+            return Err((StatusCode::NOT_FOUND, format!("Template {:?} not found", name)).into());
+        }
+    }
+    if let Some(ref resources) = req.resources {
+        resources.validate().map_err(|e| (StatusCode::BAD_REQUEST, e))?;
+    }
+    if let Some(ref policy) = req.egress {
+        egress::validate(policy).map_err(|e| (StatusCode::BAD_REQUEST, e))?;
+    }
+    if let Some(ref limits) = req.limits {
+        limits.validate().map_err(|e| (StatusCode::BAD_REQUEST, e))?;
+    }
+    let limits = match state.cgroups {
+        Some(ref cgroups) => {
+            let limits = cgroups.resolve(req.limits);
+            cgroups.check(&limits).map_err(|e| (StatusCode::FORBIDDEN, e))?;
+            Some(limits)
+        }
+        None if req.limits.is_some() => return Err(cgroups_off().into()),
+        None => None,
+    };
+    if req.trash_ttl == Some(0) {
+        return Err((StatusCode::BAD_REQUEST, "trash_ttl must be at least 1 second".to_string()).into());
+    }
+
+    if let Some(ref slug) = req.slug {
+        validate_slug(slug).map_err(|e| (StatusCode::BAD_REQUEST, e))?;
+    }
+    {
+        // A full server passes if a session could be evicted; that happens
+        // only once the create is certain to go ahead
+        let sessions = state.sessions.read().await;
+        let evictable = evictable_session(state, &sessions).is_some();
+        check_session_quota(state, &sessions, api_key.as_deref(), evictable)?;
+        check_reservation(state, &sessions, req.resources.as_ref())?;
+    }
+    state
+        .check_org_quota(api_key.as_deref())
+        .await
+        .map_err(|e| (StatusCode::TOO_MANY_REQUESTS, e))?;
+
+    let image = match req.image {
+        Some(_) if !state.backend.supports_images() => {
+            let message = format!("The {} sandbox backend can't build sessions on images", state.backend.name());
+            return Err((StatusCode::BAD_REQUEST, message).into());
+        }
+        Some(ref reference) => {
+            let progress = state
+                .progress
+                .start("image.pull")
+                .org(api_key.as_deref().map(ApiKey::org))
+                .session(&session_id);
+            let image = state.images.pull(reference, progress).await?;
+            info!("Pulled image {} ({}) for session {}", reference, image.digest, session_id);
+            Some(image)
+        }
+        None => None,
+    };
+
+    // Claim the slug before doing any sandbox work so concurrent creates can't both win
+    if let Some(ref slug) = req.slug {
+        state
+            .reserve_slug(slug, &session_id)
+            .await
+            .map_err(|e| (StatusCode::CONFLICT, e))?;
+    }
+
+    let pending = state.create_stats.begin();
+    let started = Instant::now();
+    // Warm roots are blank; template and image sessions are layered on theirs
+    let warm_root = match req.template.as_deref() {
+        None | Some(templates::BLANK_TEMPLATE) if image.is_none() => state.warm_pool.take().await,
+        _ => None,
+    };
+    let warm = warm_root.is_some();
+    let sandbox_root = match warm_root {
+        Some(root) => Ok(root),
+        None => tokio::task::spawn_blocking({
+            let (templates, template) = (state.templates.clone(), req.template.clone());
+            let backend = state.backend.clone();
+            let image_root = image.as_ref().map(|image| image.root.clone());
+            let working_dir = image.as_ref().and_then(|image| image.working_dir.clone());
+            let session_id = session_id.clone();
+            move || {
+                let root = templates.with_layer(template.as_deref(), |layer| {
+                    backend.create(&session_id, layer, image_root.as_deref())
+                })?;
+                // Container runtimes create an image's working dir if it has none
+                if let Some(dir) = working_dir {
+                    let created = safe_path::Root::open(&root)
+                        .and_then(|r| r.create_dir_all(std::path::Path::new(&dir), None));
+                    if let Err(e) = created {
+                        backend.destroy(&root);
+                        return Err(e);
+                    }
+                }
+                Ok(root)
+            }
+        })
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))
+        .and_then(|r| r.map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e))),
+    };
+    let sandbox_root = match sandbox_root {
+        Ok(root) => apply_determinism(state, determinism.clone(), root).await,
+        Err(e) => Err(e),
+    };
+    let sandbox_root = match (sandbox_root, req.egress.clone()) {
+        (Ok(root), Some(policy)) => match state.egress.enable(&session_id, &root, policy) {
+            Ok(()) => Ok(root),
+            Err(e) => {
+                let backend = state.backend.clone();
+                let _ = tokio::task::spawn_blocking(move || backend.destroy(&root)).await;
+                Err((StatusCode::INTERNAL_SERVER_ERROR, e))
+            }
+        },
+        (sandbox_root, _) => sandbox_root,
+    };
+    let sandbox_root = match (sandbox_root, &state.cgroups, limits) {
+        (Ok(root), Some(cgroups), Some(limits)) => match cgroups.attach(&session_id, &root, &limits) {
+            Ok(()) => Ok(root),
+            Err(e) => {
+                state.egress.disable(&session_id, &root);
+                let backend = state.backend.clone();
+                let _ = tokio::task::spawn_blocking(move || backend.destroy(&root)).await;
+                Err((StatusCode::INTERNAL_SERVER_ERROR, format!("session limits: {}", e)))
+            }
+        },
+        (sandbox_root, ..) => sandbox_root,
+    };
+    let sandbox_root = match sandbox_root {
+        Ok(root) => root,
+        Err(e) => {
+            if let Some(ref slug) = req.slug {
+                state.release_slug(slug).await;
+            }
+            return Err(e.into());
+        }
+    };
+
+    // Generate preview URL if preview_domain is configured
+    let preview_label = req.slug.clone().unwrap_or_else(|| session_id.clone());
+    let preview_url = state.preview_url(&preview_label);
+
+    // Image defaults sit below key defaults (registry mirrors, proxies, ...),
+    // which sit below user-provided env
+    let mut env = image.as_ref().map(|image| image.env.clone()).unwrap_or_default();
+    env.extend(api_key.as_ref().map(|key| key.env.clone()).unwrap_or_default());
+    env.extend(req.env);
+    let cwd = image
+        .and_then(|image| image.working_dir)
+        .unwrap_or_else(|| "/".to_string());
+    if let Some(seed) = determinism.seed {
+        // Python accepts hash seeds up to 2^32 - 1
+        env.entry("PYTHONHASHSEED".to_string())
+            .or_insert_with(|| (seed % (1 << 32)).to_string());
+    }
+
+    // The baseline `GET /sessions/:id/changes` compares against. A session
+    // whose tree can't be recorded still works, without change tracking
+    let recorded = tokio::task::spawn_blocking({
+        let (root, session_id) = (sandbox_root.clone(), session_id.clone());
+        move || changes::record(&root, &session_id)
+    })
+    .await;
+    if let Ok(Err(e)) | Err(e) = recorded.map_err(|e| e.to_string()) {
+        warn!("Not tracking changes in session {}: {}", session_id, e);
+    }
+
+    let session = Session {
+        id: session_id.clone(),
+        sandbox_root,
+        env,
+        cwd,
+        created_at: Instant::now(),
+        last_used: Instant::now(),
+        last_activity: None,
+        ttl,
+        preview_url: preview_url.clone(),
+        ports: Vec::new(),
+        detected_ports: Vec::new(),
+        static_site: None,
+        status: SessionStatus::Running,
+        background_pids: Vec::new(),
+        preview_auth,
+        slug: req.slug,
+        template: req.template,
+        image: req.image,
+        labels: req.labels,
+        determinism,
+        api_key: api_key.clone(),
+        org_id: api_key.as_ref().map(|key| key.org().to_string()),
+        resources: req.resources,
+        limits,
+        tokens: HashMap::new(),
+        secrets: req.secrets,
+        egress: req.egress,
+        ssh_keys: Vec::new(),
+        trash_ttl: req.trash_ttl.map(Duration::from_secs),
+        tasks: Default::default(),
+        schedules: Default::default(),
+    };
+
+    let event = SessionLifecycleEvent::from_session(&session);
+    let template = session.template.clone();
+    let evicted = {
+        // Re-check under the write lock: concurrent creates may have used up
+        // the quota or the host capacity. Evicting to make room happens here
+        // too, so a create that fails never costs another session
+        let mut sessions = state.sessions.write().await;
+        let evict = evictable_session(state, &sessions);
+        let checked = check_session_quota(state, &sessions, api_key.as_deref(), evict.is_some())
+            .and_then(|()| check_reservation(state, &sessions, session.resources.as_ref()).map_err(Into::into));
+        if let Err(e) = checked {
+            drop(sessions);
+            if let Some(ref slug) = session.slug {
+                state.release_slug(slug).await;
+            }
+            state.egress.disable(&session.id, &session.sandbox_root);
+            changes::remove(&session.id);
+            let (backend, cgroups) = (state.backend.clone(), state.cgroups.clone());
+            let (root, session_id) = (session.sandbox_root, session.id);
+            let _ = tokio::task::spawn_blocking(move || {
+                backend.destroy(&root);
+                if let Some(cgroups) = cgroups {
+                    cgroups.detach(&session_id, &root);
+                }
+            })
+            .await;
+            return Err(e);
+        }
+        let evicted = evict.and_then(|id| sessions.remove(&id));
+        state.persist_session(&session);
+        sessions.insert(session_id.clone(), session);
+        evicted
+    };
+    if let Some(evicted) = evicted {
+        info!("Evicted session {} to make room for session {}", evicted.id, session_id);
+        teardown_session(state, evicted, LifecycleTransition::Evicted).await;
+    }
+    state.create_stats.record(warm, started.elapsed());
+    drop(pending);
+    info!("Created session: {}", session_id);
+    state.timeline.record(&session_id, SessionEventKind::Created { template });
+    if let Err(e) = state.history.open(&session_id) {
+        warn!("Not keeping history of session {}: {}", session_id, e);
+    }
+    state.notify_lifecycle(LifecycleTransition::Created, &event);
+
+    Ok(CreateSessionResponse {
+        session_id,
+        preview_url,
+        preview_token,
+        capacity: Some(state.capacity(api_key.as_deref()).await),
+    })
+}
+
+/// Refuse a create whose resource reservation no longer fits on the host.
+fn check_reservation(
+    state: &AppState,
+    sessions: &HashMap<String, Session>,
+    request: Option<&Resources>,
+) -> Result<(), (StatusCode, String)> {
+    let Some(request) = request else {
+        return Ok(());
+    };
+    state
+        .reservations
+        .check(reservation::reserved(sessions), request)
+        .map_err(|e| (StatusCode::SERVICE_UNAVAILABLE, e))
+}
+
+/// Refuse a create when the server (`503`) or the key or its org (`429`)
+/// has no sessions left. A full server is fine when `evicting` a session.
+fn check_session_quota(
+    state: &AppState,
+    sessions: &HashMap<String, Session>,
+    key: Option<&ApiKey>,
+    evicting: bool,
+) -> Result<(), CreateSessionError> {
+    if !evicting && capacity::remaining(state.max_sessions, sessions.len()) == Some(0) {
+        // The soonest a session can free up on its own
+        let now = Instant::now();
+        let retry_after = sessions.values().map(|s| state.cleanup_policy.expires_in(s, now)).min();
+        return Err(CreateSessionError {
+            status: StatusCode::SERVICE_UNAVAILABLE,
+            message: "Server is at its session limit; retry later or try another instance".to_string(),
+            retry_after,
+        });
+    }
+    if state.sessions_remaining(sessions, key) == Some(0) {
+        return Err((
+            StatusCode::TOO_MANY_REQUESTS,
+            "Session limit reached; delete a session or try another instance".to_string(),
+        )
+            .into());
+    }
+    Ok(())
+}
+
+/// The session to evict to make room for a create under the server's
+/// session cap: with `--when-full evict` and the cap reached, the one idle
+/// the longest, if it has been idle long enough and isn't busy.
+fn evictable_session(state: &AppState, sessions: &HashMap<String, Session>) -> Option<String> {
+    let max = state.max_sessions?;
+    if state.full_policy != FullPolicy::Evict || sessions.len() < max {
+        return None;
+    }
+    let now = Instant::now();
+    let min_idle = Duration::from_secs(EVICT_MIN_IDLE_SECS);
+    sessions
+        .values()
+        .filter(|s| now.duration_since(s.idle_since().0) >= min_idle)
+        .filter(|s| state.activity.busy(&s.id, &state.session_stats.get(&s.id)).is_none())
+        .min_by_key(|s| s.idle_since().0)
+        .map(|s| s.id.clone())
+}
+
+/// Filters for `GET /events`; unset fields match everything.
+#[derive(Deserialize)]
+struct EventsQuery {
+    operation: Option<String>,
+    session_id: Option<String>,
+    template: Option<String>,
+}
+
+impl EventsQuery {
+    fn matches(&self, event: &ProgressEvent) -> bool {
+        self.operation.as_deref().is_none_or(|o| o == event.operation)
+            && self
+                .session_id
+                .as_ref()
+                .is_none_or(|id| event.session_id.as_ref() == Some(id))
+            && self
+                .template
+                .as_ref()
+                .is_none_or(|t| event.template.as_ref() == Some(t))
+    }
+}
+
+/// Whether a keyed caller may see a progress update: only operations its
+/// org started, and for a session-restricted key only that session's.
+fn progress_visible_to(update: &ProgressUpdate, key: Option<&ApiKey>) -> bool {
+    let Some(key) = key else {
+        return true;
+    };
+    update.org_id.as_deref() == Some(key.org())
+        && key
+            .session_id
+            .as_ref()
+            .is_none_or(|id| update.event.session_id.as_ref() == Some(id))
+}
+
+/// Stream progress events as server-sent events (`event: progress`).
+/// Keyed callers only see operations started by their own org.
+async fn progress_events(
+    State(state): State<AppState>,
+    api_key: Option<Extension<Arc<ApiKey>>>,
+    Query(query): Query<EventsQuery>,
+) -> Sse<impl futures_util::Stream<Item = Result<SseEvent, Infallible>>> {
+    let rx = state.progress.subscribe();
+    let key = api_key.map(|Extension(key)| key);
+    let stream = futures_util::stream::unfold((rx, query, key), |(mut rx, query, key)| async move {
+        loop {
+            match rx.recv().await {
+                Ok(update) if progress_visible_to(&update, key.as_deref()) && query.matches(&update.event) => {
+                    let sse = SseEvent::default()
+                        .event("progress")
+                        .json_data(&update.event)
+                        .unwrap_or_default();
+                    return Some((Ok(sse), (rx, query, key)));
+                }
+                Ok(_) => {}
+                // A slow client missed some updates; later ones carry totals so it catches up
+                Err(broadcast::error::RecvError::Lagged(_)) => {}
+                Err(broadcast::error::RecvError::Closed) => return None,
+            }
+        }
+    });
+    Sse::new(stream).keep_alive(KeepAlive::default())
+}
+
+/// Set up a session's hostname, /etc files, machine ID and seeded devices,
+/// destroying the sandbox on failure.
+async fn apply_determinism(
+    state: &AppState,
+    determinism: Determinism,
+    sandbox_root: PathBuf,
+) -> Result<PathBuf, (StatusCode, String)> {
+    let root = sandbox_root.clone();
+    let result = tokio::task::spawn_blocking(move || sandbox::apply_determinism(&root, &determinism))
+        .await
+        .map_err(|e| e.to_string())
+        .and_then(|r| r);
+    match result {
+        Ok(()) => Ok(sandbox_root),
+        Err(e) => {
+            let backend = state.backend.clone();
+            let _ = tokio::task::spawn_blocking(move || backend.destroy(&sandbox_root)).await;
+            Err((StatusCode::INTERNAL_SERVER_ERROR, format!("apply determinism: {}", e)))
+        }
+    }
+}
+
+/// Max page size for `GET /sessions?limit=`.
+const MAX_LIST_LIMIT: usize = 1000;
+
+/// Response header carrying the cursor for the next page.
+const NEXT_CURSOR_HEADER: &str = "x-next-cursor";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum SessionSort {
+    Age,
+    Idle,
+}
+
+/// Query for `GET /sessions`. `label` may repeat; all filters must match.
+struct ListSessionsQuery {
+    /// `key=value` for an exact match, or `key` to require the label exists
+    labels: Vec<(String, Option<String>)>,
+    status: Option<SessionStatus>,
+    sort: SessionSort,
+    descending: bool,
+    limit: Option<usize>,
+    cursor: Option<ListCursor>,
+    /// Fields to include (all when unset); `id` is always included
+    fields: Option<Vec<String>>,
+}
+
+/// Position after the last session of a page, for keyset pagination.
+struct ListCursor {
+    key: u128,
+    id: String,
+}
+
+impl ListSessionsQuery {
+    fn from_query(params: Vec<(String, String)>) -> Result<Self, String> {
+        let mut query = ListSessionsQuery {
+            labels: Vec::new(),
+            status: None,
+            sort: SessionSort::Age,
+            // Oldest first
+            descending: true,
+            limit: None,
+            cursor: None,
+            fields: None,
+        };
+        let mut cursor = None;
+        for (key, value) in params {
+            match key.as_str() {
+                "label" => query.labels.push(match value.split_once('=') {
+                    Some((k, v)) => (k.to_string(), Some(v.to_string())),
+                    None => (value, None),
+                }),
+                "status" => query.status = Some(value.parse()?),
+                "sort" => {
+                    query.sort = match value.as_str() {
+                        "age" => SessionSort::Age,
+                        "idle" => SessionSort::Idle,
+                        other => return Err(format!("invalid sort {:?} (expected age or idle)", other)),
+                    }
+                }
+                "order" => {
+                    query.descending = match value.as_str() {
+                        "asc" => false,
+                        "desc" => true,
+                        other => return Err(format!("invalid order {:?} (expected asc or desc)", other)),
+                    }
+                }
+                "limit" => {
+                    let limit: usize = value.parse().map_err(|_| format!("invalid limit {:?}", value))?;
+                    if limit == 0 || limit > MAX_LIST_LIMIT {
+                        return Err(format!("limit must be 1-{}", MAX_LIST_LIMIT));
+                    }
+                    query.limit = Some(limit);
+                }
+                "cursor" => cursor = Some(value),
+                "fields" => {
+                    query.fields = Some(value.split(',').map(|f| f.trim().to_string()).collect());
+                }
+                _ => {}
+            }
+        }
+        if let Some(cursor) = cursor {
+            query.cursor = Some(query.decode_cursor(&cursor)?);
+        }
+        Ok(query)
+    }
+
+    fn matches(&self, session: &Session) -> bool {
+        if self.status.is_some_and(|status| session.status != status) {
+            return false;
+        }
+        self.labels.iter().all(|(key, value)| match (session.labels.get(key), value) {
+            (Some(actual), Some(expected)) => actual == expected,
+            (Some(_), None) => true,
+            (None, _) => false,
+        })
+    }
+
+    /// Sort key: when the session was created or last used or busy. Larger
+    /// age or idle time means a smaller key.
+    fn sort_key(&self, session: &Session, started_at: Instant) -> u128 {
+        let at = match self.sort {
+            SessionSort::Age => session.created_at,
+            SessionSort::Idle => session.idle_since().0,
+        };
+        at.saturating_duration_since(started_at).as_nanos()
+    }
+
+    /// Cursors are tied to the sort they were issued for.
+    fn sort_tag(&self) -> String {
+        let sort = match self.sort {
+            SessionSort::Age => "age",
+            SessionSort::Idle => "idle",
+        };
+        format!("{}:{}", sort, if self.descending { "desc" } else { "asc" })
+    }
+
+    fn encode_cursor(&self, key: u128, id: &str) -> String {
+        hex::encode(format!("{}:{}:{}", self.sort_tag(), key, id))
+    }
+
+    fn decode_cursor(&self, cursor: &str) -> Result<ListCursor, String> {
+        let invalid = || "invalid cursor".to_string();
+        let raw = hex::decode(cursor).map_err(|_| invalid())?;
+        let raw = String::from_utf8(raw).map_err(|_| invalid())?;
+        let rest = raw
+            .strip_prefix(&format!("{}:", self.sort_tag()))
+            .ok_or_else(|| "cursor does not match sort/order".to_string())?;
+        let (key, id) = rest.split_once(':').ok_or_else(invalid)?;
+        Ok(ListCursor {
+            key: key.parse().map_err(|_| invalid())?,
+            id: id.to_string(),
+        })
+    }
+}
+
+/// `GET /sessions`: filtered, sorted, optionally paginated. The body stays a
+/// plain array; when more sessions remain the next page's cursor is returned
+/// in the `x-next-cursor` header.
+async fn list_sessions(
+    State(state): State<AppState>,
+    api_key: Option<Extension<Arc<ApiKey>>>,
+    Query(params): Query<Vec<(String, String)>>,
+) -> Result<Response, (StatusCode, String)> {
+    let key = api_key.as_ref().map(|Extension(key)| key.as_ref());
+    let query = ListSessionsQuery::from_query(params).map_err(|e| (StatusCode::BAD_REQUEST, e))?;
+    let sessions = state.sessions.read().await;
+    let now = Instant::now();
+
+    // Age/idle order is the reverse of timestamp order
+    let mut keyed: Vec<(u128, &Session)> = sessions
+        .values()
+        .filter(|s| s.visible_to(key) && query.matches(s))
+        .map(|s| (query.sort_key(s, state.started_at), s))
+        .collect();
+    keyed.sort_by(|a, b| (a.0, &a.1.id).cmp(&(b.0, &b.1.id)));
+    if !query.descending {
+        keyed.reverse();
+    }
+    if let Some(ref cursor) = query.cursor {
+        let after = |k: u128, id: &String| {
+            let pos = (k, id).cmp(&(cursor.key, &cursor.id));
+            if query.descending { pos.is_gt() } else { pos.is_lt() }
+        };
+        keyed.retain(|(k, s)| after(*k, &s.id));
+    }
+
+    let mut next_cursor = None;
+    if let Some(limit) = query.limit {
+        if keyed.len() > limit {
+            keyed.truncate(limit);
+            let (key, last) = keyed[limit - 1];
+            next_cursor = Some(query.encode_cursor(key, &last.id));
+        }
+    }
+
+    let list: Vec<serde_json::Value> = keyed
+        .iter()
+        .map(|(_, s)| {
+            let info = serde_json::to_value(session_info(s, now, &state.cleanup_policy, &state.session_stats))
+                .unwrap_or_default();
+            match (&query.fields, info) {
+                (Some(fields), serde_json::Value::Object(map)) => serde_json::Value::Object(
+                    map.into_iter()
+                        .filter(|(k, _)| k == "id" || fields.iter().any(|f| f == k))
+                        .collect(),
+                ),
+                (_, info) => info,
+            }
+        })
+        .collect();
+
+    let mut response = Json(list).into_response();
+    if let Some(cursor) = next_cursor {
+        if let Ok(value) = header::HeaderValue::from_str(&cursor) {
+            response.headers_mut().insert(NEXT_CURSOR_HEADER, value);
+        }
+    }
+    Ok(response)
+}
+
+async fn get_session(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+) -> Result<Json<SessionInfo>, StatusCode> {
+    let sessions = state.sessions.read().await;
+    let session = sessions.get(&id).ok_or(StatusCode::NOT_FOUND)?;
+    Ok(Json(session_info(session, Instant::now(), &state.cleanup_policy, &state.session_stats)))
+}
+
+/// What the session's files take on disk, and where.
+async fn session_usage(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+    Query(query): Query<DiskUsageQuery>,
+) -> Result<Json<DiskUsage>, (StatusCode, String)> {
+    let top = query.top.unwrap_or(disk_usage::DEFAULT_TOP);
+    if top > disk_usage::MAX_TOP {
+        return Err((StatusCode::BAD_REQUEST, format!("top must be at most {}", disk_usage::MAX_TOP)));
+    }
+    let depth = query.depth.unwrap_or(disk_usage::DEFAULT_DEPTH);
+    if depth > disk_usage::MAX_DEPTH {
+        return Err((StatusCode::BAD_REQUEST, format!("depth must be at most {}", disk_usage::MAX_DEPTH)));
+    }
+    let sandbox_root = {
+        let sessions = state.sessions.read().await;
+        let session = sessions
+            .get(&id)
+            .ok_or((StatusCode::NOT_FOUND, "Session not found".to_string()))?;
+        session.sandbox_root.clone()
+    };
+    let cache = state.disk_usage.clone();
+    tokio::task::spawn_blocking(move || cache.usage(&id, &sandbox_root, top, depth, query.refresh))
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+        .map(Json)
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e))
+}
+
+/// Resource use of the session's processes, with recent samples.
+async fn session_stats(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+) -> Result<Json<SessionStatsHistory>, (StatusCode, String)> {
+    if !state.sessions.read().await.contains_key(&id) {
+        return Err((StatusCode::NOT_FOUND, "Session not found".to_string()));
+    }
+    Ok(Json(state.session_stats.history(&id)))
+}
+
+/// Stream the session's samples as server-sent events (`event: stats`),
+/// starting with the latest. Ends when the session does.
+async fn session_stats_stream(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+) -> Result<Sse<impl futures_util::Stream<Item = Result<SseEvent, Infallible>>>, (StatusCode, String)> {
+    let (latest, rx) = state.session_stats.subscribe(&id);
+    if !state.sessions.read().await.contains_key(&id) {
+        return Err((StatusCode::NOT_FOUND, "Session not found".to_string()));
+    }
+    let event = |sample: &StatsSample| SseEvent::default().event("stats").json_data(sample).unwrap_or_default();
+    let first = futures_util::stream::iter(latest.map(|sample| Ok(event(&sample))));
+    let updates = futures_util::stream::unfold(rx, move |mut rx| {
+        let id = id.clone();
+        async move {
+            loop {
+                match rx.recv().await {
+                    Ok((session, Some(sample))) if *session == *id => return Some((Ok(event(&sample)), rx)),
+                    Ok((session, None)) if *session == *id => return None,
+                    Ok(_) => {}
+                    // A slow client missed some samples; the next one is current
+                    Err(broadcast::error::RecvError::Lagged(_)) => {}
+                    Err(broadcast::error::RecvError::Closed) => return None,
+                }
+            }
+        }
+    });
+    Ok(Sse::new(first.chain(updates)).keep_alive(KeepAlive::default()))
+}
+
+/// The session's recent events, oldest first.
+async fn session_events(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+    Query(query): Query<SessionEventsQuery>,
+) -> Result<Json<Vec<SessionEvent>>, (StatusCode, String)> {
+    if !state.sessions.read().await.contains_key(&id) {
+        return Err((StatusCode::NOT_FOUND, "Session not found".to_string()));
+    }
+    let events = state.timeline.events(&id, query.after).unwrap_or_default();
+    Ok(Json(events))
+}
+
+/// A page of the commands the session ran.
+async fn session_history(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+    Query(query): Query<HistoryQuery>,
+) -> Result<Json<HistoryPage>, (StatusCode, String)> {
+    if !state.sessions.read().await.contains_key(&id) {
+        return Err((StatusCode::NOT_FOUND, "Session not found".to_string()));
+    }
+    let history = state.history.clone();
+    tokio::task::spawn_blocking(move || history.page(&id, &query))
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+        .map(Json)
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e))
+}
+
+/// The kept output of one command in the history.
+async fn session_history_output(
+    State(state): State<AppState>,
+    Path((id, seq)): Path<(String, u64)>,
+) -> Result<Json<HistoryOutput>, (StatusCode, String)> {
+    if !state.sessions.read().await.contains_key(&id) {
+        return Err((StatusCode::NOT_FOUND, "Session not found".to_string()));
+    }
+    let history = state.history.clone();
+    tokio::task::spawn_blocking(move || history.output(&id, seq))
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e))?
+        .map(Json)
+        .ok_or((StatusCode::NOT_FOUND, format!("No output kept for command {}", seq)))
+}
+
+/// Stream the session's events as server-sent events (`event: timeline`,
+/// with the `seq` as the event ID), starting with the kept ones after
+/// `after` or `Last-Event-ID`. Ends after the session's last event.
+async fn session_events_stream(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+    Query(query): Query<SessionEventsQuery>,
+    headers: HeaderMap,
+) -> Result<Sse<impl futures_util::Stream<Item = Result<SseEvent, Infallible>>>, (StatusCode, String)> {
+    let after = query.after.or_else(|| {
+        headers
+            .get("last-event-id")
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.parse().ok())
+    });
+    let (backlog, rx) = state
+        .timeline
+        .subscribe(&id, after)
+        .ok_or((StatusCode::NOT_FOUND, "Session not found".to_string()))?;
+    let ended = |event: &SessionEvent| {
+        matches!(event.kind, SessionEventKind::Expired | SessionEventKind::Deleted | SessionEventKind::Evicted)
+    };
+    let sse = |event: &SessionEvent| {
+        SseEvent::default()
+            .event("timeline")
+            .id(event.seq.to_string())
+            .json_data(event)
+            .unwrap_or_default()
+    };
+    let first = futures_util::stream::iter(backlog.into_iter().map(move |event| Ok(sse(&event))));
+    let updates = futures_util::stream::unfold(Some(rx), move |rx| {
+        let id = id.clone();
+        async move {
+            let mut rx = rx?;
+            loop {
+                match rx.recv().await {
+                    Ok((session, event)) if *session == *id => {
+                        let next = (!ended(&event)).then_some(rx);
+                        return Some((Ok(sse(&event)), next));
+                    }
+                    Ok(_) => {}
+                    // A slow client missed some events; it can fetch them with `after`
+                    Err(broadcast::error::RecvError::Lagged(_)) => {}
+                    Err(broadcast::error::RecvError::Closed) => return None,
+                }
+            }
+        }
+    });
+    Ok(Sse::new(first.chain(updates)).keep_alive(KeepAlive::default()))
+}
+
+/// Mint a token that acts as the caller's key, restricted to this session and
+/// to the requested scopes.
+async fn create_session_token(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+    api_key: Option<Extension<Arc<ApiKey>>>,
+    Json(req): Json<CreateSessionTokenRequest>,
+) -> Result<Json<CreateSessionTokenResponse>, (StatusCode, String)> {
+    let Some(Extension(parent)) = api_key else {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "Session tokens require API key authentication".to_string(),
+        ));
+    };
+    if req.scopes.is_empty() {
+        return Err((StatusCode::BAD_REQUEST, "scopes must not be empty".to_string()));
+    }
+    // Expand patterns; wildcards skip scopes a token can't carry
+    let mut scopes: Vec<Scope> = Vec::new();
+    for pattern in &req.scopes {
+        scope::validate_pattern(pattern).map_err(|e| (StatusCode::BAD_REQUEST, e))?;
+        let exact = !pattern.ends_with('*');
+        for granted in Scope::ALL.iter().copied().filter(|s| s.matches(pattern)) {
+            if !granted.is_session_level() {
+                if exact {
+                    return Err((
+                        StatusCode::BAD_REQUEST,
+                        format!("scope {} can't be granted to a session token", granted),
+                    ));
+                }
+                continue;
+            }
+            if !parent.allows(granted) {
+                return Err((StatusCode::FORBIDDEN, format!("API key lacks scope {}", granted)));
+            }
+            if !scopes.contains(&granted) {
+                scopes.push(granted);
+            }
+        }
+    }
+    let ttl = match req.ttl {
+        Some(0) => return Err((StatusCode::BAD_REQUEST, "ttl must be positive".to_string())),
+        ttl => ttl.map(Duration::from_secs),
+    };
+
+    let token = format!("{}{}", SESSION_TOKEN_PREFIX, preview_auth::generate_token());
+    let scopes: Vec<String> = scopes.iter().map(|s| s.as_str().to_string()).collect();
+    let key = parent.session_token(token.clone(), &id, scopes.clone());
+    let mut sessions = state.sessions.write().await;
+    let session = sessions
+        .get_mut(&id)
+        .ok_or((StatusCode::NOT_FOUND, "Session not found".to_string()))?;
+    session.tokens.insert(
+        token.clone(),
+        SessionToken {
+            key: Arc::new(key),
+            expires_at: ttl.map(|ttl| Instant::now() + ttl),
+            share: None,
+        },
+    );
+    state.persist_session(session);
+    info!("Created session token for session {} with scopes {:?}", id, scopes);
+
+    Ok(Json(CreateSessionTokenResponse {
+        token,
+        scopes,
+        expires_in_secs: ttl.map(|ttl| ttl.as_secs()),
+    }))
+}
+
+/// Mint a token that hands someone else `access` to this session until it
+/// expires, without sharing the caller's key.
+async fn create_share(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+    api_key: Option<Extension<Arc<ApiKey>>>,
+    req: Option<Json<CreateShareRequest>>,
+) -> Result<Json<CreateShareResponse>, (StatusCode, String)> {
+    let Some(Extension(parent)) = api_key else {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "Sharing requires API key authentication".to_string(),
+        ));
+    };
+    let req = req.map(|Json(r)| r).unwrap_or_default();
+    let ttl = match req.ttl {
+        Some(0) => return Err((StatusCode::BAD_REQUEST, "ttl must be positive".to_string())),
+        Some(secs) if secs > MAX_SHARE_TTL.as_secs() => {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                format!("ttl must be at most {} seconds", MAX_SHARE_TTL.as_secs()),
+            ))
+        }
+        ttl => ttl.map_or(DEFAULT_SHARE_TTL, Duration::from_secs),
+    };
+    let scopes = scope::share_scopes(req.access);
+    if let Some(missing) = scopes.iter().find(|s| !parent.allows(**s)) {
+        return Err((StatusCode::FORBIDDEN, format!("API key lacks scope {}", missing)));
+    }
+
+    let token = format!("{}{}", SESSION_TOKEN_PREFIX, preview_auth::generate_token());
+    let scopes: Vec<String> = scopes.iter().map(|s| s.as_str().to_string()).collect();
+    let key = parent.session_token(token.clone(), &id, scopes.clone());
+    let share_id = uuid::Uuid::new_v4().to_string();
+    let mut sessions = state.sessions.write().await;
+    let session = sessions
+        .get_mut(&id)
+        .ok_or((StatusCode::NOT_FOUND, "Session not found".to_string()))?;
+    let now = Instant::now();
+    session.tokens.retain(|_, t| t.expires_at.is_none_or(|at| at > now));
+    session.tokens.insert(
+        token.clone(),
+        SessionToken {
+            key: Arc::new(key),
+            expires_at: Some(now + ttl),
+            share: Some(ShareGrant {
+                id: share_id.clone(),
+                access: req.access,
+                label: req.label.clone(),
+            }),
+        },
+    );
+    state.persist_session(session);
+    info!("Shared session {} with {} access as {}", id, req.access.as_str(), share_id);
+
+    Ok(Json(CreateShareResponse {
+        token,
+        share: Share {
+            share_id,
+            access: req.access,
+            label: req.label,
+            scopes,
+            expires_in_secs: ttl.as_secs(),
+        },
+    }))
+}
+
+async fn list_shares(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+) -> Result<Json<Vec<Share>>, (StatusCode, String)> {
+    let sessions = state.sessions.read().await;
+    let session = sessions
+        .get(&id)
+        .ok_or((StatusCode::NOT_FOUND, "Session not found".to_string()))?;
+    let now = Instant::now();
+    let mut shares: Vec<Share> = session
+        .tokens
+        .values()
+        .filter_map(|t| Some((t.share.as_ref()?, t.expires_at?, t)))
+        .filter(|(_, at, _)| *at > now)
+        .map(|(share, at, t)| Share {
+            share_id: share.id.clone(),
+            access: share.access,
+            label: share.label.clone(),
+            scopes: t.key.scopes.clone().unwrap_or_default(),
+            expires_in_secs: at.duration_since(now).as_secs(),
+        })
+        .collect();
+    shares.sort_by_key(|s| s.expires_in_secs);
+    Ok(Json(shares))
+}
+
+/// Revoke a share. Its token is refused from then on; requests already
+/// under way with it, such as open streams, aren't cut off.
+async fn delete_share(
+    State(state): State<AppState>,
+    Path((id, share_id)): Path<(String, String)>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    let mut sessions = state.sessions.write().await;
+    let session = sessions
+        .get_mut(&id)
+        .ok_or((StatusCode::NOT_FOUND, "Session not found".to_string()))?;
+    let before = session.tokens.len();
+    session
+        .tokens
+        .retain(|_, t| t.share.as_ref().is_none_or(|share| share.id != share_id));
+    if session.tokens.len() == before {
+        return Err((StatusCode::NOT_FOUND, "Share not found".to_string()));
+    }
+    state.persist_session(session);
+    info!("Revoked share {} of session {}", share_id, id);
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Mint a key pair for logging in to the session over SSH. The server keeps
+/// only the public key; the private key is in the response and nowhere else.
+async fn create_ssh_key(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+    req: Option<Json<CreateSshKeyRequest>>,
+) -> Result<Json<CreateSshKeyResponse>, (StatusCode, String)> {
+    let Some(port) = state.ssh_port else {
+        return Err((StatusCode::NOT_IMPLEMENTED, "SSH is not enabled on this server".to_string()));
+    };
+    let ttl = match req.and_then(|Json(r)| r.ttl) {
+        Some(0) => return Err((StatusCode::BAD_REQUEST, "ttl must be positive".to_string())),
+        ttl => ttl.map(Duration::from_secs),
+    };
+    let key = ssh::mint_key().map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e))?;
+    let key_id = uuid::Uuid::new_v4().to_string();
+    let mut sessions = state.sessions.write().await;
+    let session = sessions
+        .get_mut(&id)
+        .ok_or((StatusCode::NOT_FOUND, "Session not found".to_string()))?;
+    let now = Instant::now();
+    session.ssh_keys.retain(|k| k.expires_at.is_none_or(|at| at > now));
+    session.ssh_keys.push(SessionSshKey {
+        id: key_id.clone(),
+        public_key: key.public_key.clone(),
+        fingerprint: key.fingerprint.clone(),
+        expires_at: ttl.map(|ttl| now + ttl),
+    });
+    state.persist_session(session);
+    info!("Created SSH key {} for session {}", key.fingerprint, id);
+
+    Ok(Json(CreateSshKeyResponse {
+        key_id,
+        private_key: key.private_key,
+        public_key: key.public_key,
+        fingerprint: key.fingerprint,
+        username: id,
+        port,
+        expires_in_secs: ttl.map(|ttl| ttl.as_secs()),
+    }))
+}
+
+async fn list_ssh_keys(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+) -> Result<Json<Vec<SshKey>>, (StatusCode, String)> {
+    let sessions = state.sessions.read().await;
+    let session = sessions
+        .get(&id)
+        .ok_or((StatusCode::NOT_FOUND, "Session not found".to_string()))?;
+    let now = Instant::now();
+    let keys = session
+        .ssh_keys
+        .iter()
+        .filter(|k| k.expires_at.is_none_or(|at| at > now))
+        .map(|k| SshKey {
+            key_id: k.id.clone(),
+            public_key: k.public_key.clone(),
+            fingerprint: k.fingerprint.clone(),
+            expires_in_secs: k.expires_at.map(|at| at.duration_since(now).as_secs()),
+        })
+        .collect();
+    Ok(Json(keys))
+}
+
+/// Revoke an SSH key. Connections already logged in with it stay open.
+async fn delete_ssh_key(
+    State(state): State<AppState>,
+    Path((id, key_id)): Path<(String, String)>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    let mut sessions = state.sessions.write().await;
+    let session = sessions
+        .get_mut(&id)
+        .ok_or((StatusCode::NOT_FOUND, "Session not found".to_string()))?;
+    let before = session.ssh_keys.len();
+    session.ssh_keys.retain(|k| k.id != key_id);
+    if session.ssh_keys.len() == before {
+        return Err((StatusCode::NOT_FOUND, "SSH key not found".to_string()));
+    }
+    state.persist_session(session);
+    info!("Revoked SSH key {} of session {}", key_id, id);
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn keepalive(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+    req: Option<Json<KeepaliveRequest>>,
+) -> Result<Json<KeepaliveResponse>, (StatusCode, String)> {
+    let new_ttl = match req.and_then(|Json(r)| r.ttl) {
+        Some(secs) => Some(
+            state
+                .resolve_session_ttl(Some(secs))
+                .map_err(|e| (StatusCode::BAD_REQUEST, e))?,
+        ),
+        None => None,
+    };
+    let mut sessions = state.sessions.write().await;
+    let session = sessions
+        .get_mut(&id)
+        .ok_or((StatusCode::NOT_FOUND, "Session not found".to_string()))?;
+    if let Some(ttl) = new_ttl {
+        session.ttl = ttl;
+        state.persist_session(session);
+    }
+    let now = Instant::now();
+    session.last_used = now;
+    Ok(Json(KeepaliveResponse {
+        ttl_secs: state.cleanup_policy.idle_ttl(session).as_secs(),
+        expires_in_secs: state.cleanup_policy.expires_in(session, now).as_secs(),
+    }))
+}
+
+async fn delete_session(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+) -> Result<StatusCode, StatusCode> {
+    let session = state.sessions.write().await.remove(&id);
+    match session {
+        Some(session) => {
+            teardown_session(&state, session, LifecycleTransition::Deleted).await;
+            info!("Deleted session: {}", id);
+            Ok(StatusCode::NO_CONTENT)
+        }
+        None => Err(StatusCode::NOT_FOUND),
+    }
+}
+
+async fn pause_session(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+) -> Result<Json<PauseResponse>, (StatusCode, String)> {
+    set_paused(&state, &id, true).await
+}
+
+async fn resume_session(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+) -> Result<Json<PauseResponse>, (StatusCode, String)> {
+    set_paused(&state, &id, false).await
+}
+
+/// Freeze (SIGSTOP) or thaw (SIGCONT) every process in a session. Pausing
+/// doesn't count as activity, so a session left paused past its idle TTL
+/// is still removed.
+async fn set_paused(
+    state: &AppState,
+    id: &str,
+    pause: bool,
+) -> Result<Json<PauseResponse>, (StatusCode, String)> {
+    let sandbox_root = {
+        let sessions = state.sessions.read().await;
+        let session = sessions
+            .get(id)
+            .ok_or((StatusCode::NOT_FOUND, "Session not found".to_string()))?;
+        if session.status == SessionStatus::Hibernated {
+            ensure_not_suspended(session)?;
+        }
+        session.sandbox_root.clone()
+    };
+    // Scan /proc without holding the sessions lock, which every other
+    // request needs
+    let found = tokio::task::spawn_blocking(move || sandbox::session_pids(&sandbox_root))
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+
+    // Signal under the write lock so a concurrent pause/resume can't
+    // interleave and leave status out of sync with the processes
+    let mut sessions = state.sessions.write().await;
+    let session = sessions
+        .get_mut(id)
+        .ok_or((StatusCode::NOT_FOUND, "Session not found".to_string()))?;
+    if session.status == SessionStatus::Hibernated {
+        ensure_not_suspended(session)?;
+    }
+    session.last_used = Instant::now();
+    let signal = if pause {
+        nix::sys::signal::Signal::SIGSTOP
+    } else {
+        nix::sys::signal::Signal::SIGCONT
+    };
+    let pids = sandbox::signal_processes(&found, signal);
+
+    session.status = if pause { SessionStatus::Paused } else { SessionStatus::Running };
+    state.persist_session(session);
+    state.timeline.record(id, if pause { SessionEventKind::Paused } else { SessionEventKind::Resumed });
+    info!("{} session {} ({} processes)", if pause { "Paused" } else { "Resumed" }, id, pids.len());
+    Ok(Json(PauseResponse {
+        status: session.status,
+        pids,
+    }))
+}
+
+/// Dump the session's background processes with CRIU and kill whatever else
+/// runs in it, so it holds no memory until woken.
+async fn hibernate_session(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+) -> Result<Json<HibernateResponse>, (StatusCode, String)> {
+    if state.backend.name() != "chroot" {
+        return Err((
+            StatusCode::CONFLICT,
+            format!("The {} sandbox backend can't hibernate sessions", state.backend.name()),
+        ));
+    }
+    if !hibernate::available() {
+        return Err((StatusCode::NOT_IMPLEMENTED, "CRIU is not installed on this server".to_string()));
+    }
+    // Hold the write lock throughout, as for pause, so nothing starts in the
+    // session between the dump and the status change
+    let mut sessions = state.sessions.write().await;
+    let session = sessions
+        .get_mut(&id)
+        .ok_or((StatusCode::NOT_FOUND, "Session not found".to_string()))?;
+    ensure_not_suspended(session)?;
+    if session.egress.is_some() {
+        return Err((StatusCode::CONFLICT, "Sessions with egress rules can't hibernate".to_string()));
+    }
+    session.last_used = Instant::now();
+
+    state.kernels.shutdown(&id);
+    let sandbox_root = session.sandbox_root.clone();
+    let background = session.background_pids.clone();
+    let session_id = id.clone();
+    let (pids, killed) = tokio::task::spawn_blocking(move || {
+        let pids = hibernate::dump(&session_id, &background)?;
+        let killed = sandbox::signal_session_processes(&sandbox_root, nix::sys::signal::Signal::SIGKILL);
+        Ok::<_, String>((pids, killed))
+    })
+    .await
+    .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+    .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, format!("Hibernation failed: {}", e)))?;
+
+    session.status = SessionStatus::Hibernated;
+    session.background_pids.clear();
+    state.persist_session(session);
+    state.timeline.record(&id, SessionEventKind::Hibernated { pids: pids.clone() });
+    info!("Hibernated session {} ({} processes dumped, {} killed)", id, pids.len(), killed.len());
+    Ok(Json(HibernateResponse {
+        status: session.status,
+        pids,
+        killed,
+    }))
+}
+
+/// Restore the background processes a hibernated session was dumped with.
+async fn wake_session(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+) -> Result<Json<HibernateResponse>, (StatusCode, String)> {
+    let mut sessions = state.sessions.write().await;
+    let session = sessions
+        .get_mut(&id)
+        .ok_or((StatusCode::NOT_FOUND, "Session not found".to_string()))?;
+    if session.status != SessionStatus::Hibernated {
+        return Err((StatusCode::CONFLICT, "Session is not hibernated".to_string()));
+    }
+    session.last_used = Instant::now();
+
+    let session_id = id.clone();
+    let pids = tokio::task::spawn_blocking(move || hibernate::restore(&session_id))
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, format!("Waking failed: {}", e)))?;
+
+    session.status = SessionStatus::Running;
+    session.background_pids = pids.clone();
+    state.persist_session(session);
+    state.timeline.record(&id, SessionEventKind::Woken { pids: pids.clone() });
+    info!("Woke session {} ({} processes restored)", id, pids.len());
+    Ok(Json(HibernateResponse {
+        status: session.status,
+        pids,
+        killed: Vec::new(),
+    }))
+}
+
+/// Reject work that would start new processes in a paused or hibernated
+/// session.
+fn ensure_not_suspended(session: &Session) -> Result<(), (StatusCode, String)> {
+    let next = match session.status {
+        SessionStatus::Paused => "resume",
+        SessionStatus::Hibernated => "wake",
+        _ => return Ok(()),
+    };
+    Err((
+        StatusCode::CONFLICT,
+        format!("Session is {}; POST /sessions/:id/{} first", session.status, next),
+    ))
+}
+
+/// Release everything a removed session holds and notify hooks.
+async fn teardown_session(state: &AppState, session: Session, transition: LifecycleTransition) {
+    if let Some(ref slug) = session.slug {
+        state.release_slug(slug).await;
+    }
+    state.forget_session(&session.id);
+    for &port in &session.ports {
+        state.release_port(port);
+    }
+    state.kernels.shutdown(&session.id);
+    state.disk_usage.forget(&session.id);
+    state.session_stats.forget(&session.id);
+    state.jobs.forget_session(&session.id);
+    state.run_limits.forget(&session.id);
+    state.egress.disable(&session.id, &session.sandbox_root);
+    let event = SessionLifecycleEvent::from_session(&session);
+    let sandbox_root = session.sandbox_root;
+    let pids = session.background_pids;
+    let session_id = session.id;
+    let (backend, build_cache) = (state.backend.clone(), state.build_cache.clone());
+    let (history, cgroups) = (state.history.clone(), state.cgroups.clone());
+    tokio::task::spawn_blocking(move || {
+        // Kill background processes first
+        for pid in pids {
+            let _ = nix::sys::signal::kill(
+                nix::unistd::Pid::from_raw(pid as i32),
+                nix::sys::signal::Signal::SIGKILL,
+            );
+        }
+        backend.destroy(&sandbox_root);
+        if let Some(cgroups) = cgroups {
+            cgroups.detach(&session_id, &sandbox_root);
+        }
+        checkpoint::remove_all(&session_id);
+        changes::remove(&session_id);
+        history.remove(&session_id);
+        hibernate::remove(&session_id);
+        build_cache.release(&session_id);
+    });
+    let ended = match transition {
+        LifecycleTransition::Expired => SessionEventKind::Expired,
+        LifecycleTransition::Evicted => SessionEventKind::Evicted,
+        _ => SessionEventKind::Deleted,
+    };
+    state.timeline.record(&event.session_id, ended);
+    state.notify_lifecycle(transition, &event);
+}
+
+async fn set_env(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+    Json(mut req): Json<SetEnvRequest>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    state
+        .env_policy
+        .apply(&mut req.env)
+        .map_err(|e| (StatusCode::BAD_REQUEST, e))?;
+    let mut sessions = state.sessions.write().await;
+    let session = sessions
+        .get_mut(&id)
+        .ok_or((StatusCode::NOT_FOUND, "Session not found".to_string()))?;
+    if req.replace {
+        session.env = req.env;
+    } else {
+        session.env.extend(req.env);
+    }
+    session.last_used = Instant::now();
+    state.persist_session(session);
+    Ok(StatusCode::OK)
+}
+
+/// Remove one variable from the session env.
+async fn unset_env(
+    State(state): State<AppState>,
+    Path((id, name)): Path<(String, String)>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    let mut sessions = state.sessions.write().await;
+    let session = sessions
+        .get_mut(&id)
+        .ok_or((StatusCode::NOT_FOUND, "Session not found".to_string()))?;
+    if session.env.remove(&name).is_none() {
+        return Err((StatusCode::NOT_FOUND, format!("Env var {:?} not set", name)));
+    }
+    session.last_used = Instant::now();
+    state.persist_session(session);
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Add or replace the session's named tasks.
+async fn set_tasks(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+    Json(mut req): Json<SetTasksRequest>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    for (name, task) in &mut req.tasks {
+        tasks::validate(name, task).map_err(|e| (StatusCode::BAD_REQUEST, e))?;
+        state
+            .env_policy
+            .apply(&mut task.env)
+            .map_err(|e| (StatusCode::BAD_REQUEST, e))?;
+    }
+    let mut sessions = state.sessions.write().await;
+    let session = sessions
+        .get_mut(&id)
+        .ok_or((StatusCode::NOT_FOUND, "Session not found".to_string()))?;
+    let mut all = if req.replace { Default::default() } else { session.tasks.clone() };
+    all.extend(req.tasks);
+    if all.len() > tasks::MAX_TASKS {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            format!("A session may have at most {} tasks", tasks::MAX_TASKS),
+        ));
+    }
+    session.tasks = all;
+    session.last_used = Instant::now();
+    state.persist_session(session);
+    Ok(StatusCode::OK)
+}
+
+/// The session's tasks, sorted by name.
+async fn list_tasks(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+) -> Result<Json<Vec<TaskInfo>>, (StatusCode, String)> {
+    let sessions = state.sessions.read().await;
+    let session = sessions
+        .get(&id)
+        .ok_or((StatusCode::NOT_FOUND, "Session not found".to_string()))?;
+    let tasks = session
+        .tasks
+        .iter()
+        .map(|(name, task)| TaskInfo {
+            name: name.clone(),
+            task: task.clone(),
+        })
+        .collect();
+    Ok(Json(tasks))
+}
+
+async fn delete_task(
+    State(state): State<AppState>,
+    Path((id, name)): Path<(String, String)>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    let mut sessions = state.sessions.write().await;
+    let session = sessions
+        .get_mut(&id)
+        .ok_or((StatusCode::NOT_FOUND, "Session not found".to_string()))?;
+    if session.tasks.remove(&name).is_none() {
+        return Err((StatusCode::NOT_FOUND, format!("Task {:?} not found", name)));
+    }
+    session.last_used = Instant::now();
+    state.persist_session(session);
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Run a task to completion, or start it in the background, as `/run` or
+/// `/background` would its command.
+async fn run_task(
+    State(state): State<AppState>,
+    Path((id, name)): Path<(String, String)>,
+    api_key: Option<Extension<Arc<ApiKey>>>,
+    headers: HeaderMap,
+    body: Option<Json<RunTaskRequest>>,
+) -> Result<Json<TaskRunResponse>, (StatusCode, String)> {
+    let task = {
+        let sessions = state.sessions.read().await;
+        let session = sessions
+            .get(&id)
+            .ok_or((StatusCode::NOT_FOUND, "Session not found".to_string()))?;
+        session
+            .tasks
+            .get(&name)
+            .cloned()
+            .ok_or((StatusCode::NOT_FOUND, format!("Task {:?} not found", name)))?
+    };
+    let req = body.map(|Json(req)| req).unwrap_or_default();
+    let mut response = TaskRunResponse {
+        task: name,
+        run: None,
+        background: None,
+    };
+    match tasks::request(&task, req) {
+        TaskRun::Foreground(req) => {
+            let Json(result) = run_in_session(State(state), Path(id), api_key, headers, Json(req)).await?;
+            response.run = Some(result);
+        }
+        TaskRun::Background(req) => {
+            let key = api_key.as_ref().map(|Extension(key)| key.as_ref());
+            if key.is_some_and(|key| !key.allows(Scope::BackgroundManage)) {
+                return Err((
+                    StatusCode::FORBIDDEN,
+                    format!("API key lacks scope {}", Scope::BackgroundManage),
+                ));
+            }
+            response.background = Some(start_background(&state, &id, key, &headers, req).await?);
+        }
+    }
+    Ok(Json(response))
+}
+
+/// Add a schedule to the session; its command runs each time the expression
+/// fires until the schedule or the session is deleted.
+async fn create_schedule(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+    Json(mut req): Json<ScheduleDefinition>,
+) -> Result<(StatusCode, Json<ScheduleInfo>), (StatusCode, String)> {
+    state
+        .env_policy
+        .apply(&mut req.env)
+        .map_err(|e| (StatusCode::BAD_REQUEST, e))?;
+    let mut sessions = state.sessions.write().await;
+    let session = sessions
+        .get_mut(&id)
+        .ok_or((StatusCode::NOT_FOUND, "Session not found".to_string()))?;
+    if session.schedules.len() >= recurring::MAX_SCHEDULES {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            format!("A session may have at most {} schedules", recurring::MAX_SCHEDULES),
+        ));
+    }
+    let schedule = SessionSchedule::new(req, session.env.get("TZ").map(String::as_str))
+        .map_err(|e| (StatusCode::BAD_REQUEST, e))?;
+    let schedule_id = uuid::Uuid::new_v4().to_string();
+    let info = schedule.info(&schedule_id);
+    session.schedules.insert(schedule_id.clone(), schedule);
+    session.last_used = Instant::now();
+    state.persist_session(session);
+    info!(session_id = %id, schedule_id = %schedule_id, expression = %info.schedule.expression, "Created schedule");
+    Ok((StatusCode::CREATED, Json(info)))
+}
+
+async fn list_schedules(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+) -> Result<Json<Vec<ScheduleInfo>>, (StatusCode, String)> {
+    let sessions = state.sessions.read().await;
+    let session = sessions
+        .get(&id)
+        .ok_or((StatusCode::NOT_FOUND, "Session not found".to_string()))?;
+    Ok(Json(session.schedules.iter().map(|(id, schedule)| schedule.info(id)).collect()))
+}
+
+async fn get_schedule(
+    State(state): State<AppState>,
+    Path((id, schedule_id)): Path<(String, String)>,
+) -> Result<Json<ScheduleInfo>, (StatusCode, String)> {
+    let sessions = state.sessions.read().await;
+    let session = sessions
+        .get(&id)
+        .ok_or((StatusCode::NOT_FOUND, "Session not found".to_string()))?;
+    session
+        .schedules
+        .get(&schedule_id)
+        .map(|schedule| Json(schedule.info(&schedule_id)))
+        .ok_or((StatusCode::NOT_FOUND, "Schedule not found".to_string()))
+}
+
+/// Delete a schedule. A run going on is left to finish.
+async fn delete_schedule(
+    State(state): State<AppState>,
+    Path((id, schedule_id)): Path<(String, String)>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    let mut sessions = state.sessions.write().await;
+    let session = sessions
+        .get_mut(&id)
+        .ok_or((StatusCode::NOT_FOUND, "Session not found".to_string()))?;
+    if session.schedules.remove(&schedule_id).is_none() {
+        return Err((StatusCode::NOT_FOUND, "Schedule not found".to_string()));
+    }
+    session.last_used = Instant::now();
+    state.persist_session(session);
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Add or replace session secrets: env of the session's processes that is
+/// never returned (`GET /sessions/:id` lists only names) and is masked in
+/// their output.
+async fn set_secrets(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+    Json(mut req): Json<SetSecretsRequest>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    state
+        .env_policy
+        .apply(&mut req.secrets)
+        .map_err(|e| (StatusCode::BAD_REQUEST, e))?;
+    let mut sessions = state.sessions.write().await;
+    let session = sessions
+        .get_mut(&id)
+        .ok_or((StatusCode::NOT_FOUND, "Session not found".to_string()))?;
+    let mut secrets = session.secrets.clone();
+    secrets.extend(req.secrets);
+    validate_secrets(&secrets).map_err(|e| (StatusCode::BAD_REQUEST, e))?;
+    session.secrets = secrets;
+    session.last_used = Instant::now();
+    state.persist_session(session);
+    Ok(StatusCode::OK)
+}
+
+/// The session's egress policy and recent attempts through its proxy.
+async fn session_egress(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+) -> Result<Json<EgressReport>, (StatusCode, String)> {
+    if !state.sessions.read().await.contains_key(&id) {
+        return Err((StatusCode::NOT_FOUND, "Session not found".to_string()));
+    }
+    state
+        .egress
+        .report(&id)
+        .map(Json)
+        .ok_or((StatusCode::NOT_FOUND, "Session has no egress policy".to_string()))
+}
+
+/// Replace the egress policy of a session created with one.
+async fn set_egress(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+    Json(policy): Json<EgressPolicy>,
+) -> Result<Json<EgressReport>, (StatusCode, String)> {
+    egress::validate(&policy).map_err(|e| (StatusCode::BAD_REQUEST, e))?;
+    let mut sessions = state.sessions.write().await;
+    let session = sessions
+        .get_mut(&id)
+        .ok_or((StatusCode::NOT_FOUND, "Session not found".to_string()))?;
+    if session.egress.is_none() || !state.egress.set_policy(&id, policy.clone()) {
+        return Err((
+            StatusCode::CONFLICT,
+            "Session was created without an egress policy".to_string(),
+        ));
+    }
+    session.egress = Some(policy);
+    session.last_used = Instant::now();
+    state.persist_session(session);
+    drop(sessions);
+    state
+        .egress
+        .report(&id)
+        .map(Json)
+        .ok_or((StatusCode::NOT_FOUND, "Session not found".to_string()))
+}
+
+/// Change a session's CPU weight, CPU cap or process limit while it runs.
+/// Fields left out keep their value.
+async fn set_limits(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+    Json(update): Json<SessionLimits>,
+) -> Result<Json<SessionLimits>, (StatusCode, String)> {
+    update.validate().map_err(|e| (StatusCode::BAD_REQUEST, e))?;
+    let cgroups: &Cgroups = state.cgroups.as_deref().ok_or_else(cgroups_off)?;
+    let mut sessions = state.sessions.write().await;
+    let session = sessions
+        .get_mut(&id)
+        .ok_or((StatusCode::NOT_FOUND, "Session not found".to_string()))?;
+    let current = session.limits.ok_or((
+        StatusCode::CONFLICT,
+        "Session has no cgroup; it was restored without one".to_string(),
+    ))?;
+    let limits = current.merge(update);
+    cgroups.check(&limits).map_err(|e| (StatusCode::FORBIDDEN, e))?;
+    cgroups.apply(&id, &limits).map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e))?;
+    session.limits = Some(limits);
+    session.last_used = Instant::now();
+    state.persist_session(session);
+    info!("Set limits of session {}: {:?}", id, limits);
+    Ok(Json(limits))
+}
+
+/// Refusal of session limits on a server without per-session cgroups.
+fn cgroups_off() -> (StatusCode, String) {
+    (
+        StatusCode::NOT_IMPLEMENTED,
+        "Session limits need per-session cgroups, which are off on this server".to_string(),
+    )
+}
+
+/// A command that failed to run: 429 if a process limit kept it from
+/// starting, else a server error.
+fn run_error(e: String) -> (StatusCode, String) {
+    if e.starts_with(sandbox::PIDS_LIMIT_HIT) {
+        (StatusCode::TOO_MANY_REQUESTS, e)
+    } else {
+        (StatusCode::INTERNAL_SERVER_ERROR, e)
+    }
+}
+
+async fn set_cwd(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+    Json(req): Json<SetCwdRequest>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    let mut sessions = state.sessions.write().await;
+    let session = sessions
+        .get_mut(&id)
+        .ok_or((StatusCode::NOT_FOUND, "Session not found".to_string()))?;
+    sandbox::confine_cwd(&session.sandbox_root, &req.cwd).map_err(|e| (StatusCode::BAD_REQUEST, e))?;
+    session.cwd = req.cwd;
+    session.last_used = Instant::now();
+    state.persist_session(session);
+    Ok(StatusCode::OK)
+}
+
+/// Serve a directory of the sandbox at the session's preview URL, in place
+/// of its first port.
+async fn set_static_site(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+    Json(req): Json<StaticSite>,
+) -> Result<Json<StaticSite>, (StatusCode, String)> {
+    let mut sessions = state.sessions.write().await;
+    let session = sessions
+        .get_mut(&id)
+        .ok_or((StatusCode::NOT_FOUND, "Session not found".to_string()))?;
+    let site = static_site::normalize(&session.sandbox_root, req, &session.cwd)
+        .map_err(|e| (StatusCode::BAD_REQUEST, e))?;
+    session.static_site = Some(site.clone());
+    session.last_used = Instant::now();
+    state.persist_session(session);
+    Ok(Json(site))
+}
+
+/// Stop serving the session's static site; the preview URL goes back to
+/// its first port.
+async fn delete_static_site(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    let mut sessions = state.sessions.write().await;
+    let session = sessions
+        .get_mut(&id)
+        .ok_or((StatusCode::NOT_FOUND, "Session not found".to_string()))?;
+    if session.static_site.take().is_none() {
+        return Err((StatusCode::NOT_FOUND, "Session has no static site".to_string()));
+    }
+    session.last_used = Instant::now();
+    state.persist_session(session);
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn run_in_session(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+    api_key: Option<Extension<Arc<ApiKey>>>,
+    headers: HeaderMap,
+    Json(req): Json<RunRequest>,
+) -> Result<Json<RunResult>, (StatusCode, String)> {
+    let api_key = api_key.map(|Extension(key)| key);
+    run_session_command(&state, &id, api_key, &headers, req, HistorySource::Run)
+        .await
+        .map(Json)
+}
+
+/// Run a command in the session to completion, on behalf of `api_key`, and
+/// record it in the session's timeline and history.
+async fn run_session_command(
+    state: &AppState,
+    id: &str,
+    api_key: Option<Arc<ApiKey>>,
+    headers: &HeaderMap,
+    req: RunRequest,
+    source: HistorySource,
+) -> Result<RunResult, (StatusCode, String)> {
+    let record = req.record;
+    let SessionRun {
+        sandbox_root,
+        config,
+        event,
+        trace,
+    } = prepare_session_run(state, id, api_key.as_deref(), headers, req).await?;
+    let permit = run_permit(state, Some(id)).await?;
+
+    let command = secrets::redact_all(&config.command, &config.secrets);
+    state.timeline.record(id, SessionEventKind::RunStarted { command: command.clone() });
+    let started = Instant::now();
+    let cwd = config.cwd.clone();
+    let backend = state.backend.clone();
+    let egress = state.egress.clone();
+    let session_id = id.to_string();
+    let org_id = api_key.as_ref().map(|key| key.org().to_string());
+    let mut result = tokio::task::spawn_blocking(move || {
+        let _permit = permit;
+        if record {
+            let (mut result, replay_id) =
+                replay::record_run(&*backend, &sandbox_root, &config, &egress, &session_id, org_id.as_deref())?;
+            result.replay_id = Some(replay_id);
+            Ok(result)
+        } else {
+            backend.run(&sandbox_root, &config, None)
+        }
+    })
+    .await
+    .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+    .map_err(run_error)?;
+    state.record_cpu(api_key.as_deref(), result.cpu_time);
+    result.trace_id = Some(trace.trace_id);
+
+    let completion = RunCompletion {
+        command,
+        exit_code: result.exit_code,
+        signal: result.signal,
+        duration_ms: started.elapsed().as_millis() as u64,
+    };
+    state.timeline.record(id, timeline::run_finished(&completion));
+    state.notify_run_complete(&event, &completion);
+    state.notify_run_finish(api_key.as_deref(), &RunFinish::new(Some(id), &completion, result.cpu_time));
+    state.record_history(
+        id,
+        history::Command {
+            source,
+            completion: &completion,
+            cwd: &cwd,
+            result: &result,
+        },
+    );
+    Ok(result)
+}
+
+/// A session run ready to start, as worked out from its request and the
+/// session by [`prepare_session_run`].
+struct SessionRun {
+    sandbox_root: PathBuf,
+    config: RunConfig,
+    event: SessionLifecycleEvent,
+    trace: RunTrace,
+}
+
+/// Validate a run request against the session and the caller's quota, and
+/// build its config: the session's env, secrets and cwd with the request's
+/// on top.
+async fn prepare_session_run(
+    state: &AppState,
+    id: &str,
+    api_key: Option<&ApiKey>,
+    headers: &HeaderMap,
+    mut req: RunRequest,
+) -> Result<SessionRun, (StatusCode, String)> {
+    state
+        .env_policy
+        .apply(&mut req.env)
+        .map_err(|e| (StatusCode::BAD_REQUEST, e))?;
+    let stdin = decode_stdin(req.stdin.take())?;
+    let max_output_bytes = max_output_bytes(req.max_output_bytes)?;
+    let kill_grace_ms = kill_grace_ms(req.kill_grace_ms)?;
+    state
+        .check_org_quota(api_key)
+        .await
+        .map_err(|e| (StatusCode::TOO_MANY_REQUESTS, e))?;
+
+    // Get session info
+    let (sandbox_root, mut env, secret_values, cwd, determinism, event) = {
+        let mut sessions = state.sessions.write().await;
+        let session = sessions
+            .get_mut(id)
+            .ok_or((StatusCode::NOT_FOUND, "Session not found".to_string()))?;
+        ensure_not_suspended(session)?;
+        session.last_used = Instant::now();
+        (
+            session.sandbox_root.clone(),
+            session.process_env(),
+            session.secret_values(),
+            session.cwd.clone(),
+            Some(session.determinism.clone()),
+            SessionLifecycleEvent::from_session(session),
+        )
+    };
+
+    // Merge request env with session env
+    for name in &req.unset_env {
+        env.remove(name);
+    }
+    env.extend(req.env);
+    let cwd = req.cwd.filter(|c| c != DEFAULT_CWD).unwrap_or(cwd);
+    sandbox::confine_cwd(&sandbox_root, &cwd).map_err(|e| (StatusCode::BAD_REQUEST, e))?;
+    let run = RunStart {
+        session_id: Some(id.to_string()),
+        command: secrets::redact_all(&req.command, &secret_values),
+        cwd: cwd.clone(),
+        background: false,
+    };
+    state.check_run_start(api_key, &run).map_err(|e| (StatusCode::FORBIDDEN, e))?;
+    let trace = start_run_trace(headers, &mut env);
+
+    let config = RunConfig {
+        command: req.command,
+        time_ms: req.time.unwrap_or(DEFAULT_TIME_MS),
+        mem_kb: req.mem.unwrap_or(DEFAULT_MEM_KB),
+        fsize_kb: req.fsize.unwrap_or(DEFAULT_FSIZE_KB),
+        nofile: req.nofile.unwrap_or(DEFAULT_NOFILE),
+        nproc: req.pids.unwrap_or(sandbox::DEFAULT_NPROC),
+        env,
+        cwd,
+        commit_on_success: req.commit_on_success,
+        determinism,
+        stdin,
+        max_output_bytes,
+        kill_grace_ms,
+        secrets: secret_values,
+    };
+    Ok(SessionRun {
+        sandbox_root,
+        config,
+        event,
+        trace,
+    })
+}
+
+/// Start a session run in the background and return its job right away;
+/// `GET /jobs/:id` follows it.
+async fn start_job(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+    api_key: Option<Extension<Arc<ApiKey>>>,
+    headers: HeaderMap,
+    Json(req): Json<RunRequest>,
+) -> Result<(StatusCode, Json<JobInfo>), (StatusCode, String)> {
+    if req.record {
+        return Err((StatusCode::BAD_REQUEST, "Jobs can't be recorded; use /run".to_string()));
+    }
+    let api_key = api_key.map(|Extension(key)| key);
+    let SessionRun {
+        sandbox_root,
+        config,
+        event,
+        trace,
+    } = prepare_session_run(&state, &id, api_key.as_deref(), &headers, req).await?;
+    let permit = run_permit(&state, Some(&id)).await?;
+
+    let job = state.jobs.start(&id, &config, trace.trace_id.clone());
+    info!(job_id = %job.id, session_id = %id, command = ?job.command, "Starting job");
+    state.timeline.record(&id, SessionEventKind::RunStarted { command: job.command.clone() });
+    let info = job
+        .info(&JobQuery::default())
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e))?;
+    let backend = state.backend.clone();
+    tokio::spawn(async move {
+        let started = Instant::now();
+        let cwd = config.cwd.clone();
+        let running = job.clone();
+        let mut result = tokio::task::spawn_blocking(move || {
+            let _permit = permit;
+            backend.run(&sandbox_root, &config, Some(running.live()))
+        })
+        .await
+        .unwrap_or_else(|e| Err(e.to_string()));
+        if let Ok(ref mut result) = result {
+            result.trace_id = Some(trace.trace_id);
+            state.record_cpu(api_key.as_deref(), result.cpu_time);
+            let completion = RunCompletion {
+                command: job.command.clone(),
+                exit_code: result.exit_code,
+                signal: result.signal,
+                duration_ms: started.elapsed().as_millis() as u64,
+            };
+            state.timeline.record(&id, timeline::run_finished(&completion));
+            state.notify_run_complete(&event, &completion);
+            state.notify_run_finish(api_key.as_deref(), &RunFinish::new(Some(&id), &completion, result.cpu_time));
+            state.record_history(
+                &id,
+                history::Command {
+                    source: HistorySource::Job,
+                    completion: &completion,
+                    cwd: &cwd,
+                    result,
+                },
+            );
+        }
+        state.jobs.finish(&job, result);
+    });
+    Ok((StatusCode::ACCEPTED, Json(info)))
+}
+
+/// A job's status and its output from the given offsets on.
+async fn get_job(
+    State(state): State<AppState>,
+    Path(job_id): Path<String>,
+    api_key: Option<Extension<Arc<ApiKey>>>,
+    Query(query): Query<JobQuery>,
+) -> Result<Json<JobInfo>, (StatusCode, String)> {
+    let job = visible_job(&state, &job_id, api_key.as_ref().map(|Extension(key)| key.as_ref())).await?;
+    job.info(&query).map(Json).map_err(|e| (StatusCode::BAD_REQUEST, e))
+}
+
+/// Kill a running job. It's reported `cancelled` once its processes are gone.
+async fn cancel_job(
+    State(state): State<AppState>,
+    Path(job_id): Path<String>,
+    api_key: Option<Extension<Arc<ApiKey>>>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    let job = visible_job(&state, &job_id, api_key.as_ref().map(|Extension(key)| key.as_ref())).await?;
+    if !job.cancel() {
+        return Err((StatusCode::CONFLICT, "Job already finished".to_string()));
+    }
+    info!(job_id = %job_id, "Cancelled job");
+    Ok(StatusCode::ACCEPTED)
+}
+
+/// A job whose session the caller can see; others get the same 404 as a
+/// missing job.
+async fn visible_job(
+    state: &AppState,
+    job_id: &str,
+    api_key: Option<&ApiKey>,
+) -> Result<Arc<jobs::Job>, (StatusCode, String)> {
+    let not_found = || (StatusCode::NOT_FOUND, "Job not found".to_string());
+    let job = state.jobs.get(job_id).ok_or_else(not_found)?;
+    let visible = state
+        .sessions
+        .read()
+        .await
+        .get(&job.session_id)
+        .is_some_and(|s| s.visible_to(api_key));
+    if !visible {
+        return Err(not_found());
+    }
+    Ok(job)
+}
+
+async fn run_oneshot(
+    State(state): State<AppState>,
+    api_key: Option<Extension<Arc<ApiKey>>>,
+    headers: HeaderMap,
+    Json(mut req): Json<RunRequest>,
+) -> Result<Json<RunResult>, (StatusCode, String)> {
+    info!("POST /run - command: {:?}", req.command);
+    state
+        .env_policy
+        .apply(&mut req.env)
+        .map_err(|e| (StatusCode::BAD_REQUEST, e))?;
+    let stdin = decode_stdin(req.stdin.take())?;
+    let max_output_bytes = max_output_bytes(req.max_output_bytes)?;
+    let kill_grace_ms = kill_grace_ms(req.kill_grace_ms)?;
+    let api_key = api_key.map(|Extension(key)| key);
+    state
+        .check_org_quota(api_key.as_deref())
+        .await
+        .map_err(|e| (StatusCode::TOO_MANY_REQUESTS, e))?;
+    let run = RunStart {
+        session_id: None,
+        command: req.command.clone(),
+        cwd: req.cwd.clone().unwrap_or_else(|| DEFAULT_CWD.to_string()),
+        background: false,
+    };
+    state
+        .check_run_start(api_key.as_deref(), &run)
+        .map_err(|e| (StatusCode::FORBIDDEN, e))?;
+    let trace = start_run_trace(&headers, &mut req.env);
+    let config = RunConfig {
+        command: req.command,
+        time_ms: req.time.unwrap_or(DEFAULT_TIME_MS),
+        mem_kb: req.mem.unwrap_or(DEFAULT_MEM_KB),
+        fsize_kb: req.fsize.unwrap_or(DEFAULT_FSIZE_KB),
+        nofile: req.nofile.unwrap_or(DEFAULT_NOFILE),
+        nproc: req.pids.unwrap_or(sandbox::DEFAULT_NPROC),
+        env: req.env,
+        cwd: req.cwd.unwrap_or_else(|| DEFAULT_CWD.to_string()),
+        // A fresh sandbox is discarded anyway
+        commit_on_success: false,
+        determinism: None,
+        stdin,
+        max_output_bytes,
+        kill_grace_ms,
+        secrets: Vec::new(),
+    };
+
+    let permit = run_permit(&state, None).await?;
+    let started = Instant::now();
+    let backend = state.backend.clone();
+    let mut result = tokio::task::spawn_blocking(move || {
+        let _permit = permit;
+        backend.run_oneshot(&config)
+    })
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+        .map_err(run_error)?;
+    state.record_cpu(api_key.as_deref(), result.cpu_time);
+    let finish = RunFinish {
+        session_id: None,
+        command: run.command,
+        exit_code: result.exit_code,
+        signal: result.signal,
+        duration_ms: started.elapsed().as_millis() as u64,
+        cpu_time: result.cpu_time,
+    };
+    state.notify_run_finish(api_key.as_deref(), &finish);
+    result.trace_id = Some(trace.trace_id);
+
+    info!("POST /run - result: exit={:?} signal={:?}", result.exit_code, result.signal);
+    Ok(Json(result))
+}
+
+/// Wait for leave to start a run in `session_id` (`None` for a fresh
+/// sandbox) under the server's run limits; `429` if none comes in time.
+async fn run_permit(state: &AppState, session_id: Option<&str>) -> Result<RunPermit, (StatusCode, String)> {
+    let permit = state
+        .run_limits
+        .acquire(session_id)
+        .await
+        .map_err(|e| (StatusCode::TOO_MANY_REQUESTS, e))?;
+    Ok(match session_id {
+        Some(id) => permit.with_activity(state.activity.run(id)),
+        None => permit,
+    })
+}
+
+fn decode_stdin(stdin: Option<String>) -> Result<Option<Vec<u8>>, (StatusCode, String)> {
+    stdin
+        .map(|data| BASE64.decode(data))
+        .transpose()
+        .map_err(|e| (StatusCode::BAD_REQUEST, format!("Invalid base64 for stdin: {}", e)))
+}
+
+fn max_output_bytes(requested: Option<u64>) -> Result<u64, (StatusCode, String)> {
+    match requested {
+        Some(n) if n > sandbox::MAX_OUTPUT_BYTES => Err((
+            StatusCode::BAD_REQUEST,
+            format!("max_output_bytes must be at most {}", sandbox::MAX_OUTPUT_BYTES),
+        )),
+        Some(n) => Ok(n),
+        None => Ok(sandbox::DEFAULT_MAX_OUTPUT_BYTES),
+    }
+}
+
+fn kernel_timeout(requested: Option<u64>) -> Result<Duration, (StatusCode, String)> {
+    match requested {
+        Some(ms) if ms == 0 || ms > kernel::MAX_TIMEOUT_MS => Err((
+            StatusCode::BAD_REQUEST,
+            format!("timeout_ms must be between 1 and {}", kernel::MAX_TIMEOUT_MS),
+        )),
+        ms => Ok(Duration::from_millis(ms.unwrap_or(kernel::DEFAULT_TIMEOUT_MS))),
+    }
+}
+
+fn kill_grace_ms(requested: Option<u64>) -> Result<u64, (StatusCode, String)> {
+    match requested {
+        Some(ms) if ms > sandbox::MAX_KILL_GRACE_MS => Err((
+            StatusCode::BAD_REQUEST,
+            format!("kill_grace_ms must be at most {}", sandbox::MAX_KILL_GRACE_MS),
+        )),
+        Some(ms) => Ok(ms),
+        None => Ok(sandbox::DEFAULT_KILL_GRACE_MS),
+    }
+}
+
+/// Continue the caller's trace (or start one) for a run and export it to the
+/// command's environment.
+fn start_run_trace(headers: &HeaderMap, env: &mut HashMap<String, String>) -> RunTrace {
+    let trace = RunTrace::from_headers(headers);
+    trace.inject(env);
+    info!(
+        trace_id = %trace.trace_id,
+        span_id = %trace.span_id,
+        parent_span_id = %trace.parent_span_id.as_deref().unwrap_or("-"),
+        "Run trace"
+    );
+    trace
+}
+
+/// Download a recorded run's bundle (tar.gz).
+async fn download_replay(
+    Path(id): Path<String>,
+    api_key: Option<Extension<Arc<ApiKey>>>,
+) -> Result<Response, (StatusCode, String)> {
+    let path = replay::bundle_path(&id).map_err(|e| (StatusCode::BAD_REQUEST, e))?;
+    if !replay::owned_by(&path, api_key.as_ref().map(|Extension(key)| key.org())) {
+        return Err((StatusCode::NOT_FOUND, "Replay not found".to_string()));
+    }
+    let data = tokio::fs::read(&path)
+        .await
+        .map_err(|_| (StatusCode::NOT_FOUND, "Replay not found".to_string()))?;
+    Ok((
+        [
+            (header::CONTENT_TYPE, "application/gzip".to_string()),
+            (
+                header::CONTENT_DISPOSITION,
+                format!("attachment; filename=\"replay-{}.tar.gz\"", id),
+            ),
+        ],
+        data,
+    )
+        .into_response())
+}
+
+async fn delete_replay(
+    Path(id): Path<String>,
+    api_key: Option<Extension<Arc<ApiKey>>>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    let path = replay::bundle_path(&id).map_err(|e| (StatusCode::BAD_REQUEST, e))?;
+    if !replay::owned_by(&path, api_key.as_ref().map(|Extension(key)| key.org())) {
+        return Err((StatusCode::NOT_FOUND, "Replay not found".to_string()));
+    }
+    tokio::task::spawn_blocking(move || replay::remove(&path))
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+        .map_err(|_| (StatusCode::NOT_FOUND, "Replay not found".to_string()))?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Replay an uploaded bundle in a fresh sandbox.
+async fn replay_bundle(
+    State(state): State<AppState>,
+    api_key: Option<Extension<Arc<ApiKey>>>,
+    body: Bytes,
+) -> Result<Json<ReplayOutcome>, (StatusCode, String)> {
+    let permit = run_permit(&state, None).await?;
+    let progress = state
+        .progress
+        .start("replay.restore")
+        .org(api_key.as_ref().map(|Extension(key)| key.org()));
+    let backend = state.backend.clone();
+    let outcome = tokio::task::spawn_blocking(move || {
+        let _permit = permit;
+        replay::replay(&*backend, &body, progress)
+    })
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+        .map_err(|e| (StatusCode::BAD_REQUEST, e))?;
+    info!("Replayed bundle: matches={}", outcome.matches);
+    Ok(Json(outcome))
+}
+
+// Checkpoint handlers
+
+/// 404 unless the session exists; counts as use of it.
+async fn touch_session(state: &AppState, id: &str) -> Result<(), (StatusCode, String)> {
+    let mut sessions = state.sessions.write().await;
+    let session = sessions
+        .get_mut(id)
+        .ok_or((StatusCode::NOT_FOUND, "Session not found".to_string()))?;
+    session.last_used = Instant::now();
+    Ok(())
+}
+
+/// Copy the session's files into a new checkpoint.
+async fn create_checkpoint(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+    api_key: Option<Extension<Arc<ApiKey>>>,
+    body: Option<Json<CreateCheckpointRequest>>,
+) -> Result<(StatusCode, Json<CheckpointInfo>), (StatusCode, String)> {
+    let label = body.and_then(|Json(req)| req.label);
+    let sandbox_root = {
+        let mut sessions = state.sessions.write().await;
+        let session = sessions
+            .get_mut(&id)
+            .ok_or((StatusCode::NOT_FOUND, "Session not found".to_string()))?;
+        session.last_used = Instant::now();
+        session.sandbox_root.clone()
+    };
+    let progress = state
+        .progress
+        .start("checkpoint.create")
+        .org(api_key.as_ref().map(|Extension(key)| key.org()))
+        .session(&id);
+    let info = tokio::task::spawn_blocking(move || {
+        let existing = checkpoint::list(&id).map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e))?;
+        if existing.len() >= checkpoint::MAX_CHECKPOINTS {
+            return Err((
+                StatusCode::CONFLICT,
+                format!(
+                    "Session already has {} checkpoints; delete one first",
+                    checkpoint::MAX_CHECKPOINTS
+                ),
+            ));
+        }
+        checkpoint::create(&sandbox_root, &id, label, progress)
+            .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e))
+    })
+    .await
+    .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))??;
+    info!("Created checkpoint {} ({} files, {} bytes)", info.id, info.files, info.bytes);
+    Ok((StatusCode::CREATED, Json(info)))
+}
+
+async fn list_checkpoints(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+) -> Result<Json<Vec<CheckpointInfo>>, (StatusCode, String)> {
+    touch_session(&state, &id).await?;
+    tokio::task::spawn_blocking(move || checkpoint::list(&id))
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+        .map(Json)
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e))
+}
+
+async fn delete_checkpoint(
+    State(state): State<AppState>,
+    Path((id, checkpoint_id)): Path<(String, String)>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    touch_session(&state, &id).await?;
+    let removed = tokio::task::spawn_blocking(move || checkpoint::delete(&id, &checkpoint_id))
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+        .map_err(|e| (StatusCode::BAD_REQUEST, e))?;
+    if !removed {
+        return Err((StatusCode::NOT_FOUND, "Checkpoint not found".to_string()));
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// What changed between two checkpoints of a session.
+async fn diff_checkpoints(
+    State(state): State<AppState>,
+    Path((id, from, to)): Path<(String, String, String)>,
+    Query(query): Query<CheckpointDiffQuery>,
+) -> Result<Json<CheckpointDiff>, (StatusCode, String)> {
+    touch_session(&state, &id).await?;
+    tokio::task::spawn_blocking(move || {
+        let load = |checkpoint_id: &str| {
+            checkpoint::load(&id, checkpoint_id)
+                .map_err(|e| (StatusCode::BAD_REQUEST, e))?
+                .ok_or((
+                    StatusCode::NOT_FOUND,
+                    format!("Checkpoint {} not found", checkpoint_id),
+                ))
+        };
+        let (before, after) = (load(&from)?, load(&to)?);
+        checkpoint::diff(&id, &before, &after, &query).map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e))
+    })
+    .await
+    .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+    .map(Json)
+}
+
+async fn cleanup_expired_sessions(state: &AppState) {
+    let expired: Vec<Session> = {
+        let mut sessions = state.sessions.write().await;
+        let now = Instant::now();
+        let ids: Vec<String> = sessions
+            .iter()
+            .filter(|(_, s)| state.cleanup_policy.is_expired(s, now))
+            .map(|(id, _)| id.clone())
+            .collect();
+        ids.iter().filter_map(|id| sessions.remove(id)).collect()
+    };
+
+    for session in expired {
+        info!("Cleaning up expired session: {}", session.id);
+        teardown_session(state, session, LifecycleTransition::Expired).await;
+    }
+}
+
+/// Remove expired entries from the trash of every session that keeps one.
+async fn purge_trash(state: &AppState) {
+    let roots: Vec<(String, PathBuf)> = {
+        let sessions = state.sessions.read().await;
+        sessions
+            .values()
+            .filter(|s| s.trash_ttl.is_some())
+            .map(|s| (s.id.clone(), s.sandbox_root.clone()))
+            .collect()
+    };
+    if roots.is_empty() {
+        return;
+    }
+    let _ = tokio::task::spawn_blocking(move || {
+        for (id, root) in roots {
+            match trash::purge(&root) {
+                Ok(0) => {}
+                Ok(n) => info!("Purged {} expired trash entries of session {}", n, id),
+                Err(e) => warn!("Purging the trash of session {}: {}", id, e),
+            }
+        }
+    })
+    .await;
+}
+
+/// Remove uploads left unfinished past [`uploads::UPLOAD_TTL`].
+async fn purge_uploads(state: &AppState) {
+    let roots: Vec<(String, PathBuf)> = {
+        let sessions = state.sessions.read().await;
+        sessions
+            .values()
+            .map(|s| (s.id.clone(), s.sandbox_root.clone()))
+            .collect()
+    };
+    let _ = tokio::task::spawn_blocking(move || {
+        for (id, root) in roots {
+            match uploads::purge(&root) {
+                Ok(0) => {}
+                Ok(n) => info!("Removed {} expired uploads of session {}", n, id),
+                Err(e) => warn!("Purging the uploads of session {}: {}", id, e),
+            }
+        }
+    })
+    .await;
+}
+
+// File operation handlers
+
+async fn write_file(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+    api_key: Option<Extension<Arc<ApiKey>>>,
+    Json(req): Json<WriteFileRequest>,
+) -> Result<Json<WriteFileResponse>, (StatusCode, String)> {
+    #[cfg(feature = "chaos")]
+    state.faults.check_storage_write("file write")?;
+    let sandbox_root = {
+        let mut sessions = state.sessions.write().await;
+        let session = sessions
+            .get_mut(&id)
+            .ok_or((StatusCode::NOT_FOUND, "Session not found".to_string()))?;
+        session.last_used = Instant::now();
+        session.sandbox_root.clone()
+    };
+
+    // Decode base64 content
+    let content = BASE64
+        .decode(&req.content)
+        .map_err(|e| (StatusCode::BAD_REQUEST, format!("Invalid base64: {}", e)))?;
+    let at = write_position(&req)?;
+    let write = FileWrite {
+        session_id: &id,
+        path: &req.path,
+        content: Some(&content),
+    };
+    state
+        .check_file_write(api_key.as_ref().map(|Extension(key)| key.as_ref()), &write)
+        .map_err(|e| (StatusCode::FORBIDDEN, e))?;
+
+    let backend = state.backend.clone();
+    tokio::task::spawn_blocking(move || backend.write_file_at(&sandbox_root, &req.path, &content, at))
+    .await
+    .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+    .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e))?;
+
+    Ok(Json(WriteFileResponse { success: true }))
+}
+
+/// Where a write's content goes, from its `mode` and `offset`.
+fn write_position(req: &WriteFileRequest) -> Result<WriteAt, (StatusCode, String)> {
+    match (req.mode, req.offset) {
+        (WriteMode::Overwrite, None) => Ok(WriteAt::Truncate),
+        (WriteMode::Overwrite, Some(offset)) => Ok(WriteAt::Offset(offset)),
+        (WriteMode::Append, None) => Ok(WriteAt::End),
+        (WriteMode::Append, Some(_)) => Err((
+            StatusCode::BAD_REQUEST,
+            format!("{}: offset can't be combined with append", req.path),
+        )),
+    }
+}
+
+async fn write_files_bulk(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+    api_key: Option<Extension<Arc<ApiKey>>>,
+    Json(req): Json<WriteFilesRequest>,
+) -> Result<Json<WriteFilesResponse>, (StatusCode, String)> {
+    #[cfg(feature = "chaos")]
+    state.faults.check_storage_write("file write")?;
+    let sandbox_root = {
+        let mut sessions = state.sessions.write().await;
+        let session = sessions
+            .get_mut(&id)
+            .ok_or((StatusCode::NOT_FOUND, "Session not found".to_string()))?;
+        session.last_used = Instant::now();
+        session.sandbox_root.clone()
+    };
+
+    // Decode all files from base64 first
+    let mut decoded_files: Vec<(String, Vec<u8>, WriteAt)> = Vec::with_capacity(req.files.len());
+    for entry in &req.files {
+        let content = BASE64
+            .decode(&entry.content)
+            .map_err(|e| (StatusCode::BAD_REQUEST, format!("Invalid base64 for {}: {}", entry.path, e)))?;
+        decoded_files.push((entry.path.clone(), content, write_position(entry)?));
+    }
+    // A refused file refuses the whole batch, before anything is written
+    let api_key = api_key.as_ref().map(|Extension(key)| key.as_ref());
+    for (path, content, _) in &decoded_files {
+        let write = FileWrite {
+            session_id: &id,
+            path,
+            content: Some(content),
+        };
+        state.check_file_write(api_key, &write).map_err(|e| (StatusCode::FORBIDDEN, e))?;
+    }
+
+    // Write all files in a single blocking task
+    let backend = state.backend.clone();
+    let errors = tokio::task::spawn_blocking(move || {
+        let mut errors = Vec::new();
+        for (path, content, at) in &decoded_files {
+            if let Err(e) = backend.write_file_at(&sandbox_root, path, content, *at) {
+                errors.push(WriteFileError {
+                    path: path.clone(),
+                    error: e,
+                });
+            }
+        }
+        errors
+    })
+    .await
+    .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+
+    Ok(Json(WriteFilesResponse {
+        success: errors.is_empty(),
+        errors,
+    }))
+}
+
+async fn read_file(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+    Query(query): Query<ReadFileQuery>,
+) -> Result<Json<ReadFileResponse>, (StatusCode, String)> {
+    let sandbox_root = {
+        let mut sessions = state.sessions.write().await;
+        let session = sessions
+            .get_mut(&id)
+            .ok_or((StatusCode::NOT_FOUND, "Session not found".to_string()))?;
+        session.last_used = Instant::now();
+        session.sandbox_root.clone()
+    };
+
+    let (backend, path) = (state.backend.clone(), query.path.clone());
+    let content = tokio::task::spawn_blocking(move || backend.read_file(&sandbox_root, &path))
+    .await
+    .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+    .map_err(|e| (StatusCode::NOT_FOUND, e))?;
+
+    Ok(Json(ReadFileResponse {
+        content: BASE64.encode(&content),
+    }))
+}
+
+/// Stream a file's raw bytes, honouring `Range`, `If-None-Match` and
+/// `If-Modified-Since`.
+async fn download_file(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+    Query(query): Query<ReadFileQuery>,
+    headers: HeaderMap,
+) -> Result<Response, (StatusCode, String)> {
+    let sandbox_root = {
+        let mut sessions = state.sessions.write().await;
+        let session = sessions
+            .get_mut(&id)
+            .ok_or((StatusCode::NOT_FOUND, "Session not found".to_string()))?;
+        session.last_used = Instant::now();
+        session.sandbox_root.clone()
+    };
+
+    let (backend, path) = (state.backend.clone(), query.path.clone());
+    let (file, meta) = tokio::task::spawn_blocking(move || {
+        let file = backend.open_file(&sandbox_root, &path)?;
+        let meta = file.metadata().map_err(|e| format!("read file: {}", e))?;
+        Ok::<_, String>((file, meta))
+    })
+    .await
+    .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+    .map_err(|e| (StatusCode::NOT_FOUND, e))?;
+    file_response(file, &meta, download::content_type(&query.path), &headers).await
+}
+
+/// Response with `file`'s bytes, or the range or `304` that `headers` ask for.
+async fn file_response(
+    file: std::fs::File,
+    meta: &std::fs::Metadata,
+    content_type: &str,
+    headers: &HeaderMap,
+) -> Result<Response, (StatusCode, String)> {
+    let validators = Validators::of(meta);
+    let response = Response::builder()
+        .header(header::ETAG, &validators.etag)
+        .header(header::LAST_MODIFIED, validators.last_modified_header())
+        .header(header::CACHE_CONTROL, "no-cache");
+    let internal = |e: axum::http::Error| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string());
+    if validators.not_modified(headers) {
+        return response.status(StatusCode::NOT_MODIFIED).body(Body::empty()).map_err(internal);
+    }
+
+    let size = meta.len();
+    let response = response
+        .header(header::ACCEPT_RANGES, "bytes")
+        .header(header::CONTENT_TYPE, content_type);
+    let (response, start, len) = match download::requested_range(headers, &validators, size) {
+        ByteRange::Full => (response.status(StatusCode::OK), 0, size),
+        ByteRange::Partial { start, end } => (
+            response
+                .status(StatusCode::PARTIAL_CONTENT)
+                .header(header::CONTENT_RANGE, format!("bytes {}-{}/{}", start, end, size)),
+            start,
+            end - start + 1,
+        ),
+        ByteRange::Unsatisfiable => {
+            return response
+                .status(StatusCode::RANGE_NOT_SATISFIABLE)
+                .header(header::CONTENT_RANGE, format!("bytes */{}", size))
+                .body(Body::empty())
+                .map_err(internal);
+        }
+    };
+
+    let mut file = tokio::fs::File::from_std(file);
+    file.seek(std::io::SeekFrom::Start(start))
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, format!("read file: {}", e)))?;
+    response
+        .header(header::CONTENT_LENGTH, len)
+        .body(Body::from_stream(download::body(file, len)))
+        .map_err(internal)
+}
+
+/// Checksum of one file.
+async fn hash_file(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+    Query(query): Query<FileHashQuery>,
+) -> Result<Json<FileHash>, (StatusCode, String)> {
+    let sandbox_root = {
+        let mut sessions = state.sessions.write().await;
+        let session = sessions
+            .get_mut(&id)
+            .ok_or((StatusCode::NOT_FOUND, "Session not found".to_string()))?;
+        session.last_used = Instant::now();
+        session.sandbox_root.clone()
+    };
+
+    let backend = state.backend.clone();
+    let hash = tokio::task::spawn_blocking(move || {
+        file_hash::hash_file(backend.as_ref(), &sandbox_root, &query.path, query.algo)
+    })
+    .await
+    .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+    .map_err(|e| (StatusCode::NOT_FOUND, e))?;
+    Ok(Json(hash))
+}
+
+/// Checksums of several files. Files that can't be read are reported per
+/// path rather than failing the request.
+async fn hash_files(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+    Json(req): Json<FileHashesRequest>,
+) -> Result<Json<FileHashesResponse>, (StatusCode, String)> {
+    if req.paths.len() > file_hash::MAX_PATHS {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            format!("At most {} paths per request", file_hash::MAX_PATHS),
+        ));
+    }
+    let sandbox_root = {
+        let mut sessions = state.sessions.write().await;
+        let session = sessions
+            .get_mut(&id)
+            .ok_or((StatusCode::NOT_FOUND, "Session not found".to_string()))?;
+        session.last_used = Instant::now();
+        session.sandbox_root.clone()
+    };
+
+    let backend = state.backend.clone();
+    let response = tokio::task::spawn_blocking(move || {
+        let (mut hashes, mut errors) = (Vec::new(), Vec::new());
+        for path in req.paths {
+            match file_hash::hash_file(backend.as_ref(), &sandbox_root, &path, req.algo) {
+                Ok(hash) => hashes.push(hash),
+                Err(error) => errors.push(FileHashError { path, error }),
+            }
+        }
+        FileHashesResponse { hashes, errors }
+    })
+    .await
+    .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    Ok(Json(response))
+}
+
+/// What differs between two paths, or between a path and its checkpoint copy.
+async fn diff_files(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+    Json(req): Json<FilesDiffRequest>,
+) -> Result<Json<FilesDiff>, (StatusCode, String)> {
+    let sandbox_root = {
+        let mut sessions = state.sessions.write().await;
+        let session = sessions
+            .get_mut(&id)
+            .ok_or((StatusCode::NOT_FOUND, "Session not found".to_string()))?;
+        session.last_used = Instant::now();
+        session.sandbox_root.clone()
+    };
+
+    tokio::task::spawn_blocking(move || file_diff::diff(&sandbox_root, &id, &req))
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+        .map(Json)
+}
+
+/// Files the session added, removed or modified since it was created.
+async fn session_changes(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+    Query(query): Query<SessionChangesQuery>,
+) -> Result<Json<SessionChanges>, (StatusCode, String)> {
+    let sandbox_root = {
+        let mut sessions = state.sessions.write().await;
+        let session = sessions
+            .get_mut(&id)
+            .ok_or((StatusCode::NOT_FOUND, "Session not found".to_string()))?;
+        session.last_used = Instant::now();
+        session.sandbox_root.clone()
+    };
+
+    tokio::task::spawn_blocking(move || changes::changes(&sandbox_root, &id, &query))
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+        .map(Json)
+}
+
+/// Delete a file or directory, into the trash if the session keeps one.
+async fn delete_file(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+    Json(req): Json<DeleteFileRequest>,
+) -> Result<Json<DeleteFileResponse>, (StatusCode, String)> {
+    let (sandbox_root, trash_ttl) = {
+        let mut sessions = state.sessions.write().await;
+        let session = sessions
+            .get_mut(&id)
+            .ok_or((StatusCode::NOT_FOUND, "Session not found".to_string()))?;
+        session.last_used = Instant::now();
+        (session.sandbox_root.clone(), session.trash_ttl)
+    };
+
+    tokio::task::spawn_blocking(move || trash::delete(&sandbox_root, &req, trash_ttl))
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+        .map(Json)
+}
+
+async fn list_trash(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+) -> Result<Json<Vec<TrashEntry>>, (StatusCode, String)> {
+    let sandbox_root = {
+        let mut sessions = state.sessions.write().await;
+        let session = sessions
+            .get_mut(&id)
+            .ok_or((StatusCode::NOT_FOUND, "Session not found".to_string()))?;
+        session.last_used = Instant::now();
+        session.sandbox_root.clone()
+    };
+
+    tokio::task::spawn_blocking(move || trash::list(&sandbox_root))
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+        .map(Json)
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e))
+}
+
+async fn restore_file(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+    Json(req): Json<RestoreFileRequest>,
+) -> Result<Json<RestoreFileResponse>, (StatusCode, String)> {
+    let sandbox_root = {
+        let mut sessions = state.sessions.write().await;
+        let session = sessions
+            .get_mut(&id)
+            .ok_or((StatusCode::NOT_FOUND, "Session not found".to_string()))?;
+        session.last_used = Instant::now();
+        session.sandbox_root.clone()
+    };
+
+    tokio::task::spawn_blocking(move || trash::restore(&sandbox_root, &req))
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+        .map(Json)
+}
+
+/// Write what a sync manifest says differs and came with content; see
+/// [`crate::file_sync`].
+async fn sync_files(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+    api_key: Option<Extension<Arc<ApiKey>>>,
+    Json(req): Json<SyncRequest>,
+) -> Result<Json<SyncPushResponse>, (StatusCode, String)> {
+    #[cfg(feature = "chaos")]
+    state.faults.check_storage_write("file sync")?;
+    let sandbox_root = touch_sandbox_root(&state, &id).await?;
+    if !state.hooks.is_empty() {
+        let api_key = api_key.as_ref().map(|Extension(key)| key.as_ref());
+        for file in &req.files {
+            // Files without content aren't written; the response asks for them
+            let Some(ref encoded) = file.content else {
+                continue;
+            };
+            let content = BASE64
+                .decode(encoded)
+                .map_err(|e| (StatusCode::BAD_REQUEST, format!("Invalid base64 for {}: {}", file.path, e)))?;
+            let path = format!("{}/{}", req.path.trim_end_matches('/'), file.path);
+            let write = FileWrite {
+                session_id: &id,
+                path: &path,
+                content: Some(&content),
+            };
+            state.check_file_write(api_key, &write).map_err(|e| (StatusCode::FORBIDDEN, e))?;
+        }
+    }
+    tokio::task::spawn_blocking(move || file_sync::push(&sandbox_root, &req))
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+        .map(Json)
+}
+
+/// The session's files that differ from a sync manifest.
+async fn pull_files(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+    Json(req): Json<SyncRequest>,
+) -> Result<Json<SyncPullResponse>, (StatusCode, String)> {
+    let sandbox_root = touch_sandbox_root(&state, &id).await?;
+    tokio::task::spawn_blocking(move || file_sync::pull(&sandbox_root, &req))
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+        .map(Json)
+}
+
+// Resumable upload handlers
+
+const UPLOAD_OFFSET_HEADER: &str = "upload-offset";
+const UPLOAD_LENGTH_HEADER: &str = "upload-length";
+
+/// The session's sandbox root; counts as use of it.
+async fn touch_sandbox_root(state: &AppState, id: &str) -> Result<PathBuf, (StatusCode, String)> {
+    let mut sessions = state.sessions.write().await;
+    let session = sessions
+        .get_mut(id)
+        .ok_or((StatusCode::NOT_FOUND, "Session not found".to_string()))?;
+    session.last_used = Instant::now();
+    Ok(session.sandbox_root.clone())
+}
+
+/// `Upload-Offset` and `Upload-Length` headers for `status`.
+fn upload_headers(status: &UploadStatus) -> [(&'static str, String); 2] {
+    [
+        (UPLOAD_OFFSET_HEADER, status.offset.to_string()),
+        (UPLOAD_LENGTH_HEADER, status.size.to_string()),
+    ]
+}
+
+/// Start a resumable upload; see [`crate::uploads`].
+async fn create_upload(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+    api_key: Option<Extension<Arc<ApiKey>>>,
+    OriginalUri(uri): OriginalUri,
+    Json(req): Json<CreateUploadRequest>,
+) -> Result<Response, (StatusCode, String)> {
+    let sandbox_root = touch_sandbox_root(&state, &id).await?;
+    let write = FileWrite {
+        session_id: &id,
+        path: &req.path,
+        content: None,
+    };
+    state
+        .check_file_write(api_key.as_ref().map(|Extension(key)| key.as_ref()), &write)
+        .map_err(|e| (StatusCode::FORBIDDEN, e))?;
+    let status = tokio::task::spawn_blocking(move || uploads::create(&sandbox_root, &req))
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))??;
+    let location = format!("{}/{}", uri.path().trim_end_matches('/'), status.id);
+    Ok((
+        StatusCode::CREATED,
+        [(header::LOCATION, location)],
+        upload_headers(&status),
+        Json(status),
+    )
+        .into_response())
+}
+
+async fn upload_status(
+    State(state): State<AppState>,
+    Path((id, upload_id)): Path<(String, String)>,
+) -> Result<Response, (StatusCode, String)> {
+    let sandbox_root = touch_sandbox_root(&state, &id).await?;
+    let status = tokio::task::spawn_blocking(move || uploads::status(&sandbox_root, &upload_id))
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))??;
+    Ok((upload_headers(&status), Json(status)).into_response())
+}
+
+/// Add the request body to an upload at the `Upload-Offset` it names.
+async fn upload_chunk(
+    State(state): State<AppState>,
+    Path((id, upload_id)): Path<(String, String)>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<Response, (StatusCode, String)> {
+    #[cfg(feature = "chaos")]
+    state.faults.check_storage_write("upload chunk")?;
+    let offset = headers
+        .get(UPLOAD_OFFSET_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<u64>().ok())
+        .ok_or((StatusCode::BAD_REQUEST, "Upload-Offset header required".to_string()))?;
+    let sandbox_root = touch_sandbox_root(&state, &id).await?;
+    let offset = tokio::task::spawn_blocking(move || uploads::append(&sandbox_root, &upload_id, offset, &body))
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))??;
+    Ok((StatusCode::NO_CONTENT, [(UPLOAD_OFFSET_HEADER, offset.to_string())]).into_response())
+}
+
+async fn finalize_upload(
+    State(state): State<AppState>,
+    Path((id, upload_id)): Path<(String, String)>,
+) -> Result<Json<FinalizeUploadResponse>, (StatusCode, String)> {
+    let sandbox_root = touch_sandbox_root(&state, &id).await?;
+    tokio::task::spawn_blocking(move || uploads::finalize(&sandbox_root, &upload_id))
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+        .map(Json)
+}
+
+async fn cancel_upload(
+    State(state): State<AppState>,
+    Path((id, upload_id)): Path<(String, String)>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    let sandbox_root = touch_sandbox_root(&state, &id).await?;
+    tokio::task::spawn_blocking(move || uploads::cancel(&sandbox_root, &upload_id))
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))??;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn list_files(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+    Query(query): Query<ListFilesQuery>,
+) -> Result<Json<ListFilesResponse>, (StatusCode, String)> {
+    let sandbox_root = {
+        let mut sessions = state.sessions.write().await;
+        let session = sessions
+            .get_mut(&id)
+            .ok_or((StatusCode::NOT_FOUND, "Session not found".to_string()))?;
+        session.last_used = Instant::now();
+        session.sandbox_root.clone()
+    };
+
+    let (backend, path) = (state.backend.clone(), query.path.clone());
+    let entries = tokio::task::spawn_blocking(move || backend.list_files(&sandbox_root, &path))
+    .await
+    .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+    .map_err(|e| (StatusCode::NOT_FOUND, e))?;
+
+    let files: Vec<FileEntry> = entries
+        .into_iter()
+        .map(|e| FileEntry {
+            name: e.name,
+            path: e.path,
+            is_directory: e.is_directory,
+            size: e.size,
+        })
+        .collect();
+
+    Ok(Json(ListFilesResponse { files }))
+}
+
+// Background process handler
+
+async fn run_background(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+    api_key: Option<Extension<Arc<ApiKey>>>,
+    headers: HeaderMap,
+    Json(req): Json<BackgroundRunRequest>,
+) -> Result<Json<BackgroundRunResponse>, (StatusCode, String)> {
+    let key = api_key.as_ref().map(|Extension(key)| key.as_ref());
+    start_background(&state, &id, key, &headers, req).await.map(Json)
+}
+
+/// Start a background process in session `id` and start watching it.
+async fn start_background(
+    state: &AppState,
+    id: &str,
+    api_key: Option<&ApiKey>,
+    headers: &HeaderMap,
+    mut req: BackgroundRunRequest,
+) -> Result<BackgroundRunResponse, (StatusCode, String)> {
+    state
+        .env_policy
+        .apply(&mut req.env)
+        .map_err(|e| (StatusCode::BAD_REQUEST, e))?;
+    state
+        .check_org_quota(api_key)
+        .await
+        .map_err(|e| (StatusCode::TOO_MANY_REQUESTS, e))?;
+
+    let (sandbox_root, mut env, secret_values, cwd, determinism, preview_url) = {
+        let mut sessions = state.sessions.write().await;
+        let session = sessions
+            .get_mut(id)
+            .ok_or((StatusCode::NOT_FOUND, "Session not found".to_string()))?;
+        ensure_not_suspended(session)?;
+        session.last_used = Instant::now();
+        (
+            session.sandbox_root.clone(),
+            session.process_env(),
+            session.secret_values(),
+            session.cwd.clone(),
+            Some(session.determinism.clone()),
+            session.preview_url.clone(),
+        )
+    };
+
+    for name in &req.unset_env {
+        env.remove(name);
+    }
+    env.extend(req.env);
+    let cwd = req.cwd.filter(|c| c != DEFAULT_CWD).unwrap_or(cwd);
+    sandbox::confine_cwd(&sandbox_root, &cwd).map_err(|e| (StatusCode::BAD_REQUEST, e))?;
+    let run = RunStart {
+        session_id: Some(id.to_string()),
+        command: secrets::redact_all(&req.command, &secret_values),
+        cwd: cwd.clone(),
+        background: true,
+    };
+    state.check_run_start(api_key, &run).map_err(|e| (StatusCode::FORBIDDEN, e))?;
+
+    // Auto-assign a unique port if client sends 0, otherwise use requested port
+    let requested = req.port.unwrap_or(DEFAULT_BACKGROUND_PORT);
+    let port = match requested {
+        0 => state.allocate_port().ok_or((
+            StatusCode::SERVICE_UNAVAILABLE,
+            "No free ports for background processes".to_string(),
+        ))?,
+        port => port,
+    };
+
+    // Inject port as env var so vite/dev servers can use it
+    env.insert("VITE_PORT".to_string(), port.to_string());
+    env.insert("PORT".to_string(), port.to_string());
+    let trace = start_run_trace(headers, &mut env);
+
+    info!("Assigning port {} for background process in session {}", port, id);
+
+    let config = RunConfig {
+        command: req.command,
+        time_ms: 0,
+        mem_kb: 0,
+        fsize_kb: 0,
+        nofile: 0,
+        nproc: 0,
+        env,
+        cwd,
+        commit_on_success: false,
+        determinism,
+        stdin: None,
+        max_output_bytes: 0,
+        kill_grace_ms: 0,
+        secrets: secret_values,
+    };
+
+    let command = secrets::redact_all(&config.command, &config.secrets);
+    let backend = state.backend.clone();
+    let spawned = tokio::task::spawn_blocking(move || backend.run_background(&sandbox_root, &config))
+    .await
+    .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))
+    .and_then(|r| r.map_err(run_error));
+    let child = match spawned {
+        Ok(child) => child,
+        Err(e) => {
+            if requested == 0 {
+                state.release_port(port);
+            }
+            return Err(e);
+        }
+    };
+    let pid = child.id();
+
+    // Track the background process and port
+    let event = {
+        let mut sessions = state.sessions.write().await;
+        sessions.get_mut(id).map(|session| {
+            session.background_pids.push(pid);
+            state.timeline.record(id, SessionEventKind::BackgroundStarted { pid, port, command });
+            if !session.ports.contains(&port) {
+                session.ports.push(port);
+                // Keep auto-assignment off a port the caller picked
+                state.ports.claim(port);
+                state.timeline.record(id, SessionEventKind::PortRegistered { port, detected: false });
+            }
+            state.persist_session(session);
+            SessionLifecycleEvent::from_session(session)
+        })
+    };
+    match event {
+        Some(event) => watch_background_exit(state.clone(), event, child, port),
+        // Session was deleted while starting; don't leave the process behind
+        None => {
+            let mut child = child;
+            let _ = child.kill();
+            let _ = child.wait();
+            if requested == 0 {
+                state.release_port(port);
+            }
+            return Err((StatusCode::NOT_FOUND, "Session not found".to_string()));
+        }
+    }
+
+    info!("Started background process pid={} port={} session={}", pid, port, id);
+
+    Ok(BackgroundRunResponse {
+        pid,
+        port,
+        preview_url,
+        trace_id: Some(trace.trace_id),
+    })
+}
+
+/// Create an ephemeral session, unpack the uploaded app, start its dev server
+/// and return the preview URL once it answers. The session is deleted again
+/// if any step fails.
+async fn run_preview(
+    State(state): State<AppState>,
+    api_key: Option<Extension<Arc<ApiKey>>>,
+    headers: HeaderMap,
+    Json(req): Json<RunPreviewRequest>,
+) -> Result<Json<RunPreviewResponse>, (StatusCode, String)> {
+    if req.command.is_empty() {
+        return Err((StatusCode::BAD_REQUEST, "command must not be empty".to_string()));
+    }
+    let ready_timeout = req.ready_timeout.unwrap_or(DEFAULT_READY_TIMEOUT_SECS);
+    if ready_timeout == 0 || ready_timeout > MAX_READY_TIMEOUT_SECS {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            format!("ready_timeout must be 1-{} seconds", MAX_READY_TIMEOUT_SECS),
+        ));
+    }
+    let archive = BASE64
+        .decode(&req.archive)
+        .map_err(|e| (StatusCode::BAD_REQUEST, format!("Invalid base64: {}", e)))?;
+
+    let api_key = api_key.map(|Extension(key)| key);
+    let created = create_session_for(&state, api_key.clone(), req.session).await?;
+    let id = created.session_id.clone();
+    let dir = req.dir.unwrap_or_else(|| DEFAULT_PREVIEW_DIR.to_string());
+    let dir = format!("/{}", dir.trim_matches('/'));
+    let background = BackgroundRunRequest {
+        command: req.command,
+        port: Some(req.port.unwrap_or(0)),
+        env: HashMap::new(),
+        unset_env: Vec::new(),
+        cwd: Some(dir),
+    };
+    let ready_timeout = Duration::from_secs(ready_timeout);
+    let started = async {
+        let (background, sandbox_root) =
+            start_preview(&state, &id, api_key.as_deref(), &headers, archive, background).await?;
+        let launched = Instant::now();
+        let ready_path = req.ready_path.as_deref();
+        let ready = wait_until_ready(background.port, background.pid, ready_path, ready_timeout).await;
+        if let Err((status, reason)) = ready {
+            let secret_values = state.sessions.read().await.get(&id).map(Session::secret_values);
+            let log = background_log_tail(&state, sandbox_root, background.pid, secret_values.unwrap_or_default()).await;
+            return Err((status, format!("{}. Log output:\n{}", reason, log)));
+        }
+        Ok((background, launched.elapsed().as_millis() as u64))
+    }
+    .await;
+    let (background, ready_ms) = match started {
+        Ok(started) => started,
+        Err(e) => {
+            if let Some(session) = state.sessions.write().await.remove(&id) {
+                teardown_session(&state, session, LifecycleTransition::Deleted).await;
+            }
+            return Err(e);
+        }
+    };
+    info!("Preview ready for session {} on port {} after {}ms", id, background.port, ready_ms);
+
+    Ok(Json(RunPreviewResponse {
+        session_id: id,
+        preview_url: created.preview_url,
+        preview_token: created.preview_token,
+        pid: background.pid,
+        port: background.port,
+        trace_id: background.trace_id.unwrap_or_default(),
+        ready_ms,
+        capacity: state.capacity(api_key.as_deref()).await,
+    }))
+}
+
+/// Unpack the app of a `/run-preview` session and start its dev server.
+/// Returns the process and the session's sandbox root.
+async fn start_preview(
+    state: &AppState,
+    id: &str,
+    api_key: Option<&ApiKey>,
+    headers: &HeaderMap,
+    archive: Vec<u8>,
+    req: BackgroundRunRequest,
+) -> Result<(BackgroundRunResponse, PathBuf), (StatusCode, String)> {
+    let sandbox_root = state
+        .sessions
+        .read()
+        .await
+        .get(id)
+        .map(|s| s.sandbox_root.clone())
+        .ok_or((StatusCode::NOT_FOUND, "Session not found".to_string()))?;
+
+    let root = sandbox_root.clone();
+    let dir = req.cwd.clone().unwrap_or_else(|| DEFAULT_CWD.to_string());
+    let progress = state
+        .progress
+        .start("preview.extract")
+        .org(api_key.map(ApiKey::org))
+        .session(id);
+    tokio::task::spawn_blocking(move || sandbox::extract_archive_in_sandbox(&root, &dir, &archive, progress))
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+        .map_err(|e| (StatusCode::BAD_REQUEST, e))?;
+
+    let background = start_background(state, id, api_key, headers, req).await?;
+    Ok((background, sandbox_root))
+}
+
+/// Poll a dev server until it accepts connections (or answers `ready_path`
+/// with a non-5xx status). Fails if the process exits or `timeout` passes.
+async fn wait_until_ready(
+    port: u16,
+    pid: u32,
+    ready_path: Option<&str>,
+    timeout: Duration,
+) -> Result<(), (StatusCode, String)> {
+    let deadline = Instant::now() + timeout;
+    let client = reqwest::Client::builder()
+        .connect_timeout(READY_POLL_INTERVAL)
+        .timeout(Duration::from_secs(PROXY_CONNECT_TIMEOUT_SECS))
+        .build()
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, format!("Ready check client error: {}", e)))?;
+    loop {
+        let ready = match ready_path {
+            Some(path) => {
+                let url = format!("http://127.0.0.1:{}/{}", port, path.trim_start_matches('/'));
+                client
+                    .get(&url)
+                    .send()
+                    .await
+                    .is_ok_and(|resp| !resp.status().is_server_error())
+            }
+            None => tokio::net::TcpStream::connect(("127.0.0.1", port)).await.is_ok(),
+        };
+        if ready {
+            return Ok(());
+        }
+        if !sandbox::is_process_alive(pid) {
+            return Err((
+                StatusCode::BAD_GATEWAY,
+                format!("Dev server (pid {}) exited before it was ready", pid),
+            ));
+        }
+        if Instant::now() >= deadline {
+            return Err((
+                StatusCode::GATEWAY_TIMEOUT,
+                format!("Dev server was not ready on port {} after {}s", port, timeout.as_secs()),
+            ));
+        }
+        tokio::time::sleep(READY_POLL_INTERVAL).await;
+    }
+}
+
+/// The end of background process `pid`'s log, for failure messages, with
+/// `secret_values` masked.
+async fn background_log_tail(state: &AppState, sandbox_root: PathBuf, pid: u32, secret_values: Vec<String>) -> String {
+    let logs = state.background_logs.clone();
+    tokio::task::spawn_blocking(move || {
+        let size = logs.read(&sandbox_root, pid, u64::MAX, Some(0)).map(|chunk| chunk.size).unwrap_or(0);
+        let offset = size.saturating_sub(READY_FAILURE_LOG_BYTES);
+        logs.read(&sandbox_root, pid, offset, None)
+            .map(|chunk| secrets::redact(&chunk.data, &secret_values))
+            .unwrap_or_default()
+    })
+    .await
+    .unwrap_or_default()
+}
+
+/// Reap a background process on a dedicated thread and notify hooks when it exits.
+fn watch_background_exit(state: AppState, event: SessionLifecycleEvent, mut child: Child, port: u16) {
+    use std::os::unix::process::ExitStatusExt;
+
+    let pid = child.id();
+    let spawned = std::thread::Builder::new()
+        .name(format!("bg-wait-{}", pid))
+        .spawn(move || {
+            let (exit_code, signal) = match child.wait() {
+                Ok(status) => (status.code(), status.signal()),
+                Err(_) => (None, None),
+            };
+            // Killed by CRIU once dumped; it comes back when the session wakes
+            let hibernated = state
+                .sessions
+                .blocking_read()
+                .get(&event.session_id)
+                .is_some_and(|session| session.status == SessionStatus::Hibernated);
+            if hibernated {
+                info!("Background process pid={} session={} was hibernated", pid, event.session_id);
+                return;
+            }
+            info!(
+                "Background process pid={} session={} exited: code={:?} signal={:?}",
+                pid, event.session_id, exit_code, signal
+            );
+            let exit = BackgroundExit {
+                pid,
+                port,
+                exit_code,
+                signal,
+            };
+            state.timeline.record(&event.session_id, timeline::background_exit(&exit));
+            state.notify_background_exit(&event, &exit);
+            release_exited_port(&state, &event.session_id, port);
+        });
+    if let Err(e) = spawned {
+        tracing::warn!("Failed to watch background process {}: {}", pid, e);
+    }
+}
+
+/// Detach a background process's port from its session once nothing listens
+/// on it anymore, and return it to the pool. Another process in the session
+/// may have been started on the same port.
+fn release_exited_port(state: &AppState, session_id: &str, port: u16) {
+    if !ports::is_available(port) {
+        return;
+    }
+    let mut sessions = state.sessions.blocking_write();
+    let Some(session) = sessions.get_mut(session_id) else {
+        return;
+    };
+    let Some(pos) = session.ports.iter().position(|&p| p == port) else {
+        return;
+    };
+    session.ports.remove(pos);
+    session.detected_ports.retain(|&p| p != port);
+    state.persist_session(session);
+    state.release_port(port);
+    state.timeline.record(session_id, SessionEventKind::PortReleased { port });
+}
+
+// Kill all background processes for a session
+
+async fn kill_background(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+) -> Result<Json<KillBackgroundResponse>, (StatusCode, String)> {
+    let pids = {
+        let mut sessions = state.sessions.write().await;
+        let session = sessions
+            .get_mut(&id)
+            .ok_or((StatusCode::NOT_FOUND, "Session not found".to_string()))?;
+        // Its processes live in the dumps until it wakes
+        if session.status == SessionStatus::Hibernated {
+            ensure_not_suspended(session)?;
+        }
+        session.last_used = Instant::now();
+        let pids = session.background_pids.clone();
+        session.background_pids.clear();
+        for port in session.ports.drain(..) {
+            state.release_port(port);
+        }
+        session.detected_ports.clear();
+        state.persist_session(session);
+        pids
+    };
+
+    let killed: Vec<u32> = pids
+        .iter()
+        .filter(|&&pid| {
+            let result = nix::sys::signal::kill(
+                nix::unistd::Pid::from_raw(pid as i32),
+                nix::sys::signal::Signal::SIGKILL,
+            );
+            result.is_ok()
+        })
+        .copied()
+        .collect();
+
+    info!("Killed {} background processes for session {}: {:?}", killed.len(), id, killed);
+
+    Ok(Json(KillBackgroundResponse {
+        killed,
+        total: pids.len(),
+    }))
+}
+
+// Packages
+
+/// Install pip or npm packages into the session from the shared cache,
+/// downloading on the host only what the cache lacks. Reports progress as
+/// `packages.install` events.
+async fn install_packages(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+    api_key: Option<Extension<Arc<ApiKey>>>,
+    Json(req): Json<InstallPackagesRequest>,
+) -> Result<Json<InstallPackagesResult>, (StatusCode, String)> {
+    packages::validate(req.manager, &req.packages).map_err(|e| (StatusCode::BAD_REQUEST, e))?;
+    let api_key = api_key.map(|Extension(key)| key);
+    state
+        .check_org_quota(api_key.as_deref())
+        .await
+        .map_err(|e| (StatusCode::TOO_MANY_REQUESTS, e))?;
+
+    let (sandbox_root, env, secret_values, cwd, determinism) = {
+        let mut sessions = state.sessions.write().await;
+        let session = sessions
+            .get_mut(&id)
+            .ok_or((StatusCode::NOT_FOUND, "Session not found".to_string()))?;
+        ensure_not_suspended(session)?;
+        session.last_used = Instant::now();
+        (
+            session.sandbox_root.clone(),
+            session.process_env(),
+            session.secret_values(),
+            session.cwd.clone(),
+            Some(session.determinism.clone()),
+        )
+    };
+    let cwd = req.dir.unwrap_or(cwd);
+    sandbox::confine_cwd(&sandbox_root, &cwd).map_err(|e| (StatusCode::BAD_REQUEST, e))?;
+    let config = RunConfig {
+        command: Vec::new(),
+        time_ms: DEFAULT_TIME_MS,
+        mem_kb: DEFAULT_MEM_KB,
+        fsize_kb: DEFAULT_FSIZE_KB,
+        nofile: DEFAULT_NOFILE,
+        nproc: sandbox::DEFAULT_NPROC,
+        env,
+        cwd,
+        commit_on_success: false,
+        determinism,
+        stdin: None,
+        max_output_bytes: sandbox::DEFAULT_MAX_OUTPUT_BYTES,
+        kill_grace_ms: sandbox::DEFAULT_KILL_GRACE_MS,
+        secrets: secret_values,
+    };
+
+    let permit = run_permit(&state, Some(&id)).await?;
+    let (cache, backend) = (state.packages.clone(), state.backend.clone());
+    let progress = state
+        .progress
+        .start("packages.install")
+        .org(api_key.as_deref().map(ApiKey::org))
+        .session(&id);
+    let result = tokio::task::spawn_blocking(move || {
+        let _permit = permit;
+        cache.install(&*backend, &sandbox_root, &config, req.manager, &req.packages, progress)
+    })
+    .await
+    .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+    .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e))?;
+    Ok(Json(result))
+}
+
+// Build cache
+
+/// Mount the cached directory for the project's lockfile, if there is one.
+async fn restore_build_cache(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+    api_key: Option<Extension<Arc<ApiKey>>>,
+    Json(req): Json<BuildCacheRequest>,
+) -> Result<Json<BuildCacheResult>, (StatusCode, String)> {
+    let (sandbox_root, cwd) = {
+        let mut sessions = state.sessions.write().await;
+        let session = sessions
+            .get_mut(&id)
+            .ok_or((StatusCode::NOT_FOUND, "Session not found".to_string()))?;
+        ensure_not_suspended(session)?;
+        session.last_used = Instant::now();
+        (session.sandbox_root.clone(), session.cwd.clone())
+    };
+    let dir = req.dir.unwrap_or(cwd);
+    sandbox::confine_cwd(&sandbox_root, &dir).map_err(|e| (StatusCode::BAD_REQUEST, e))?;
+    let org = build_cache_org(api_key.as_ref().map(|Extension(key)| &**key));
+    let cache = state.build_cache.clone();
+    let result = tokio::task::spawn_blocking(move || cache.restore(&id, &org, &sandbox_root, req.kind, &dir))
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))??;
+    Ok(Json(result))
+}
+
+/// Copy the project's directory into the build cache, unless its lockfile
+/// already has an entry.
+async fn save_build_cache(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+    api_key: Option<Extension<Arc<ApiKey>>>,
+    Json(req): Json<BuildCacheRequest>,
+) -> Result<Json<BuildCacheResult>, (StatusCode, String)> {
+    let (sandbox_root, env, secret_values, cwd) = {
+        let mut sessions = state.sessions.write().await;
+        let session = sessions
+            .get_mut(&id)
+            .ok_or((StatusCode::NOT_FOUND, "Session not found".to_string()))?;
+        ensure_not_suspended(session)?;
+        session.last_used = Instant::now();
+        (
+            session.sandbox_root.clone(),
+            session.process_env(),
+            session.secret_values(),
+            session.cwd.clone(),
+        )
+    };
+    let dir = req.dir.unwrap_or(cwd);
+    sandbox::confine_cwd(&sandbox_root, &dir).map_err(|e| (StatusCode::BAD_REQUEST, e))?;
+    let config = RunConfig {
+        command: Vec::new(),
+        time_ms: DEFAULT_TIME_MS,
+        mem_kb: DEFAULT_MEM_KB,
+        fsize_kb: DEFAULT_FSIZE_KB,
+        nofile: DEFAULT_NOFILE,
+        nproc: sandbox::DEFAULT_NPROC,
+        env,
+        cwd: DEFAULT_CWD.to_string(),
+        commit_on_success: false,
+        determinism: None,
+        stdin: None,
+        max_output_bytes: sandbox::DEFAULT_MAX_OUTPUT_BYTES,
+        kill_grace_ms: sandbox::DEFAULT_KILL_GRACE_MS,
+        secrets: secret_values,
+    };
+
+    let org = build_cache_org(api_key.as_ref().map(|Extension(key)| &**key));
+    let permit = run_permit(&state, Some(&id)).await?;
+    let (cache, backend) = (state.build_cache.clone(), state.backend.clone());
+    let result = tokio::task::spawn_blocking(move || {
+        let _permit = permit;
+        cache.save(&*backend, &org, &sandbox_root, &config, req.kind, &dir)
+    })
+    .await
+    .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))??;
+    Ok(Json(result))
+}
+
+/// Whose build cache entries a caller shares: its org's, or with auth off,
+/// everyone's.
+fn build_cache_org(api_key: Option<&ApiKey>) -> String {
+    api_key.map(|key| key.org().to_string()).unwrap_or_default()
+}
+
+// Git
+
+/// Sandbox root, run config and secrets for a git operation in `dir`
+/// (default the session's cwd).
+async fn git_context(
+    state: &AppState,
+    id: &str,
+    api_key: Option<Extension<Arc<ApiKey>>>,
+    dir: Option<String>,
+    max_output_bytes: u64,
+) -> Result<(PathBuf, RunConfig, HashMap<String, String>), (StatusCode, String)> {
+    let api_key = api_key.map(|Extension(key)| key);
+    state
+        .check_org_quota(api_key.as_deref())
+        .await
+        .map_err(|e| (StatusCode::TOO_MANY_REQUESTS, e))?;
+    let mut sessions = state.sessions.write().await;
+    let session = sessions
+        .get_mut(id)
+        .ok_or((StatusCode::NOT_FOUND, "Session not found".to_string()))?;
+    ensure_not_suspended(session)?;
+    session.last_used = Instant::now();
+    let cwd = dir.unwrap_or_else(|| session.cwd.clone());
+    sandbox::confine_cwd(&session.sandbox_root, &cwd).map_err(|e| (StatusCode::BAD_REQUEST, e))?;
+    let config = RunConfig {
+        command: Vec::new(),
+        time_ms: DEFAULT_TIME_MS,
+        mem_kb: DEFAULT_MEM_KB,
+        fsize_kb: DEFAULT_FSIZE_KB,
+        nofile: DEFAULT_NOFILE,
+        nproc: sandbox::DEFAULT_NPROC,
+        env: session.process_env(),
+        cwd,
+        commit_on_success: false,
+        determinism: Some(session.determinism.clone()),
+        stdin: None,
+        max_output_bytes,
+        kill_grace_ms: sandbox::DEFAULT_KILL_GRACE_MS,
+        secrets: session.secret_values(),
+    };
+    Ok((session.sandbox_root.clone(), config, session.secrets.clone()))
+}
+
+async fn run_git<T: Send + 'static>(
+    state: &AppState,
+    id: &str,
+    f: impl FnOnce(&dyn SandboxBackend) -> Result<T, String> + Send + 'static,
+) -> Result<Json<T>, (StatusCode, String)> {
+    let permit = run_permit(state, Some(id)).await?;
+    let backend = state.backend.clone();
+    tokio::task::spawn_blocking(move || {
+        let _permit = permit;
+        f(&*backend)
+    })
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+        .map(Json)
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e))
+}
+
+/// Clone a repository into the session, authenticating with a session secret
+/// if `credential` names one.
+async fn git_clone(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+    api_key: Option<Extension<Arc<ApiKey>>>,
+    Json(req): Json<GitCloneRequest>,
+) -> Result<Json<GitResult>, (StatusCode, String)> {
+    git::validate_clone(&req).map_err(|e| (StatusCode::BAD_REQUEST, e))?;
+    let (sandbox_root, config, secrets) =
+        git_context(&state, &id, api_key, None, sandbox::DEFAULT_MAX_OUTPUT_BYTES).await?;
+    let credential = git::credential_env(req.credential.as_ref(), &secrets).map_err(|e| (StatusCode::BAD_REQUEST, e))?;
+    run_git(&state, &id, move |backend| git::clone(backend, &sandbox_root, &config, &req, credential)).await
+}
+
+async fn git_pull(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+    api_key: Option<Extension<Arc<ApiKey>>>,
+    Json(req): Json<GitPullRequest>,
+) -> Result<Json<GitResult>, (StatusCode, String)> {
+    git::validate_pull(&req).map_err(|e| (StatusCode::BAD_REQUEST, e))?;
+    let (sandbox_root, config, secrets) =
+        git_context(&state, &id, api_key, req.dir.clone(), sandbox::DEFAULT_MAX_OUTPUT_BYTES).await?;
+    let credential = git::credential_env(req.credential.as_ref(), &secrets).map_err(|e| (StatusCode::BAD_REQUEST, e))?;
+    run_git(&state, &id, move |backend| git::pull(backend, &sandbox_root, &config, &req, credential)).await
+}
+
+async fn git_commit(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+    api_key: Option<Extension<Arc<ApiKey>>>,
+    Json(req): Json<GitCommitRequest>,
+) -> Result<Json<GitResult>, (StatusCode, String)> {
+    git::validate_commit(&req).map_err(|e| (StatusCode::BAD_REQUEST, e))?;
+    let (sandbox_root, config, _) =
+        git_context(&state, &id, api_key, req.dir.clone(), sandbox::DEFAULT_MAX_OUTPUT_BYTES).await?;
+    run_git(&state, &id, move |backend| git::commit(backend, &sandbox_root, &config, &req)).await
+}
+
+/// Patch of the session's working tree, new files included, for extracting
+/// what a run changed.
+async fn git_diff(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+    api_key: Option<Extension<Arc<ApiKey>>>,
+    Query(query): Query<GitDiffQuery>,
+) -> Result<Json<GitDiffResult>, (StatusCode, String)> {
+    git::validate_diff(&query).map_err(|e| (StatusCode::BAD_REQUEST, e))?;
+    let max_output_bytes = max_output_bytes(query.max_output_bytes)?;
+    let (sandbox_root, config, _) = git_context(&state, &id, api_key, query.dir, max_output_bytes).await?;
+    let diff = run_git(&state, &id, move |backend| {
+        git::diff(backend, &sandbox_root, &config, query.base.as_deref())
+    })
+    .await?;
+    diff.0.map(Json).map_err(|e| (StatusCode::BAD_REQUEST, e))
+}
+
+/// Fetch an archive URL or a git ref straight into the session. Archives
+/// are downloaded here, held to the session's egress policy; git runs in
+/// the sandbox like `git/clone`.
+async fn import_project(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+    api_key: Option<Extension<Arc<ApiKey>>>,
+    Json(req): Json<ImportRequest>,
+) -> Result<Json<ImportResult>, (StatusCode, String)> {
+    import::validate(&req).map_err(|e| (StatusCode::BAD_REQUEST, e))?;
+    let (sandbox_root, config, secrets) =
+        git_context(&state, &id, api_key, None, sandbox::DEFAULT_MAX_OUTPUT_BYTES).await?;
+    let dest = import::destination(&req, &config.cwd);
+    let policy = state.sessions.read().await.get(&id).and_then(|s| s.egress.clone());
+    let limit = state.body_limits.uploads as u64;
+    let archive = match import::kind(&req) {
+        import::ImportKind::Archive => Some(import::download(&req.url, policy.as_ref(), limit).await?),
+        import::ImportKind::Git => None,
+    };
+    let credential = git::credential_env(req.credential.as_ref(), &secrets).map_err(|e| (StatusCode::BAD_REQUEST, e))?;
+    let permit = run_permit(&state, Some(&id)).await?;
+    let backend = state.backend.clone();
+    tokio::task::spawn_blocking(move || {
+        let _permit = permit;
+        import::import(&*backend, &sandbox_root, &config, &req, &dest, archive, credential, limit)
+    })
+    .await
+    .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+    .map(Json)
+}
+
+/// What the session changed under `dir`, as a patch or, in a repository, a
+/// bundle, to apply back where its files came from.
+async fn export_patch(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+    api_key: Option<Extension<Arc<ApiKey>>>,
+    Query(query): Query<PatchExportQuery>,
+) -> Result<Response, (StatusCode, String)> {
+    let (sandbox_root, config, _) =
+        git_context(&state, &id, api_key, query.dir.clone(), sandbox::MAX_OUTPUT_BYTES).await?;
+    let permit = run_permit(&state, Some(&id)).await?;
+    let backend = state.backend.clone();
+    let export = tokio::task::spawn_blocking(move || {
+        let _permit = permit;
+        patch::export(&*backend, &sandbox_root, &id, &config, &query)
+    })
+    .await
+    .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))??;
+
+    let internal = |e: String| (StatusCode::INTERNAL_SERVER_ERROR, e);
+    let response = Response::builder()
+        .header("x-patch-source", export.source)
+        .header("x-patch-omitted", export.omitted.to_string());
+    let (content_type, body) = match export.content {
+        ExportContent::Patch(patch) => ("text/x-diff", Body::from(patch)),
+        ExportContent::Bundle(file) => {
+            let len = file.metadata().map_err(|e| internal(e.to_string()))?.len();
+            let stream = download::body(tokio::fs::File::from_std(file), len);
+            ("application/x-git-bundle", Body::from_stream(stream))
+        }
+    };
+    response
+        .header(header::CONTENT_TYPE, content_type)
+        .body(body)
+        .map_err(|e| internal(e.to_string()))
+}
+
+// Code-interpreter kernel
+
+/// Run a cell in the session's Python kernel, which keeps its variables
+/// between calls. The first call starts the kernel with the session's env
+/// and cwd.
+async fn kernel_execute(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+    api_key: Option<Extension<Arc<ApiKey>>>,
+    Json(req): Json<KernelExecuteRequest>,
+) -> Result<Json<KernelExecuteResult>, (StatusCode, String)> {
+    let timeout = kernel_timeout(req.timeout_ms)?;
+    let max_output_bytes = max_output_bytes(req.max_output_bytes)?;
+    let api_key = api_key.map(|Extension(key)| key);
+    state
+        .check_org_quota(api_key.as_deref())
+        .await
+        .map_err(|e| (StatusCode::TOO_MANY_REQUESTS, e))?;
+
+    let (sandbox_root, env, secret_values, cwd, determinism) = {
+        let mut sessions = state.sessions.write().await;
+        let session = sessions
+            .get_mut(&id)
+            .ok_or((StatusCode::NOT_FOUND, "Session not found".to_string()))?;
+        ensure_not_suspended(session)?;
+        session.last_used = Instant::now();
+        (
+            session.sandbox_root.clone(),
+            session.process_env(),
+            session.secret_values(),
+            session.cwd.clone(),
+            Some(session.determinism.clone()),
+        )
+    };
+    sandbox::confine_cwd(&sandbox_root, &cwd).map_err(|e| (StatusCode::BAD_REQUEST, e))?;
+    let config = RunConfig {
+        command: Vec::new(),
+        time_ms: 0,
+        mem_kb: 0,
+        fsize_kb: 0,
+        nofile: 0,
+        nproc: 0,
+        env,
+        cwd,
+        commit_on_success: false,
+        determinism,
+        stdin: None,
+        max_output_bytes,
+        kill_grace_ms: 0,
+        secrets: secret_values,
+    };
+
+    let kernels = state.kernels.clone();
+    let result = tokio::task::spawn_blocking(move || {
+        kernels.execute(&id, &sandbox_root, &config, &req.code, timeout, max_output_bytes)
+    })
+    .await
+    .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+    .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e))?;
+    Ok(Json(result))
+}
+
+/// Stop the session's Python kernel; the next execute starts a fresh one.
+async fn kernel_shutdown(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    if !state.sessions.read().await.contains_key(&id) {
+        return Err((StatusCode::NOT_FOUND, "Session not found".to_string()));
+    }
+    if !state.kernels.shutdown(&id) {
+        return Err((StatusCode::NOT_FOUND, "No kernel running".to_string()));
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
+
+// Background diagnostics handler
+
+#[derive(Deserialize)]
+struct BackgroundStatusQuery {
+    /// Process whose log to read (default: the last one started)
+    #[serde(default)]
+    pid: Option<u32>,
+    /// Byte offset into the log to start from (a previous `next_offset`)
+    #[serde(default)]
+    offset: u64,
+    /// Max log bytes to return
+    #[serde(default)]
+    limit_bytes: Option<u64>,
+}
+
+async fn background_status(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+    Query(query): Query<BackgroundStatusQuery>,
+) -> Result<Json<BackgroundStatusResponse>, (StatusCode, String)> {
+    let (sandbox_root, pids, secret_values) = {
+        let sessions = state.sessions.read().await;
+        let session = sessions
+            .get(&id)
+            .ok_or((StatusCode::NOT_FOUND, "Session not found".to_string()))?;
+        (session.sandbox_root.clone(), session.background_pids.clone(), session.secret_values())
+    };
+    let log_pid = match query.pid {
+        Some(pid) if !pids.contains(&pid) => {
+            return Err((StatusCode::NOT_FOUND, format!("No background process {} in this session", pid)));
+        }
+        Some(pid) => Some(pid),
+        None => pids.last().copied(),
+    };
+
+    let background_logs = state.background_logs.clone();
+    let (pid_statuses, log) = tokio::task::spawn_blocking(move || {
+        let statuses: Vec<BackgroundPidStatus> = pids
+            .iter()
+            .map(|&pid| BackgroundPidStatus {
+                pid,
+                alive: sandbox::is_process_alive(pid),
+            })
+            .collect();
+        let log = log_pid
+            .and_then(|pid| background_logs.read(&sandbox_root, pid, query.offset, query.limit_bytes).ok())
+            .unwrap_or_default();
+        (statuses, log)
+    })
+    .await
+    .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+
+    Ok(Json(BackgroundStatusResponse {
+        pids: pid_statuses,
+        log: secrets::redact(&log.data, &secret_values),
+        log_pid,
+        offset: log.offset,
+        next_offset: log.next_offset,
+        log_start: log.start,
+        log_size: log.size,
+    }))
+}
+
+// Preview proxy handler (HTTP + WebSocket)
+
+async fn preview_proxy(
+    State(state): State<AppState>,
+    Host(host): Host,
+    ws: Option<WebSocketUpgrade>,
+    req: Request<Body>,
+) -> Response {
+    // Parse session ID (or custom slug) from host: {label}[.{region}].{preview_domain}
+    let label = match state.parse_preview_host(&host) {
+        PreviewHost::Label(label) => label,
+        PreviewHost::OtherRegion(region) => {
+            // DNS sent traffic for another region here; don't guess a session
+            return (
+                StatusCode::MISDIRECTED_REQUEST,
+                format!("Preview host is for region {:?}, not this instance", region),
+            )
+                .into_response();
+        }
+        PreviewHost::NotPreview => {
+            // A root-relative request of a page under a path-based preview
+            if let Some(prefix) = preview_path::referer_prefix(req.headers()) {
+                let path_and_query = req.uri().path_and_query().map_or("/", |p| p.as_str());
+                let location = format!("{}{}", prefix.trim_end_matches('/'), path_and_query);
+                return Redirect::temporary(&location).into_response();
+            }
+            return (StatusCode::NOT_FOUND, "Not found").into_response();
+        }
+    };
+    let session_id = state.resolve_preview_label(label).await;
+    let upstream = req.uri().path_and_query().map_or("/", |p| p.as_str()).to_string();
+    let target = PreviewTarget {
+        session_id,
+        of: PreviewOf::Default,
+        prefix: None,
+        upstream,
+    };
+    proxy_preview(state, target, host, ws, req).await
+}
+
+/// `/preview/:label/:port/*path`: a session port's preview on this server's
+/// own host; see [`crate::preview_path`].
+async fn path_preview(
+    State(state): State<AppState>,
+    Host(host): Host,
+    Path(params): Path<HashMap<String, String>>,
+    ws: Option<WebSocketUpgrade>,
+    req: Request<Body>,
+) -> Response {
+    // On a preview domain the path is the app's own
+    if !matches!(state.parse_preview_host(&host), PreviewHost::NotPreview) {
+        return preview_proxy(State(state), Host(host), ws, req).await;
+    }
+    let (Some(label), Some(port)) = (params.get("label"), params.get("port")) else {
+        return (StatusCode::NOT_FOUND, "Not found").into_response();
+    };
+    let of = match port.parse() {
+        Ok(port) => PreviewOf::Port(port),
+        Err(_) if port == preview_path::STATIC_SITE => PreviewOf::StaticSite,
+        Err(_) => return (StatusCode::NOT_FOUND, "Not found").into_response(),
+    };
+    let prefix = preview_path::prefix(label, port);
+    // The rest of the path as sent, not percent-decoded
+    let Some(path) = req.uri().path().splitn(5, '/').nth(4) else {
+        // Without the trailing slash, relative URLs would resolve above the app
+        return Redirect::permanent(&prefix).into_response();
+    };
+    let query = req.uri().query().map(|q| format!("?{}", q)).unwrap_or_default();
+    let target = PreviewTarget {
+        session_id: state.resolve_preview_label(label).await,
+        of,
+        upstream: format!("/{}{}", path, query),
+        prefix: Some(prefix),
+    };
+    proxy_preview(state, target, host, ws, req).await
+}
+
+/// Where a preview request goes.
+struct PreviewTarget {
+    session_id: String,
+    of: PreviewOf,
+    /// Path prefix of a path-based preview
+    prefix: Option<String>,
+    /// Path and query to request from the app
+    upstream: String,
+}
+
+/// What of a session a preview shows.
+enum PreviewOf {
+    /// Its static site if it has one, else its first port
+    Default,
+    Port(u16),
+    StaticSite,
+}
+
+/// What answers a preview request: an app on a port, or a static site.
+enum PreviewBackend {
+    Port(u16),
+    StaticSite { sandbox_root: PathBuf, site: StaticSite },
+}
+
+/// The port a preview URL without one goes to: the session's first
+/// registered port, or 5173.
+fn default_preview_port(session: &Session) -> u16 {
+    session.ports.first().copied().unwrap_or(DEFAULT_BACKGROUND_PORT)
+}
+
+/// Proxy a preview request, publishing it to the event stream once the
+/// response starts if anyone is subscribed.
+async fn proxy_preview(
+    state: AppState,
+    target: PreviewTarget,
+    host: String,
+    ws: Option<WebSocketUpgrade>,
+    req: Request<Body>,
+) -> Response {
+    if !state.events.is_observed() {
+        return forward_preview(state, target, host, ws, req).await;
+    }
+    let started = Instant::now();
+    let session_id = target.session_id.clone();
+    let (port, org) = {
+        let sessions = state.sessions.read().await;
+        let session = sessions.get(&session_id);
+        let port = match &target.of {
+            PreviewOf::Port(port) => Some(*port),
+            PreviewOf::StaticSite => None,
+            PreviewOf::Default => session
+                .filter(|s| s.static_site.is_none())
+                .map(default_preview_port),
+        };
+        (port, session.and_then(|s| s.org_id.clone()))
+    };
+    let method = req.method().to_string();
+    let path = req.uri().path().to_string();
+    let websocket = ws.is_some();
+    let response = forward_preview(state.clone(), target, host, ws, req).await;
+    let kind = ServerEventKind::ProxyRequest {
+        port,
+        method,
+        path,
+        status: response.status().as_u16(),
+        duration_ms: started.elapsed().as_millis() as u64,
+        websocket,
+    };
+    state.publish_event(Some(&session_id), org.as_deref(), kind);
+    response
+}
+
+/// Proxy a preview request (HTTP or a WebSocket upgrade) to the app, after
+/// checking the session's preview auth.
+async fn forward_preview(
+    state: AppState,
+    target: PreviewTarget,
+    host: String,
+    ws: Option<WebSocketUpgrade>,
+    req: Request<Body>,
+) -> Response {
+    let PreviewTarget {
+        session_id,
+        of,
+        prefix,
+        upstream,
+    } = target;
+
+    // Look up session and find the port
+    let (backend, preview_auth) = {
+        let mut sessions = state.sessions.write().await;
+        let session = match sessions.get_mut(&session_id) {
+            Some(s) => s,
+            None => {
+                return (StatusCode::NOT_FOUND, format!("Session {} not found", session_id))
+                    .into_response();
+            }
+        };
+        if session.status.is_suspended() {
+            return (StatusCode::SERVICE_UNAVAILABLE, format!("Session is {}", session.status)).into_response();
+        }
+        let backend = match (of, &session.static_site) {
+            // Only the session's own ports: anything else on the host is not its to show
+            (PreviewOf::Port(port), _) if !session.ports.contains(&port) => {
+                return (StatusCode::NOT_FOUND, format!("Session {} has no port {}", session_id, port))
+                    .into_response();
+            }
+            (PreviewOf::Port(port), _) => PreviewBackend::Port(port),
+            (PreviewOf::Default | PreviewOf::StaticSite, Some(site)) => PreviewBackend::StaticSite {
+                sandbox_root: session.sandbox_root.clone(),
+                site: site.clone(),
+            },
+            (PreviewOf::StaticSite, None) => {
+                return (StatusCode::NOT_FOUND, format!("Session {} has no static site", session_id))
+                    .into_response();
+            }
+            (PreviewOf::Default, None) => PreviewBackend::Port(default_preview_port(session)),
+        };
+        session.last_used = Instant::now();
+        (backend, session.preview_auth.clone())
+    };
+
+    // Enforce per-session preview auth before touching the sandbox
+    if let Some(ref auth) = preview_auth {
+        let outcome = preview_auth::check(
+            auth,
+            &session_id,
+            &state.preview_cookie_key,
+            req.headers(),
+            req.uri().path(),
+            req.uri().query(),
+            prefix.as_deref().unwrap_or("/"),
+        );
+        if let PreviewAuthOutcome::Respond(resp) = outcome {
+            return resp;
+        }
+    }
+    let port = match backend {
+        PreviewBackend::Port(port) => port,
+        PreviewBackend::StaticSite { sandbox_root, site } => {
+            return serve_static_site(sandbox_root, site, prefix.as_deref(), &upstream, req.method(), req.headers())
+                .await
+                .unwrap_or_else(IntoResponse::into_response);
+        }
+    };
+    // Credentials for the preview gate are not meant for the sandboxed app
+    let strip_authorization = matches!(
+        preview_auth,
+        Some(PreviewAuth::Bearer { .. }) | Some(PreviewAuth::Basic { .. })
+    );
+    let client = req.extensions().get::<ConnectInfo<SocketAddr>>().map(|ConnectInfo(addr)| addr.ip());
+    let tls = req.extensions().get::<TlsTerminated>().is_some();
+    let mut forwarded = forwarded_headers(req.headers(), client, tls, &host, strip_authorization);
+    if let Some(ref prefix) = prefix {
+        if let Ok(value) = HeaderValue::from_str(prefix.trim_end_matches('/')) {
+            forwarded.insert("x-forwarded-prefix", value);
+        }
+        // Pages are rewritten, so they must come uncompressed
+        forwarded.remove(header::ACCEPT_ENCODING);
+    }
+
+    // Handle WebSocket upgrade
+    if let Some(ws) = ws {
+        let ws_url = format!("ws://127.0.0.1:{}{}", port, upstream);
+        info!("WebSocket proxy: {} -> {}", host, ws_url);
+        let connection = state.activity.connection(&session_id);
+        // Connect to the app first, so the client's upgrade can be answered
+        // with the subprotocol the app picked, or refused if it isn't up
+        let mut backend_req = match ws_url.as_str().into_client_request() {
+            Ok(r) => r,
+            Err(e) => return (StatusCode::BAD_REQUEST, format!("Invalid WebSocket URL: {}", e)).into_response(),
+        };
+        for (name, value) in &forwarded {
+            if !WS_HANDSHAKE_HEADERS.contains(&name.as_str()) {
+                backend_req.headers_mut().append(name.clone(), value.clone());
+            }
+        }
+        let (backend_ws, backend_resp) = match tokio_tungstenite::connect_async(backend_req).await {
+            Ok(conn) => conn,
+            Err(e) => {
+                info!("WebSocket backend connection failed: {} -> {}", ws_url, e);
+                return (
+                    StatusCode::BAD_GATEWAY,
+                    format!("Could not open a WebSocket to the sandbox web server on port {}: {}", port, e),
+                )
+                    .into_response();
+            }
+        };
+        let ws = match backend_resp
+            .headers()
+            .get(header::SEC_WEBSOCKET_PROTOCOL)
+            .and_then(|v| v.to_str().ok())
+        {
+            Some(protocol) => ws.protocols([protocol.trim().to_string()]),
+            None => ws,
+        };
+        return ws.on_upgrade(move |socket| async move {
+            ws_proxy(socket, backend_ws, ws_url).await;
+            drop(connection);
+        });
+    }
+
+    // Regular HTTP proxy
+    let target_url = format!("http://127.0.0.1:{}{}", port, upstream);
+
+    info!("Preview proxy: {} -> {}", host, target_url);
+    let connection = state.activity.connection(&session_id);
+
+    // Only bound the connect phase: long-polling endpoints legitimately hold a
+    // request open for minutes, and streaming responses never "finish".
+    let mut builder = reqwest::Client::builder().connect_timeout(Duration::from_secs(PROXY_CONNECT_TIMEOUT_SECS));
+    if prefix.is_some() {
+        // The browser must follow a redirect itself, to the prefixed location
+        builder = builder.redirect(reqwest::redirect::Policy::none());
+    }
+    let client = match builder.build() {
+        Ok(c) => c,
+        Err(e) => {
+            return (StatusCode::INTERNAL_SERVER_ERROR, format!("Proxy client error: {}", e))
+                .into_response();
+        }
+    };
+    let method = match req.method().as_str() {
+        "GET" => reqwest::Method::GET,
+        "POST" => reqwest::Method::POST,
+        "PUT" => reqwest::Method::PUT,
+        "DELETE" => reqwest::Method::DELETE,
+        "PATCH" => reqwest::Method::PATCH,
+        "HEAD" => reqwest::Method::HEAD,
+        "OPTIONS" => reqwest::Method::OPTIONS,
+        _ => reqwest::Method::GET,
+    };
+
+    let proxy_req = client.request(method, &target_url).headers(forwarded);
+
+    // Stream the body through as it arrives, however big, unless a preview
+    // limit is set. Requests that have none (most GETs) are sent without one.
+    let has_body = req.headers().contains_key(header::TRANSFER_ENCODING)
+        || req
+            .headers()
+            .get(header::CONTENT_LENGTH)
+            .is_some_and(|v| v.as_bytes() != b"0");
+    let req = match state.body_limits.preview {
+        Some(limit) => {
+            if let Some(response) = body_limit::check_length(req.headers(), limit, "previews") {
+                return response;
+            }
+            body_limit::limit_body(req, limit)
+        }
+        None => req,
+    };
+    let proxy_req = if has_body {
+        proxy_req.body(reqwest::Body::wrap_stream(req.into_body().into_data_stream()))
+    } else {
+        proxy_req
+    };
+
+    // Execute the proxied request
+    match proxy_req.send().await {
+        Ok(proxy_resp) => {
+            let status = StatusCode::from_u16(proxy_resp.status().as_u16())
+                .unwrap_or(StatusCode::BAD_GATEWAY);
+            let mut response = Response::builder().status(status);
+
+            // Pages of a path-based preview, to point at its prefix
+            let rewrite_prefix = prefix.as_deref().filter(|_| is_html_response(proxy_resp.headers()));
+
+            // Forward response headers, each value on its own (several
+            // Set-Cookie headers can't be joined into one)
+            for (name, value) in proxy_resp.headers() {
+                if is_hop_by_hop(name, proxy_resp.headers())
+                    || (rewrite_prefix.is_some() && name == reqwest::header::CONTENT_LENGTH)
+                {
+                    continue;
+                }
+                let location = prefix
+                    .as_deref()
+                    .filter(|_| name == reqwest::header::LOCATION)
+                    .and_then(|prefix| preview_path::rewrite_location(value.to_str().ok()?, prefix));
+                match location {
+                    Some(location) => response = response.header(name, location),
+                    None => response = response.header(name, value),
+                }
+            }
+
+            // Relay streaming responses (SSE, HMR fallbacks) chunk by chunk;
+            // buffering them would hold every event until the upstream closes.
+            if is_streaming_response(proxy_resp.headers()) {
+                info!("Preview proxy streaming: {} -> {}", host, target_url);
+                if !proxy_resp.headers().contains_key(reqwest::header::CACHE_CONTROL) {
+                    response = response.header(header::CACHE_CONTROL, "no-cache");
+                }
+                // Tell any fronting nginx/fly proxy not to buffer either
+                response = response.header("x-accel-buffering", "no");
+                // The connection is open for as long as the stream is
+                let stream = proxy_resp.bytes_stream().map(move |chunk| {
+                    let _connection = &connection;
+                    chunk
+                });
+                return response
+                    .body(Body::from_stream(stream))
+                    .unwrap_or_else(|_| (StatusCode::BAD_GATEWAY, "Proxy error").into_response());
+            }
+
+            match proxy_resp.bytes().await {
+                Ok(body) => {
+                    let body = match rewrite_prefix {
+                        Some(prefix) => {
+                            Body::from(preview_path::rewrite_html(&String::from_utf8_lossy(&body), prefix))
+                        }
+                        None => Body::from(body),
+                    };
+                    response
+                        .body(body)
+                        .unwrap_or_else(|_| (StatusCode::BAD_GATEWAY, "Proxy error").into_response())
+                }
+                Err(e) => (StatusCode::BAD_GATEWAY, format!("Failed to read response: {}", e))
+                    .into_response(),
+            }
+        }
+        Err(e) => {
+            info!("Preview proxy error: {}", e);
+            (
+                StatusCode::BAD_GATEWAY,
+                format!("Could not connect to sandbox web server on port {}: {}", port, e),
+            )
+                .into_response()
+        }
+    }
+}
+
+/// Answer a preview request from a session's static site. Pages of a
+/// path-based preview are rewritten as an app's would be.
+async fn serve_static_site(
+    sandbox_root: PathBuf,
+    site: StaticSite,
+    prefix: Option<&str>,
+    upstream: &str,
+    method: &Method,
+    headers: &HeaderMap,
+) -> Result<Response, (StatusCode, String)> {
+    if method != Method::GET && method != Method::HEAD {
+        return Ok((
+            StatusCode::METHOD_NOT_ALLOWED,
+            [(header::ALLOW, "GET, HEAD")],
+            "A static site only answers GET and HEAD",
+        )
+            .into_response());
+    }
+    let (path, query) = match upstream.split_once('?') {
+        Some((path, query)) => (path.to_string(), format!("?{}", query)),
+        None => (upstream.to_string(), String::new()),
+    };
+    let page = headers
+        .get(header::ACCEPT)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|accept| accept.contains("text/html"));
+    let lookup_path = path.clone();
+    let found = tokio::task::spawn_blocking(move || static_site::lookup(&sandbox_root, &site, &lookup_path, page))
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e))?;
+    let (file, file_path) = match found {
+        Lookup::File { file, path } => (file, path),
+        Lookup::AddSlash => {
+            let root = prefix.map_or("", |prefix| prefix.trim_end_matches('/'));
+            return Ok(Redirect::permanent(&format!("{}{}/{}", root, path, query)).into_response());
+        }
+        Lookup::NotFound => return Err((StatusCode::NOT_FOUND, format!("{} not found", path))),
+    };
+
+    let read_error = |e: std::io::Error| (StatusCode::INTERNAL_SERVER_ERROR, format!("read file: {}", e));
+    let content_type = download::content_type(&file_path);
+    match prefix.filter(|_| content_type.starts_with("text/html")) {
+        Some(prefix) => {
+            let mut html = Vec::new();
+            tokio::fs::File::from_std(file).read_to_end(&mut html).await.map_err(read_error)?;
+            let html = preview_path::rewrite_html(&String::from_utf8_lossy(&html), prefix);
+            Ok(([(header::CONTENT_TYPE, content_type), (header::CACHE_CONTROL, "no-cache")], html).into_response())
+        }
+        None => {
+            let meta = file.metadata().map_err(read_error)?;
+            file_response(file, &meta, content_type, headers).await
+        }
+    }
+}
+
+/// Whether an upstream response is an HTML page.
+fn is_html_response(headers: &reqwest::header::HeaderMap) -> bool {
+    headers
+        .get(reqwest::header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.split(';').next())
+        .is_some_and(|mime| mime.trim().eq_ignore_ascii_case("text/html"))
+}
+
+/// Whether an upstream response must be passed through without buffering.
+fn is_streaming_response(headers: &reqwest::header::HeaderMap) -> bool {
+    let content_type = headers
+        .get(reqwest::header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or("");
+    // Ignore parameters such as "; charset=utf-8"
+    let mime = content_type.split(';').next().unwrap_or("").trim().to_ascii_lowercase();
+    STREAMING_CONTENT_TYPES.contains(&mime.as_str())
+}
+
+/// Request headers for a preview app: the client's, without hop-by-hop ones,
+/// `Host`, or the preview gate's credentials, plus `X-Forwarded-For`,
+/// `X-Forwarded-Proto` and `X-Forwarded-Host`. A fronting proxy's `-Proto`
+/// and `-Host` are kept, as they describe what the client asked for; the
+/// client's address is appended to its `-For`.
+fn forwarded_headers(
+    headers: &HeaderMap,
+    client: Option<std::net::IpAddr>,
+    tls: bool,
+    host: &str,
+    strip_authorization: bool,
+) -> HeaderMap {
+    let mut forwarded = HeaderMap::new();
+    for (name, value) in headers {
+        if name == header::HOST
+            || (name == header::AUTHORIZATION && strip_authorization)
+            || is_hop_by_hop(name, headers)
+        {
+            continue;
+        }
+        forwarded.append(name.clone(), value.clone());
+    }
+    if let Some(client) = client {
+        // One comma-separated value, which is all some frameworks read
+        let mut chain: Vec<&str> = headers
+            .get_all("x-forwarded-for")
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .collect();
+        let client = client.to_string();
+        chain.push(&client);
+        if let Ok(value) = HeaderValue::from_str(&chain.join(", ")) {
+            forwarded.insert("x-forwarded-for", value);
+        }
+    }
+    if !forwarded.contains_key("x-forwarded-proto") {
+        forwarded.insert("x-forwarded-proto", HeaderValue::from_static(if tls { "https" } else { "http" }));
+    }
+    if !forwarded.contains_key("x-forwarded-host") {
+        if let Ok(value) = HeaderValue::from_str(host) {
+            forwarded.insert("x-forwarded-host", value);
+        }
+    }
+    forwarded
+}
+
+/// Whether a header of this message only concerns the connection it came
+/// over: a standard hop-by-hop header or one its `Connection` header names.
+fn is_hop_by_hop(name: &HeaderName, headers: &HeaderMap) -> bool {
+    HOP_BY_HOP_HEADERS.contains(&name.as_str())
+        || headers
+            .get_all(header::CONNECTION)
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .flat_map(|v| v.split(','))
+            .any(|token| token.trim().eq_ignore_ascii_case(name.as_str()))
+}
+
+/// Tunnel a WebSocket to a TCP port that a process of the session listens
+/// on (Postgres, a debugger, ...). Binary frames carry the stream both ways.
+async fn tcp_forward(
+    State(state): State<AppState>,
+    Path((id, port)): Path<(String, u16)>,
+    ws: WebSocketUpgrade,
+) -> Result<Response, (StatusCode, String)> {
+    let sandbox_root = {
+        let mut sessions = state.sessions.write().await;
+        let session = sessions
+            .get_mut(&id)
+            .ok_or((StatusCode::NOT_FOUND, "Session not found".to_string()))?;
+        if session.status.is_suspended() {
+            return Err((StatusCode::SERVICE_UNAVAILABLE, format!("Session is {}", session.status)));
+        }
+        session.last_used = Instant::now();
+        session.sandbox_root.clone()
+    };
+    let stream = tokio::task::spawn_blocking(move || {
+        if !sandbox::session_listens_on(&sandbox_root, port) {
+            return Err((
+                StatusCode::BAD_GATEWAY,
+                format!("Nothing in the session is listening on port {}", port),
+            ));
+        }
+        let timeout = Duration::from_secs(PROXY_CONNECT_TIMEOUT_SECS);
+        let stream = sandbox::connect_to_session(&sandbox_root, port, timeout)
+            .and_then(|stream| stream.set_nonblocking(true).map(|()| stream))
+            .map_err(|e| (StatusCode::BAD_GATEWAY, format!("Could not connect to port {}: {}", port, e)))?;
+        Ok(stream)
+    })
+    .await
+    .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))??;
+    let stream =
+        tokio::net::TcpStream::from_std(stream).map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    info!("TCP tunnel opened: session {} port {}", id, port);
+    Ok(ws.on_upgrade(move |socket| tcp_tunnel(state, socket, stream, id, port)))
+}
+
+/// Relay between a WebSocket and a TCP stream until either side closes or
+/// the session is deleted. An open tunnel keeps its session from going idle.
+async fn tcp_tunnel(
+    state: AppState,
+    socket: WebSocket,
+    stream: tokio::net::TcpStream,
+    session_id: String,
+    port: u16,
+) {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let _connection = state.activity.connection(&session_id);
+    let (mut ws_tx, mut ws_rx) = socket.split();
+    let (mut tcp_rx, mut tcp_tx) = stream.into_split();
+
+    // Relay: client -> TCP; text frames count as bytes too
+    let c2t = async move {
+        while let Some(Ok(msg)) = ws_rx.next().await {
+            let data = match msg {
+                AxumWsMsg::Binary(b) => b,
+                AxumWsMsg::Text(t) => t.into_bytes(),
+                AxumWsMsg::Close(_) => break,
+                _ => continue,
+            };
+            if tcp_tx.write_all(&data).await.is_err() {
+                return;
+            }
+        }
+        let _ = tcp_tx.shutdown().await;
+    };
+
+    // Relay: TCP -> client
+    let t2c = async move {
+        let mut buf = vec![0u8; 16 * 1024];
+        loop {
+            match tcp_rx.read(&mut buf).await {
+                Ok(0) | Err(_) => break,
+                Ok(n) => {
+                    if ws_tx.send(AxumWsMsg::Binary(buf[..n].to_vec())).await.is_err() {
+                        return;
+                    }
+                }
+            }
+        }
+        let _ = ws_tx.send(AxumWsMsg::Close(None)).await;
+    };
+
+    let session_gone = async {
+        let mut ticks = interval(Duration::from_secs(30));
+        loop {
+            ticks.tick().await;
+            if !state.sessions.read().await.contains_key(&session_id) {
+                return;
+            }
+        }
+    };
+
+    tokio::select! {
+        _ = c2t => {},
+        _ = t2c => {},
+        _ = session_gone => {},
+    }
+
+    info!("TCP tunnel closed: session {} port {}", session_id, port);
+}
+
+/// Bidirectional WebSocket proxy between client and backend (e.g., Vite HMR).
+async fn ws_proxy(
+    client_ws: WebSocket,
+    backend_ws: tokio_tungstenite::WebSocketStream<tokio_tungstenite::MaybeTlsStream<tokio::net::TcpStream>>,
+    backend_url: String,
+) {
+    info!("WebSocket proxy connected: {}", backend_url);
+
+    let (mut client_tx, mut client_rx) = client_ws.split();
+    let (mut backend_tx, mut backend_rx) = backend_ws.split();
+
+    // Relay: client -> backend
+    let c2b = async move {
+        while let Some(msg) = client_rx.next().await {
+            match msg {
+                Ok(axum_msg) => {
+                    let tung_msg = match axum_msg {
+                        AxumWsMsg::Text(t) => TungsteniteMsg::Text(t.to_string()),
+                        AxumWsMsg::Binary(b) => TungsteniteMsg::Binary(b.to_vec()),
+                        AxumWsMsg::Ping(p) => TungsteniteMsg::Ping(p.to_vec()),
+                        AxumWsMsg::Pong(p) => TungsteniteMsg::Pong(p.to_vec()),
+                        AxumWsMsg::Close(_) => return,
+                    };
+                    if backend_tx.send(tung_msg).await.is_err() {
+                        return;
+                    }
+                }
+                Err(_) => return,
+            }
+        }
+    };
+
+    // Relay: backend -> client
+    let b2c = async move {
+        while let Some(msg) = backend_rx.next().await {
+            match msg {
+                Ok(tung_msg) => {
+                    let axum_msg = match tung_msg {
+                        TungsteniteMsg::Text(t) => AxumWsMsg::Text(t.to_string()),
+                        TungsteniteMsg::Binary(b) => AxumWsMsg::Binary(b),
+                        TungsteniteMsg::Ping(p) => AxumWsMsg::Ping(p),
+                        TungsteniteMsg::Pong(p) => AxumWsMsg::Pong(p),
+                        TungsteniteMsg::Close(_) => return,
+                        _ => continue,
+                    };
+                    if client_tx.send(axum_msg).await.is_err() {
+                        return;
+                    }
+                }
+                Err(_) => return,
+            }
+        }
+    };
+
+    // Run both directions concurrently; when one ends, drop the other
+    tokio::select! {
+        _ = c2b => {},
+        _ = b2c => {},
+    }
+
+    info!("WebSocket proxy closed: {}", backend_url);
+}
@@ -0,0 +1,207 @@
+//! `Idempotency-Key` support for requests that start things.
+//!
+//! A client retrying a `POST /sessions`, a run or a background start after a
+//! dropped connection can't tell whether the first attempt went through, and
+//! retrying blindly creates a second session or a second dev server. With an
+//! `Idempotency-Key` header the first response is kept for
+//! [`IdempotencyCache::window`] and a retry with the same key, path and body
+//! gets it back, marked with `Idempotent-Replayed: true`, without running the
+//! handler again. A retry while the first attempt is still going gets `409`.
+//!
+//! Entries are kept per caller (API key), so one key's responses never
+//! reach another. Server errors and `429`s aren't kept: nothing happened, so
+//! the retry should run.
+
+use crate::auth::ApiKey;
+use crate::body_limit;
+use axum::{
+    body::{to_bytes, Body, Bytes},
+    extract::{Extension, Request},
+    http::{HeaderMap, HeaderValue, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tracing::Instrument;
+
+pub const IDEMPOTENCY_KEY_HEADER: &str = "idempotency-key";
+pub const REPLAYED_HEADER: &str = "idempotent-replayed";
+
+/// Longest `Idempotency-Key` accepted.
+const MAX_KEY_LEN: usize = 255;
+
+/// How long responses are kept by default.
+pub const DEFAULT_WINDOW: Duration = Duration::from_secs(3600);
+
+/// What a request is recognized by: caller, key, path and a hash of the body.
+#[derive(Clone, PartialEq, Eq, Hash)]
+struct CacheKey {
+    caller: String,
+    key: String,
+    path: String,
+    body_sha256: [u8; 32],
+}
+
+enum Entry {
+    /// The first attempt is still running
+    InFlight,
+    Done {
+        status: StatusCode,
+        headers: HeaderMap,
+        body: Bytes,
+        expires: Instant,
+    },
+}
+
+/// Responses to requests sent with an `Idempotency-Key`.
+pub struct IdempotencyCache {
+    window: Duration,
+    entries: Mutex<HashMap<CacheKey, Entry>>,
+}
+
+impl Default for IdempotencyCache {
+    fn default() -> Self {
+        Self::new(DEFAULT_WINDOW)
+    }
+}
+
+impl IdempotencyCache {
+    /// Keep responses for `window`; zero turns the header off.
+    pub fn new(window: Duration) -> Self {
+        Self {
+            window,
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn window(&self) -> Duration {
+        self.window
+    }
+
+    /// Claim `key` for a first attempt, or return what a claimed one holds:
+    /// `Some(None)` while it runs, `Some(Some(response))` once it finished.
+    fn claim(&self, key: &CacheKey) -> Option<Option<Response>> {
+        let mut entries = self.entries.lock().unwrap_or_else(|e| e.into_inner());
+        let now = Instant::now();
+        entries.retain(|_, entry| !matches!(entry, Entry::Done { expires, .. } if *expires <= now));
+        match entries.get(key) {
+            Some(Entry::InFlight) => Some(None),
+            Some(Entry::Done { status, headers, body, .. }) => {
+                let mut response = Response::new(Body::from(body.clone()));
+                *response.status_mut() = *status;
+                *response.headers_mut() = headers.clone();
+                response
+                    .headers_mut()
+                    .insert(REPLAYED_HEADER, HeaderValue::from_static("true"));
+                Some(Some(response))
+            }
+            None => {
+                entries.insert(key.clone(), Entry::InFlight);
+                None
+            }
+        }
+    }
+
+    /// Keep the response to a claimed key, or give the key up if it
+    /// shouldn't be replayed.
+    fn finish(&self, key: CacheKey, response: Option<(StatusCode, HeaderMap, Bytes)>) {
+        let mut entries = self.entries.lock().unwrap_or_else(|e| e.into_inner());
+        match response {
+            Some((status, headers, body)) => {
+                let expires = Instant::now() + self.window;
+                entries.insert(key, Entry::Done { status, headers, body, expires });
+            }
+            None => {
+                entries.remove(&key);
+            }
+        }
+    }
+}
+
+/// Axum layer for a single route: answer a retried request that carries an
+/// `Idempotency-Key` with the first attempt's response. Runs after the
+/// API key check, so entries are kept per caller.
+pub async fn replay(
+    cache: Option<Extension<Arc<IdempotencyCache>>>,
+    api_key: Option<Extension<Arc<ApiKey>>>,
+    req: Request,
+    next: Next,
+) -> Response {
+    let Some(Extension(cache)) = cache.filter(|Extension(cache)| !cache.window.is_zero()) else {
+        return next.run(req).await;
+    };
+    let Some(key) = req.headers().get(IDEMPOTENCY_KEY_HEADER) else {
+        return next.run(req).await;
+    };
+    let key = match key.to_str() {
+        Ok(key) if !key.is_empty() && key.len() <= MAX_KEY_LEN => key.to_string(),
+        _ => {
+            let message = format!("Idempotency-Key must be 1 to {} visible ASCII characters", MAX_KEY_LEN);
+            return (StatusCode::BAD_REQUEST, message).into_response();
+        }
+    };
+
+    let (parts, body) = req.into_parts();
+    // Already held to the route's limit by body_limit::enforce
+    let body = match to_bytes(body, usize::MAX).await {
+        Ok(body) => body,
+        Err(e) if body_limit::is_too_large(&e) => {
+            return (StatusCode::PAYLOAD_TOO_LARGE, "Request body is over the limit for API calls").into_response();
+        }
+        Err(e) => return (StatusCode::BAD_REQUEST, format!("Failed to read body: {}", e)).into_response(),
+    };
+    let cache_key = CacheKey {
+        caller: api_key.map(|Extension(key)| key.key.clone()).unwrap_or_default(),
+        key,
+        path: parts.uri.path().to_string(),
+        body_sha256: Sha256::digest(&body).into(),
+    };
+    match cache.claim(&cache_key) {
+        Some(Some(response)) => return response,
+        Some(None) => {
+            return (
+                StatusCode::CONFLICT,
+                "A request with this Idempotency-Key is still in progress",
+            )
+                .into_response();
+        }
+        None => {}
+    }
+
+    // Finished even if the client goes away, so its retry finds the response
+    // instead of a half-done first attempt
+    let task = {
+        let cache = cache.clone();
+        let cache_key = cache_key.clone();
+        tokio::spawn(async move {
+            let response = next.run(Request::from_parts(parts, Body::from(body))).await;
+            let status = response.status();
+            if status.is_server_error() || status == StatusCode::TOO_MANY_REQUESTS {
+                cache.finish(cache_key, None);
+                return response;
+            }
+            let (parts, body) = response.into_parts();
+            match to_bytes(body, usize::MAX).await {
+                Ok(body) => {
+                    cache.finish(cache_key, Some((parts.status, parts.headers.clone(), body.clone())));
+                    Response::from_parts(parts, Body::from(body))
+                }
+                Err(e) => {
+                    cache.finish(cache_key, None);
+                    (StatusCode::INTERNAL_SERVER_ERROR, format!("read response: {}", e)).into_response()
+                }
+            }
+        }
+        .in_current_span())
+    };
+    match task.await {
+        Ok(response) => response,
+        Err(e) => {
+            cache.finish(cache_key, None);
+            (StatusCode::INTERNAL_SERVER_ERROR, format!("request failed: {}", e)).into_response()
+        }
+    }
+}
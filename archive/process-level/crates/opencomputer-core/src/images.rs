@@ -0,0 +1,677 @@
+//! OCI images as session roots.
+//!
+//! A session created with `image` (e.g. `ghcr.io/org/devimage:1.2`) is
+//! layered on that image's filesystem instead of the host's `/bin`, `/lib`,
+//! `/usr` and `/etc`, and takes the image's `Env` and `WorkingDir` as its
+//! defaults. The image is pulled over the registry HTTP API (the one Docker
+//! and OCI registries share), its layers flattened into one directory.
+//!
+//! Pulled images are cached under `{dir}/sha256-{hex}`, keyed by the digest
+//! of the manifest (or multi-platform index) the reference resolved to, so a
+//! tag is looked up on every create but downloaded once, and a reference by
+//! digest that is cached never reaches the registry. Sandboxes bind mount
+//! the cached directory, so removing it doesn't affect live sessions.
+//!
+//! Registries are reached over HTTPS, or plain HTTP for `localhost` and
+//! registries configured as insecure; anonymously unless credentials are
+//! configured for them. Layers may be tar or gzipped tar.
+
+use crate::progress::{Progress, ProgressReader};
+use axum::http::StatusCode;
+use base64::Engine;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fs;
+use std::io::Read;
+use std::path::{Component, Path, PathBuf};
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+
+pub const DEFAULT_IMAGES_DIR: &str = "/var/lib/opensandbox/images";
+
+/// Registry `docker.io` references resolve to, and its API host.
+const DOCKER_HUB: &str = "docker.io";
+const DOCKER_HUB_API: &str = "registry-1.docker.io";
+
+const OCI_INDEX: &str = "application/vnd.oci.image.index.v1+json";
+const OCI_MANIFEST: &str = "application/vnd.oci.image.manifest.v1+json";
+const DOCKER_MANIFEST_LIST: &str = "application/vnd.docker.distribution.manifest.list.v2+json";
+const DOCKER_MANIFEST: &str = "application/vnd.docker.distribution.manifest.v2+json";
+
+/// Largest manifest or image config accepted.
+const MAX_METADATA_BYTES: usize = 4 * 1024 * 1024;
+
+/// Longest a registry may take to accept a connection.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(30);
+
+type PullError = (StatusCode, String);
+
+/// An image reference split into its parts. `docker.io` images without an
+/// organization are under `library/`, as with `docker pull`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ImageRef {
+    pub registry: String,
+    pub repository: String,
+    /// Tag, or digest (`sha256:...`)
+    pub reference: String,
+}
+
+impl ImageRef {
+    pub fn parse(image: &str) -> Result<Self, String> {
+        let invalid = |why: &str| format!("invalid image reference {:?}: {}", image, why);
+        let (name, digest) = match image.split_once('@') {
+            Some((name, digest)) => (name, Some(digest)),
+            None => (image, None),
+        };
+        let (name, tag) = match name.rsplit_once(':') {
+            Some((repo, tag)) if !tag.contains('/') => (repo, Some(tag)),
+            _ => (name, None),
+        };
+        let (registry, repository) = match name.split_once('/') {
+            Some((host, rest)) if host.contains(['.', ':']) || host == "localhost" => (host, rest.to_string()),
+            _ => (DOCKER_HUB, name.to_string()),
+        };
+        let repository = if registry == DOCKER_HUB && !repository.contains('/') {
+            format!("library/{}", repository)
+        } else {
+            repository
+        };
+        if repository.is_empty()
+            || repository.split('/').any(|part| part.is_empty())
+            || !repository
+                .chars()
+                .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || matches!(c, '.' | '_' | '-' | '/'))
+        {
+            return Err(invalid("the repository may only contain lowercase letters, digits, '.', '_', '-' and '/'"));
+        }
+        let reference = match (tag, digest) {
+            (_, Some(digest)) => {
+                validate_digest(digest).map_err(|e| invalid(&e))?;
+                digest.to_string()
+            }
+            (Some(tag), None) => {
+                if tag.is_empty()
+                    || tag.len() > 128
+                    || !tag.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | '-'))
+                {
+                    return Err(invalid("tags are 1-128 letters, digits, '.', '_' or '-'"));
+                }
+                tag.to_string()
+            }
+            (None, None) => "latest".to_string(),
+        };
+        Ok(Self {
+            registry: registry.to_string(),
+            repository,
+            reference,
+        })
+    }
+
+    fn digest(&self) -> Option<&str> {
+        self.reference.starts_with("sha256:").then_some(self.reference.as_str())
+    }
+}
+
+impl std::fmt::Display for ImageRef {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let separator = if self.digest().is_some() { '@' } else { ':' };
+        write!(f, "{}/{}{}{}", self.registry, self.repository, separator, self.reference)
+    }
+}
+
+/// A pulled image, unpacked in the cache.
+#[derive(Debug, Clone)]
+pub struct Image {
+    /// Digest of the manifest (or index) the reference resolved to
+    pub digest: String,
+    /// The image's root filesystem
+    pub root: PathBuf,
+    /// Env defaults from the image config
+    pub env: HashMap<String, String>,
+    /// Working directory from the image config, if any
+    pub working_dir: Option<String>,
+}
+
+/// Login for a registry, sent as basic auth or traded for a bearer token.
+#[derive(Debug, Clone)]
+pub struct RegistryCredentials {
+    pub username: String,
+    pub password: String,
+}
+
+pub struct ImageStore {
+    dir: PathBuf,
+    client: reqwest::Client,
+    /// Registries (`host[:port]`) reached over plain HTTP
+    insecure: Vec<String>,
+    credentials: HashMap<String, RegistryCredentials>,
+}
+
+impl ImageStore {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self {
+            dir: dir.into(),
+            client: reqwest::Client::builder()
+                .connect_timeout(CONNECT_TIMEOUT)
+                .build()
+                .unwrap_or_default(),
+            insecure: Vec::new(),
+            credentials: HashMap::new(),
+        }
+    }
+
+    /// Reach these registries (`host[:port]`) over plain HTTP.
+    pub fn with_insecure_registries(mut self, registries: Vec<String>) -> Self {
+        self.insecure = registries;
+        self
+    }
+
+    /// Log in to `registry` (`host[:port]`, `docker.io` for Docker Hub).
+    pub fn with_credentials(mut self, registry: &str, credentials: RegistryCredentials) -> Self {
+        self.credentials.insert(registry.to_string(), credentials);
+        self
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Pull an image into the cache, or find it there. Stages: `download`
+    /// (layer bytes received) and `extract` (layer bytes unpacked), both
+    /// skipped when the image is cached.
+    pub async fn pull(&self, image: &str, mut progress: Progress) -> Result<Image, PullError> {
+        let image = ImageRef::parse(image).map_err(|e| (StatusCode::BAD_REQUEST, e))?;
+        if let Some(cached) = image.digest().and_then(|digest| self.cached(digest)) {
+            return Ok(cached);
+        }
+        let result = self.fetch(&image, &mut progress).await;
+        progress.finish(&result.as_ref().map_err(|(_, e)| e.clone()));
+        result
+    }
+
+    async fn fetch(&self, image: &ImageRef, progress: &mut Progress) -> Result<Image, PullError> {
+        let mut registry = Registry::new(self, image);
+        let (body, media_type) = registry.manifest(&image.reference).await?;
+        let digest = sha256_digest(&body);
+        if image.digest().is_some_and(|pinned| pinned != digest) {
+            return Err((StatusCode::BAD_GATEWAY, format!("manifest of {} doesn't match its digest", image)));
+        }
+        if let Some(cached) = self.cached(&digest) {
+            return Ok(cached);
+        }
+        let mut manifest: Manifest = parse_json(&body, "manifest")?;
+        if matches!(media_type.as_str(), OCI_INDEX | DOCKER_MANIFEST_LIST) || !manifest.manifests.is_empty() {
+            let arch = platform_architecture();
+            let platform = manifest
+                .manifests
+                .iter()
+                .find(|m| m.platform.as_ref().is_some_and(|p| p.os == "linux" && p.architecture == arch))
+                .ok_or_else(|| (StatusCode::BAD_REQUEST, format!("{} has no linux/{} image", image, arch)))?;
+            let platform_digest = platform.digest.clone();
+            validate_digest(&platform_digest).map_err(|e| (StatusCode::BAD_GATEWAY, e))?;
+            let (body, _) = registry.manifest(&platform_digest).await?;
+            if sha256_digest(&body) != platform_digest {
+                return Err((StatusCode::BAD_GATEWAY, format!("manifest of {} doesn't match its digest", image)));
+            }
+            manifest = parse_json(&body, "manifest")?;
+        }
+        let config = manifest
+            .config
+            .ok_or_else(|| (StatusCode::BAD_GATEWAY, format!("manifest of {} has no config", image)))?;
+        for descriptor in std::iter::once(&config).chain(&manifest.layers) {
+            validate_digest(&descriptor.digest).map_err(|e| (StatusCode::BAD_GATEWAY, e))?;
+        }
+        let layers = manifest
+            .layers
+            .iter()
+            .map(|layer| Ok((layer.digest.clone(), layer_compression(&layer.media_type)?)))
+            .collect::<Result<Vec<_>, PullError>>()?;
+        let config_json = registry.blob(&config.digest).await?;
+        let config: ImageConfig = parse_json(&config_json, "image config")?;
+
+        let staging = self.dir.join(format!(".staging-{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(&staging).map_err(|e| internal(format!("mkdir {}: {}", staging.display(), e)))?;
+        let result = async {
+            let total: u64 = manifest.layers.iter().map(|l| l.size).sum();
+            progress.begin_stage("download", Some(total), None);
+            let mut files = Vec::new();
+            for (i, (digest, gzip)) in layers.iter().enumerate() {
+                let file = staging.join(format!("layer-{}", i));
+                registry.download(digest, &file, progress).await?;
+                files.push((file, *gzip));
+            }
+            fs::write(staging.join("config.json"), &config_json)
+                .map_err(|e| internal(format!("write image config: {}", e)))?;
+            // Extraction runs on a blocking thread, which needs its own reporter
+            let mut extract = std::mem::replace(progress, Progress::disabled());
+            let rootfs = staging.join("rootfs");
+            let (unpacked, extract) = tokio::task::spawn_blocking(move || {
+                extract.begin_stage("extract", Some(total), None);
+                let result = unpack_layers(&files, &rootfs, &mut extract);
+                for (file, _) in &files {
+                    let _ = fs::remove_file(file);
+                }
+                (result, extract)
+            })
+            .await
+            .map_err(|e| internal(e.to_string()))?;
+            *progress = extract;
+            unpacked.map_err(internal)?;
+            let path = self.dir.join(cache_key(&digest));
+            match fs::rename(&staging, &path) {
+                Ok(()) => Ok(path),
+                // Pulled concurrently by another session
+                Err(_) if path.join("rootfs").is_dir() => Ok(path),
+                Err(e) => Err(internal(format!("install {}: {}", path.display(), e))),
+            }
+        }
+        .await;
+        if staging.exists() {
+            let _ = fs::remove_dir_all(&staging);
+        }
+        let path = result?;
+        Ok(image_from_config(digest, path.join("rootfs"), config))
+    }
+
+    fn cached(&self, digest: &str) -> Option<Image> {
+        let path = self.dir.join(cache_key(digest));
+        let root = path.join("rootfs");
+        if !root.is_dir() {
+            return None;
+        }
+        let config = serde_json::from_slice(&fs::read(path.join("config.json")).ok()?).ok()?;
+        Some(image_from_config(digest.to_string(), root, config))
+    }
+}
+
+/// Requests to one repository of a registry, with the auth it asked for.
+struct Registry<'a> {
+    client: &'a reqwest::Client,
+    credentials: Option<&'a RegistryCredentials>,
+    image: &'a ImageRef,
+    /// `{scheme}://{host}/v2/{repository}`
+    base: String,
+    authorization: Option<String>,
+}
+
+impl<'a> Registry<'a> {
+    fn new(store: &'a ImageStore, image: &'a ImageRef) -> Self {
+        let host = if image.registry == DOCKER_HUB { DOCKER_HUB_API } else { &image.registry };
+        let hostname = host.rsplit_once(':').map_or(host, |(name, _)| name);
+        let insecure =
+            matches!(hostname, "localhost" | "127.0.0.1" | "[::1]") || store.insecure.contains(&image.registry);
+        Self {
+            client: &store.client,
+            credentials: store.credentials.get(&image.registry),
+            image,
+            base: format!("{}://{}/v2/{}", if insecure { "http" } else { "https" }, host, image.repository),
+            authorization: None,
+        }
+    }
+
+    /// A manifest and its media type.
+    async fn manifest(&mut self, reference: &str) -> Result<(Vec<u8>, String), PullError> {
+        let accept = [OCI_INDEX, OCI_MANIFEST, DOCKER_MANIFEST_LIST, DOCKER_MANIFEST].join(", ");
+        let response = self.get(&format!("manifests/{}", reference), Some(&accept)).await?;
+        let media_type = response
+            .headers()
+            .get(reqwest::header::CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .unwrap_or_default()
+            .to_string();
+        Ok((read_limited(response, "manifest").await?, media_type))
+    }
+
+    /// A small blob, checked against its digest.
+    async fn blob(&mut self, digest: &str) -> Result<Vec<u8>, PullError> {
+        let response = self.get(&format!("blobs/{}", digest), None).await?;
+        let body = read_limited(response, "blob").await?;
+        if sha256_digest(&body) != digest {
+            return Err((StatusCode::BAD_GATEWAY, format!("blob {} doesn't match its digest", digest)));
+        }
+        Ok(body)
+    }
+
+    /// Stream a blob to `dest`, checking it against its digest.
+    async fn download(&mut self, digest: &str, dest: &Path, progress: &mut Progress) -> Result<(), PullError> {
+        let mut response = self.get(&format!("blobs/{}", digest), None).await?;
+        let mut file = tokio::fs::File::create(dest)
+            .await
+            .map_err(|e| internal(format!("create {}: {}", dest.display(), e)))?;
+        let mut hasher = Sha256::new();
+        while let Some(chunk) = response.chunk().await.map_err(|e| bad_gateway(self.image, e))? {
+            hasher.update(&chunk);
+            file.write_all(&chunk)
+                .await
+                .map_err(|e| internal(format!("write {}: {}", dest.display(), e)))?;
+            progress.add_bytes(chunk.len() as u64);
+        }
+        file.flush().await.map_err(|e| internal(format!("write {}: {}", dest.display(), e)))?;
+        if format!("sha256:{}", hex::encode(hasher.finalize())) != digest {
+            return Err((StatusCode::BAD_GATEWAY, format!("blob {} doesn't match its digest", digest)));
+        }
+        Ok(())
+    }
+
+    /// GET `{base}/{path}`, authenticating once if the registry asks to.
+    async fn get(&mut self, path: &str, accept: Option<&str>) -> Result<reqwest::Response, PullError> {
+        let url = format!("{}/{}", self.base, path);
+        let mut retried = false;
+        loop {
+            let mut request = self.client.get(&url);
+            if let Some(accept) = accept {
+                request = request.header(reqwest::header::ACCEPT, accept);
+            }
+            if let Some(ref authorization) = self.authorization {
+                request = request.header(reqwest::header::AUTHORIZATION, authorization);
+            }
+            let response = request.send().await.map_err(|e| bad_gateway(self.image, e))?;
+            let status = response.status();
+            if status == reqwest::StatusCode::UNAUTHORIZED && !retried {
+                let challenge = response
+                    .headers()
+                    .get(reqwest::header::WWW_AUTHENTICATE)
+                    .and_then(|v| v.to_str().ok())
+                    .unwrap_or_default()
+                    .to_string();
+                self.authenticate(&challenge).await?;
+                retried = true;
+                continue;
+            }
+            return match status.as_u16() {
+                200..=299 => Ok(response),
+                404 if path.starts_with("manifests/") => {
+                    Err((StatusCode::NOT_FOUND, format!("Image {} not found", self.image)))
+                }
+                401 | 403 => Err((
+                    StatusCode::FORBIDDEN,
+                    format!("Registry denied access to {} (private, or doesn't exist)", self.image),
+                )),
+                _ => Err((
+                    StatusCode::BAD_GATEWAY,
+                    format!("Registry returned {} for {}", status, url),
+                )),
+            };
+        }
+    }
+
+    /// Answer a `WWW-Authenticate` challenge: basic auth with the configured
+    /// credentials, or a bearer token from the registry's token service,
+    /// anonymous without credentials.
+    async fn authenticate(&mut self, challenge: &str) -> Result<(), PullError> {
+        let (scheme, params) = challenge.split_once(' ').unwrap_or((challenge, ""));
+        let basic = self.credentials.map(|c| {
+            let login = format!("{}:{}", c.username, c.password);
+            format!("Basic {}", base64::engine::general_purpose::STANDARD.encode(login))
+        });
+        if scheme.eq_ignore_ascii_case("basic") {
+            self.authorization = basic;
+            return Ok(());
+        }
+        if !scheme.eq_ignore_ascii_case("bearer") {
+            return Err((
+                StatusCode::BAD_GATEWAY,
+                format!("Registry of {} wants unsupported auth {:?}", self.image, scheme),
+            ));
+        }
+        let params = challenge_params(params);
+        let realm = params
+            .get("realm")
+            .ok_or_else(|| (StatusCode::BAD_GATEWAY, "Registry token challenge has no realm".to_string()))?;
+        let scope = params
+            .get("scope")
+            .cloned()
+            .unwrap_or_else(|| format!("repository:{}:pull", self.image.repository));
+        let mut query = vec![("scope", scope)];
+        if let Some(service) = params.get("service") {
+            query.push(("service", service.clone()));
+        }
+        let mut request = self.client.get(realm).query(&query);
+        if let Some(basic) = basic {
+            request = request.header(reqwest::header::AUTHORIZATION, basic);
+        }
+        let response = request.send().await.map_err(|e| bad_gateway(self.image, e))?;
+        if !response.status().is_success() {
+            return Err((
+                StatusCode::FORBIDDEN,
+                format!("Registry denied a token for {} ({})", self.image, response.status()),
+            ));
+        }
+        let token: TokenResponse = parse_json(&read_limited(response, "token").await?, "token")?;
+        let token = token
+            .token
+            .or(token.access_token)
+            .ok_or_else(|| (StatusCode::BAD_GATEWAY, "Registry token response has no token".to_string()))?;
+        self.authorization = Some(format!("Bearer {}", token));
+        Ok(())
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Manifest {
+    /// Set on indexes: one manifest per platform
+    #[serde(default)]
+    manifests: Vec<Descriptor>,
+    config: Option<Descriptor>,
+    #[serde(default)]
+    layers: Vec<Descriptor>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Descriptor {
+    #[serde(default)]
+    media_type: String,
+    digest: String,
+    #[serde(default)]
+    size: u64,
+    platform: Option<Platform>,
+}
+
+#[derive(Deserialize)]
+struct Platform {
+    os: String,
+    architecture: String,
+}
+
+#[derive(Default, Deserialize)]
+struct ImageConfig {
+    #[serde(default)]
+    config: RuntimeConfig,
+}
+
+#[derive(Default, Deserialize)]
+struct RuntimeConfig {
+    #[serde(default, rename = "Env")]
+    env: Option<Vec<String>>,
+    #[serde(default, rename = "WorkingDir")]
+    working_dir: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    token: Option<String>,
+    access_token: Option<String>,
+}
+
+fn image_from_config(digest: String, root: PathBuf, config: ImageConfig) -> Image {
+    let env = config
+        .config
+        .env
+        .unwrap_or_default()
+        .into_iter()
+        .filter_map(|var| var.split_once('=').map(|(k, v)| (k.to_string(), v.to_string())))
+        .collect();
+    Image {
+        digest,
+        root,
+        env,
+        working_dir: config.config.working_dir.filter(|dir| dir.starts_with('/')),
+    }
+}
+
+/// Whether a layer is gzipped, for the layer types that can be unpacked.
+fn layer_compression(media_type: &str) -> Result<bool, PullError> {
+    match media_type {
+        "application/vnd.oci.image.layer.v1.tar" => Ok(false),
+        "application/vnd.oci.image.layer.v1.tar+gzip" | "application/vnd.docker.image.rootfs.diff.tar.gzip" => {
+            Ok(true)
+        }
+        _ => Err((StatusCode::BAD_REQUEST, format!("unsupported image layer type {:?}", media_type))),
+    }
+}
+
+/// Apply layers, bottom first, onto an empty `rootfs`.
+fn unpack_layers(layers: &[(PathBuf, bool)], rootfs: &Path, progress: &mut Progress) -> Result<(), String> {
+    fs::create_dir_all(rootfs).map_err(|e| format!("mkdir {}: {}", rootfs.display(), e))?;
+    for (path, gzip) in layers {
+        // Whiteouts hide files of the layers below, not of their own, so
+        // they're applied before the layer's files are unpacked
+        for entry in open_layer(path, *gzip, None)?.entries().map_err(|e| format!("read layer: {}", e))? {
+            let entry = entry.map_err(|e| format!("read layer: {}", e))?;
+            let entry_path = entry.path().map_err(|e| format!("read layer: {}", e))?;
+            if let Some(name) = entry_path.file_name().and_then(|n| n.to_str()) {
+                if let Some(hidden) = name.strip_prefix(".wh.") {
+                    apply_whiteout(rootfs, entry_path.parent().unwrap_or(Path::new("")), hidden)?;
+                }
+            }
+        }
+        let mut archive = open_layer(path, *gzip, Some(progress))?;
+        for entry in archive.entries().map_err(|e| format!("read layer: {}", e))? {
+            let mut entry = entry.map_err(|e| format!("read layer: {}", e))?;
+            let is_whiteout = entry
+                .path()
+                .map_err(|e| format!("read layer: {}", e))?
+                .file_name()
+                .and_then(|n| n.to_str())
+                .is_some_and(|n| n.starts_with(".wh."));
+            if !is_whiteout {
+                // `unpack_in` refuses entries that would land outside `rootfs`
+                entry.unpack_in(rootfs).map_err(|e| format!("unpack layer: {}", e))?;
+            }
+        }
+    }
+    Ok(())
+}
+
+fn open_layer<'a>(
+    path: &Path,
+    gzip: bool,
+    progress: Option<&'a mut Progress>,
+) -> Result<tar::Archive<Box<dyn Read + 'a>>, String> {
+    let file = fs::File::open(path).map_err(|e| format!("open {}: {}", path.display(), e))?;
+    let file: Box<dyn Read + 'a> = match progress {
+        Some(progress) => Box::new(ProgressReader::new(file, progress)),
+        None => Box::new(file),
+    };
+    let reader: Box<dyn Read + 'a> = if gzip { Box::new(flate2::read::GzDecoder::new(file)) } else { file };
+    let mut archive = tar::Archive::new(reader);
+    archive.set_preserve_permissions(true);
+    archive.set_preserve_ownerships(true);
+    archive.set_preserve_mtime(true);
+    Ok(archive)
+}
+
+/// Delete `{dir}/{name}`, or everything in `dir` for the opaque marker
+/// `.wh..wh..opq`. Symlinks on the way aren't followed, since they would be
+/// resolved against the host's filesystem.
+fn apply_whiteout(rootfs: &Path, dir: &Path, name: &str) -> Result<(), String> {
+    let mut path = rootfs.to_path_buf();
+    for component in dir.components() {
+        match component {
+            Component::Normal(part) => path.push(part),
+            Component::RootDir | Component::CurDir => {}
+            _ => return Err(format!("invalid whiteout in {}", dir.display())),
+        }
+        match fs::symlink_metadata(&path) {
+            Ok(meta) if meta.is_dir() => {}
+            // Nothing below to hide
+            _ => return Ok(()),
+        }
+    }
+    let remove = |path: &Path| match fs::symlink_metadata(path) {
+        Ok(meta) if meta.is_dir() => fs::remove_dir_all(path),
+        Ok(_) => fs::remove_file(path),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e),
+    };
+    let result = if name == ".wh..opq" {
+        fs::read_dir(&path).and_then(|entries| entries.flatten().try_for_each(|entry| remove(&entry.path())))
+    } else if name.is_empty() || name.contains('/') || name == "." || name == ".." {
+        return Err(format!("invalid whiteout {:?}", name));
+    } else {
+        remove(&path.join(name))
+    };
+    result.map_err(|e| format!("apply whiteout in {}: {}", dir.display(), e))
+}
+
+/// `key="value"` pairs of a `WWW-Authenticate` challenge.
+fn challenge_params(params: &str) -> HashMap<String, String> {
+    let mut result = HashMap::new();
+    let mut rest = params.trim();
+    while let Some((key, after)) = rest.split_once('=') {
+        let key = key.trim().trim_start_matches(',').trim().to_ascii_lowercase();
+        let (value, after) = match after.strip_prefix('"') {
+            Some(quoted) => quoted.split_once('"').unwrap_or((quoted, "")),
+            None => after.split_once(',').unwrap_or((after, "")),
+        };
+        result.insert(key, value.to_string());
+        rest = after.trim_start_matches(',').trim();
+    }
+    result
+}
+
+/// Architecture name images use for this host's.
+fn platform_architecture() -> &'static str {
+    match std::env::consts::ARCH {
+        "x86_64" => "amd64",
+        "aarch64" => "arm64",
+        "powerpc64" => "ppc64le",
+        arch => arch,
+    }
+}
+
+fn validate_digest(digest: &str) -> Result<(), String> {
+    match digest.strip_prefix("sha256:") {
+        Some(hex) if hex.len() == 64 && hex.chars().all(|c| matches!(c, '0'..='9' | 'a'..='f')) => Ok(()),
+        _ => Err(format!("unsupported digest {:?} (expected sha256:<64 hex digits>)", digest)),
+    }
+}
+
+fn sha256_digest(data: &[u8]) -> String {
+    format!("sha256:{}", hex::encode(Sha256::digest(data)))
+}
+
+/// Cache directory name for a digest (`sha256:abc` → `sha256-abc`).
+fn cache_key(digest: &str) -> String {
+    digest.replace(':', "-")
+}
+
+async fn read_limited(mut response: reqwest::Response, what: &str) -> Result<Vec<u8>, PullError> {
+    let mut body = Vec::new();
+    while let Some(chunk) = response
+        .chunk()
+        .await
+        .map_err(|e| (StatusCode::BAD_GATEWAY, format!("read {}: {}", what, e)))?
+    {
+        body.extend_from_slice(&chunk);
+        if body.len() > MAX_METADATA_BYTES {
+            return Err((StatusCode::BAD_GATEWAY, format!("{} is larger than {} bytes", what, MAX_METADATA_BYTES)));
+        }
+    }
+    Ok(body)
+}
+
+fn parse_json<T: serde::de::DeserializeOwned>(data: &[u8], what: &str) -> Result<T, PullError> {
+    serde_json::from_slice(data).map_err(|e| (StatusCode::BAD_GATEWAY, format!("invalid {}: {}", what, e)))
+}
+
+fn bad_gateway(image: &ImageRef, e: reqwest::Error) -> PullError {
+    (StatusCode::BAD_GATEWAY, format!("pull {}: {}", image, e))
+}
+
+fn internal(e: String) -> PullError {
+    (StatusCode::INTERNAL_SERVER_ERROR, e)
+}
@@ -0,0 +1,442 @@
+//! Project import (`POST /sessions/:id/import`): the server fetches a tar or
+//! tar.gz archive over HTTP(S), or a ref of a git repository, straight into
+//! the sandbox, so clients don't download a project only to upload it again.
+//!
+//! Archives are downloaded by the server into an anonymous host file. Every
+//! host along the redirects is checked against the session's egress policy,
+//! and the addresses it resolves to with [`egress::address_refusal`], as the
+//! egress proxy does. They're unpacked through `safe_path`; an archive
+//! holding one top-level directory, as GitHub and GitLab archives do, has it
+//! stripped. Git imports run `git` in the sandbox as the session's user, so
+//! they go through the session's egress proxy like any command. Either way the result is put
+//! together in a staging directory next to the destination and renamed into
+//! place, so a failed import leaves nothing behind.
+
+use crate::backend::SandboxBackend;
+use crate::egress::{self, EgressPolicy};
+use crate::file_diff::{normalize, walk};
+use crate::git;
+use crate::safe_path::Root;
+use crate::sandbox::{self, RunConfig};
+use axum::http::StatusCode;
+use futures_util::StreamExt;
+use nix::sys::stat::SFlag;
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, Read, Seek};
+use std::net::SocketAddr;
+use std::path::{Component, Path};
+use std::time::{Duration, Instant};
+use tokio::io::AsyncWriteExt;
+
+pub use opencomputer_types::{ImportKind, ImportRequest, ImportResult};
+
+/// Redirects followed when downloading an archive.
+const MAX_REDIRECTS: usize = 5;
+
+/// Longest an archive download may take.
+const DOWNLOAD_TIMEOUT: Duration = Duration::from_secs(600);
+
+/// What `req` fetches: as given, or guessed from its URL.
+pub fn kind(req: &ImportRequest) -> ImportKind {
+    if let Some(kind) = req.kind {
+        return kind;
+    }
+    let url = req.url.trim_end_matches('/');
+    let remote = ["git@", "git://", "ssh://"].iter().any(|prefix| url.starts_with(prefix));
+    if remote || url.ends_with(".git") {
+        ImportKind::Git
+    } else {
+        ImportKind::Archive
+    }
+}
+
+pub fn validate(req: &ImportRequest) -> Result<(), String> {
+    if req.url.is_empty() || req.url.starts_with('-') {
+        return Err(format!("invalid url {:?}", req.url));
+    }
+    if kind(req) == ImportKind::Archive {
+        if req.git_ref.is_some() || req.credential.is_some() {
+            return Err("ref and credential are only for git imports".to_string());
+        }
+        if !req.url.starts_with("http://") && !req.url.starts_with("https://") {
+            return Err("archives are imported from http:// or https:// URLs".to_string());
+        }
+    }
+    if let Some(ref git_ref) = req.git_ref {
+        if git_ref.is_empty() || git_ref.starts_with('-') {
+            return Err(format!("invalid ref {:?}", git_ref));
+        }
+    }
+    for (what, path) in [("subdir", &req.subdir), ("dir", &req.dir)] {
+        let escapes = path
+            .as_deref()
+            .is_some_and(|p| Path::new(p).components().any(|c| c == Component::ParentDir));
+        if escapes {
+            return Err(format!("{} can't contain ..", what));
+        }
+    }
+    Ok(())
+}
+
+/// Where `req` goes: `req.dir`, relative to `cwd` unless absolute, or a
+/// directory in `cwd` named after the URL.
+pub fn destination(req: &ImportRequest, cwd: &str) -> String {
+    let dir = req.dir.clone().unwrap_or_else(|| {
+        let name = git::humanish(&req.url);
+        [".tar.gz", ".tgz", ".tar"]
+            .iter()
+            .find_map(|ext| name.strip_suffix(ext).filter(|n| !n.is_empty()))
+            .map_or(name.clone(), str::to_string)
+    });
+    normalize(&Path::new(cwd).join(dir).display().to_string())
+}
+
+/// Download the archive at `url` into an anonymous file, refusing hosts the
+/// session's `policy` doesn't allow and bodies over `limit` bytes.
+pub async fn download(url: &str, policy: Option<&EgressPolicy>, limit: u64) -> Result<File, (StatusCode, String)> {
+    let mut url = reqwest::Url::parse(url).map_err(|e| (StatusCode::BAD_REQUEST, format!("invalid url: {}", e)))?;
+    for _ in 0..=MAX_REDIRECTS {
+        let client = client_for(&url, policy).await?;
+        let resp = client
+            .get(url.clone())
+            .send()
+            .await
+            .map_err(|e| (StatusCode::BAD_GATEWAY, format!("fetch {}: {}", url, e)))?;
+        let status = resp.status();
+        if status.is_redirection() {
+            let location = resp
+                .headers()
+                .get(reqwest::header::LOCATION)
+                .and_then(|v| v.to_str().ok())
+                .ok_or((StatusCode::BAD_GATEWAY, format!("{} redirected without a Location", url)))?;
+            url = url
+                .join(location)
+                .map_err(|e| (StatusCode::BAD_GATEWAY, format!("{} redirected to {:?}: {}", url, location, e)))?;
+            continue;
+        }
+        if !status.is_success() {
+            return Err((StatusCode::BAD_GATEWAY, format!("{} answered {}", url, status)));
+        }
+        let too_large = || (StatusCode::PAYLOAD_TOO_LARGE, format!("{} is over the {} byte import limit", url, limit));
+        if resp.content_length().is_some_and(|len| len > limit) {
+            return Err(too_large());
+        }
+        let internal = |e: io::Error| (StatusCode::INTERNAL_SERVER_ERROR, format!("save archive: {}", e));
+        let mut file = tokio::fs::File::from_std(anonymous_file().map_err(internal)?);
+        let mut body = resp.bytes_stream();
+        let mut received = 0u64;
+        while let Some(chunk) = body.next().await {
+            let chunk = chunk.map_err(|e| (StatusCode::BAD_GATEWAY, format!("fetch {}: {}", url, e)))?;
+            received += chunk.len() as u64;
+            if received > limit {
+                return Err(too_large());
+            }
+            file.write_all(&chunk).await.map_err(internal)?;
+        }
+        let mut file = file.into_std().await;
+        file.rewind().map_err(internal)?;
+        return Ok(file);
+    }
+    Err((StatusCode::BAD_GATEWAY, format!("more than {} redirects", MAX_REDIRECTS)))
+}
+
+/// A client that reaches `url`'s host only at the addresses checked here,
+/// so a second lookup can't lead somewhere else.
+async fn client_for(url: &reqwest::Url, policy: Option<&EgressPolicy>) -> Result<reqwest::Client, (StatusCode, String)> {
+    if !matches!(url.scheme(), "http" | "https") {
+        return Err((StatusCode::BAD_REQUEST, format!("can't import from {}", url)));
+    }
+    let host = url
+        .host_str()
+        .ok_or((StatusCode::BAD_REQUEST, format!("{} has no host", url)))?;
+    if let Some(reason) = policy.and_then(|policy| egress::refusal(policy, host)) {
+        return Err((StatusCode::FORBIDDEN, format!("{}: {}", host, reason)));
+    }
+    let port = url.port_or_known_default().unwrap_or(443);
+    let addrs: Vec<SocketAddr> = tokio::net::lookup_host((host, port))
+        .await
+        .map_err(|e| (StatusCode::BAD_GATEWAY, format!("resolve {}: {}", host, e)))?
+        .collect();
+    if addrs.is_empty() {
+        return Err((StatusCode::BAD_GATEWAY, format!("resolve {}: no addresses", host)));
+    }
+    let named = policy.is_some_and(|policy| egress::explicitly_allows(policy, host));
+    if let Some(reason) = addrs.iter().find_map(|a| egress::address_refusal(a.ip(), named)) {
+        return Err((StatusCode::FORBIDDEN, format!("{}: {}", host, reason)));
+    }
+    reqwest::Client::builder()
+        .redirect(reqwest::redirect::Policy::none())
+        .timeout(DOWNLOAD_TIMEOUT)
+        .resolve_to_addrs(host, &addrs)
+        .build()
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))
+}
+
+/// Put `req` at `dest`: unpack `archive`, or fetch the git ref with
+/// `config`'s env and limits. Imports over `limit` bytes are refused.
+#[allow(clippy::too_many_arguments)]
+pub fn import(
+    backend: &dyn SandboxBackend,
+    sandbox_root: &Path,
+    config: &RunConfig,
+    req: &ImportRequest,
+    dest: &str,
+    archive: Option<File>,
+    credential: HashMap<String, String>,
+    limit: u64,
+) -> Result<ImportResult, (StatusCode, String)> {
+    let started = Instant::now();
+    let root = Root::open(sandbox_root).map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e))?;
+    let owner = sandbox::sandbox_owner(sandbox_root);
+    let staging = stage(&root, dest, owner)?;
+    let filled = match archive {
+        Some(archive) => {
+            let free = sandbox::disk_capacity(sandbox_root).saturating_sub(sandbox::disk_usage(sandbox_root));
+            unpack(&root, archive, &staging, owner, free).map(|()| None)
+        }
+        None => {
+            let config = RunConfig {
+                cwd: staging.clone(),
+                ..config.clone()
+            };
+            fetch_git(backend, sandbox_root, &root, &config, req, credential, limit).map(Some)
+        }
+    };
+    let placed = filled.and_then(|commit| place(&root, &staging, req.subdir.as_deref(), dest).map(|()| commit));
+    let commit = match placed {
+        Ok(commit) => commit,
+        Err(e) => {
+            let _ = root.remove_all(Path::new(&staging));
+            return Err(e);
+        }
+    };
+    let (files, bytes) = tree_size(&root, dest)?;
+    Ok(ImportResult {
+        path: dest.to_string(),
+        kind: kind(req),
+        commit,
+        files,
+        bytes,
+        duration_ms: started.elapsed().as_millis() as u64,
+    })
+}
+
+/// Check `dest` is missing or an empty directory and create a staging
+/// directory next to it.
+fn stage(root: &Root, dest: &str, owner: Option<(u32, u32)>) -> Result<String, (StatusCode, String)> {
+    let top = dest.trim_start_matches('/').split('/').next().unwrap_or_default();
+    if dest == "/" || sandbox::is_mounted_top_level(top) {
+        return Err((StatusCode::BAD_REQUEST, format!("{} is part of the sandbox itself", dest)));
+    }
+    let internal = |e| (StatusCode::INTERNAL_SERVER_ERROR, e);
+    if let Ok(entry) = root.symlink_metadata(Path::new(dest)) {
+        let empty = entry.is_directory && root.list(Path::new(dest)).map_err(internal)?.is_empty();
+        if !empty {
+            return Err((StatusCode::CONFLICT, format!("{} already exists and isn't empty", dest)));
+        }
+    }
+    let parent = Path::new(dest).parent().unwrap_or(Path::new("/"));
+    let staging = format!(
+        "{}/.import-{}",
+        parent.display().to_string().trim_end_matches('/'),
+        uuid::Uuid::new_v4().simple()
+    );
+    root.create_dir_all(Path::new(&staging), owner).map_err(internal)?;
+    Ok(staging)
+}
+
+/// Unpack a tar or tar.gz archive into `staging`, refusing to write more
+/// than `max_bytes`. Only directories, regular files and symlinks are
+/// unpacked.
+fn unpack(
+    root: &Root,
+    mut archive: File,
+    staging: &str,
+    owner: Option<(u32, u32)>,
+    max_bytes: u64,
+) -> Result<(), (StatusCode, String)> {
+    let bad = |e: io::Error| (StatusCode::BAD_REQUEST, format!("unpack archive: {}", e));
+    let internal = |e| (StatusCode::INTERNAL_SERVER_ERROR, e);
+    let strip = single_top_dir(&mut archive).map_err(bad)?;
+    let mut tar = tar::Archive::new(decompressed(archive).map_err(bad)?);
+    let mut links: Vec<String> = Vec::new();
+    let mut written = 0u64;
+    for entry in tar.entries().map_err(bad)? {
+        let mut entry = entry.map_err(bad)?;
+        let Some(rel) = relative(&entry.path().map_err(bad)?, strip.as_deref())? else {
+            continue;
+        };
+        // A link the archive made could lead anywhere in the sandbox
+        if links.iter().any(|link| rel.starts_with(&format!("{}/", link))) {
+            return Err((StatusCode::BAD_REQUEST, format!("unpack archive: {} is below a symlink", rel)));
+        }
+        let path = format!("{}/{}", staging, rel);
+        let mode = entry.header().mode().unwrap_or(0o644);
+        match entry.header().entry_type() {
+            tar::EntryType::Directory => root.create_dir_all(Path::new(&path), owner).map_err(internal)?,
+            tar::EntryType::Regular | tar::EntryType::Continuous => {
+                written += entry.size();
+                if written > max_bytes {
+                    return Err((
+                        StatusCode::INSUFFICIENT_STORAGE,
+                        format!("the archive unpacks to more than the session's {} free bytes", max_bytes),
+                    ));
+                }
+                let mut file = root.create(Path::new(&path), owner).map_err(internal)?;
+                io::copy(&mut entry, &mut file).map_err(bad)?;
+                root.set_mode(Path::new(&path), mode).map_err(internal)?;
+            }
+            tar::EntryType::Symlink => {
+                let target = entry
+                    .link_name()
+                    .map_err(bad)?
+                    .ok_or((StatusCode::BAD_REQUEST, format!("unpack archive: {} has no target", rel)))?;
+                root.symlink(&target.to_string_lossy(), Path::new(&path), owner)
+                    .map_err(internal)?;
+                links.push(rel);
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// The name of the directory everything in the archive is below, if there
+/// is exactly one. Leaves `archive` rewound.
+fn single_top_dir(archive: &mut File) -> io::Result<Option<String>> {
+    let mut top: Option<String> = None;
+    let mut nested = false;
+    {
+        let mut tar = tar::Archive::new(decompressed(archive.try_clone()?)?);
+        for entry in tar.entries()? {
+            let entry = entry?;
+            if !matches!(
+                entry.header().entry_type(),
+                tar::EntryType::Directory | tar::EntryType::Regular | tar::EntryType::Continuous | tar::EntryType::Symlink
+            ) {
+                continue;
+            }
+            let path = entry.path()?;
+            let mut components = path.components().filter(|c| *c != Component::CurDir);
+            let Some(first) = components.next() else {
+                continue;
+            };
+            let first = first.as_os_str().to_string_lossy().to_string();
+            nested |= components.next().is_some();
+            match top {
+                None => top = Some(first),
+                Some(ref name) if *name == first => {}
+                Some(_) => {
+                    archive.rewind()?;
+                    return Ok(None);
+                }
+            }
+        }
+    }
+    archive.rewind()?;
+    Ok(top.filter(|_| nested))
+}
+
+/// `path` below the archive's root with `strip` taken off the front, or
+/// `None` for the root itself.
+fn relative(path: &Path, strip: Option<&str>) -> Result<Option<String>, (StatusCode, String)> {
+    let mut parts = Vec::new();
+    for component in path.components() {
+        match component {
+            Component::Normal(part) => parts.push(part.to_string_lossy().to_string()),
+            Component::CurDir => {}
+            _ => {
+                return Err((
+                    StatusCode::BAD_REQUEST,
+                    format!("unpack archive: {} leads outside the archive", path.display()),
+                ))
+            }
+        }
+    }
+    if strip.is_some() && !parts.is_empty() {
+        parts.remove(0);
+    }
+    Ok((!parts.is_empty()).then(|| parts.join("/")))
+}
+
+/// The archive's content, gunzipped if it starts with the gzip magic.
+fn decompressed(mut archive: File) -> io::Result<Box<dyn Read>> {
+    let mut magic = [0u8; 2];
+    let gzip = archive.read_exact(&mut magic).is_ok() && magic == [0x1f, 0x8b];
+    archive.rewind()?;
+    Ok(if gzip {
+        Box::new(flate2::read::GzDecoder::new(archive))
+    } else {
+        Box::new(archive)
+    })
+}
+
+/// Fetch the git ref into `config.cwd`, refusing checkouts over `limit`
+/// bytes. The commit checked out.
+fn fetch_git(
+    backend: &dyn SandboxBackend,
+    sandbox_root: &Path,
+    root: &Root,
+    config: &RunConfig,
+    req: &ImportRequest,
+    credential: HashMap<String, String>,
+    limit: u64,
+) -> Result<String, (StatusCode, String)> {
+    let commit = git::fetch(backend, sandbox_root, config, &req.url, req.git_ref.as_deref(), credential)
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e))?
+        .map_err(|stderr| (StatusCode::BAD_GATEWAY, format!("git fetch failed: {}", stderr.trim())))?;
+    let (_, bytes) = tree_size(root, &config.cwd)?;
+    if bytes > limit {
+        return Err((
+            StatusCode::PAYLOAD_TOO_LARGE,
+            format!("the checkout is {} bytes, over the {} byte import limit", bytes, limit),
+        ));
+    }
+    Ok(commit)
+}
+
+/// Move `staging`, or its `subdir`, to `dest`.
+fn place(root: &Root, staging: &str, subdir: Option<&str>, dest: &str) -> Result<(), (StatusCode, String)> {
+    let internal = |e| (StatusCode::INTERNAL_SERVER_ERROR, e);
+    let from = match subdir.map(|s| s.trim_matches('/')).filter(|s| !s.is_empty()) {
+        Some(subdir) => format!("{}/{}", staging, subdir),
+        None => staging.to_string(),
+    };
+    if !root.symlink_metadata(Path::new(&from)).is_ok_and(|entry| entry.is_directory) {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            format!("{} isn't a directory of the import", subdir.unwrap_or_default()),
+        ));
+    }
+    // Checked empty by stage
+    if root.symlink_metadata(Path::new(dest)).is_ok() {
+        root.remove_all(Path::new(dest)).map_err(internal)?;
+    }
+    root.rename(Path::new(&from), Path::new(dest)).map_err(internal)?;
+    if from != staging {
+        root.remove_all(Path::new(staging)).map_err(internal)?;
+    }
+    Ok(())
+}
+
+/// Regular files under `dir` and their total size.
+fn tree_size(root: &Root, dir: &str) -> Result<(u64, u64), (StatusCode, String)> {
+    let (mut files, mut bytes) = (0, 0);
+    walk(root, dir, "", &mut |_, _, entry, _| {
+        if entry.file_type() == SFlag::S_IFREG {
+            files += 1;
+            bytes += entry.size;
+        }
+        Ok(())
+    })?;
+    Ok((files, bytes))
+}
+
+/// A read-write file with no name, gone once closed.
+fn anonymous_file() -> io::Result<File> {
+    let path = std::env::temp_dir().join(format!("opensandbox-import-{}", uuid::Uuid::new_v4()));
+    let file = fs::OpenOptions::new().read(true).write(true).create_new(true).open(&path)?;
+    let _ = fs::remove_file(&path);
+    Ok(file)
+}
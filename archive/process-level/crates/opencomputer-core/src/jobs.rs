@@ -0,0 +1,248 @@
+//! Asynchronous runs (`POST /sessions/:id/jobs`, `GET` and `DELETE /jobs/:id`).
+//!
+//! A job is a session run whose request returns as soon as it has started,
+//! so nothing holds a connection open for a long build and a proxy's idle
+//! timeout can't cut one short. It runs like any other run, on a blocking
+//! thread, with its output shared through a [`LiveRun`] as it comes in;
+//! clients poll for what's new by offset. Jobs go with their session (the
+//! running ones are killed), and only the last [`MAX_FINISHED`] finished
+//! jobs of a session are kept.
+
+use crate::sandbox::{LiveOutput, LiveRun, RunConfig, RunResult};
+use crate::secrets;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+pub use opencomputer_types::{Job as JobInfo, JobQuery, JobStatus};
+
+/// Finished jobs kept per session; older ones are dropped as others finish.
+pub const MAX_FINISHED: usize = 32;
+
+/// Jobs by ID.
+#[derive(Default)]
+pub struct Jobs {
+    by_id: Mutex<HashMap<String, Arc<Job>>>,
+}
+
+pub struct Job {
+    pub id: String,
+    pub session_id: String,
+    /// The command, secrets masked
+    pub command: Vec<String>,
+    secrets: Vec<String>,
+    max_output_bytes: u64,
+    trace_id: String,
+    started: Instant,
+    live: LiveRun,
+    outcome: Mutex<Option<Outcome>>,
+}
+
+struct Outcome {
+    result: Result<RunResult, String>,
+    at: Instant,
+}
+
+impl Jobs {
+    /// Register a job about to run `config`.
+    pub fn start(&self, session_id: &str, config: &RunConfig, trace_id: String) -> Arc<Job> {
+        let job = Arc::new(Job {
+            id: uuid::Uuid::new_v4().to_string(),
+            session_id: session_id.to_string(),
+            command: secrets::redact_all(&config.command, &config.secrets),
+            secrets: config.secrets.clone(),
+            max_output_bytes: config.max_output_bytes,
+            trace_id,
+            started: Instant::now(),
+            live: LiveRun::default(),
+            outcome: Mutex::default(),
+        });
+        self.lock().insert(job.id.clone(), job.clone());
+        job
+    }
+
+    pub fn get(&self, job_id: &str) -> Option<Arc<Job>> {
+        self.lock().get(job_id).cloned()
+    }
+
+    /// Record how a job ended, dropping its session's oldest finished jobs
+    /// beyond [`MAX_FINISHED`].
+    pub fn finish(&self, job: &Job, result: Result<RunResult, String>) {
+        *job.outcome.lock().unwrap_or_else(|e| e.into_inner()) = Some(Outcome {
+            result,
+            at: Instant::now(),
+        });
+        let mut jobs = self.lock();
+        let mut finished: Vec<(Instant, String)> = jobs
+            .values()
+            .filter(|j| j.session_id == job.session_id)
+            .filter_map(|j| j.finished_at().map(|at| (at, j.id.clone())))
+            .collect();
+        if finished.len() > MAX_FINISHED {
+            finished.sort();
+            for (_, id) in &finished[..finished.len() - MAX_FINISHED] {
+                jobs.remove(id);
+            }
+        }
+    }
+
+    /// Drop a session's jobs once the session is gone, killing those still
+    /// running.
+    pub fn forget_session(&self, session_id: &str) {
+        self.lock().retain(|_, job| {
+            if job.session_id != session_id {
+                return true;
+            }
+            if job.finished_at().is_none() {
+                job.live.cancel();
+            }
+            false
+        });
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<String, Arc<Job>>> {
+        self.by_id.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl Job {
+    /// What the run shares while it goes on.
+    pub fn live(&self) -> &LiveRun {
+        &self.live
+    }
+
+    /// Kill the job. False if it had already finished.
+    pub fn cancel(&self) -> bool {
+        let outcome = self.outcome.lock().unwrap_or_else(|e| e.into_inner());
+        if outcome.is_some() {
+            return false;
+        }
+        self.live.cancel();
+        true
+    }
+
+    fn finished_at(&self) -> Option<Instant> {
+        self.outcome.lock().unwrap_or_else(|e| e.into_inner()).as_ref().map(|o| o.at)
+    }
+
+    /// The job's state, with output from the query's offsets on. Errors if
+    /// an offset falls inside a character.
+    pub fn info(&self, query: &JobQuery) -> Result<JobInfo, String> {
+        let outcome = self.outcome.lock().unwrap_or_else(|e| e.into_inner());
+        let mut info = JobInfo {
+            job_id: self.id.clone(),
+            session_id: self.session_id.clone(),
+            command: self.command.clone(),
+            status: JobStatus::Running,
+            stdout: String::new(),
+            stderr: String::new(),
+            stdout_offset: 0,
+            stderr_offset: 0,
+            stdout_bytes: 0,
+            stderr_bytes: 0,
+            stdout_truncated: false,
+            stderr_truncated: false,
+            exit_code: None,
+            signal: None,
+            committed: None,
+            error: None,
+            trace_id: Some(self.trace_id.clone()),
+            duration_ms: self.started.elapsed().as_millis() as u64,
+        };
+        let (stdout, stderr) = match outcome.as_ref() {
+            None => (
+                self.shown(&self.live.stdout(), false),
+                self.shown(&self.live.stderr(), false),
+            ),
+            Some(outcome) => {
+                info.duration_ms = outcome.at.duration_since(self.started).as_millis() as u64;
+                match outcome.result {
+                    Ok(ref result) => {
+                        info.status = if self.live.is_cancelled() {
+                            JobStatus::Cancelled
+                        } else if result.success() {
+                            JobStatus::Succeeded
+                        } else {
+                            JobStatus::Failed
+                        };
+                        info.exit_code = result.exit_code;
+                        info.signal = result.signal;
+                        info.committed = result.committed;
+                    }
+                    Err(ref e) => {
+                        info.status = JobStatus::Failed;
+                        info.error = Some(e.clone());
+                    }
+                }
+                (
+                    self.shown(&self.live.stdout(), true),
+                    self.shown(&self.live.stderr(), true),
+                )
+            }
+        };
+        (info.stdout, info.stdout_offset) = from_offset(&stdout.0, query.stdout_offset)?;
+        (info.stderr, info.stderr_offset) = from_offset(&stderr.0, query.stderr_offset)?;
+        (info.stdout_bytes, info.stderr_bytes) = (stdout.1, stderr.1);
+        info.stdout_truncated = stdout.1 > self.max_output_bytes;
+        info.stderr_truncated = stderr.1 > self.max_output_bytes;
+        Ok(info)
+    }
+
+    /// A stream's output as it can be shown, with secrets masked, and how
+    /// many bytes were written to it. Until the run is `done`, a trailing
+    /// partial character or start of a secret is left out: what follows
+    /// could change how it's shown, and what was shown can't change.
+    fn shown(&self, output: &LiveOutput, done: bool) -> (String, u64) {
+        let kept = &output.kept[..];
+        let mut end = kept.len();
+        if !done {
+            // Leaving out one can leave a partial other at the end
+            loop {
+                let cut = complete_chars(&kept[..end]).min(end - self.partial_secret(&kept[..end]));
+                if cut == end {
+                    break;
+                }
+                end = cut;
+            }
+        }
+        let text = secrets::redact(&String::from_utf8_lossy(&kept[..end]), &self.secrets);
+        (text, output.bytes)
+    }
+
+    /// Length of the longest end of `bytes` that starts a secret.
+    fn partial_secret(&self, bytes: &[u8]) -> usize {
+        self.secrets
+            .iter()
+            .filter_map(|secret| {
+                let secret = secret.as_bytes();
+                (1..secret.len().min(bytes.len() + 1)).rev().find(|&n| bytes.ends_with(&secret[..n]))
+            })
+            .max()
+            .unwrap_or(0)
+    }
+}
+
+/// Length of `bytes` without a trailing incomplete UTF-8 character.
+fn complete_chars(bytes: &[u8]) -> usize {
+    let end = bytes.len();
+    for start in (end.saturating_sub(3)..end).rev() {
+        let width = match bytes[start] {
+            0x80..=0xbf => continue,
+            0xc0..=0xdf => 2,
+            0xe0..=0xef => 3,
+            0xf0..=0xf7 => 4,
+            _ => 1,
+        };
+        return if start + width > end { start } else { end };
+    }
+    end
+}
+
+/// `text` from byte `offset` on, and the offset after it.
+fn from_offset(text: &str, offset: u64) -> Result<(String, u64), String> {
+    let offset = usize::try_from(offset).unwrap_or(usize::MAX).min(text.len());
+    let rest = text
+        .get(offset..)
+        .ok_or_else(|| format!("offset {} is inside a character", offset))?;
+    Ok((rest.to_string(), text.len() as u64))
+}
@@ -0,0 +1,291 @@
+//! Long-lived Python interpreters for `POST /sessions/:id/kernel/python/execute`.
+//!
+//! A session has at most one kernel: `python3` running [`DRIVER`] in the
+//! sandbox, started by the first execute and kept until it is shut down or
+//! the session goes away. The driver reads one JSON request per line on its
+//! stdin and answers each with one JSON line on its stdout. While a cell
+//! runs, fds 1 and 2 point at temporary files, so output of subprocesses is
+//! captured too and nothing the cell prints can reach the protocol pipe.
+
+use crate::sandbox::{self, RunConfig};
+use crate::secrets;
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Write};
+use std::path::Path;
+use std::process::{Child, ChildStdin, Stdio};
+use std::sync::mpsc::{Receiver, RecvTimeoutError};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+pub use opencomputer_types::{KernelError, KernelExecuteRequest, KernelExecuteResult, KernelImage};
+
+/// Wall-clock limit of a cell that doesn't set `timeout_ms`.
+pub const DEFAULT_TIMEOUT_MS: u64 = 30_000;
+
+/// Longest `timeout_ms` a cell may ask for.
+pub const MAX_TIMEOUT_MS: u64 = 300_000;
+
+/// How long an interrupted cell gets to unwind before the kernel is killed.
+const INTERRUPT_GRACE: Duration = Duration::from_secs(2);
+
+/// The kernel's main loop, run with `python3 -u -c`.
+const DRIVER: &str = r#"
+import ast, base64, io, json, os, signal, sys, tempfile, traceback
+
+proto_in = os.fdopen(os.dup(0), "r", encoding="utf-8")
+proto_out = os.fdopen(os.dup(1), "w", encoding="utf-8")
+devnull = os.open(os.devnull, os.O_RDWR)
+for fd in (0, 1, 2):
+    os.dup2(devnull, fd)
+namespace = {"__name__": "__main__", "__builtins__": __builtins__}
+count = 0
+
+def png(data):
+    return {"mime": "image/png", "data": base64.b64encode(data).decode()}
+
+def run(code, reply):
+    tree = ast.parse(code, "<cell>", "exec")
+    last = None
+    if tree.body and isinstance(tree.body[-1], ast.Expr):
+        last = ast.Expression(tree.body.pop().value)
+    exec(compile(tree, "<cell>", "exec"), namespace)
+    if last is not None:
+        value = eval(compile(last, "<cell>", "eval"), namespace)
+        if value is not None:
+            namespace["_"] = value
+            reply["result"] = repr(value)
+            repr_png = getattr(value, "_repr_png_", None)
+            data = repr_png() if callable(repr_png) else None
+            if isinstance(data, bytes):
+                reply["images"].append(png(data))
+    plt = sys.modules.get("matplotlib.pyplot")
+    if plt is not None:
+        for num in plt.get_fignums():
+            buf = io.BytesIO()
+            plt.figure(num).savefig(buf, format="png")
+            reply["images"].append(png(buf.getvalue()))
+        plt.close("all")
+
+def error(e):
+    if isinstance(e, SyntaxError):
+        lines = traceback.format_exception_only(type(e), e)
+    else:
+        tb = e.__traceback__
+        while tb is not None and tb.tb_frame.f_code.co_filename != "<cell>":
+            tb = tb.tb_next
+        lines = traceback.format_exception(type(e), e, tb or e.__traceback__)
+    return {"ename": type(e).__name__, "evalue": str(e), "traceback": lines}
+
+def captured(f, limit):
+    size = f.seek(0, 2)
+    f.seek(0)
+    return f.read(limit).decode("utf-8", "replace"), size, size > limit
+
+signal.signal(signal.SIGINT, signal.SIG_IGN)
+for line in proto_in:
+    request = json.loads(line)
+    count += 1
+    reply = {"execution_count": count, "images": []}
+    out, err = tempfile.TemporaryFile(), tempfile.TemporaryFile()
+    os.dup2(out.fileno(), 1)
+    os.dup2(err.fileno(), 2)
+    try:
+        signal.signal(signal.SIGINT, signal.default_int_handler)
+        run(request["code"], reply)
+    except BaseException as e:
+        reply["error"] = error(e)
+    finally:
+        signal.signal(signal.SIGINT, signal.SIG_IGN)
+        sys.stdout.flush()
+        sys.stderr.flush()
+        os.dup2(devnull, 1)
+        os.dup2(devnull, 2)
+    limit = request["max_output_bytes"]
+    reply["stdout"], reply["stdout_bytes"], reply["stdout_truncated"] = captured(out, limit)
+    reply["stderr"], reply["stderr_bytes"], reply["stderr_truncated"] = captured(err, limit)
+    out.close()
+    err.close()
+    proto_out.write(json.dumps(reply) + "\n")
+    proto_out.flush()
+"#;
+
+/// Running kernels by session ID.
+#[derive(Default)]
+pub struct Kernels {
+    by_session: Mutex<HashMap<String, KernelHandle>>,
+}
+
+struct KernelHandle {
+    pid: u32,
+    kernel: Arc<Mutex<Kernel>>,
+}
+
+struct Kernel {
+    child: Child,
+    input: ChildStdin,
+    /// Reply lines, read from the driver's stdout by a thread
+    replies: Receiver<String>,
+}
+
+impl Drop for Kernel {
+    fn drop(&mut self) {
+        kill_group(self.child.id());
+        let _ = self.child.wait();
+    }
+}
+
+impl Kernels {
+    /// Run a cell in the session's kernel, starting one (with `config`'s env,
+    /// cwd and hostname) if it has none. Blocks until the cell finishes or
+    /// times out.
+    pub fn execute(
+        &self,
+        session_id: &str,
+        sandbox_root: &Path,
+        config: &RunConfig,
+        code: &str,
+        timeout: Duration,
+        max_output_bytes: u64,
+    ) -> Result<KernelExecuteResult, String> {
+        let started_at = Instant::now();
+        let (kernel, started) = self.get_or_start(session_id, sandbox_root, config)?;
+        let mut guard = kernel.lock().unwrap_or_else(|e| e.into_inner());
+        let request = serde_json::json!({ "code": code, "max_output_bytes": max_output_bytes });
+        let sent = writeln!(guard.input, "{}", request).and_then(|_| guard.input.flush());
+
+        let mut timed_out = false;
+        let reply = match sent {
+            Err(_) => Err(RecvTimeoutError::Disconnected),
+            Ok(()) => match guard.replies.recv_timeout(timeout) {
+                Err(RecvTimeoutError::Timeout) => {
+                    timed_out = true;
+                    let _ = nix::sys::signal::kill(
+                        nix::unistd::Pid::from_raw(guard.child.id() as i32),
+                        nix::sys::signal::Signal::SIGINT,
+                    );
+                    guard.replies.recv_timeout(INTERRUPT_GRACE)
+                }
+                reply => reply,
+            },
+        };
+
+        let mut result = match reply {
+            Ok(line) => serde_json::from_str::<KernelExecuteResult>(&line)
+                .map_err(|e| format!("bad reply from kernel: {}", e))?,
+            Err(failure) => {
+                // The kernel is gone or stuck: drop it so the next call starts afresh
+                drop(guard);
+                self.remove(session_id, &kernel);
+                let (ename, evalue) = match failure {
+                    RecvTimeoutError::Timeout => (
+                        "TimeoutError",
+                        "the cell ignored the interrupt, so the kernel was restarted and its state is lost",
+                    ),
+                    RecvTimeoutError::Disconnected => {
+                        ("KernelDied", "the kernel exited, so its state is lost")
+                    }
+                };
+                KernelExecuteResult {
+                    error: Some(KernelError {
+                        ename: ename.to_string(),
+                        evalue: evalue.to_string(),
+                        traceback: Vec::new(),
+                    }),
+                    ..Default::default()
+                }
+            }
+        };
+        result.stdout = secrets::redact(&result.stdout, &config.secrets);
+        result.stderr = secrets::redact(&result.stderr, &config.secrets);
+        result.result = result.result.map(|r| secrets::redact(&r, &config.secrets));
+        if let Some(ref mut error) = result.error {
+            error.evalue = secrets::redact(&error.evalue, &config.secrets);
+            error.traceback = secrets::redact_all(&error.traceback, &config.secrets);
+        }
+        result.timed_out = timed_out;
+        result.started = started;
+        result.duration_ms = started_at.elapsed().as_millis() as u64;
+        Ok(result)
+    }
+
+    /// Kill a session's kernel. Returns false if it had none.
+    pub fn shutdown(&self, session_id: &str) -> bool {
+        let handle = self.by_session.lock().unwrap().remove(session_id);
+        match handle {
+            Some(handle) => {
+                // Kill now rather than when a running cell lets go of the kernel
+                kill_group(handle.pid);
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn get_or_start(
+        &self,
+        session_id: &str,
+        sandbox_root: &Path,
+        config: &RunConfig,
+    ) -> Result<(Arc<Mutex<Kernel>>, bool), String> {
+        let mut kernels = self.by_session.lock().unwrap();
+        if let Some(handle) = kernels.get(session_id) {
+            return Ok((handle.kernel.clone(), false));
+        }
+        let kernel = Arc::new(Mutex::new(start(sandbox_root, config)?));
+        let pid = kernel.lock().unwrap().child.id();
+        kernels.insert(
+            session_id.to_string(),
+            KernelHandle {
+                pid,
+                kernel: kernel.clone(),
+            },
+        );
+        Ok((kernel, true))
+    }
+
+    /// Forget a session's kernel if it is still `kernel`.
+    fn remove(&self, session_id: &str, kernel: &Arc<Mutex<Kernel>>) {
+        let mut kernels = self.by_session.lock().unwrap();
+        if kernels.get(session_id).is_some_and(|h| Arc::ptr_eq(&h.kernel, kernel)) {
+            kernels.remove(session_id);
+        }
+    }
+}
+
+fn start(sandbox_root: &Path, config: &RunConfig) -> Result<Kernel, String> {
+    use std::os::unix::process::CommandExt;
+
+    let mut config = config.clone();
+    config.command = vec!["python3".into(), "-u".into(), "-c".into(), DRIVER.into()];
+    // Figures render to PNG; there is no display
+    config.env.entry("MPLBACKEND".to_string()).or_insert_with(|| "Agg".to_string());
+    let mut cmd = sandbox::sandboxed_command(sandbox_root, &config)?;
+    // Its own process group, so the kernel goes away with everything it started
+    cmd.process_group(0)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null());
+    let mut child = cmd.spawn().map_err(|e| format!("start python3: {}", e))?;
+
+    let (input, output) = match (child.stdin.take(), child.stdout.take()) {
+        (Some(input), Some(output)) => (input, output),
+        _ => unreachable!("stdin and stdout are piped"),
+    };
+    let (tx, replies) = std::sync::mpsc::channel();
+    std::thread::spawn(move || {
+        for line in BufReader::new(output).lines() {
+            let Ok(line) = line else { break };
+            if tx.send(line).is_err() {
+                break;
+            }
+        }
+    });
+    Ok(Kernel { child, input, replies })
+}
+
+fn kill_group(pid: u32) {
+    let _ = nix::sys::signal::killpg(
+        nix::unistd::Pid::from_raw(pid as i32),
+        nix::sys::signal::Signal::SIGKILL,
+    );
+}
@@ -0,0 +1,122 @@
+//! OpenSandbox core: sandbox session management with HTTP and gRPC APIs.
+//!
+//! The `isolate` binary is a thin wrapper around this crate. Other Rust
+//! services can embed it to manage sandboxes in-process:
+//!
+//! ```no_run
+//! # async fn embed() {
+//! let state = opencomputer_core::AppState::builder()
+//!     .templates_dir("/var/lib/sandbox/templates")
+//!     .warm_pool(2)
+//!     .build()
+//!     .unwrap();
+//! opencomputer_core::http_server::spawn_cleanup_task(state.clone());
+//! let app = opencomputer_core::build_router(state);
+//! let listener = tokio::net::TcpListener::bind("0.0.0.0:8080").await.unwrap();
+//! axum::serve(listener, app).await.unwrap();
+//! # }
+//! ```
+//!
+//! To mount the API inside an existing Axum app, use [`build_router_with`]:
+//!
+//! ```no_run
+//! # fn mount(state: opencomputer_core::AppState) -> axum::Router {
+//! use opencomputer_core::RouterOptions;
+//!
+//! let sandbox = opencomputer_core::build_router_with(
+//!     state,
+//!     RouterOptions::new()
+//!         .base_path("/sandbox")
+//!         .api_key_auth(false)
+//!         .preview_proxy(false)
+//!         .map_api(|api| api.layer(tower_http::trace::TraceLayer::new_for_http())),
+//! );
+//! axum::Router::new().merge(sandbox)
+//! # }
+//! ```
+//!
+//! The operator routes (`/admin/...`, see [`admin`]) aren't mounted by
+//! default. Serve them with [`build_admin_router`] on another listener, or
+//! with the tenant API via `RouterOptions::new().admin_routes(true)`, behind
+//! `.admin_token(..)` unless keys granted the operator scopes should reach them.
+
+#[cfg(not(target_os = "linux"))]
+compile_error!("opencomputer-core only works on Linux.");
+
+pub mod acme;
+pub mod admin;
+pub mod activity;
+pub mod auth;
+pub mod backend;
+pub mod background_log;
+pub mod body_limit;
+pub mod cache;
+pub mod cgroups;
+pub mod capacity;
+pub mod changes;
+pub mod checkpoint;
+#[cfg(feature = "chaos")]
+pub mod chaos;
+pub mod cleanup_policy;
+pub mod disk_usage;
+pub mod download;
+pub mod egress;
+pub mod events;
+pub mod env_policy;
+pub mod file_diff;
+pub mod file_hash;
+pub mod file_sync;
+pub mod git;
+pub mod health;
+pub mod grpc_server;
+pub mod hibernate;
+pub mod history;
+pub mod hooks;
+pub mod http_server;
+pub mod idempotency;
+pub mod images;
+pub mod import;
+pub mod jobs;
+pub mod kernel;
+pub mod lifecycle;
+pub mod oidc;
+pub mod overview;
+pub mod packages;
+pub mod patch;
+pub mod persistence;
+pub mod pool;
+pub mod port_scan;
+pub mod ports;
+pub mod preview_auth;
+pub mod preview_path;
+pub mod progress;
+pub mod quota;
+pub mod recurring;
+pub mod replay;
+pub mod request_id;
+pub mod reservation;
+pub mod run_limits;
+pub mod safe_path;
+pub mod sandbox;
+pub mod schedule;
+pub mod scope;
+pub mod secrets;
+pub mod ssh;
+pub mod static_site;
+pub mod state;
+pub mod stats;
+pub mod store_key;
+pub mod tasks;
+pub mod templates;
+pub mod timeline;
+pub mod tls;
+pub mod trace_context;
+pub mod trash;
+pub mod uploads;
+pub mod userns;
+pub mod webhooks;
+
+pub use http_server::{build_admin_router, build_router, build_router_with, RouterOptions};
+pub use hooks::Hooks;
+pub use lifecycle::{SessionLifecycleEvent, SessionLifecycleHook};
+pub use state::{AppState, AppStateBuilder};
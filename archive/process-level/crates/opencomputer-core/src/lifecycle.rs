@@ -0,0 +1,92 @@
+//! Session lifecycle notifications for embedders.
+//!
+//! Implement [`SessionLifecycleHook`] and register it with
+//! [`AppState::add_lifecycle_hook`](crate::state::AppState::add_lifecycle_hook)
+//! to drive external schedulers, billing, or DNS from session events. Hooks run
+//! inline on the request/cleanup task (or, for background exits, the thread
+//! waiting on the process) after the session map is updated and no locks are
+//! held; hand slow work off to a spawned task.
+
+use crate::state::Session;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+/// Snapshot of a session at the time of a lifecycle event.
+#[derive(Debug, Clone)]
+pub struct SessionLifecycleEvent {
+    pub session_id: String,
+    pub org_id: Option<String>,
+    pub slug: Option<String>,
+    pub preview_url: Option<String>,
+    pub sandbox_root: PathBuf,
+    pub labels: HashMap<String, String>,
+}
+
+impl SessionLifecycleEvent {
+    pub fn from_session(session: &Session) -> Self {
+        Self {
+            session_id: session.id.clone(),
+            org_id: session.org_id.clone(),
+            slug: session.slug.clone(),
+            preview_url: session.preview_url.clone(),
+            sandbox_root: session.sandbox_root.clone(),
+            labels: session.labels.clone(),
+        }
+    }
+}
+
+/// A session background process exited.
+#[derive(Debug, Clone, Serialize)]
+pub struct BackgroundExit {
+    pub pid: u32,
+    pub port: u16,
+    pub exit_code: Option<i32>,
+    pub signal: Option<i32>,
+}
+
+/// A foreground run in a session finished.
+#[derive(Debug, Clone, Serialize)]
+pub struct RunCompletion {
+    pub command: Vec<String>,
+    pub exit_code: Option<i32>,
+    pub signal: Option<i32>,
+    pub duration_ms: u64,
+}
+
+/// Which lifecycle transition occurred.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LifecycleTransition {
+    Created,
+    Expired,
+    Deleted,
+    Evicted,
+}
+
+/// Callbacks for session lifecycle transitions. All methods default to no-ops.
+pub trait SessionLifecycleHook: Send + Sync {
+    /// A session was created and is ready to use.
+    fn on_create(&self, _event: &SessionLifecycleEvent) {}
+
+    /// A session was reaped after exceeding its idle TTL.
+    fn on_expire(&self, _event: &SessionLifecycleEvent) {}
+
+    /// A session was deleted through the API.
+    fn on_delete(&self, _event: &SessionLifecycleEvent) {}
+
+    /// A session idle the longest was removed so a new one fits under the
+    /// server's session cap.
+    fn on_evict(&self, _event: &SessionLifecycleEvent) {}
+
+    /// A background process started through the API exited.
+    fn on_background_exit(&self, _event: &SessionLifecycleEvent, _exit: &BackgroundExit) {}
+
+    /// A run in the session finished (successfully or not).
+    fn on_run_complete(&self, _event: &SessionLifecycleEvent, _run: &RunCompletion) {}
+
+    /// Notifications queued or being delivered, for hooks that send them
+    /// asynchronously. Reported by `GET /admin/overview`.
+    fn pending(&self) -> usize {
+        0
+    }
+}
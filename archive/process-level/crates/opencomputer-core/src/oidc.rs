@@ -0,0 +1,339 @@
+//! JWT authentication against an OIDC issuer, alongside static API keys.
+//!
+//! ```toml
+//! [auth.oidc]
+//! issuer = "https://login.example.com/"
+//! audience = "opensandbox"
+//! org_claim = "org_id"          # default
+//! scopes_claim = "scope"        # default; a space-separated string or an array
+//! scope_prefix = "sandbox:"     # stripped from the claim's scopes
+//! default_scopes = ["sessions.read"]   # for tokens naming no scope (default none)
+//! ```
+//!
+//! A bearer credential that is a JWT is verified with the issuer's signing
+//! keys, fetched from the JWKS its discovery document points to (or
+//! `jwks_url`) and cached for an hour; a token signed with a key not in the
+//! cache refetches them, at most once a minute, so key rotation needs no
+//! restart. `iss`, `aud` and `exp` are required and checked, `nbf` if set,
+//! with a minute of leeway for clock skew. RS, PS, ES and EdDSA algorithms are
+//! accepted; `none` and the HMAC ones never are.
+//!
+//! A valid token acts as an API key (see [`crate::auth`]) whose org is the
+//! `org_claim` claim, so its sessions and quotas are shared with keys of the
+//! same `org_id`, and whose scopes are the `scopes_claim` entries that name
+//! scopes (see [`crate::scope`]) once `scope_prefix` is removed. Others, such
+//! as `openid`, are ignored.
+
+use crate::auth::ApiKey;
+use crate::scope;
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD as B64, Engine};
+use ring::signature::{self, UnparsedPublicKey, VerificationAlgorithm};
+use serde::Deserialize;
+use serde_json::{Map, Value};
+use std::collections::HashMap;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::sync::Mutex;
+use tracing::{info, warn};
+
+/// How long fetched signing keys are used before they're fetched again.
+const JWKS_MAX_AGE: Duration = Duration::from_secs(3600);
+
+/// Least time between fetches for tokens signed with an unknown key.
+const JWKS_MIN_REFETCH: Duration = Duration::from_secs(60);
+
+/// Clock skew tolerated on `exp` and `nbf`.
+const LEEWAY_SECS: u64 = 60;
+
+const FETCH_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct OidcConfig {
+    /// Expected `iss`, and where `/.well-known/openid-configuration` is
+    pub issuer: String,
+    /// Expected in `aud`
+    pub audience: String,
+    /// Signing keys (default the discovery document's `jwks_uri`)
+    #[serde(default)]
+    pub jwks_url: Option<String>,
+    #[serde(default = "default_org_claim")]
+    pub org_claim: String,
+    #[serde(default = "default_scopes_claim")]
+    pub scopes_claim: String,
+    #[serde(default)]
+    pub scope_prefix: Option<String>,
+    /// Scopes of tokens whose claim names none (default none)
+    #[serde(default)]
+    pub default_scopes: Vec<String>,
+}
+
+fn default_org_claim() -> String {
+    "org_id".to_string()
+}
+
+fn default_scopes_claim() -> String {
+    "scope".to_string()
+}
+
+/// A verifier for one issuer's tokens.
+#[derive(Debug)]
+pub struct Oidc {
+    config: OidcConfig,
+    client: reqwest::Client,
+    keys: Mutex<KeySet>,
+}
+
+#[derive(Debug, Default)]
+struct KeySet {
+    /// By `kid` (`""` for a key without one)
+    keys: HashMap<String, Jwk>,
+    fetched_at: Option<Instant>,
+    attempted_at: Option<Instant>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct Jwk {
+    kty: String,
+    #[serde(default)]
+    kid: Option<String>,
+    #[serde(default)]
+    alg: Option<String>,
+    #[serde(default, rename = "use")]
+    usage: Option<String>,
+    #[serde(default)]
+    n: Option<String>,
+    #[serde(default)]
+    e: Option<String>,
+    #[serde(default)]
+    crv: Option<String>,
+    #[serde(default)]
+    x: Option<String>,
+    #[serde(default)]
+    y: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct JwkSet {
+    keys: Vec<Jwk>,
+}
+
+#[derive(Deserialize)]
+struct Header {
+    alg: String,
+    #[serde(default)]
+    kid: Option<String>,
+}
+
+/// Whether a presented credential looks like a JWT rather than a key.
+pub fn is_jwt(token: &str) -> bool {
+    token.split('.').count() == 3 && token.starts_with("eyJ")
+}
+
+impl Oidc {
+    pub fn new(config: OidcConfig) -> Result<Self, String> {
+        let secure = |url: &str| url.starts_with("https://") || url.starts_with("http://localhost");
+        if !secure(&config.issuer) {
+            return Err(format!("issuer {:?} must be an https:// URL", config.issuer));
+        }
+        if let Some(ref url) = config.jwks_url {
+            if !secure(url) {
+                return Err(format!("jwks_url {:?} must be an https:// URL", url));
+            }
+        }
+        if config.audience.is_empty() {
+            return Err("audience must not be empty".to_string());
+        }
+        for pattern in &config.default_scopes {
+            scope::validate_pattern(pattern).map_err(|e| format!("default_scopes: {}", e))?;
+        }
+        let client = reqwest::Client::builder()
+            .timeout(FETCH_TIMEOUT)
+            .build()
+            .map_err(|e| e.to_string())?;
+        Ok(Self {
+            config,
+            client,
+            keys: Mutex::new(KeySet::default()),
+        })
+    }
+
+    /// Verify `token` and map its claims to the key it acts as.
+    pub async fn authenticate(&self, token: &str) -> Result<ApiKey, String> {
+        let mut parts = token.split('.');
+        let (Some(header), Some(payload), Some(sig), None) = (parts.next(), parts.next(), parts.next(), parts.next())
+        else {
+            return Err("malformed token".to_string());
+        };
+        let header: Header = decode_json(header)?;
+        let sig = B64.decode(sig).map_err(|_| "malformed signature".to_string())?;
+        let jwk = self.key(header.kid.as_deref().unwrap_or_default()).await?;
+        if jwk.alg.as_deref().is_some_and(|alg| alg != header.alg) || jwk.usage.as_deref().is_some_and(|u| u != "sig") {
+            return Err(format!("key {:?} isn't for {} signatures", jwk.kid, header.alg));
+        }
+        let signed = &token[..header_len(token)];
+        verify(&jwk, &header.alg, signed.as_bytes(), &sig)?;
+
+        let claims: Map<String, Value> = decode_json(payload)?;
+        self.check_claims(&claims)?;
+        Ok(self.api_key(&claims))
+    }
+
+    fn check_claims(&self, claims: &Map<String, Value>) -> Result<(), String> {
+        if claims.get("iss").and_then(Value::as_str) != Some(self.config.issuer.as_str()) {
+            return Err("wrong issuer".to_string());
+        }
+        let audience = match claims.get("aud") {
+            Some(Value::String(aud)) => *aud == self.config.audience,
+            Some(Value::Array(auds)) => auds.iter().any(|a| a.as_str() == Some(self.config.audience.as_str())),
+            _ => false,
+        };
+        if !audience {
+            return Err("wrong audience".to_string());
+        }
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+        let time = |name| claims.get(name).and_then(Value::as_u64);
+        match time("exp") {
+            Some(exp) if exp + LEEWAY_SECS > now => {}
+            Some(_) => return Err("expired".to_string()),
+            None => return Err("no exp claim".to_string()),
+        }
+        if time("nbf").is_some_and(|nbf| nbf > now + LEEWAY_SECS) {
+            return Err("not valid yet".to_string());
+        }
+        Ok(())
+    }
+
+    fn api_key(&self, claims: &Map<String, Value>) -> ApiKey {
+        let text = |name: &str| claims.get(name).and_then(Value::as_str).filter(|v| !v.is_empty());
+        let subject = text("sub").unwrap_or("unknown");
+        let prefix = self.config.scope_prefix.as_deref().unwrap_or_default();
+        let listed: Vec<&str> = match claims.get(&self.config.scopes_claim) {
+            Some(Value::String(list)) => list.split_whitespace().collect(),
+            Some(Value::Array(list)) => list.iter().filter_map(Value::as_str).collect(),
+            _ => Vec::new(),
+        };
+        let mut scopes: Vec<String> = listed
+            .into_iter()
+            .filter_map(|s| s.strip_prefix(prefix))
+            .filter(|s| scope::validate_pattern(s).is_ok())
+            .map(str::to_string)
+            .collect();
+        if scopes.is_empty() {
+            scopes = self.config.default_scopes.clone();
+        }
+        ApiKey {
+            // Stable across the subject's tokens, so it's the same caller
+            key: format!("oidc:{}:{}", self.config.issuer, subject),
+            name: text("email").unwrap_or(subject).to_string(),
+            env: HashMap::new(),
+            max_sessions: None,
+            org_id: text(&self.config.org_claim).map(str::to_string),
+            scopes: Some(scopes),
+            session_id: None,
+        }
+    }
+
+    /// The signing key `kid`, fetching the issuer's keys if they're stale or
+    /// don't have it.
+    async fn key(&self, kid: &str) -> Result<Jwk, String> {
+        let mut set = self.keys.lock().await;
+        let due = |at: Option<Instant>, after: Duration| at.is_none_or(|at| at.elapsed() > after);
+        let wanted = due(set.fetched_at, JWKS_MAX_AGE) || !set.keys.contains_key(kid);
+        // Failed fetches, and tokens with made-up key IDs, retry after a minute
+        if wanted && due(set.attempted_at, JWKS_MIN_REFETCH) {
+            set.attempted_at = Some(Instant::now());
+            match self.fetch_keys().await {
+                Ok(keys) => {
+                    info!("Fetched {} signing keys of {}", keys.len(), self.config.issuer);
+                    set.keys = keys;
+                    set.fetched_at = set.attempted_at;
+                }
+                Err(e) => warn!("Fetch signing keys of {}: {}", self.config.issuer, e),
+            }
+        }
+        set.keys.get(kid).cloned().ok_or_else(|| format!("unknown signing key {:?}", kid))
+    }
+
+    async fn fetch_keys(&self) -> Result<HashMap<String, Jwk>, String> {
+        let url = match self.config.jwks_url {
+            Some(ref url) => url.clone(),
+            None => {
+                let discovery = format!(
+                    "{}/.well-known/openid-configuration",
+                    self.config.issuer.trim_end_matches('/')
+                );
+                let doc: Value = self.get_json(&discovery).await?;
+                doc.get("jwks_uri")
+                    .and_then(Value::as_str)
+                    .ok_or_else(|| format!("{} has no jwks_uri", discovery))?
+                    .to_string()
+            }
+        };
+        let set: JwkSet = self.get_json(&url).await?;
+        Ok(set
+            .keys
+            .into_iter()
+            .map(|key| (key.kid.clone().unwrap_or_default(), key))
+            .collect())
+    }
+
+    async fn get_json<T: serde::de::DeserializeOwned>(&self, url: &str) -> Result<T, String> {
+        let resp = self.client.get(url).send().await.map_err(|e| format!("GET {}: {}", url, e))?;
+        if !resp.status().is_success() {
+            return Err(format!("GET {}: {}", url, resp.status()));
+        }
+        resp.json().await.map_err(|e| format!("GET {}: {}", url, e))
+    }
+}
+
+/// Length of the signed part of a token: its header and payload.
+fn header_len(token: &str) -> usize {
+    token.rfind('.').unwrap_or(token.len())
+}
+
+fn decode_json<T: serde::de::DeserializeOwned>(part: &str) -> Result<T, String> {
+    let data = B64.decode(part).map_err(|_| "malformed token".to_string())?;
+    serde_json::from_slice(&data).map_err(|_| "malformed token".to_string())
+}
+
+/// Check `sig` over `signed` with `jwk`, which must suit `alg`.
+fn verify(jwk: &Jwk, alg: &str, signed: &[u8], sig: &[u8]) -> Result<(), String> {
+    let field = |value: &Option<String>| -> Result<Vec<u8>, String> {
+        let value = value.as_deref().ok_or_else(|| format!("{} key is incomplete", jwk.kty))?;
+        B64.decode(value).map_err(|_| format!("{} key is malformed", jwk.kty))
+    };
+    let unsupported = || format!("unsupported algorithm {} for a {} key", alg, jwk.kty);
+    let bad = |_| "bad signature".to_string();
+    match jwk.kty.as_str() {
+        "RSA" => {
+            let params: &signature::RsaParameters = match alg {
+                "RS256" => &signature::RSA_PKCS1_2048_8192_SHA256,
+                "RS384" => &signature::RSA_PKCS1_2048_8192_SHA384,
+                "RS512" => &signature::RSA_PKCS1_2048_8192_SHA512,
+                "PS256" => &signature::RSA_PSS_2048_8192_SHA256,
+                "PS384" => &signature::RSA_PSS_2048_8192_SHA384,
+                "PS512" => &signature::RSA_PSS_2048_8192_SHA512,
+                _ => return Err(unsupported()),
+            };
+            let (n, e) = (field(&jwk.n)?, field(&jwk.e)?);
+            signature::RsaPublicKeyComponents { n: &n, e: &e }
+                .verify(params, signed, sig)
+                .map_err(bad)
+        }
+        "EC" => {
+            let algorithm: &dyn VerificationAlgorithm = match (alg, jwk.crv.as_deref()) {
+                ("ES256", Some("P-256")) => &signature::ECDSA_P256_SHA256_FIXED,
+                ("ES384", Some("P-384")) => &signature::ECDSA_P384_SHA384_FIXED,
+                _ => return Err(unsupported()),
+            };
+            let point = [vec![0x04], field(&jwk.x)?, field(&jwk.y)?].concat();
+            UnparsedPublicKey::new(algorithm, point).verify(signed, sig).map_err(bad)
+        }
+        "OKP" if alg == "EdDSA" && jwk.crv.as_deref() == Some("Ed25519") => {
+            UnparsedPublicKey::new(&signature::ED25519, field(&jwk.x)?)
+                .verify(signed, sig)
+                .map_err(bad)
+        }
+        _ => Err(unsupported()),
+    }
+}
@@ -0,0 +1,140 @@
+//! Host and per-session resource usage for `GET /admin/overview`.
+//!
+//! Usage is read from `/proc` on demand, with no background collection:
+//! processes are attributed to a session by their root directory (as in
+//! [`sandbox::session_pids`]), memory is their summed RSS, and CPU percent
+//! comes from two readings of their CPU time a short window apart. Processes
+//! that start or exit inside the window only count for the part they were
+//! seen in.
+
+use crate::sandbox;
+use std::collections::HashMap;
+use std::fs;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+pub use opencomputer_types::{
+    HostOverview, Overview, OverviewQuery, QueueDepths, SessionUsage, SessionUsageTotals, TopConsumers,
+};
+
+pub const DEFAULT_TOP: usize = 5;
+pub const MAX_TOP: usize = 50;
+pub const DEFAULT_SAMPLE_MS: u64 = 200;
+pub const MAX_SAMPLE_MS: u64 = 2000;
+
+/// Resource use of one sandbox root, measured by [`sample`].
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RootUsage {
+    pub cpu_percent: f64,
+    pub cpu_secs: f64,
+    pub mem_bytes: u64,
+    pub disk_bytes: u64,
+    pub processes: usize,
+}
+
+/// CPU ticks and resident bytes of one process.
+#[derive(Clone, Copy)]
+struct ProcReading {
+    ticks: u64,
+    rss_bytes: u64,
+}
+
+/// Measure every root's processes and disk over `window`. Blocks for the
+/// window; run it on a blocking thread.
+pub fn sample(roots: &[PathBuf], window: Duration) -> HashMap<PathBuf, RootUsage> {
+    let (tick_hz, page_size) = unsafe {
+        (
+            libc::sysconf(libc::_SC_CLK_TCK).max(1) as f64,
+            libc::sysconf(libc::_SC_PAGESIZE).max(0) as u64,
+        )
+    };
+
+    let before = read_processes(roots, page_size);
+    let started = Instant::now();
+    std::thread::sleep(window);
+    let after = read_processes(roots, page_size);
+    let elapsed = started.elapsed().as_secs_f64();
+
+    roots
+        .iter()
+        .map(|root| {
+            let empty = HashMap::new();
+            let (old, new) = (before.get(root).unwrap_or(&empty), after.get(root).unwrap_or(&empty));
+            let delta: u64 = new
+                .iter()
+                .map(|(pid, now)| now.ticks.saturating_sub(old.get(pid).map_or(now.ticks, |then| then.ticks)))
+                .sum();
+            let usage = RootUsage {
+                cpu_percent: delta as f64 / tick_hz / elapsed * 100.0,
+                cpu_secs: new.values().map(|p| p.ticks).sum::<u64>() as f64 / tick_hz,
+                mem_bytes: new.values().map(|p| p.rss_bytes).sum(),
+                disk_bytes: sandbox::disk_usage(root),
+                processes: new.len(),
+            };
+            (root.clone(), usage)
+        })
+        .collect()
+}
+
+/// Readings of every process whose root is one of `roots`, keyed by root
+/// then PID. One pass over `/proc` serves all roots.
+fn read_processes(roots: &[PathBuf], page_size: u64) -> HashMap<PathBuf, HashMap<u32, ProcReading>> {
+    let mut by_root: HashMap<PathBuf, HashMap<u32, ProcReading>> = HashMap::new();
+    let Ok(entries) = fs::read_dir("/proc") else {
+        return by_root;
+    };
+    let process_roots = sandbox::ProcessRoots::new(roots);
+    for pid in entries
+        .flatten()
+        .filter_map(|e| e.file_name().to_str().and_then(|n| n.parse::<u32>().ok()))
+    {
+        let Some(root) = process_roots.of(pid) else {
+            continue;
+        };
+        if let Some(reading) = read_process(pid, page_size) {
+            by_root.entry(root.clone()).or_default().insert(pid, reading);
+        }
+    }
+    by_root
+}
+
+fn read_process(pid: u32, page_size: u64) -> Option<ProcReading> {
+    let stat = fs::read_to_string(format!("/proc/{}/stat", pid)).ok()?;
+    // The command name may contain spaces; fields resume after its ')'
+    let fields: Vec<&str> = stat.get(stat.rfind(')')? + 2..)?.split_whitespace().collect();
+    // utime and stime are fields 14 and 15 of the full line
+    let ticks = fields.get(11)?.parse::<u64>().ok()? + fields.get(12)?.parse::<u64>().ok()?;
+    let statm = fs::read_to_string(format!("/proc/{}/statm", pid)).ok()?;
+    let resident: u64 = statm.split_whitespace().nth(1)?.parse().ok()?;
+    Some(ProcReading {
+        ticks,
+        rss_bytes: resident * page_size,
+    })
+}
+
+/// Load averages and memory of the host, from `/proc`. Unreadable values
+/// are zero.
+pub fn host_load() -> ([f64; 3], u64, u64) {
+    let mut load = [0.0; 3];
+    if let Ok(loadavg) = fs::read_to_string("/proc/loadavg") {
+        for (slot, value) in load.iter_mut().zip(loadavg.split_whitespace()) {
+            *slot = value.parse().unwrap_or(0.0);
+        }
+    }
+    let meminfo = fs::read_to_string("/proc/meminfo").unwrap_or_default();
+    let kb = |name: &str| -> u64 {
+        meminfo
+            .lines()
+            .find(|l| l.starts_with(name))
+            .and_then(|l| l.split_whitespace().nth(1)?.parse().ok())
+            .unwrap_or(0)
+    };
+    (load, kb("MemTotal:") * 1024, kb("MemAvailable:") * 1024)
+}
+
+/// The `n` heaviest sessions by `key`, heaviest first.
+pub fn top_by<K: PartialOrd>(sessions: &[SessionUsage], n: usize, key: impl Fn(&SessionUsage) -> K) -> Vec<SessionUsage> {
+    let mut ranked: Vec<&SessionUsage> = sessions.iter().collect();
+    ranked.sort_by(|a, b| key(b).partial_cmp(&key(a)).unwrap_or(std::cmp::Ordering::Equal));
+    ranked.into_iter().take(n).cloned().collect()
+}
@@ -0,0 +1,294 @@
+//! Package installs (`POST /sessions/:id/packages`) through a shared cache.
+//!
+//! The host keeps one cache per package manager under the cache dir: a
+//! wheelhouse for pip (`{dir}/pip`) and an npm cache (`{dir}/npm`). Only the
+//! server writes to it, as root on the host, by downloading without running
+//! any package code (`pip download --only-binary`, `npm install
+//! --ignore-scripts` into a throwaway prefix). Sessions never change it:
+//! during an install it is mounted into the sandbox as an overlay whose
+//! writes are dropped afterwards (npm rewrites its index even when reading),
+//! and the package manager runs there, as the session's user, offline
+//! against it. Only when that fails is anything downloaded, after which the
+//! install is retried.
+
+use crate::backend::SandboxBackend;
+use crate::progress::Progress;
+use crate::sandbox::{self, RunConfig};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::RwLock;
+use std::time::{Duration, Instant};
+
+pub use opencomputer_types::{InstallPackagesRequest, InstallPackagesResult, PackageManager};
+
+pub const DEFAULT_PACKAGE_CACHE_DIR: &str = "/var/cache/opensandbox/packages";
+
+/// Most packages one request may name.
+pub const MAX_PACKAGES: usize = 100;
+
+/// Where a manager's cache is mounted inside the sandbox, under the root.
+const SANDBOX_CACHE_DIR: &str = ".opensandbox/package-cache";
+
+/// Longest a download into the cache may take.
+const DOWNLOAD_TIMEOUT: Duration = Duration::from_secs(600);
+
+pub struct PackageCache {
+    dir: PathBuf,
+    /// Read-held while sandboxes have the cache mounted, write-held while
+    /// downloading into it, so overlays never see it change underneath
+    lock: RwLock<()>,
+}
+
+impl PackageCache {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self {
+            dir: dir.into(),
+            lock: RwLock::new(()),
+        }
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Install packages into a sandbox. `config` supplies the env, cwd and
+    /// limits of the install command. Stages: `install`, then on a cache miss
+    /// `download` and `install` again.
+    pub fn install(
+        &self,
+        backend: &dyn SandboxBackend,
+        sandbox_root: &Path,
+        config: &RunConfig,
+        manager: PackageManager,
+        packages: &[String],
+        mut progress: Progress,
+    ) -> Result<InstallPackagesResult, String> {
+        let started = Instant::now();
+        let result = (|| {
+            let cache = self.dir.join(manager_dir(manager));
+            fs::create_dir_all(&cache).map_err(|e| format!("mkdir {}: {}", cache.display(), e))?;
+
+            progress.begin_stage("install", None, None);
+            let first = self.install_offline(backend, sandbox_root, config, manager, packages, &cache)?;
+            if first.success() {
+                return Ok(finish(first, true));
+            }
+
+            progress.begin_stage("download", None, None);
+            let (ok, stdout, stderr) = self.download(manager, packages, &cache)?;
+            if !ok {
+                return Ok(InstallPackagesResult {
+                    installed: false,
+                    cache_hit: false,
+                    stdout,
+                    stderr,
+                    exit_code: None,
+                    duration_ms: 0,
+                });
+            }
+
+            progress.begin_stage("install", None, None);
+            let second = self.install_offline(backend, sandbox_root, config, manager, packages, &cache)?;
+            Ok(finish(second, false))
+        })();
+        progress.finish(&result);
+        result.map(|mut r| {
+            r.duration_ms = started.elapsed().as_millis() as u64;
+            r
+        })
+    }
+
+    /// Fetch packages and their dependencies into the cache, on the host.
+    /// Returns whether it worked, with the downloader's output.
+    fn download(
+        &self,
+        manager: PackageManager,
+        packages: &[String],
+        cache: &Path,
+    ) -> Result<(bool, String, String), String> {
+        let _downloading = self.lock.write().unwrap_or_else(|e| e.into_inner());
+        let mut cmd;
+        let mut staging = None;
+        match manager {
+            PackageManager::Pip => {
+                // Wheels only: building an sdist would run its code on the host
+                cmd = Command::new("/usr/bin/python3");
+                cmd.args(["-m", "pip", "download", "--only-binary=:all:", "--progress-bar", "off", "--dest"])
+                    .arg(cache)
+                    .arg("--find-links")
+                    .arg(cache);
+            }
+            PackageManager::Npm => {
+                // Resolving into a throwaway prefix caches the whole dependency tree
+                let prefix = self.dir.join(format!(".staging-{}", uuid::Uuid::new_v4()));
+                fs::create_dir_all(&prefix).map_err(|e| format!("mkdir {}: {}", prefix.display(), e))?;
+                cmd = Command::new("npm");
+                cmd.args(["install", "--ignore-scripts", "--no-audit", "--no-fund", "--prefix"])
+                    .arg(&prefix)
+                    .arg("--cache")
+                    .arg(cache);
+                staging = Some(prefix);
+            }
+            PackageManager::Apt => return Err(apt_unsupported()),
+        }
+        cmd.arg("--").args(packages);
+        let result = run_with_timeout(cmd, DOWNLOAD_TIMEOUT);
+        if let Some(prefix) = staging {
+            let _ = fs::remove_dir_all(prefix);
+        }
+        // Sandbox users make their (dropped) writes through the shared group
+        sandbox::share_with_sandboxes(cache)?;
+        result
+    }
+
+    /// Run the package manager in the sandbox against the cache, offline.
+    fn install_offline(
+        &self,
+        backend: &dyn SandboxBackend,
+        sandbox_root: &Path,
+        config: &RunConfig,
+        manager: PackageManager,
+        packages: &[String],
+        cache: &Path,
+    ) -> Result<sandbox::RunResult, String> {
+        let inside = format!("/{}/{}", SANDBOX_CACHE_DIR, manager_dir(manager));
+        let mut command: Vec<String> = match manager {
+            PackageManager::Pip => [
+                "python3", "-m", "pip", "install", "--user", "--break-system-packages", "--no-index",
+                "--no-warn-script-location", "--progress-bar", "off", "--find-links", &inside,
+            ]
+            .map(String::from)
+            .to_vec(),
+            PackageManager::Npm => [
+                "npm", "install", "--offline", "--no-audit", "--no-fund", "--logs-dir", "/tmp/.npm-logs", "--cache",
+                &inside,
+            ]
+            .map(String::from)
+            .to_vec(),
+            PackageManager::Apt => return Err(apt_unsupported()),
+        };
+        // npm's cache is keyed by registry URL, so use the one the host downloaded from
+        if manager == PackageManager::Npm {
+            if let Some(registry) = ["npm_config_registry", "NPM_CONFIG_REGISTRY"]
+                .iter()
+                .find_map(|name| std::env::var(name).ok())
+            {
+                command.extend(["--registry".to_string(), registry]);
+            }
+        }
+        command.push("--".to_string());
+        command.extend(packages.iter().cloned());
+
+        let _mounted = self.lock.read().unwrap_or_else(|e| e.into_inner());
+        let mountpoint = sandbox_root.join(&inside[1..]);
+        let scratch = sandbox::mount_scratch_overlay(sandbox_root, cache, &mountpoint)?;
+        let config = RunConfig {
+            command,
+            ..config.clone()
+        };
+        let result = backend.run(sandbox_root, &config, None);
+        sandbox::unmount_scratch_overlay(&mountpoint, &scratch);
+        result
+    }
+}
+
+fn finish(run: sandbox::RunResult, cache_hit: bool) -> InstallPackagesResult {
+    InstallPackagesResult {
+        installed: run.success(),
+        cache_hit: cache_hit && run.success(),
+        exit_code: run.exit_code,
+        stdout: run.stdout,
+        stderr: run.stderr,
+        duration_ms: 0,
+    }
+}
+
+fn manager_dir(manager: PackageManager) -> &'static str {
+    match manager {
+        PackageManager::Pip => "pip",
+        PackageManager::Npm => "npm",
+        PackageManager::Apt => "apt",
+    }
+}
+
+fn apt_unsupported() -> String {
+    "apt packages can't be installed: /usr in a sandbox is the host's, read-only".to_string()
+}
+
+/// Reject requests that would make the host downloader read local paths,
+/// fetch URLs or take flags.
+pub fn validate(manager: PackageManager, packages: &[String]) -> Result<(), String> {
+    if manager == PackageManager::Apt {
+        return Err(apt_unsupported());
+    }
+    if packages.is_empty() {
+        return Err("packages must not be empty".to_string());
+    }
+    if packages.len() > MAX_PACKAGES {
+        return Err(format!("at most {} packages per request", MAX_PACKAGES));
+    }
+    for spec in packages {
+        let allowed = |c: char| c.is_ascii_alphanumeric() || "._-@=<>!~^*[],+".contains(c);
+        // npm scopes (`@scope/name`) are the only specs with a slash
+        let name = match spec.strip_prefix('@') {
+            Some(scoped) if manager == PackageManager::Npm => scoped.replacen('/', "", 1),
+            _ => spec.clone(),
+        };
+        if spec.is_empty() || spec.len() > 214 || spec.starts_with(['-', '.']) || !name.chars().all(allowed) {
+            return Err(format!("invalid package spec {:?}", spec));
+        }
+    }
+    Ok(())
+}
+
+/// Run a host command, killing it after `timeout`. Returns whether it
+/// exited 0, with its stdout and stderr.
+fn run_with_timeout(mut cmd: Command, timeout: Duration) -> Result<(bool, String, String), String> {
+    let stdout = tempfile_for("stdout")?;
+    let stderr = tempfile_for("stderr")?;
+    let mut child = cmd
+        .stdin(Stdio::null())
+        .stdout(stdout.try_clone().map_err(|e| e.to_string())?)
+        .stderr(stderr.try_clone().map_err(|e| e.to_string())?)
+        .spawn()
+        .map_err(|e| format!("start download: {}", e))?;
+    let deadline = Instant::now() + timeout;
+    let status = loop {
+        if let Some(status) = child.try_wait().map_err(|e| format!("wait for download: {}", e))? {
+            break Some(status);
+        }
+        if Instant::now() >= deadline {
+            let _ = child.kill();
+            let _ = child.wait();
+            break None;
+        }
+        std::thread::sleep(Duration::from_millis(100));
+    };
+    let read = |file: fs::File| -> String {
+        use std::io::{Read, Seek};
+        let mut file = file;
+        let mut text = String::new();
+        let _ = file.rewind();
+        let _ = file.take(sandbox::DEFAULT_MAX_OUTPUT_BYTES).read_to_string(&mut text);
+        text
+    };
+    let (out, mut err) = (read(stdout), read(stderr));
+    if status.is_none() {
+        err.push_str(&format!("\ndownload timed out after {}s\n", timeout.as_secs()));
+    }
+    Ok((status.is_some_and(|s| s.success()), out, err))
+}
+
+/// An anonymous file for a host command's output.
+fn tempfile_for(stream: &str) -> Result<fs::File, String> {
+    let path = std::env::temp_dir().join(format!("opensandbox-download-{}-{}", stream, uuid::Uuid::new_v4()));
+    let file = fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create_new(true)
+        .open(&path)
+        .map_err(|e| format!("create {}: {}", path.display(), e))?;
+    let _ = fs::remove_file(&path);
+    Ok(file)
+}
@@ -0,0 +1,249 @@
+//! Patch export (`GET /sessions/:id/export/patch`) of what a session
+//! changed, to apply back to the repository its files came from.
+//!
+//! In a git repository this is `git diff` of the working tree against the
+//! branch's upstream (see `git::export_base`), or a bundle of the commits
+//! since then. Elsewhere the patch is put together here from the session's
+//! changes since creation (see `changes`), diffed against the template or
+//! image layer the session started from. Paths a text diff can't carry
+//! (binary, too large, or with no original left to diff against) are listed
+//! in the patch's preamble, which `git apply` and `patch` skip.
+
+use crate::backend::SandboxBackend;
+use crate::changes::{self, ChangeKind, SessionChangesQuery};
+use crate::checkpoint;
+use crate::file_diff::below;
+use crate::git;
+use crate::safe_path::Root;
+use crate::sandbox::{self, RunConfig};
+use axum::http::StatusCode;
+use nix::sys::stat::SFlag;
+use similar::TextDiff;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+pub use opencomputer_types::{PatchExportQuery, PatchFormat};
+
+/// Largest patch an export returns.
+pub const MAX_PATCH_BYTES: u64 = sandbox::MAX_OUTPUT_BYTES;
+
+/// Largest file diffed as text outside a repository.
+const MAX_FILE_BYTES: u64 = 8 * 1024 * 1024;
+
+/// Mode git records for symlinks.
+const SYMLINK_MODE: u32 = 0o120000;
+
+/// An export, ready to send.
+pub struct Export {
+    /// "git" or "files": whether it came from a repository
+    pub source: &'static str,
+    /// Changed paths the patch only lists
+    pub omitted: usize,
+    pub content: ExportContent,
+}
+
+pub enum ExportContent {
+    Patch(String),
+    /// An unlinked file in the sandbox
+    Bundle(File),
+}
+
+/// One side of a changed path.
+enum Side {
+    Absent,
+    /// Git's mode for it and its content
+    Text(u32, String),
+    /// There, but not as text a patch can carry
+    Opaque,
+}
+
+/// Export what changed in `config.cwd` as `query` asks.
+pub fn export(
+    backend: &dyn SandboxBackend,
+    sandbox_root: &Path,
+    session_id: &str,
+    config: &RunConfig,
+    query: &PatchExportQuery,
+) -> Result<Export, (StatusCode, String)> {
+    let internal = |e| (StatusCode::INTERNAL_SERVER_ERROR, e);
+    let failed = |stderr: String| (StatusCode::CONFLICT, stderr.trim().to_string());
+    if !git::is_repository(backend, sandbox_root, config).map_err(internal)? {
+        if query.format == PatchFormat::Bundle || query.base.is_some() {
+            return Err((
+                StatusCode::CONFLICT,
+                format!("{} isn't in a git repository; only a diff can be exported", config.cwd),
+            ));
+        }
+        return files_patch(sandbox_root, session_id, &config.cwd);
+    }
+    let base = git::export_base(backend, sandbox_root, config, query.base.as_deref())
+        .map_err(|e| (StatusCode::BAD_REQUEST, e))?;
+    let content = match query.format {
+        PatchFormat::Diff => {
+            let diff = git::diff(backend, sandbox_root, config, Some(&base))
+                .map_err(internal)?
+                .map_err(failed)?;
+            if diff.truncated {
+                return Err((
+                    StatusCode::UNPROCESSABLE_ENTITY,
+                    format!("The patch is over {} bytes; export a bundle instead", MAX_PATCH_BYTES),
+                ));
+            }
+            ExportContent::Patch(diff.diff)
+        }
+        PatchFormat::Bundle => {
+            // git writes it as the session's user; it's read back through
+            // safe_path and unlinked right away
+            let path = format!("/tmp/.opensandbox-bundle-{}", uuid::Uuid::new_v4().simple());
+            git::bundle(backend, sandbox_root, config, &base, &path)
+                .map_err(internal)?
+                .map_err(failed)?;
+            let root = Root::open(sandbox_root).map_err(internal)?;
+            let file = root.open_file(Path::new(&path));
+            let _ = root.remove_all(Path::new(&path));
+            ExportContent::Bundle(file.map_err(internal)?)
+        }
+    };
+    Ok(Export {
+        source: "git",
+        omitted: 0,
+        content,
+    })
+}
+
+/// Patch of what the session changed under directory `dir` since it was
+/// created, with paths relative to `dir`.
+fn files_patch(sandbox_root: &Path, session_id: &str, dir: &str) -> Result<Export, (StatusCode, String)> {
+    let root = Root::open(sandbox_root).map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e))?;
+    if !root.metadata(Path::new(dir)).is_ok_and(|entry| entry.is_directory) {
+        return Err((StatusCode::BAD_REQUEST, format!("{} is not a directory", dir)));
+    }
+    let query = SessionChangesQuery {
+        path: Some(dir.to_string()),
+        hash: false,
+    };
+    let changed = changes::changes(sandbox_root, session_id, &query)?;
+    let originals: Vec<Root> = sandbox::original_layers(sandbox_root)
+        .iter()
+        .filter_map(|layer| Root::open(layer).ok())
+        .collect();
+
+    let (mut patch, mut omitted) = (String::new(), Vec::new());
+    for change in changed.changes {
+        // Git has no empty directories; the files in them carry the rest
+        if change.kind == "dir" {
+            continue;
+        }
+        let Some(rel) = below(dir, &change.path).filter(|rel| !rel.is_empty()) else {
+            continue;
+        };
+        let old = match change.change {
+            ChangeKind::Added => Side::Absent,
+            _ => original(&originals, &change.path),
+        };
+        match (old, side(&root, &change.path)) {
+            (Side::Absent, Side::Absent) => {}
+            (Side::Opaque, _) | (_, Side::Opaque) => omitted.push(rel.to_string()),
+            (old, new) => patch.push_str(&file_patch(rel, text(&old), text(&new))),
+        }
+        if patch.len() as u64 > MAX_PATCH_BYTES {
+            return Err((
+                StatusCode::UNPROCESSABLE_ENTITY,
+                format!("The patch is over {} bytes; export a narrower dir", MAX_PATCH_BYTES),
+            ));
+        }
+    }
+    if !omitted.is_empty() {
+        // Text before the first diff is skipped by git apply and patch
+        let mut preamble =
+            "Not in this patch (binary, over 8 MiB, or without an original to diff against):\n".to_string();
+        for rel in &omitted {
+            preamble.push_str(&format!("  {}\n", rel.trim_start_matches('/')));
+        }
+        preamble.push('\n');
+        patch.insert_str(0, &preamble);
+    }
+    Ok(Export {
+        source: "files",
+        omitted: omitted.len(),
+        content: ExportContent::Patch(patch),
+    })
+}
+
+/// What `path` was when the session started: in the topmost original layer
+/// that has it, or opaque if none does, as a root without layers has none.
+fn original(originals: &[Root], path: &str) -> Side {
+    originals
+        .iter()
+        .find(|layer| layer.symlink_metadata(Path::new(path)).is_ok())
+        .map_or(Side::Opaque, |layer| side(layer, path))
+}
+
+fn side(root: &Root, path: &str) -> Side {
+    let Ok(entry) = root.symlink_metadata(Path::new(path)) else {
+        return Side::Absent;
+    };
+    match entry.file_type() {
+        SFlag::S_IFLNK => match root.read_link(Path::new(path)) {
+            Ok(target) => Side::Text(SYMLINK_MODE, target),
+            Err(_) => Side::Opaque,
+        },
+        SFlag::S_IFREG if entry.size <= MAX_FILE_BYTES => {
+            let mut data = Vec::new();
+            let read = root
+                .open_file(Path::new(path))
+                .and_then(|file| file.take(MAX_FILE_BYTES + 1).read_to_end(&mut data).map_err(|e| e.to_string()));
+            let mode = if entry.mode & 0o111 != 0 { 0o100755 } else { 0o100644 };
+            match read.ok().and_then(|_| checkpoint::as_text(data)) {
+                Some(text) => Side::Text(mode, text),
+                None => Side::Opaque,
+            }
+        }
+        SFlag::S_IFREG => Side::Opaque,
+        // Directories, and whiteouts in an image's layers
+        _ => Side::Absent,
+    }
+}
+
+fn text(side: &Side) -> Option<(u32, &str)> {
+    match side {
+        Side::Text(mode, text) => Some((*mode, text.as_str())),
+        _ => None,
+    }
+}
+
+/// Git-style patch of `rel` going from `old` to `new`, each a mode and
+/// content, `None` for absent.
+fn file_patch(rel: &str, old: Option<(u32, &str)>, new: Option<(u32, &str)>) -> String {
+    let file_type = |mode: u32| mode & 0o170000;
+    if let (Some(o), Some(n)) = (old, new) {
+        // A file that became a symlink (or back) is a removal and an addition
+        if file_type(o.0) != file_type(n.0) {
+            return file_patch(rel, old, None) + &file_patch(rel, None, new);
+        }
+    }
+    let (a, b) = (format!("a{}", rel), format!("b{}", rel));
+    let mut patch = format!("diff --git {} {}\n", a, b);
+    match (old, new) {
+        (None, Some((mode, _))) => patch.push_str(&format!("new file mode {:o}\n", mode)),
+        (Some((mode, _)), None) => patch.push_str(&format!("deleted file mode {:o}\n", mode)),
+        (Some((old_mode, _)), Some((new_mode, _))) if old_mode != new_mode => {
+            patch.push_str(&format!("old mode {:o}\nnew mode {:o}\n", old_mode, new_mode))
+        }
+        _ => {}
+    }
+    let (old_text, new_text) = (old.map_or("", |o| o.1), new.map_or("", |n| n.1));
+    if old_text == new_text && old.is_some() && new.is_some() {
+        return if patch.lines().count() > 1 { patch } else { String::new() };
+    }
+    let hunks = TextDiff::from_lines(old_text, new_text)
+        .unified_diff()
+        .context_radius(3)
+        .header(
+            if old.is_some() { a.as_str() } else { "/dev/null" },
+            if new.is_some() { b.as_str() } else { "/dev/null" },
+        )
+        .to_string();
+    patch + &hunks
+}
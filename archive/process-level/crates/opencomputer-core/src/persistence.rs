@@ -0,0 +1,462 @@
+//! Session records kept in SQLite so sessions survive a server restart.
+//!
+//! Sandbox roots (tmpfs mounts under `/tmp`) and background processes outlive
+//! the server process; only the session table is lost. With a store enabled,
+//! every session is written through on create and on each change, and at
+//! startup [`AppState::enable_persistence`](crate::AppState::enable_persistence)
+//! reconciles the stored records with what is actually on disk:
+//!
+//! - a record whose sandbox root is still mounted is re-adopted, keeping only
+//!   background PIDs that are still running inside it;
+//! - a record whose root is gone is dropped;
+//! - a sandbox root with no record (including leftover warm-pool roots) is
+//!   unmounted and removed, after killing any process still inside it.
+//!
+//! With a [`StoreKey`] records are encrypted in the database. A record sealed
+//! with another key, or found without one, fails the load rather than being
+//! dropped, since dropping it would destroy its sandbox.
+
+use crate::auth::ApiKeys;
+use crate::cgroups::SessionLimits;
+use crate::egress::EgressPolicy;
+use crate::preview_auth::PreviewAuth;
+use crate::recurring::{ScheduleDefinition, SessionSchedule};
+use crate::reservation::Resources;
+use crate::sandbox::Determinism;
+use crate::secrets;
+use crate::state::{IdleReason, Session, SessionSshKey, SessionStatus, SessionToken, ShareAccess, ShareGrant};
+use crate::static_site::StaticSite;
+use crate::store_key::{self, StoreKey};
+use crate::tasks::TaskDefinition;
+use rusqlite::{params, Connection};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashMap};
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tracing::warn;
+
+/// Outcome of reconciling stored sessions at startup.
+#[derive(Debug, Default)]
+pub struct RestoreSummary {
+    /// Sessions re-adopted with their sandbox intact
+    pub adopted: usize,
+    /// Records dropped because their sandbox was gone or unreadable
+    pub dropped: usize,
+    /// Sandbox roots removed because no record claimed them
+    pub orphans_removed: usize,
+}
+
+/// SQLite database of live sessions.
+pub struct SessionStore {
+    conn: Mutex<Connection>,
+    key: Option<StoreKey>,
+}
+
+impl SessionStore {
+    /// Open or create the database at `path`, sealing records with `key` if
+    /// given. The file holds session tokens, secrets and preview credentials,
+    /// so it is made readable by the owner only.
+    pub fn open(path: &Path, key: Option<StoreKey>) -> Result<Self, String> {
+        if let Some(dir) = path.parent().filter(|d| !d.as_os_str().is_empty()) {
+            std::fs::create_dir_all(dir).map_err(|e| format!("mkdir {}: {}", dir.display(), e))?;
+        }
+        let conn = Connection::open(path).map_err(|e| format!("open {}: {}", path.display(), e))?;
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))
+            .map_err(|e| format!("chmod {}: {}", path.display(), e))?;
+        conn.execute_batch(
+            "PRAGMA journal_mode = WAL;
+             PRAGMA synchronous = NORMAL;
+             CREATE TABLE IF NOT EXISTS sessions (
+                 id TEXT PRIMARY KEY,
+                 record TEXT NOT NULL
+             );",
+        )
+        .map_err(|e| format!("init {}: {}", path.display(), e))?;
+        if key.is_some() {
+            // Pages freed by rewriting records, e.g. plaintext ones, are zeroed
+            conn.execute_batch("PRAGMA secure_delete = ON;")
+                .map_err(|e| format!("init {}: {}", path.display(), e))?;
+        }
+        Ok(Self {
+            conn: Mutex::new(conn),
+            key,
+        })
+    }
+
+    /// A record as stored: JSON, sealed if there's a key.
+    fn encode(&self, record: &SessionRecord) -> Result<String, String> {
+        let mut json = serde_json::to_string(record).map_err(|e| e.to_string())?;
+        let Some(ref key) = self.key else {
+            return Ok(json);
+        };
+        let sealed = key.seal(&record.id, json.as_bytes());
+        secrets::wipe(&mut json);
+        sealed.map_err(|e| format!("seal session {}: {}", record.id, e))
+    }
+
+    /// Parse a stored record. `Ok(None)` means it's damaged.
+    fn decode(&self, id: &str, stored: &str) -> Result<Option<SessionRecord>, String> {
+        if !store_key::is_sealed(stored) {
+            return Ok(serde_json::from_str(stored).ok());
+        }
+        let Some(ref key) = self.key else {
+            return Err(format!("session {} is encrypted, but no state key is configured", id));
+        };
+        if store_key::sealed_key_id(stored) != Some(key.id()) {
+            return Err(format!(
+                "session {} is encrypted with key {}, not the configured key {}",
+                id,
+                store_key::sealed_key_id(stored).unwrap_or_default(),
+                key.id()
+            ));
+        }
+        let Ok(mut json) = key.open(id, stored) else {
+            return Ok(None);
+        };
+        let record = serde_json::from_slice(&json).ok();
+        secrets::wipe_bytes(&mut json);
+        Ok(record)
+    }
+
+    /// Insert or replace a session's record.
+    pub fn save(&self, record: &SessionRecord) -> Result<(), String> {
+        let json = self.encode(record)?;
+        self.conn
+            .lock()
+            .unwrap()
+            .execute(
+                "INSERT OR REPLACE INTO sessions (id, record) VALUES (?1, ?2)",
+                params![record.id, json],
+            )
+            .map(|_| ())
+            .map_err(|e| format!("save session {}: {}", record.id, e))
+    }
+
+    /// Replace every record in one transaction.
+    pub fn save_all(&self, records: &[SessionRecord]) -> Result<(), String> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction().map_err(|e| e.to_string())?;
+        for record in records {
+            let json = self.encode(record)?;
+            tx.execute(
+                "INSERT OR REPLACE INTO sessions (id, record) VALUES (?1, ?2)",
+                params![record.id, json],
+            )
+            .map_err(|e| format!("save session {}: {}", record.id, e))?;
+        }
+        tx.commit().map_err(|e| e.to_string())
+    }
+
+    pub fn remove(&self, id: &str) -> Result<(), String> {
+        self.conn
+            .lock()
+            .unwrap()
+            .execute("DELETE FROM sessions WHERE id = ?1", params![id])
+            .map(|_| ())
+            .map_err(|e| format!("remove session {}: {}", id, e))
+    }
+
+    /// Every stored record. Rows that no longer parse or decrypt are skipped
+    /// and returned by ID so the caller can drop them; rows sealed with
+    /// another key fail the whole load.
+    pub fn load(&self) -> Result<(Vec<SessionRecord>, Vec<String>), String> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn
+            .prepare("SELECT id, record FROM sessions")
+            .map_err(|e| e.to_string())?;
+        let rows = stmt
+            .query_map([], |row| Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?)))
+            .map_err(|e| e.to_string())?;
+        let mut records = Vec::new();
+        let mut corrupt = Vec::new();
+        for row in rows {
+            let (id, stored) = row.map_err(|e| e.to_string())?;
+            match self.decode(&id, &stored)? {
+                Some(record) => records.push(record),
+                None => corrupt.push(id),
+            }
+        }
+        Ok((records, corrupt))
+    }
+}
+
+/// A [`Session`] in storable form: wall-clock times instead of `Instant`s and
+/// a fingerprint of the API key instead of the key itself.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SessionRecord {
+    pub id: String,
+    pub sandbox_root: PathBuf,
+    pub env: HashMap<String, String>,
+    pub cwd: String,
+    /// Unix time in milliseconds
+    pub created_at_ms: u64,
+    pub last_used_ms: u64,
+    #[serde(default)]
+    pub last_activity_ms: Option<(u64, IdleReason)>,
+    pub ttl_secs: u64,
+    pub preview_url: Option<String>,
+    pub ports: Vec<u16>,
+    pub status: SessionStatus,
+    pub background_pids: Vec<u32>,
+    pub preview_auth: Option<PreviewAuth>,
+    pub slug: Option<String>,
+    pub template: Option<String>,
+    #[serde(default)]
+    pub image: Option<String>,
+    pub labels: HashMap<String, String>,
+    pub determinism: Determinism,
+    /// SHA-256 of the key the session was created with
+    pub api_key_sha256: Option<String>,
+    /// The session's org; for a key without `org_id`, which is its own org,
+    /// the key's SHA-256 like `api_key_sha256`
+    pub org_id: Option<String>,
+    pub resources: Option<Resources>,
+    pub tokens: Vec<TokenRecord>,
+    #[serde(default)]
+    pub secrets: HashMap<String, String>,
+    #[serde(default)]
+    pub egress: Option<EgressPolicy>,
+    #[serde(default)]
+    pub ssh_keys: Vec<SshKeyRecord>,
+    #[serde(default)]
+    pub trash_ttl_secs: Option<u64>,
+    #[serde(default)]
+    pub tasks: BTreeMap<String, TaskDefinition>,
+    #[serde(default)]
+    pub schedules: BTreeMap<String, ScheduleDefinition>,
+    #[serde(default)]
+    pub detected_ports: Vec<u16>,
+    #[serde(default)]
+    pub static_site: Option<StaticSite>,
+    #[serde(default)]
+    pub limits: Option<SessionLimits>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TokenRecord {
+    pub token: String,
+    pub scopes: Vec<String>,
+    pub expires_at_ms: Option<u64>,
+    #[serde(default)]
+    pub share: Option<ShareRecord>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ShareRecord {
+    pub id: String,
+    pub access: ShareAccess,
+    pub label: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SshKeyRecord {
+    pub id: String,
+    pub public_key: String,
+    pub fingerprint: String,
+    pub expires_at_ms: Option<u64>,
+}
+
+impl SessionRecord {
+    pub fn from_session(session: &Session) -> Self {
+        let clock = Clock::now();
+        Self {
+            id: session.id.clone(),
+            sandbox_root: session.sandbox_root.clone(),
+            env: session.env.clone(),
+            cwd: session.cwd.clone(),
+            created_at_ms: clock.to_wall(session.created_at),
+            last_used_ms: clock.to_wall(session.last_used),
+            last_activity_ms: session.last_activity.map(|(at, reason)| (clock.to_wall(at), reason)),
+            ttl_secs: session.ttl.as_secs(),
+            preview_url: session.preview_url.clone(),
+            ports: session.ports.clone(),
+            detected_ports: session.detected_ports.clone(),
+            static_site: session.static_site.clone(),
+            status: session.status,
+            background_pids: session.background_pids.clone(),
+            preview_auth: session.preview_auth.clone(),
+            slug: session.slug.clone(),
+            template: session.template.clone(),
+            image: session.image.clone(),
+            labels: session.labels.clone(),
+            determinism: session.determinism.clone(),
+            api_key_sha256: session.api_key.as_ref().map(|key| key_fingerprint(&key.key)),
+            org_id: session.org_id.as_deref().map(|org| match session.api_key {
+                Some(ref key) if key.org_id.is_none() && key.key == org => key_fingerprint(org),
+                _ => org.to_string(),
+            }),
+            resources: session.resources,
+            limits: session.limits,
+            tokens: session
+                .tokens
+                .iter()
+                .map(|(token, t)| TokenRecord {
+                    token: token.clone(),
+                    scopes: t.key.scopes.clone().unwrap_or_default(),
+                    expires_at_ms: t.expires_at.map(|at| clock.to_wall(at)),
+                    share: t.share.as_ref().map(|share| ShareRecord {
+                        id: share.id.clone(),
+                        access: share.access,
+                        label: share.label.clone(),
+                    }),
+                })
+                .collect(),
+            secrets: session.secrets.clone(),
+            egress: session.egress.clone(),
+            ssh_keys: session
+                .ssh_keys
+                .iter()
+                .map(|k| SshKeyRecord {
+                    id: k.id.clone(),
+                    public_key: k.public_key.clone(),
+                    fingerprint: k.fingerprint.clone(),
+                    expires_at_ms: k.expires_at.map(|at| clock.to_wall(at)),
+                })
+                .collect(),
+            trash_ttl_secs: session.trash_ttl.map(|ttl| ttl.as_secs()),
+            tasks: session.tasks.clone(),
+            schedules: session
+                .schedules
+                .iter()
+                .map(|(id, schedule)| (id.clone(), schedule.definition.clone()))
+                .collect(),
+        }
+    }
+
+    /// Rebuild the session. The creating key is looked up among the
+    /// currently configured keys; if it was removed the session keeps its
+    /// org but loses the key and its tokens. A key that was its own org gets
+    /// its sessions back; once removed, nobody else's key matches them.
+    pub fn into_session(self, api_keys: &ApiKeys) -> Session {
+        let clock = Clock::now();
+        let api_key = self
+            .api_key_sha256
+            .as_deref()
+            .and_then(|fingerprint| api_keys.all().into_iter().find(|k| key_fingerprint(&k.key) == fingerprint));
+        let tokens = match api_key {
+            Some(ref parent) => self
+                .tokens
+                .into_iter()
+                .map(|t| {
+                    let key = parent.session_token(t.token.clone(), &self.id, t.scopes);
+                    let token = SessionToken {
+                        key: Arc::new(key),
+                        expires_at: t.expires_at_ms.map(|ms| clock.to_instant(ms)),
+                        share: t.share.map(|share| ShareGrant {
+                            id: share.id,
+                            access: share.access,
+                            label: share.label,
+                        }),
+                    };
+                    (t.token, token)
+                })
+                .collect(),
+            None => HashMap::new(),
+        };
+        let schedules = self
+            .schedules
+            .into_iter()
+            .filter_map(|(id, definition)| match SessionSchedule::new(definition, None) {
+                Ok(schedule) => Some((id, schedule)),
+                Err(e) => {
+                    warn!("Dropping schedule {} of session {}: {}", id, self.id, e);
+                    None
+                }
+            })
+            .collect();
+        Session {
+            id: self.id,
+            sandbox_root: self.sandbox_root,
+            env: self.env,
+            cwd: self.cwd,
+            created_at: clock.to_instant(self.created_at_ms),
+            last_used: clock.to_instant(self.last_used_ms),
+            last_activity: self.last_activity_ms.map(|(ms, reason)| (clock.to_instant(ms), reason)),
+            ttl: Duration::from_secs(self.ttl_secs),
+            preview_url: self.preview_url,
+            ports: self.ports,
+            detected_ports: self.detected_ports,
+            static_site: self.static_site,
+            status: self.status,
+            background_pids: self.background_pids,
+            preview_auth: self.preview_auth,
+            slug: self.slug,
+            template: self.template,
+            image: self.image,
+            labels: self.labels,
+            determinism: self.determinism,
+            org_id: match (self.org_id, &api_key) {
+                (Some(org), Some(key)) if key.org_id.is_none() && org == key_fingerprint(&key.key) => {
+                    Some(key.key.clone())
+                }
+                (org, _) => org,
+            },
+            api_key,
+            resources: self.resources,
+            limits: self.limits,
+            tokens,
+            secrets: self.secrets,
+            egress: self.egress,
+            ssh_keys: self
+                .ssh_keys
+                .into_iter()
+                .map(|k| SessionSshKey {
+                    id: k.id,
+                    public_key: k.public_key,
+                    fingerprint: k.fingerprint,
+                    expires_at: k.expires_at_ms.map(|ms| clock.to_instant(ms)),
+                })
+                .collect(),
+            trash_ttl: self.trash_ttl_secs.map(Duration::from_secs),
+            tasks: self.tasks,
+            schedules,
+        }
+    }
+}
+
+fn key_fingerprint(key: &str) -> String {
+    hex::encode(Sha256::digest(key.as_bytes()))
+}
+
+/// A matching pair of monotonic and wall-clock readings for converting
+/// between the two.
+struct Clock {
+    instant: Instant,
+    wall_ms: u64,
+}
+
+impl Clock {
+    fn now() -> Self {
+        Self {
+            instant: Instant::now(),
+            wall_ms: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_millis() as u64)
+                .unwrap_or(0),
+        }
+    }
+
+    fn to_wall(&self, at: Instant) -> u64 {
+        if at <= self.instant {
+            self.wall_ms
+                .saturating_sub(self.instant.duration_since(at).as_millis() as u64)
+        } else {
+            self.wall_ms + at.duration_since(self.instant).as_millis() as u64
+        }
+    }
+
+    /// Times before the monotonic clock's origin (e.g. before a reboot)
+    /// clamp to now.
+    fn to_instant(&self, wall_ms: u64) -> Instant {
+        if wall_ms <= self.wall_ms {
+            self.instant
+                .checked_sub(Duration::from_millis(self.wall_ms - wall_ms))
+                .unwrap_or(self.instant)
+        } else {
+            self.instant + Duration::from_millis(wall_ms - self.wall_ms)
+        }
+    }
+}
@@ -0,0 +1,157 @@
+//! Warm pool of pre-created sandbox roots.
+//!
+//! Creating a sandbox root (tmpfs + bind mounts + /proc) dominates session
+//! creation latency. The pool keeps `target` roots ready in the background;
+//! `create_session` takes one when available and the pool refills
+//! asynchronously.
+
+use crate::backend::{ChrootBackend, SandboxBackend};
+use std::collections::VecDeque;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use tokio::sync::{Mutex, Notify};
+use tracing::{info, warn};
+
+pub use opencomputer_types::PoolStats;
+
+/// Delay before retrying after a failed sandbox creation.
+const REFILL_RETRY_SECS: u64 = 5;
+
+/// Pre-created sandbox roots waiting to be handed to new sessions.
+pub struct WarmPool {
+    target: usize,
+    backend: Arc<dyn SandboxBackend>,
+    ready: Mutex<VecDeque<PathBuf>>,
+    refill: Notify,
+    draining: AtomicBool,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    created: AtomicU64,
+    failures: AtomicU64,
+}
+
+impl Default for WarmPool {
+    /// A disabled pool.
+    fn default() -> Self {
+        Self::new(0, Arc::new(ChrootBackend))
+    }
+}
+
+impl WarmPool {
+    /// A pool that keeps `target` roots ready, created by `backend`. Call
+    /// [`WarmPool::start`] to fill it.
+    pub fn new(target: usize, backend: Arc<dyn SandboxBackend>) -> Self {
+        Self {
+            target,
+            backend,
+            ready: Mutex::default(),
+            refill: Notify::new(),
+            draining: AtomicBool::default(),
+            hits: AtomicU64::default(),
+            misses: AtomicU64::default(),
+            created: AtomicU64::default(),
+            failures: AtomicU64::default(),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.target > 0
+    }
+
+    /// Spawn the background refill task. Requires a Tokio runtime.
+    pub fn start(self: &Arc<Self>) {
+        if !self.is_enabled() {
+            return;
+        }
+        let pool = self.clone();
+        tokio::spawn(async move { pool.refill_loop().await });
+    }
+
+    /// Take a ready sandbox root, if any, and trigger a refill.
+    pub async fn take(&self) -> Option<PathBuf> {
+        if !self.is_enabled() || self.draining.load(Ordering::Relaxed) {
+            return None;
+        }
+        let root = self.ready.lock().await.pop_front();
+        match root {
+            Some(_) => self.hits.fetch_add(1, Ordering::Relaxed),
+            None => self.misses.fetch_add(1, Ordering::Relaxed),
+        };
+        self.refill.notify_one();
+        root
+    }
+
+    /// Stop refilling and destroy every pooled root. Used on shutdown.
+    pub async fn drain(&self) {
+        self.draining.store(true, Ordering::Relaxed);
+        self.refill.notify_one();
+        let roots: Vec<PathBuf> = self.ready.lock().await.drain(..).collect();
+        if roots.is_empty() {
+            return;
+        }
+        info!("Draining {} pooled sandboxes", roots.len());
+        let backend = self.backend.clone();
+        let _ = tokio::task::spawn_blocking(move || {
+            for root in roots {
+                backend.destroy(&root);
+            }
+        })
+        .await;
+    }
+
+    /// Roots a create could take right now.
+    pub async fn available(&self) -> usize {
+        if self.draining.load(Ordering::Relaxed) {
+            return 0;
+        }
+        self.ready.lock().await.len()
+    }
+
+    pub async fn stats(&self) -> PoolStats {
+        PoolStats {
+            target: self.target,
+            available: self.ready.lock().await.len(),
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            created: self.created.load(Ordering::Relaxed),
+            failures: self.failures.load(Ordering::Relaxed),
+            draining: self.draining.load(Ordering::Relaxed),
+        }
+    }
+
+    async fn refill_loop(self: Arc<Self>) {
+        info!("Warm pool enabled: target {} sandboxes", self.target);
+        loop {
+            if self.draining.load(Ordering::Relaxed) {
+                return;
+            }
+            if self.ready.lock().await.len() >= self.target {
+                self.refill.notified().await;
+                continue;
+            }
+            let backend = self.backend.clone();
+            let created = tokio::task::spawn_blocking(move || backend.create_pooled()).await;
+            match created {
+                Ok(Ok(root)) => {
+                    self.created.fetch_add(1, Ordering::Relaxed);
+                    if self.draining.load(Ordering::Relaxed) {
+                        let backend = self.backend.clone();
+                        let _ = tokio::task::spawn_blocking(move || backend.destroy(&root)).await;
+                        return;
+                    }
+                    self.ready.lock().await.push_back(root);
+                }
+                Ok(Err(e)) => {
+                    self.failures.fetch_add(1, Ordering::Relaxed);
+                    warn!("Warm pool: failed to create sandbox: {}", e);
+                    tokio::time::sleep(std::time::Duration::from_secs(REFILL_RETRY_SECS)).await;
+                }
+                Err(e) => {
+                    self.failures.fetch_add(1, Ordering::Relaxed);
+                    warn!("Warm pool: create task failed: {}", e);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,34 @@
+//! Ports a session's processes listen on without having been started
+//! through `/background`, e.g. a dev server run with a plain `/run` or from
+//! an SSH shell. Every [`SCAN_INTERVAL`] they're attached to their session,
+//! so it gets a working preview, and detached again once nothing in the
+//! session listens on them. Ports registered by `/background` are left to it.
+
+use crate::sandbox::{self, ProcessRoots};
+use std::collections::{BTreeSet, HashMap};
+use std::fs;
+use std::path::PathBuf;
+use std::time::Duration;
+
+/// Time between scans.
+pub const SCAN_INTERVAL: Duration = Duration::from_secs(2);
+
+/// The ports each of `roots` has a process listening on (possibly none), in
+/// one pass over `/proc`.
+pub fn listening_ports(roots: &[PathBuf]) -> HashMap<PathBuf, BTreeSet<u16>> {
+    let mut pids: HashMap<&PathBuf, Vec<u32>> = roots.iter().map(|root| (root, Vec::new())).collect();
+    if let Ok(entries) = fs::read_dir("/proc") {
+        let process_roots = ProcessRoots::new(roots);
+        for pid in entries
+            .flatten()
+            .filter_map(|e| e.file_name().to_str().and_then(|n| n.parse::<u32>().ok()))
+        {
+            if let Some(root) = process_roots.of(pid) {
+                pids.entry(root).or_default().push(pid);
+            }
+        }
+    }
+    pids.into_iter()
+        .map(|(root, pids)| (root.clone(), sandbox::listening_ports(&pids)))
+        .collect()
+}
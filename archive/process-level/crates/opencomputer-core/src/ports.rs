@@ -0,0 +1,113 @@
+//! Host ports for background processes that ask for `port: 0`.
+//!
+//! Ports come from a fixed range below the kernel's ephemeral range. Released
+//! ports go on a free list and are handed out again, oldest first, before
+//! untouched ones. Every candidate is test-bound first, so a port some other
+//! process is listening on is skipped rather than assigned.
+
+use std::collections::{HashSet, VecDeque};
+use std::net::TcpListener;
+use std::sync::Mutex;
+
+pub use opencomputer_types::PortStats;
+
+/// First port handed out.
+pub const PORT_RANGE_START: u16 = 10000;
+
+/// Last port handed out; the kernel's ephemeral range starts above it.
+pub const PORT_RANGE_END: u16 = 32767;
+
+pub struct PortAllocator {
+    start: u16,
+    end: u16,
+    inner: Mutex<Inner>,
+}
+
+struct Inner {
+    /// Next never-used port; past `end` once the range has been walked
+    next: u32,
+    /// Released (or busy when tried) ports, oldest first
+    free: VecDeque<u16>,
+    in_use: HashSet<u16>,
+}
+
+impl Default for PortAllocator {
+    fn default() -> Self {
+        Self::new(PORT_RANGE_START, PORT_RANGE_END)
+    }
+}
+
+impl PortAllocator {
+    pub fn new(start: u16, end: u16) -> Self {
+        Self {
+            start,
+            end,
+            inner: Mutex::new(Inner {
+                next: start as u32,
+                free: VecDeque::new(),
+                in_use: HashSet::new(),
+            }),
+        }
+    }
+
+    /// Assign a free port, or `None` if every port in the range is taken.
+    pub fn allocate(&self) -> Option<u16> {
+        let mut inner = self.inner.lock().unwrap();
+        for _ in 0..inner.free.len() {
+            let port = inner.free.pop_front()?;
+            if inner.in_use.contains(&port) {
+                continue;
+            }
+            if is_available(port) {
+                inner.in_use.insert(port);
+                return Some(port);
+            }
+            inner.free.push_back(port);
+        }
+        while inner.next <= self.end as u32 {
+            let port = inner.next as u16;
+            inner.next += 1;
+            if inner.in_use.contains(&port) {
+                continue;
+            }
+            if is_available(port) {
+                inner.in_use.insert(port);
+                return Some(port);
+            }
+            inner.free.push_back(port);
+        }
+        None
+    }
+
+    /// Mark a port chosen elsewhere (a caller-supplied port, or one held by a
+    /// restored session) as taken. Ports outside the range are ignored.
+    pub fn claim(&self, port: u16) {
+        if (self.start..=self.end).contains(&port) {
+            self.inner.lock().unwrap().in_use.insert(port);
+        }
+    }
+
+    pub fn stats(&self) -> PortStats {
+        let inner = self.inner.lock().unwrap();
+        PortStats {
+            range_start: self.start,
+            range_end: self.end,
+            in_use: inner.in_use.len(),
+            free: inner.free.len(),
+            untouched: (self.end as u32 + 1).saturating_sub(inner.next) as usize,
+        }
+    }
+
+    /// Return a port to the pool. Releasing a port that isn't taken is a no-op.
+    pub fn release(&self, port: u16) {
+        let mut inner = self.inner.lock().unwrap();
+        if inner.in_use.remove(&port) {
+            inner.free.push_back(port);
+        }
+    }
+}
+
+/// Whether nothing is listening on `port` on any interface.
+pub fn is_available(port: u16) -> bool {
+    TcpListener::bind(("0.0.0.0", port)).is_ok()
+}
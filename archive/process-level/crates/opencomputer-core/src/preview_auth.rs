@@ -0,0 +1,257 @@
+//! Optional authentication for preview URLs.
+//!
+//! A session may require preview visitors to present a bearer token, HTTP
+//! basic credentials, or a signed cookie. The cookie mode is browser-friendly:
+//! the first visit carries `?oc_token=<token>`, which is exchanged for a signed
+//! `oc_preview` cookie and a redirect to the same URL without the token.
+
+use axum::http::{header, HeaderMap, StatusCode};
+use axum::response::{IntoResponse, Response};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+pub use opencomputer_types::PreviewAuthRequest;
+
+/// Query parameter used to exchange a token for a preview cookie.
+pub const TOKEN_QUERY_PARAM: &str = "oc_token";
+
+/// Name of the signed preview cookie.
+pub const COOKIE_NAME: &str = "oc_preview";
+
+/// Lifetime of a signed preview cookie in seconds (24 hours).
+const COOKIE_TTL_SECS: u64 = 86400;
+
+/// Preview auth policy stored on a session.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "mode", rename_all = "lowercase")]
+pub enum PreviewAuth {
+    Bearer { token: String },
+    Basic { username: String, password: String },
+    Cookie { token: String },
+}
+
+impl PreviewAuth {
+    /// Build the stored policy, generating tokens where the caller didn't supply one.
+    pub fn from_request(req: PreviewAuthRequest) -> Result<Self, String> {
+        let auth = match req {
+            PreviewAuthRequest::Bearer { token } => PreviewAuth::Bearer {
+                token: token.unwrap_or_else(generate_token),
+            },
+            PreviewAuthRequest::Basic { username, password } => {
+                if username.is_empty() || username.contains(':') {
+                    return Err("preview_auth.username must be non-empty and contain no ':'".to_string());
+                }
+                PreviewAuth::Basic { username, password }
+            }
+            PreviewAuthRequest::Cookie { token } => PreviewAuth::Cookie {
+                token: token.unwrap_or_else(generate_token),
+            },
+        };
+        if let Some(token) = auth.token() {
+            if token.is_empty() {
+                return Err("preview_auth.token must not be empty".to_string());
+            }
+        }
+        Ok(auth)
+    }
+
+    /// Mode name as reported in session info.
+    pub fn mode(&self) -> &'static str {
+        match self {
+            PreviewAuth::Bearer { .. } => "bearer",
+            PreviewAuth::Basic { .. } => "basic",
+            PreviewAuth::Cookie { .. } => "cookie",
+        }
+    }
+
+    /// The token a client presents, for modes that use one.
+    pub fn token(&self) -> Option<&str> {
+        match self {
+            PreviewAuth::Bearer { token } | PreviewAuth::Cookie { token } => Some(token),
+            PreviewAuth::Basic { .. } => None,
+        }
+    }
+}
+
+/// Outcome of checking a preview request against a session's policy.
+pub enum PreviewAuthOutcome {
+    /// Forward the request to the sandbox.
+    Allowed,
+    /// Respond directly (auth challenge, or cookie issuance redirect).
+    Respond(Response),
+}
+
+/// Check a preview request against the session's auth policy. An issued
+/// cookie is scoped to `cookie_path`: `/` on a preview domain, the preview's
+/// prefix for a path-based one, whose host other sessions share.
+pub fn check(
+    auth: &PreviewAuth,
+    session_id: &str,
+    signing_key: &[u8],
+    headers: &HeaderMap,
+    path: &str,
+    query: Option<&str>,
+    cookie_path: &str,
+) -> PreviewAuthOutcome {
+    match auth {
+        PreviewAuth::Bearer { token } => {
+            let presented = header_str(headers, header::AUTHORIZATION)
+                .and_then(|v| v.strip_prefix("Bearer "))
+                .map(str::trim);
+            match presented {
+                Some(p) if constant_time_eq(p.as_bytes(), token.as_bytes()) => PreviewAuthOutcome::Allowed,
+                _ => unauthorized("Bearer realm=\"preview\""),
+            }
+        }
+        PreviewAuth::Basic { username, password } => {
+            let decoded = header_str(headers, header::AUTHORIZATION)
+                .and_then(|v| v.strip_prefix("Basic "))
+                .and_then(|b| BASE64.decode(b.trim()).ok())
+                .and_then(|b| String::from_utf8(b).ok());
+            let expected = format!("{}:{}", username, password);
+            match decoded {
+                Some(d) if constant_time_eq(d.as_bytes(), expected.as_bytes()) => PreviewAuthOutcome::Allowed,
+                _ => unauthorized("Basic realm=\"preview\""),
+            }
+        }
+        PreviewAuth::Cookie { token } => {
+            if let Some(value) = cookie_value(headers, COOKIE_NAME) {
+                if verify_cookie(signing_key, session_id, value) {
+                    return PreviewAuthOutcome::Allowed;
+                }
+            }
+            // Exchange a valid ?oc_token= for a signed cookie
+            let (presented, remaining_query) = split_token_param(query);
+            match presented {
+                Some(p) if constant_time_eq(p.as_bytes(), token.as_bytes()) => {
+                    let location = match remaining_query {
+                        Some(q) => format!("{}?{}", path, q),
+                        None => path.to_string(),
+                    };
+                    let cookie = format!(
+                        "{}={}; Path={}; Max-Age={}; HttpOnly; Secure; SameSite=Lax",
+                        COOKIE_NAME,
+                        sign_cookie(signing_key, session_id),
+                        cookie_path,
+                        COOKIE_TTL_SECS
+                    );
+                    PreviewAuthOutcome::Respond(
+                        (
+                            StatusCode::SEE_OTHER,
+                            [(header::LOCATION, location), (header::SET_COOKIE, cookie)],
+                        )
+                            .into_response(),
+                    )
+                }
+                _ => PreviewAuthOutcome::Respond(
+                    (StatusCode::UNAUTHORIZED, "Preview requires a valid token").into_response(),
+                ),
+            }
+        }
+    }
+}
+
+/// Generate a random URL-safe token.
+pub fn generate_token() -> String {
+    format!(
+        "{}{}",
+        uuid::Uuid::new_v4().simple(),
+        uuid::Uuid::new_v4().simple()
+    )
+}
+
+/// Generate a random key for signing preview cookies.
+pub fn generate_signing_key() -> Vec<u8> {
+    let mut key = Vec::with_capacity(32);
+    key.extend_from_slice(uuid::Uuid::new_v4().as_bytes());
+    key.extend_from_slice(uuid::Uuid::new_v4().as_bytes());
+    key
+}
+
+fn unauthorized(challenge: &'static str) -> PreviewAuthOutcome {
+    PreviewAuthOutcome::Respond(
+        (
+            StatusCode::UNAUTHORIZED,
+            [(header::WWW_AUTHENTICATE, challenge)],
+            "Preview requires authentication",
+        )
+            .into_response(),
+    )
+}
+
+fn header_str(headers: &HeaderMap, name: header::HeaderName) -> Option<&str> {
+    headers.get(name).and_then(|v| v.to_str().ok())
+}
+
+fn cookie_value<'a>(headers: &'a HeaderMap, name: &str) -> Option<&'a str> {
+    headers
+        .get_all(header::COOKIE)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(';'))
+        .filter_map(|pair| pair.trim().split_once('='))
+        .find(|(k, _)| *k == name)
+        .map(|(_, v)| v)
+}
+
+/// Pull `oc_token` out of a query string, returning it and the rest of the query.
+fn split_token_param(query: Option<&str>) -> (Option<&str>, Option<String>) {
+    let Some(query) = query else {
+        return (None, None);
+    };
+    let mut token = None;
+    let mut rest = Vec::new();
+    for pair in query.split('&') {
+        match pair.split_once('=') {
+            Some((k, v)) if k == TOKEN_QUERY_PARAM => token = Some(v),
+            _ => rest.push(pair),
+        }
+    }
+    let rest = if rest.is_empty() { None } else { Some(rest.join("&")) };
+    (token, rest)
+}
+
+fn cookie_mac(signing_key: &[u8], session_id: &str, expires: u64) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(signing_key).expect("HMAC accepts any key length");
+    mac.update(session_id.as_bytes());
+    mac.update(b"|");
+    mac.update(expires.to_string().as_bytes());
+    mac
+}
+
+/// Cookie value format: `{expires_unix}.{hex hmac}`.
+fn sign_cookie(signing_key: &[u8], session_id: &str) -> String {
+    let expires = unix_now() + COOKIE_TTL_SECS;
+    let sig = cookie_mac(signing_key, session_id, expires).finalize().into_bytes();
+    format!("{}.{}", expires, hex::encode(sig))
+}
+
+fn verify_cookie(signing_key: &[u8], session_id: &str, value: &str) -> bool {
+    let Some((expires, sig)) = value.split_once('.') else {
+        return false;
+    };
+    let (Ok(expires), Ok(sig)) = (expires.parse::<u64>(), hex::decode(sig)) else {
+        return false;
+    };
+    if expires < unix_now() {
+        return false;
+    }
+    cookie_mac(signing_key, session_id, expires).verify_slice(&sig).is_ok()
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+pub(crate) fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
@@ -0,0 +1,89 @@
+//! Path-based previews, `/preview/:session/:port/*path` on the API server,
+//! for deployments that can't point a wildcard DNS record at it.
+//!
+//! An app served under a path prefix doesn't know about it, so its pages
+//! would point at the server root. The proxy asks the app for uncompressed
+//! responses and prefixes root-relative `src`, `href` and `action`
+//! attributes in HTML (a `<base href="/">` among them) and root-relative
+//! redirects. Relative URLs need nothing: the page's own URL has the prefix.
+//! Requests that still miss, such as a script's `fetch("/api")`, reach the
+//! server root with a `Referer` under a prefix and are redirected there.
+
+use axum::http::{header, HeaderMap};
+
+/// Where path-based previews live.
+pub const PATH_PREFIX: &str = "/preview";
+
+/// Stands for the port in the path of a session's static site.
+pub const STATIC_SITE: &str = "static";
+
+/// Attributes whose root-relative URLs are prefixed.
+const URL_ATTRIBUTES: &[&str] = &["src", "href", "action"];
+
+/// Path prefix of the preview of a session's port (or [`STATIC_SITE`]), with
+/// a trailing slash. `label` is the session ID or its slug.
+pub fn prefix(label: &str, port: impl std::fmt::Display) -> String {
+    format!("{}/{}/{}/", PATH_PREFIX, label, port)
+}
+
+/// The preview prefix of the page a request came from, per its `Referer`.
+pub fn referer_prefix(headers: &HeaderMap) -> Option<String> {
+    let referer = headers.get(header::REFERER)?.to_str().ok()?;
+    // The path of an absolute URL
+    let path = match referer.split_once("://") {
+        Some((_, rest)) => &rest[rest.find('/')?..],
+        None => referer,
+    };
+    let mut parts = path.strip_prefix(PATH_PREFIX)?.strip_prefix('/')?.splitn(3, '/');
+    let label = parts.next().filter(|label| !label.is_empty())?;
+    let port = parts.next().filter(|port| *port == STATIC_SITE || port.parse::<u16>().is_ok())?;
+    Some(prefix(label, port))
+}
+
+/// A redirect target of the app, prefixed if it is root-relative.
+pub fn rewrite_location(location: &str, prefix: &str) -> Option<String> {
+    if !location.starts_with('/') || location.starts_with("//") || location.starts_with(prefix) {
+        return None;
+    }
+    Some(format!("{}{}", prefix.trim_end_matches('/'), location))
+}
+
+/// `html` with the root-relative URLs of [`URL_ATTRIBUTES`] under `prefix`.
+pub fn rewrite_html(html: &str, prefix: &str) -> String {
+    // ASCII lowercasing keeps byte offsets, so positions carry over
+    let lower = html.to_ascii_lowercase();
+    let root = prefix.trim_end_matches('/');
+    let mut out = String::with_capacity(html.len());
+    let mut copied = 0;
+    for (eq, _) in lower.match_indices('=') {
+        let Some(at) = root_relative_value(&lower, eq) else {
+            continue;
+        };
+        if html[at..].starts_with(prefix) {
+            continue;
+        }
+        out.push_str(&html[copied..at]);
+        out.push_str(root);
+        copied = at;
+    }
+    out.push_str(&html[copied..]);
+    out
+}
+
+/// Where the value of a URL attribute whose `=` is at `eq` starts, past
+/// any quote, if it is root-relative (`/path`, not `//host/path`).
+fn root_relative_value(lower: &str, eq: usize) -> Option<usize> {
+    let name = lower[..eq].trim_end();
+    let attribute = URL_ATTRIBUTES.iter().find(|attr| name.ends_with(*attr))?;
+    let before = name[..name.len() - attribute.len()].chars().next_back()?;
+    if !before.is_ascii_whitespace() {
+        return None;
+    }
+    let value = lower[eq + 1..].trim_start();
+    let value_at = lower.len() - value.len();
+    let (value, value_at) = match value.strip_prefix(['"', '\'']) {
+        Some(unquoted) => (unquoted, value_at + 1),
+        None => (value, value_at),
+    };
+    (value.starts_with('/') && !value.starts_with("//")).then_some(value_at)
+}
@@ -0,0 +1,199 @@
+//! Progress events for long-running filesystem operations.
+//!
+//! Template registration (archive extraction or directory copy), replay
+//! bundle restore, app uploads for `POST /run-preview` and package installs
+//! report their stages and bytes/files processed through a [`ProgressHub`].
+//! `GET /events` streams them to clients as server-sent events so a
+//! multi-second operation isn't a silent hang. Each operation is tagged
+//! with the org that started it, and keyed subscribers only see their own.
+
+use std::io::Read;
+use std::time::{Duration, Instant};
+use tokio::sync::broadcast;
+
+pub use opencomputer_types::ProgressEvent;
+
+/// Events buffered per subscriber before slow ones start missing updates.
+const CHANNEL_CAPACITY: usize = 1024;
+
+/// Minimum time between updates for one operation (start, finish and
+/// whole-percent steps are always sent).
+const EMIT_INTERVAL: Duration = Duration::from_millis(250);
+
+/// A progress event with the org of the caller that started the operation.
+#[derive(Debug, Clone)]
+pub struct ProgressUpdate {
+    /// `None` for operations started without an API key.
+    pub org_id: Option<String>,
+    pub event: ProgressEvent,
+}
+
+/// Fan-out of progress events to `GET /events` subscribers.
+#[derive(Clone)]
+pub struct ProgressHub {
+    tx: broadcast::Sender<ProgressUpdate>,
+}
+
+impl Default for ProgressHub {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ProgressHub {
+    pub fn new() -> Self {
+        let (tx, _) = broadcast::channel(CHANNEL_CAPACITY);
+        Self { tx }
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<ProgressUpdate> {
+        self.tx.subscribe()
+    }
+
+    /// Start reporting an operation. Nothing is sent until the first update.
+    pub fn start(&self, operation: &'static str) -> Progress {
+        Progress::new(Some(self.tx.clone()), operation)
+    }
+}
+
+/// Progress of one operation. Updated from the blocking thread doing the work.
+pub struct Progress {
+    tx: Option<broadcast::Sender<ProgressUpdate>>,
+    org_id: Option<String>,
+    event: ProgressEvent,
+    last_emit: Option<Instant>,
+    last_percent: Option<u64>,
+}
+
+impl Progress {
+    fn new(tx: Option<broadcast::Sender<ProgressUpdate>>, operation: &'static str) -> Self {
+        Self {
+            tx,
+            org_id: None,
+            event: ProgressEvent {
+                operation_id: uuid::Uuid::new_v4().to_string(),
+                operation: operation.to_string(),
+                stage: String::new(),
+                template: None,
+                session_id: None,
+                bytes: 0,
+                total_bytes: None,
+                files: 0,
+                total_files: None,
+                percent: None,
+                done: false,
+                error: None,
+            },
+            last_emit: None,
+            last_percent: None,
+        }
+    }
+
+    /// A reporter that sends nothing, for callers nobody is watching.
+    pub fn disabled() -> Self {
+        Self::new(None, "")
+    }
+
+    /// Tag the operation with the org of the caller that started it.
+    pub fn org(mut self, org_id: Option<&str>) -> Self {
+        self.org_id = org_id.map(str::to_string);
+        self
+    }
+
+    pub fn template(mut self, name: &str) -> Self {
+        self.event.template = Some(name.to_string());
+        self
+    }
+
+    pub fn session(mut self, session_id: &str) -> Self {
+        self.event.session_id = Some(session_id.to_string());
+        self
+    }
+
+    /// Start a stage with fresh counters. Sends an update right away.
+    pub fn begin_stage(&mut self, stage: &'static str, total_bytes: Option<u64>, total_files: Option<u64>) {
+        self.event.stage = stage.to_string();
+        self.event.bytes = 0;
+        self.event.files = 0;
+        self.event.total_bytes = total_bytes;
+        self.event.total_files = total_files;
+        self.emit(true);
+    }
+
+    pub fn add_bytes(&mut self, bytes: u64) {
+        self.event.bytes += bytes;
+        self.emit(false);
+    }
+
+    pub fn add_file(&mut self, bytes: u64) {
+        self.event.files += 1;
+        self.event.bytes += bytes;
+        self.emit(false);
+    }
+
+    /// Send the final event for the operation.
+    pub fn finish(mut self, result: &Result<impl Sized, String>) {
+        self.event.done = true;
+        if let Err(e) = result {
+            self.event.error = Some(e.clone());
+        } else if self.event.total_bytes.is_some() || self.event.total_files.is_some() {
+            self.event.percent = Some(100.0);
+        }
+        self.emit(true);
+    }
+
+    fn emit(&mut self, force: bool) {
+        let Some(ref tx) = self.tx else {
+            return;
+        };
+        if !self.event.done {
+            self.event.percent = percent(self.event.bytes, self.event.total_bytes)
+                .or_else(|| percent(self.event.files, self.event.total_files));
+        }
+        let whole = self.event.percent.map(|p| p as u64);
+        let due = self.last_emit.is_none_or(|t| t.elapsed() >= EMIT_INTERVAL);
+        if !(force || due || whole != self.last_percent) {
+            return;
+        }
+        self.last_emit = Some(Instant::now());
+        self.last_percent = whole;
+        // No subscribers is fine
+        let _ = tx.send(ProgressUpdate {
+            org_id: self.org_id.clone(),
+            event: self.event.clone(),
+        });
+    }
+}
+
+fn percent(done: u64, total: Option<u64>) -> Option<f64> {
+    match total {
+        Some(0) => Some(100.0),
+        Some(total) => Some((done as f64 * 100.0 / total as f64).min(100.0)),
+        None => None,
+    }
+}
+
+/// Reader that reports the bytes read through it, e.g. archive bytes consumed
+/// during extraction.
+pub struct ProgressReader<'a, R> {
+    inner: R,
+    progress: &'a mut Progress,
+}
+
+impl<'a, R: Read> ProgressReader<'a, R> {
+    pub fn new(inner: R, progress: &'a mut Progress) -> Self {
+        Self { inner, progress }
+    }
+
+    pub fn get_ref(&self) -> &R {
+        &self.inner
+    }
+}
+
+impl<R: Read> Read for ProgressReader<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.progress.add_bytes(n as u64);
+        Ok(n)
+    }
+}
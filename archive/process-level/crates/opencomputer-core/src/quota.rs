@@ -0,0 +1,56 @@
+//! Per-organization quotas.
+//!
+//! Each API key belongs to an org (`org_id`, or a private org of its own).
+//! Limits are configured next to the keys:
+//!
+//! ```json
+//! {"keys": [{"key": "osb_...", "org_id": "acme"}],
+//!  "orgs": {"acme": {"max_sessions": 50, "max_disk_mb": 20480, "max_cpu_secs": 36000}}}
+//! ```
+//!
+//! `max_sessions` and `max_disk_mb` bound what an org holds at once (disk is
+//! the tmpfs usage of its live sessions). `max_cpu_secs` bounds the CPU time
+//! consumed by its foreground runs since the server started. Creates and runs
+//! are refused once a limit is reached; the run that crosses the CPU limit is
+//! allowed to finish.
+
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Duration;
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct OrgQuota {
+    #[serde(default)]
+    pub max_sessions: Option<usize>,
+    #[serde(default)]
+    pub max_disk_mb: Option<u64>,
+    #[serde(default)]
+    pub max_cpu_secs: Option<u64>,
+}
+
+/// CPU time consumed by each org's runs.
+#[derive(Debug, Default)]
+pub struct CpuUsage {
+    by_org: Mutex<HashMap<String, Duration>>,
+}
+
+impl CpuUsage {
+    pub fn used(&self, org: &str) -> Duration {
+        self.by_org
+            .lock()
+            .unwrap()
+            .get(org)
+            .copied()
+            .unwrap_or_default()
+    }
+
+    /// CPU time of every org that ran something.
+    pub fn all(&self) -> HashMap<String, Duration> {
+        self.by_org.lock().unwrap().clone()
+    }
+
+    pub fn add(&self, org: &str, cpu: Duration) {
+        *self.by_org.lock().unwrap().entry(org.to_string()).or_default() += cpu;
+    }
+}
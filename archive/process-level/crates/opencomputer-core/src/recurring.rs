@@ -0,0 +1,108 @@
+//! Commands the server runs in a session on a cron schedule
+//! (`POST /sessions/:id/schedules`), for periodic work that would otherwise
+//! be a `sleep` loop the reaper kills.
+//!
+//! Schedules live in their session and go with it. Every
+//! [`CHECK_INTERVAL`] the server runs the due ones like a `/run` by the
+//! session's creator, so they count as activity and keep the session from
+//! going idle. A fire time that comes while the schedule's previous run is
+//! still going is skipped, as are those that pass while the server is down.
+
+use crate::schedule;
+use chrono::{DateTime, SecondsFormat, Utc};
+use chrono_tz::Tz;
+use std::collections::VecDeque;
+use std::time::Duration;
+
+pub use opencomputer_types::{ScheduleDefinition, ScheduleInfo, ScheduledRun};
+
+/// Most schedules a session may have.
+pub const MAX_SCHEDULES: usize = 16;
+
+/// Runs kept per schedule; older ones are dropped as others finish.
+pub const RUNS_KEPT: usize = 20;
+
+/// How often the server looks for due schedules.
+pub const CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Debug)]
+pub struct SessionSchedule {
+    /// As created, with the timezone filled in
+    pub definition: ScheduleDefinition,
+    cron: cron::Schedule,
+    tz: Tz,
+    next: Option<DateTime<Utc>>,
+    running: bool,
+    runs: VecDeque<ScheduledRun>,
+}
+
+impl SessionSchedule {
+    /// Check `definition` and work out its first fire time. Without a
+    /// timezone of its own it takes `session_tz`, the session's `TZ`.
+    pub fn new(mut definition: ScheduleDefinition, session_tz: Option<&str>) -> Result<Self, String> {
+        if definition.command.is_empty() {
+            return Err("Schedule has no command".to_string());
+        }
+        let timezone = definition
+            .timezone
+            .take()
+            .or(session_tz.map(str::to_string))
+            .unwrap_or_else(|| "UTC".to_string());
+        let tz = schedule::parse_timezone(&timezone)?;
+        let cron = schedule::parse(&definition.expression)
+            .map_err(|e| format!("Invalid expression {:?}: {}", definition.expression, e))?;
+        definition.timezone = Some(timezone);
+        let next = next_after(&cron, tz, Utc::now());
+        Ok(SessionSchedule {
+            definition,
+            cron,
+            tz,
+            next,
+            running: false,
+            runs: VecDeque::new(),
+        })
+    }
+
+    pub fn is_due(&self, now: DateTime<Utc>) -> bool {
+        self.next.is_some_and(|next| next <= now)
+    }
+
+    /// Whether to start a run at `now`. A due schedule moves on to its next
+    /// fire time either way, and is marked running if it wasn't already.
+    pub fn take_due(&mut self, now: DateTime<Utc>) -> bool {
+        if !self.is_due(now) {
+            return false;
+        }
+        self.next = next_after(&self.cron, self.tz, now);
+        if self.running {
+            return false;
+        }
+        self.running = true;
+        true
+    }
+
+    /// Record how a run started by [`take_due`](Self::take_due) went.
+    pub fn finish(&mut self, run: ScheduledRun) {
+        self.running = false;
+        if self.runs.len() == RUNS_KEPT {
+            self.runs.pop_front();
+        }
+        self.runs.push_back(run);
+    }
+
+    pub fn info(&self, id: &str) -> ScheduleInfo {
+        ScheduleInfo {
+            schedule_id: id.to_string(),
+            schedule: self.definition.clone(),
+            next_run: self
+                .next
+                .map(|t| t.with_timezone(&self.tz).to_rfc3339_opts(SecondsFormat::Secs, false)),
+            running: self.running,
+            runs: self.runs.iter().cloned().collect(),
+        }
+    }
+}
+
+fn next_after(cron: &cron::Schedule, tz: Tz, after: DateTime<Utc>) -> Option<DateTime<Utc>> {
+    cron.after(&after.with_timezone(&tz)).next().map(|t| t.with_timezone(&Utc))
+}
@@ -0,0 +1,422 @@
+//! Record/replay of session runs.
+//!
+//! A recorded run writes a bundle (`{REPLAY_DIR}/{id}.tar.gz`) holding the
+//! run's inputs as they were just before it started: command, env, cwd,
+//! limits, and the session's files (`files/`, with a manifest of sizes, modes
+//! and SHA-256 hashes in `bundle.json`), plus the recorded result. Replaying a
+//! bundle restores the files into a fresh sandbox and runs the same command.
+//!
+//! Only the sandbox's own files are captured; the read-only system dirs come
+//! from whichever host replays the bundle. Fetches through the session's
+//! egress proxy during the run are listed in the bundle (URLs of plain HTTP
+//! requests, host and port of HTTPS tunnels) but not their responses, and
+//! replays don't refetch them; sessions without egress rules record none.
+//! Session secrets are masked in the recorded command, env and output, so a
+//! replay sees `***` where the original run saw a secret.
+//!
+//! A bundle belongs to the org of the key that recorded it: a SHA-256 of the
+//! org ID is kept beside it (`{id}.org`), and other orgs' keys can't fetch or
+//! delete it. Bundles recorded without a key are only for callers without
+//! one.
+
+use crate::backend::SandboxBackend;
+use crate::egress::{Egress, EgressAttempt};
+use crate::progress::{Progress, ProgressReader};
+use crate::sandbox::{self, Determinism, RunConfig, RunResult};
+use crate::secrets;
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+pub use opencomputer_types::ReplayOutcome;
+
+pub const REPLAY_DIR: &str = "/tmp/opensandbox-replays";
+
+/// Bumped when the bundle layout changes incompatibly.
+const BUNDLE_VERSION: u32 = 1;
+const BUNDLE_JSON: &str = "bundle.json";
+const FILES_PREFIX: &str = "files";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReplayBundle {
+    pub version: u32,
+    /// Unix seconds
+    pub recorded_at: u64,
+    pub command: Vec<String>,
+    pub env: HashMap<String, String>,
+    pub cwd: String,
+    pub time_ms: u64,
+    pub mem_kb: u64,
+    pub fsize_kb: u64,
+    pub nofile: u64,
+    pub commit_on_success: bool,
+    /// Session determinism settings, reapplied to the replay sandbox
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub determinism: Option<Determinism>,
+    /// The run's stdin, base64 encoded
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stdin: Option<String>,
+    /// Output kept per stream and the timeout grace period; older bundles
+    /// used the defaults
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_output_bytes: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub kill_grace_ms: Option<u64>,
+    /// The run's process limit; older bundles used the default
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub nproc: Option<u64>,
+    /// Connections the run made through the session's egress proxy
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub fetches: Vec<EgressAttempt>,
+    pub files: Vec<ManifestEntry>,
+    pub result: RunResult,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ManifestEntry {
+    /// Absolute path inside the sandbox
+    pub path: String,
+    /// "file", "dir" or "symlink"
+    pub kind: String,
+    pub mode: u32,
+    pub size: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sha256: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub target: Option<String>,
+}
+
+/// Validate a replay ID (a UUID) so it can be used as a file name.
+pub fn bundle_path(replay_id: &str) -> Result<PathBuf, String> {
+    uuid::Uuid::parse_str(replay_id).map_err(|_| "invalid replay ID".to_string())?;
+    Ok(Path::new(REPLAY_DIR).join(format!("{}.tar.gz", replay_id)))
+}
+
+/// Whether the org `org_id` may fetch or delete the bundle at `path`; `None`
+/// is a caller without a key, who may see every bundle.
+pub fn owned_by(path: &Path, org_id: Option<&str>) -> bool {
+    match org_id {
+        None => true,
+        Some(org_id) => fs::read_to_string(owner_path(path)).is_ok_and(|owner| owner == org_digest(org_id)),
+    }
+}
+
+/// Delete the bundle at `path` and its owner.
+pub fn remove(path: &Path) -> std::io::Result<()> {
+    fs::remove_file(path)?;
+    let _ = fs::remove_file(owner_path(path));
+    Ok(())
+}
+
+/// `{id}.org` beside the bundle `{id}.tar.gz`.
+fn owner_path(bundle: &Path) -> PathBuf {
+    bundle.with_extension("").with_extension("org")
+}
+
+/// What's stored of a bundle's org: a digest, as org IDs of keys without
+/// one are the key itself.
+fn org_digest(org_id: &str) -> String {
+    hex::encode(Sha256::digest(org_id.as_bytes()))
+}
+
+/// Run a command in a session, recording its inputs, its fetches through
+/// `egress` and its result into a bundle owned by `org_id`. Returns the
+/// result and the replay ID.
+pub fn record_run(
+    backend: &dyn SandboxBackend,
+    sandbox_root: &Path,
+    config: &RunConfig,
+    egress: &Egress,
+    session_id: &str,
+    org_id: Option<&str>,
+) -> Result<(RunResult, String), String> {
+    let replay_id = uuid::Uuid::new_v4().to_string();
+    let path = bundle_path(&replay_id)?;
+    fs::create_dir_all(REPLAY_DIR).map_err(|e| format!("mkdir {}: {}", REPLAY_DIR, e))?;
+    let partial = path.with_extension("partial");
+
+    let recorded = (|| {
+        let file = File::create(&partial).map_err(|e| format!("create bundle: {}", e))?;
+        let mut builder = tar::Builder::new(GzEncoder::new(file, flate2::Compression::fast()));
+        builder.follow_symlinks(false);
+
+        // Snapshot files before the run so the bundle holds its inputs
+        let mut manifest = Vec::new();
+        append_tree(&mut builder, sandbox_root, Path::new(""), &mut manifest)?;
+
+        let started_ms = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.as_millis() as u64)
+            .unwrap_or(0);
+        let result = backend.run(sandbox_root, config, None)?;
+        let fetches = egress
+            .attempts_since(session_id, started_ms)
+            .into_iter()
+            .map(|attempt| EgressAttempt {
+                url: attempt.url.map(|url| secrets::redact(&url, &config.secrets)),
+                ..attempt
+            })
+            .collect();
+
+        let bundle = ReplayBundle {
+            version: BUNDLE_VERSION,
+            recorded_at: std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or(0),
+            command: secrets::redact_all(&config.command, &config.secrets),
+            env: config
+                .env
+                .iter()
+                .map(|(name, value)| (name.clone(), secrets::redact(value, &config.secrets)))
+                .collect(),
+            cwd: config.cwd.clone(),
+            time_ms: config.time_ms,
+            mem_kb: config.mem_kb,
+            fsize_kb: config.fsize_kb,
+            nofile: config.nofile,
+            commit_on_success: config.commit_on_success,
+            determinism: config.determinism.clone(),
+            stdin: config.stdin.as_ref().map(|data| BASE64.encode(data)),
+            max_output_bytes: Some(config.max_output_bytes),
+            kill_grace_ms: Some(config.kill_grace_ms),
+            nproc: Some(config.nproc),
+            fetches,
+            files: manifest,
+            result: result.clone(),
+        };
+        let json = serde_json::to_vec_pretty(&bundle).map_err(|e| e.to_string())?;
+        let mut header = tar::Header::new_gnu();
+        header.set_size(json.len() as u64);
+        header.set_mode(0o644);
+        header.set_cksum();
+        builder
+            .append_data(&mut header, BUNDLE_JSON, json.as_slice())
+            .map_err(|e| format!("write bundle: {}", e))?;
+        builder
+            .into_inner()
+            .and_then(|gz| gz.finish())
+            .map_err(|e| format!("write bundle: {}", e))?;
+        Ok(result)
+    })();
+
+    let owned = recorded.and_then(|result| match org_id {
+        Some(org_id) => fs::write(owner_path(&path), org_digest(org_id))
+            .map(|()| result)
+            .map_err(|e| format!("save bundle owner: {}", e)),
+        None => Ok(result),
+    });
+    match owned {
+        Ok(result) => {
+            fs::rename(&partial, &path).map_err(|e| format!("save bundle: {}", e))?;
+            Ok((result, replay_id))
+        }
+        Err(e) => {
+            let _ = fs::remove_file(&partial);
+            Err(e)
+        }
+    }
+}
+
+/// Restore a bundle into a fresh sandbox, run its command, and compare results.
+/// Progress covers the restore (bundle extraction, then copying its files
+/// into the sandbox), not the run.
+pub fn replay(backend: &dyn SandboxBackend, bundle: &[u8], mut progress: Progress) -> Result<ReplayOutcome, String> {
+    fs::create_dir_all(REPLAY_DIR).map_err(|e| format!("mkdir {}: {}", REPLAY_DIR, e))?;
+    let staging = Path::new(REPLAY_DIR).join(format!(".staging-{}", uuid::Uuid::new_v4()));
+    let restored = restore(backend, bundle, &staging, &mut progress);
+    let _ = fs::remove_dir_all(&staging);
+    progress.finish(&restored);
+    let (sandbox_root, bundle) = restored?;
+    run_bundle(backend, &sandbox_root, bundle)
+}
+
+/// Unpack and verify a bundle, then copy its files into a new sandbox.
+fn restore(
+    backend: &dyn SandboxBackend,
+    data: &[u8],
+    staging: &Path,
+    progress: &mut Progress,
+) -> Result<(PathBuf, ReplayBundle), String> {
+    progress.begin_stage("extract", Some(data.len() as u64), None);
+    {
+        let mut archive = tar::Archive::new(GzDecoder::new(ProgressReader::new(data, progress)));
+        archive.set_preserve_permissions(true);
+        archive
+            .unpack(staging)
+            .map_err(|e| format!("unpack bundle: {}", e))?;
+    }
+    let json = fs::read(staging.join(BUNDLE_JSON)).map_err(|e| format!("read {}: {}", BUNDLE_JSON, e))?;
+    let bundle: ReplayBundle =
+        serde_json::from_slice(&json).map_err(|e| format!("parse {}: {}", BUNDLE_JSON, e))?;
+    if bundle.version != BUNDLE_VERSION {
+        return Err(format!("unsupported bundle version {}", bundle.version));
+    }
+    let files = staging.join(FILES_PREFIX);
+    verify_manifest(&files, &bundle.files)?;
+
+    let copied: Vec<_> = bundle.files.iter().filter(|e| e.kind != "dir").collect();
+    progress.begin_stage(
+        "copy",
+        Some(copied.iter().map(|e| e.size).sum()),
+        Some(copied.len() as u64),
+    );
+    let sandbox_root = backend.create(&format!("replay-{}", uuid::Uuid::new_v4()), None, None)?;
+    let populated = if files.is_dir() {
+        sandbox::populate_from_template(&sandbox_root, &files, progress)
+    } else {
+        Ok(())
+    };
+    let populated = match bundle.determinism {
+        Some(ref determinism) => populated.and_then(|_| sandbox::apply_determinism(&sandbox_root, determinism)),
+        None => populated,
+    };
+    if let Err(e) = populated {
+        backend.destroy(&sandbox_root);
+        return Err(e);
+    }
+    Ok((sandbox_root, bundle))
+}
+
+fn run_bundle(
+    backend: &dyn SandboxBackend,
+    sandbox_root: &Path,
+    bundle: ReplayBundle,
+) -> Result<ReplayOutcome, String> {
+    let stdin = match bundle.stdin {
+        Some(ref data) => match BASE64.decode(data) {
+            Ok(data) => Some(data),
+            Err(e) => {
+                backend.destroy(sandbox_root);
+                return Err(format!("bundle stdin: {}", e));
+            }
+        },
+        None => None,
+    };
+    let config = RunConfig {
+        command: bundle.command,
+        time_ms: bundle.time_ms,
+        mem_kb: bundle.mem_kb,
+        fsize_kb: bundle.fsize_kb,
+        nofile: bundle.nofile,
+        nproc: bundle.nproc.unwrap_or(sandbox::DEFAULT_NPROC),
+        env: bundle.env,
+        cwd: bundle.cwd,
+        commit_on_success: bundle.commit_on_success,
+        determinism: bundle.determinism,
+        stdin,
+        max_output_bytes: bundle
+            .max_output_bytes
+            .unwrap_or(sandbox::DEFAULT_MAX_OUTPUT_BYTES)
+            .min(sandbox::MAX_OUTPUT_BYTES),
+        kill_grace_ms: bundle
+            .kill_grace_ms
+            .unwrap_or(sandbox::DEFAULT_KILL_GRACE_MS)
+            .min(sandbox::MAX_KILL_GRACE_MS),
+        secrets: Vec::new(),
+    };
+    let replayed = backend.run(sandbox_root, &config, None);
+    backend.destroy(sandbox_root);
+    let replayed = replayed?;
+
+    let recorded = bundle.result;
+    let matches = recorded.exit_code == replayed.exit_code
+        && recorded.signal == replayed.signal
+        && recorded.stdout == replayed.stdout;
+    Ok(ReplayOutcome {
+        recorded,
+        replayed,
+        matches,
+        fetches: bundle.fetches,
+    })
+}
+
+/// Check that every manifest file was restored with the recorded contents.
+fn verify_manifest(files: &Path, manifest: &[ManifestEntry]) -> Result<(), String> {
+    for entry in manifest {
+        let Some(ref expected) = entry.sha256 else {
+            continue;
+        };
+        let path = files.join(entry.path.trim_start_matches('/'));
+        let actual = sha256_file(&path).map_err(|e| format!("{}: {}", entry.path, e))?;
+        if actual != *expected {
+            return Err(format!("{}: content does not match manifest", entry.path));
+        }
+    }
+    Ok(())
+}
+
+fn append_tree<W: std::io::Write>(
+    builder: &mut tar::Builder<W>,
+    root: &Path,
+    rel: &Path,
+    manifest: &mut Vec<ManifestEntry>,
+) -> Result<(), String> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let dir = root.join(rel);
+    let mut entries: Vec<_> = fs::read_dir(&dir)
+        .map_err(|e| format!("read {}: {}", dir.display(), e))?
+        .flatten()
+        .collect();
+    entries.sort_by_key(|e| e.file_name());
+
+    for entry in entries {
+        let name = entry.file_name();
+        if rel.as_os_str().is_empty() && sandbox::is_mounted_top_level(&name.to_string_lossy()) {
+            continue;
+        }
+        let rel_path = rel.join(&name);
+        let full = entry.path();
+        let meta = fs::symlink_metadata(&full).map_err(|e| format!("stat {}: {}", full.display(), e))?;
+        let file_type = meta.file_type();
+        let (kind, sha256, target) = if file_type.is_dir() {
+            ("dir", None, None)
+        } else if file_type.is_symlink() {
+            let target = fs::read_link(&full).map_err(|e| format!("readlink {}: {}", full.display(), e))?;
+            ("symlink", None, Some(target.to_string_lossy().to_string()))
+        } else if file_type.is_file() {
+            let hash = sha256_file(&full).map_err(|e| format!("{}: {}", full.display(), e))?;
+            ("file", Some(hash), None)
+        } else {
+            // Sockets, fifos and devices can't be meaningfully replayed
+            continue;
+        };
+
+        builder
+            .append_path_with_name(&full, Path::new(FILES_PREFIX).join(&rel_path))
+            .map_err(|e| format!("archive {}: {}", full.display(), e))?;
+        manifest.push(ManifestEntry {
+            path: format!("/{}", rel_path.display()),
+            kind: kind.to_string(),
+            mode: meta.permissions().mode() & 0o7777,
+            size: if kind == "file" { meta.len() } else { 0 },
+            sha256,
+            target,
+        });
+        if kind == "dir" {
+            append_tree(builder, root, &rel_path, manifest)?;
+        }
+    }
+    Ok(())
+}
+
+pub(crate) fn sha256_file(path: &Path) -> Result<String, String> {
+    let mut file = File::open(path).map_err(|e| format!("open: {}", e))?;
+    let mut hasher = Sha256::new();
+    let mut buf = [0u8; 64 * 1024];
+    loop {
+        let n = file.read(&mut buf).map_err(|e| format!("read: {}", e))?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+    }
+    Ok(hex::encode(hasher.finalize()))
+}
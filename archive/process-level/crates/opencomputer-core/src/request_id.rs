@@ -0,0 +1,114 @@
+//! Request IDs and access logging.
+//!
+//! Every request gets an `X-Request-Id`: the caller's, if it sent a usable
+//! one, otherwise a fresh UUID. The ID is echoed on the response, forwarded
+//! to preview backends, recorded on the `request` span that wraps the
+//! handler, and appended to plain-text API error messages. One access-log
+//! line (target `access`) is emitted per request.
+
+use axum::{
+    body::{Body, HttpBody},
+    extract::Request,
+    http::{header, HeaderValue},
+    middleware::Next,
+    response::Response,
+};
+use std::time::Instant;
+use tracing::{info, info_span, Instrument};
+
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+
+/// Longest caller-supplied request ID that is accepted as is.
+const MAX_REQUEST_ID_LEN: usize = 128;
+
+/// Error bodies larger than this are passed through unannotated.
+const MAX_ANNOTATED_BODY: usize = 64 * 1024;
+
+/// The request's ID, available to handlers as an extension.
+#[derive(Debug, Clone)]
+pub struct RequestId(pub String);
+
+/// Axum middleware: assign the request ID, run the request inside its span,
+/// and log the outcome.
+pub async fn track_request(mut req: Request, next: Next) -> Response {
+    let id = req
+        .headers()
+        .get(REQUEST_ID_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .filter(|v| is_valid_request_id(v))
+        .map(str::to_string)
+        .unwrap_or_else(|| uuid::Uuid::new_v4().to_string());
+    let header_value = HeaderValue::from_str(&id).expect("request IDs are visible ASCII");
+    req.headers_mut().insert(REQUEST_ID_HEADER, header_value.clone());
+    req.extensions_mut().insert(RequestId(id.clone()));
+
+    let method = req.method().clone();
+    let path = req.uri().path().to_string();
+    let session_id = session_id_from_path(&path).unwrap_or("-").to_string();
+    let span = info_span!("request", request_id = %id);
+    let started = Instant::now();
+
+    let mut response = next.run(req).instrument(span.clone()).await;
+    response.headers_mut().insert(REQUEST_ID_HEADER, header_value);
+
+    span.in_scope(|| {
+        info!(
+            target: "access",
+            method = %method,
+            path = %path,
+            status = response.status().as_u16(),
+            latency_ms = started.elapsed().as_millis() as u64,
+            session_id = %session_id,
+            "request"
+        )
+    });
+    response
+}
+
+/// Axum middleware for API routes: append the request ID to plain-text
+/// error responses so it shows up wherever the message is surfaced.
+pub async fn annotate_errors(req: Request, next: Next) -> Response {
+    let id = req.extensions().get::<RequestId>().cloned();
+    let response = next.run(req).await;
+    let Some(RequestId(id)) = id else {
+        return response;
+    };
+    let is_text = response
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.starts_with("text/plain"));
+    let is_error = response.status().is_client_error() || response.status().is_server_error();
+    let small = response
+        .body()
+        .size_hint()
+        .upper()
+        .is_some_and(|len| len <= MAX_ANNOTATED_BODY as u64);
+    if !(is_error && is_text && small) {
+        return response;
+    }
+
+    let (mut parts, body) = response.into_parts();
+    let message = match axum::body::to_bytes(body, MAX_ANNOTATED_BODY).await {
+        Ok(bytes) => String::from_utf8_lossy(&bytes).into_owned(),
+        Err(_) => String::new(),
+    };
+    let message = if message.is_empty() {
+        format!("request_id: {}", id)
+    } else {
+        format!("{} (request_id: {})", message, id)
+    };
+    parts.headers.remove(header::CONTENT_LENGTH);
+    Response::from_parts(parts, Body::from(message))
+}
+
+fn is_valid_request_id(id: &str) -> bool {
+    !id.is_empty() && id.len() <= MAX_REQUEST_ID_LEN && id.bytes().all(|b| b.is_ascii_graphic())
+}
+
+/// Session ID from API paths like `/sessions/{id}/run` (under any base path).
+fn session_id_from_path(path: &str) -> Option<&str> {
+    let mut segments = path.split('/');
+    segments.find(|s| *s == "sessions")?;
+    segments.next().filter(|s| !s.is_empty())
+}
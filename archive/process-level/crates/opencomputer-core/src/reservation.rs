@@ -0,0 +1,119 @@
+//! Resource reservations declared at session creation.
+//!
+//! A create may declare the session's expected peak usage:
+//!
+//! ```json
+//! {"resources": {"cpu": 2, "mem_mb": 4096, "disk_mb": 1024}}
+//! ```
+//!
+//! The reservations of live sessions are summed per resource, and a create
+//! that would push any resource past host capacity times the overcommit ratio
+//! is refused before its sandbox is built. Sessions that declare nothing
+//! reserve nothing. Reservations are bookkeeping only: they don't limit what
+//! a session actually uses.
+
+use crate::state::Session;
+use std::collections::HashMap;
+use std::path::Path;
+
+pub use opencomputer_types::Resources;
+
+/// Reservations may add up to this multiple of host capacity by default.
+pub const DEFAULT_OVERCOMMIT_RATIO: f64 = 1.0;
+
+/// Filesystem whose size is taken as the host's disk capacity; session
+/// sandboxes live under it.
+pub(crate) const SANDBOX_FS: &str = "/tmp";
+
+/// Host capacity and how far reservations may exceed it.
+#[derive(Debug, Clone)]
+pub struct ReservationPolicy {
+    pub host: Resources,
+    pub overcommit_ratio: f64,
+}
+
+impl Default for ReservationPolicy {
+    fn default() -> Self {
+        Self {
+            host: detect_host(),
+            overcommit_ratio: DEFAULT_OVERCOMMIT_RATIO,
+        }
+    }
+}
+
+impl ReservationPolicy {
+    /// Most that may be reserved across all sessions.
+    pub fn limit(&self) -> Resources {
+        Resources {
+            cpu: self.host.cpu * self.overcommit_ratio,
+            mem_mb: (self.host.mem_mb as f64 * self.overcommit_ratio) as u64,
+            disk_mb: (self.host.disk_mb as f64 * self.overcommit_ratio) as u64,
+        }
+    }
+
+    /// What is left to reserve given the current reservations.
+    pub fn available(&self, reserved: Resources) -> Resources {
+        let limit = self.limit();
+        Resources {
+            cpu: (limit.cpu - reserved.cpu).max(0.0),
+            mem_mb: limit.mem_mb.saturating_sub(reserved.mem_mb),
+            disk_mb: limit.disk_mb.saturating_sub(reserved.disk_mb),
+        }
+    }
+
+    /// Refuse `request` if any resource it asks for is no longer available.
+    pub fn check(&self, reserved: Resources, request: &Resources) -> Result<(), String> {
+        let left = self.available(reserved);
+        let mut short = Vec::new();
+        if request.cpu > left.cpu {
+            short.push(format!("cpu {} requested, {} available", request.cpu, left.cpu));
+        }
+        if request.mem_mb > left.mem_mb {
+            short.push(format!("mem_mb {} requested, {} available", request.mem_mb, left.mem_mb));
+        }
+        if request.disk_mb > left.disk_mb {
+            short.push(format!("disk_mb {} requested, {} available", request.disk_mb, left.disk_mb));
+        }
+        if short.is_empty() {
+            Ok(())
+        } else {
+            Err(format!(
+                "Host capacity exhausted (overcommit ratio {}): {}",
+                self.overcommit_ratio,
+                short.join(", ")
+            ))
+        }
+    }
+}
+
+/// Sum of the reservations held by `sessions`.
+pub fn reserved(sessions: &HashMap<String, Session>) -> Resources {
+    sessions
+        .values()
+        .filter_map(|s| s.resources.as_ref())
+        .fold(Resources::default(), |total, r| Resources {
+            cpu: total.cpu + r.cpu,
+            mem_mb: total.mem_mb + r.mem_mb,
+            disk_mb: total.disk_mb + r.disk_mb,
+        })
+}
+
+/// CPUs, total memory and the size of the sandbox filesystem. Anything that
+/// can't be read counts as zero, so reservations of it are refused.
+pub fn detect_host() -> Resources {
+    let cpu = std::thread::available_parallelism()
+        .map(|n| n.get() as f64)
+        .unwrap_or(0.0);
+    let mem_mb = std::fs::read_to_string("/proc/meminfo")
+        .ok()
+        .and_then(|info| {
+            let line = info.lines().find(|l| l.starts_with("MemTotal:"))?;
+            let kb: u64 = line.split_whitespace().nth(1)?.parse().ok()?;
+            Some(kb / 1024)
+        })
+        .unwrap_or(0);
+    let disk_mb = nix::sys::statvfs::statvfs(Path::new(SANDBOX_FS))
+        .map(|st| st.blocks() * st.fragment_size() / (1024 * 1024))
+        .unwrap_or(0);
+    Resources { cpu, mem_mb, disk_mb }
+}
@@ -0,0 +1,102 @@
+//! Caps on concurrent runs, per session and server-wide.
+//!
+//! A run holds a thread of Tokio's blocking pool until its command exits,
+//! and file reads and writes, checkpoints and the like need the same pool,
+//! so enough long runs stall sessions that aren't running anything. Before
+//! a run starts it takes a permit from its session, then one from the
+//! server. When either has none left it waits up to the queue timeout for
+//! one and is then refused (`429`). Both caps are off by default.
+
+use crate::activity::ActivityGuard;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tokio::time::Instant;
+
+#[derive(Default)]
+pub struct RunLimits {
+    /// Most runs in progress across the server
+    max_runs: Option<usize>,
+    /// Most runs in progress in one session
+    max_session_runs: Option<usize>,
+    /// How long a run waits for a permit before it's refused
+    queue_timeout: Duration,
+    server: Option<Arc<Semaphore>>,
+    by_session: Mutex<HashMap<String, Arc<Semaphore>>>,
+}
+
+/// Leave to run, given back when dropped.
+pub struct RunPermit {
+    _session: Option<OwnedSemaphorePermit>,
+    _server: Option<OwnedSemaphorePermit>,
+    _activity: Option<ActivityGuard>,
+}
+
+impl RunPermit {
+    /// Also hold `activity` until the run ends, so its session counts as
+    /// busy.
+    pub fn with_activity(mut self, activity: ActivityGuard) -> Self {
+        self._activity = Some(activity);
+        self
+    }
+}
+
+impl RunLimits {
+    pub fn new(max_runs: Option<usize>, max_session_runs: Option<usize>, queue_timeout: Duration) -> Self {
+        Self {
+            max_runs,
+            max_session_runs,
+            queue_timeout,
+            server: max_runs.map(|max| Arc::new(Semaphore::new(max))),
+            by_session: Mutex::default(),
+        }
+    }
+
+    /// Wait for leave to start a run in `session_id` (`None` for runs in a
+    /// fresh sandbox, which only count against the server). Errors once the
+    /// queue timeout passes without it.
+    pub async fn acquire(&self, session_id: Option<&str>) -> Result<RunPermit, String> {
+        let deadline = Instant::now() + self.queue_timeout;
+        let session = match (self.max_session_runs, session_id) {
+            (Some(max), Some(id)) => {
+                let semaphore = self
+                    .by_session
+                    .lock()
+                    .unwrap_or_else(|e| e.into_inner())
+                    .entry(id.to_string())
+                    .or_insert_with(|| Arc::new(Semaphore::new(max)))
+                    .clone();
+                let permit = take(semaphore, deadline).await;
+                Some(permit.ok_or_else(|| format!("Session already has {} runs in progress", max))?)
+            }
+            _ => None,
+        };
+        let server = match (self.max_runs, &self.server) {
+            (Some(max), Some(semaphore)) => {
+                let permit = take(semaphore.clone(), deadline).await;
+                Some(permit.ok_or_else(|| format!("Server already has {} runs in progress", max))?)
+            }
+            _ => None,
+        };
+        Ok(RunPermit {
+            _session: session,
+            _server: server,
+            _activity: None,
+        })
+    }
+
+    /// Drop a session's count once the session is gone. Its runs still in
+    /// progress keep their permits until they end.
+    pub fn forget(&self, session_id: &str) {
+        self.by_session.lock().unwrap_or_else(|e| e.into_inner()).remove(session_id);
+    }
+}
+
+/// A permit of `semaphore`, waiting for one until `deadline`.
+async fn take(semaphore: Arc<Semaphore>, deadline: Instant) -> Option<OwnedSemaphorePermit> {
+    if let Ok(permit) = semaphore.clone().try_acquire_owned() {
+        return Some(permit);
+    }
+    tokio::time::timeout_at(deadline, semaphore.acquire_owned()).await.ok()?.ok()
+}
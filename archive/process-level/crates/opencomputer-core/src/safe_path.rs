@@ -0,0 +1,497 @@
+//! Path resolution inside a sandbox root for the server's file API.
+//!
+//! Everything under a sandbox root belongs to sandboxed code, which can plant
+//! symlinks or swap a directory for one between any two of our syscalls. So
+//! paths are never joined onto the root and handed to `std::fs`. Each lookup
+//! is one `openat2(2)` from a descriptor of the root with `RESOLVE_IN_ROOT`,
+//! which has the kernel resolve symlinks (absolute ones included) as if the
+//! root were `/`, and `RESOLVE_NO_MAGICLINKS`, so `/proc/*/root` and the like
+//! can't lead out. Everything after that (reads, writes, chown, stat) goes
+//! through the descriptors it returned. Paths with `..` are refused before
+//! any of that, as walks component by component (creating directories,
+//! taking a parent) can't give them a meaning.
+
+use nix::errno::Errno;
+use nix::fcntl::{openat2, readlinkat, renameat2, AtFlags, OFlag, OpenHow, RenameFlags, ResolveFlag};
+use nix::sys::stat::{fchmod, fstat, fstatat, mkdirat, FileStat, Mode, SFlag};
+use nix::unistd::{fchownat, symlinkat, unlinkat, Gid, Uid, UnlinkatFlags};
+use std::ffi::{OsStr, OsString};
+use std::fs::File;
+use std::io::{Read, Write};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::FileExt;
+use std::path::{Component, Path, PathBuf};
+
+/// Retries of a lookup that raced a rename elsewhere in the root (`EAGAIN`).
+const MAX_RETRIES: usize = 16;
+
+/// A directory entry, not following symlinks.
+pub struct Entry {
+    pub name: String,
+    pub is_directory: bool,
+    pub size: u64,
+    /// `st_mode`, file type bits included
+    pub mode: u32,
+    /// Modification time in nanoseconds since the epoch
+    pub mtime_ns: i64,
+}
+
+impl Entry {
+    fn new(name: String, stat: &FileStat) -> Self {
+        Self {
+            name,
+            is_directory: SFlag::from_bits_truncate(stat.st_mode) & SFlag::S_IFMT == SFlag::S_IFDIR,
+            size: stat.st_size as u64,
+            mode: stat.st_mode,
+            mtime_ns: stat.st_mtime * 1_000_000_000 + stat.st_mtime_nsec,
+        }
+    }
+
+    pub fn file_type(&self) -> SFlag {
+        SFlag::from_bits_truncate(self.mode) & SFlag::S_IFMT
+    }
+}
+
+/// Where [`Root::write_at`] puts its content.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WriteAt {
+    /// Replace the file
+    Truncate,
+    /// After what the file holds
+    End,
+    /// Over the bytes from this offset on; a gap past the end reads as zeros
+    Offset(u64),
+}
+
+/// Descriptor of a sandbox root to resolve paths against.
+pub struct Root(OwnedFd);
+
+impl Root {
+    pub fn open(sandbox_root: &Path) -> Result<Self, String> {
+        let fd = nix::fcntl::open(sandbox_root, OFlag::O_PATH | OFlag::O_DIRECTORY | OFlag::O_CLOEXEC, Mode::empty())
+            .map_err(|e| format!("open {}: {}", sandbox_root.display(), e))?;
+        // SAFETY: open just returned this descriptor and nothing else owns it
+        Ok(Self(unsafe { OwnedFd::from_raw_fd(fd) }))
+    }
+
+    /// Open `path` (absolute or not, both relative to the root).
+    fn resolve(&self, path: &Path, flags: OFlag, mode: Mode) -> nix::Result<OwnedFd> {
+        let path = relative(path)?;
+        let how = OpenHow::new()
+            .flags(flags | OFlag::O_CLOEXEC)
+            .mode(mode)
+            .resolve(ResolveFlag::RESOLVE_IN_ROOT | ResolveFlag::RESOLVE_NO_MAGICLINKS);
+        let mut attempts = 0;
+        loop {
+            match openat2(self.0.as_raw_fd(), &path, how) {
+                // SAFETY: openat2 just returned this descriptor and nothing else owns it
+                Ok(fd) => return Ok(unsafe { OwnedFd::from_raw_fd(fd) }),
+                Err(Errno::EAGAIN) if attempts < MAX_RETRIES => attempts += 1,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Read a regular file. Anything else (a FIFO, a device) is refused
+    /// rather than read, so it can't stall the server.
+    pub fn read(&self, path: &Path) -> Result<Vec<u8>, String> {
+        let mut content = Vec::new();
+        self.open_file(path)?
+            .read_to_end(&mut content)
+            .map_err(|e| format!("read file: {}", e))?;
+        Ok(content)
+    }
+
+    /// Open a regular file for reading, refusing anything else as
+    /// [`Root::read`] does.
+    pub fn open_file(&self, path: &Path) -> Result<File, String> {
+        let fd = self
+            .resolve(path, OFlag::O_RDONLY | OFlag::O_NONBLOCK | OFlag::O_NOCTTY, Mode::empty())
+            .map_err(|e| format!("read file: {}", e))?;
+        let stat = fstat(fd.as_raw_fd()).map_err(|e| format!("read file: {}", e))?;
+        if SFlag::from_bits_truncate(stat.st_mode) & SFlag::S_IFMT != SFlag::S_IFREG {
+            return Err("read file: not a regular file".to_string());
+        }
+        Ok(File::from(fd))
+    }
+
+    /// Create or truncate a file with mode 0644 and write `content`, creating
+    /// missing parent directories. `owner` gets the file and every directory
+    /// leading to it.
+    pub fn write(&self, path: &Path, content: &[u8], owner: Option<(u32, u32)>) -> Result<(), String> {
+        self.write_at(path, content, WriteAt::Truncate, owner)
+    }
+
+    /// Like [`Root::write`], but `at` may keep what the file holds. A file
+    /// that already exists then keeps its mode and owner.
+    pub fn write_at(
+        &self,
+        path: &Path,
+        content: &[u8],
+        at: WriteAt,
+        owner: Option<(u32, u32)>,
+    ) -> Result<(), String> {
+        let mut file = self.open_for_write(path, at, owner)?;
+        match at {
+            WriteAt::Offset(offset) => file.write_all_at(content, offset),
+            WriteAt::Truncate | WriteAt::End => file.write_all(content),
+        }
+        .map_err(|e| format!("write file: {}", e))
+    }
+
+    /// Open a regular file for appending, created as by [`Root::write`] if
+    /// missing, for a writer that holds it open such as a process's stdout.
+    pub fn open_append(&self, path: &Path, owner: Option<(u32, u32)>) -> Result<File, String> {
+        self.open_for_write(path, WriteAt::End, owner)
+    }
+
+    /// Create or truncate a file as [`Root::write`] does and return it, for
+    /// content that's streamed in rather than held in memory.
+    pub fn create(&self, path: &Path, owner: Option<(u32, u32)>) -> Result<File, String> {
+        self.open_for_write(path, WriteAt::Truncate, owner)
+    }
+
+    /// Create a symlink at `path` to `target`, which is stored as given and
+    /// only ever resolved within the root. Missing parent directories are
+    /// created; `owner` gets them and the link.
+    pub fn symlink(&self, target: &str, path: &Path, owner: Option<(u32, u32)>) -> Result<(), String> {
+        let err = |e| format!("symlink {}: {}", path.display(), e);
+        if let Some(parent) = relative(path).map_err(err)?.parent() {
+            self.create_dir_all(parent, owner)?;
+        }
+        let (dir, name) = self.parent(path).map_err(err)?;
+        symlinkat(target, Some(dir.as_raw_fd()), name.as_os_str()).map_err(err)?;
+        let Some((uid, gid)) = owner else {
+            return Ok(());
+        };
+        fchownat(
+            Some(dir.as_raw_fd()),
+            name.as_os_str(),
+            Some(Uid::from_raw(uid)),
+            Some(Gid::from_raw(gid)),
+            AtFlags::AT_SYMLINK_NOFOLLOW,
+        )
+        .map_err(|e| format!("chown: {}", e))
+    }
+
+    /// Open `path` for [`Root::write_at`].
+    fn open_for_write(&self, path: &Path, at: WriteAt, owner: Option<(u32, u32)>) -> Result<File, String> {
+        let rel = relative(path).map_err(|e| format!("write file: {}", e))?;
+        if let Some(parent) = rel.parent() {
+            self.create_dir_all(parent, owner)?;
+        }
+        let flags = OFlag::O_WRONLY | OFlag::O_NONBLOCK | OFlag::O_NOCTTY;
+        let mode = Mode::from_bits_truncate(0o644);
+        let opened = match at {
+            WriteAt::Truncate => self.resolve(path, flags | OFlag::O_CREAT | OFlag::O_TRUNC, mode).map(|fd| (fd, true)),
+            WriteAt::End | WriteAt::Offset(_) => {
+                let flags = if at == WriteAt::End { flags | OFlag::O_APPEND } else { flags };
+                match self.resolve(path, flags | OFlag::O_CREAT | OFlag::O_EXCL, mode) {
+                    Ok(fd) => Ok((fd, true)),
+                    Err(Errno::EEXIST) => self.resolve(path, flags, Mode::empty()).map(|fd| (fd, false)),
+                    Err(e) => Err(e),
+                }
+            }
+        };
+        let (fd, replaced) = opened.map_err(|e| format!("write file: {}", e))?;
+        let stat = fstat(fd.as_raw_fd()).map_err(|e| format!("write file: {}", e))?;
+        if SFlag::from_bits_truncate(stat.st_mode) & SFlag::S_IFMT != SFlag::S_IFREG {
+            return Err("write file: not a regular file".to_string());
+        }
+        if replaced {
+            fchmod(fd.as_raw_fd(), mode).map_err(|e| format!("chmod: {}", e))?;
+            chown(&fd, owner)?;
+        }
+        Ok(File::from(fd))
+    }
+
+    /// Create `path` and its missing ancestors, giving each directory along
+    /// the way to `owner`.
+    pub fn create_dir_all(&self, path: &Path, owner: Option<(u32, u32)>) -> Result<(), String> {
+        let rel = relative(path).map_err(|e| format!("mkdir {}: {}", path.display(), e))?;
+        let mut dir = self.resolve(Path::new(""), OFlag::O_PATH | OFlag::O_DIRECTORY, Mode::empty());
+        let mut prefix = PathBuf::new();
+        for component in rel.components() {
+            prefix.push(component);
+            let parent = dir.map_err(|e| format!("mkdir {}: {}", prefix.display(), e))?;
+            let open = |root: &Self| root.resolve(&prefix, OFlag::O_PATH | OFlag::O_DIRECTORY, Mode::empty());
+            dir = match open(self) {
+                Err(Errno::ENOENT) => {
+                    let name = prefix.file_name().unwrap_or_default();
+                    match mkdirat(Some(parent.as_raw_fd()), name, Mode::from_bits_truncate(0o755)) {
+                        Ok(()) | Err(Errno::EEXIST) => open(self),
+                        Err(e) => Err(e),
+                    }
+                }
+                opened => opened,
+            };
+            if let Ok(ref fd) = dir {
+                chown(fd, owner)?;
+            }
+        }
+        dir.map(drop).map_err(|e| format!("mkdir {}: {}", prefix.display(), e))
+    }
+
+    /// An `O_PATH` descriptor of directory `path`, e.g. to mount on through
+    /// `/proc/self/fd`. A mount made on one is only seen by descriptors
+    /// opened after it.
+    pub fn dir(&self, path: &Path) -> Result<OwnedFd, String> {
+        self.resolve(path, OFlag::O_PATH | OFlag::O_DIRECTORY, Mode::empty())
+            .map_err(|e| format!("open dir {}: {}", path.display(), e))
+    }
+
+    /// What `path` is, following symlinks (within the root).
+    pub fn metadata(&self, path: &Path) -> Result<Entry, String> {
+        let fd = self
+            .resolve(path, OFlag::O_PATH, Mode::empty())
+            .map_err(|e| format!("stat {}: {}", path.display(), e))?;
+        let stat = fstat(fd.as_raw_fd()).map_err(|e| format!("stat {}: {}", path.display(), e))?;
+        let name = path.file_name().map(|n| n.to_string_lossy().to_string()).unwrap_or_default();
+        Ok(Entry::new(name, &stat))
+    }
+
+    /// Target of symlink `path`.
+    pub fn read_link(&self, path: &Path) -> Result<String, String> {
+        let (parent, name) = self
+            .parent(path)
+            .map_err(|e| format!("readlink {}: {}", path.display(), e))?;
+        readlinkat(Some(parent.as_raw_fd()), name.as_os_str())
+            .map(|target| target.to_string_lossy().to_string())
+            .map_err(|e| format!("readlink {}: {}", path.display(), e))
+    }
+
+    /// What `path` itself is: a symlink is reported as one, not followed.
+    pub fn symlink_metadata(&self, path: &Path) -> Result<Entry, String> {
+        let (parent, name) = self.parent(path).map_err(|e| format!("stat {}: {}", path.display(), e))?;
+        let stat = fstatat(Some(parent.as_raw_fd()), name.as_os_str(), AtFlags::AT_SYMLINK_NOFOLLOW)
+            .map_err(|e| format!("stat {}: {}", path.display(), e))?;
+        Ok(Entry::new(name.to_string_lossy().to_string(), &stat))
+    }
+
+    /// Give the entry at `path` (not what it links to) to `owner`. No-op
+    /// without an owner.
+    pub fn set_owner(&self, path: &Path, owner: Option<(u32, u32)>) -> Result<(), String> {
+        let fd = self
+            .resolve(path, OFlag::O_PATH | OFlag::O_NOFOLLOW, Mode::empty())
+            .map_err(|e| format!("chown {}: {}", path.display(), e))?;
+        chown(&fd, owner)
+    }
+
+    /// Set the permission bits of regular file `path`. Setuid, setgid and
+    /// sticky bits are dropped.
+    pub fn set_mode(&self, path: &Path, mode: u32) -> Result<(), String> {
+        let file = self.open_file(path)?;
+        fchmod(file.as_raw_fd(), Mode::from_bits_truncate(mode & 0o777))
+            .map_err(|e| format!("chmod {}: {}", path.display(), e))
+    }
+
+    /// Move the entry at `from` (not what it links to) to `to`, which must
+    /// not exist yet. Both stay within the root and on its filesystem.
+    pub fn rename(&self, from: &Path, to: &Path) -> Result<(), String> {
+        let err = |e| format!("rename {} to {}: {}", from.display(), to.display(), e);
+        let (from_dir, from_name) = self.parent(from).map_err(err)?;
+        let (to_dir, to_name) = self.parent(to).map_err(err)?;
+        renameat2(
+            Some(from_dir.as_raw_fd()),
+            from_name.as_os_str(),
+            Some(to_dir.as_raw_fd()),
+            to_name.as_os_str(),
+            RenameFlags::RENAME_NOREPLACE,
+        )
+        .map_err(err)
+    }
+
+    /// Remove `path` and, if it's a directory, everything in it. Symlinks
+    /// are removed, never followed, and directories are descended through
+    /// descriptors, so swapping one for a link midway can't redirect it.
+    pub fn remove_all(&self, path: &Path) -> Result<(), String> {
+        let (parent, name) = self.parent(path).map_err(|e| format!("remove {}: {}", path.display(), e))?;
+        remove_at(parent.as_raw_fd(), name.as_os_str()).map_err(|e| format!("remove {}: {}", path.display(), e))
+    }
+
+    /// The directory holding `path`, and its last component. The root
+    /// itself has none.
+    fn parent(&self, path: &Path) -> nix::Result<(OwnedFd, OsString)> {
+        let rel = relative(path)?;
+        let name = match rel.file_name() {
+            Some(name) if rel != Path::new(".") => name.to_os_string(),
+            _ => return Err(Errno::EINVAL),
+        };
+        let dir = self.resolve(rel.parent().unwrap_or(Path::new("")), OFlag::O_PATH | OFlag::O_DIRECTORY, Mode::empty())?;
+        Ok((dir, name))
+    }
+
+    /// Entries of directory `path`, unsorted.
+    pub fn list(&self, path: &Path) -> Result<Vec<Entry>, String> {
+        let fd = self
+            .resolve(path, OFlag::O_RDONLY | OFlag::O_DIRECTORY, Mode::empty())
+            .map_err(|e| format!("read dir {}: {}", path.display(), e))?;
+        let mut dir = nix::dir::Dir::from(fd).map_err(|e| format!("read dir {}: {}", path.display(), e))?;
+        let dir_fd = dir.as_raw_fd();
+        let mut entries = Vec::new();
+        for entry in dir.iter() {
+            let entry = entry.map_err(|e| format!("read entry: {}", e))?;
+            let name = entry.file_name();
+            if name.to_bytes() == b"." || name.to_bytes() == b".." {
+                continue;
+            }
+            let stat = fstatat(Some(dir_fd), name, AtFlags::AT_SYMLINK_NOFOLLOW)
+                .map_err(|e| format!("metadata: {}", e))?;
+            entries.push(Entry::new(name.to_string_lossy().to_string(), &stat));
+        }
+        Ok(entries)
+    }
+}
+
+/// `path` without its root, so it resolves relative to a descriptor; the
+/// empty path becomes `.`. A `..` anywhere in it is `EINVAL`.
+fn relative(path: &Path) -> nix::Result<PathBuf> {
+    if path.components().any(|c| c == Component::ParentDir) {
+        return Err(Errno::EINVAL);
+    }
+    let rest: PathBuf = path.components().filter(|c| !matches!(c, Component::RootDir)).collect();
+    if rest.as_os_str().is_empty() {
+        Ok(PathBuf::from("."))
+    } else {
+        Ok(rest)
+    }
+}
+
+/// Remove entry `name` of directory `dir`, emptying it first if it's a
+/// directory itself.
+fn remove_at(dir: RawFd, name: &OsStr) -> nix::Result<()> {
+    match unlinkat(Some(dir), name, UnlinkatFlags::NoRemoveDir) {
+        Err(Errno::EISDIR) => {}
+        done => return done,
+    }
+    let flags = OFlag::O_RDONLY | OFlag::O_DIRECTORY | OFlag::O_NOFOLLOW | OFlag::O_CLOEXEC;
+    let mut child = nix::dir::Dir::openat(Some(dir), name, flags, Mode::empty())?;
+    // Listed before removing anything, as readdir may skip entries otherwise
+    let names: Vec<OsString> = child
+        .iter()
+        .filter_map(|entry| entry.ok())
+        .map(|entry| OsStr::from_bytes(entry.file_name().to_bytes()).to_os_string())
+        .filter(|name| name != "." && name != "..")
+        .collect();
+    for name in names {
+        remove_at(child.as_raw_fd(), &name)?;
+    }
+    unlinkat(Some(dir), name, UnlinkatFlags::RemoveDir)
+}
+
+/// Change who owns the entry `fd` refers to. No-op without an owner.
+fn chown(fd: &OwnedFd, owner: Option<(u32, u32)>) -> Result<(), String> {
+    let Some((uid, gid)) = owner else {
+        return Ok(());
+    };
+    fchownat(
+        Some(fd.as_raw_fd()),
+        "",
+        Some(Uid::from_raw(uid)),
+        Some(Gid::from_raw(gid)),
+        AtFlags::AT_EMPTY_PATH,
+    )
+    .map_err(|e| format!("chown: {}", e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+
+    /// A fresh `root` and a sibling `outside` that nothing may reach.
+    struct Scratch {
+        dir: PathBuf,
+    }
+
+    impl Scratch {
+        fn new() -> Self {
+            let dir = std::env::temp_dir().join(format!("safe-path-{}", uuid::Uuid::new_v4()));
+            fs::create_dir_all(dir.join("root")).unwrap();
+            fs::create_dir_all(dir.join("outside")).unwrap();
+            Self { dir }
+        }
+
+        fn root(&self) -> Root {
+            Root::open(&self.dir.join("root")).unwrap()
+        }
+
+        fn outside(&self) -> PathBuf {
+            self.dir.join("outside")
+        }
+
+        fn outside_is_empty(&self) -> bool {
+            fs::read_dir(self.outside()).unwrap().next().is_none()
+        }
+    }
+
+    impl Drop for Scratch {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.dir);
+        }
+    }
+
+    #[test]
+    fn parent_components_are_refused() {
+        let scratch = Scratch::new();
+        let root = scratch.root();
+        assert!(root.write(Path::new("../outside/f"), b"x", None).is_err());
+        assert!(root.create_dir_all(Path::new("a/../../outside/d"), None).is_err());
+        assert!(root.create_dir_all(Path::new(".."), None).is_err());
+        assert!(root.symlink("/", Path::new("../outside/l"), None).is_err());
+        assert!(root.remove_all(Path::new("a/..")).is_err());
+        assert!(root.read(Path::new("/../outside/f")).is_err());
+        assert!(scratch.outside_is_empty());
+        assert!(!scratch.dir.join("root/a").exists());
+    }
+
+    #[test]
+    fn absolute_symlink_resolves_inside_root() {
+        let scratch = Scratch::new();
+        std::os::unix::fs::symlink("/etc", scratch.dir.join("root/etc-link")).unwrap();
+        let root = scratch.root();
+        // Nothing at the root's own /etc yet, so the host's isn't read
+        assert!(root.read(Path::new("etc-link/passwd")).is_err());
+        assert!(root.read(Path::new("/etc-link/hostname")).is_err());
+
+        fs::create_dir(scratch.dir.join("root/etc")).unwrap();
+        fs::write(scratch.dir.join("root/etc/passwd"), "inside").unwrap();
+        assert_eq!(root.read(Path::new("etc-link/passwd")).unwrap(), b"inside");
+        root.write(Path::new("etc-link/new"), b"x", None).unwrap();
+        assert!(scratch.dir.join("root/etc/new").exists());
+    }
+
+    #[test]
+    fn symlink_swapped_in_mid_walk_stays_inside_root() {
+        let scratch = Scratch::new();
+        let root_path = scratch.dir.join("root");
+        fs::create_dir(root_path.join("d")).unwrap();
+        std::os::unix::fs::symlink(scratch.outside(), root_path.join("swap")).unwrap();
+
+        // Keep exchanging the directory `d` with a link to `outside`
+        let stop = Arc::new(AtomicBool::new(false));
+        let swapper = {
+            let (stop, root_path) = (stop.clone(), root_path.clone());
+            std::thread::spawn(move || {
+                let dir = nix::fcntl::open(&root_path, OFlag::O_PATH | OFlag::O_DIRECTORY, Mode::empty()).unwrap();
+                while !stop.load(Ordering::Relaxed) {
+                    renameat2(Some(dir), "d", Some(dir), "swap", RenameFlags::RENAME_EXCHANGE).unwrap();
+                }
+                nix::unistd::close(dir).unwrap();
+            })
+        };
+
+        let root = scratch.root();
+        for i in 0..2000 {
+            let _ = root.write(Path::new(&format!("d/sub{}/f", i % 8)), b"x", None);
+            let _ = root.symlink("/", Path::new(&format!("d/l{}", i % 8)), None);
+            let _ = root.remove_all(Path::new(&format!("d/sub{}", (i + 4) % 8)));
+        }
+        stop.store(true, Ordering::Relaxed);
+        swapper.join().unwrap();
+        assert!(scratch.outside_is_empty());
+    }
+}
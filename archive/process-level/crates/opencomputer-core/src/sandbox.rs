@@ -0,0 +1,2114 @@
+//! Core sandbox execution logic.
+
+use nix::mount::{mount, umount2, MntFlags, MsFlags};
+use nix::sched::{clone, CloneFlags};
+use nix::sys::resource::{setrlimit, Resource};
+use nix::sys::signal::Signal;
+use nix::sys::wait::WaitStatus;
+use nix::unistd::{chdir, chroot, execvpe};
+use crate::background_log;
+use crate::cgroups::SessionCgroup;
+use crate::progress::{Progress, ProgressReader};
+use crate::safe_path;
+use crate::secrets;
+use crate::userns;
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeSet, HashMap};
+use std::ffi::CString;
+use std::fs;
+use std::io::{Read, Write};
+use std::os::fd::{AsRawFd, OwnedFd};
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::process::Child;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tracing::{info, warn};
+
+pub use opencomputer_types::{Determinism, LimitExceeded, RunResult};
+
+/// Output kept per stream when a run doesn't set `max_output_bytes`.
+pub const DEFAULT_MAX_OUTPUT_BYTES: u64 = 1024 * 1024;
+
+/// Most output a run may ask to keep per stream.
+pub const MAX_OUTPUT_BYTES: u64 = 16 * 1024 * 1024;
+
+/// Pause between SIGTERM and SIGKILL when a run doesn't set `kill_grace_ms`.
+pub const DEFAULT_KILL_GRACE_MS: u64 = 2000;
+
+/// Longest grace period a run may ask for.
+pub const MAX_KILL_GRACE_MS: u64 = 60_000;
+
+/// RLIMIT_NPROC of runs that don't set `pids`. Counts every process and
+/// thread of the sandbox's user, so it bounds the whole session at the
+/// moment the run forks.
+pub const DEFAULT_NPROC: u64 = 256;
+
+/// Start of errors for a command that couldn't be started because a
+/// process limit was hit: the run's `pids`, or the host's own.
+pub const PIDS_LIMIT_HIT: &str = "pid limit hit";
+
+/// How often a run's total CPU time is checked against its limit.
+const CPU_WATCH_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Each sandbox root is owned by its own UID (with the same GID) from this
+/// range, and commands in it run as that user.
+const SANDBOX_UID_BASE: u32 = 100_000;
+const SANDBOX_UID_COUNT: u32 = 65_536;
+
+/// Held from picking a free sandbox UID until the root claiming it is mounted.
+static UID_ALLOCATION: std::sync::Mutex<()> = std::sync::Mutex::new(());
+
+/// Supplementary group of every sandbox user. Template files belong to it
+/// (see [`share_with_sandboxes`]) so sessions layered on a template can
+/// change them.
+pub const SANDBOX_SHARED_GID: u32 = 99_999;
+
+/// Read-only directory layered under every new sandbox root, if configured.
+static BASE_LAYER: std::sync::OnceLock<PathBuf> = std::sync::OnceLock::new();
+
+/// Network namespaces of sandboxes that don't share the host's network, by
+/// sandbox root. See [`set_network_namespace`].
+static NETWORK_NAMESPACES: Mutex<Option<HashMap<PathBuf, Arc<OwnedFd>>>> = Mutex::new(None);
+
+/// Cgroups sandboxes' commands join, by sandbox root. See [`set_cgroup`].
+static CGROUPS: Mutex<Option<HashMap<PathBuf, Arc<SessionCgroup>>>> = Mutex::new(None);
+
+/// Host directories bind mounted read-only into every sandbox not built
+/// from an image.
+pub(crate) const SYSTEM_BIND_DIRS: &[&str] = &["/bin", "/lib", "/lib64", "/usr", "/etc"];
+
+/// Device nodes bind mounted from the host into every sandbox's /dev.
+pub(crate) const DEVICE_NODES: &[&str] = &["null", "zero", "urandom", "random"];
+
+/// Size of the seeded stand-in for /dev/urandom and /dev/random.
+const SEEDED_RANDOM_BYTES: usize = 1024 * 1024;
+
+/// Sandbox-specific /etc files (hostname, hosts, machine-id, ...), layered
+/// over the host's /etc. Image roots have an /etc of their own and get them
+/// written there instead.
+const ETC_OVERRIDES_DIR: &str = ".opensandbox/etc";
+
+/// Used when the host has no usable /etc/resolv.conf.
+const FALLBACK_RESOLV_CONF: &str = "nameserver 1.1.1.1\nnameserver 8.8.8.8\n";
+
+/// Used when the host has no os-release.
+const FALLBACK_OS_RELEASE: &str = "NAME=\"OpenSandbox\"\nID=opensandbox\nPRETTY_NAME=\"OpenSandbox\"\n";
+
+/// The machine ID to install, if any: the configured one, else one derived
+/// from the seed.
+pub fn machine_id(determinism: &Determinism) -> Option<String> {
+    determinism.machine_id.clone().or_else(|| {
+        determinism.seed.map(|seed| {
+            let digest = Sha256::digest(format!("machine-id:{}", seed));
+            hex::encode(&digest[..16])
+        })
+    })
+}
+
+/// Configuration for running a command in the sandbox.
+#[derive(Debug, Clone)]
+pub struct RunConfig {
+    pub command: Vec<String>,
+    pub time_ms: u64,
+    pub mem_kb: u64,
+    pub fsize_kb: u64,
+    pub nofile: u64,
+    /// RLIMIT_NPROC, 0 for none. Background runs ignore this; the session's
+    /// cgroup `pids.max` bounds them.
+    pub nproc: u64,
+    pub env: HashMap<String, String>,
+    pub cwd: String,
+    /// Stage file changes in an overlay and keep them only if the command exits 0
+    pub commit_on_success: bool,
+    /// The session's determinism settings. Runs apply the hostname; the rest
+    /// is set up on the sandbox by [`apply_determinism`].
+    pub determinism: Option<Determinism>,
+    /// Bytes fed to the command's stdin, which then sees EOF. Without them
+    /// stdin is left as is. Background runs ignore this.
+    pub stdin: Option<Vec<u8>>,
+    /// Bytes of stdout and of stderr kept in the result; the rest is counted
+    /// and dropped. Background runs ignore this.
+    pub max_output_bytes: u64,
+    /// Once the run's processes together used `time_ms` of CPU they get
+    /// SIGTERM, then SIGKILL this long after. Background runs ignore this.
+    pub kill_grace_ms: u64,
+    /// Session secret values in `env`, masked in the result's output and in
+    /// logs of the command
+    pub secrets: Vec<String>,
+}
+
+/// Run a command in a fresh sandbox (no session, cleanup after).
+pub fn run_oneshot(config: &RunConfig) -> Result<RunResult, String> {
+    info!("=== run_oneshot called ===");
+    info!(command = ?config.command, "Command to run");
+    let sandbox_root = PathBuf::from("/tmp/sandbox-oneshot");
+    info!("Setting up sandbox dir...");
+    setup_sandbox_dir(&sandbox_root, None, None)?;
+    info!("Sandbox dir ready, running command...");
+    let result = run_in_sandbox(&sandbox_root, config, None, None, None);
+    info!(result = ?result, "Command finished");
+    cleanup_sandbox(&sandbox_root);
+    result
+}
+
+/// Run a command in an existing session sandbox.
+pub fn run_in_session(sandbox_root: &Path, config: &RunConfig) -> Result<RunResult, String> {
+    run_in_session_live(sandbox_root, config, None)
+}
+
+/// [`run_in_session`], sharing the run's output and a way to stop it through
+/// `live` while it goes on.
+pub fn run_in_session_live(
+    sandbox_root: &Path,
+    config: &RunConfig,
+    live: Option<&LiveRun>,
+) -> Result<RunResult, String> {
+    let network = network_namespace(sandbox_root);
+    let cgroup = cgroup(sandbox_root);
+    let result = if config.commit_on_success && userns::is_rootless_root(sandbox_root) {
+        Err("commit_on_success needs the chroot backend".to_string())
+    } else if config.commit_on_success {
+        run_transactional(sandbox_root, config, live, network, cgroup)
+    } else {
+        run_in_sandbox(sandbox_root, config, live, network, cgroup)
+    };
+    result.map(|mut r| {
+        r.stdout = secrets::redact(&r.stdout, &config.secrets);
+        r.stderr = secrets::redact(&r.stderr, &config.secrets);
+        r
+    })
+}
+
+/// Run a command against an overlay of the session root. File changes land in
+/// the overlay's upper dir and are copied into the session only when the
+/// command exits 0 (within its time limit); otherwise they are discarded.
+fn run_transactional(
+    sandbox_root: &Path,
+    config: &RunConfig,
+    live: Option<&LiveRun>,
+    network: Option<Arc<OwnedFd>>,
+    cgroup: Option<Arc<SessionCgroup>>,
+) -> Result<RunResult, String> {
+    let txn = FileTransaction::begin(sandbox_root)?;
+    let result = run_in_sandbox(&txn.merged, config, live, network, cgroup);
+    let mut result = match result {
+        Ok(r) => r,
+        Err(e) => {
+            txn.rollback();
+            return Err(e);
+        }
+    };
+    if result.exit_code == Some(0) {
+        txn.commit()?;
+        result.committed = Some(true);
+    } else {
+        info!(exit_code = ?result.exit_code, signal = ?result.signal, "Discarding staged file changes");
+        txn.rollback();
+        result.committed = Some(false);
+    }
+    Ok(result)
+}
+
+/// A run in progress as seen from outside it: the output kept so far, and a
+/// way to kill it. Output is kept as [`run_in_session`] would keep it and is
+/// raw, so secrets aren't masked yet.
+#[derive(Default)]
+pub struct LiveRun {
+    stdout: Arc<Mutex<LiveOutput>>,
+    stderr: Arc<Mutex<LiveOutput>>,
+    /// The run's first process, until it has exited
+    child: Mutex<Option<nix::unistd::Pid>>,
+    cancelled: AtomicBool,
+}
+
+/// One output stream of a [`LiveRun`].
+#[derive(Debug, Clone, Default)]
+pub struct LiveOutput {
+    /// The first `max_output_bytes` of the stream
+    pub kept: Vec<u8>,
+    /// Everything written so far, including what wasn't kept
+    pub bytes: u64,
+}
+
+impl LiveRun {
+    pub fn stdout(&self) -> LiveOutput {
+        self.stdout.lock().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+
+    pub fn stderr(&self) -> LiveOutput {
+        self.stderr.lock().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+
+    /// Kill the run: SIGKILL to its first process, the init of its PID
+    /// namespace, takes everything it started down too. A run that hasn't
+    /// started yet is killed as soon as it does.
+    pub fn cancel(&self) {
+        let child = self.child.lock().unwrap_or_else(|e| e.into_inner());
+        self.cancelled.store(true, Ordering::SeqCst);
+        if let Some(pid) = *child {
+            let _ = nix::sys::signal::kill(pid, Signal::SIGKILL);
+        }
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst)
+    }
+
+    fn started(&self, pid: nix::unistd::Pid) {
+        let mut child = self.child.lock().unwrap_or_else(|e| e.into_inner());
+        if self.is_cancelled() {
+            let _ = nix::sys::signal::kill(pid, Signal::SIGKILL);
+        }
+        *child = Some(pid);
+    }
+
+    /// The first process has exited but isn't reaped yet, so its PID can't
+    /// have been reused by anything [`LiveRun::cancel`] would then kill.
+    fn exited(&self) {
+        *self.child.lock().unwrap_or_else(|e| e.into_inner()) = None;
+    }
+}
+
+/// Staged view of a session root: an overlayfs whose lower layer is the
+/// session tmpfs and whose upper layer collects the run's mutations.
+struct FileTransaction {
+    lower: PathBuf,
+    /// Layers the overlay stacks on: the session's own upper dir and lower
+    /// layers rather than its merged root, so the stack stays shallow
+    lowers: Vec<PathBuf>,
+    staging: PathBuf,
+    upper: PathBuf,
+    merged: PathBuf,
+}
+
+impl FileTransaction {
+    fn begin(sandbox_root: &Path) -> Result<Self, String> {
+        let staging = PathBuf::from(format!("/tmp/sandbox-txn-{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(&staging).map_err(|e| format!("mkdir staging: {}", e))?;
+        // overlayfs can't use an overlay (e.g. a container's /tmp) as its upper
+        // layer, so give the staging area its own tmpfs
+        mount(
+            Some("tmpfs"),
+            &staging,
+            Some("tmpfs"),
+            MsFlags::MS_NOSUID | MsFlags::MS_NODEV,
+            Some("size=2G,mode=755"),
+        )
+        .map_err(|e| format!("mount staging tmpfs: {}", e))?;
+
+        let txn = Self {
+            lowers: overlay_layers(sandbox_root).unwrap_or_else(|| vec![sandbox_root.to_path_buf()]),
+            lower: sandbox_root.to_path_buf(),
+            upper: staging.join("upper"),
+            merged: staging.join("merged"),
+            staging,
+        };
+        if let Err(e) = txn.mount_overlay() {
+            txn.rollback();
+            return Err(e);
+        }
+        Ok(txn)
+    }
+
+    fn mount_overlay(&self) -> Result<(), String> {
+        let work = self.staging.join("work");
+        for dir in [&self.upper, &work, &self.merged] {
+            fs::create_dir_all(dir).map_err(|e| format!("mkdir {}: {}", dir.display(), e))?;
+        }
+        // The merged root takes its owner from the upper dir
+        chown_entry(&self.upper, sandbox_owner(&self.lower))?;
+        // redirect_dir/metacopy off: every change must be a self-contained
+        // upper entry so commit can replay it onto the session root. index off:
+        // the session's upper dir is in use by its own mount
+        let options = format!(
+            "lowerdir={},upperdir={},workdir={},redirect_dir=off,metacopy=off,index=off",
+            join_lowers(&self.lowers),
+            self.upper.display(),
+            work.display()
+        );
+        mount(
+            Some("overlay"),
+            &self.merged,
+            Some("overlay"),
+            MsFlags::MS_NOSUID | MsFlags::MS_NODEV,
+            Some(options.as_str()),
+        )
+        .map_err(|e| format!("mount overlay: {}", e))?;
+        // The overlay only sees the session's files, not its submounts
+        if !is_image_root(&self.lower) {
+            mount_system_dirs(&self.merged, &etc_overrides(&self.lower))?;
+        }
+        mount_devices_and_proc(&self.merged)?;
+        Ok(())
+    }
+
+    /// Replay the upper layer onto the session root, then tear down.
+    fn commit(self) -> Result<(), String> {
+        // The session's layers must not change under a mounted overlay
+        cleanup_sandbox(&self.merged);
+        let result = apply_overlay_upper(
+            &self.upper,
+            &self.lower,
+            true,
+            sandbox_owner(&self.lower),
+            &mut Progress::disabled(),
+        );
+        self.rollback();
+        result
+    }
+
+    /// Tear down the overlay and drop all staged changes.
+    fn rollback(self) {
+        cleanup_sandbox(&self.merged);
+        let _ = umount2(&self.staging, MntFlags::MNT_DETACH);
+        let _ = fs::remove_dir_all(&self.staging);
+    }
+}
+
+/// Copy an overlayfs upper dir onto `lower`, honoring whiteouts and opaque dirs.
+fn apply_overlay_upper(
+    upper: &Path,
+    lower: &Path,
+    top_level: bool,
+    owner: Option<(u32, u32)>,
+    progress: &mut Progress,
+) -> Result<(), String> {
+    use std::os::unix::fs::{FileTypeExt, MetadataExt};
+
+    let entries = fs::read_dir(upper).map_err(|e| format!("read {}: {}", upper.display(), e))?;
+    for entry in entries {
+        let entry = entry.map_err(|e| format!("read entry: {}", e))?;
+        let name = entry.file_name();
+        // Never write through the system bind mounts, /dev, or /proc
+        if top_level && is_mounted_top_level(&name.to_string_lossy()) {
+            continue;
+        }
+        let src = entry.path();
+        let dst = lower.join(&name);
+        let meta = fs::symlink_metadata(&src).map_err(|e| format!("stat {}: {}", src.display(), e))?;
+        let file_type = meta.file_type();
+
+        if file_type.is_char_device() && meta.rdev() == 0 {
+            // Whiteout: the file was deleted in the transaction
+            remove_path(&dst)?;
+        } else if file_type.is_dir() {
+            // Opaque dirs replace the lower dir wholesale; a dir also replaces a non-dir
+            let replaces_lower = is_opaque_dir(&src)
+                || dst.symlink_metadata().map(|m| !m.is_dir()).unwrap_or(false);
+            if replaces_lower {
+                remove_path(&dst)?;
+            }
+            fs::create_dir_all(&dst).map_err(|e| format!("mkdir {}: {}", dst.display(), e))?;
+            fs::set_permissions(&dst, meta.permissions())
+                .map_err(|e| format!("chmod {}: {}", dst.display(), e))?;
+            chown_entry(&dst, owner)?;
+            apply_overlay_upper(&src, &dst, false, owner, progress)?;
+        } else if file_type.is_symlink() {
+            remove_path(&dst)?;
+            let target = fs::read_link(&src).map_err(|e| format!("readlink {}: {}", src.display(), e))?;
+            std::os::unix::fs::symlink(&target, &dst)
+                .map_err(|e| format!("symlink {}: {}", dst.display(), e))?;
+            chown_entry(&dst, owner)?;
+            progress.add_file(meta.len());
+        } else if file_type.is_file() {
+            if dst.symlink_metadata().map(|m| !m.is_file()).unwrap_or(false) {
+                remove_path(&dst)?;
+            }
+            fs::copy(&src, &dst).map_err(|e| format!("copy {}: {}", dst.display(), e))?;
+            chown_entry(&dst, owner)?;
+            progress.add_file(meta.len());
+        }
+        // Other special files (fifos, sockets, devices) are not committed
+    }
+    Ok(())
+}
+
+/// Top-level sandbox entries that are mountpoints set up by the sandbox itself.
+pub(crate) fn is_mounted_top_level(name: &str) -> bool {
+    name == "proc" || name == "dev" || SYSTEM_BIND_DIRS.iter().any(|d| d[1..] == *name)
+}
+
+/// Copy a directory tree into `dst`, preserving modes and symlinks.
+pub(crate) fn copy_tree(src: &Path, dst: &Path, progress: &mut Progress) -> Result<(), String> {
+    apply_overlay_upper(src, dst, false, None, progress)
+}
+
+fn remove_path(path: &Path) -> Result<(), String> {
+    match fs::symlink_metadata(path) {
+        Ok(m) if m.is_dir() => fs::remove_dir_all(path),
+        Ok(_) => fs::remove_file(path),
+        Err(_) => return Ok(()),
+    }
+    .map_err(|e| format!("remove {}: {}", path.display(), e))
+}
+
+fn is_opaque_dir(path: &Path) -> bool {
+    use std::os::unix::ffi::OsStrExt;
+
+    let Ok(c_path) = CString::new(path.as_os_str().as_bytes()) else {
+        return false;
+    };
+    let mut buf = [0u8; 4];
+    let len = unsafe {
+        libc::lgetxattr(
+            c_path.as_ptr(),
+            c"trusted.overlay.opaque".as_ptr(),
+            buf.as_mut_ptr() as *mut libc::c_void,
+            buf.len(),
+        )
+    };
+    len == 1 && buf[0] == b'y'
+}
+
+/// Start a background process in a session. Unlike [`run_in_session`] it
+/// gets no PID namespace, so it outlives the call. The caller owns the
+/// returned child and must wait on it so it is reaped when it exits. Its
+/// output goes to a log of its own; see [`crate::background_log`].
+pub fn run_background_in_session(sandbox_root: &Path, config: &RunConfig) -> Result<Child, String> {
+    let command = secrets::redact_all(&config.command, &config.secrets);
+    info!(command = ?command, "Starting background process in {:?}", sandbox_root);
+
+    // Opened here (host side, before chroot) and named after the PID once it's known
+    let (log_file, starting_path) = background_log::create(sandbox_root)?;
+    let log_file_err = log_file
+        .try_clone()
+        .map_err(|e| format!("clone log file: {}", e))?;
+
+    let mut cmd = sandboxed_command(sandbox_root, config)?;
+    cmd.stdout(log_file)
+        .stderr(log_file_err)
+        .stdin(std::process::Stdio::null());
+
+    let mut child = cmd.spawn()
+        .map_err(|e| spawn_error("spawn background", e))?;
+
+    let pid = child.id();
+    let log_path = match background_log::name_after(sandbox_root, &starting_path, pid) {
+        Ok(path) => path,
+        Err(e) => {
+            warn!(pid = pid, "Background log keeps its temporary name: {}", e);
+            starting_path
+        }
+    };
+    info!(pid = pid, log = %log_path.display(), "Background process started successfully");
+
+    // Wait briefly and check if the process is still alive
+    std::thread::sleep(Duration::from_millis(500));
+    let alive = matches!(child.try_wait(), Ok(None));
+    info!(pid = pid, alive = alive, "Background process status check");
+
+    if !alive {
+        // Process died immediately - read the log to see why
+        let log_content = safe_path::Root::open(sandbox_root)
+            .and_then(|root| root.read(&log_path))
+            .map(|log| String::from_utf8_lossy(&log).into_owned())
+            .unwrap_or_default();
+        let truncated = if log_content.len() > 2000 {
+            &log_content[log_content.len() - 2000..]
+        } else {
+            &log_content
+        };
+        info!(pid = pid, "Background process died immediately. Log:\n{}", truncated);
+        return Err(format!(
+            "Background process (pid {}) died immediately. Log output:\n{}",
+            pid, truncated
+        ));
+    }
+
+    Ok(child)
+}
+
+/// A command that runs `config.command` chrooted into a sandbox, as its
+/// user, with the session's env and cwd. It is not in the sandbox's PID
+/// namespace, so it outlives the request that started it; stdio is left to
+/// the caller.
+pub(crate) fn sandboxed_command(sandbox_root: &Path, config: &RunConfig) -> Result<std::process::Command, String> {
+    use std::os::unix::process::CommandExt;
+    use std::process::Command;
+
+    // Build environment
+    let mut env_vars: Vec<(String, String)> = config
+        .env
+        .iter()
+        .map(|(k, v)| (k.clone(), v.clone()))
+        .collect();
+    // Images set their own PATH
+    if !config.env.contains_key("PATH") {
+        env_vars.push(("PATH".to_string(), "/usr/local/sbin:/usr/local/bin:/usr/sbin:/usr/bin:/sbin:/bin".to_string()));
+    }
+    env_vars.push(("HOME".to_string(), "/home".to_string()));
+
+    let cwd = if config.cwd.is_empty() || config.cwd == "/" {
+        "/".to_string()
+    } else {
+        config.cwd.clone()
+    };
+
+    // Use pre_exec to chroot into the sandbox after fork but before exec.
+    // This avoids needing the chroot binary (which may not be in PATH) and
+    // avoids CLONE_NEWPID (which kills child processes when parent exits).
+    let sandbox_root_owned = sandbox_root.to_path_buf();
+    let cwd_for_preexec = cwd.clone();
+    let hostname = config.determinism.as_ref().and_then(|d| d.hostname.clone());
+    let owner = sandbox_owner(sandbox_root);
+    let network = network_namespace(sandbox_root);
+
+    // Execute the command array directly instead of wrapping in sh -c.
+    // The client may already send ["sh", "-c", "npm run dev"], so wrapping
+    // again would break argument parsing (sh -c npm treats "run" as $0).
+    // pre_exec handles chroot and chdir, so no need for a shell wrapper.
+    if config.command.is_empty() {
+        return Err("empty command".to_string());
+    }
+    let mut cmd = Command::new(&config.command[0]);
+    for arg in &config.command[1..] {
+        cmd.arg(arg);
+    }
+    cmd.env_clear().envs(env_vars);
+
+    // Runs first of the pre_exec hooks, so with either backend
+    if let Some(cgroup) = cgroup(sandbox_root) {
+        unsafe {
+            cmd.pre_exec(move || cgroup.join());
+        }
+    }
+
+    if userns::is_rootless_root(sandbox_root) {
+        userns::sandbox_command(&mut cmd, sandbox_root, move || {
+            if let Some(ref hostname) = hostname {
+                set_hostname(hostname).map_err(std::io::Error::other)?;
+            }
+            nix::unistd::chdir(cwd_for_preexec.as_str())
+                .map_err(|e| std::io::Error::other(format!("chdir: {}", e)))
+        });
+        return Ok(cmd);
+    }
+
+    unsafe {
+        cmd.pre_exec(move || {
+            if let Some(ref hostname) = hostname {
+                set_hostname(hostname).map_err(std::io::Error::other)?;
+            }
+            if let Some(ref ns) = network {
+                nix::sched::setns(ns, CloneFlags::CLONE_NEWNET)
+                    .map_err(|e| std::io::Error::other(format!("join network namespace: {}", e)))?;
+            }
+            // chroot into sandbox filesystem
+            nix::unistd::chroot(&sandbox_root_owned)
+                .map_err(|e| std::io::Error::other(format!("chroot: {}", e)))?;
+            // chdir to working directory
+            nix::unistd::chdir(cwd_for_preexec.as_str())
+                .map_err(|e| std::io::Error::other(format!("chdir: {}", e)))?;
+            // After chroot, which needs root
+            if let Some((uid, gid)) = owner {
+                drop_privileges(uid, gid).map_err(std::io::Error::other)?;
+            }
+            Ok(())
+        });
+    }
+    Ok(cmd)
+}
+
+/// Check if a process is still alive.
+pub fn is_process_alive(pid: u32) -> bool {
+    // kill with signal 0 checks if process exists without sending a signal
+    nix::sys::signal::kill(
+        nix::unistd::Pid::from_raw(pid as i32),
+        None, // signal 0
+    )
+    .is_ok()
+}
+
+/// Tells which of a set of sandbox roots a process lives in. A chrooted
+/// process's `/proc/<pid>/root` reads as its sandbox root, but a rootless
+/// one has pivoted into its root, so the link reads `/` and only the
+/// directory's device and inode name the sandbox.
+pub(crate) struct ProcessRoots<'a> {
+    roots: &'a [PathBuf],
+    rootless: HashMap<(u64, u64), &'a PathBuf>,
+}
+
+impl<'a> ProcessRoots<'a> {
+    pub(crate) fn new(roots: &'a [PathBuf]) -> Self {
+        use std::os::unix::fs::MetadataExt;
+        let rootless = roots
+            .iter()
+            .filter(|root| userns::is_rootless_root(root))
+            .filter_map(|root| fs::metadata(root).ok().map(|m| ((m.dev(), m.ino()), root)))
+            .collect();
+        ProcessRoots { roots, rootless }
+    }
+
+    /// The root `pid` lives in, if it's one of ours.
+    pub(crate) fn of(&self, pid: u32) -> Option<&'a PathBuf> {
+        use std::os::unix::fs::MetadataExt;
+        let link = fs::read_link(format!("/proc/{}/root", pid)).ok()?;
+        if let Some(root) = self.roots.iter().find(|root| **root == link) {
+            return Some(root);
+        }
+        if self.rootless.is_empty() || link != Path::new("/") {
+            return None;
+        }
+        let meta = fs::metadata(format!("/proc/{}/root/", pid)).ok()?;
+        self.rootless.get(&(meta.dev(), meta.ino())).copied()
+    }
+}
+
+/// Find every host PID whose root directory is this sandbox (i.e. processes
+/// chrooted into it, including descendants of background processes).
+pub fn session_pids(sandbox_root: &Path) -> Vec<u32> {
+    let Ok(entries) = fs::read_dir("/proc") else {
+        return Vec::new();
+    };
+    let roots = [sandbox_root.to_path_buf()];
+    let roots = ProcessRoots::new(&roots);
+    entries
+        .filter_map(|e| e.ok())
+        .filter_map(|e| e.file_name().to_str().and_then(|n| n.parse::<u32>().ok()))
+        .filter(|&pid| roots.of(pid).is_some())
+        .collect()
+}
+
+/// Send a signal to every process in the sandbox. Returns the PIDs signalled.
+pub fn signal_session_processes(sandbox_root: &Path, signal: Signal) -> Vec<u32> {
+    signal_processes(&session_pids(sandbox_root), signal)
+}
+
+/// Send a signal to each of `pids`, e.g. found earlier by [`session_pids`].
+/// Returns the PIDs signalled, leaving out those that have exited.
+pub fn signal_processes(pids: &[u32], signal: Signal) -> Vec<u32> {
+    pids.iter()
+        .copied()
+        .filter(|&pid| {
+            nix::sys::signal::kill(nix::unistd::Pid::from_raw(pid as i32), signal).is_ok()
+        })
+        .collect()
+}
+
+/// Whether a process of the sandbox has a TCP socket listening on `port`,
+/// on any address. Tells the session's own listeners from anything else on
+/// the host, which sandboxes share the network of.
+pub fn session_listens_on(sandbox_root: &Path, port: u16) -> bool {
+    listening_ports(&session_pids(sandbox_root)).contains(&port)
+}
+
+/// TCP ports that any of `pids`, all processes of one sandbox, listen on.
+pub(crate) fn listening_ports(pids: &[u32]) -> BTreeSet<u16> {
+    let Some(&pid) = pids.first() else {
+        return BTreeSet::new();
+    };
+    // Listening sockets in the socket tables of the process's network
+    // namespace, by inode
+    let mut sockets = HashMap::new();
+    for table in ["tcp", "tcp6"] {
+        let Ok(content) = fs::read_to_string(format!("/proc/{}/net/{}", pid, table)) else {
+            continue;
+        };
+        for line in content.lines().skip(1) {
+            // sl local_address rem_address st ... inode, addresses as hex ADDR:PORT
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let (Some(local), Some(&state), Some(inode)) = (fields.get(1), fields.get(3), fields.get(9)) else {
+                continue;
+            };
+            let local_port = local.rsplit_once(':').and_then(|(_, p)| u16::from_str_radix(p, 16).ok());
+            if let (Some(port), "0A") = (local_port, state) {
+                sockets.insert(format!("socket:[{}]", inode), port);
+            }
+        }
+    }
+    let mut ports = BTreeSet::new();
+    if sockets.is_empty() {
+        return ports;
+    }
+    for pid in pids {
+        let Ok(fds) = fs::read_dir(format!("/proc/{}/fd", pid)) else {
+            continue;
+        };
+        for fd in fds.flatten() {
+            let Ok(target) = fs::read_link(fd.path()) else {
+                continue;
+            };
+            if let Some(&port) = target.to_str().and_then(|t| sockets.get(t)) {
+                ports.insert(port);
+            }
+        }
+    }
+    ports
+}
+
+/// Connect to `port` on the sandbox's loopback, from its network namespace
+/// if it has one of its own.
+pub fn connect_to_session(sandbox_root: &Path, port: u16, timeout: Duration) -> std::io::Result<std::net::TcpStream> {
+    let addr = std::net::SocketAddr::from((std::net::Ipv4Addr::LOCALHOST, port));
+    let Some(ns) = network_namespace(sandbox_root) else {
+        return std::net::TcpStream::connect_timeout(&addr, timeout);
+    };
+    // setns moves the calling thread, so connect from one of our own; the
+    // socket stays in the namespace it was made in
+    std::thread::spawn(move || {
+        nix::sched::setns(&*ns, CloneFlags::CLONE_NEWNET).map_err(std::io::Error::from)?;
+        std::net::TcpStream::connect_timeout(&addr, timeout)
+    })
+    .join()
+    .map_err(|_| std::io::Error::other("connect thread panicked"))?
+}
+
+/// Create a new session sandbox directory, layered on `template` if given,
+/// and on the root filesystem of an OCI image (see [`crate::images`])
+/// instead of the host's system directories if `image` is given.
+pub fn create_session_sandbox(
+    session_id: &str,
+    template: Option<&Path>,
+    image: Option<&Path>,
+) -> Result<PathBuf, String> {
+    let sandbox_root = PathBuf::from(format!("/tmp/sandbox-{}", session_id));
+    setup_sandbox_dir(&sandbox_root, template, image)?;
+    Ok(sandbox_root)
+}
+
+/// Create a sandbox directory for the warm pool (not yet tied to a session).
+pub fn create_pooled_sandbox() -> Result<PathBuf, String> {
+    let sandbox_root = PathBuf::from(format!("/tmp/sandbox-pool-{}", uuid::Uuid::new_v4()));
+    setup_sandbox_dir(&sandbox_root, None, None)?;
+    Ok(sandbox_root)
+}
+
+/// Layer every sandbox created from now on over `dir`, e.g. a shared
+/// toolchain. Only the first call takes effect.
+pub fn set_base_layer(dir: PathBuf) {
+    let _ = BASE_LAYER.set(dir);
+}
+
+pub fn base_layer() -> Option<&'static Path> {
+    BASE_LAYER.get().map(PathBuf::as_path)
+}
+
+/// Run the sandbox's commands from now on in the network namespace `ns`
+/// (a descriptor of one, e.g. `/proc/{pid}/ns/net`) instead of the host's;
+/// `None` goes back to the host's.
+pub fn set_network_namespace(sandbox_root: &Path, ns: Option<OwnedFd>) {
+    let mut namespaces = NETWORK_NAMESPACES.lock().unwrap_or_else(|e| e.into_inner());
+    let namespaces = namespaces.get_or_insert_with(HashMap::new);
+    match ns {
+        Some(ns) => namespaces.insert(sandbox_root.to_path_buf(), Arc::new(ns)),
+        None => namespaces.remove(sandbox_root),
+    };
+}
+
+fn network_namespace(sandbox_root: &Path) -> Option<Arc<OwnedFd>> {
+    let namespaces = NETWORK_NAMESPACES.lock().unwrap_or_else(|e| e.into_inner());
+    namespaces.as_ref()?.get(sandbox_root).cloned()
+}
+
+/// Move the sandbox's commands from now on into a session's cgroup; `None`
+/// leaves them in the server's. See [`crate::cgroups`].
+pub fn set_cgroup(sandbox_root: &Path, cgroup: Option<SessionCgroup>) {
+    let mut cgroups = CGROUPS.lock().unwrap_or_else(|e| e.into_inner());
+    let cgroups = cgroups.get_or_insert_with(HashMap::new);
+    match cgroup {
+        Some(cgroup) => cgroups.insert(sandbox_root.to_path_buf(), Arc::new(cgroup)),
+        None => cgroups.remove(sandbox_root),
+    };
+}
+
+fn cgroup(sandbox_root: &Path) -> Option<Arc<SessionCgroup>> {
+    let cgroups = CGROUPS.lock().unwrap_or_else(|e| e.into_inner());
+    cgroups.as_ref()?.get(sandbox_root).cloned()
+}
+
+/// Session and warm-pool sandbox roots left on disk, e.g. by a previous
+/// server process. Transaction staging dirs and the one-shot root are not
+/// included.
+pub fn existing_sandbox_roots() -> Vec<PathBuf> {
+    let Ok(entries) = fs::read_dir("/tmp") else {
+        return Vec::new();
+    };
+    entries
+        .filter_map(|e| e.ok())
+        .filter(|e| {
+            e.file_name().to_str().and_then(|n| n.strip_prefix("sandbox-")).is_some_and(|rest| {
+                let id = rest.strip_prefix("pool-").unwrap_or(rest);
+                uuid::Uuid::parse_str(id).is_ok()
+            })
+        })
+        .map(|e| e.path())
+        .collect()
+}
+
+/// Whether a sandbox root still has its tmpfs mounted (it sits on a
+/// different device than its parent directory). Rootless roots have
+/// nothing mounted and only need to exist.
+pub fn is_sandbox_mounted(sandbox_root: &Path) -> bool {
+    use std::os::unix::fs::MetadataExt;
+
+    if userns::is_rootless_root(sandbox_root) {
+        return sandbox_root.is_dir();
+    }
+
+    let Some(parent) = sandbox_root.parent() else {
+        return false;
+    };
+    match (fs::metadata(sandbox_root), fs::metadata(parent)) {
+        (Ok(root), Ok(parent)) => root.is_dir() && root.dev() != parent.dev(),
+        _ => false,
+    }
+}
+
+/// Copy a template's files into a sandbox root. Entries shadowing the
+/// system mounts, /dev, or /proc are skipped.
+pub fn populate_from_template(
+    sandbox_root: &Path,
+    template_dir: &Path,
+    progress: &mut Progress,
+) -> Result<(), String> {
+    apply_overlay_upper(template_dir, sandbox_root, true, sandbox_owner(sandbox_root), progress)
+}
+
+/// Cleanup a session sandbox.
+pub fn destroy_session_sandbox(sandbox_root: &Path) {
+    if userns::is_rootless_root(sandbox_root) {
+        userns::remove_root(sandbox_root);
+    } else {
+        cleanup_sandbox(sandbox_root);
+    }
+}
+
+/// Write a file directly into the sandbox filesystem.
+pub fn write_file_in_sandbox(sandbox_root: &Path, path: &str, content: &[u8]) -> Result<(), String> {
+    // Owned by the sandbox's user, so its commands can change the file
+    safe_path::Root::open(sandbox_root)?.write(Path::new(path), content, sandbox_owner(sandbox_root))
+}
+
+/// Write into a file in the sandbox filesystem at `at`, e.g. to append to it.
+pub fn write_file_in_sandbox_at(
+    sandbox_root: &Path,
+    path: &str,
+    content: &[u8],
+    at: safe_path::WriteAt,
+) -> Result<(), String> {
+    safe_path::Root::open(sandbox_root)?.write_at(Path::new(path), content, at, sandbox_owner(sandbox_root))
+}
+
+/// Read a file directly from the sandbox filesystem.
+pub fn read_file_in_sandbox(sandbox_root: &Path, path: &str) -> Result<Vec<u8>, String> {
+    safe_path::Root::open(sandbox_root)?.read(Path::new(path))
+}
+
+/// Open a file in the sandbox filesystem for reading, e.g. to stream it.
+pub fn open_file_in_sandbox(sandbox_root: &Path, path: &str) -> Result<fs::File, String> {
+    safe_path::Root::open(sandbox_root)?.open_file(Path::new(path))
+}
+
+/// Most symlinks followed while resolving a cwd, like the kernel's limit.
+const MAX_CWD_SYMLINKS: usize = 40;
+
+/// Check that `cwd` is an absolute path naming a directory inside the
+/// sandbox. `..` and symlinks are resolved as a sandboxed process sees them,
+/// with absolute link targets starting at the sandbox root, except that `..`
+/// above the root is an escape rather than a no-op.
+pub fn confine_cwd(sandbox_root: &Path, cwd: &str) -> Result<(), String> {
+    use std::path::Component;
+
+    if !cwd.starts_with('/') {
+        return Err(format!("cwd {:?} must be an absolute path", cwd));
+    }
+    // Components still to resolve, the next one last
+    let parts = |path: &Path| -> Vec<std::ffi::OsString> {
+        let mut parts: Vec<_> = path
+            .components()
+            .filter_map(|c| match c {
+                Component::ParentDir => Some("..".into()),
+                Component::Normal(name) => Some(name.to_os_string()),
+                _ => None,
+            })
+            .collect();
+        parts.reverse();
+        parts
+    };
+    let mut pending = parts(Path::new(cwd));
+    let mut resolved = PathBuf::new();
+    let mut links = 0;
+    while let Some(part) = pending.pop() {
+        if part == ".." {
+            if !resolved.pop() {
+                return Err(format!("cwd {:?} is outside the sandbox", cwd));
+            }
+            continue;
+        }
+        let candidate = resolved.join(&part);
+        let host = sandbox_root.join(&candidate);
+        match fs::symlink_metadata(&host) {
+            Ok(meta) if meta.file_type().is_symlink() => {
+                links += 1;
+                if links > MAX_CWD_SYMLINKS {
+                    return Err(format!("cwd {:?} has too many levels of symlinks", cwd));
+                }
+                let target = fs::read_link(&host).map_err(|e| format!("cwd {:?}: {}", cwd, e))?;
+                if target.is_absolute() {
+                    resolved = PathBuf::new();
+                }
+                pending.extend(parts(&target));
+            }
+            Ok(meta) if meta.is_dir() => resolved = candidate,
+            Ok(_) => return Err(format!("cwd {:?} is not a directory", cwd)),
+            Err(_) => return Err(format!("cwd {:?} does not exist", cwd)),
+        }
+    }
+    Ok(())
+}
+
+/// Unpack a tar (or tar.gz) archive into directory `path` of the sandbox,
+/// creating it if needed. Paths under the system mounts, /dev, or /proc are
+/// refused.
+pub fn extract_archive_in_sandbox(
+    sandbox_root: &Path,
+    path: &str,
+    data: &[u8],
+    mut progress: Progress,
+) -> Result<(), String> {
+    let relative = Path::new(path.trim_start_matches('/'));
+    let mut components = relative.components();
+    match components.next() {
+        Some(std::path::Component::Normal(first)) if !is_mounted_top_level(&first.to_string_lossy()) => {}
+        _ => return Err(format!("cannot extract into {:?}", path)),
+    }
+    if components.any(|c| !matches!(c, std::path::Component::Normal(_))) {
+        return Err(format!("cannot extract into {:?}", path));
+    }
+    let dest = sandbox_root.join(relative);
+    fs::create_dir_all(&dest).map_err(|e| format!("mkdir {}: {}", path, e))?;
+    progress.begin_stage("extract", Some(data.len() as u64), None);
+    let result = crate::templates::unpack_tarball(ProgressReader::new(data, &mut progress), &dest)
+        .and_then(|_| give_to_sandbox_user(sandbox_root, &dest));
+    progress.finish(&result);
+    result
+}
+
+/// Entry in a directory listing.
+#[derive(Debug, Clone)]
+pub struct SandboxFileEntry {
+    pub name: String,
+    pub path: String,
+    pub is_directory: bool,
+    pub size: u64,
+}
+
+/// List files in a directory within the sandbox filesystem.
+pub fn list_files_in_sandbox(sandbox_root: &Path, path: &str) -> Result<Vec<SandboxFileEntry>, String> {
+    // Normalize the path to be relative to sandbox root
+    let normalized_path = if path.starts_with('/') {
+        path.trim_start_matches('/')
+    } else {
+        path
+    };
+
+    let entries = safe_path::Root::open(sandbox_root)?.list(Path::new(normalized_path))?;
+
+    let mut result = Vec::new();
+    for entry in entries {
+        // Compute the path as it appears inside the sandbox (with leading /)
+        let entry_path = if normalized_path.is_empty() {
+            format!("/{}", entry.name)
+        } else if path.starts_with('/') {
+            format!("{}/{}", path.trim_end_matches('/'), entry.name)
+        } else {
+            format!("/{}/{}", normalized_path.trim_end_matches('/'), entry.name)
+        };
+
+        result.push(SandboxFileEntry {
+            name: entry.name,
+            path: entry_path,
+            is_directory: entry.is_directory,
+            size: entry.size,
+        });
+    }
+
+    // Sort by name for consistent ordering
+    result.sort_by(|a, b| a.name.cmp(&b.name));
+
+    Ok(result)
+}
+
+/// Build a sandbox root: an overlay whose upper dir (on a tmpfs of its own)
+/// takes the session's writes, over the template if any, then the image or
+/// the base layer.
+fn setup_sandbox_dir(sandbox_root: &Path, template: Option<&Path>, image: Option<&Path>) -> Result<(), String> {
+    // Clean up if exists
+    if sandbox_root.exists() {
+        cleanup_sandbox(sandbox_root);
+    }
+
+    fs::create_dir_all(sandbox_root).map_err(|e| format!("mkdir: {}", e))?;
+    let layers = layers_dir(sandbox_root).ok_or_else(|| format!("invalid sandbox root {}", sandbox_root.display()))?;
+    fs::create_dir_all(&layers).map_err(|e| format!("mkdir {}: {}", layers.display(), e))?;
+    // overlayfs can't use an overlay (e.g. a container's /tmp) as its upper
+    // layer, so the layers get their own tmpfs
+    mount(
+        Some("tmpfs"),
+        &layers,
+        Some("tmpfs"),
+        MsFlags::MS_NOSUID | MsFlags::MS_NODEV,
+        Some("size=2G,mode=755"),
+    )
+    .map_err(|e| format!("mount layers tmpfs: {}", e))?;
+    let (upper, work) = (layers.join("upper"), layers.join("work"));
+    for dir in [&upper, &work] {
+        fs::create_dir(dir).map_err(|e| format!("mkdir {}: {}", dir.display(), e))?;
+    }
+
+    let mut lowers = Vec::new();
+    if let Some(template) = template {
+        // Mount the template's directory itself, which stays in place if the
+        // template is replaced or removed while the session lives
+        let bound = layers.join("template");
+        fs::create_dir(&bound).map_err(|e| format!("mkdir {}: {}", bound.display(), e))?;
+        mount(Some(template), &bound, None::<&str>, MsFlags::MS_BIND, None::<&str>)
+            .map_err(|e| format!("bind mount template: {}", e))?;
+        lowers.push(bound);
+    }
+    if let Some(image) = image {
+        // Bound like the template; its presence also marks an image root
+        let bound = layers.join(IMAGE_LAYER);
+        fs::create_dir(&bound).map_err(|e| format!("mkdir {}: {}", bound.display(), e))?;
+        mount(Some(image), &bound, None::<&str>, MsFlags::MS_BIND, None::<&str>)
+            .map_err(|e| format!("bind mount image: {}", e))?;
+        lowers.push(bound);
+    } else if let Some(base) = base_layer() {
+        lowers.push(base.to_path_buf());
+    }
+    if lowers.is_empty() {
+        let empty = layers.join("empty");
+        fs::create_dir(&empty).map_err(|e| format!("mkdir {}: {}", empty.display(), e))?;
+        lowers.push(empty);
+    }
+    fs::write(layers.join(LOWERS_FILE), join_lowers(&lowers)).map_err(|e| format!("write lowers: {}", e))?;
+
+    // Mount the overlay at the sandbox root, owned by the sandbox's user
+    let uid = {
+        let _allocating = UID_ALLOCATION.lock().unwrap_or_else(|e| e.into_inner());
+        let uid = free_sandbox_uid()?;
+        // The merged root takes its owner from the upper dir
+        std::os::unix::fs::chown(&upper, Some(uid), Some(uid)).map_err(|e| format!("chown upper: {}", e))?;
+        // redirect_dir/metacopy off: every change is a self-contained upper
+        // entry, so the upper dir can serve as a transaction's lower layer
+        let options = format!(
+            "lowerdir={},upperdir={},workdir={},redirect_dir=off,metacopy=off",
+            join_lowers(&lowers),
+            upper.display(),
+            work.display()
+        );
+        mount(
+            Some("overlay"),
+            sandbox_root,
+            Some("overlay"),
+            MsFlags::MS_NOSUID | MsFlags::MS_NODEV,
+            Some(options.as_str()),
+        )
+        .map_err(|e| format!("mount overlay: {}", e))?;
+        uid
+    };
+
+    if image.is_none() {
+        mount_system_dirs(sandbox_root, &etc_overrides(sandbox_root))?;
+    }
+
+    // Create writable directories
+    let tmp_dir = sandbox_root.join("tmp");
+    fs::create_dir_all(&tmp_dir).map_err(|e| format!("mkdir tmp: {}", e))?;
+    fs::set_permissions(&tmp_dir, fs::Permissions::from_mode(0o1777))
+        .map_err(|e| format!("chmod tmp: {}", e))?;
+
+    // Create home directory for the sandbox
+    let home_dir = sandbox_root.join("home");
+    fs::create_dir_all(&home_dir).map_err(|e| format!("mkdir home: {}", e))?;
+    fs::set_permissions(&home_dir, fs::Permissions::from_mode(0o755))
+        .map_err(|e| format!("chmod home: {}", e))?;
+    std::os::unix::fs::chown(&home_dir, Some(uid), Some(uid)).map_err(|e| format!("chown home: {}", e))?;
+
+    mount_devices_and_proc(sandbox_root)?;
+
+    Ok(())
+}
+
+/// Lists a sandbox's lower layers, topmost first, in its layers dir.
+const LOWERS_FILE: &str = "lowers";
+
+/// Where an image root's image is mounted in its layers dir.
+const IMAGE_LAYER: &str = "image";
+
+/// Where a sandbox root keeps its overlay layers: a tmpfs next to the root,
+/// `/tmp/sandbox-layers-{suffix}` for `/tmp/sandbox-{suffix}`. `None` for
+/// paths that aren't sandbox roots.
+pub(crate) fn layers_dir(sandbox_root: &Path) -> Option<PathBuf> {
+    let suffix = sandbox_root.file_name()?.to_str()?.strip_prefix("sandbox-")?;
+    Some(sandbox_root.with_file_name(format!("sandbox-layers-{}", suffix)))
+}
+
+/// Whether a sandbox root is built from an image, whose system directories
+/// it has instead of the host's.
+fn is_image_root(sandbox_root: &Path) -> bool {
+    layers_dir(sandbox_root).is_some_and(|l| l.join(IMAGE_LAYER).is_dir())
+}
+
+/// A sandbox root's upper dir followed by its lower layers, or `None` for
+/// roots that are a plain tmpfs (created before roots became overlays).
+fn overlay_layers(sandbox_root: &Path) -> Option<Vec<PathBuf>> {
+    let layers = layers_dir(sandbox_root)?;
+    let lowers = fs::read_to_string(layers.join(LOWERS_FILE)).ok()?;
+    Some(
+        std::iter::once(layers.join("upper"))
+            .chain(lowers.split(':').filter(|l| !l.is_empty()).map(PathBuf::from))
+            .collect(),
+    )
+}
+
+/// The layers below a sandbox root's upper dir, topmost first: the files it
+/// started from. Empty for a plain tmpfs root.
+pub(crate) fn original_layers(sandbox_root: &Path) -> Vec<PathBuf> {
+    overlay_layers(sandbox_root).map(|layers| layers[1..].to_vec()).unwrap_or_default()
+}
+
+/// The `lowerdir=` value for these layers, topmost first.
+fn join_lowers(lowers: &[PathBuf]) -> String {
+    lowers.iter().map(|l| l.display().to_string()).collect::<Vec<_>>().join(":")
+}
+
+/// The upper dir of an overlay root, which holds every file the session
+/// created or changed.
+fn upper_dir(sandbox_root: &Path) -> Option<PathBuf> {
+    layers_dir(sandbox_root).map(|l| l.join("upper")).filter(|u| u.is_dir())
+}
+
+/// Where a sandbox's own files are: its upper dir, or for a plain tmpfs
+/// root the root itself, whose top-level system mounts are not its own.
+pub(crate) fn own_files_dir(sandbox_root: &Path) -> PathBuf {
+    upper_dir(sandbox_root).unwrap_or_else(|| sandbox_root.to_path_buf())
+}
+
+/// Where a sandbox's /etc files are stored. For overlay roots that is the
+/// upper dir, so the /etc overlay doesn't stack on the root's overlay.
+fn etc_overrides(sandbox_root: &Path) -> PathBuf {
+    if let Some(dir) = userns::etc_overrides(sandbox_root).filter(|_| userns::is_rootless_root(sandbox_root)) {
+        return dir;
+    }
+    match upper_dir(sandbox_root) {
+        Some(upper) => upper.join(ETC_OVERRIDES_DIR),
+        None => sandbox_root.join(ETC_OVERRIDES_DIR),
+    }
+}
+
+/// Let every sandbox user change a tree: its entries join the shared
+/// sandbox group, with group permissions matching the owner's.
+pub(crate) fn share_with_sandboxes(path: &Path) -> Result<(), String> {
+    let meta = fs::symlink_metadata(path).map_err(|e| format!("stat {}: {}", path.display(), e))?;
+    std::os::unix::fs::lchown(path, None, Some(SANDBOX_SHARED_GID))
+        .map_err(|e| format!("chown {}: {}", path.display(), e))?;
+    if meta.file_type().is_symlink() {
+        return Ok(());
+    }
+    let mode = meta.permissions().mode() & 0o7777;
+    fs::set_permissions(path, fs::Permissions::from_mode(mode | (mode & 0o700) >> 3))
+        .map_err(|e| format!("chmod {}: {}", path.display(), e))?;
+    if meta.is_dir() {
+        for entry in fs::read_dir(path).map_err(|e| format!("read {}: {}", path.display(), e))?.flatten() {
+            share_with_sandboxes(&entry.path())?;
+        }
+    }
+    Ok(())
+}
+
+/// Lowest sandbox UID no root under /tmp is owned by.
+fn free_sandbox_uid() -> Result<u32, String> {
+    use std::os::unix::fs::MetadataExt;
+
+    let taken: std::collections::HashSet<u32> = fs::read_dir("/tmp")
+        .map_err(|e| format!("read /tmp: {}", e))?
+        .flatten()
+        .filter(|e| e.file_name().to_string_lossy().starts_with("sandbox-"))
+        .filter_map(|e| fs::metadata(e.path()).ok().map(|m| m.uid()))
+        .collect();
+    (SANDBOX_UID_BASE..SANDBOX_UID_BASE + SANDBOX_UID_COUNT)
+        .find(|uid| !taken.contains(uid))
+        .ok_or_else(|| "no free sandbox UIDs".to_string())
+}
+
+/// UID and GID commands in a sandbox run as: the owner of its root. `None`
+/// for roots owned by root, which predate per-sandbox users.
+pub fn sandbox_owner(sandbox_root: &Path) -> Option<(u32, u32)> {
+    use std::os::unix::fs::MetadataExt;
+
+    let meta = fs::metadata(sandbox_root).ok()?;
+    (meta.uid() != 0).then_some((meta.uid(), meta.gid()))
+}
+
+/// Become `uid`/`gid`, with only the shared sandbox group as supplementary
+/// group, for good. Uses raw
+/// syscalls: libc's wrappers try to switch every thread of the server, whose
+/// thread list a cloned child inherits but whose threads it doesn't have.
+fn drop_privileges(uid: u32, gid: u32) -> Result<(), String> {
+    let check = |name: &str, ret: libc::c_long| match ret {
+        0 => Ok(()),
+        _ => Err(format!("{}: {}", name, std::io::Error::last_os_error())),
+    };
+    unsafe {
+        let groups: [libc::gid_t; 1] = [SANDBOX_SHARED_GID];
+        check("setgroups", libc::syscall(libc::SYS_setgroups, 1, groups.as_ptr()))?;
+        check("setresgid", libc::syscall(libc::SYS_setresgid, gid, gid, gid))?;
+        check("setresuid", libc::syscall(libc::SYS_setresuid, uid, uid, uid))?;
+    }
+    Ok(())
+}
+
+/// Give `path` (a file or a whole tree) inside a sandbox, and the
+/// directories leading to it, to the sandbox's user, so files the server
+/// writes stay changeable by the sandbox's commands.
+fn give_to_sandbox_user(sandbox_root: &Path, path: &Path) -> Result<(), String> {
+    let Some(owner) = sandbox_owner(sandbox_root) else {
+        return Ok(());
+    };
+    let rel = path.strip_prefix(sandbox_root).map_err(|_| format!("{} is outside the sandbox", path.display()))?;
+    let mut dir = sandbox_root.to_path_buf();
+    for component in rel.parent().into_iter().flat_map(|p| p.components()) {
+        dir.push(component);
+        chown_entry(&dir, Some(owner))?;
+    }
+    chown_tree(path, owner, rel.as_os_str().is_empty())
+}
+
+fn chown_tree(path: &Path, owner: (u32, u32), top_level: bool) -> Result<(), String> {
+    chown_entry(path, Some(owner))?;
+    if !fs::symlink_metadata(path).map(|m| m.is_dir()).unwrap_or(false) {
+        return Ok(());
+    }
+    let entries = fs::read_dir(path).map_err(|e| format!("read {}: {}", path.display(), e))?;
+    for entry in entries.flatten() {
+        if top_level && is_mounted_top_level(&entry.file_name().to_string_lossy()) {
+            continue;
+        }
+        chown_tree(&entry.path(), owner, false)?;
+    }
+    Ok(())
+}
+
+/// Change who owns one entry, not following symlinks. No-op without an owner.
+fn chown_entry(path: &Path, owner: Option<(u32, u32)>) -> Result<(), String> {
+    match owner {
+        Some((uid, gid)) => std::os::unix::fs::lchown(path, Some(uid), Some(gid))
+            .map_err(|e| format!("chown {}: {}", path.display(), e)),
+        None => Ok(()),
+    }
+}
+
+/// Read-only bind mount the host system directories into a sandbox root.
+/// /etc becomes an overlay instead when the sandbox has its own /etc files
+/// in `etc_overrides`.
+fn mount_system_dirs(sandbox_root: &Path, etc_overrides: &Path) -> Result<(), String> {
+    for dir in SYSTEM_BIND_DIRS {
+        if *dir == "/etc" && mount_etc_overlay(sandbox_root, etc_overrides)? {
+            continue;
+        }
+        let target = sandbox_root.join(&dir[1..]);
+        if Path::new(dir).exists() {
+            fs::create_dir_all(&target).map_err(|e| format!("mkdir {}: {}", dir, e))?;
+            mount(
+                Some(*dir),
+                &target,
+                None::<&str>,
+                MsFlags::MS_BIND | MsFlags::MS_REC,
+                None::<&str>,
+            )
+            .map_err(|e| format!("bind mount {}: {}", dir, e))?;
+            mount(
+                None::<&str>,
+                &target,
+                None::<&str>,
+                MsFlags::MS_BIND | MsFlags::MS_REMOUNT | MsFlags::MS_RDONLY | MsFlags::MS_REC,
+                None::<&str>,
+            )
+            .map_err(|e| format!("remount ro {}: {}", dir, e))?;
+        }
+    }
+    Ok(())
+}
+
+/// Mount a host directory at `target`, a path inside a sandbox root, as an
+/// overlay whose writes land in a scratch dir on the root's layers tmpfs.
+/// The host directory is never changed; [`unmount_scratch_overlay`] drops
+/// the writes. Returns the scratch dir.
+pub(crate) fn mount_scratch_overlay(sandbox_root: &Path, source: &Path, target: &Path) -> Result<PathBuf, String> {
+    let layers = layers_dir(sandbox_root)
+        .filter(|l| l.is_dir())
+        .ok_or_else(|| format!("{} is not an overlay root", sandbox_root.display()))?;
+    let scratch = layers.join(format!("scratch-{}", uuid::Uuid::new_v4()));
+    let (upper, work) = (scratch.join("upper"), scratch.join("work"));
+    for dir in [&upper, &work, &target.to_path_buf()] {
+        fs::create_dir_all(dir).map_err(|e| format!("mkdir {}: {}", dir.display(), e))?;
+    }
+    let options = format!(
+        "lowerdir={},upperdir={},workdir={}",
+        source.display(),
+        upper.display(),
+        work.display()
+    );
+    let mounted = mount(
+        Some("overlay"),
+        target,
+        Some("overlay"),
+        MsFlags::MS_NOSUID | MsFlags::MS_NODEV,
+        Some(options.as_str()),
+    );
+    if let Err(e) = mounted {
+        unmount_scratch_overlay(target, &scratch);
+        return Err(format!("mount overlay of {}: {}", source.display(), e));
+    }
+    Ok(scratch)
+}
+
+/// Undo [`mount_scratch_overlay`], removing the mountpoint and the writes.
+pub(crate) fn unmount_scratch_overlay(target: &Path, scratch: &Path) {
+    let _ = umount2(target, MntFlags::MNT_DETACH);
+    let _ = fs::remove_dir(target);
+    let _ = fs::remove_dir_all(scratch);
+}
+
+/// Mount /etc as a read-only overlay of the sandbox's own /etc files on top
+/// of the host's. Returns false if the sandbox has none.
+fn mount_etc_overlay(sandbox_root: &Path, overrides: &Path) -> Result<bool, String> {
+    if !overrides.is_dir() {
+        return Ok(false);
+    }
+    let target = sandbox_root.join("etc");
+    fs::create_dir_all(&target).map_err(|e| format!("mkdir etc: {}", e))?;
+    // Two lower layers and no upper dir: a read-only merged view
+    let options = format!("lowerdir={}:/etc", overrides.display());
+    mount(
+        Some("overlay"),
+        &target,
+        Some("overlay"),
+        MsFlags::MS_RDONLY | MsFlags::MS_NOSUID | MsFlags::MS_NODEV,
+        Some(options.as_str()),
+    )
+    .map_err(|e| format!("mount etc overlay: {}", e))?;
+    Ok(true)
+}
+
+/// Hostname for a session that doesn't pick one: `oc-` and the first 8
+/// characters of its ID.
+pub fn default_hostname(session_id: &str) -> String {
+    let short: String = session_id
+        .chars()
+        .filter(|c| c.is_ascii_alphanumeric())
+        .take(8)
+        .collect::<String>()
+        .to_ascii_lowercase();
+    format!("oc-{}", short)
+}
+
+/// Set up a session sandbox's identity: /etc/hostname, /etc/hosts,
+/// /etc/resolv.conf (and /etc/os-release if the host has none), the machine
+/// ID, and seeded random devices. The hostname itself is applied per run in
+/// a UTS namespace.
+pub fn apply_determinism(sandbox_root: &Path, determinism: &Determinism) -> Result<(), String> {
+    let mut files: Vec<(&str, String)> = Vec::new();
+    if let Some(ref hostname) = determinism.hostname {
+        files.push(("hostname", format!("{}\n", hostname)));
+        files.push((
+            "hosts",
+            format!(
+                "127.0.0.1\tlocalhost\n127.0.1.1\t{}\n::1\tlocalhost ip6-localhost ip6-loopback\n",
+                hostname
+            ),
+        ));
+        // Read through the host path so container-managed bind mounts are honoured
+        let resolv = fs::read_to_string("/etc/resolv.conf")
+            .ok()
+            .filter(|c| c.lines().any(|l| l.trim_start().starts_with("nameserver")))
+            .unwrap_or_else(|| FALLBACK_RESOLV_CONF.to_string());
+        files.push(("resolv.conf", resolv));
+        let host_release = Path::new("/etc/os-release").exists() || Path::new("/usr/lib/os-release").exists();
+        if !host_release && !is_image_root(sandbox_root) {
+            files.push(("os-release", FALLBACK_OS_RELEASE.to_string()));
+        }
+    }
+    if let Some(id) = machine_id(determinism) {
+        files.push(("machine-id", format!("{}\n", id)));
+        let dbus = sandbox_root.join("var/lib/dbus");
+        fs::create_dir_all(&dbus).map_err(|e| format!("mkdir {}: {}", dbus.display(), e))?;
+        fs::write(dbus.join("machine-id"), format!("{}\n", id))
+            .map_err(|e| format!("write machine-id: {}", e))?;
+    }
+    if !files.is_empty() {
+        let files = files.into_iter().map(|(name, content)| (name, content.into_bytes())).collect::<Vec<_>>();
+        write_etc_files(sandbox_root, &files)?;
+    }
+    if let Some(seed) = determinism.seed {
+        let bytes = seeded_bytes(seed, SEEDED_RANDOM_BYTES);
+        for dev in ["urandom", "random"] {
+            let path = sandbox_root.join("dev").join(dev);
+            let _ = umount2(&path, MntFlags::MNT_DETACH);
+            fs::write(&path, &bytes).map_err(|e| format!("write /dev/{}: {}", dev, e))?;
+            fs::set_permissions(&path, fs::Permissions::from_mode(0o444))
+                .map_err(|e| format!("chmod /dev/{}: {}", dev, e))?;
+        }
+    }
+    Ok(())
+}
+
+/// Give the sandbox's user an entry in its /etc/passwd, named `sandbox`
+/// with `/home` and `shell`, if it has none: `scp`, `sftp-server` and the
+/// like look themselves up and give up without one. The entry goes into the
+/// sandbox's own /etc files, over a copy of the host's.
+pub fn add_user_entry(sandbox_root: &Path, shell: &str) -> Result<(), String> {
+    let Some((uid, gid)) = sandbox_owner(sandbox_root) else {
+        return Ok(());
+    };
+    let passwd = Path::new("/etc/passwd");
+    let mut content = safe_path::Root::open(sandbox_root)?.read(passwd).unwrap_or_default();
+    let uid_field = uid.to_string();
+    if String::from_utf8_lossy(&content)
+        .lines()
+        .any(|line| line.split(':').nth(2) == Some(uid_field.as_str()))
+    {
+        return Ok(());
+    }
+    if content.last().is_some_and(|&b| b != b'\n') {
+        content.push(b'\n');
+    }
+    content.extend(format!("sandbox:x:{}:{}:sandbox:/home:{}\n", uid, gid, shell).into_bytes());
+    write_etc_files(sandbox_root, &[("passwd", content)])
+}
+
+/// Write files into a sandbox's /etc, owned by root: into its own /etc
+/// files for roots on the host's /etc (remounting the overlay), or straight
+/// into an image root's /etc. A rootless root's commands mount its own /etc
+/// files as they start, so those are only written.
+fn write_etc_files(sandbox_root: &Path, files: &[(&str, Vec<u8>)]) -> Result<(), String> {
+    if userns::is_rootless_root(sandbox_root) {
+        let dir = etc_overrides(sandbox_root);
+        fs::create_dir_all(&dir).map_err(|e| format!("mkdir {}: {}", dir.display(), e))?;
+        for (name, content) in files {
+            fs::write(dir.join(name), content).map_err(|e| format!("write /etc/{}: {}", name, e))?;
+        }
+        return Ok(());
+    }
+    // Resolved inside the sandbox's files, which its processes may have changed
+    if is_image_root(sandbox_root) {
+        let root = safe_path::Root::open(sandbox_root)?;
+        for (name, content) in files {
+            root.write(&Path::new("/etc").join(name), content, None)?;
+        }
+        return Ok(());
+    }
+    let root = safe_path::Root::open(&own_files_dir(sandbox_root))?;
+    for (name, content) in files {
+        root.write(&Path::new(ETC_OVERRIDES_DIR).join(name), content, None)?;
+    }
+    let _ = umount2(&sandbox_root.join("etc"), MntFlags::MNT_DETACH);
+    mount_etc_overlay(sandbox_root, &etc_overrides(sandbox_root))?;
+    Ok(())
+}
+
+/// SHA-256 in counter mode: the same seed always yields the same bytes.
+fn seeded_bytes(seed: u64, len: usize) -> Vec<u8> {
+    let mut out = Vec::with_capacity(len);
+    let mut counter: u64 = 0;
+    while out.len() < len {
+        let mut hasher = Sha256::new();
+        hasher.update(seed.to_le_bytes());
+        hasher.update(counter.to_le_bytes());
+        out.extend_from_slice(&hasher.finalize());
+        counter += 1;
+    }
+    out.truncate(len);
+    out
+}
+
+/// Move the calling process into a new UTS namespace with the given hostname.
+fn set_hostname(hostname: &str) -> Result<(), String> {
+    nix::sched::unshare(CloneFlags::CLONE_NEWUTS).map_err(|e| format!("unshare uts: {}", e))?;
+    sethostname(hostname)
+}
+
+fn sethostname(hostname: &str) -> Result<(), String> {
+    let ret = unsafe { libc::sethostname(hostname.as_ptr() as *const libc::c_char, hostname.len()) };
+    if ret != 0 {
+        return Err(format!("sethostname: {}", std::io::Error::last_os_error()));
+    }
+    Ok(())
+}
+
+/// Bind essential device nodes from the host and mount a fresh /proc.
+fn mount_devices_and_proc(sandbox_root: &Path) -> Result<(), String> {
+    let dev_dir = sandbox_root.join("dev");
+    fs::create_dir_all(&dev_dir).map_err(|e| format!("mkdir dev: {}", e))?;
+
+    // Create essential device nodes by bind mounting from host
+    for dev in DEVICE_NODES {
+        let host_dev = format!("/dev/{}", dev);
+        let sandbox_dev = dev_dir.join(dev);
+        // A seeded stand-in (see apply_determinism) replaces the host device
+        if sandbox_dev.metadata().is_ok_and(|m| m.len() > 0) {
+            continue;
+        }
+        if Path::new(&host_dev).exists() {
+            // Only create the mount point if missing, so a staged (overlay)
+            // root doesn't copy it up
+            if !sandbox_dev.exists() {
+                fs::write(&sandbox_dev, "").map_err(|e| format!("touch {}: {}", dev, e))?;
+            }
+            mount(
+                Some(host_dev.as_str()),
+                &sandbox_dev,
+                None::<&str>,
+                MsFlags::MS_BIND,
+                None::<&str>,
+            )
+            .map_err(|e| format!("bind mount {}: {}", dev, e))?;
+        }
+    }
+
+    // Mount proc
+    let proc_dir = sandbox_root.join("proc");
+    fs::create_dir_all(&proc_dir).map_err(|e| format!("mkdir proc: {}", e))?;
+    mount(
+        Some("proc"),
+        &proc_dir,
+        Some("proc"),
+        MsFlags::MS_NOSUID | MsFlags::MS_NODEV | MsFlags::MS_NOEXEC,
+        None::<&str>,
+    )
+    .map_err(|e| format!("mount proc: {}", e))?;
+
+    Ok(())
+}
+
+/// The copy of a run's config handed to its child process. The parent's
+/// copy of the child's env and secrets is wiped once the child is started.
+struct SpawnCopy(RunConfig);
+
+impl std::ops::Deref for SpawnCopy {
+    type Target = RunConfig;
+
+    fn deref(&self) -> &RunConfig {
+        &self.0
+    }
+}
+
+impl Drop for SpawnCopy {
+    fn drop(&mut self) {
+        secrets::wipe_env(&mut self.0.env);
+        self.0.secrets.iter_mut().for_each(secrets::wipe);
+    }
+}
+
+/// Run a command in `sandbox_root`, in the network namespace `network` if
+/// given.
+fn run_in_sandbox(
+    sandbox_root: &Path,
+    config: &RunConfig,
+    live: Option<&LiveRun>,
+    network: Option<Arc<OwnedFd>>,
+    cgroup: Option<Arc<SessionCgroup>>,
+) -> Result<RunResult, String> {
+    info!(command = ?secrets::redact_all(&config.command, &config.secrets), "Running command");
+    info!(sandbox_root = ?sandbox_root, "Sandbox root");
+    info!(time_ms = config.time_ms, mem_kb = config.mem_kb,
+          fsize_kb = config.fsize_kb, nofile = config.nofile, nproc = config.nproc, "Limits");
+
+    // Create pipes for stdout/stderr capture
+    let (stdout_read, stdout_write) = nix::unistd::pipe().map_err(|e| format!("pipe: {}", e))?;
+    let (stderr_read, stderr_write) = nix::unistd::pipe().map_err(|e| format!("pipe: {}", e))?;
+
+    // Stdin pipe, close-on-exec so only the command keeps its read end (as fd
+    // 0) and nothing but the feeder thread holds the write end
+    let stdin = match config.stdin {
+        Some(ref data) => {
+            let (read, write) = nix::unistd::pipe2(nix::fcntl::OFlag::O_CLOEXEC)
+                .map_err(|e| format!("pipe: {}", e))?;
+            Some((read, write, data.clone()))
+        }
+        None => None,
+    };
+
+    // Get raw fds for the child process
+    let stdout_write_fd = stdout_write.as_raw_fd();
+    let stderr_write_fd = stderr_write.as_raw_fd();
+    let stdin_read_fd = stdin.as_ref().map(|(read, _, _)| read.as_raw_fd());
+
+    let (max_output_bytes, time_ms, kill_grace_ms) = (config.max_output_bytes, config.time_ms, config.kill_grace_ms);
+    let owner = sandbox_owner(sandbox_root);
+    let rootless = userns::is_rootless_root(sandbox_root).then(userns::server_ids);
+    let sandbox_root = sandbox_root.to_path_buf();
+    let config = SpawnCopy(config.clone());
+
+    const STACK_SIZE: usize = 1024 * 1024;
+    let mut stack = vec![0u8; STACK_SIZE];
+
+    let mut clone_flags = CloneFlags::CLONE_NEWPID | CloneFlags::CLONE_NEWNS;
+    let hostname = config.determinism.as_ref().and_then(|d| d.hostname.clone());
+    if hostname.is_some() {
+        clone_flags |= CloneFlags::CLONE_NEWUTS;
+    }
+    if rootless.is_some() {
+        clone_flags |= userns::CLONE_FLAGS;
+    }
+
+    // The group's count of forks failed at pids.max, to tell whether the run
+    // hit it
+    let pids_max_hits = cgroup.clone().map(|cgroup| {
+        let hits = cgroup.pids_max_hits();
+        (cgroup, hits)
+    });
+
+    let child_fn = Box::new(move || {
+        // Redirect stdout/stderr to pipes
+        unsafe {
+            libc::dup2(stdout_write_fd, 1);
+            libc::dup2(stderr_write_fd, 2);
+            libc::close(stdout_write_fd);
+            libc::close(stderr_write_fd);
+            if let Some(fd) = stdin_read_fd {
+                libc::dup2(fd, 0);
+            }
+        }
+
+        // Before anything the command could fork
+        if let Some(ref cgroup) = cgroup {
+            if let Err(e) = cgroup.join() {
+                eprintln!("Child error: join cgroup: {}", e);
+                return 1;
+            }
+        }
+        if let Some(ref ns) = network {
+            if let Err(e) = nix::sched::setns(ns, CloneFlags::CLONE_NEWNET) {
+                eprintln!("Child error: join network namespace: {}", e);
+                return 1;
+            }
+        }
+        let result = match rootless {
+            // Already running as the sandbox's user, whose IDs are the server's
+            Some(ids) => userns::enter(&sandbox_root, ids).and_then(|_| run_child(Path::new("/"), &config, None)),
+            None => run_child(&sandbox_root, &config, owner),
+        };
+        if let Err(e) = result {
+            eprintln!("Child error: {}", e);
+            return 1;
+        }
+        0
+    });
+
+    info!("Calling clone()...");
+    let oom_kills = oom_kill_count();
+    let started = std::time::Instant::now();
+    let child_pid = unsafe {
+        clone(
+            child_fn,
+            &mut stack,
+            clone_flags,
+            Some(Signal::SIGCHLD as i32),
+        )
+    }
+    .map_err(|e| spawn_error("clone", e.into()))?;
+    info!(child_pid = ?child_pid, "Child spawned");
+    if let Some(live) = live {
+        live.started(child_pid);
+    }
+
+    // Close write ends in parent
+    drop(stdout_write);
+    drop(stderr_write);
+
+    // Feed stdin from a thread so a command that writes before it reads
+    // can't block on us. The write fails once every reader has exited.
+    if let Some((read, write, data)) = stdin {
+        drop(read);
+        std::thread::spawn(move || {
+            let _ = fs::File::from(write).write_all(&data);
+        });
+    }
+
+    // Drain the pipes while the child runs so it never blocks on a full one
+    let stdout = spawn_capture(stdout_read, max_output_bytes, live.map(|l| l.stdout.clone()));
+    let stderr = spawn_capture(stderr_read, max_output_bytes, live.map(|l| l.stderr.clone()));
+
+    // RLIMIT_CPU bounds each process on its own, so a parent that waits on
+    // busy children never hits it; watch the whole run's CPU time too. The
+    // child is unreaped, so its PID (and namespace) can't be reused yet.
+    let (stop_watch, watch) = std::sync::mpsc::channel::<()>();
+    let watchdog = match fs::read_link(format!("/proc/{}/ns/pid", child_pid)) {
+        Ok(namespace) if time_ms > 0 => {
+            Some(std::thread::spawn(move || watch_cpu_time(&namespace, time_ms, kill_grace_ms, watch)))
+        }
+        _ => None,
+    };
+
+    // Wait for child
+    info!("Waiting for child...");
+    if let Some(live) = live {
+        wait_for_exit(child_pid)?;
+        live.exited();
+    }
+    let (status, usage) = wait_with_usage(child_pid)?;
+    let wall_time = started.elapsed();
+    info!(status = ?status, "Child exited");
+    drop(stop_watch);
+    let out_of_time = watchdog.is_some_and(|watchdog| watchdog.join().unwrap_or(false));
+
+    let stdout = stdout.join().unwrap_or_default();
+    let stderr = stderr.join().unwrap_or_default();
+    info!(stdout_bytes = stdout.bytes, stderr_bytes = stderr.bytes, "Output captured");
+
+    let (exit_code, signal) = match status {
+        WaitStatus::Exited(_, code) => (Some(code), None),
+        WaitStatus::Signaled(_, sig, _) => (None, Some(sig as i32)),
+        _ => (None, None),
+    };
+    let limit_exceeded = match signal {
+        _ if out_of_time => Some(LimitExceeded::Time),
+        Some(libc::SIGXCPU) => Some(LimitExceeded::Time),
+        Some(libc::SIGXFSZ) => Some(LimitExceeded::FileSize),
+        // RLIMIT_CPU's hard limit is its soft one, so it ends in SIGKILL
+        Some(libc::SIGKILL) if usage.cpu_time().as_secs() >= std::cmp::max(1, time_ms / 1000) => {
+            Some(LimitExceeded::Time)
+        }
+        // The OOM killer leaves no mark on the process but its SIGKILL and
+        // the host's count of kills; another process's OOM kill at the same
+        // time would be blamed on this one
+        Some(libc::SIGKILL) if oom_kill_count() > oom_kills => Some(LimitExceeded::Memory),
+        // Forks failing at pids.max don't stop the command; like OOM kills,
+        // the group's count blames this run for background processes' hits
+        _ if pids_max_hits.is_some_and(|(cgroup, hits)| cgroup.pids_max_hits() > hits) => Some(LimitExceeded::Pids),
+        _ => None,
+    };
+
+    Ok(RunResult {
+        stdout: stdout.text,
+        stderr: stderr.text,
+        stdout_bytes: stdout.bytes,
+        stderr_bytes: stderr.bytes,
+        stdout_truncated: stdout.truncated,
+        stderr_truncated: stderr.truncated,
+        exit_code,
+        signal,
+        committed: None,
+        replay_id: None,
+        trace_id: None,
+        wall_time_ms: wall_time.as_millis() as u64,
+        user_time_ms: usage.user.as_millis() as u64,
+        sys_time_ms: usage.sys.as_millis() as u64,
+        max_rss_kb: usage.max_rss_kb,
+        limit_exceeded,
+        cpu_time: usage.cpu_time(),
+    })
+}
+
+/// Resources a child and its reaped descendants used.
+struct Usage {
+    user: Duration,
+    sys: Duration,
+    /// Largest peak RSS among them
+    max_rss_kb: u64,
+}
+
+impl Usage {
+    fn cpu_time(&self) -> Duration {
+        self.user + self.sys
+    }
+}
+
+/// Wait for a child and report what it (and its reaped descendants) used.
+fn wait_with_usage(pid: nix::unistd::Pid) -> Result<(WaitStatus, Usage), String> {
+    let mut status = 0;
+    let mut usage: libc::rusage = unsafe { std::mem::zeroed() };
+    let ret = loop {
+        let ret = unsafe { libc::wait4(pid.as_raw(), &mut status, 0, &mut usage) };
+        if ret >= 0 || nix::errno::Errno::last() != nix::errno::Errno::EINTR {
+            break ret;
+        }
+    };
+    if ret < 0 {
+        return Err(format!("wait4: {}", nix::errno::Errno::last()));
+    }
+    let status = WaitStatus::from_raw(pid, status).map_err(|e| format!("wait4: {}", e))?;
+    let timeval = |tv: libc::timeval| Duration::new(tv.tv_sec as u64, tv.tv_usec as u32 * 1000);
+    let usage = Usage {
+        user: timeval(usage.ru_utime),
+        sys: timeval(usage.ru_stime),
+        // Kilobytes on Linux
+        max_rss_kb: usage.ru_maxrss.max(0) as u64,
+    };
+    Ok((status, usage))
+}
+
+/// Processes the host's OOM killer has killed since boot (0 if unknown).
+fn oom_kill_count() -> u64 {
+    fs::read_to_string("/proc/vmstat")
+        .ok()
+        .and_then(|vmstat| {
+            vmstat
+                .lines()
+                .find_map(|line| line.strip_prefix("oom_kill ")?.trim().parse().ok())
+        })
+        .unwrap_or(0)
+}
+
+/// Wait for a child to exit without reaping it.
+fn wait_for_exit(pid: nix::unistd::Pid) -> Result<(), String> {
+    use nix::sys::wait::{waitid, Id, WaitPidFlag};
+
+    loop {
+        match waitid(Id::Pid(pid), WaitPidFlag::WEXITED | WaitPidFlag::WNOWAIT) {
+            Ok(_) => return Ok(()),
+            Err(nix::errno::Errno::EINTR) => continue,
+            Err(e) => return Err(format!("waitid: {}", e)),
+        }
+    }
+}
+
+/// Once the processes in a run's PID namespace have together used `time_ms`
+/// of CPU (counting children they reaped), send them all SIGTERM, then
+/// SIGKILL after `grace_ms`. Returns early when `stop` is dropped, and
+/// whether it signalled them.
+fn watch_cpu_time(namespace: &Path, time_ms: u64, grace_ms: u64, stop: std::sync::mpsc::Receiver<()>) -> bool {
+    use std::sync::mpsc::RecvTimeoutError;
+
+    let tick_hz = unsafe { libc::sysconf(libc::_SC_CLK_TCK) }.max(1) as u64;
+    let limit_ticks = time_ms * tick_hz / 1000;
+    loop {
+        if stop.recv_timeout(CPU_WATCH_INTERVAL) != Err(RecvTimeoutError::Timeout) {
+            return false;
+        }
+        let ticks: u64 = namespace_pids(namespace).into_iter().filter_map(cpu_ticks).sum();
+        if ticks >= limit_ticks {
+            break;
+        }
+    }
+
+    info!(time_ms, grace_ms, "Run used its CPU time, terminating its processes");
+    signal_namespace(namespace, Signal::SIGTERM);
+    if stop.recv_timeout(Duration::from_millis(grace_ms)) != Err(RecvTimeoutError::Timeout) {
+        return true;
+    }
+    // Killing the namespace's init takes the rest down with it
+    signal_namespace(namespace, Signal::SIGKILL);
+    true
+}
+
+/// Host PIDs of every process in a PID namespace, named by the target of a
+/// `/proc/{pid}/ns/pid` link.
+fn namespace_pids(namespace: &Path) -> Vec<u32> {
+    let Ok(entries) = fs::read_dir("/proc") else {
+        return Vec::new();
+    };
+    entries
+        .filter_map(|e| e.ok())
+        .filter_map(|e| e.file_name().to_str().and_then(|n| n.parse::<u32>().ok()))
+        .filter(|pid| {
+            fs::read_link(format!("/proc/{}/ns/pid", pid))
+                .map(|ns| ns == namespace)
+                .unwrap_or(false)
+        })
+        .collect()
+}
+
+fn signal_namespace(namespace: &Path, signal: Signal) {
+    for pid in namespace_pids(namespace) {
+        let _ = nix::sys::signal::kill(nix::unistd::Pid::from_raw(pid as i32), signal);
+    }
+}
+
+/// CPU ticks a process and the children it reaped have used.
+fn cpu_ticks(pid: u32) -> Option<u64> {
+    let stat = fs::read_to_string(format!("/proc/{}/stat", pid)).ok()?;
+    // Fields resume after the command name's ')'; utime, stime, cutime and
+    // cstime are fields 14-17 of the full line
+    let fields: Vec<&str> = stat.get(stat.rfind(')')? + 2..)?.split_whitespace().collect();
+    fields.get(11..15)?.iter().map(|f| f.parse::<u64>().ok()).sum()
+}
+
+/// Bytes used on a session's tmpfs (0 if it can't be read).
+pub fn disk_usage(sandbox_root: &Path) -> u64 {
+    match nix::sys::statvfs::statvfs(sandbox_root) {
+        Ok(st) => st.blocks().saturating_sub(st.blocks_free()) * st.fragment_size(),
+        Err(_) => 0,
+    }
+}
+
+/// Size of a session's tmpfs (0 if it can't be read).
+pub fn disk_capacity(sandbox_root: &Path) -> u64 {
+    match nix::sys::statvfs::statvfs(sandbox_root) {
+        Ok(st) => st.blocks() * st.fragment_size(),
+        Err(_) => 0,
+    }
+}
+
+/// What was read from one of a command's output pipes.
+#[derive(Default)]
+struct Captured {
+    text: String,
+    /// Everything the command wrote, including what wasn't kept
+    bytes: u64,
+    truncated: bool,
+}
+
+/// Read `fd` to EOF on a thread, keeping the first `limit` bytes and
+/// counting the rest. `live` gets the same as it's read.
+fn spawn_capture(fd: OwnedFd, limit: u64, live: Option<Arc<Mutex<LiveOutput>>>) -> std::thread::JoinHandle<Captured> {
+    std::thread::spawn(move || {
+        let mut file = fs::File::from(fd);
+        let mut kept = Vec::new();
+        let mut bytes = 0u64;
+        let mut buf = [0u8; 64 * 1024];
+        loop {
+            let n = match file.read(&mut buf) {
+                Ok(0) => break,
+                Ok(n) => n,
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+                Err(_) => break,
+            };
+            let room = limit.saturating_sub(bytes).min(n as u64) as usize;
+            kept.extend_from_slice(&buf[..room]);
+            bytes += n as u64;
+            if let Some(ref live) = live {
+                let mut live = live.lock().unwrap_or_else(|e| e.into_inner());
+                live.kept.extend_from_slice(&buf[..room]);
+                live.bytes = bytes;
+            }
+        }
+        Captured {
+            text: String::from_utf8_lossy(&kept).into_owned(),
+            bytes,
+            truncated: bytes > limit,
+        }
+    })
+}
+
+fn run_child(sandbox_root: &Path, config: &RunConfig, owner: Option<(u32, u32)>) -> Result<(), String> {
+    eprintln!("[child] Starting, sandbox_root={:?}", sandbox_root);
+
+    // Already in a new UTS namespace (see run_in_sandbox)
+    if let Some(hostname) = config.determinism.as_ref().and_then(|d| d.hostname.as_deref()) {
+        sethostname(hostname)?;
+    }
+
+    // chroot into sandbox
+    eprintln!("[child] chroot...");
+    chroot(sandbox_root).map_err(|e| format!("chroot: {}", e))?;
+    eprintln!("[child] chdir to {:?}...", config.cwd);
+    chdir(config.cwd.as_str()).map_err(|e| format!("chdir: {}", e))?;
+
+    // Set resource limits
+    eprintln!("[child] Setting resource limits...");
+    set_resource_limits(config)?;
+    eprintln!("[child] Resource limits set");
+
+    match owner {
+        Some((uid, gid)) => {
+            eprintln!("[child] Dropping privileges to {}:{}...", uid, gid);
+            drop_privileges(uid, gid)?;
+        }
+        // Roots from before per-sandbox users are owned by root, and
+        // rootless ones by the server's own user
+        None => eprintln!("[child] Sandbox has no user of its own, keeping the current one"),
+    }
+
+    // Execute command
+    let cmd = CString::new(config.command[0].as_str()).map_err(|e| format!("cmd: {}", e))?;
+    let args: Vec<CString> = config
+        .command
+        .iter()
+        .map(|s| CString::new(s.as_str()).unwrap())
+        .collect();
+
+    // Build environment
+    let mut env: Vec<CString> = config
+        .env
+        .iter()
+        .map(|(k, v)| CString::new(format!("{}={}", k, v)).unwrap())
+        .collect();
+    // Images set their own PATH
+    if !config.env.contains_key("PATH") {
+        env.push(CString::new("PATH=/usr/bin:/bin").unwrap());
+    }
+    env.push(CString::new("HOME=/home").unwrap());
+
+    eprintln!("[child] About to exec: {:?}", secrets::redact_all(&config.command, &config.secrets));
+    eprintln!("[child] Flushing stderr before exec...");
+    let _ = std::io::stderr().flush();
+    execvpe(&cmd, &args, &env).map_err(|e| spawn_error("exec", e.into()))?;
+    Ok(())
+}
+
+/// The error for starting a process failing with `error`, marked with
+/// [`PIDS_LIMIT_HIT`] when a process limit is why. Exec fails that way once
+/// the user's processes are at RLIMIT_NPROC.
+fn spawn_error(what: &str, error: std::io::Error) -> String {
+    if error.raw_os_error() == Some(libc::EAGAIN) {
+        format!("{}: {}: {}", PIDS_LIMIT_HIT, what, error)
+    } else {
+        format!("{}: {}", what, error)
+    }
+}
+
+fn set_resource_limits(config: &RunConfig) -> Result<(), String> {
+    let cpu_seconds = std::cmp::max(1, config.time_ms / 1000);
+    eprintln!("[rlimit] CPU: {} seconds", cpu_seconds);
+    setrlimit(Resource::RLIMIT_CPU, cpu_seconds, cpu_seconds)
+        .map_err(|e| format!("rlimit cpu: {}", e))?;
+
+    let mem_bytes = config.mem_kb * 1024;
+    eprintln!("[rlimit] AS (mem): {} bytes ({} MB)", mem_bytes, mem_bytes / 1024 / 1024);
+    setrlimit(Resource::RLIMIT_AS, mem_bytes, mem_bytes)
+        .map_err(|e| format!("rlimit as: {}", e))?;
+
+    let fsize_bytes = config.fsize_kb * 1024;
+    eprintln!("[rlimit] FSIZE: {} bytes", fsize_bytes);
+    setrlimit(Resource::RLIMIT_FSIZE, fsize_bytes, fsize_bytes)
+        .map_err(|e| format!("rlimit fsize: {}", e))?;
+
+    eprintln!("[rlimit] NOFILE: {}", config.nofile);
+    setrlimit(Resource::RLIMIT_NOFILE, config.nofile, config.nofile)
+        .map_err(|e| format!("rlimit nofile: {}", e))?;
+
+    eprintln!("[rlimit] CORE: 0");
+    setrlimit(Resource::RLIMIT_CORE, 0, 0).map_err(|e| format!("rlimit core: {}", e))?;
+
+    if config.nproc > 0 {
+        eprintln!("[rlimit] NPROC: {}", config.nproc);
+        setrlimit(Resource::RLIMIT_NPROC, config.nproc, config.nproc)
+            .map_err(|e| format!("rlimit nproc: {}", e))?;
+    }
+
+    eprintln!("[rlimit] All limits set successfully");
+    Ok(())
+}
+
+fn cleanup_sandbox(sandbox_root: &Path) {
+    let _ = umount2(&sandbox_root.join("proc"), MntFlags::MNT_DETACH);
+    for dir in SYSTEM_BIND_DIRS.iter().rev() {
+        let path = sandbox_root.join(&dir[1..]);
+        if path.exists() {
+            let _ = umount2(&path, MntFlags::MNT_DETACH);
+        }
+    }
+    // Unmount device bind mounts
+    let dev_dir = sandbox_root.join("dev");
+    if dev_dir.exists() {
+        for dev in DEVICE_NODES {
+            let dev_path = dev_dir.join(dev);
+            if dev_path.exists() {
+                let _ = umount2(&dev_path, MntFlags::MNT_DETACH);
+            }
+        }
+    }
+    let _ = umount2(sandbox_root, MntFlags::MNT_DETACH);
+    let _ = fs::remove_dir_all(sandbox_root);
+    if let Some(layers) = layers_dir(sandbox_root).filter(|l| l.exists()) {
+        let _ = umount2(&layers.join("template"), MntFlags::MNT_DETACH);
+        let _ = umount2(&layers.join(IMAGE_LAYER), MntFlags::MNT_DETACH);
+        let _ = umount2(&layers, MntFlags::MNT_DETACH);
+        let _ = fs::remove_dir_all(&layers);
+    }
+}
@@ -0,0 +1,85 @@
+//! Cron expression parsing for `POST /schedules/validate`.
+//!
+//! Accepts standard five-field expressions (`min hour dom month dow`, with 0
+//! or 7 = Sunday) as well as the six/seven-field form of the `cron` crate,
+//! with leading seconds, trailing year, and 1 = Sunday.
+//! Fire times are computed in an IANA timezone, so DST transitions are
+//! reflected in the preview.
+
+use chrono::{DateTime, SecondsFormat, Utc};
+use chrono_tz::Tz;
+use cron::Schedule;
+use std::str::FromStr;
+
+/// Fire times returned when the request does not ask for a count.
+pub const DEFAULT_PREVIEW_COUNT: usize = 5;
+
+/// Most fire times a single request may preview.
+pub const MAX_PREVIEW_COUNT: usize = 100;
+
+/// Parse `expr`, prepending a zero seconds field to five-field expressions.
+pub fn parse(expr: &str) -> Result<Schedule, String> {
+    let expr = expr.trim();
+    let fields: Vec<&str> = expr.split_whitespace().collect();
+    let normalized = match fields.len() {
+        5 => {
+            let dow = translate_day_of_week(fields[4])?;
+            format!("0 {} {}", fields[..4].join(" "), dow)
+        }
+        6 | 7 => expr.to_string(),
+        n => return Err(format!("expected 5, 6 or 7 fields, got {}", n)),
+    };
+    Schedule::from_str(&normalized).map_err(|e| e.to_string())
+}
+
+/// Rewrite a standard day-of-week field (0-7, 0 and 7 = Sunday) into the
+/// `cron` crate's numbering (1-7, 1 = Sunday). Numeric items are expanded
+/// into explicit day lists; named days pass through unchanged.
+fn translate_day_of_week(field: &str) -> Result<String, String> {
+    let invalid = |item: &str| format!("invalid day of week '{}'", item);
+    let mut out = Vec::new();
+    for item in field.split(',') {
+        let (range, step) = match item.split_once('/') {
+            Some((range, step)) => (range, Some(step)),
+            None => (item, None),
+        };
+        let numeric = range == "*" || range.split('-').all(|p| p.parse::<u32>().is_ok());
+        if !numeric {
+            out.push(item.to_string());
+            continue;
+        }
+        let num = |s: &str| s.parse::<u32>().map_err(|_| invalid(item));
+        let (lo, hi) = match range.split_once('-') {
+            _ if range == "*" => (0, 6),
+            Some((lo, hi)) => (num(lo)?, num(hi)?),
+            None if step.is_some() => (num(range)?, 6),
+            None => (num(range)?, num(range)?),
+        };
+        let step = step.map(num).transpose()?.unwrap_or(1);
+        if hi > 7 || lo > hi || step == 0 {
+            return Err(invalid(item));
+        }
+        let mut days: Vec<u32> = (lo..=hi).step_by(step as usize).map(|d| d % 7 + 1).collect();
+        days.sort_unstable();
+        days.dedup();
+        out.extend(days.iter().map(u32::to_string));
+    }
+    Ok(out.join(","))
+}
+
+/// Resolve an IANA name; a leading `:` as allowed in `TZ` is ignored.
+pub fn parse_timezone(name: &str) -> Result<Tz, String> {
+    name.trim_start_matches(':')
+        .parse::<Tz>()
+        .map_err(|_| format!("unknown timezone '{}'", name))
+}
+
+/// The next `count` fire times strictly after `after`, as RFC 3339 strings
+/// with the timezone's offset.
+pub fn next_fire_times(schedule: &Schedule, tz: Tz, after: DateTime<Utc>, count: usize) -> Vec<String> {
+    schedule
+        .after(&after.with_timezone(&tz))
+        .take(count)
+        .map(|t| t.to_rfc3339_opts(SecondsFormat::Secs, false))
+        .collect()
+}
@@ -0,0 +1,183 @@
+//! Permission scopes for API keys and session tokens.
+//!
+//! Every API route requires one scope. A key lists the scopes it holds, either
+//! exactly (`files.read`), by group (`files.*`) or all of them (`*`):
+//!
+//! ```json
+//! {"keys": [{"key": "osb_...", "name": "log-viewer",
+//!            "scopes": ["sessions.read", "background.read"]}]}
+//! ```
+//!
+//! Keys without `scopes` hold every scope except the operator ones
+//! (`admin.read`, `admin.write`, `faults.admin`, `templates.write`), which
+//! `*` doesn't grant either: a key only holds them when they are listed, or
+//! their group is.
+
+use opencomputer_types::ShareAccess;
+use std::fmt;
+use std::str::FromStr;
+
+/// Permission required by a class of routes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Scope {
+    /// List and inspect sessions, capacity, pool stats and progress events
+    SessionsRead,
+    /// Create, delete, pause and configure sessions; mint session tokens
+    SessionsWrite,
+    FilesRead,
+    FilesWrite,
+    /// Run commands, in a session or one-shot, and replay bundles
+    ExecRun,
+    /// Background process status and logs
+    BackgroundRead,
+    /// Start and kill background processes
+    BackgroundManage,
+    /// Create preview sessions with `POST /run-preview`
+    PreviewAdmin,
+    TemplatesRead,
+    /// Register and delete templates, which every org's sessions share
+    TemplatesWrite,
+    /// Download replay bundles
+    ReplaysRead,
+    /// Delete replay bundles
+    ReplaysWrite,
+    /// Configure fault injection (`chaos` builds only)
+    FaultsAdmin,
+    /// Host-wide overview, metrics, and every org's sessions and quotas
+    AdminRead,
+    /// Force-delete any org's sessions, reload API keys and drain the server
+    AdminWrite,
+}
+
+impl Scope {
+    pub const ALL: &'static [Scope] = &[
+        Scope::SessionsRead,
+        Scope::SessionsWrite,
+        Scope::FilesRead,
+        Scope::FilesWrite,
+        Scope::ExecRun,
+        Scope::BackgroundRead,
+        Scope::BackgroundManage,
+        Scope::PreviewAdmin,
+        Scope::TemplatesRead,
+        Scope::TemplatesWrite,
+        Scope::ReplaysRead,
+        Scope::ReplaysWrite,
+        Scope::FaultsAdmin,
+        Scope::AdminRead,
+        Scope::AdminWrite,
+    ];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Scope::SessionsRead => "sessions.read",
+            Scope::SessionsWrite => "sessions.write",
+            Scope::FilesRead => "files.read",
+            Scope::FilesWrite => "files.write",
+            Scope::ExecRun => "exec.run",
+            Scope::BackgroundRead => "background.read",
+            Scope::BackgroundManage => "background.manage",
+            Scope::PreviewAdmin => "preview.admin",
+            Scope::TemplatesRead => "templates.read",
+            Scope::TemplatesWrite => "templates.write",
+            Scope::ReplaysRead => "replays.read",
+            Scope::ReplaysWrite => "replays.write",
+            Scope::FaultsAdmin => "faults.admin",
+            Scope::AdminRead => "admin.read",
+            Scope::AdminWrite => "admin.write",
+        }
+    }
+
+    /// Whether a session token may carry this scope. Tokens act on a single
+    /// session, so server-wide scopes are excluded.
+    pub fn is_session_level(self) -> bool {
+        !matches!(
+            self,
+            Scope::PreviewAdmin
+                | Scope::TemplatesRead
+                | Scope::TemplatesWrite
+                | Scope::ReplaysRead
+                | Scope::ReplaysWrite
+                | Scope::FaultsAdmin
+                | Scope::AdminRead
+                | Scope::AdminWrite
+        )
+    }
+
+    /// Whether the scope reaches across every org or reconfigures the
+    /// server, so it is never held by default. Templates are shared by every
+    /// org, so writing them is one.
+    pub fn is_operator(self) -> bool {
+        self.is_admin() || self == Scope::TemplatesWrite
+    }
+
+    /// Whether the scope is one of the `/admin` routes', which callers
+    /// without a key only hold through the admin token.
+    pub fn is_admin(self) -> bool {
+        matches!(self, Scope::FaultsAdmin | Scope::AdminRead | Scope::AdminWrite)
+    }
+
+    /// Whether `pattern` (`*`, `group.*` or an exact scope) grants this scope.
+    /// `*` grants everything but operator scopes.
+    pub fn matches(self, pattern: &str) -> bool {
+        let name = self.as_str();
+        match pattern.strip_suffix(".*") {
+            _ if pattern == "*" => !self.is_operator(),
+            Some(group) => name.split_once('.').is_some_and(|(g, _)| g == group),
+            None => pattern == name,
+        }
+    }
+}
+
+impl fmt::Display for Scope {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.pad(self.as_str())
+    }
+}
+
+impl FromStr for Scope {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Scope::ALL
+            .iter()
+            .copied()
+            .find(|scope| scope.as_str() == s)
+            .ok_or_else(|| format!("unknown scope {:?}", s))
+    }
+}
+
+/// Check that a scope pattern names at least one scope.
+pub fn validate_pattern(pattern: &str) -> Result<(), String> {
+    if Scope::ALL.iter().any(|scope| scope.matches(pattern)) {
+        Ok(())
+    } else {
+        Err(format!("unknown scope {:?}", pattern))
+    }
+}
+
+/// Scopes of a share token with `access`. None of them let it change the
+/// session itself or mint tokens of its own.
+pub fn share_scopes(access: ShareAccess) -> &'static [Scope] {
+    match access {
+        ShareAccess::Read => &[Scope::SessionsRead, Scope::FilesRead, Scope::BackgroundRead],
+        ShareAccess::Run => &[Scope::SessionsRead, Scope::FilesRead, Scope::BackgroundRead, Scope::ExecRun],
+        ShareAccess::Full => &[
+            Scope::SessionsRead,
+            Scope::FilesRead,
+            Scope::BackgroundRead,
+            Scope::ExecRun,
+            Scope::FilesWrite,
+            Scope::BackgroundManage,
+        ],
+    }
+}
+
+/// Whether `patterns` grant `scope`; `None` grants everything but operator
+/// scopes.
+pub fn allows(patterns: Option<&[String]>, scope: Scope) -> bool {
+    match patterns {
+        None => !scope.is_operator(),
+        Some(patterns) => patterns.iter().any(|p| scope.matches(p)),
+    }
+}
@@ -0,0 +1,73 @@
+//! Session secrets (`/sessions/:id/secrets`).
+//!
+//! Secrets reach sandboxed processes as environment variables, like the
+//! session env, but the server never hands their values back: session
+//! listings carry only their names, and wherever the server returns or
+//! records what a process did (run output, background logs, kernel output,
+//! run webhooks, replay bundles and its own logs) the values are masked.
+//! Masking is textual, so a process that transforms a secret (e.g. base64
+//! encodes it) can still print it.
+//!
+//! The config copy a run's child process is started from, and records
+//! decrypted from the session store (see [`crate::store_key`]), are
+//! [`wipe`]d once used.
+
+use std::collections::HashMap;
+
+/// What a secret value is replaced with.
+pub const MASK: &str = "***";
+
+/// Secrets shorter than this aren't masked: hiding every `1` or `on` would
+/// garble output without protecting anything.
+const MIN_MASKED_LEN: usize = 4;
+
+/// The values worth masking, longest first so a secret containing another
+/// is masked whole.
+pub fn maskable(secrets: &HashMap<String, String>) -> Vec<String> {
+    let mut values: Vec<String> = secrets
+        .values()
+        .filter(|v| v.len() >= MIN_MASKED_LEN)
+        .cloned()
+        .collect();
+    values.sort_by(|a, b| b.len().cmp(&a.len()).then_with(|| a.cmp(b)));
+    values.dedup();
+    values
+}
+
+/// `text` with every occurrence of `values` replaced by [`MASK`].
+pub fn redact(text: &str, values: &[String]) -> String {
+    let mut text = text.to_string();
+    for value in values {
+        if !value.is_empty() && text.contains(value.as_str()) {
+            text = text.replace(value.as_str(), MASK);
+        }
+    }
+    text
+}
+
+/// [`redact`] applied to each of `args`.
+pub fn redact_all(args: &[String], values: &[String]) -> Vec<String> {
+    args.iter().map(|arg| redact(arg, values)).collect()
+}
+
+/// Overwrite `bytes` with zeros in a way the compiler can't skip, so a
+/// plaintext copy doesn't linger in freed memory.
+pub fn wipe_bytes(bytes: &mut [u8]) {
+    for byte in bytes.iter_mut() {
+        // SAFETY: `byte` is a valid, exclusive reference
+        unsafe { std::ptr::write_volatile(byte, 0) };
+    }
+    std::sync::atomic::compiler_fence(std::sync::atomic::Ordering::SeqCst);
+}
+
+/// [`wipe_bytes`] for a string, which is left empty.
+pub fn wipe(value: &mut String) {
+    // SAFETY: all-zero bytes are valid UTF-8, and the string is cleared after
+    wipe_bytes(unsafe { value.as_bytes_mut() });
+    value.clear();
+}
+
+/// [`wipe`] every value of an environment.
+pub fn wipe_env(env: &mut HashMap<String, String>) {
+    env.values_mut().for_each(wipe);
+}
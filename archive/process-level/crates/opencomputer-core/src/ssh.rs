@@ -0,0 +1,653 @@
+//! SSH access to sessions.
+//!
+//! The embedded SSH server (off unless a listen address is configured) lets
+//! people `ssh` and `scp` into a session and point an IDE's remote
+//! development at it. The user name is the session's ID or slug, and the
+//! only way in is a key minted for that session with
+//! `POST /sessions/:id/ssh-keys`; the server keeps just its public half.
+//! Shells and commands run the way background processes do: chrooted into
+//! the sandbox as its user, with the session's env and cwd, in its network
+//! namespace if it has an egress policy. Port forwards (`ssh -L`) reach only
+//! ports something in the session listens on, as TCP tunnels do. An open
+//! connection keeps its session from going idle.
+
+use crate::sandbox::{self, RunConfig};
+use crate::state::AppState;
+use russh::keys::ssh_key::LineEnding;
+use russh::keys::{Algorithm, HashAlg, PrivateKey, PublicKey};
+use russh::server::{Auth, Handle, Msg, Server as _, Session};
+use russh::{Channel, ChannelId, ChannelMsg, Sig};
+use std::collections::HashMap;
+use std::io::Write;
+use std::net::SocketAddr;
+use std::os::fd::{AsRawFd, OwnedFd};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::mpsc;
+use tokio::task::{AbortHandle, JoinHandle};
+use tracing::{error, info, warn};
+
+/// Where the server's host key is kept (generated on first start).
+pub const DEFAULT_HOST_KEY_PATH: &str = "/var/lib/opensandbox/ssh_host_ed25519_key";
+
+/// Where sandbox images usually keep OpenSSH's SFTP server, for `sftp` and
+/// for `scp` (which speaks SFTP unless run with `-O`).
+const SFTP_SERVERS: &[&str] = &[
+    "/usr/lib/openssh/sftp-server",
+    "/usr/libexec/openssh/sftp-server",
+    "/usr/lib/ssh/sftp-server",
+    "/usr/libexec/sftp-server",
+];
+
+/// How long a terminal's output is drained after its shell exits; processes
+/// the shell left running may hold the terminal open indefinitely.
+const PTY_DRAIN: Duration = Duration::from_secs(1);
+
+/// A key minted for a session. Only the caller gets the private half.
+pub struct MintedKey {
+    /// OpenSSH format
+    pub private_key: String,
+    /// `ssh-ed25519 AAAA...`
+    pub public_key: String,
+    /// `SHA256:...`
+    pub fingerprint: String,
+}
+
+/// Generate an Ed25519 key pair for logging in to a session.
+pub fn mint_key() -> Result<MintedKey, String> {
+    let key = PrivateKey::random(&mut russh::keys::key::safe_rng(), Algorithm::Ed25519)
+        .map_err(|e| format!("generate key: {}", e))?;
+    let public = key.public_key();
+    Ok(MintedKey {
+        private_key: key
+            .to_openssh(LineEnding::LF)
+            .map_err(|e| format!("encode key: {}", e))?
+            .to_string(),
+        public_key: public.to_openssh().map_err(|e| format!("encode key: {}", e))?,
+        fingerprint: public.fingerprint(HashAlg::Sha256).to_string(),
+    })
+}
+
+/// The server's host key from `path`, generated and saved there (readable
+/// only by the server) if there is none yet.
+pub fn load_or_create_host_key(path: &Path) -> Result<PrivateKey, String> {
+    if path.exists() {
+        return russh::keys::load_secret_key(path, None)
+            .map_err(|e| format!("read SSH host key {}: {}", path.display(), e));
+    }
+    let key = PrivateKey::random(&mut russh::keys::key::safe_rng(), Algorithm::Ed25519)
+        .map_err(|e| format!("generate SSH host key: {}", e))?;
+    let encoded = key
+        .to_openssh(LineEnding::LF)
+        .map_err(|e| format!("encode SSH host key: {}", e))?;
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir).map_err(|e| format!("mkdir {}: {}", dir.display(), e))?;
+    }
+    std::fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(path)
+        .and_then(|mut file| file.write_all(encoded.as_bytes()))
+        .map_err(|e| format!("write SSH host key {}: {}", path.display(), e))?;
+    info!("Generated SSH host key at {}", path.display());
+    Ok(key)
+}
+
+/// Serve SSH on `addr` until the listener fails.
+pub async fn run_server(addr: SocketAddr, state: AppState, host_key: PrivateKey) {
+    let config = russh::server::Config {
+        keys: vec![host_key],
+        methods: [russh::MethodKind::PublicKey].as_slice().into(),
+        // Clients probe with "none" first; only refused keys are slowed down
+        auth_rejection_time_initial: Some(Duration::ZERO),
+        // Idle shells stay open; dead clients are noticed by keepalives
+        inactivity_timeout: None,
+        keepalive_interval: Some(Duration::from_secs(30)),
+        ..Default::default()
+    };
+    info!("Starting SSH server on {}", addr);
+    let mut server = Server { state };
+    if let Err(e) = server.run_on_address(Arc::new(config), addr).await {
+        error!("SSH server on {} failed: {}", addr, e);
+    }
+}
+
+struct Server {
+    state: AppState,
+}
+
+impl russh::server::Server for Server {
+    type Handler = Connection;
+
+    fn new_client(&mut self, peer: Option<SocketAddr>) -> Connection {
+        Connection {
+            state: self.state.clone(),
+            peer,
+            session_id: None,
+            keepalive: None,
+        }
+    }
+}
+
+/// One client connection.
+struct Connection {
+    state: AppState,
+    peer: Option<SocketAddr>,
+    /// Session logged in to
+    session_id: Option<String>,
+    /// Keeps the session from going idle while connected
+    keepalive: Option<AbortHandle>,
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        if let Some(ref keepalive) = self.keepalive {
+            keepalive.abort();
+        }
+    }
+}
+
+impl Connection {
+    /// The session `user` names, if `key` may log in to it.
+    async fn authorize(&self, user: &str, key: &PublicKey) -> Option<String> {
+        let id = self.state.slugs.read().await.get(user).cloned().unwrap_or_else(|| user.to_string());
+        let fingerprint = key.fingerprint(HashAlg::Sha256).to_string();
+        let sessions = self.state.sessions.read().await;
+        let now = Instant::now();
+        sessions
+            .get(&id)?
+            .ssh_keys
+            .iter()
+            .any(|k| k.fingerprint == fingerprint && k.expires_at.is_none_or(|at| at > now))
+            .then_some(id)
+    }
+}
+
+impl russh::server::Handler for Connection {
+    type Error = russh::Error;
+
+    async fn auth_publickey_offered(&mut self, user: &str, key: &PublicKey) -> Result<Auth, Self::Error> {
+        Ok(match self.authorize(user, key).await {
+            Some(_) => Auth::Accept,
+            None => Auth::reject(),
+        })
+    }
+
+    async fn auth_publickey(&mut self, user: &str, key: &PublicKey) -> Result<Auth, Self::Error> {
+        let Some(id) = self.authorize(user, key).await else {
+            return Ok(Auth::reject());
+        };
+        info!(
+            "SSH login to session {} with key {} from {:?}",
+            id,
+            key.fingerprint(HashAlg::Sha256),
+            self.peer
+        );
+        let state = self.state.clone();
+        let session_id = id.clone();
+        let keepalive = tokio::spawn(async move {
+            let mut ticks = tokio::time::interval(Duration::from_secs(30));
+            loop {
+                ticks.tick().await;
+                match state.sessions.write().await.get_mut(&session_id) {
+                    Some(session) => session.last_used = Instant::now(),
+                    None => return,
+                }
+            }
+        });
+        self.keepalive = Some(keepalive.abort_handle());
+        self.session_id = Some(id);
+        Ok(Auth::Accept)
+    }
+
+    async fn channel_open_session(
+        &mut self,
+        channel: Channel<Msg>,
+        session: &mut Session,
+    ) -> Result<bool, Self::Error> {
+        let Some(ref id) = self.session_id else {
+            return Ok(false);
+        };
+        tokio::spawn(serve_channel(self.state.clone(), id.clone(), channel, session.handle()));
+        Ok(true)
+    }
+
+    async fn channel_open_direct_tcpip(
+        &mut self,
+        channel: Channel<Msg>,
+        host: &str,
+        port: u32,
+        _originator_address: &str,
+        _originator_port: u32,
+        _session: &mut Session,
+    ) -> Result<bool, Self::Error> {
+        let Some(ref id) = self.session_id else {
+            return Ok(false);
+        };
+        let Ok(port) = u16::try_from(port) else {
+            return Ok(false);
+        };
+        if !matches!(host, "localhost" | "127.0.0.1" | "::1") {
+            return Ok(false);
+        }
+        let sandbox_root = match self.state.sessions.read().await.get(id) {
+            Some(session) if !session.status.is_suspended() => session.sandbox_root.clone(),
+            _ => return Ok(false),
+        };
+        let stream = tokio::task::spawn_blocking(move || {
+            if !sandbox::session_listens_on(&sandbox_root, port) {
+                return None;
+            }
+            let stream = sandbox::connect_to_session(&sandbox_root, port, Duration::from_secs(10)).ok()?;
+            stream.set_nonblocking(true).ok()?;
+            tokio::net::TcpStream::from_std(stream).ok()
+        })
+        .await
+        .ok()
+        .flatten();
+        let Some(mut stream) = stream else {
+            return Ok(false);
+        };
+        info!("SSH port forward opened: session {} port {}", id, port);
+        tokio::spawn(async move {
+            let mut channel = channel.into_stream();
+            let _ = tokio::io::copy_bidirectional(&mut channel, &mut stream).await;
+        });
+        Ok(true)
+    }
+}
+
+/// What a session channel runs.
+enum Program {
+    Shell,
+    Exec(String),
+    Subsystem(String),
+}
+
+/// Terminal requested for a channel.
+struct Pty {
+    term: String,
+    size: libc::winsize,
+}
+
+/// A channel's process.
+struct Process {
+    child: tokio::process::Child,
+    /// Also its process group
+    pid: i32,
+    /// Master side of its terminal
+    pty: Option<OwnedFd>,
+    /// Input for it; dropped on EOF
+    stdin: Option<mpsc::UnboundedSender<Vec<u8>>>,
+    /// Tasks relaying its output, left to finish once it exits
+    output: Vec<JoinHandle<()>>,
+}
+
+impl Process {
+    /// Wait for the process to exit and its output to be sent; its exit
+    /// status, or 128 + the signal that killed it.
+    async fn wait(&mut self) -> u32 {
+        use std::os::unix::process::ExitStatusExt;
+
+        let status = self.child.wait().await;
+        let drain = async {
+            while let Some(task) = self.output.last_mut() {
+                let _ = task.await;
+                self.output.pop();
+            }
+        };
+        if self.pty.is_some() {
+            let _ = tokio::time::timeout(PTY_DRAIN, drain).await;
+        } else {
+            drain.await;
+        }
+        match status {
+            Ok(status) => match (status.code(), status.signal()) {
+                (Some(code), _) => code as u32,
+                (None, Some(signal)) => 128 + signal as u32,
+                (None, None) => 255,
+            },
+            Err(_) => 255,
+        }
+    }
+
+    fn resize(&self, size: &libc::winsize) {
+        if let Some(ref master) = self.pty {
+            unsafe { libc::ioctl(master.as_raw_fd(), libc::TIOCSWINSZ, size) };
+        }
+    }
+}
+
+impl Drop for Process {
+    /// Hang up on what the channel left running, as closing a terminal would.
+    fn drop(&mut self) {
+        if self.pid > 0 {
+            let _ = nix::sys::signal::killpg(nix::unistd::Pid::from_raw(self.pid), nix::sys::signal::Signal::SIGHUP);
+        }
+    }
+}
+
+/// Handle a session channel's requests: at most one shell, command or
+/// subsystem, its terminal and input, until it exits or the client closes
+/// the channel.
+async fn serve_channel(state: AppState, session_id: String, channel: Channel<Msg>, handle: Handle) {
+    let id = channel.id();
+    let (mut requests, writer) = channel.split();
+    let mut pty: Option<Pty> = None;
+    let mut env: HashMap<String, String> = HashMap::new();
+    let mut process: Option<Process> = None;
+    loop {
+        let exited = async {
+            match process.as_mut() {
+                Some(process) => process.wait().await,
+                None => std::future::pending().await,
+            }
+        };
+        tokio::select! {
+            status = exited => {
+                let _ = writer.exit_status(status).await;
+                let _ = writer.eof().await;
+                let _ = writer.close().await;
+                break;
+            }
+            request = requests.wait() => {
+                let Some(request) = request else { break };
+                match request {
+                    ChannelMsg::RequestPty { term, col_width, row_height, pix_width, pix_height, .. } => {
+                        let size = libc::winsize {
+                            ws_row: row_height as u16,
+                            ws_col: col_width as u16,
+                            ws_xpixel: pix_width as u16,
+                            ws_ypixel: pix_height as u16,
+                        };
+                        pty = Some(Pty { term, size });
+                        reply(&handle, id, process.is_none()).await;
+                    }
+                    // As sshd's default AcceptEnv
+                    ChannelMsg::SetEnv { variable_name, variable_value, .. }
+                        if variable_name == "LANG" || variable_name.starts_with("LC_") =>
+                    {
+                        env.insert(variable_name, variable_value);
+                    }
+                    ChannelMsg::RequestShell { .. } | ChannelMsg::Exec { .. } | ChannelMsg::RequestSubsystem { .. }
+                        if process.is_some() =>
+                    {
+                        reply(&handle, id, false).await;
+                    }
+                    ChannelMsg::RequestShell { .. } => {
+                        process = start(&state, &session_id, Program::Shell, pty.as_ref(), &env, &handle, &writer).await;
+                    }
+                    ChannelMsg::Exec { command, .. } => {
+                        let command = String::from_utf8_lossy(&command).into_owned();
+                        let program = Program::Exec(command);
+                        process = start(&state, &session_id, program, pty.as_ref(), &env, &handle, &writer).await;
+                    }
+                    ChannelMsg::RequestSubsystem { name, .. } => {
+                        let program = Program::Subsystem(name);
+                        process = start(&state, &session_id, program, pty.as_ref(), &env, &handle, &writer).await;
+                    }
+                    ChannelMsg::Data { data } => {
+                        if let Some(stdin) = process.as_ref().and_then(|p| p.stdin.as_ref()) {
+                            let _ = stdin.send(data.to_vec());
+                        }
+                    }
+                    // A terminal has no EOF of its own; ^D arrives as data
+                    ChannelMsg::Eof => {
+                        if let Some(process) = process.as_mut().filter(|p| p.pty.is_none()) {
+                            process.stdin = None;
+                        }
+                    }
+                    ChannelMsg::WindowChange { col_width, row_height, pix_width, pix_height } => {
+                        let size = libc::winsize {
+                            ws_row: row_height as u16,
+                            ws_col: col_width as u16,
+                            ws_xpixel: pix_width as u16,
+                            ws_ypixel: pix_height as u16,
+                        };
+                        match process {
+                            Some(ref process) => process.resize(&size),
+                            None => {
+                                if let Some(ref mut pty) = pty {
+                                    pty.size = size;
+                                }
+                            }
+                        }
+                    }
+                    ChannelMsg::Signal { signal } => {
+                        if let (Some(process), Some(signal)) = (process.as_ref(), to_signal(&signal)) {
+                            let _ = nix::sys::signal::kill(nix::unistd::Pid::from_raw(process.pid), signal);
+                        }
+                    }
+                    _ => {}
+                }
+            }
+        }
+    }
+}
+
+async fn reply(handle: &Handle, id: ChannelId, success: bool) {
+    let _ = match success {
+        true => handle.channel_success(id).await,
+        false => handle.channel_failure(id).await,
+    };
+}
+
+/// Start `program` in the session for a channel and answer the request. If
+/// it can't start, the channel gets the reason on stderr and exit status 1,
+/// as a shell would give it (or a failure, for an unknown subsystem).
+async fn start(
+    state: &AppState,
+    session_id: &str,
+    program: Program,
+    pty: Option<&Pty>,
+    env: &HashMap<String, String>,
+    handle: &Handle,
+    writer: &russh::ChannelWriteHalf<Msg>,
+) -> Option<Process> {
+    let id = writer.id();
+    let context = {
+        let mut sessions = state.sessions.write().await;
+        match sessions.get_mut(session_id) {
+            None => Err("Session not found".to_string()),
+            Some(session) if session.status.is_suspended() => Err(format!("Session is {}", session.status)),
+            Some(session) => {
+                session.last_used = Instant::now();
+                Ok((
+                    session.sandbox_root.clone(),
+                    session.process_env(),
+                    session.secret_values(),
+                    session.cwd.clone(),
+                    session.determinism.clone(),
+                ))
+            }
+        }
+    };
+    let spawned = context.and_then(|(sandbox_root, mut process_env, secrets, cwd, determinism)| {
+        let shell = if sandbox_root.join("bin/bash").exists() { "/bin/bash" } else { "/bin/sh" };
+        let command = match program {
+            Program::Shell => vec![shell.to_string(), "-l".to_string()],
+            Program::Exec(ref command) => vec![shell.to_string(), "-c".to_string(), command.clone()],
+            Program::Subsystem(ref name) if name == "sftp" => {
+                let server = SFTP_SERVERS
+                    .iter()
+                    .find(|path| sandbox_root.join(path.trim_start_matches('/')).exists())
+                    .ok_or_else(|| "No SFTP server in the sandbox".to_string())?;
+                vec![server.to_string()]
+            }
+            Program::Subsystem(ref name) => return Err(format!("Unknown subsystem {}", name)),
+        };
+        process_env.extend(env.iter().map(|(k, v)| (k.clone(), v.clone())));
+        if let Some(pty) = pty {
+            process_env.insert("TERM".to_string(), pty.term.clone());
+        }
+        let cwd = if sandbox::confine_cwd(&sandbox_root, &cwd).is_ok() { cwd } else { "/".to_string() };
+        let config = RunConfig {
+            command,
+            time_ms: 0,
+            mem_kb: 0,
+            fsize_kb: 0,
+            nofile: 0,
+            nproc: 0,
+            env: process_env,
+            cwd,
+            commit_on_success: false,
+            determinism: Some(determinism),
+            stdin: None,
+            max_output_bytes: 0,
+            kill_grace_ms: 0,
+            secrets,
+        };
+        if let Err(e) = sandbox::add_user_entry(&sandbox_root, shell) {
+            warn!("Could not add the sandbox user to /etc/passwd in session {}: {}", session_id, e);
+        }
+        let command = sandbox::sandboxed_command(&sandbox_root, &config)?;
+        spawn(command, pty, writer)
+    });
+    match spawned {
+        Ok(process) => {
+            reply(handle, id, true).await;
+            info!("SSH {} started in session {} (pid {})", describe(&program), session_id, process.pid);
+            Some(process)
+        }
+        Err(e) if matches!(program, Program::Subsystem(_)) => {
+            warn!("SSH subsystem refused in session {}: {}", session_id, e);
+            reply(handle, id, false).await;
+            None
+        }
+        Err(e) => {
+            reply(handle, id, true).await;
+            let mut stderr = writer.make_writer_ext(Some(1));
+            let _ = stderr.write_all(format!("{}\r\n", e).as_bytes()).await;
+            let _ = stderr.flush().await;
+            let _ = writer.exit_status(1).await;
+            let _ = writer.eof().await;
+            let _ = writer.close().await;
+            None
+        }
+    }
+}
+
+fn describe(program: &Program) -> &str {
+    match program {
+        Program::Shell => "shell",
+        Program::Exec(_) => "command",
+        Program::Subsystem(name) => name,
+    }
+}
+
+/// Spawn `command` in its own process group, on a new terminal if one was
+/// requested or on pipes otherwise, relaying its output to the channel.
+fn spawn(
+    mut command: std::process::Command,
+    pty: Option<&Pty>,
+    writer: &russh::ChannelWriteHalf<Msg>,
+) -> Result<Process, String> {
+    use std::os::unix::process::CommandExt;
+    use std::process::Stdio;
+
+    let (stdin_tx, mut stdin_rx) = mpsc::unbounded_channel::<Vec<u8>>();
+    let mut output = Vec::new();
+    let process = match pty {
+        Some(pty) => {
+            let terminal = nix::pty::openpty(Some(&pty.size), None).map_err(|e| format!("openpty: {}", e))?;
+            let slave = |fd: &OwnedFd| fd.try_clone().map(Stdio::from).map_err(|e| format!("dup terminal: {}", e));
+            command.stdin(slave(&terminal.slave)?).stdout(slave(&terminal.slave)?).stderr(slave(&terminal.slave)?);
+            unsafe {
+                command.pre_exec(|| {
+                    nix::unistd::setsid().map_err(std::io::Error::from)?;
+                    if libc::ioctl(0, libc::TIOCSCTTY, 0) < 0 {
+                        return Err(std::io::Error::last_os_error());
+                    }
+                    Ok(())
+                });
+            }
+            let child = tokio::process::Command::from(command)
+                .spawn()
+                .map_err(|e| format!("spawn: {}", e))?;
+            drop(terminal.slave);
+            let master = terminal.master;
+            let dup = |fd: &OwnedFd| {
+                fd.try_clone()
+                    .map(|fd| tokio::fs::File::from_std(std::fs::File::from(fd)))
+                    .map_err(|e| format!("dup terminal: {}", e))
+            };
+            let (mut reader, mut input) = (dup(&master)?, dup(&master)?);
+            let mut stdout = writer.make_writer();
+            output.push(tokio::spawn(async move {
+                let _ = tokio::io::copy(&mut reader, &mut stdout).await;
+            }));
+            tokio::spawn(async move {
+                while let Some(data) = stdin_rx.recv().await {
+                    if input.write_all(&data).await.is_err() {
+                        return;
+                    }
+                }
+            });
+            Process {
+                pid: child.id().unwrap_or_default() as i32,
+                child,
+                pty: Some(master),
+                stdin: Some(stdin_tx),
+                output,
+            }
+        }
+        None => {
+            command.stdin(Stdio::piped()).stdout(Stdio::piped()).stderr(Stdio::piped()).process_group(0);
+            let mut child = tokio::process::Command::from(command)
+                .spawn()
+                .map_err(|e| format!("spawn: {}", e))?;
+            let (mut input, mut stdout, mut stderr) = (
+                child.stdin.take().ok_or("no stdin")?,
+                child.stdout.take().ok_or("no stdout")?,
+                child.stderr.take().ok_or("no stderr")?,
+            );
+            let (mut out, mut err) = (writer.make_writer(), writer.make_writer_ext(Some(1)));
+            output.push(tokio::spawn(async move {
+                let _ = tokio::io::copy(&mut stdout, &mut out).await;
+            }));
+            output.push(tokio::spawn(async move {
+                let _ = tokio::io::copy(&mut stderr, &mut err).await;
+            }));
+            tokio::spawn(async move {
+                while let Some(data) = stdin_rx.recv().await {
+                    if input.write_all(&data).await.is_err() {
+                        return;
+                    }
+                }
+                let _ = input.shutdown().await;
+            });
+            Process {
+                pid: child.id().unwrap_or_default() as i32,
+                child,
+                pty: None,
+                stdin: Some(stdin_tx),
+                output,
+            }
+        }
+    };
+    Ok(process)
+}
+
+fn to_signal(signal: &Sig) -> Option<nix::sys::signal::Signal> {
+    use nix::sys::signal::Signal;
+
+    Some(match signal {
+        Sig::ABRT => Signal::SIGABRT,
+        Sig::ALRM => Signal::SIGALRM,
+        Sig::FPE => Signal::SIGFPE,
+        Sig::HUP => Signal::SIGHUP,
+        Sig::ILL => Signal::SIGILL,
+        Sig::INT => Signal::SIGINT,
+        Sig::KILL => Signal::SIGKILL,
+        Sig::PIPE => Signal::SIGPIPE,
+        Sig::QUIT => Signal::SIGQUIT,
+        Sig::SEGV => Signal::SIGSEGV,
+        Sig::TERM => Signal::SIGTERM,
+        Sig::USR1 => Signal::SIGUSR1,
+        Sig::Custom(_) => return None,
+    })
+}
@@ -0,0 +1,1282 @@
+//! Shared application state and session types.
+
+use crate::activity::ActivityTracker;
+use crate::backend::{ChrootBackend, SandboxBackend};
+use crate::background_log::{BackgroundLogs, LogRotation};
+use crate::body_limit::BodyLimits;
+use crate::cache::{self, BuildCache, DEFAULT_BUILD_CACHE_DIR};
+use crate::cgroups::{Cgroups, SessionLimits};
+use crate::auth::{ApiKey, ApiKeys};
+use crate::capacity::{self, Capacity, CreateStats};
+#[cfg(feature = "chaos")]
+use crate::chaos::FaultInjector;
+use crate::cleanup_policy::CleanupPolicy;
+use crate::disk_usage::DiskUsageCache;
+use crate::env_policy::EnvPolicy;
+use crate::egress::{self, Egress, EgressPolicy};
+use crate::events::{EventBus, ServerEventKind};
+use crate::history::{self, History};
+use crate::hooks::{FileWrite, Hooks, RunFinish, RunStart};
+use crate::idempotency::IdempotencyCache;
+use crate::images::{ImageStore, DEFAULT_IMAGES_DIR};
+use crate::jobs::Jobs;
+use crate::kernel::Kernels;
+use crate::lifecycle::{
+    BackgroundExit, LifecycleTransition, RunCompletion, SessionLifecycleEvent, SessionLifecycleHook,
+};
+use crate::oidc;
+use crate::packages::{PackageCache, DEFAULT_PACKAGE_CACHE_DIR};
+use crate::persistence::{RestoreSummary, SessionRecord, SessionStore};
+use crate::pool::WarmPool;
+use crate::ports::PortAllocator;
+use crate::preview_auth::{self, PreviewAuth};
+use crate::progress::ProgressHub;
+use crate::quota::CpuUsage;
+use crate::recurring::SessionSchedule;
+use crate::reservation::{self, ReservationPolicy, Resources};
+use crate::run_limits::RunLimits;
+use crate::sandbox::{self, Determinism};
+use crate::static_site::StaticSite;
+use crate::stats::StatsCollector;
+use crate::store_key::StoreKey;
+use crate::tasks::TaskDefinition;
+use crate::templates::{TemplateRegistry, DEFAULT_TEMPLATES_DIR};
+use crate::timeline::Timeline;
+use opencomputer_types::CreateSessionRequest;
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::RwLock;
+use tracing::{info, warn};
+
+pub use opencomputer_types::{IdleReason, SessionStatus, ShareAccess};
+
+/// Default session TTL in seconds (5 minutes)
+pub const SESSION_TTL_SECS: u64 = 300;
+
+/// Default upper bound for a requested session TTL in seconds (24 hours)
+pub const MAX_SESSION_TTL_SECS: u64 = 86400;
+
+/// How long a session must have been idle before it may be evicted, so a
+/// burst of creates can't push out sessions that are in use.
+pub const EVICT_MIN_IDLE_SECS: u64 = 60;
+
+/// What a create does once the server has `max_sessions` sessions.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FullPolicy {
+    /// Refuse it with `503` and a `Retry-After` of when the next session
+    /// expires
+    #[default]
+    Reject,
+    /// Remove the session idle the longest (at least
+    /// [`EVICT_MIN_IDLE_SECS`], and not busy) to make room
+    Evict,
+}
+
+impl std::str::FromStr for FullPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "reject" => Ok(FullPolicy::Reject),
+            "evict" => Ok(FullPolicy::Evict),
+            other => Err(format!("invalid policy {:?} (expected reject or evict)", other)),
+        }
+    }
+}
+
+/// Subdomains that can't be claimed as preview slugs.
+const RESERVED_SLUGS: &[&str] = &[
+    "www", "api", "app", "admin", "auth", "login", "preview", "static", "assets", "cdn",
+    "mail", "smtp", "docs", "status", "health", "dashboard", "grpc", "internal", "localhost",
+];
+
+/// A sandbox session with persistent environment and working directory.
+#[derive(Debug)]
+pub struct Session {
+    pub id: String,
+    pub sandbox_root: PathBuf,
+    pub env: HashMap<String, String>,
+    pub cwd: String,
+    pub created_at: Instant,
+    pub last_used: Instant,
+    /// When the cleanup task last saw the session busy, and how; see
+    /// [`crate::activity`]
+    pub last_activity: Option<(Instant, IdleReason)>,
+    /// Idle time after which the session is reaped
+    pub ttl: Duration,
+    /// Preview URL for accessing web servers in the sandbox
+    pub preview_url: Option<String>,
+    /// Exposed ports
+    pub ports: Vec<u16>,
+    /// Those of `ports` the port scan found; see [`crate::port_scan`]
+    pub detected_ports: Vec<u16>,
+    /// Directory served at the preview URL; see [`crate::static_site`]
+    pub static_site: Option<StaticSite>,
+    /// Current session status
+    pub status: SessionStatus,
+    /// PIDs of background processes (e.g., dev servers)
+    pub background_pids: Vec<u32>,
+    /// Optional auth required on preview requests
+    pub preview_auth: Option<PreviewAuth>,
+    /// Custom preview subdomain used instead of the session ID
+    pub slug: Option<String>,
+    /// Template the sandbox was created from
+    pub template: Option<String>,
+    /// OCI image the sandbox was built on, as requested; see [`crate::images`]
+    pub image: Option<String>,
+    /// Caller-supplied metadata for filtering and bookkeeping
+    pub labels: HashMap<String, String>,
+    /// Hostname, machine ID and random seed fixed at creation
+    pub determinism: Determinism,
+    /// API key the session was created with (counts against its quota)
+    pub api_key: Option<Arc<ApiKey>>,
+    /// Org that owns the session; only its keys can see or use it
+    pub org_id: Option<String>,
+    /// Resources reserved against host capacity at creation
+    pub resources: Option<Resources>,
+    /// CPU share and cap of the session's cgroup (None = not in one); see
+    /// [`crate::cgroups`]
+    pub limits: Option<SessionLimits>,
+    /// Session tokens by token string; they die with the session
+    pub tokens: HashMap<String, SessionToken>,
+    /// Env values the server never returns (e.g. git tokens); see [`crate::secrets`]
+    pub secrets: HashMap<String, String>,
+    /// Domains the session may reach through its egress proxy (None = the
+    /// host's network, unrestricted); see [`crate::egress`]
+    pub egress: Option<EgressPolicy>,
+    /// Keys that may log in over SSH, minted with `POST /sessions/:id/ssh-keys`
+    pub ssh_keys: Vec<SessionSshKey>,
+    /// How long entries deleted through the file API stay restorable (None =
+    /// deleted outright); see [`crate::trash`]
+    pub trash_ttl: Option<Duration>,
+    /// Named commands by name; see [`crate::tasks`]
+    pub tasks: BTreeMap<String, TaskDefinition>,
+    /// Recurring commands by schedule ID; see [`crate::recurring`]
+    pub schedules: BTreeMap<String, SessionSchedule>,
+}
+
+/// A token minted with `POST /sessions/:id/tokens` or `/share`.
+#[derive(Debug, Clone)]
+pub struct SessionToken {
+    /// Key the token authenticates as, bound to this session
+    pub key: Arc<ApiKey>,
+    pub expires_at: Option<Instant>,
+    /// Set for tokens minted with `POST /sessions/:id/share`
+    pub share: Option<ShareGrant>,
+}
+
+/// What a share token was minted as, to list and revoke it by.
+#[derive(Debug, Clone)]
+pub struct ShareGrant {
+    pub id: String,
+    pub access: ShareAccess,
+    pub label: Option<String>,
+}
+
+/// Public half of a key minted with `POST /sessions/:id/ssh-keys`; the
+/// private half is only given to the caller.
+#[derive(Debug, Clone)]
+pub struct SessionSshKey {
+    pub id: String,
+    /// OpenSSH format, `ssh-ed25519 AAAA...`
+    pub public_key: String,
+    pub fingerprint: String,
+    pub expires_at: Option<Instant>,
+}
+
+impl Session {
+    /// When the session's idle time starts: its last API call or the last
+    /// time it was seen busy, whichever is later, and which it was.
+    pub fn idle_since(&self) -> (Instant, IdleReason) {
+        match self.last_activity {
+            Some((at, reason)) if at > self.last_used => (at, reason),
+            _ => (self.last_used, IdleReason::Api),
+        }
+    }
+
+    /// Whether a caller may see the session. Without API keys everything is
+    /// visible; session tokens see only their own session.
+    pub fn visible_to(&self, key: Option<&ApiKey>) -> bool {
+        key.is_none_or(|key| {
+            self.org_id.as_deref() == Some(key.org())
+                && key.session_id.as_ref().is_none_or(|id| *id == self.id)
+        })
+    }
+
+    /// Env of the session's processes: the session env with its secrets on
+    /// top, and the egress proxy if the session has one.
+    pub fn process_env(&self) -> HashMap<String, String> {
+        let mut env = self.env.clone();
+        env.extend(self.secrets.iter().map(|(k, v)| (k.clone(), v.clone())));
+        if self.egress.is_some() {
+            for name in ["HTTP_PROXY", "HTTPS_PROXY", "http_proxy", "https_proxy"] {
+                env.insert(name.to_string(), egress::proxy_url());
+            }
+            for name in ["NO_PROXY", "no_proxy"] {
+                env.insert(name.to_string(), "localhost,127.0.0.1".to_string());
+            }
+        }
+        env
+    }
+
+    /// Secret values to mask in what the session's processes output.
+    pub fn secret_values(&self) -> Vec<String> {
+        crate::secrets::maskable(&self.secrets)
+    }
+}
+
+/// Result of [`AppState::parse_preview_host`].
+#[derive(Debug, PartialEq)]
+pub enum PreviewHost<'a> {
+    /// Session slug or ID to serve
+    Label(&'a str),
+    /// Preview host names a region this instance doesn't serve
+    OtherRegion(&'a str),
+    NotPreview,
+}
+
+/// Thread-safe session storage.
+pub type Sessions = Arc<RwLock<HashMap<String, Session>>>;
+
+/// Preview slug -> session ID index.
+pub type Slugs = Arc<RwLock<HashMap<String, String>>>;
+
+/// Reads the API keys and org quotas again, for `POST /admin/reload`.
+pub type KeySource = dyn Fn() -> Result<ApiKeys, String> + Send + Sync;
+
+/// Shared application state.
+#[derive(Clone)]
+pub struct AppState {
+    pub sessions: Sessions,
+    /// Reference point for ordering session timestamps (e.g. list cursors)
+    pub started_at: Instant,
+    /// Custom preview slugs claimed by live sessions
+    pub slugs: Slugs,
+    /// Preview domain for generating preview URLs (e.g., "preview.opensandbox.fly.dev")
+    pub preview_domain: Option<String>,
+    /// Region or instance label inserted into preview URLs
+    /// (`{session}.{region}.{domain}`) so DNS can route to this instance
+    pub preview_region: Option<String>,
+    /// Ports auto-assigned to background processes
+    pub ports: Arc<PortAllocator>,
+    /// HMAC key for signed preview auth cookies
+    pub preview_cookie_key: Arc<Vec<u8>>,
+    /// Configured API keys (empty = authentication disabled)
+    pub api_keys: Arc<ApiKeys>,
+    /// Forbidden environment variable policy
+    pub env_policy: Arc<EnvPolicy>,
+    /// TTL applied when a session doesn't request one
+    pub default_session_ttl: Duration,
+    /// Largest TTL a session may request
+    pub max_session_ttl: Duration,
+    /// Embedder callbacks for session create/expire/delete
+    pub lifecycle_hooks: Arc<Vec<Arc<dyn SessionLifecycleHook>>>,
+    /// Embedder checks on creates, runs and file writes; see [`crate::hooks`]
+    pub hooks: Arc<Vec<Arc<dyn Hooks>>>,
+    /// How sessions are isolated; see [`crate::backend`]
+    pub backend: Arc<dyn SandboxBackend>,
+    /// Pre-created sandbox roots (disabled unless configured)
+    pub warm_pool: Arc<WarmPool>,
+    /// Named base filesystems for new sessions
+    pub templates: Arc<TemplateRegistry>,
+    /// OCI images pulled for sessions created from one
+    pub images: Arc<ImageStore>,
+    /// Long-lived interpreters of sessions using the kernel endpoint
+    pub kernels: Arc<Kernels>,
+    /// Recent walks of session files behind `GET /sessions/:id/usage`
+    pub disk_usage: Arc<DiskUsageCache>,
+    /// Sampled resource use of each session's processes
+    pub session_stats: Arc<StatsCollector>,
+    /// Recent events of each session, for `GET /sessions/:id/events`
+    pub timeline: Arc<Timeline>,
+    /// Every command each session ran, for `GET /sessions/:id/history`
+    pub history: Arc<History>,
+    /// Runs started by `POST /sessions/:id/jobs`, for `GET /jobs/:id`
+    pub jobs: Arc<Jobs>,
+    /// Egress proxies of sessions created with an egress policy
+    pub egress: Arc<Egress>,
+    /// Host-managed pip/npm cache package installs are served from
+    pub packages: Arc<PackageCache>,
+    /// Lockfile-keyed `node_modules`, venvs and `target` dirs sessions share
+    pub build_cache: Arc<BuildCache>,
+    /// Label-driven retention rules applied by the cleanup task
+    pub cleanup_policy: Arc<CleanupPolicy>,
+    /// Progress of long filesystem operations, streamed by `GET /events`
+    pub progress: ProgressHub,
+    /// Session, run and proxy events for `GET /events/stream`
+    pub events: EventBus,
+    /// Server-wide cap on live sessions (None = unlimited)
+    pub max_sessions: Option<usize>,
+    /// What a create does once `max_sessions` is reached
+    pub full_policy: FullPolicy,
+    /// Caps on runs in progress, per session and server-wide
+    pub run_limits: Arc<RunLimits>,
+    /// Per-process background logs and their rotation
+    pub background_logs: Arc<BackgroundLogs>,
+    /// Responses kept for retries sent with an `Idempotency-Key`
+    pub idempotency: Arc<IdempotencyCache>,
+    /// Largest request bodies accepted, per kind of route
+    pub body_limits: BodyLimits,
+    /// Runs and connections in progress, which keep sessions from idling
+    pub activity: Arc<ActivityTracker>,
+    /// In-flight creates and create latency, reported as capacity hints
+    pub create_stats: Arc<CreateStats>,
+    /// CPU time used per org, checked against org quotas
+    pub cpu_usage: Arc<CpuUsage>,
+    /// Host capacity that session resource reservations are checked against
+    pub reservations: Arc<ReservationPolicy>,
+    /// Per-session cgroups for CPU fairness (None = sessions share the
+    /// server's); see [`crate::cgroups`]
+    pub cgroups: Option<Arc<Cgroups>>,
+    /// Where sessions are saved to survive restarts (None = memory only)
+    pub session_store: Option<Arc<SessionStore>>,
+    /// Port of the embedded SSH server (None = SSH is off); see [`crate::ssh`]
+    pub ssh_port: Option<u16>,
+    /// Where `POST /admin/reload` gets API keys from (None = can't reload)
+    pub key_source: Option<Arc<KeySource>>,
+    /// Set by `POST /admin/drain`: no new sessions, and not ready
+    pub draining: Arc<AtomicBool>,
+    /// Faults injected for resilience testing, set with `PUT /admin/faults`
+    #[cfg(feature = "chaos")]
+    pub faults: Arc<FaultInjector>,
+}
+
+impl AppState {
+    pub fn new() -> Self {
+        Self {
+            sessions: Arc::new(RwLock::new(HashMap::new())),
+            started_at: Instant::now(),
+            slugs: Arc::new(RwLock::new(HashMap::new())),
+            preview_domain: None,
+            preview_region: None,
+            ports: Arc::new(PortAllocator::default()),
+            preview_cookie_key: Arc::new(preview_auth::generate_signing_key()),
+            api_keys: Arc::new(ApiKeys::default()),
+            env_policy: Arc::new(EnvPolicy::default()),
+            default_session_ttl: Duration::from_secs(SESSION_TTL_SECS),
+            max_session_ttl: Duration::from_secs(MAX_SESSION_TTL_SECS),
+            lifecycle_hooks: Arc::new(Vec::new()),
+            hooks: Arc::new(Vec::new()),
+            backend: Arc::new(ChrootBackend),
+            warm_pool: Arc::new(WarmPool::default()),
+            templates: Arc::new(TemplateRegistry::new(DEFAULT_TEMPLATES_DIR)),
+            images: Arc::new(ImageStore::new(DEFAULT_IMAGES_DIR)),
+            kernels: Arc::new(Kernels::default()),
+            disk_usage: Arc::new(DiskUsageCache::default()),
+            session_stats: Arc::new(StatsCollector::default()),
+            timeline: Arc::new(Timeline::default()),
+            history: Arc::new(History::default()),
+            jobs: Arc::new(Jobs::default()),
+            egress: Arc::new(Egress::default()),
+            packages: Arc::new(PackageCache::new(DEFAULT_PACKAGE_CACHE_DIR)),
+            build_cache: Arc::new(BuildCache::new(DEFAULT_BUILD_CACHE_DIR, cache::DEFAULT_MAX_MB)),
+            cleanup_policy: Arc::new(CleanupPolicy::default()),
+            progress: ProgressHub::new(),
+            events: EventBus::new(),
+            max_sessions: None,
+            full_policy: FullPolicy::default(),
+            run_limits: Arc::new(RunLimits::default()),
+            background_logs: Arc::new(BackgroundLogs::default()),
+            idempotency: Arc::new(IdempotencyCache::default()),
+            body_limits: BodyLimits::default(),
+            activity: Arc::new(ActivityTracker::default()),
+            create_stats: Arc::new(CreateStats::default()),
+            cpu_usage: Arc::new(CpuUsage::default()),
+            reservations: Arc::new(ReservationPolicy::default()),
+            cgroups: None,
+            session_store: None,
+            ssh_port: None,
+            key_source: None,
+            draining: Arc::new(AtomicBool::new(false)),
+            #[cfg(feature = "chaos")]
+            faults: Arc::new(FaultInjector::default()),
+        }
+    }
+
+    pub fn with_preview_domain(preview_domain: Option<String>) -> Self {
+        Self {
+            preview_domain,
+            ..Self::new()
+        }
+    }
+
+    /// Chainable construction for embedders; see [`AppStateBuilder`].
+    pub fn builder() -> AppStateBuilder {
+        AppStateBuilder::default()
+    }
+
+    /// Put this instance's region label in preview URLs.
+    pub fn set_preview_region(&mut self, region: &str) -> Result<(), String> {
+        validate_region(region)?;
+        self.preview_region = Some(region.to_string());
+        Ok(())
+    }
+
+    /// Public preview URL for a session's slug or ID, if previews are enabled.
+    pub fn preview_url(&self, label: &str) -> Option<String> {
+        let domain = self.preview_domain.as_ref()?;
+        Some(match self.preview_region {
+            Some(ref region) => format!("https://{}.{}.{}", label, region, domain),
+            None => format!("https://{}.{}", label, domain),
+        })
+    }
+
+    /// Classify a request Host against the preview domain. Hosts without a
+    /// region (`{label}.{domain}`) are still accepted so a central proxy
+    /// keeps working.
+    pub fn parse_preview_host<'a>(&self, host: &'a str) -> PreviewHost<'a> {
+        let Some(ref domain) = self.preview_domain else {
+            return PreviewHost::NotPreview;
+        };
+        let Some(rest) = host
+            .strip_suffix(domain.as_str())
+            .and_then(|h| h.strip_suffix('.'))
+        else {
+            return PreviewHost::NotPreview;
+        };
+        match rest.rsplit_once('.') {
+            None => PreviewHost::Label(rest),
+            Some((label, region)) if self.preview_region.as_deref() == Some(region) => {
+                PreviewHost::Label(label)
+            }
+            Some((_, region)) => PreviewHost::OtherRegion(region),
+        }
+    }
+
+    /// Use a fixed secret for preview cookies so they stay valid across restarts.
+    pub fn set_preview_cookie_secret(&mut self, secret: &str) {
+        self.preview_cookie_key = Arc::new(secret.as_bytes().to_vec());
+    }
+
+    /// Require API keys on all API requests.
+    pub fn set_api_keys(&mut self, keys: ApiKeys) {
+        self.api_keys = Arc::new(keys);
+    }
+
+    /// Let `POST /admin/reload` swap in the keys `source` returns, e.g. the
+    /// key file read again.
+    pub fn set_key_source(&mut self, source: impl Fn() -> Result<ApiKeys, String> + Send + Sync + 'static) {
+        self.key_source = Some(Arc::new(source));
+    }
+
+    /// Read the API keys and org quotas again from the key source and use
+    /// them from now on. Returns how many keys and orgs are configured.
+    pub fn reload_api_keys(&self) -> Result<(usize, usize), String> {
+        let source = self
+            .key_source
+            .as_ref()
+            .ok_or_else(|| "API keys aren't loaded from a file, so there's nothing to reload".to_string())?;
+        let keys = source()?;
+        let counts = (keys.all().len(), keys.orgs().len());
+        // An empty table would turn authentication off
+        if counts.0 == 0 && self.api_keys.oidc().is_none() {
+            return Err("no keys left after reloading; keeping the current ones".to_string());
+        }
+        self.api_keys.replace(keys);
+        info!("Reloaded API keys: {} keys, {} orgs with quotas", counts.0, counts.1);
+        Ok(counts)
+    }
+
+    /// Stop taking new sessions and empty the warm pool, ahead of a shutdown
+    /// or upgrade. Live sessions keep running; readiness fails so load
+    /// balancers send creates elsewhere. Lasts until restart.
+    pub async fn drain(&self) {
+        if !self.draining.swap(true, Ordering::Relaxed) {
+            info!("Draining: no longer taking new sessions");
+        }
+        self.warm_pool.drain().await;
+    }
+
+    pub fn is_draining(&self) -> bool {
+        self.draining.load(Ordering::Relaxed)
+    }
+
+    /// Replace the forbidden environment variable policy.
+    pub fn set_env_policy(&mut self, policy: EnvPolicy) {
+        self.env_policy = Arc::new(policy);
+    }
+
+    /// Configure the default and maximum session TTL.
+    pub fn set_session_ttl(&mut self, default: Duration, max: Duration) {
+        self.default_session_ttl = default;
+        self.max_session_ttl = max;
+    }
+
+    /// Resolve a requested TTL in seconds against the server default and cap.
+    pub fn resolve_session_ttl(&self, requested_secs: Option<u64>) -> Result<Duration, String> {
+        match requested_secs {
+            None => Ok(self.default_session_ttl),
+            Some(0) => Err("ttl must be greater than 0".to_string()),
+            Some(secs) if secs > self.max_session_ttl.as_secs() => Err(format!(
+                "ttl {}s exceeds the maximum of {}s",
+                secs,
+                self.max_session_ttl.as_secs()
+            )),
+            Some(secs) => Ok(Duration::from_secs(secs)),
+        }
+    }
+
+    /// Apply label-driven retention rules. Call before spawning the cleanup task.
+    pub fn set_cleanup_policy(&mut self, policy: CleanupPolicy) {
+        self.cleanup_policy = Arc::new(policy);
+    }
+
+    /// Store templates under `dir` instead of the default location.
+    pub fn set_templates_dir(&mut self, dir: impl Into<PathBuf>) {
+        let source_dirs = self.templates.source_dirs().to_vec();
+        self.templates = Arc::new(TemplateRegistry::new(dir).with_source_dirs(source_dirs));
+    }
+
+    /// Allow `POST /templates` to copy server directories inside `dirs`.
+    pub fn set_template_source_dirs(&mut self, dirs: Vec<PathBuf>) {
+        let dir = self.templates.dir().to_path_buf();
+        self.templates = Arc::new(TemplateRegistry::new(dir).with_source_dirs(dirs));
+    }
+
+    /// Pull and cache OCI images with `images` instead of the default store.
+    pub fn set_image_store(&mut self, images: ImageStore) {
+        self.images = Arc::new(images);
+    }
+
+    pub fn set_package_cache_dir(&mut self, dir: impl Into<PathBuf>) {
+        self.packages = Arc::new(PackageCache::new(dir));
+    }
+
+    /// Keep the build cache in `dir`, evicting down to `max_mb`.
+    pub fn set_build_cache(&mut self, dir: impl Into<PathBuf>, max_mb: u64) {
+        self.build_cache = Arc::new(BuildCache::new(dir, max_mb));
+    }
+
+    /// Isolate sessions with `backend` instead of the chroot backend. Call
+    /// before [`AppState::enable_warm_pool`] so the pool uses it too.
+    pub fn set_sandbox_backend(&mut self, backend: Arc<dyn SandboxBackend>) {
+        self.backend = backend;
+    }
+
+    /// Keep `size` sandbox roots pre-created and start filling the pool.
+    /// Must be called from within a Tokio runtime.
+    pub fn enable_warm_pool(&mut self, size: usize) {
+        self.warm_pool = Arc::new(WarmPool::new(size, self.backend.clone()));
+        self.warm_pool.start();
+    }
+
+    /// Cap runs in progress; see [`RunLimits`].
+    pub fn set_run_limits(&mut self, limits: RunLimits) {
+        self.run_limits = Arc::new(limits);
+    }
+
+    /// How long responses to requests with an `Idempotency-Key` are kept;
+    /// zero ignores the header.
+    pub fn set_idempotency_window(&mut self, window: Duration) {
+        self.idempotency = Arc::new(IdempotencyCache::new(window));
+    }
+
+    /// Replace the request body limits.
+    pub fn set_body_limits(&mut self, limits: BodyLimits) {
+        self.body_limits = limits;
+    }
+
+    /// When background logs are rotated and how many segments are kept.
+    pub fn set_background_log_rotation(&mut self, rotation: LogRotation) {
+        self.background_logs = Arc::new(BackgroundLogs::new(rotation));
+    }
+
+    /// Replace the detected host capacity or the overcommit ratio.
+    pub fn set_reservation_policy(&mut self, policy: ReservationPolicy) {
+        self.reservations = Arc::new(policy);
+    }
+
+    /// Run each session's commands in a cgroup of its own; see
+    /// [`crate::cgroups`].
+    pub fn set_cgroups(&mut self, cgroups: Cgroups) {
+        self.cgroups = Some(Arc::new(cgroups));
+    }
+
+    /// Save sessions to the SQLite database at `path`, encrypted with `key`
+    /// if given, and re-adopt the ones a previous server process left behind.
+    /// Sandbox roots under `/tmp` that belong to no stored session are
+    /// destroyed, so only one server per host may use this. Call after
+    /// [`AppState::set_api_keys`] and before [`AppState::enable_warm_pool`] or
+    /// serving any request.
+    pub fn enable_persistence(&mut self, path: &Path, key: Option<StoreKey>) -> Result<RestoreSummary, String> {
+        if let Some(ref key) = key {
+            info!("Encrypting stored sessions with key {}", key.id());
+        }
+        let store = Arc::new(SessionStore::open(path, key)?);
+        let (records, corrupt) = store.load()?;
+        let mut summary = RestoreSummary::default();
+        for id in corrupt {
+            warn!("Dropping unreadable record for session {}", id);
+            store.remove(&id)?;
+            summary.dropped += 1;
+        }
+
+        let mut sessions = self
+            .sessions
+            .try_write()
+            .map_err(|_| "sessions are already in use".to_string())?;
+        let mut slugs = self
+            .slugs
+            .try_write()
+            .map_err(|_| "slugs are already in use".to_string())?;
+        for record in records {
+            if !sandbox::is_sandbox_mounted(&record.sandbox_root) {
+                warn!("Dropping session {}: sandbox {} is gone", record.id, record.sandbox_root.display());
+                self.backend.destroy(&record.sandbox_root);
+                store.remove(&record.id)?;
+                summary.dropped += 1;
+                continue;
+            }
+            let mut session = record.into_session(&self.api_keys);
+            // PIDs may have exited, or been reused outside the sandbox
+            let running = sandbox::session_pids(&session.sandbox_root);
+            session.background_pids.retain(|pid| running.contains(pid));
+            // The old proxy went with the old server; processes still
+            // running stay in its namespace, cut off
+            if let Some(ref policy) = session.egress {
+                if let Err(e) = self.egress.enable(&session.id, &session.sandbox_root, policy.clone()) {
+                    warn!("Dropping session {}: {}", session.id, e);
+                    sandbox::signal_session_processes(&session.sandbox_root, nix::sys::signal::Signal::SIGKILL);
+                    self.backend.destroy(&session.sandbox_root);
+                    store.remove(&session.id)?;
+                    summary.dropped += 1;
+                    continue;
+                }
+            }
+            // Processes still running are in the session's group already
+            session.limits = match self.cgroups {
+                Some(ref cgroups) => {
+                    let limits = cgroups.resolve(session.limits);
+                    match cgroups.attach(&session.id, &session.sandbox_root, &limits) {
+                        Ok(()) => Some(limits),
+                        Err(e) => {
+                            warn!("Session {} runs without its limits: {}", session.id, e);
+                            None
+                        }
+                    }
+                }
+                None => None,
+            };
+            if let Some(ref slug) = session.slug {
+                slugs.insert(slug.clone(), session.id.clone());
+            }
+            for &port in &session.ports {
+                self.ports.claim(port);
+            }
+            store.save(&SessionRecord::from_session(&session))?;
+            self.timeline.open(&session.id);
+            if let Err(e) = self.history.open(&session.id) {
+                warn!("Not keeping history of session {}: {}", session.id, e);
+            }
+            sessions.insert(session.id.clone(), session);
+            summary.adopted += 1;
+        }
+
+        for root in sandbox::existing_sandbox_roots() {
+            if sessions.values().any(|s| s.sandbox_root == root) {
+                continue;
+            }
+            warn!("Removing orphaned sandbox {}", root.display());
+            sandbox::signal_session_processes(&root, nix::sys::signal::Signal::SIGKILL);
+            self.backend.destroy(&root);
+            summary.orphans_removed += 1;
+        }
+        for id in self.history.sessions() {
+            if !sessions.contains_key(&id) {
+                self.history.remove(&id);
+            }
+        }
+        drop(sessions);
+        drop(slugs);
+
+        info!(
+            "Restored {} sessions from {} ({} dropped, {} orphaned sandboxes removed)",
+            summary.adopted,
+            path.display(),
+            summary.dropped,
+            summary.orphans_removed
+        );
+        self.session_store = Some(store);
+        Ok(summary)
+    }
+
+    /// Save a session's current state, if persistence is enabled.
+    pub fn persist_session(&self, session: &Session) {
+        if let Some(ref store) = self.session_store {
+            if let Err(e) = store.save(&SessionRecord::from_session(session)) {
+                warn!("Failed to persist session {}: {}", session.id, e);
+            }
+        }
+    }
+
+    /// Delete a removed session's stored record.
+    pub fn forget_session(&self, id: &str) {
+        if let Some(ref store) = self.session_store {
+            if let Err(e) = store.remove(id) {
+                warn!("Failed to forget session {}: {}", id, e);
+            }
+        }
+    }
+
+    /// Save every live session, picking up changes that aren't written
+    /// through (e.g. last-used times).
+    pub async fn sync_sessions(&self) {
+        let Some(store) = self.session_store.clone() else {
+            return;
+        };
+        let records: Vec<SessionRecord> = self
+            .sessions
+            .read()
+            .await
+            .values()
+            .map(SessionRecord::from_session)
+            .collect();
+        let saved = tokio::task::spawn_blocking(move || store.save_all(&records)).await;
+        if let Ok(Err(e)) = saved {
+            warn!("Failed to persist sessions: {}", e);
+        }
+    }
+
+    /// Let sessions mint SSH keys for the server listening on `port`.
+    pub fn set_ssh_port(&mut self, port: u16) {
+        self.ssh_port = Some(port);
+    }
+
+    /// Cap the number of live sessions across all keys.
+    pub fn set_max_sessions(&mut self, max: usize) {
+        self.max_sessions = Some(max);
+    }
+
+    /// Choose what a create does once the session cap is reached.
+    pub fn set_full_policy(&mut self, policy: FullPolicy) {
+        self.full_policy = policy;
+    }
+
+    /// Sessions `key` may still create given the current sessions: the
+    /// tightest of the key's own limit, its org's, and the server-wide one.
+    pub fn sessions_remaining(
+        &self,
+        sessions: &HashMap<String, Session>,
+        key: Option<&ApiKey>,
+    ) -> Option<usize> {
+        let server = capacity::remaining(self.max_sessions, sessions.len());
+        let (key_left, org_left) = match key {
+            Some(key) => {
+                let used = sessions
+                    .values()
+                    .filter(|s| s.api_key.as_ref().is_some_and(|k| k.key == key.key))
+                    .count();
+                let org_used = sessions.values().filter(|s| s.visible_to(Some(key))).count();
+                let org_max = self.api_keys.org_quota(key.org()).and_then(|q| q.max_sessions);
+                (
+                    capacity::remaining(key.max_sessions, used),
+                    capacity::remaining(org_max, org_used),
+                )
+            }
+            None => (None, None),
+        };
+        [server, key_left, org_left].into_iter().flatten().min()
+    }
+
+    /// Refuse new sessions and runs once the caller's org has used up its
+    /// disk or CPU quota.
+    pub async fn check_org_quota(&self, key: Option<&ApiKey>) -> Result<(), String> {
+        let Some(key) = key else {
+            return Ok(());
+        };
+        let Some(quota) = self.api_keys.org_quota(key.org()) else {
+            return Ok(());
+        };
+        if let Some(max_secs) = quota.max_cpu_secs {
+            if self.cpu_usage.used(key.org()) >= Duration::from_secs(max_secs) {
+                return Err(format!("Org CPU quota of {}s used up", max_secs));
+            }
+        }
+        if let Some(max_mb) = quota.max_disk_mb {
+            let roots: Vec<PathBuf> = self
+                .sessions
+                .read()
+                .await
+                .values()
+                .filter(|s| s.visible_to(Some(key)))
+                .map(|s| s.sandbox_root.clone())
+                .collect();
+            let used: u64 = roots.iter().map(|root| sandbox::disk_usage(root)).sum();
+            if used >= max_mb * 1024 * 1024 {
+                return Err(format!("Org disk quota of {} MB used up", max_mb));
+            }
+        }
+        Ok(())
+    }
+
+    /// Resolve an unexpired session token to the key it acts as.
+    pub async fn lookup_session_token(&self, token: &str) -> Option<Arc<ApiKey>> {
+        let now = Instant::now();
+        self.sessions
+            .read()
+            .await
+            .values()
+            .find_map(|s| s.tokens.get(token))
+            .filter(|t| t.expires_at.is_none_or(|at| at > now))
+            .map(|t| t.key.clone())
+    }
+
+    /// Resolve a credential that isn't a configured API key: a JWT from the
+    /// OIDC issuer, or a session token.
+    pub async fn lookup_token(&self, token: &str) -> Result<Arc<ApiKey>, String> {
+        if let Some(oidc) = self.api_keys.oidc().filter(|_| oidc::is_jwt(token)) {
+            return oidc
+                .authenticate(token)
+                .await
+                .map(Arc::new)
+                .map_err(|e| format!("Invalid token: {}", e));
+        }
+        self.lookup_session_token(token)
+            .await
+            .ok_or_else(|| "Missing or invalid API key".to_string())
+    }
+
+    /// Charge a run's CPU time to the caller's org.
+    pub fn record_cpu(&self, key: Option<&ApiKey>, cpu: Duration) {
+        if let Some(key) = key {
+            self.cpu_usage.add(key.org(), cpu);
+        }
+    }
+
+    /// Capacity snapshot as seen by `key`.
+    pub async fn capacity(&self, key: Option<&ApiKey>) -> Capacity {
+        let (active_sessions, sessions_remaining, reserved) = {
+            let sessions = self.sessions.read().await;
+            (
+                sessions.len(),
+                self.sessions_remaining(&sessions, key),
+                reservation::reserved(&sessions),
+            )
+        };
+        let queue_depth = self.create_stats.queue_depth();
+        let warm_available = self.warm_pool.available().await;
+        Capacity {
+            sessions_remaining,
+            active_sessions,
+            queue_depth,
+            warm_available,
+            estimated_cold_start_ms: self.create_stats.estimate_ms(warm_available, queue_depth),
+            resources_available: Some(self.reservations.available(reserved)),
+        }
+    }
+
+    /// Register a session lifecycle hook. Call before cloning the state into servers.
+    pub fn add_lifecycle_hook(&mut self, hook: Arc<dyn SessionLifecycleHook>) {
+        Arc::make_mut(&mut self.lifecycle_hooks).push(hook);
+    }
+
+    /// Invoke every registered hook for a transition.
+    pub fn notify_lifecycle(&self, transition: LifecycleTransition, event: &SessionLifecycleEvent) {
+        for hook in self.lifecycle_hooks.iter() {
+            match transition {
+                LifecycleTransition::Created => hook.on_create(event),
+                LifecycleTransition::Expired => hook.on_expire(event),
+                LifecycleTransition::Deleted => hook.on_delete(event),
+                LifecycleTransition::Evicted => hook.on_evict(event),
+            }
+        }
+        if transition != LifecycleTransition::Created {
+            for hook in self.hooks.iter() {
+                hook.on_session_destroy(event, transition);
+            }
+        }
+        let kind = match transition {
+            LifecycleTransition::Created => ServerEventKind::SessionCreated {
+                slug: event.slug.clone(),
+                labels: event.labels.clone(),
+            },
+            LifecycleTransition::Expired => ServerEventKind::SessionExpired,
+            LifecycleTransition::Deleted => ServerEventKind::SessionDeleted,
+            LifecycleTransition::Evicted => ServerEventKind::SessionEvicted,
+        };
+        self.publish_event(Some(&event.session_id), event.org_id.as_deref(), kind);
+    }
+
+    /// Publish a server event, with the org shown as operators see it.
+    pub fn publish_event(&self, session_id: Option<&str>, org: Option<&str>, kind: ServerEventKind) {
+        if self.events.is_observed() {
+            let org = org.map(|org| self.api_keys.org_label(org));
+            self.events.publish(session_id, org.as_deref(), kind);
+        }
+    }
+
+    pub fn notify_background_exit(&self, event: &SessionLifecycleEvent, exit: &BackgroundExit) {
+        for hook in self.lifecycle_hooks.iter() {
+            hook.on_background_exit(event, exit);
+        }
+        let kind = ServerEventKind::BackgroundExited {
+            pid: exit.pid,
+            port: exit.port,
+            exit_code: exit.exit_code,
+            signal: exit.signal,
+        };
+        self.publish_event(Some(&event.session_id), event.org_id.as_deref(), kind);
+    }
+
+    pub fn notify_run_complete(&self, event: &SessionLifecycleEvent, run: &RunCompletion) {
+        for hook in self.lifecycle_hooks.iter() {
+            hook.on_run_complete(event, run);
+        }
+    }
+
+    /// Register policy, billing or scanning hooks. Call before cloning the
+    /// state into servers.
+    pub fn add_hooks(&mut self, hooks: Arc<dyn Hooks>) {
+        Arc::make_mut(&mut self.hooks).push(hooks);
+    }
+
+    /// Ask every hook whether a session may be created.
+    pub fn check_session_create(&self, key: Option<&ApiKey>, request: &CreateSessionRequest) -> Result<(), String> {
+        self.hooks.iter().try_for_each(|hook| hook.on_session_create(key, request))
+    }
+
+    /// Ask every hook whether a run may start, and publish its start if so.
+    pub fn check_run_start(&self, key: Option<&ApiKey>, run: &RunStart) -> Result<(), String> {
+        self.hooks.iter().try_for_each(|hook| hook.on_run_start(key, run))?;
+        if self.events.is_observed() {
+            let kind = ServerEventKind::RunStarted {
+                command: run.command.clone(),
+                cwd: run.cwd.clone(),
+                background: run.background,
+            };
+            self.publish_event(run.session_id.as_deref(), key.map(ApiKey::org), kind);
+        }
+        Ok(())
+    }
+
+    /// Ask every hook whether a file may be written.
+    pub fn check_file_write(&self, key: Option<&ApiKey>, write: &FileWrite) -> Result<(), String> {
+        self.hooks.iter().try_for_each(|hook| hook.on_file_write(key, write))
+    }
+
+    pub fn notify_run_finish(&self, key: Option<&ApiKey>, run: &RunFinish) {
+        for hook in self.hooks.iter() {
+            hook.on_run_finish(key, run);
+        }
+        let kind = ServerEventKind::RunFinished {
+            exit_code: run.exit_code,
+            signal: run.signal,
+            duration_ms: run.duration_ms,
+            cpu_ms: run.cpu_time.as_millis() as u64,
+        };
+        self.publish_event(run.session_id.as_deref(), key.map(ApiKey::org), kind);
+    }
+
+    /// Add a finished command to its session's history. A failure only costs
+    /// that entry.
+    pub fn record_history(&self, session_id: &str, command: history::Command) {
+        if let Err(e) = self.history.record(session_id, command) {
+            warn!("Failed to record history of session {}: {}", session_id, e);
+        }
+    }
+
+    /// Claim a preview slug for a session. Fails if it's taken.
+    pub async fn reserve_slug(&self, slug: &str, session_id: &str) -> Result<(), String> {
+        let mut slugs = self.slugs.write().await;
+        if slugs.contains_key(slug) {
+            return Err(format!("slug {:?} is already in use", slug));
+        }
+        slugs.insert(slug.to_string(), session_id.to_string());
+        Ok(())
+    }
+
+    /// Release a slug when its session goes away.
+    pub async fn release_slug(&self, slug: &str) {
+        self.slugs.write().await.remove(slug);
+    }
+
+    /// Map a preview subdomain label (slug or raw session ID) to a session ID.
+    pub async fn resolve_preview_label(&self, label: &str) -> String {
+        self.slugs
+            .read()
+            .await
+            .get(label)
+            .cloned()
+            .unwrap_or_else(|| label.to_string())
+    }
+
+    /// Assign a free port to a background process.
+    pub fn allocate_port(&self) -> Option<u16> {
+        self.ports.allocate()
+    }
+
+    /// Return a port whose process exited or whose session went away.
+    pub fn release_port(&self, port: u16) {
+        self.ports.release(port);
+    }
+}
+
+impl Default for AppState {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Builds an [`AppState`] in one expression, for services embedding the API
+/// (see [`crate::http_server::build_router_with`]). Each method stands for the
+/// `AppState` setter of the same name; [`build`](Self::build) applies the ones
+/// with ordering rules (persistence, then the warm pool) last, so they may be
+/// given in any order:
+///
+/// ```no_run
+/// # async fn example() -> Result<(), String> {
+/// use opencomputer_core::AppState;
+/// let state = AppState::builder()
+///     .preview_domain("preview.example.com")
+///     .templates_dir("/var/lib/sandbox/templates")
+///     .persistence("/var/lib/sandbox/sessions.db")
+///     .warm_pool(4)
+///     .build()?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Default)]
+pub struct AppStateBuilder {
+    state: AppState,
+    errors: Vec<String>,
+    persistence: Option<(PathBuf, Option<StoreKey>)>,
+    warm_pool: Option<usize>,
+}
+
+impl AppStateBuilder {
+    pub fn preview_domain(mut self, domain: impl Into<String>) -> Self {
+        self.state.preview_domain = Some(domain.into());
+        self
+    }
+
+    pub fn preview_region(mut self, region: &str) -> Self {
+        if let Err(e) = self.state.set_preview_region(region) {
+            self.errors.push(format!("preview region: {}", e));
+        }
+        self
+    }
+
+    pub fn preview_cookie_secret(mut self, secret: &str) -> Self {
+        self.state.set_preview_cookie_secret(secret);
+        self
+    }
+
+    pub fn api_keys(mut self, keys: ApiKeys) -> Self {
+        self.state.set_api_keys(keys);
+        self
+    }
+
+    pub fn env_policy(mut self, policy: EnvPolicy) -> Self {
+        self.state.set_env_policy(policy);
+        self
+    }
+
+    pub fn session_ttl(mut self, default: Duration, max: Duration) -> Self {
+        self.state.set_session_ttl(default, max);
+        self
+    }
+
+    pub fn cleanup_policy(mut self, policy: CleanupPolicy) -> Self {
+        self.state.set_cleanup_policy(policy);
+        self
+    }
+
+    pub fn templates_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.state.set_templates_dir(dir);
+        self
+    }
+
+    pub fn template_source_dirs(mut self, dirs: Vec<PathBuf>) -> Self {
+        self.state.set_template_source_dirs(dirs);
+        self
+    }
+
+    pub fn image_store(mut self, images: ImageStore) -> Self {
+        self.state.set_image_store(images);
+        self
+    }
+
+    pub fn package_cache_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.state.set_package_cache_dir(dir);
+        self
+    }
+
+    pub fn build_cache(mut self, dir: impl Into<PathBuf>, max_mb: u64) -> Self {
+        self.state.set_build_cache(dir, max_mb);
+        self
+    }
+
+    pub fn sandbox_backend(mut self, backend: Arc<dyn SandboxBackend>) -> Self {
+        self.state.set_sandbox_backend(backend);
+        self
+    }
+
+    pub fn run_limits(mut self, limits: RunLimits) -> Self {
+        self.state.set_run_limits(limits);
+        self
+    }
+
+    pub fn body_limits(mut self, limits: BodyLimits) -> Self {
+        self.state.set_body_limits(limits);
+        self
+    }
+
+    pub fn max_sessions(mut self, max: usize) -> Self {
+        self.state.set_max_sessions(max);
+        self
+    }
+
+    pub fn cgroups(mut self, cgroups: Cgroups) -> Self {
+        self.state.set_cgroups(cgroups);
+        self
+    }
+
+    pub fn lifecycle_hook(mut self, hook: Arc<dyn SessionLifecycleHook>) -> Self {
+        self.state.add_lifecycle_hook(hook);
+        self
+    }
+
+    pub fn hooks(mut self, hooks: Arc<dyn Hooks>) -> Self {
+        self.state.add_hooks(hooks);
+        self
+    }
+
+    pub fn key_source(mut self, source: impl Fn() -> Result<ApiKeys, String> + Send + Sync + 'static) -> Self {
+        self.state.set_key_source(source);
+        self
+    }
+
+    /// Save sessions to the SQLite database at `path`; see
+    /// [`AppState::enable_persistence`].
+    pub fn persistence(mut self, path: impl Into<PathBuf>) -> Self {
+        self.persistence = Some((path.into(), None));
+        self
+    }
+
+    /// Like [`persistence`](Self::persistence), encrypting records with `key`.
+    pub fn encrypted_persistence(mut self, path: impl Into<PathBuf>, key: StoreKey) -> Self {
+        self.persistence = Some((path.into(), Some(key)));
+        self
+    }
+
+    /// Keep `size` sandbox roots pre-created. [`build`](Self::build) must
+    /// then be called from within a Tokio runtime.
+    pub fn warm_pool(mut self, size: usize) -> Self {
+        self.warm_pool = Some(size);
+        self
+    }
+
+    /// Any other setting, e.g. `.with(|s| s.set_ssh_port(2222))`.
+    pub fn with(mut self, f: impl FnOnce(&mut AppState)) -> Self {
+        f(&mut self.state);
+        self
+    }
+
+    /// The state, or every setting that was refused.
+    pub fn build(self) -> Result<AppState, String> {
+        let mut state = self.state;
+        if !self.errors.is_empty() {
+            return Err(self.errors.join("; "));
+        }
+        if let Some((path, key)) = self.persistence {
+            state
+                .enable_persistence(&path, key)
+                .map_err(|e| format!("persistence {}: {}", path.display(), e))?;
+        }
+        if let Some(size) = self.warm_pool {
+            state.enable_warm_pool(size);
+        }
+        Ok(state)
+    }
+}
+
+/// Max labels per session.
+pub const MAX_LABELS: usize = 64;
+
+/// Labels: at most [`MAX_LABELS`]; keys 1-63 chars of letters, digits, and
+/// `-_./`; values up to 255 chars.
+pub fn validate_labels(labels: &HashMap<String, String>) -> Result<(), String> {
+    if labels.len() > MAX_LABELS {
+        return Err(format!("at most {} labels are allowed", MAX_LABELS));
+    }
+    for (key, value) in labels {
+        if key.is_empty() || key.len() > 63 {
+            return Err(format!("label key {:?} must be 1-63 characters", key));
+        }
+        if !key
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.' | '/'))
+        {
+            return Err(format!("label key {:?} may only contain letters, digits, '-', '_', '.' and '/'", key));
+        }
+        if value.len() > 255 {
+            return Err(format!("label {:?} value must be at most 255 characters", key));
+        }
+    }
+    Ok(())
+}
+
+/// Max secrets per session.
+pub const MAX_SECRETS: usize = 64;
+
+/// Secrets: at most [`MAX_SECRETS`]; names are environment variable names
+/// (1-63 chars of letters, digits and `_`, not starting with a digit);
+/// values up to 64 KiB.
+pub fn validate_secrets(secrets: &HashMap<String, String>) -> Result<(), String> {
+    if secrets.len() > MAX_SECRETS {
+        return Err(format!("at most {} secrets are allowed", MAX_SECRETS));
+    }
+    for (name, value) in secrets {
+        if name.is_empty() || name.len() > 63 {
+            return Err(format!("secret name {:?} must be 1-63 characters", name));
+        }
+        let env_name = name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+            && !name.starts_with(|c: char| c.is_ascii_digit());
+        if !env_name {
+            return Err(format!("secret name {:?} must be an environment variable name", name));
+        }
+        if value.len() > 64 * 1024 {
+            return Err(format!("secret {:?} must be at most 64 KiB", name));
+        }
+    }
+    Ok(())
+}
+
+/// Region labels must be a single DNS label.
+pub fn validate_region(region: &str) -> Result<(), String> {
+    if region.is_empty() || region.len() > 63 {
+        return Err("preview region must be 1-63 characters".to_string());
+    }
+    if !region.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-') {
+        return Err("preview region may only contain lowercase letters, digits, and '-'".to_string());
+    }
+    if region.starts_with('-') || region.ends_with('-') {
+        return Err("preview region must not start or end with '-'".to_string());
+    }
+    Ok(())
+}
+
+/// Validate a requested preview slug: a lowercase DNS label that isn't
+/// reserved and can't be mistaken for a session ID.
+pub fn validate_slug(slug: &str) -> Result<(), String> {
+    if slug.len() < 3 || slug.len() > 63 {
+        return Err("slug must be 3-63 characters".to_string());
+    }
+    if !slug.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-') {
+        return Err("slug may only contain lowercase letters, digits, and '-'".to_string());
+    }
+    if slug.starts_with('-') || slug.ends_with('-') {
+        return Err("slug must not start or end with '-'".to_string());
+    }
+    if RESERVED_SLUGS.contains(&slug) {
+        return Err(format!("slug {:?} is reserved", slug));
+    }
+    if uuid::Uuid::parse_str(slug).is_ok() {
+        return Err("slug must not look like a session ID".to_string());
+    }
+    Ok(())
+}
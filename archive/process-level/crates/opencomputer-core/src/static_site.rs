@@ -0,0 +1,115 @@
+//! Directories of built files served at a session's preview URL
+//! (`POST /sessions/:id/static`), so a built app needs no `vite preview` or
+//! other server kept alive in the sandbox.
+//!
+//! Files are opened through [`crate::safe_path`] like the file API's, so
+//! symlinks the sandbox plants can't lead out of it. A directory is served
+//! as its `index.html`, after a redirect adding the trailing slash its
+//! relative links need. With `spa` on, a page request (one that accepts
+//! `text/html`, as a browser's navigation does) for a path that matches no
+//! file gets the site's own `index.html`, for apps that route on the
+//! client; a missing script or image still gets a 404.
+
+use crate::safe_path::Root;
+use std::fs::File;
+use std::path::{Component, Path, PathBuf};
+
+pub use opencomputer_types::StaticSite;
+
+/// File a directory is served as.
+const INDEX: &str = "index.html";
+
+/// What a static site answers a request with.
+pub enum Lookup {
+    /// `file`, found at `path` (whose extension gives its type)
+    File { file: File, path: String },
+    /// A directory, asked for without the trailing slash
+    AddSlash,
+    NotFound,
+}
+
+/// `site` with its directory made absolute against `cwd` and checked to be
+/// a directory of the sandbox, and `spa` filled in.
+pub fn normalize(sandbox_root: &Path, site: StaticSite, cwd: &str) -> Result<StaticSite, String> {
+    let mut dir = PathBuf::from("/");
+    for component in Path::new(cwd).join(&site.dir).components() {
+        match component {
+            Component::Normal(name) => dir.push(name),
+            Component::ParentDir => {
+                dir.pop();
+            }
+            _ => {}
+        }
+    }
+    let entry = Root::open(sandbox_root)?
+        .metadata(&dir)
+        .map_err(|_| format!("Static site directory {:?} does not exist", site.dir))?;
+    if !entry.is_directory {
+        return Err(format!("Static site directory {:?} is not a directory", site.dir));
+    }
+    Ok(StaticSite {
+        dir: dir.to_string_lossy().to_string(),
+        spa: Some(site.spa.unwrap_or(true)),
+    })
+}
+
+/// Find what answers `path` (as sent, percent-encoded, without the query)
+/// in `site`, for a request that wants a page if `page`.
+pub fn lookup(sandbox_root: &Path, site: &StaticSite, path: &str, page: bool) -> Result<Lookup, String> {
+    let Some(relative) = decode_path(path) else {
+        return Ok(Lookup::NotFound);
+    };
+    let root = Root::open(sandbox_root)?;
+    let dir = Path::new(&site.dir);
+    let open = |path: &str| root.open_file(&dir.join(path)).ok();
+
+    // `""` and `docs/` name directories
+    let directory = relative.is_empty() || relative.ends_with('/');
+    if !directory {
+        if let Some(file) = open(&relative) {
+            return Ok(Lookup::File { file, path: relative });
+        }
+    }
+    let index = if directory {
+        format!("{}{}", relative, INDEX)
+    } else {
+        format!("{}/{}", relative, INDEX)
+    };
+    if let Some(file) = open(&index) {
+        if !directory {
+            return Ok(Lookup::AddSlash);
+        }
+        return Ok(Lookup::File { file, path: index });
+    }
+    if page && site.spa.unwrap_or(true) {
+        if let Some(file) = open(INDEX) {
+            return Ok(Lookup::File {
+                file,
+                path: INDEX.to_string(),
+            });
+        }
+    }
+    Ok(Lookup::NotFound)
+}
+
+/// `path` percent-decoded and without its leading slash, if it names
+/// something inside the site: no `..` or `.` segments, NUL bytes, or
+/// invalid UTF-8.
+fn decode_path(path: &str) -> Option<String> {
+    let bytes = path.trim_start_matches('/').as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = bytes.get(i + 1..i + 3).filter(|hex| hex.iter().all(u8::is_ascii_hexdigit))?;
+            decoded.push(u8::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok()?);
+            i += 3;
+        } else {
+            decoded.push(bytes[i]);
+            i += 1;
+        }
+    }
+    let decoded = String::from_utf8(decoded).ok()?;
+    let escapes = decoded.split('/').any(|segment| segment == ".." || segment == ".");
+    (!escapes && !decoded.contains('\0') && !decoded.starts_with('/')).then_some(decoded)
+}
@@ -0,0 +1,247 @@
+//! Per-session resource stats (`GET /sessions/:id/stats` and the `stats` of
+//! session info).
+//!
+//! A background task reads `/proc` every [`SAMPLE_INTERVAL`], attributing
+//! processes to sessions by their root directory (as in
+//! [`crate::sandbox::session_pids`]). A process's CPU time and IO counters
+//! include the children it has reaped, so a session's totals are what its
+//! live processes report plus the last reading of each process tree that
+//! has exited: the top of a tree (a run, a background process, a kernel) is
+//! reaped by the server, not by anything in the session. What a tree does
+//! after its last sample is missed, so a run shorter than an interval may
+//! not count at all. Peak RSS is the highest sampled sum.
+//!
+//! Each sample is also broadcast, for `GET /sessions/:id/stats/stream`.
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fs;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::sync::broadcast;
+
+pub use opencomputer_types::{SessionStats, SessionStatsHistory, StatsSample};
+
+/// Time between samples.
+pub const SAMPLE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Samples kept per session: five minutes' worth.
+pub const WINDOW: usize = 300;
+
+/// Samples buffered per stream subscriber before slow ones start missing
+/// some.
+const CHANNEL_CAPACITY: usize = 4096;
+
+/// A session's new sample, or `None` once the session is gone.
+pub type StatsUpdate = (Arc<str>, Option<StatsSample>);
+
+/// Trackers by session ID.
+pub struct StatsCollector {
+    by_session: Mutex<HashMap<String, Tracker>>,
+    tx: broadcast::Sender<StatsUpdate>,
+}
+
+impl Default for StatsCollector {
+    fn default() -> Self {
+        let (tx, _) = broadcast::channel(CHANNEL_CAPACITY);
+        Self {
+            by_session: Mutex::default(),
+            tx,
+        }
+    }
+}
+
+#[derive(Default)]
+struct Tracker {
+    /// Last reading of each live process, by PID and start time
+    live: HashMap<(u32, u64), Reading>,
+    /// Last readings of process trees that have exited
+    exited: Counters,
+    latest: SessionStats,
+    sampled_at: Option<Instant>,
+    samples: VecDeque<StatsSample>,
+}
+
+/// Cumulative counters of a process and the children it reaped.
+#[derive(Debug, Clone, Copy, Default)]
+struct Counters {
+    ticks: u64,
+    read_bytes: u64,
+    write_bytes: u64,
+}
+
+impl std::ops::AddAssign for Counters {
+    fn add_assign(&mut self, other: Self) {
+        self.ticks += other.ticks;
+        self.read_bytes += other.read_bytes;
+        self.write_bytes += other.write_bytes;
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Reading {
+    ppid: u32,
+    counters: Counters,
+    rss_bytes: u64,
+}
+
+impl StatsCollector {
+    /// Read every session's processes, in one pass over `/proc`. Sessions
+    /// missing from `sessions` (ID and sandbox root) are forgotten.
+    pub fn sample(&self, sessions: &[(String, PathBuf)]) {
+        let (tick_hz, page_size) = unsafe {
+            (
+                libc::sysconf(libc::_SC_CLK_TCK).max(1) as f64,
+                libc::sysconf(libc::_SC_PAGESIZE).max(0) as u64,
+            )
+        };
+        let roots: Vec<PathBuf> = sessions.iter().map(|(_, root)| root.clone()).collect();
+        let mut by_root = read_processes(&roots, page_size);
+        let now = Instant::now();
+        let at_ms = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_millis() as u64)
+            .unwrap_or(0);
+
+        let mut trackers = self.by_session.lock().unwrap_or_else(|e| e.into_inner());
+        trackers.retain(|id, _| {
+            let live = sessions.iter().any(|(live, _)| live == id);
+            if !live {
+                let _ = self.tx.send((id.as_str().into(), None));
+            }
+            live
+        });
+        for (id, root) in sessions {
+            let tracker = trackers.entry(id.clone()).or_default();
+            let processes = by_root.remove(root).unwrap_or_default();
+            tracker.update(processes, now, tick_hz);
+            let sample = StatsSample {
+                at_ms,
+                stats: tracker.latest,
+            };
+            tracker.samples.push_back(sample.clone());
+            while tracker.samples.len() > WINDOW {
+                tracker.samples.pop_front();
+            }
+            let _ = self.tx.send((id.as_str().into(), Some(sample)));
+        }
+    }
+
+    /// Updates of every session as they're sampled, and the latest sample of
+    /// `session_id` if it has one. Subscribe before checking the session
+    /// exists, so its end can't be missed.
+    pub fn subscribe(&self, session_id: &str) -> (Option<StatsSample>, broadcast::Receiver<StatsUpdate>) {
+        let trackers = self.by_session.lock().unwrap_or_else(|e| e.into_inner());
+        let latest = trackers.get(session_id).and_then(|t| t.samples.back().cloned());
+        (latest, self.tx.subscribe())
+    }
+
+    /// Latest figures of a session (zero before its first sample).
+    pub fn get(&self, session_id: &str) -> SessionStats {
+        let trackers = self.by_session.lock().unwrap_or_else(|e| e.into_inner());
+        trackers.get(session_id).map(|t| t.latest).unwrap_or_default()
+    }
+
+    pub fn history(&self, session_id: &str) -> SessionStatsHistory {
+        let trackers = self.by_session.lock().unwrap_or_else(|e| e.into_inner());
+        let tracker = trackers.get(session_id);
+        SessionStatsHistory {
+            stats: tracker.map(|t| t.latest).unwrap_or_default(),
+            interval_ms: SAMPLE_INTERVAL.as_millis() as u64,
+            samples: tracker.map(|t| t.samples.iter().cloned().collect()).unwrap_or_default(),
+        }
+    }
+
+    /// Drop a session's figures once the session is gone, ending its streams.
+    pub fn forget(&self, session_id: &str) {
+        self.by_session.lock().unwrap_or_else(|e| e.into_inner()).remove(session_id);
+        let _ = self.tx.send((session_id.into(), None));
+    }
+}
+
+impl Tracker {
+    fn update(&mut self, processes: HashMap<(u32, u64), Reading>, now: Instant, tick_hz: f64) {
+        // A process that went away while its parent lives on was reaped into
+        // the parent's counters; otherwise keep its last reading
+        let pids: HashSet<u32> = processes.keys().map(|&(pid, _)| pid).collect();
+        for (key, reading) in &self.live {
+            if !processes.contains_key(key) && !pids.contains(&reading.ppid) {
+                self.exited += reading.counters;
+            }
+        }
+        self.live = processes;
+
+        let mut total = self.exited;
+        for reading in self.live.values() {
+            total += reading.counters;
+        }
+        let previous = self.latest;
+        // Counters only grow; a reaped process can briefly be in neither place
+        let cpu_secs = (total.ticks as f64 / tick_hz).max(previous.cpu_secs);
+        let elapsed = self.sampled_at.map(|t| now.duration_since(t).as_secs_f64()).unwrap_or(0.0);
+        let rss_bytes: u64 = self.live.values().map(|r| r.rss_bytes).sum();
+        self.latest = SessionStats {
+            cpu_secs,
+            cpu_percent: if elapsed > 0.0 { (cpu_secs - previous.cpu_secs) / elapsed * 100.0 } else { 0.0 },
+            rss_bytes,
+            peak_rss_bytes: previous.peak_rss_bytes.max(rss_bytes),
+            processes: self.live.len() as u32,
+            read_bytes: total.read_bytes.max(previous.read_bytes),
+            write_bytes: total.write_bytes.max(previous.write_bytes),
+        };
+        self.sampled_at = Some(now);
+    }
+}
+
+/// Readings of every process whose root is one of `roots`, keyed by root
+/// then PID and start time (so a reused PID is a new process).
+fn read_processes(roots: &[PathBuf], page_size: u64) -> HashMap<PathBuf, HashMap<(u32, u64), Reading>> {
+    let mut by_root: HashMap<PathBuf, HashMap<(u32, u64), Reading>> = HashMap::new();
+    let Ok(entries) = fs::read_dir("/proc") else {
+        return by_root;
+    };
+    let process_roots = crate::sandbox::ProcessRoots::new(roots);
+    for pid in entries
+        .flatten()
+        .filter_map(|e| e.file_name().to_str().and_then(|n| n.parse::<u32>().ok()))
+    {
+        let Some(root) = process_roots.of(pid) else {
+            continue;
+        };
+        if let Some((started, reading)) = read_process(pid, page_size) {
+            by_root.entry(root.clone()).or_default().insert((pid, started), reading);
+        }
+    }
+    by_root
+}
+
+/// A process's start time (in ticks since boot) and reading.
+fn read_process(pid: u32, page_size: u64) -> Option<(u64, Reading)> {
+    let stat = fs::read_to_string(format!("/proc/{}/stat", pid)).ok()?;
+    // The command name may contain spaces; fields resume after its ')'
+    let fields: Vec<&str> = stat.get(stat.rfind(')')? + 2..)?.split_whitespace().collect();
+    let field = |n: usize| fields.get(n - 3).and_then(|f| f.parse::<u64>().ok());
+    // ppid is field 4 of the full line, utime to cstime 14-17, starttime 22
+    let ppid = field(4)? as u32;
+    let ticks = field(14)? + field(15)? + field(16)? + field(17)?;
+    let started = field(22)?;
+    let statm = fs::read_to_string(format!("/proc/{}/statm", pid)).ok()?;
+    let resident: u64 = statm.split_whitespace().nth(1)?.parse().ok()?;
+    // rchar and wchar count every read and write, tmpfs and pipes included
+    let io = fs::read_to_string(format!("/proc/{}/io", pid)).unwrap_or_default();
+    let io_field = |name: &str| -> u64 {
+        io.lines()
+            .find_map(|l| l.strip_prefix(name))
+            .and_then(|v| v.trim().parse().ok())
+            .unwrap_or(0)
+    };
+    let reading = Reading {
+        ppid,
+        counters: Counters {
+            ticks,
+            read_bytes: io_field("rchar:"),
+            write_bytes: io_field("wchar:"),
+        },
+        rss_bytes: resident * page_size,
+    };
+    Some((started, reading))
+}
@@ -0,0 +1,138 @@
+//! Encryption of stored session records.
+//!
+//! Records carry session env, secrets, session tokens and preview
+//! credentials. With a key configured, [`SessionStore`](crate::persistence::SessionStore)
+//! seals each record with AES-256-GCM, bound to its session ID, before it
+//! reaches the database. The key is 32 bytes, hex or base64, read at startup
+//! from a file or from what a command prints (e.g. a KMS decrypt call), and
+//! never written anywhere.
+//!
+//! A sealed record is `enc:v1:{key id}:{base64 of nonce and ciphertext}`. The
+//! key ID, the first 8 hex digits of the key's SHA-256, tells a wrong key from
+//! a damaged record. Plaintext records written before a key was configured
+//! are still read; they are sealed when rewritten at startup.
+
+use crate::secrets;
+use base64::{engine::general_purpose::STANDARD as B64, Engine};
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM, NONCE_LEN};
+use ring::rand::{SecureRandom, SystemRandom};
+use sha2::{Digest, Sha256};
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+use tracing::warn;
+
+const SEALED_PREFIX: &str = "enc:v1:";
+
+/// Key for sealing session records.
+pub struct StoreKey {
+    key: LessSafeKey,
+    id: String,
+}
+
+impl StoreKey {
+    /// Parse a key given as 64 hex digits or as base64.
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let text = text.trim();
+        let decoded = if text.len() == 64 && text.bytes().all(|b| b.is_ascii_hexdigit()) {
+            hex::decode(text).ok()
+        } else {
+            B64.decode(text).ok()
+        };
+        let Some(mut bytes) = decoded.filter(|b| b.len() == 32) else {
+            return Err("key must be 32 bytes, as 64 hex digits or base64".to_string());
+        };
+        let id = hex::encode(&Sha256::digest(&bytes)[..4]);
+        let key = UnboundKey::new(&AES_256_GCM, &bytes).map_err(|_| "invalid key".to_string());
+        secrets::wipe_bytes(&mut bytes);
+        Ok(Self {
+            key: LessSafeKey::new(key?),
+            id,
+        })
+    }
+
+    /// Read the key from a file, which only its owner should be able to read.
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let mut text = std::fs::read_to_string(path).map_err(|e| format!("read {}: {}", path.display(), e))?;
+        let key = Self::parse(&text).map_err(|e| format!("{}: {}", path.display(), e));
+        secrets::wipe(&mut text);
+        if std::fs::metadata(path).is_ok_and(|m| m.permissions().mode() & 0o077 != 0) {
+            warn!("State key {} is readable by other users", path.display());
+        }
+        key
+    }
+
+    /// Run `command` with `sh -c` and read the key from its output.
+    pub fn from_command(command: &str) -> Result<Self, String> {
+        let output = std::process::Command::new("sh")
+            .arg("-c")
+            .arg(command)
+            .stderr(std::process::Stdio::inherit())
+            .output()
+            .map_err(|e| format!("run {:?}: {}", command, e))?;
+        let mut stdout = output.stdout;
+        let key = if output.status.success() {
+            Self::parse(&String::from_utf8_lossy(&stdout))
+        } else {
+            Err(format!("{:?} failed: {}", command, output.status))
+        };
+        secrets::wipe_bytes(&mut stdout);
+        key
+    }
+
+    /// Short fingerprint of the key, safe to log.
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    /// Encrypt `plaintext`, bound to `context` (the session ID).
+    pub fn seal(&self, context: &str, plaintext: &[u8]) -> Result<String, String> {
+        let mut nonce = [0u8; NONCE_LEN];
+        SystemRandom::new()
+            .fill(&mut nonce)
+            .map_err(|_| "no randomness for a nonce".to_string())?;
+        let mut data = plaintext.to_vec();
+        self.key
+            .seal_in_place_append_tag(
+                Nonce::assume_unique_for_key(nonce),
+                Aad::from(context.as_bytes()),
+                &mut data,
+            )
+            .map_err(|_| "encryption failed".to_string())?;
+        let mut sealed = nonce.to_vec();
+        sealed.extend_from_slice(&data);
+        Ok(format!("{}{}:{}", SEALED_PREFIX, self.id, B64.encode(sealed)))
+    }
+
+    /// Decrypt what [`seal`](Self::seal) produced for the same `context`.
+    /// Callers should [`wipe`](secrets::wipe_bytes) the result once used.
+    pub fn open(&self, context: &str, sealed: &str) -> Result<Vec<u8>, String> {
+        let id = sealed_key_id(sealed).ok_or_else(|| "not sealed".to_string())?;
+        if id != self.id {
+            return Err(format!("sealed with key {}, not the configured key {}", id, self.id));
+        }
+        let encoded = &sealed[SEALED_PREFIX.len() + id.len() + 1..];
+        let mut data = B64.decode(encoded).map_err(|_| "damaged record".to_string())?;
+        if data.len() < NONCE_LEN {
+            return Err("damaged record".to_string());
+        }
+        let mut ciphertext = data.split_off(NONCE_LEN);
+        let nonce = Nonce::try_assume_unique_for_key(&data).map_err(|_| "damaged record".to_string())?;
+        let len = self
+            .key
+            .open_in_place(nonce, Aad::from(context.as_bytes()), &mut ciphertext)
+            .map_err(|_| "damaged record".to_string())?
+            .len();
+        ciphertext.truncate(len);
+        Ok(ciphertext)
+    }
+}
+
+/// Whether a stored value was sealed, whatever the key.
+pub fn is_sealed(text: &str) -> bool {
+    text.starts_with(SEALED_PREFIX)
+}
+
+/// ID of the key a sealed value needs.
+pub fn sealed_key_id(text: &str) -> Option<&str> {
+    text.strip_prefix(SEALED_PREFIX)?.split_once(':').map(|(id, _)| id)
+}
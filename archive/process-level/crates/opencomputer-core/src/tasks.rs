@@ -0,0 +1,71 @@
+//! Named commands of a session (`dev`, `test`, `build`), registered with
+//! `POST /sessions/:id/tasks` and run with `POST /sessions/:id/tasks/:name/run`.
+//!
+//! A task is only a stored request: running it builds the `/run` or
+//! `/background` request it stands for, which then goes through the same
+//! checks, limits and env merging as one sent directly.
+
+use opencomputer_types::{BackgroundRunRequest, RunRequest};
+
+pub use opencomputer_types::{RunTaskRequest, SetTasksRequest, TaskDefinition, TaskInfo, TaskRunResponse};
+
+/// Most tasks a session may have.
+pub const MAX_TASKS: usize = 64;
+
+/// What a task run turns into.
+pub enum TaskRun {
+    Foreground(RunRequest),
+    Background(BackgroundRunRequest),
+}
+
+/// Names are what a UI shows on a button and goes in the URL, so they're
+/// short and URL-safe.
+pub fn validate_name(name: &str) -> Result<(), String> {
+    let valid = !name.is_empty()
+        && name.len() <= 64
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.' | ':'));
+    if valid {
+        Ok(())
+    } else {
+        Err(format!(
+            "Invalid task name {:?}: use 1-64 letters, digits, '-', '_', '.' or ':'",
+            name
+        ))
+    }
+}
+
+pub fn validate(name: &str, task: &TaskDefinition) -> Result<(), String> {
+    validate_name(name)?;
+    if task.command.is_empty() {
+        return Err(format!("Task {:?} has no command", name));
+    }
+    if task.port.is_some() && !task.background {
+        return Err(format!("Task {:?} has a port but doesn't run in the background", name));
+    }
+    Ok(())
+}
+
+/// The request running `task` with `req` stands for: the run's args go after
+/// the task's command and its env on top of the task's.
+pub fn request(task: &TaskDefinition, req: RunTaskRequest) -> TaskRun {
+    let mut command = task.command.clone();
+    command.extend(req.args);
+    let mut env = task.env.clone();
+    env.extend(req.env);
+    if req.background.unwrap_or(task.background) {
+        TaskRun::Background(BackgroundRunRequest {
+            command,
+            port: task.port,
+            env,
+            unset_env: Vec::new(),
+            cwd: task.cwd.clone(),
+        })
+    } else {
+        TaskRun::Foreground(RunRequest {
+            command,
+            env,
+            cwd: task.cwd.clone(),
+            ..Default::default()
+        })
+    }
+}
@@ -0,0 +1,300 @@
+//! Template registry: named base filesystems new sandboxes are layered on.
+//!
+//! Each template is a directory under the registry dir (`{dir}/{name}`),
+//! registered from an uploaded tarball or from a directory on the server,
+//! which must be inside one of the source dirs the operator allows.
+//! `blank` is built in and adds nothing to the sandbox.
+//!
+//! A session created from a template mounts its directory as a read-only
+//! lower layer instead of copying it. Replacing or removing a template
+//! therefore renames the old directory to `.retired-{uuid}`; the next
+//! replace or remove deletes retired directories no sandbox has mounted.
+
+use crate::progress::{Progress, ProgressReader};
+use crate::sandbox;
+use std::fs;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::sync::RwLock;
+
+pub use opencomputer_types::TemplateInfo;
+
+pub const DEFAULT_TEMPLATES_DIR: &str = "/var/lib/opensandbox/templates";
+
+/// Built-in empty template.
+pub const BLANK_TEMPLATE: &str = "blank";
+
+pub struct TemplateRegistry {
+    dir: PathBuf,
+    /// Server directories templates may be registered from (none = only
+    /// from tarballs)
+    source_dirs: Vec<PathBuf>,
+    /// Held for reading while a sandbox is layered on a template, and for
+    /// writing while one is installed or removed.
+    lock: RwLock<()>,
+}
+
+impl TemplateRegistry {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self {
+            dir: dir.into(),
+            source_dirs: Vec::new(),
+            lock: RwLock::new(()),
+        }
+    }
+
+    /// Allow registering templates from directories inside `dirs`.
+    pub fn with_source_dirs(mut self, dirs: Vec<PathBuf>) -> Self {
+        self.source_dirs = dirs;
+        self
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    pub fn source_dirs(&self) -> &[PathBuf] {
+        &self.source_dirs
+    }
+
+    /// Resolve `source` for [`Self::register_from_dir`], refusing anything
+    /// outside the allowed source dirs, symlinks included.
+    pub fn check_source(&self, source: &Path) -> Result<PathBuf, String> {
+        if self.source_dirs.is_empty() {
+            return Err("templates can't be registered from server directories here; upload a tarball".to_string());
+        }
+        let resolved = source
+            .canonicalize()
+            .map_err(|e| format!("{}: {}", source.display(), e))?;
+        let allowed = self
+            .source_dirs
+            .iter()
+            .filter_map(|dir| dir.canonicalize().ok())
+            .any(|dir| resolved.starts_with(dir));
+        if !allowed {
+            return Err(format!("{} is not in a template source directory", source.display()));
+        }
+        Ok(resolved)
+    }
+
+    pub fn exists(&self, name: &str) -> bool {
+        name == BLANK_TEMPLATE || (validate_template_name(name).is_ok() && self.path(name).is_dir())
+    }
+
+    pub fn list(&self) -> Result<Vec<TemplateInfo>, String> {
+        let _guard = self.lock.read().unwrap();
+        let mut result = vec![TemplateInfo {
+            name: BLANK_TEMPLATE.to_string(),
+            files: 0,
+            size_bytes: 0,
+        }];
+        let entries = match fs::read_dir(&self.dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(result),
+            Err(e) => return Err(format!("read {}: {}", self.dir.display(), e)),
+        };
+        for entry in entries.flatten() {
+            let name = entry.file_name().to_string_lossy().to_string();
+            // Skips staging dirs and anything else that isn't a template
+            if validate_template_name(&name).is_err() || !entry.path().is_dir() {
+                continue;
+            }
+            result.push(template_info(&name, &entry.path()));
+        }
+        result.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(result)
+    }
+
+    /// Register (or replace) a template by copying a directory on the server,
+    /// one [`Self::check_source`] allowed.
+    pub fn register_from_dir(
+        &self,
+        name: &str,
+        source: &Path,
+        mut progress: Progress,
+    ) -> Result<TemplateInfo, String> {
+        validate_registrable(name)?;
+        if !source.is_dir() {
+            return Err(format!("{} is not a directory", source.display()));
+        }
+        let (files, bytes) = tree_size(source);
+        progress.begin_stage("copy", Some(bytes), Some(files));
+        let result = self.staging_dir().and_then(|staging| {
+            let result = sandbox::copy_tree(source, &staging, &mut progress)
+                .and_then(|_| self.install(name, &staging));
+            let _ = fs::remove_dir_all(&staging);
+            result
+        });
+        progress.finish(&result);
+        result
+    }
+
+    /// Register (or replace) a template from a tar archive, optionally
+    /// gzipped. Progress counts archive bytes consumed.
+    pub fn register_from_tarball(
+        &self,
+        name: &str,
+        data: &[u8],
+        mut progress: Progress,
+    ) -> Result<TemplateInfo, String> {
+        validate_registrable(name)?;
+        progress.begin_stage("extract", Some(data.len() as u64), None);
+        let result = self.staging_dir().and_then(|staging| {
+            let result = unpack_tarball(ProgressReader::new(data, &mut progress), &staging)
+                .and_then(|_| self.install(name, &staging));
+            let _ = fs::remove_dir_all(&staging);
+            result
+        });
+        progress.finish(&result);
+        result
+    }
+
+    /// Remove a template. Returns `false` if it did not exist.
+    pub fn remove(&self, name: &str) -> Result<bool, String> {
+        validate_registrable(name)?;
+        let _guard = self.lock.write().unwrap();
+        let path = self.path(name);
+        if !path.is_dir() {
+            return Ok(false);
+        }
+        self.retire(&path)?;
+        Ok(true)
+    }
+
+    /// Call `f` with the directory of a template's files (`None` for
+    /// `blank`), which stays in place until `f` returns. Templates
+    /// registered before they were shared with sandbox users are shared
+    /// first.
+    pub fn with_layer<T>(
+        &self,
+        name: Option<&str>,
+        f: impl FnOnce(Option<&Path>) -> Result<T, String>,
+    ) -> Result<T, String> {
+        let Some(name) = name.filter(|n| *n != BLANK_TEMPLATE) else {
+            return f(None);
+        };
+        validate_template_name(name)?;
+        let path = self.path(name);
+        if path.is_dir() && !is_shared(&path) {
+            let _guard = self.lock.write().unwrap();
+            sandbox::share_with_sandboxes(&path)?;
+        }
+        let _guard = self.lock.read().unwrap();
+        if !path.is_dir() {
+            return Err(format!("template {:?} not found", name));
+        }
+        f(Some(&path))
+    }
+
+    fn path(&self, name: &str) -> PathBuf {
+        self.dir.join(name)
+    }
+
+    fn staging_dir(&self) -> Result<PathBuf, String> {
+        let staging = self.dir.join(format!(".staging-{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(&staging).map_err(|e| format!("mkdir {}: {}", staging.display(), e))?;
+        Ok(staging)
+    }
+
+    /// Swap a fully populated staging dir into place.
+    fn install(&self, name: &str, staging: &Path) -> Result<TemplateInfo, String> {
+        sandbox::share_with_sandboxes(staging)?;
+        let _guard = self.lock.write().unwrap();
+        let path = self.path(name);
+        if path.exists() {
+            self.retire(&path)?;
+        }
+        fs::rename(staging, &path).map_err(|e| format!("install {}: {}", path.display(), e))?;
+        Ok(template_info(name, &path))
+    }
+
+    /// Move a template out of the way, for sandboxes still layered on it,
+    /// and delete retired templates no sandbox uses any more.
+    fn retire(&self, path: &Path) -> Result<(), String> {
+        let retired = self.dir.join(format!(".retired-{}", uuid::Uuid::new_v4()));
+        fs::rename(path, &retired).map_err(|e| format!("remove {}: {}", path.display(), e))?;
+        // A mount's root in mountinfo follows renames of its source
+        let mounts = fs::read_to_string("/proc/self/mountinfo").unwrap_or_default();
+        for entry in fs::read_dir(&self.dir).into_iter().flatten().flatten() {
+            let name = entry.file_name().to_string_lossy().to_string();
+            if name.starts_with(".retired-") && !mounts.contains(&name) {
+                let _ = fs::remove_dir_all(entry.path());
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Whether a template's files were shared with sandbox users.
+fn is_shared(path: &Path) -> bool {
+    use std::os::unix::fs::MetadataExt;
+    fs::metadata(path).is_ok_and(|m| m.gid() == sandbox::SANDBOX_SHARED_GID)
+}
+
+/// Template names: 1-63 lowercase letters, digits, '-', '_' or '.', not starting with '.'.
+pub fn validate_template_name(name: &str) -> Result<(), String> {
+    if name.is_empty() || name.len() > 63 {
+        return Err("template name must be 1-63 characters".to_string());
+    }
+    if !name
+        .chars()
+        .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || matches!(c, '-' | '_' | '.'))
+    {
+        return Err("template name may only contain lowercase letters, digits, '-', '_' and '.'".to_string());
+    }
+    if name.starts_with('.') {
+        return Err("template name must not start with '.'".to_string());
+    }
+    Ok(())
+}
+
+fn validate_registrable(name: &str) -> Result<(), String> {
+    validate_template_name(name)?;
+    if name == BLANK_TEMPLATE {
+        return Err(format!("template {:?} is built in", BLANK_TEMPLATE));
+    }
+    Ok(())
+}
+
+/// Unpack a tar archive, gunzipping it first if it starts with the gzip magic.
+pub(crate) fn unpack_tarball(data: ProgressReader<'_, &[u8]>, dest: &Path) -> Result<(), String> {
+    let reader: Box<dyn Read + '_> = if data.get_ref().starts_with(&[0x1f, 0x8b]) {
+        Box::new(flate2::read::GzDecoder::new(data))
+    } else {
+        Box::new(data)
+    };
+    let mut archive = tar::Archive::new(reader);
+    archive.set_preserve_permissions(true);
+    // `unpack` refuses entries that would escape `dest` (absolute paths, `..`)
+    archive.unpack(dest).map_err(|e| format!("unpack tarball: {}", e))
+}
+
+fn template_info(name: &str, path: &Path) -> TemplateInfo {
+    let (files, size_bytes) = tree_size(path);
+    TemplateInfo {
+        name: name.to_string(),
+        files,
+        size_bytes,
+    }
+}
+
+fn tree_size(path: &Path) -> (u64, u64) {
+    let mut files = 0;
+    let mut bytes = 0;
+    if let Ok(entries) = fs::read_dir(path) {
+        for entry in entries.flatten() {
+            let Ok(meta) = entry.path().symlink_metadata() else {
+                continue;
+            };
+            if meta.is_dir() {
+                let (f, b) = tree_size(&entry.path());
+                files += f;
+                bytes += b;
+            } else {
+                files += 1;
+                bytes += meta.len();
+            }
+        }
+    }
+    (files, bytes)
+}
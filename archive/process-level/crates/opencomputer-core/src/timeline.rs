@@ -0,0 +1,162 @@
+//! Per-session event timeline (`GET /sessions/:id/events` and `/stream`).
+//!
+//! Handlers record what happens to a session (creation, runs, background
+//! processes and their ports, pauses, TTL warnings) as it happens. The last
+//! [`CAPACITY`] events of each session are kept, numbered so a client can
+//! ask for what it hasn't seen, and broadcast to streams. A timeline ends
+//! with its session: the `expired` or `deleted` event is recorded, ends the
+//! session's streams, and the timeline is dropped.
+
+use crate::lifecycle::{BackgroundExit, RunCompletion};
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::broadcast;
+
+pub use opencomputer_types::{SessionEvent, SessionEventKind, SessionEventsQuery};
+
+/// Events kept per session.
+pub const CAPACITY: usize = 256;
+
+/// How long before expiry a session gets a `ttl_warning` (half its TTL if
+/// that is shorter).
+pub const TTL_WARNING: Duration = Duration::from_secs(60);
+
+/// Events buffered per stream subscriber before slow ones start missing
+/// some.
+const CHANNEL_CAPACITY: usize = 1024;
+
+/// An event of the session with the given ID, as broadcast to streams.
+pub type TimelineUpdate = (Arc<str>, SessionEvent);
+
+/// Timelines by session ID.
+pub struct Timeline {
+    by_session: Mutex<HashMap<String, Ring>>,
+    tx: broadcast::Sender<TimelineUpdate>,
+}
+
+#[derive(Default)]
+struct Ring {
+    next_seq: u64,
+    events: VecDeque<SessionEvent>,
+    /// Whether the current approach to expiry has been warned about
+    ttl_warned: bool,
+}
+
+impl Default for Timeline {
+    fn default() -> Self {
+        let (tx, _) = broadcast::channel(CHANNEL_CAPACITY);
+        Self {
+            by_session: Mutex::default(),
+            tx,
+        }
+    }
+}
+
+impl Timeline {
+    /// Start a session's timeline, e.g. for a session restored at startup.
+    /// `created` events start one too.
+    pub fn open(&self, session_id: &str) {
+        self.lock().entry(session_id.to_string()).or_default();
+    }
+
+    /// Append an event to a session's timeline. Ignored for sessions without
+    /// one (already gone); ending events drop the timeline.
+    pub fn record(&self, session_id: &str, kind: SessionEventKind) {
+        let mut rings = self.lock();
+        let ends = matches!(kind, SessionEventKind::Expired | SessionEventKind::Deleted | SessionEventKind::Evicted);
+        let ring = match kind {
+            SessionEventKind::Created { .. } => rings.entry(session_id.to_string()).or_default(),
+            _ => match rings.get_mut(session_id) {
+                Some(ring) => ring,
+                None => return,
+            },
+        };
+        let event = SessionEvent {
+            seq: ring.next_seq,
+            at_ms: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_millis() as u64)
+                .unwrap_or(0),
+            kind,
+        };
+        ring.next_seq += 1;
+        ring.events.push_back(event.clone());
+        while ring.events.len() > CAPACITY {
+            ring.events.pop_front();
+        }
+        // Sent under the lock so subscribers see each event exactly once
+        let _ = self.tx.send((session_id.into(), event));
+        if ends {
+            rings.remove(session_id);
+        }
+    }
+
+    /// Kept events of a session after `after`, oldest first; `None` if the
+    /// session has no timeline.
+    pub fn events(&self, session_id: &str, after: Option<u64>) -> Option<Vec<SessionEvent>> {
+        let rings = self.lock();
+        let ring = rings.get(session_id)?;
+        Some(ring.events.iter().filter(|e| after.is_none_or(|a| e.seq > a)).cloned().collect())
+    }
+
+    /// [`Timeline::events`] and a receiver of every event recorded after
+    /// them, for any session.
+    pub fn subscribe(
+        &self,
+        session_id: &str,
+        after: Option<u64>,
+    ) -> Option<(Vec<SessionEvent>, broadcast::Receiver<TimelineUpdate>)> {
+        let rings = self.lock();
+        let ring = rings.get(session_id)?;
+        let backlog = ring.events.iter().filter(|e| after.is_none_or(|a| e.seq > a)).cloned().collect();
+        Some((backlog, self.tx.subscribe()))
+    }
+
+    /// Record a `ttl_warning` the first time a session comes within
+    /// [`TTL_WARNING`] (or half its TTL) of expiring; it can warn again once
+    /// it has been kept alive.
+    pub fn check_ttl(&self, session_id: &str, ttl: Duration, expires_in: Duration) {
+        let near = expires_in <= TTL_WARNING.min(ttl / 2);
+        let warn = {
+            let mut rings = self.lock();
+            let Some(ring) = rings.get_mut(session_id) else {
+                return;
+            };
+            let warn = near && !ring.ttl_warned;
+            ring.ttl_warned = near;
+            warn
+        };
+        if warn {
+            let expires_in_secs = expires_in.as_secs();
+            self.record(session_id, SessionEventKind::TtlWarning { expires_in_secs });
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<String, Ring>> {
+        self.by_session.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+pub fn run_finished(run: &RunCompletion) -> SessionEventKind {
+    SessionEventKind::RunFinished {
+        command: run.command.clone(),
+        exit_code: run.exit_code,
+        signal: run.signal,
+        duration_ms: run.duration_ms,
+    }
+}
+
+/// `process_exited` for a clean exit, `process_crashed` otherwise.
+pub fn background_exit(exit: &BackgroundExit) -> SessionEventKind {
+    let (pid, port) = (exit.pid, exit.port);
+    match (exit.exit_code, exit.signal) {
+        (Some(0), _) => SessionEventKind::ProcessExited { pid, port },
+        (exit_code, signal) => SessionEventKind::ProcessCrashed {
+            pid,
+            port,
+            exit_code,
+            signal,
+        },
+    }
+}
@@ -0,0 +1,221 @@
+//! TLS for the HTTP and gRPC listeners.
+//!
+//! Both listeners resolve their certificate through one [`CertStore`], so a
+//! renewed certificate (issued by [`crate::acme`], or rewritten on disk)
+//! applies to new connections without a restart.
+
+use rustls::crypto::ring::sign::any_supported_type;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::server::{ClientHello, ResolvesServerCert};
+use rustls::sign::CertifiedKey;
+use rustls::ServerConfig;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::{Arc, RwLock};
+use std::task::{Context, Poll};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::{TcpListener, TcpStream};
+use tonic::transport::server::{Connected, TcpConnectInfo};
+use tracing::{info, warn};
+
+/// How often certificate files are checked for changes.
+const FILE_RELOAD_INTERVAL: Duration = Duration::from_secs(60);
+
+/// The certificate currently served, replaceable at runtime.
+#[derive(Debug)]
+pub struct CertStore {
+    current: RwLock<Cert>,
+}
+
+#[derive(Debug)]
+struct Cert {
+    key: Arc<CertifiedKey>,
+    not_after: Option<SystemTime>,
+    names: Vec<String>,
+}
+
+impl CertStore {
+    /// Build from a PEM certificate chain (leaf first) and PEM private key.
+    pub fn from_pem(cert_pem: &[u8], key_pem: &[u8]) -> Result<Self, String> {
+        Ok(Self {
+            current: RwLock::new(parse_pem(cert_pem, key_pem)?),
+        })
+    }
+
+    pub fn load(cert: &Path, key: &Path) -> Result<Self, String> {
+        let (cert_pem, key_pem) = read_pair(cert, key)?;
+        Self::from_pem(&cert_pem, &key_pem)
+    }
+
+    /// Serve a new certificate from now on. On error the old one is kept.
+    pub fn replace(&self, cert_pem: &[u8], key_pem: &[u8]) -> Result<(), String> {
+        let cert = parse_pem(cert_pem, key_pem)?;
+        *self.current.write().unwrap() = cert;
+        Ok(())
+    }
+
+    /// When the leaf certificate expires.
+    pub fn not_after(&self) -> Option<SystemTime> {
+        self.current.read().unwrap().not_after
+    }
+
+    /// DNS names the leaf certificate covers.
+    pub fn names(&self) -> Vec<String> {
+        self.current.read().unwrap().names.clone()
+    }
+
+    /// A rustls server config resolving through this store.
+    pub fn server_config(self: &Arc<Self>, alpn: &[&[u8]]) -> Arc<ServerConfig> {
+        let mut config = ServerConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .expect("ring supports the default TLS versions")
+            .with_no_client_auth()
+            .with_cert_resolver(self.clone());
+        config.alpn_protocols = alpn.iter().map(|p| p.to_vec()).collect();
+        Arc::new(config)
+    }
+}
+
+impl ResolvesServerCert for CertStore {
+    fn resolve(&self, _client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        Some(self.current.read().unwrap().key.clone())
+    }
+}
+
+fn read_pair(cert: &Path, key: &Path) -> Result<(Vec<u8>, Vec<u8>), String> {
+    let cert_pem = std::fs::read(cert).map_err(|e| format!("read {}: {}", cert.display(), e))?;
+    let key_pem = std::fs::read(key).map_err(|e| format!("read {}: {}", key.display(), e))?;
+    Ok((cert_pem, key_pem))
+}
+
+fn parse_pem(cert_pem: &[u8], key_pem: &[u8]) -> Result<Cert, String> {
+    let chain: Vec<CertificateDer<'static>> = rustls_pemfile::certs(&mut &cert_pem[..])
+        .collect::<Result<_, _>>()
+        .map_err(|e| format!("parse certificate: {}", e))?;
+    let leaf = chain.first().ok_or("no certificate in PEM")?;
+    let key: PrivateKeyDer<'static> = rustls_pemfile::private_key(&mut &key_pem[..])
+        .map_err(|e| format!("parse private key: {}", e))?
+        .ok_or("no private key in PEM")?;
+    let signing_key = any_supported_type(&key).map_err(|e| format!("unsupported private key: {}", e))?;
+
+    let (not_after, names) = match x509_parser::parse_x509_certificate(leaf) {
+        Ok((_, parsed)) => {
+            let not_after = u64::try_from(parsed.validity().not_after.timestamp())
+                .ok()
+                .map(|secs| UNIX_EPOCH + Duration::from_secs(secs));
+            let names = parsed
+                .subject_alternative_name()
+                .ok()
+                .flatten()
+                .map(|san| {
+                    san.value
+                        .general_names
+                        .iter()
+                        .filter_map(|name| match name {
+                            x509_parser::extensions::GeneralName::DNSName(dns) => Some(dns.to_string()),
+                            _ => None,
+                        })
+                        .collect()
+                })
+                .unwrap_or_default();
+            (not_after, names)
+        }
+        Err(e) => return Err(format!("parse certificate: {}", e)),
+    };
+
+    Ok(Cert {
+        key: Arc::new(CertifiedKey::new(chain, signing_key)),
+        not_after,
+        names,
+    })
+}
+
+/// Reload the certificate whenever either file changes, e.g. after an
+/// external renewal. A pair that doesn't parse is logged and skipped.
+pub fn spawn_file_reload(store: Arc<CertStore>, cert: PathBuf, key: PathBuf) {
+    let modified = |cert: &Path, key: &Path| -> Option<(SystemTime, SystemTime)> {
+        let mtime = |path: &Path| std::fs::metadata(path).and_then(|m| m.modified()).ok();
+        Some((mtime(cert)?, mtime(key)?))
+    };
+    tokio::spawn(async move {
+        let mut last = modified(&cert, &key);
+        loop {
+            tokio::time::sleep(FILE_RELOAD_INTERVAL).await;
+            let now = modified(&cert, &key);
+            if now.is_none() || now == last {
+                continue;
+            }
+            last = now;
+            let reloaded = read_pair(&cert, &key).and_then(|(c, k)| store.replace(&c, &k));
+            match reloaded {
+                Ok(()) => info!("Reloaded TLS certificate from {}", cert.display()),
+                Err(e) => warn!("Keeping current TLS certificate: {}", e),
+            }
+        }
+    });
+}
+
+/// A TLS connection accepted for the gRPC server.
+pub struct TlsConn(tokio_rustls::server::TlsStream<TcpStream>);
+
+impl Connected for TlsConn {
+    type ConnectInfo = TcpConnectInfo;
+
+    fn connect_info(&self) -> Self::ConnectInfo {
+        self.0.get_ref().0.connect_info()
+    }
+}
+
+impl AsyncRead for TlsConn {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.0).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for TlsConn {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.0).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.0).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.0).poll_shutdown(cx)
+    }
+}
+
+/// Accept TLS connections on `listener`. Handshakes run concurrently, and
+/// ones that fail are logged and dropped rather than ending the stream.
+pub fn incoming(
+    listener: TcpListener,
+    store: Arc<CertStore>,
+) -> impl futures_util::Stream<Item = Result<TlsConn, io::Error>> {
+    let acceptor = tokio_rustls::TlsAcceptor::from(store.server_config(&[b"h2"]));
+    let (tx, rx) = tokio::sync::mpsc::channel(64);
+    tokio::spawn(async move {
+        while !tx.is_closed() {
+            let (tcp, peer) = match listener.accept().await {
+                Ok(conn) => conn,
+                Err(e) => {
+                    warn!("gRPC accept failed: {}", e);
+                    tokio::time::sleep(Duration::from_millis(100)).await;
+                    continue;
+                }
+            };
+            let (acceptor, tx) = (acceptor.clone(), tx.clone());
+            tokio::spawn(async move {
+                match acceptor.accept(tcp).await {
+                    Ok(tls) => {
+                        let _ = tx.send(Ok(TlsConn(tls))).await;
+                    }
+                    Err(e) => tracing::debug!("TLS handshake with {} failed: {}", peer, e),
+                }
+            });
+        }
+    });
+    futures_util::stream::unfold(rx, |mut rx| async move { rx.recv().await.map(|conn| (conn, rx)) })
+}
@@ -0,0 +1,112 @@
+//! Trace context handed to sandboxed processes.
+//!
+//! Each run gets a W3C trace context: the caller's `traceparent` header is
+//! continued when it sends a valid one, otherwise a new trace is started. The
+//! run is a child span of the caller's, and the command sees
+//!
+//! - `OC_TRACE_ID` - the 32-hex trace ID
+//! - `TRACEPARENT` - `00-{trace_id}-{run span id}-{flags}`, the variable
+//!   OpenTelemetry SDKs read to parent their spans
+//! - `TRACESTATE` - forwarded from the caller's `tracestate`, if any
+//!
+//! so spans emitted by instrumented user code join the originating API call's
+//! trace. The trace ID is also returned in the run result and logged on the
+//! server's request span.
+
+use axum::http::HeaderMap;
+use std::collections::HashMap;
+
+pub const TRACEPARENT_HEADER: &str = "traceparent";
+pub const TRACESTATE_HEADER: &str = "tracestate";
+
+/// Longest `tracestate` forwarded (the W3C limit is 32 members of ~256 bytes;
+/// anything longer is dropped rather than truncated).
+const MAX_TRACESTATE_LEN: usize = 512;
+
+#[derive(Debug, Clone)]
+pub struct RunTrace {
+    pub trace_id: String,
+    /// Span ID of the run itself, the parent of spans created inside it
+    pub span_id: String,
+    /// Caller's span, when the trace was continued
+    pub parent_span_id: Option<String>,
+    flags: String,
+    tracestate: Option<String>,
+}
+
+impl RunTrace {
+    /// Continue the caller's trace, or start a sampled one.
+    pub fn from_traceparent(traceparent: Option<&str>, tracestate: Option<&str>) -> Self {
+        let span_id = random_hex(16);
+        match traceparent.and_then(parse_traceparent) {
+            Some((trace_id, parent, flags)) => Self {
+                trace_id,
+                span_id,
+                parent_span_id: Some(parent),
+                flags,
+                tracestate: tracestate.filter(|s| is_valid_tracestate(s)).map(str::to_string),
+            },
+            None => Self {
+                trace_id: random_hex(32),
+                span_id,
+                parent_span_id: None,
+                flags: "01".to_string(),
+                tracestate: None,
+            },
+        }
+    }
+
+    pub fn from_headers(headers: &HeaderMap) -> Self {
+        let get = |name| headers.get(name).and_then(|v| v.to_str().ok());
+        Self::from_traceparent(get(TRACEPARENT_HEADER), get(TRACESTATE_HEADER))
+    }
+
+    pub fn traceparent(&self) -> String {
+        format!("00-{}-{}-{}", self.trace_id, self.span_id, self.flags)
+    }
+
+    /// Export the context to the command. Overrides caller-provided values
+    /// so the variables always describe this run.
+    pub fn inject(&self, env: &mut HashMap<String, String>) {
+        env.insert("OC_TRACE_ID".to_string(), self.trace_id.clone());
+        env.insert("TRACEPARENT".to_string(), self.traceparent());
+        match self.tracestate {
+            Some(ref state) => env.insert("TRACESTATE".to_string(), state.clone()),
+            None => env.remove("TRACESTATE"),
+        };
+    }
+}
+
+/// `(trace_id, parent_id, flags)` from a version-00 `traceparent`. Later
+/// versions may append fields, which are ignored as the spec requires.
+fn parse_traceparent(value: &str) -> Option<(String, String, String)> {
+    let mut parts = value.trim().split('-');
+    let version = parts.next()?;
+    let trace_id = parts.next()?;
+    let parent_id = parts.next()?;
+    let flags = parts.next()?;
+    let extra = parts.next().is_some();
+    let valid = is_lower_hex(version, 2)
+        && version != "ff"
+        && (version != "00" || !extra)
+        && is_lower_hex(trace_id, 32)
+        && is_lower_hex(parent_id, 16)
+        && is_lower_hex(flags, 2)
+        && trace_id.bytes().any(|b| b != b'0')
+        && parent_id.bytes().any(|b| b != b'0');
+    valid.then(|| (trace_id.to_string(), parent_id.to_string(), flags.to_string()))
+}
+
+fn is_valid_tracestate(s: &str) -> bool {
+    s.len() <= MAX_TRACESTATE_LEN && s.bytes().all(|b| b.is_ascii_graphic() || b == b' ')
+}
+
+fn is_lower_hex(s: &str, len: usize) -> bool {
+    s.len() == len && s.bytes().all(|b| b.is_ascii_digit() || (b'a'..=b'f').contains(&b))
+}
+
+fn random_hex(len: usize) -> String {
+    let mut hex = uuid::Uuid::new_v4().simple().to_string();
+    hex.truncate(len);
+    hex
+}
@@ -0,0 +1,174 @@
+//! Deletion through the file API, with a trash for sessions created with
+//! `trash_ttl`.
+//!
+//! A trashed entry is renamed into `/.trash/<id>` inside the sandbox, so
+//! deleting a large tree costs no copy and restoring it is another rename.
+//! `/.trash/manifest.json` records where each entry came from and when it
+//! expires, and the cleanup task purges expired ones. The server creates the
+//! trash as root, so sandboxed code can look but not change it. Trashed
+//! entries still count against the session's disk use until purged.
+
+use crate::file_diff::{self, below, normalize};
+use crate::safe_path::Root;
+use crate::sandbox;
+use axum::http::StatusCode;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+pub use opencomputer_types::{DeleteFileRequest, DeleteFileResponse, RestoreFileRequest, RestoreFileResponse, TrashEntry};
+
+pub const TRASH_DIR: &str = "/.trash";
+const MANIFEST: &str = "/.trash/manifest.json";
+
+/// Serializes manifest updates; they're rare and quick.
+static MANIFEST_LOCK: Mutex<()> = Mutex::new(());
+
+/// Delete `req.path`, into the trash if the session keeps one for `trash_ttl`
+/// and the request isn't `permanent`.
+pub fn delete(
+    sandbox_root: &Path,
+    req: &DeleteFileRequest,
+    trash_ttl: Option<Duration>,
+) -> Result<DeleteFileResponse, (StatusCode, String)> {
+    let path = normalize(&req.path);
+    check_deletable(&path)?;
+    let root = Root::open(sandbox_root).map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e))?;
+    let entry = root
+        .symlink_metadata(Path::new(&path))
+        .map_err(|e| (StatusCode::NOT_FOUND, e))?;
+    if entry.is_directory && !req.recursive {
+        let empty = root
+            .list(Path::new(&path))
+            .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e))?
+            .is_empty();
+        if !empty {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                format!("{} is a directory that isn't empty; set recursive to delete it", path),
+            ));
+        }
+    }
+    let Some(ttl) = trash_ttl.filter(|_| !req.permanent) else {
+        root.remove_all(Path::new(&path))
+            .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e))?;
+        return Ok(DeleteFileResponse { path, trash_id: None });
+    };
+
+    let _lock = MANIFEST_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    let internal = |e| (StatusCode::INTERNAL_SERVER_ERROR, e);
+    root.create_dir_all(Path::new(TRASH_DIR), None).map_err(internal)?;
+    let mut manifest = load(&root).map_err(internal)?;
+    let id = uuid::Uuid::new_v4().simple().to_string();
+    let trashed = format!("{}/{}", TRASH_DIR, id);
+    root.rename(Path::new(&path), Path::new(&trashed)).map_err(internal)?;
+    let now = unix_now();
+    let kind = file_diff::kind(&entry);
+    manifest.push(TrashEntry {
+        id: id.clone(),
+        path: path.clone(),
+        kind: kind.to_string(),
+        size: if kind == "file" { entry.size } else { 0 },
+        deleted_at: now,
+        expires_at: now.saturating_add(ttl.as_secs()),
+    });
+    if let Err(e) = save(&root, &manifest) {
+        // Unrecorded, it could never be restored or purged
+        let _ = root.rename(Path::new(&trashed), Path::new(&path));
+        return Err(internal(e));
+    }
+    Ok(DeleteFileResponse { path, trash_id: Some(id) })
+}
+
+/// What's in the trash, oldest first.
+pub fn list(sandbox_root: &Path) -> Result<Vec<TrashEntry>, String> {
+    let root = Root::open(sandbox_root)?;
+    let _lock = MANIFEST_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    load(&root)
+}
+
+/// Move trashed entry `req.id` back to where it was deleted from, or to
+/// `req.to`. Its parent directories are recreated if they're gone.
+pub fn restore(sandbox_root: &Path, req: &RestoreFileRequest) -> Result<RestoreFileResponse, (StatusCode, String)> {
+    let root = Root::open(sandbox_root).map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e))?;
+    let _lock = MANIFEST_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    let internal = |e| (StatusCode::INTERNAL_SERVER_ERROR, e);
+    let mut manifest = load(&root).map_err(internal)?;
+    let index = manifest
+        .iter()
+        .position(|e| e.id == req.id)
+        .ok_or((StatusCode::NOT_FOUND, format!("Nothing in the trash with ID {}", req.id)))?;
+    let path = normalize(req.to.as_deref().unwrap_or(&manifest[index].path));
+    check_deletable(&path)?;
+    if root.symlink_metadata(Path::new(&path)).is_ok() {
+        return Err((
+            StatusCode::CONFLICT,
+            format!("{} exists; delete it or restore to another path", path),
+        ));
+    }
+    if let Some(parent) = Path::new(&path).parent() {
+        root.create_dir_all(parent, None).map_err(internal)?;
+    }
+    let trashed = format!("{}/{}", TRASH_DIR, req.id);
+    root.rename(Path::new(&trashed), Path::new(&path)).map_err(internal)?;
+    manifest.remove(index);
+    save(&root, &manifest).map_err(internal)?;
+    Ok(RestoreFileResponse { path })
+}
+
+/// Remove the entries that expired by now, returning how many.
+pub fn purge(sandbox_root: &Path) -> Result<usize, String> {
+    let root = Root::open(sandbox_root)?;
+    let expired = {
+        let _lock = MANIFEST_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        if root.symlink_metadata(Path::new(MANIFEST)).is_err() {
+            return Ok(0);
+        }
+        let now = unix_now();
+        let (expired, kept): (Vec<TrashEntry>, Vec<TrashEntry>) =
+            load(&root)?.into_iter().partition(|e| e.expires_at <= now);
+        if expired.is_empty() {
+            return Ok(0);
+        }
+        save(&root, &kept)?;
+        expired
+    };
+    // Unlisted now, so nothing else touches them while they're removed
+    for entry in &expired {
+        root.remove_all(Path::new(&format!("{}/{}", TRASH_DIR, entry.id)))?;
+    }
+    Ok(expired.len())
+}
+
+/// Refuse paths whose deletion (or replacement by a restore) would break
+/// the sandbox or the trash.
+fn check_deletable(path: &str) -> Result<(), (StatusCode, String)> {
+    let top = path.trim_start_matches('/').split('/').next().unwrap_or_default();
+    if path == "/" || sandbox::is_mounted_top_level(top) {
+        return Err((StatusCode::BAD_REQUEST, format!("{} is part of the sandbox itself", path)));
+    }
+    if below(TRASH_DIR, path).is_some() {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            format!("{} is the trash; its entries are purged when they expire", path),
+        ));
+    }
+    Ok(())
+}
+
+fn load(root: &Root) -> Result<Vec<TrashEntry>, String> {
+    match root.read(Path::new(MANIFEST)) {
+        Ok(data) => serde_json::from_slice(&data).map_err(|e| format!("parse {}: {}", MANIFEST, e)),
+        Err(_) if root.symlink_metadata(Path::new(MANIFEST)).is_err() => Ok(Vec::new()),
+        Err(e) => Err(e),
+    }
+}
+
+fn save(root: &Root, manifest: &[TrashEntry]) -> Result<(), String> {
+    let data = serde_json::to_vec(manifest).map_err(|e| e.to_string())?;
+    root.write(Path::new(MANIFEST), &data, None)
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs()
+}
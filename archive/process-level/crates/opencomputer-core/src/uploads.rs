@@ -0,0 +1,309 @@
+//! Resumable uploads, for files too large to send in one request or over
+//! a connection that may drop.
+//!
+//! An upload is created with its destination and size, then sent in chunks
+//! with `PATCH`, each saying the offset it starts at. After a dropped
+//! connection the client asks for the offset and carries on from there.
+//! Finalizing checks the size (and the SHA-256, if one was given) and
+//! renames the data into place, so the destination never holds a partial
+//! file.
+//!
+//! Uploads live in `/.uploads` inside the sandbox, like the trash, so their
+//! data counts against the session's disk and a finalize is a rename.
+//! `/.uploads/manifest.json` records them; the server creates the directory
+//! as root, and the cleanup task removes uploads left unfinished for
+//! [`UPLOAD_TTL`].
+
+use crate::file_diff::{below, normalize};
+use crate::safe_path::{Root, WriteAt};
+use crate::sandbox;
+use crate::trash::TRASH_DIR;
+use axum::http::StatusCode;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeSet;
+use std::io;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+pub use opencomputer_types::{CreateUploadRequest, FinalizeUploadResponse, UploadStatus};
+
+pub const UPLOADS_DIR: &str = "/.uploads";
+const MANIFEST: &str = "/.uploads/manifest.json";
+
+/// How long an unfinished upload is kept after it's created.
+pub const UPLOAD_TTL: Duration = Duration::from_secs(24 * 3600);
+
+/// Serializes manifest updates; they're rare and quick.
+static MANIFEST_LOCK: Mutex<()> = Mutex::new(());
+
+/// Uploads a chunk is being written to. A second chunk for one of them is
+/// refused rather than queued, since it would be at the wrong offset.
+static WRITING: Mutex<BTreeSet<String>> = Mutex::new(BTreeSet::new());
+
+/// An upload as the manifest records it; its offset is the data's length.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Upload {
+    id: String,
+    path: String,
+    size: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    sha256: Option<String>,
+    created_at: u64,
+    expires_at: u64,
+}
+
+impl Upload {
+    fn status(&self, offset: u64) -> UploadStatus {
+        UploadStatus {
+            id: self.id.clone(),
+            path: self.path.clone(),
+            size: self.size,
+            offset,
+            sha256: self.sha256.clone(),
+            created_at: self.created_at,
+            expires_at: self.expires_at,
+        }
+    }
+}
+
+/// Start an upload of `req.size` bytes to `req.path`.
+pub fn create(sandbox_root: &Path, req: &CreateUploadRequest) -> Result<UploadStatus, (StatusCode, String)> {
+    let path = normalize(&req.path);
+    check_destination(&path)?;
+    let sha256 = match &req.sha256 {
+        Some(hash) if hash.len() == 64 && hash.bytes().all(|b| b.is_ascii_hexdigit()) => Some(hash.to_ascii_lowercase()),
+        Some(_) => return Err((StatusCode::BAD_REQUEST, "sha256 must be 64 hex digits".to_string())),
+        None => None,
+    };
+    let free = sandbox::disk_capacity(sandbox_root).saturating_sub(sandbox::disk_usage(sandbox_root));
+    if req.size > free {
+        return Err((
+            StatusCode::INSUFFICIENT_STORAGE,
+            format!("{} bytes won't fit; the session has {} bytes free", req.size, free),
+        ));
+    }
+
+    let root = Root::open(sandbox_root).map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e))?;
+    let _lock = MANIFEST_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    let internal = |e| (StatusCode::INTERNAL_SERVER_ERROR, e);
+    root.create_dir_all(Path::new(UPLOADS_DIR), None).map_err(internal)?;
+    let mut manifest = load(&root).map_err(internal)?;
+    let now = unix_now();
+    let upload = Upload {
+        id: uuid::Uuid::new_v4().simple().to_string(),
+        path,
+        size: req.size,
+        sha256,
+        created_at: now,
+        expires_at: now.saturating_add(UPLOAD_TTL.as_secs()),
+    };
+    root.write(Path::new(&data_path(&upload.id)), &[], None).map_err(internal)?;
+    manifest.push(upload.clone());
+    if let Err(e) = save(&root, &manifest) {
+        let _ = root.remove_all(Path::new(&data_path(&upload.id)));
+        return Err(internal(e));
+    }
+    Ok(upload.status(0))
+}
+
+/// Where upload `id` stands.
+pub fn status(sandbox_root: &Path, id: &str) -> Result<UploadStatus, (StatusCode, String)> {
+    let root = Root::open(sandbox_root).map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e))?;
+    let upload = find(&root, id)?;
+    let offset = received(&root, id)?;
+    Ok(upload.status(offset))
+}
+
+/// Add `chunk` to upload `id`, where it must start at `offset`: what's been
+/// received so far. Returns the new offset.
+pub fn append(sandbox_root: &Path, id: &str, offset: u64, chunk: &[u8]) -> Result<u64, (StatusCode, String)> {
+    let root = Root::open(sandbox_root).map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e))?;
+    let upload = find(&root, id)?;
+    let _writing = Writing::start(id)?;
+    let received = received(&root, id)?;
+    if offset != received {
+        return Err((
+            StatusCode::CONFLICT,
+            format!("Upload-Offset is {} but {} bytes have been received", offset, received),
+        ));
+    }
+    let end = received.saturating_add(chunk.len() as u64);
+    if end > upload.size {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            format!("the chunk ends at {}, past the upload's size of {}", end, upload.size),
+        ));
+    }
+    root.write_at(Path::new(&data_path(id)), chunk, WriteAt::End, None)
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e))?;
+    Ok(end)
+}
+
+/// Move the finished upload `id` to its path, replacing a file there.
+pub fn finalize(sandbox_root: &Path, id: &str) -> Result<FinalizeUploadResponse, (StatusCode, String)> {
+    let root = Root::open(sandbox_root).map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e))?;
+    let internal = |e| (StatusCode::INTERNAL_SERVER_ERROR, e);
+    let upload = find(&root, id)?;
+    let _writing = Writing::start(id)?;
+    let received = received(&root, id)?;
+    if received != upload.size {
+        return Err((
+            StatusCode::CONFLICT,
+            format!("{} of {} bytes have been received", received, upload.size),
+        ));
+    }
+    let data = data_path(id);
+    let file = root.open_file(Path::new(&data)).map_err(internal)?;
+    let sha256 = digest(file).map_err(|e| internal(format!("read upload: {}", e)))?;
+    if upload.sha256.as_ref().is_some_and(|expected| *expected != sha256) {
+        // The data is wrong somewhere; resuming can't fix it
+        let _ = remove(&root, id);
+        return Err((
+            StatusCode::UNPROCESSABLE_ENTITY,
+            format!("the upload's SHA-256 is {}, not {}; it was discarded", sha256, upload.sha256.unwrap_or_default()),
+        ));
+    }
+
+    let owner = sandbox::sandbox_owner(sandbox_root);
+    let path = Path::new(&upload.path);
+    match root.symlink_metadata(path) {
+        Ok(entry) if entry.is_directory => {
+            return Err((StatusCode::CONFLICT, format!("{} is a directory", upload.path)));
+        }
+        Ok(_) => root.remove_all(path).map_err(internal)?,
+        Err(_) => {
+            if let Some(parent) = path.parent() {
+                root.create_dir_all(parent, owner).map_err(internal)?;
+            }
+        }
+    }
+    root.rename(Path::new(&data), path).map_err(internal)?;
+    root.set_owner(path, owner).map_err(internal)?;
+    let _lock = MANIFEST_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    let mut manifest = load(&root).map_err(internal)?;
+    manifest.retain(|u| u.id != id);
+    save(&root, &manifest).map_err(internal)?;
+    Ok(FinalizeUploadResponse {
+        path: upload.path,
+        size: upload.size,
+        sha256,
+    })
+}
+
+/// Abandon upload `id` and remove what it received.
+pub fn cancel(sandbox_root: &Path, id: &str) -> Result<(), (StatusCode, String)> {
+    let root = Root::open(sandbox_root).map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e))?;
+    find(&root, id)?;
+    let _writing = Writing::start(id)?;
+    remove(&root, id).map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e))
+}
+
+/// Remove the uploads that expired by now, returning how many.
+pub fn purge(sandbox_root: &Path) -> Result<usize, String> {
+    let root = Root::open(sandbox_root)?;
+    let expired = {
+        let _lock = MANIFEST_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        if root.symlink_metadata(Path::new(MANIFEST)).is_err() {
+            return Ok(0);
+        }
+        let now = unix_now();
+        let (expired, kept): (Vec<Upload>, Vec<Upload>) = load(&root)?.into_iter().partition(|u| u.expires_at <= now);
+        if expired.is_empty() {
+            return Ok(0);
+        }
+        save(&root, &kept)?;
+        expired
+    };
+    for upload in &expired {
+        root.remove_all(Path::new(&data_path(&upload.id)))?;
+    }
+    Ok(expired.len())
+}
+
+/// Marks an upload as being written to until dropped.
+struct Writing(String);
+
+impl Writing {
+    fn start(id: &str) -> Result<Self, (StatusCode, String)> {
+        let mut writing = WRITING.lock().unwrap_or_else(|e| e.into_inner());
+        if !writing.insert(id.to_string()) {
+            return Err((StatusCode::CONFLICT, format!("upload {} is busy with another request", id)));
+        }
+        Ok(Self(id.to_string()))
+    }
+}
+
+impl Drop for Writing {
+    fn drop(&mut self) {
+        WRITING.lock().unwrap_or_else(|e| e.into_inner()).remove(&self.0);
+    }
+}
+
+/// Refuse destinations that would replace part of the sandbox, the trash
+/// or the uploads themselves.
+fn check_destination(path: &str) -> Result<(), (StatusCode, String)> {
+    let top = path.trim_start_matches('/').split('/').next().unwrap_or_default();
+    if path == "/" || sandbox::is_mounted_top_level(top) {
+        return Err((StatusCode::BAD_REQUEST, format!("{} is part of the sandbox itself", path)));
+    }
+    if below(TRASH_DIR, path).is_some() || below(UPLOADS_DIR, path).is_some() {
+        return Err((StatusCode::BAD_REQUEST, format!("{} is managed by the server", path)));
+    }
+    Ok(())
+}
+
+fn find(root: &Root, id: &str) -> Result<Upload, (StatusCode, String)> {
+    let _lock = MANIFEST_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    load(root)
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e))?
+        .into_iter()
+        .find(|u| u.id == id)
+        .ok_or((StatusCode::NOT_FOUND, format!("No upload with ID {}", id)))
+}
+
+/// Bytes of upload `id` received so far.
+fn received(root: &Root, id: &str) -> Result<u64, (StatusCode, String)> {
+    root.symlink_metadata(Path::new(&data_path(id)))
+        .map(|entry| entry.size)
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e))
+}
+
+/// Drop upload `id` from the manifest, then its data.
+fn remove(root: &Root, id: &str) -> Result<(), String> {
+    {
+        let _lock = MANIFEST_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let mut manifest = load(root)?;
+        manifest.retain(|u| u.id != id);
+        save(root, &manifest)?;
+    }
+    root.remove_all(Path::new(&data_path(id)))
+}
+
+fn data_path(id: &str) -> String {
+    format!("{}/{}.part", UPLOADS_DIR, id)
+}
+
+fn digest(mut reader: impl io::Read) -> io::Result<String> {
+    let mut hasher = Sha256::new();
+    io::copy(&mut reader, &mut hasher)?;
+    Ok(hex::encode(hasher.finalize()))
+}
+
+fn load(root: &Root) -> Result<Vec<Upload>, String> {
+    match root.read(Path::new(MANIFEST)) {
+        Ok(data) => serde_json::from_slice(&data).map_err(|e| format!("parse {}: {}", MANIFEST, e)),
+        Err(_) if root.symlink_metadata(Path::new(MANIFEST)).is_err() => Ok(Vec::new()),
+        Err(e) => Err(e),
+    }
+}
+
+fn save(root: &Root, manifest: &[Upload]) -> Result<(), String> {
+    let data = serde_json::to_vec(manifest).map_err(|e| e.to_string())?;
+    root.write(Path::new(MANIFEST), &data, None)
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs()
+}
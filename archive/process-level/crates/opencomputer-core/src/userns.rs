@@ -0,0 +1,260 @@
+//! Rootless sandbox roots, used by the `userns` backend.
+//!
+//! The chroot backend needs root for its tmpfs, overlay and bind mounts on
+//! the host. A rootless root is instead a plain directory, owned by the
+//! server's user, at the same `/tmp/sandbox-{id}` path, with a marker in
+//! its layers dir; nothing is mounted on the host. Every command starts in
+//! new user, mount, PID and IPC namespaces, bwrap-style: it maps the
+//! server's UID and GID to themselves, bind-mounts the host's system
+//! directories read-only and the device nodes over the root's placeholders,
+//! mounts a /proc of its own, and pivots into the root, so the host's
+//! filesystem is out of reach rather than just outside the cwd.
+//!
+//! Needs unprivileged user namespaces, and Linux 5.11+ for the /etc
+//! overlay of sessions with their own /etc files.
+
+use crate::progress::Progress;
+use crate::sandbox::{self, RunConfig, RunResult};
+use nix::mount::{mount, umount2, MntFlags, MsFlags};
+use nix::sched::{unshare, CloneFlags};
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// Marks a rootless root, in its layers dir.
+const MARKER: &str = "rootless";
+
+/// Whether `sandbox_root` was created by [`create_root`].
+pub fn is_rootless_root(sandbox_root: &Path) -> bool {
+    sandbox::layers_dir(sandbox_root).is_some_and(|l| l.join(MARKER).is_file())
+}
+
+/// Create a rootless root for a session, with a copy of `template`'s files
+/// if given.
+pub fn create_session_root(session_id: &str, template: Option<&Path>) -> Result<PathBuf, String> {
+    let sandbox_root = PathBuf::from(format!("/tmp/sandbox-{}", session_id));
+    create_root(&sandbox_root, template)?;
+    Ok(sandbox_root)
+}
+
+/// Create a rootless root for the warm pool.
+pub fn create_pooled_root() -> Result<PathBuf, String> {
+    let sandbox_root = PathBuf::from(format!("/tmp/sandbox-pool-{}", uuid::Uuid::new_v4()));
+    create_root(&sandbox_root, None)?;
+    Ok(sandbox_root)
+}
+
+/// Run a command in a fresh rootless root, removed afterwards.
+pub fn run_oneshot(config: &RunConfig) -> Result<RunResult, String> {
+    let sandbox_root = PathBuf::from("/tmp/sandbox-oneshot");
+    create_root(&sandbox_root, None)?;
+    let result = sandbox::run_in_session(&sandbox_root, config);
+    remove_root(&sandbox_root);
+    result
+}
+
+/// Lay out a root: mount points for the system directories, /dev and
+/// /proc, and the writable /tmp and /home.
+fn create_root(sandbox_root: &Path, template: Option<&Path>) -> Result<(), String> {
+    if sandbox_root.exists() {
+        remove_root(sandbox_root);
+    }
+    let layers = sandbox::layers_dir(sandbox_root)
+        .ok_or_else(|| format!("invalid sandbox root {}", sandbox_root.display()))?;
+    for dir in [sandbox_root, &layers] {
+        fs::create_dir_all(dir).map_err(|e| format!("mkdir {}: {}", dir.display(), e))?;
+        fs::set_permissions(dir, fs::Permissions::from_mode(0o755))
+            .map_err(|e| format!("chmod {}: {}", dir.display(), e))?;
+    }
+    fs::write(layers.join(MARKER), "").map_err(|e| format!("write marker: {}", e))?;
+
+    let created = (|| {
+        for dir in sandbox::SYSTEM_BIND_DIRS.iter().filter(|d| Path::new(d).exists()) {
+            let target = sandbox_root.join(&dir[1..]);
+            fs::create_dir(&target).map_err(|e| format!("mkdir {}: {}", target.display(), e))?;
+        }
+        for dir in ["dev", "proc", "home"] {
+            fs::create_dir(sandbox_root.join(dir)).map_err(|e| format!("mkdir {}: {}", dir, e))?;
+        }
+        for dev in sandbox::DEVICE_NODES {
+            fs::write(sandbox_root.join("dev").join(dev), "").map_err(|e| format!("touch {}: {}", dev, e))?;
+        }
+        let tmp = sandbox_root.join("tmp");
+        fs::create_dir(&tmp).map_err(|e| format!("mkdir tmp: {}", e))?;
+        fs::set_permissions(&tmp, fs::Permissions::from_mode(0o1777)).map_err(|e| format!("chmod tmp: {}", e))?;
+        if let Some(template) = template {
+            sandbox::populate_from_template(sandbox_root, template, &mut Progress::disabled())?;
+        }
+        Ok(())
+    })();
+    if created.is_err() {
+        remove_root(sandbox_root);
+    }
+    created
+}
+
+/// Delete a root and its layers dir. Its commands' mounts went with their
+/// namespaces, so there is nothing to unmount.
+pub fn remove_root(sandbox_root: &Path) {
+    let _ = fs::remove_dir_all(sandbox_root);
+    if let Some(layers) = sandbox::layers_dir(sandbox_root) {
+        let _ = fs::remove_dir_all(layers);
+    }
+}
+
+/// Where a rootless root keeps its own /etc files, out of its commands'
+/// reach. They are mounted over the host's /etc as each command starts.
+pub(crate) fn etc_overrides(sandbox_root: &Path) -> Option<PathBuf> {
+    sandbox::layers_dir(sandbox_root).map(|l| l.join("etc"))
+}
+
+/// Namespaces a foreground run of a rootless root is cloned into, on top of
+/// the chroot backend's.
+pub(crate) const CLONE_FLAGS: CloneFlags = CloneFlags::CLONE_NEWUSER.union(CloneFlags::CLONE_NEWIPC);
+
+/// The server's UID and GID, which sandboxed commands keep.
+pub(crate) fn server_ids() -> (u32, u32) {
+    (nix::unistd::geteuid().as_raw(), nix::unistd::getegid().as_raw())
+}
+
+/// Set up a freshly cloned child of [`sandbox`]'s run: map `ids`, build
+/// the root's mounts and pivot into it. The child must be in new user,
+/// mount and PID namespaces; afterwards the root is `/`.
+pub(crate) fn enter(sandbox_root: &Path, ids: (u32, u32)) -> Result<(), String> {
+    write_id_maps(ids)?;
+    mount(None::<&str>, "/", None::<&str>, MsFlags::MS_REC | MsFlags::MS_PRIVATE, None::<&str>)
+        .map_err(|e| format!("make mounts private: {}", e))?;
+    // pivot_root needs the new root to be a mount point
+    mount(Some(sandbox_root), sandbox_root, None::<&str>, MsFlags::MS_BIND | MsFlags::MS_REC, None::<&str>)
+        .map_err(|e| format!("bind mount root: {}", e))?;
+
+    let overrides = etc_overrides(sandbox_root).filter(|o| o.is_dir());
+    for dir in sandbox::SYSTEM_BIND_DIRS.iter().filter(|d| Path::new(d).exists()) {
+        let target = sandbox_root.join(&dir[1..]);
+        match overrides {
+            // Two lower layers and no upper dir: a read-only merged view
+            Some(ref overrides) if *dir == "/etc" => {
+                let options = format!("lowerdir={}:/etc", overrides.display());
+                let flags = MsFlags::MS_RDONLY | MsFlags::MS_NOSUID | MsFlags::MS_NODEV;
+                mount(Some("overlay"), &target, Some("overlay"), flags, Some(options.as_str()))
+                    .map_err(|e| format!("mount etc overlay: {}", e))?;
+            }
+            _ => bind_read_only(Path::new(dir), &target)?,
+        }
+    }
+    for dev in sandbox::DEVICE_NODES {
+        let target = sandbox_root.join("dev").join(dev);
+        // A seeded stand-in (see sandbox::apply_determinism) replaces the host device
+        if target.metadata().is_ok_and(|m| m.len() > 0) {
+            continue;
+        }
+        let host = Path::new("/dev").join(dev);
+        if host.exists() {
+            mount(Some(&host), &target, None::<&str>, MsFlags::MS_BIND, None::<&str>)
+                .map_err(|e| format!("bind mount {}: {}", dev, e))?;
+        }
+    }
+    let flags = MsFlags::MS_NOSUID | MsFlags::MS_NODEV | MsFlags::MS_NOEXEC;
+    mount(Some("proc"), &sandbox_root.join("proc"), Some("proc"), flags, None::<&str>)
+        .map_err(|e| format!("mount proc: {}", e))?;
+
+    nix::unistd::chdir(sandbox_root).map_err(|e| format!("chdir root: {}", e))?;
+    nix::unistd::pivot_root(".", ".").map_err(|e| format!("pivot_root: {}", e))?;
+    // The old root is stacked under the new one; drop it
+    umount2(".", MntFlags::MNT_DETACH).map_err(|e| format!("unmount old root: {}", e))?;
+    nix::unistd::chdir("/").map_err(|e| format!("chdir /: {}", e))?;
+    Ok(())
+}
+
+/// Map `ids` to themselves in the calling process's new user namespace.
+fn write_id_maps((uid, gid): (u32, u32)) -> Result<(), String> {
+    // An unprivileged process may only map its own IDs, and gid_map only
+    // after giving up setgroups
+    let write = |file: &str, content: String| {
+        fs::write(format!("/proc/self/{}", file), content).map_err(|e| format!("write {}: {}", file, e))
+    };
+    write("setgroups", "deny".to_string())?;
+    write("uid_map", format!("{} {} 1\n", uid, uid))?;
+    write("gid_map", format!("{} {} 1\n", gid, gid))
+}
+
+/// Bind `source` read-only at `target`. A user namespace may not clear the
+/// flags the source's mount already has, so they are kept.
+fn bind_read_only(source: &Path, target: &Path) -> Result<(), String> {
+    use nix::sys::statvfs::{statvfs, FsFlags};
+
+    mount(Some(source), target, None::<&str>, MsFlags::MS_BIND | MsFlags::MS_REC, None::<&str>)
+        .map_err(|e| format!("bind mount {}: {}", source.display(), e))?;
+    let locked = statvfs(source).map(|s| s.flags()).unwrap_or(FsFlags::empty());
+    let mut flags = MsFlags::MS_BIND | MsFlags::MS_REMOUNT | MsFlags::MS_RDONLY;
+    for (fs_flag, ms_flag) in [
+        (FsFlags::ST_NOSUID, MsFlags::MS_NOSUID),
+        (FsFlags::ST_NODEV, MsFlags::MS_NODEV),
+        (FsFlags::ST_NOEXEC, MsFlags::MS_NOEXEC),
+        (FsFlags::ST_NOATIME, MsFlags::MS_NOATIME),
+        (FsFlags::ST_NODIRATIME, MsFlags::MS_NODIRATIME),
+        (FsFlags::ST_RELATIME, MsFlags::MS_RELATIME),
+    ] {
+        if locked.contains(fs_flag) {
+            flags |= ms_flag;
+        }
+    }
+    mount(None::<&str>, target, None::<&str>, flags, None::<&str>)
+        .map_err(|e| format!("remount ro {}: {}", source.display(), e))
+}
+
+/// Make `cmd` start in a rootless root, outliving the call like the chroot
+/// backend's background processes. The spawned process unshares the
+/// namespaces and stays outside the new PID namespace as a monitor, the way
+/// bwrap does: it forks the command as the namespace's init, which dies
+/// with it, and exits with the command's status. `setup` runs in the
+/// command after [`enter`].
+pub(crate) fn sandbox_command(
+    cmd: &mut Command,
+    sandbox_root: &Path,
+    setup: impl Fn() -> std::io::Result<()> + Send + Sync + 'static,
+) {
+    use std::os::unix::process::CommandExt;
+
+    let sandbox_root = sandbox_root.to_path_buf();
+    let ids = server_ids();
+    let flags = CloneFlags::CLONE_NEWUSER
+        | CloneFlags::CLONE_NEWNS
+        | CloneFlags::CLONE_NEWPID
+        | CloneFlags::CLONE_NEWIPC;
+    unsafe {
+        cmd.pre_exec(move || {
+            unshare(flags).map_err(|e| std::io::Error::other(format!("unshare: {}", e)))?;
+            match nix::unistd::fork().map_err(|e| std::io::Error::other(format!("fork: {}", e)))? {
+                nix::unistd::ForkResult::Parent { child } => monitor_command(child),
+                nix::unistd::ForkResult::Child => {
+                    libc::prctl(libc::PR_SET_PDEATHSIG, libc::SIGKILL);
+                    enter(&sandbox_root, ids).map_err(std::io::Error::other)?;
+                    setup()
+                }
+            }
+        });
+    }
+}
+
+/// Wait for the command forked by [`sandbox_command`] and exit as it did.
+/// Never returns.
+fn monitor_command(child: nix::unistd::Pid) -> ! {
+    use nix::sys::wait::{waitpid, WaitStatus};
+
+    // Let the spawning server see the command's exec go through rather than
+    // wait for this process to exit: close our copy of its status pipe
+    unsafe {
+        libc::syscall(libc::SYS_close_range, 3, libc::c_uint::MAX, 0);
+    }
+    let code = loop {
+        match waitpid(child, None) {
+            Ok(WaitStatus::Exited(_, code)) => break code,
+            Ok(WaitStatus::Signaled(_, signal, _)) => break 128 + signal as i32,
+            Ok(_) | Err(nix::errno::Errno::EINTR) => continue,
+            Err(_) => break 1,
+        }
+    };
+    unsafe { libc::_exit(code) }
+}
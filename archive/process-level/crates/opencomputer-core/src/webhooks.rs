@@ -0,0 +1,174 @@
+//! Signed lifecycle webhooks.
+//!
+//! [`WebhookDispatcher`] is a [`SessionLifecycleHook`] that POSTs a JSON event
+//! to each configured URL:
+//!
+//! ```json
+//! {"id": "…", "type": "run.completed", "timestamp": 1700000000,
+//!  "session_id": "…", "slug": null, "labels": {}, "data": {…}}
+//! ```
+//!
+//! Types are `session.created`, `session.expired`, `session.deleted`,
+//! `session.evicted`, `background.exited` and `run.completed`. Each request carries
+//! `X-OpenSandbox-Signature: t={timestamp},v1={hex}` where `hex` is
+//! HMAC-SHA256 of `"{timestamp}.{body}"` under the shared secret. Receivers
+//! should recompute it and reject stale timestamps.
+
+use crate::lifecycle::{BackgroundExit, RunCompletion, SessionLifecycleEvent, SessionLifecycleHook};
+use hmac::{Hmac, Mac};
+use serde::Serialize;
+use sha2::Sha256;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+pub const SIGNATURE_HEADER: &str = "x-opensandbox-signature";
+
+const DELIVERY_TIMEOUT_SECS: u64 = 10;
+/// Attempts per URL before an event is dropped.
+const DELIVERY_ATTEMPTS: u32 = 3;
+
+#[derive(Serialize)]
+struct WebhookEvent<'a> {
+    id: String,
+    #[serde(rename = "type")]
+    kind: &'static str,
+    timestamp: u64,
+    session_id: &'a str,
+    slug: Option<&'a str>,
+    labels: &'a HashMap<String, String>,
+    data: serde_json::Value,
+}
+
+pub struct WebhookDispatcher {
+    urls: Arc<Vec<String>>,
+    secret: Arc<Vec<u8>>,
+    client: reqwest::Client,
+    runtime: tokio::runtime::Handle,
+    /// Deliveries not yet finished, retries included
+    in_flight: Arc<AtomicUsize>,
+}
+
+impl WebhookDispatcher {
+    /// Must be called from within a tokio runtime; deliveries are spawned
+    /// onto it so hooks invoked from plain threads still work.
+    pub fn new(urls: Vec<String>, secret: &str) -> Result<Self, String> {
+        if secret.is_empty() {
+            return Err("webhook secret must not be empty".to_string());
+        }
+        for url in &urls {
+            reqwest::Url::parse(url).map_err(|e| format!("invalid webhook URL {}: {}", url, e))?;
+        }
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(DELIVERY_TIMEOUT_SECS))
+            .build()
+            .map_err(|e| format!("build webhook client: {}", e))?;
+        Ok(Self {
+            urls: Arc::new(urls),
+            secret: Arc::new(secret.as_bytes().to_vec()),
+            client,
+            runtime: tokio::runtime::Handle::try_current()
+                .map_err(|_| "webhooks need a tokio runtime".to_string())?,
+            in_flight: Arc::new(AtomicUsize::new(0)),
+        })
+    }
+
+    fn dispatch(&self, kind: &'static str, event: &SessionLifecycleEvent, data: serde_json::Value) {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        let body = WebhookEvent {
+            id: uuid::Uuid::new_v4().to_string(),
+            kind,
+            timestamp,
+            session_id: &event.session_id,
+            slug: event.slug.as_deref(),
+            labels: &event.labels,
+            data,
+        };
+        let body = match serde_json::to_vec(&body) {
+            Ok(body) => body,
+            Err(e) => {
+                tracing::warn!("Failed to encode {} webhook: {}", kind, e);
+                return;
+            }
+        };
+        let signature = format!("t={},v1={}", timestamp, sign(&self.secret, timestamp, &body));
+
+        for url in self.urls.iter() {
+            let client = self.client.clone();
+            let url = url.clone();
+            let body = body.clone();
+            let signature = signature.clone();
+            let in_flight = self.in_flight.clone();
+            in_flight.fetch_add(1, Ordering::Relaxed);
+            self.runtime.spawn(async move {
+                deliver(&client, &url, kind, body, &signature).await;
+                in_flight.fetch_sub(1, Ordering::Relaxed);
+            });
+        }
+    }
+}
+
+impl SessionLifecycleHook for WebhookDispatcher {
+    fn on_create(&self, event: &SessionLifecycleEvent) {
+        self.dispatch("session.created", event, serde_json::json!({}));
+    }
+
+    fn on_expire(&self, event: &SessionLifecycleEvent) {
+        self.dispatch("session.expired", event, serde_json::json!({}));
+    }
+
+    fn on_delete(&self, event: &SessionLifecycleEvent) {
+        self.dispatch("session.deleted", event, serde_json::json!({}));
+    }
+
+    fn on_evict(&self, event: &SessionLifecycleEvent) {
+        self.dispatch("session.evicted", event, serde_json::json!({}));
+    }
+
+    fn on_background_exit(&self, event: &SessionLifecycleEvent, exit: &BackgroundExit) {
+        self.dispatch("background.exited", event, serde_json::to_value(exit).unwrap_or_default());
+    }
+
+    fn on_run_complete(&self, event: &SessionLifecycleEvent, run: &RunCompletion) {
+        self.dispatch("run.completed", event, serde_json::to_value(run).unwrap_or_default());
+    }
+
+    fn pending(&self) -> usize {
+        self.in_flight.load(Ordering::Relaxed)
+    }
+}
+
+/// Hex HMAC-SHA256 of `"{timestamp}.{body}"`.
+pub fn sign(secret: &[u8], timestamp: u64, body: &[u8]) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret).expect("HMAC accepts any key length");
+    mac.update(timestamp.to_string().as_bytes());
+    mac.update(b".");
+    mac.update(body);
+    hex::encode(mac.finalize().into_bytes())
+}
+
+async fn deliver(client: &reqwest::Client, url: &str, kind: &str, body: Vec<u8>, signature: &str) {
+    for attempt in 1..=DELIVERY_ATTEMPTS {
+        let result = client
+            .post(url)
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .header(SIGNATURE_HEADER, signature)
+            .body(body.clone())
+            .send()
+            .await;
+        let error = match result {
+            Ok(resp) if resp.status().is_success() => return,
+            Ok(resp) => format!("HTTP {}", resp.status()),
+            Err(e) => e.to_string(),
+        };
+        if attempt == DELIVERY_ATTEMPTS {
+            tracing::warn!("Dropping {} webhook to {} after {} attempts: {}", kind, url, attempt, error);
+            return;
+        }
+        tokio::time::sleep(Duration::from_secs(1 << attempt)).await;
+    }
+}
@@ -0,0 +1,2368 @@
+//! Request and response types for the OpenSandbox HTTP API.
+//!
+//! Shared by the server (`opencomputer-core`) and the client
+//! (`opencomputer-client`) so both sides agree on the wire format. Optional
+//! request fields are omitted when unset so the server's defaults apply.
+
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt;
+use std::time::Duration;
+
+// Sessions
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct CreateSessionRequest {
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub env: HashMap<String, String>,
+    /// Require auth on preview requests (bearer, basic, or cookie)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub preview_auth: Option<PreviewAuthRequest>,
+    /// Custom preview subdomain instead of the session UUID
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub slug: Option<String>,
+    /// Idle TTL in seconds (defaults to the server setting; values over its
+    /// max are rejected)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ttl: Option<u64>,
+    /// Registered template to copy into the new sandbox
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub template: Option<String>,
+    /// OCI image whose filesystem the sandbox is built on instead of the
+    /// host's, e.g. `ghcr.io/org/devimage:1.2`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub image: Option<String>,
+    /// Arbitrary key/value metadata, filterable with `GET /sessions?label=k=v`
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub labels: HashMap<String, String>,
+    /// Fixed hostname, machine ID and random seed for reproducible runs
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub determinism: Option<Determinism>,
+    /// Expected peak usage, reserved against host capacity
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub resources: Option<Resources>,
+    /// CPU and process limits of the session, when the server runs sessions
+    /// in cgroups (defaults to the server's)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub limits: Option<SessionLimits>,
+    /// Env vars whose values are never returned and are masked in output,
+    /// e.g. tokens
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub secrets: HashMap<String, String>,
+    /// Cut the session off from the network except for HTTP(S) through the
+    /// server's proxy, to the domains these rules allow
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub egress: Option<EgressPolicy>,
+    /// Move entries deleted with `POST /sessions/:id/files/delete` to a trash
+    /// they can be restored from for this many seconds
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub trash_ttl: Option<u64>,
+}
+
+/// Preview auth mode requested at session creation.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "mode", rename_all = "lowercase")]
+pub enum PreviewAuthRequest {
+    /// Require `Authorization: Bearer <token>`. A token is generated if omitted.
+    Bearer {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        token: Option<String>,
+    },
+    /// Require HTTP basic auth with the given credentials.
+    Basic { username: String, password: String },
+    /// Require a signed cookie obtained by visiting with `?oc_token=<token>`.
+    Cookie {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        token: Option<String>,
+    },
+}
+
+/// Controls over nondeterminism visible inside a sandbox, for reproducible runs.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Determinism {
+    /// Hostname seen by commands (they get their own UTS namespace).
+    /// Sessions default to `oc-{first 8 chars of the session ID}`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hostname: Option<String>,
+    /// 32 lowercase hex chars for /etc/machine-id; derived from `seed` if unset
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub machine_id: Option<String>,
+    /// Replace /dev/urandom and /dev/random with a fixed byte stream derived
+    /// from this seed, and default `PYTHONHASHSEED` to it. Only reads of the
+    /// device files are affected, not the getrandom(2) syscall.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub seed: Option<u64>,
+}
+
+impl Determinism {
+    pub fn validate(&self) -> Result<(), String> {
+        if let Some(ref hostname) = self.hostname {
+            let valid = !hostname.is_empty()
+                && hostname.len() <= 63
+                && hostname.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+                && !hostname.starts_with('-')
+                && !hostname.ends_with('-');
+            if !valid {
+                return Err("hostname must be 1-63 letters, digits or '-', not starting or ending with '-'".to_string());
+            }
+        }
+        if let Some(ref id) = self.machine_id {
+            if id.len() != 32 || !id.chars().all(|c| c.is_ascii_digit() || ('a'..='f').contains(&c)) {
+                return Err("machine_id must be 32 lowercase hex characters".to_string());
+            }
+        }
+        Ok(())
+    }
+}
+
+/// CPU and process limits of a session, enforced with cgroups. Set at
+/// creation or with `PATCH /sessions/:id/limits`, where fields left out
+/// keep their value.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct SessionLimits {
+    /// Share of CPU time when sessions compete for it, relative to theirs:
+    /// 1 to 10000, 100 being an even share (cgroup `cpu.weight`)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cpu_weight: Option<u32>,
+    /// Most CPUs the session's processes may keep busy together, e.g. `0.5`
+    /// or `2` (cgroup `cpu.max`). Zero lifts the cap
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cpu_max: Option<f64>,
+    /// Most processes and threads the session may have at once, background
+    /// ones included (cgroup `pids.max`). Zero lifts the limit
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pids_max: Option<u64>,
+}
+
+impl SessionLimits {
+    pub const MAX_CPU_WEIGHT: u32 = 10_000;
+    /// Smallest `cpu_max` other than zero: 1ms of CPU per 100ms period
+    pub const MIN_CPU_MAX: f64 = 0.01;
+
+    pub fn validate(&self) -> Result<(), String> {
+        if self.cpu_weight.is_some_and(|w| !(1..=Self::MAX_CPU_WEIGHT).contains(&w)) {
+            return Err(format!("limits.cpu_weight must be between 1 and {}", Self::MAX_CPU_WEIGHT));
+        }
+        if let Some(max) = self.cpu_max {
+            if !max.is_finite() || max < 0.0 || (max > 0.0 && max < Self::MIN_CPU_MAX) {
+                return Err(format!("limits.cpu_max must be 0 or at least {}", Self::MIN_CPU_MAX));
+            }
+        }
+        Ok(())
+    }
+
+    /// These limits with the fields `update` sets replaced. A zero
+    /// `cpu_max` or `pids_max` comes out unset.
+    pub fn merge(self, update: SessionLimits) -> SessionLimits {
+        SessionLimits {
+            cpu_weight: update.cpu_weight.or(self.cpu_weight),
+            cpu_max: update.cpu_max.or(self.cpu_max).filter(|&max| max > 0.0),
+            pids_max: update.pids_max.or(self.pids_max).filter(|&max| max > 0),
+        }
+    }
+}
+
+/// CPU, memory and disk, as reserved by a session or left on the host.
+/// Zero means none.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Resources {
+    /// CPU cores (fractions allowed)
+    pub cpu: f64,
+    pub mem_mb: u64,
+    pub disk_mb: u64,
+}
+
+impl Resources {
+    pub fn validate(&self) -> Result<(), String> {
+        if !self.cpu.is_finite() || self.cpu < 0.0 {
+            return Err("resources.cpu must be a non-negative number".to_string());
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CreateSessionResponse {
+    pub session_id: String,
+    pub preview_url: Option<String>,
+    /// Token for bearer/cookie preview auth (only returned at creation)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub preview_token: Option<String>,
+    /// Capacity left after this create, for placement decisions (absent
+    /// from older servers)
+    #[serde(default)]
+    pub capacity: Option<Capacity>,
+}
+
+/// Status of a sandbox session.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SessionStatus {
+    #[default]
+    Running,
+    Idle,
+    /// All session processes are stopped (SIGSTOP)
+    Paused,
+    /// Background processes are dumped to disk (CRIU) and nothing runs
+    Hibernated,
+    Terminating,
+}
+
+impl SessionStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SessionStatus::Running => "running",
+            SessionStatus::Idle => "idle",
+            SessionStatus::Paused => "paused",
+            SessionStatus::Hibernated => "hibernated",
+            SessionStatus::Terminating => "terminating",
+        }
+    }
+
+    /// Whether the session's processes are stopped or dumped, so it takes no
+    /// new commands or traffic.
+    pub fn is_suspended(&self) -> bool {
+        matches!(self, SessionStatus::Paused | SessionStatus::Hibernated)
+    }
+}
+
+impl fmt::Display for SessionStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.pad(self.as_str())
+    }
+}
+
+impl std::str::FromStr for SessionStatus {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "running" => Ok(SessionStatus::Running),
+            "idle" => Ok(SessionStatus::Idle),
+            "paused" => Ok(SessionStatus::Paused),
+            "hibernated" => Ok(SessionStatus::Hibernated),
+            "terminating" => Ok(SessionStatus::Terminating),
+            other => Err(format!(
+                "invalid status {:?} (expected running, idle, paused, hibernated, or terminating)",
+                other
+            )),
+        }
+    }
+}
+
+/// What last kept a session from being idle.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum IdleReason {
+    /// An API call that used the session, or an open SSH connection
+    #[default]
+    Api,
+    /// A run, job, package install or git operation in progress
+    Run,
+    /// Its processes using CPU
+    Cpu,
+    /// An open preview or tunnel connection
+    Connection,
+}
+
+/// Session as returned by `GET /sessions` and `GET /sessions/:id`. Fields
+/// left out with `?fields=` deserialize to defaults.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SessionInfo {
+    pub id: String,
+    pub env: HashMap<String, String>,
+    pub cwd: String,
+    pub age_secs: u64,
+    /// Time since the session was last used or seen active
+    pub idle_secs: u64,
+    /// What `idle_secs` counts from
+    pub idle_reason: IdleReason,
+    pub preview_url: Option<String>,
+    pub ports: Vec<u16>,
+    /// Directory served at the preview URL instead of a port, if any
+    pub static_site: Option<StaticSite>,
+    pub status: SessionStatus,
+    /// Preview auth mode (`bearer`, `basic` or `cookie`), if any
+    pub preview_auth: Option<String>,
+    pub slug: Option<String>,
+    pub template: Option<String>,
+    /// OCI image the session was created from, as requested
+    pub image: Option<String>,
+    pub labels: HashMap<String, String>,
+    pub determinism: Determinism,
+    /// Resources reserved at creation, if any
+    pub resources: Option<Resources>,
+    /// CPU and process limits in force, when the server runs sessions in
+    /// cgroups
+    pub limits: Option<SessionLimits>,
+    /// Names of the session's secrets, sorted; their values are never returned
+    pub secrets: Vec<String>,
+    pub ttl_secs: u64,
+    pub expires_in_secs: u64,
+    /// Resource use of the session's processes
+    pub stats: SessionStats,
+}
+
+/// Scopes and lifetime of a token minted with `POST /sessions/:id/tokens`.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct CreateSessionTokenRequest {
+    /// Scope patterns, e.g. `["sessions.read", "background.read"]`; each must
+    /// be held by the caller
+    pub scopes: Vec<String>,
+    /// Lifetime in seconds (default: until the session ends)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ttl: Option<u64>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CreateSessionTokenResponse {
+    /// Send as `Authorization: Bearer <token>` or `X-API-Key`
+    pub token: String,
+    pub scopes: Vec<String>,
+    pub expires_in_secs: Option<u64>,
+}
+
+/// What a share token may do in its session.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ShareAccess {
+    /// Inspect the session, read files and background logs
+    #[default]
+    Read,
+    /// Read, and run commands
+    Run,
+    /// Run, write files and manage background processes
+    Full,
+}
+
+impl ShareAccess {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ShareAccess::Read => "read",
+            ShareAccess::Run => "run",
+            ShareAccess::Full => "full",
+        }
+    }
+}
+
+/// `POST /sessions/:id/share`
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct CreateShareRequest {
+    #[serde(default)]
+    pub access: ShareAccess,
+    /// Lifetime in seconds (default a day, max 30 days)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ttl: Option<u64>,
+    /// Who it's for, shown when listing shares
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub label: Option<String>,
+}
+
+/// A share, as listed by `GET /sessions/:id/shares`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Share {
+    pub share_id: String,
+    pub access: ShareAccess,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub label: Option<String>,
+    pub scopes: Vec<String>,
+    pub expires_in_secs: u64,
+}
+
+/// A freshly minted share. The token is only ever returned here.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CreateShareResponse {
+    /// Send as `Authorization: Bearer <token>` or `X-API-Key`
+    pub token: String,
+    #[serde(flatten)]
+    pub share: Share,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct KeepaliveRequest {
+    /// Replace the session's TTL (seconds) while extending it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ttl: Option<u64>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct KeepaliveResponse {
+    pub ttl_secs: u64,
+    pub expires_in_secs: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PauseResponse {
+    pub status: SessionStatus,
+    /// PIDs that were stopped or continued
+    pub pids: Vec<u32>,
+}
+
+/// `POST /sessions/:id/hibernate` and `POST /sessions/:id/wake`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HibernateResponse {
+    pub status: SessionStatus,
+    /// Background processes dumped, or restored when waking
+    pub pids: Vec<u32>,
+    /// Other processes of the session (runs, kernels, shells) that
+    /// hibernating killed
+    #[serde(default)]
+    pub killed: Vec<u32>,
+}
+
+/// `POST /sessions/:id/env`: merged into the session env, or with `replace`
+/// swapped in for all of it.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct SetEnvRequest {
+    pub env: HashMap<String, String>,
+    /// Drop every variable not in `env`
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub replace: bool,
+}
+
+/// `POST /sessions/:id/secrets`: values replace secrets of the same name.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct SetSecretsRequest {
+    pub secrets: HashMap<String, String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SetCwdRequest {
+    pub cwd: String,
+}
+
+/// Placement hints from `GET /capacity` and session creation.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Capacity {
+    /// Further sessions the caller may create: the tighter of its API key's
+    /// `max_sessions` and the server's `--max-sessions` (null = unlimited)
+    pub sessions_remaining: Option<usize>,
+    pub active_sessions: usize,
+    /// Session creates currently in progress
+    pub queue_depth: usize,
+    /// Pre-created sandbox roots ready to hand out
+    pub warm_available: usize,
+    /// Expected time for a new create to finish, from recent creates
+    pub estimated_cold_start_ms: u64,
+    /// Resources still reservable: host capacity times the overcommit ratio,
+    /// minus live sessions' reservations (absent from older servers)
+    #[serde(default)]
+    pub resources_available: Option<Resources>,
+}
+
+/// Pool counters reported by `GET /pool`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PoolStats {
+    pub target: usize,
+    pub available: usize,
+    /// Sessions that got a pre-created root
+    pub hits: u64,
+    /// Sessions created while the pool was empty
+    pub misses: u64,
+    pub created: u64,
+    pub failures: u64,
+    pub draining: bool,
+}
+
+/// Dependency checks from `GET /healthz` and `GET /readyz`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HealthReport {
+    /// Whether the checks the endpoint answers for passed; the response is
+    /// `503` otherwise
+    pub healthy: bool,
+    pub checks: Vec<HealthCheck>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HealthCheck {
+    /// `disk`, `blocking_pool` or `sessions`
+    pub name: String,
+    pub ok: bool,
+    /// What was measured, e.g. "812 MB free of 4096 MB on /tmp"
+    pub detail: String,
+}
+
+// Commands
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct RunRequest {
+    pub command: Vec<String>,
+    /// CPU time limit in milliseconds
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub time: Option<u64>,
+    /// Memory limit in KB
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mem: Option<u64>,
+    /// Max file size in KB
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fsize: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub nofile: Option<u64>,
+    /// Most processes (threads included) the sandbox's user may have for the
+    /// command to fork another (default 256, 0 for none)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pids: Option<u64>,
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub env: HashMap<String, String>,
+    /// Session env vars and secrets this run doesn't get; `env` still applies
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub unset_env: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cwd: Option<String>,
+    /// Keep the run's file changes only if it exits 0 (session runs only)
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub commit_on_success: bool,
+    /// Record inputs and result into a replay bundle (session runs only)
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub record: bool,
+    /// Input for the command, base64 encoded; it sees EOF after the last byte
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stdin: Option<String>,
+    /// Bytes of stdout and of stderr to return (default 1 MiB, max 16 MiB)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_output_bytes: Option<u64>,
+    /// Once the run's processes together used `time` of CPU they all get
+    /// SIGTERM, then SIGKILL after this many ms (default 2000, max 60000)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub kill_grace_ms: Option<u64>,
+}
+
+impl RunRequest {
+    pub fn new<I, S>(command: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        Self {
+            command: command.into_iter().map(Into::into).collect(),
+            ..Default::default()
+        }
+    }
+
+    /// Run `script` with `/bin/sh -c`.
+    pub fn shell(script: impl Into<String>) -> Self {
+        Self::new(["/bin/sh".to_string(), "-c".to_string(), script.into()])
+    }
+}
+
+/// Result of running a command.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct RunResult {
+    pub stdout: String,
+    pub stderr: String,
+    /// Bytes the command wrote to stdout, including any not returned
+    #[serde(default)]
+    pub stdout_bytes: u64,
+    #[serde(default)]
+    pub stderr_bytes: u64,
+    /// Whether stdout was cut at `max_output_bytes`
+    #[serde(default)]
+    pub stdout_truncated: bool,
+    #[serde(default)]
+    pub stderr_truncated: bool,
+    pub exit_code: Option<i32>,
+    pub signal: Option<i32>,
+    /// For `commit_on_success` runs, whether staged file changes were committed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub committed: Option<bool>,
+    /// For recorded runs, the ID of the replay bundle
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub replay_id: Option<String>,
+    /// Trace the run belongs to, exported to the command as `OC_TRACE_ID`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub trace_id: Option<String>,
+    /// Time from the command starting to it exiting
+    #[serde(default)]
+    pub wall_time_ms: u64,
+    /// CPU time of the command and the children it waited for, in user mode
+    #[serde(default)]
+    pub user_time_ms: u64,
+    /// and in the kernel
+    #[serde(default)]
+    pub sys_time_ms: u64,
+    /// Peak resident set size of the command or of the largest child it
+    /// waited for
+    #[serde(default)]
+    pub max_rss_kb: u64,
+    /// Limit the command was stopped for, if any, so a failure can be told
+    /// apart from running out of time or memory
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub limit_exceeded: Option<LimitExceeded>,
+    /// User + system CPU time of the command and the children it waited for.
+    /// Measured by the server for quotas; not part of the wire format.
+    #[serde(skip)]
+    pub cpu_time: Duration,
+}
+
+impl RunResult {
+    pub fn success(&self) -> bool {
+        self.exit_code == Some(0)
+    }
+}
+
+/// A run limit a command was stopped for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LimitExceeded {
+    /// Its CPU time (`time_ms`)
+    Time,
+    /// Memory: killed by the kernel's OOM killer
+    Memory,
+    /// The largest file it may write (`fsize_kb`)
+    FileSize,
+    /// Processes: a fork failed at its session's `pids_max`. Unlike the
+    /// others this doesn't stop the command, which may have carried on
+    Pids,
+}
+
+impl LimitExceeded {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            LimitExceeded::Time => "time",
+            LimitExceeded::Memory => "memory",
+            LimitExceeded::FileSize => "file_size",
+            LimitExceeded::Pids => "pids",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct BackgroundRunRequest {
+    pub command: Vec<String>,
+    /// Port the process listens on (default 5173); 0 lets the server assign one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub port: Option<u16>,
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub env: HashMap<String, String>,
+    /// Session env vars and secrets the process doesn't get
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub unset_env: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cwd: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BackgroundRunResponse {
+    pub pid: u32,
+    pub port: u16,
+    pub preview_url: Option<String>,
+    /// Trace exported to the process as `OC_TRACE_ID`
+    #[serde(default)]
+    pub trace_id: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct KillBackgroundResponse {
+    pub killed: Vec<u32>,
+    pub total: usize,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BackgroundStatusResponse {
+    pub pids: Vec<BackgroundPidStatus>,
+    pub log: String,
+    /// Process `log` is from: the one asked for with `?pid=`, or the last
+    /// one started
+    #[serde(default)]
+    pub log_pid: Option<u32>,
+    /// Byte offset `log` starts at
+    pub offset: u64,
+    /// Pass as `?offset=` to continue after `log`
+    pub next_offset: u64,
+    /// Oldest offset still kept; output before it was rotated out
+    #[serde(default)]
+    pub log_start: u64,
+    /// Bytes the process has written, rotated ones included
+    pub log_size: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BackgroundPidStatus {
+    pub pid: u32,
+    pub alive: bool,
+}
+
+// Tasks
+
+/// A named command of a session, e.g. `dev`, `test` or `build`.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct TaskDefinition {
+    pub command: Vec<String>,
+    /// Env on top of the session's; a run's own env goes on top of this
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub env: HashMap<String, String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cwd: Option<String>,
+    /// Start it as a background process (e.g. a dev server) rather than run
+    /// it to completion
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub background: bool,
+    /// Port a background task listens on, as for `/background`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub port: Option<u16>,
+    /// Shown to users, e.g. as a button's tooltip
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+}
+
+/// `POST /sessions/:id/tasks`: added to the session's tasks, replacing those
+/// of the same name, or with `replace` swapped in for all of them.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct SetTasksRequest {
+    pub tasks: HashMap<String, TaskDefinition>,
+    /// Drop every task not in `tasks`
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub replace: bool,
+}
+
+/// A task as listed by `GET /sessions/:id/tasks`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TaskInfo {
+    pub name: String,
+    #[serde(flatten)]
+    pub task: TaskDefinition,
+}
+
+/// Body of `POST /sessions/:id/tasks/:name/run`; every field is optional.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct RunTaskRequest {
+    /// Appended to the task's command
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub args: Vec<String>,
+    /// On top of the task's env
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub env: HashMap<String, String>,
+    /// Overrides the task's `background`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub background: Option<bool>,
+}
+
+/// What running a task did: `run` for a task run to completion,
+/// `background` for one started in the background.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TaskRunResponse {
+    pub task: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub run: Option<RunResult>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub background: Option<BackgroundRunResponse>,
+}
+
+// History
+
+/// What ran a command recorded in a session's history.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum HistorySource {
+    /// `POST /sessions/:id/run`, tasks included
+    Run,
+    /// `POST /sessions/:id/jobs`
+    Job,
+    /// gRPC `RunCommand`
+    Grpc,
+    /// A session schedule
+    Schedule,
+}
+
+/// A finished command, as listed by `GET /sessions/:id/history`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HistoryEntry {
+    /// Position in the session's history, from 0
+    pub seq: u64,
+    pub source: HistorySource,
+    /// Argv with secrets masked
+    pub command: Vec<String>,
+    pub cwd: String,
+    pub exit_code: Option<i32>,
+    pub signal: Option<i32>,
+    /// Unix timestamp in milliseconds
+    pub started_at_ms: u64,
+    pub duration_ms: u64,
+    pub stdout_bytes: u64,
+    pub stderr_bytes: u64,
+    /// Whether the kept output misses some of what the command printed
+    #[serde(default)]
+    pub output_truncated: bool,
+    /// Where to fetch the kept output, until newer commands displace it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub output: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub trace_id: Option<String>,
+}
+
+/// `GET /sessions/:id/history` query.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct HistoryQuery {
+    /// Only entries after this `seq`; pass the last page's `next_after`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub after: Option<u64>,
+    /// Entries per page (default 100, max 1000)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub limit: Option<usize>,
+}
+
+/// A page of history, oldest first.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HistoryPage {
+    pub entries: Vec<HistoryEntry>,
+    /// `after` for the next page, if there is one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub next_after: Option<u64>,
+}
+
+/// Output of a command in the history, from `GET
+/// /sessions/:id/history/:seq/output`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HistoryOutput {
+    pub seq: u64,
+    pub stdout: String,
+    pub stderr: String,
+    pub stdout_truncated: bool,
+    pub stderr_truncated: bool,
+}
+
+/// `POST /run-preview`: create a session, upload an app, start its dev server
+/// and wait until it serves. Session options sit at the top level.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct RunPreviewRequest {
+    #[serde(flatten)]
+    pub session: CreateSessionRequest,
+    /// tar or tar.gz of the app, base64 encoded
+    pub archive: String,
+    /// Directory the archive is unpacked into (default `/home/app`); also
+    /// the command's cwd
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dir: Option<String>,
+    /// Dev server command, started in the background
+    pub command: Vec<String>,
+    /// Port the server listens on; 0 or unset lets the server assign one,
+    /// exported as `PORT`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub port: Option<u16>,
+    /// Path polled until it answers; without it an open port counts as ready
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ready_path: Option<String>,
+    /// Seconds to wait for readiness (default 60)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ready_timeout: Option<u64>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RunPreviewResponse {
+    pub session_id: String,
+    pub preview_url: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub preview_token: Option<String>,
+    pub pid: u32,
+    pub port: u16,
+    pub trace_id: String,
+    /// Time from process start until the server answered
+    pub ready_ms: u64,
+    pub capacity: Capacity,
+}
+
+/// `POST /sessions/:id/static`: serve a directory of built files at the
+/// session's preview URL, with no server process in the sandbox.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StaticSite {
+    /// Directory to serve, e.g. `dist`; relative to the session's cwd
+    /// unless absolute. Returned absolute.
+    pub dir: String,
+    /// Answer paths that match no file with the directory's `index.html`,
+    /// for client-side routing (default true)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub spa: Option<bool>,
+}
+
+// Kernels
+
+/// `POST /sessions/:id/kernel/python/execute`: run a cell in the session's
+/// long-lived interpreter.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct KernelExecuteRequest {
+    pub code: String,
+    /// Wall-clock limit in ms (default 30000, max 300000). A cell that runs
+    /// over is interrupted with `KeyboardInterrupt`; the kernel keeps its state
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timeout_ms: Option<u64>,
+    /// Bytes of stdout and of stderr to return (default 1 MiB, max 16 MiB)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_output_bytes: Option<u64>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct KernelExecuteResult {
+    /// Cells this kernel has run, this one included
+    pub execution_count: u64,
+    pub stdout: String,
+    pub stderr: String,
+    /// Bytes the cell wrote to stdout, including any not returned
+    #[serde(default)]
+    pub stdout_bytes: u64,
+    #[serde(default)]
+    pub stderr_bytes: u64,
+    #[serde(default)]
+    pub stdout_truncated: bool,
+    #[serde(default)]
+    pub stderr_truncated: bool,
+    /// `repr()` of the cell's last expression, unless it is `None`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub result: Option<String>,
+    /// Open matplotlib figures, and the last expression if it has `_repr_png_`
+    #[serde(default)]
+    pub images: Vec<KernelImage>,
+    /// Set when the cell raised
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<KernelError>,
+    /// Whether the cell was interrupted for running past `timeout_ms`
+    #[serde(default)]
+    pub timed_out: bool,
+    /// Whether this call started the kernel, i.e. no earlier state exists
+    #[serde(default)]
+    pub started: bool,
+    #[serde(default)]
+    pub duration_ms: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct KernelImage {
+    /// e.g. `image/png`
+    pub mime: String,
+    /// Base64 encoded image bytes
+    pub data: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct KernelError {
+    /// Exception type, e.g. `ZeroDivisionError`
+    pub ename: String,
+    pub evalue: String,
+    /// Formatted traceback lines, starting at the cell
+    pub traceback: Vec<String>,
+}
+
+// Packages
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PackageManager {
+    Pip,
+    Npm,
+    /// Rejected: a sandbox's `/usr` is the host's, read-only
+    Apt,
+}
+
+/// `POST /sessions/:id/packages`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct InstallPackagesRequest {
+    pub manager: PackageManager,
+    /// Package specs, e.g. `requests==2.32.3` or `lodash@^4`; no paths or URLs
+    pub packages: Vec<String>,
+    /// Directory npm installs into (default the session's cwd)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dir: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct InstallPackagesResult {
+    pub installed: bool,
+    /// Whether everything came from the shared cache, with nothing downloaded
+    pub cache_hit: bool,
+    /// Output of the step that decided the outcome: the install, or the
+    /// download into the cache if that failed
+    pub stdout: String,
+    pub stderr: String,
+    /// Exit code of the install; `None` if it never ran or was killed
+    pub exit_code: Option<i32>,
+    pub duration_ms: u64,
+}
+
+// Build cache
+
+/// A directory the build cache keeps, and the lockfiles it's keyed on (the
+/// first one found in the project directory).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BuildCacheKind {
+    /// `node_modules`, keyed on `package-lock.json`, `yarn.lock` or
+    /// `pnpm-lock.yaml`
+    NodeModules,
+    /// `.venv`, keyed on `poetry.lock`, `uv.lock`, `Pipfile.lock` or
+    /// `requirements.txt`
+    Venv,
+    /// `target`, keyed on `Cargo.lock`
+    CargoTarget,
+}
+
+/// `POST /sessions/:id/build-cache/restore` and `.../save`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BuildCacheRequest {
+    pub kind: BuildCacheKind,
+    /// Project directory holding the lockfile (default the session's cwd)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dir: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BuildCacheResult {
+    pub kind: BuildCacheKind,
+    /// Lockfile the key was taken from, e.g. `package-lock.json`
+    pub lockfile: String,
+    /// Hex SHA-256 of the kind and the lockfile's name and contents
+    pub key: String,
+    /// Restore: an entry was found and mounted. Save: one already existed,
+    /// so nothing was copied
+    pub hit: bool,
+    /// The cached directory in the sandbox, e.g. `/app/node_modules`
+    pub path: String,
+    /// Size of the entry; 0 on a restore miss
+    pub size_bytes: u64,
+    pub duration_ms: u64,
+}
+
+// Git
+
+/// A session secret to answer a remote's username/password prompt with.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GitCredential {
+    /// Name of the session secret holding the password or token
+    pub secret: String,
+    /// Username sent with it (default `x-access-token`)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub username: Option<String>,
+}
+
+/// `POST /sessions/:id/git/clone`
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct GitCloneRequest {
+    pub url: String,
+    /// Directory to clone into, relative to the session's cwd (default named
+    /// after the repository, as `git clone` does)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dir: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub branch: Option<String>,
+    /// Fetch only this many commits
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub depth: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub credential: Option<GitCredential>,
+}
+
+/// `POST /sessions/:id/git/pull`
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct GitPullRequest {
+    /// Repository directory (default the session's cwd)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dir: Option<String>,
+    /// Remote to pull from (default the branch's upstream)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub remote: Option<String>,
+    /// Remote branch to pull; needs `remote`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub branch: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub credential: Option<GitCredential>,
+}
+
+/// `POST /sessions/:id/git/commit`
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct GitCommitRequest {
+    /// Repository directory (default the session's cwd)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dir: Option<String>,
+    pub message: String,
+    /// Paths to stage (default everything, including new and deleted files)
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub paths: Vec<String>,
+    /// Author and committer (default the repository's configured identity)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub author_name: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub author_email: Option<String>,
+}
+
+/// Outcome of a clone, pull or commit.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct GitResult {
+    pub success: bool,
+    /// `HEAD` of the repository afterwards, if it has one
+    pub commit: Option<String>,
+    /// Output of the git command that decided the outcome
+    pub stdout: String,
+    pub stderr: String,
+    pub exit_code: Option<i32>,
+    pub duration_ms: u64,
+}
+
+/// Query of `GET /sessions/:id/git/diff`.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct GitDiffQuery {
+    /// Repository directory (default the session's cwd)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dir: Option<String>,
+    /// Commit to diff the working tree against (default `HEAD`, or the empty
+    /// tree in a repository without commits)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub base: Option<String>,
+    /// Bytes of the patch to return (default 1 MiB, max 16 MiB)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_output_bytes: Option<u64>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct GitDiffResult {
+    /// Changes of the working tree since `base`, new files included, as a
+    /// patch `git apply` accepts (binary files as binary patches)
+    pub diff: String,
+    /// Size of the whole patch, including any part not returned
+    pub diff_bytes: u64,
+    /// Whether `diff` was cut at `max_output_bytes`
+    pub truncated: bool,
+}
+
+/// What `GET /sessions/:id/export/patch` returns.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PatchFormat {
+    /// A patch `git apply` (or `patch -p1`) accepts
+    #[default]
+    Diff,
+    /// A git bundle of the commits since `base` (repositories only)
+    Bundle,
+}
+
+/// Query of `GET /sessions/:id/export/patch`.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct PatchExportQuery {
+    /// Directory to export, paths in the patch being relative to it; in a
+    /// repository, anywhere in it (default the session's cwd)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dir: Option<String>,
+    /// Commit to export changes since, in a repository (default the branch's
+    /// upstream, else `HEAD`)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub base: Option<String>,
+    #[serde(default)]
+    pub format: PatchFormat,
+}
+
+// Project import
+
+/// What `POST /sessions/:id/import` fetches.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ImportKind {
+    /// A ref of a git repository
+    Git,
+    /// A tar or tar.gz archive over HTTP(S)
+    Archive,
+}
+
+impl ImportKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ImportKind::Git => "git",
+            ImportKind::Archive => "archive",
+        }
+    }
+}
+
+/// `POST /sessions/:id/import`
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ImportRequest {
+    /// Archive URL or git remote
+    pub url: String,
+    /// Branch, tag or commit to fetch (git only; default the remote's `HEAD`)
+    #[serde(default, rename = "ref", skip_serializing_if = "Option::is_none")]
+    pub git_ref: Option<String>,
+    /// Import only this directory of the archive or repository
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub subdir: Option<String>,
+    /// Where to put it, relative to the session's cwd; must be missing or
+    /// empty (default named after the URL)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dir: Option<String>,
+    /// Guessed from the URL if unset: `git` for `.git`, `git@`, `git://` and
+    /// `ssh://` URLs
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub kind: Option<ImportKind>,
+    /// Session secret to answer the remote's password prompt with (git only)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub credential: Option<GitCredential>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ImportResult {
+    /// Where it was put
+    pub path: String,
+    pub kind: ImportKind,
+    /// Commit checked out, for git imports
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub commit: Option<String>,
+    /// Regular files imported
+    pub files: u64,
+    /// Their total size
+    pub bytes: u64,
+    pub duration_ms: u64,
+}
+
+// Files
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WriteFileRequest {
+    pub path: String,
+    /// Base64 encoded
+    pub content: String,
+    #[serde(default)]
+    pub mode: WriteMode,
+    /// Write `content` at this byte offset, leaving the rest of the file in
+    /// place, e.g. for one chunk of an upload. Only with `overwrite`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub offset: Option<u64>,
+}
+
+/// How a file write treats what's already in the file.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WriteMode {
+    /// Replace the file, or the bytes at `offset`
+    #[default]
+    Overwrite,
+    /// Add to the end of the file
+    Append,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WriteFileResponse {
+    pub success: bool,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct WriteFilesRequest {
+    pub files: Vec<WriteFileRequest>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WriteFilesResponse {
+    pub success: bool,
+    pub errors: Vec<WriteFileError>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WriteFileError {
+    pub path: String,
+    pub error: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReadFileResponse {
+    /// Base64 encoded
+    pub content: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FileEntry {
+    pub name: String,
+    pub path: String,
+    pub is_directory: bool,
+    pub size: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ListFilesResponse {
+    pub files: Vec<FileEntry>,
+}
+
+/// Digest algorithms for `GET /sessions/:id/files/hash`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HashAlgo {
+    #[default]
+    Sha256,
+    Sha512,
+    Sha1,
+    /// What S3 reports as the ETag of a single-part upload
+    Md5,
+}
+
+/// Query for `GET /sessions/:id/files/hash`.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct FileHashQuery {
+    pub path: String,
+    #[serde(default)]
+    pub algo: HashAlgo,
+}
+
+/// Body of `POST /sessions/:id/files/hash`.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct FileHashesRequest {
+    pub paths: Vec<String>,
+    #[serde(default)]
+    pub algo: HashAlgo,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FileHash {
+    pub path: String,
+    pub algo: HashAlgo,
+    /// Lowercase hex
+    pub hash: String,
+    pub size: u64,
+}
+
+/// Hashes of the files that could be read; the rest are in `errors`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FileHashesResponse {
+    pub hashes: Vec<FileHash>,
+    pub errors: Vec<FileHashError>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FileHashError {
+    pub path: String,
+    pub error: String,
+}
+
+// Templates
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RegisterTemplateRequest {
+    pub name: String,
+    /// Directory on the server to copy, inside one of its template source dirs
+    pub path: String,
+}
+
+/// Template summary returned by `GET /templates`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TemplateInfo {
+    pub name: String,
+    pub files: u64,
+    pub size_bytes: u64,
+}
+
+// Progress events
+
+/// Update on a long filesystem operation, streamed by `GET /events`. Every
+/// update of one operation shares `operation_id`; the last has `done` set.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ProgressEvent {
+    pub operation_id: String,
+    /// `template.register`, `replay.restore`, `preview.extract`,
+    /// `packages.install` or `image.pull`
+    pub operation: String,
+    /// `extract` (archive bytes consumed), `copy` (files copied), for
+    /// package installs `install` and `download`, and for image pulls
+    /// `download` (layer bytes received) and `extract`; counters restart at
+    /// each stage
+    pub stage: String,
+    /// Template the operation concerns, if any
+    pub template: Option<String>,
+    pub session_id: Option<String>,
+    pub bytes: u64,
+    pub total_bytes: Option<u64>,
+    pub files: u64,
+    pub total_files: Option<u64>,
+    /// 0-100, when the total is known
+    pub percent: Option<f64>,
+    pub done: bool,
+    pub error: Option<String>,
+}
+
+// Record/replay
+
+/// Outcome of replaying a bundle.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReplayOutcome {
+    pub recorded: RunResult,
+    pub replayed: RunResult,
+    /// Whether exit code, signal and stdout are identical. stderr is not
+    /// compared since it carries sandbox paths.
+    pub matches: bool,
+    /// What the recorded run fetched through its session's egress proxy.
+    /// Replays don't refetch them, so a mismatch may come from the network.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub fetches: Vec<EgressAttempt>,
+}
+
+// Checkpoints
+
+/// Optional body of `POST /sessions/:id/checkpoints`.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct CreateCheckpointRequest {
+    /// Free-form note, e.g. the task step the checkpoint precedes
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub label: Option<String>,
+}
+
+/// A saved copy of a session's files.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CheckpointInfo {
+    pub id: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub label: Option<String>,
+    /// Unix seconds
+    pub created_at: u64,
+    /// Regular files captured
+    pub files: u64,
+    /// Total size of those files
+    pub bytes: u64,
+}
+
+/// `GET /sessions/:id/checkpoints/:a/diff/:b` query.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct CheckpointDiffQuery {
+    /// Only report paths under this directory
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub path: Option<String>,
+    /// Include unified diffs of small text files
+    #[serde(default)]
+    pub content: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ChangeKind {
+    Added,
+    Removed,
+    Modified,
+}
+
+/// One path that differs between two checkpoints or directories.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FileChange {
+    pub path: String,
+    pub change: ChangeKind,
+    /// "file", "dir" or "symlink" (the newer kind if it changed)
+    pub kind: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub size_before: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub size_after: Option<u64>,
+    /// Set when only the permission bits differ
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub mode_only: bool,
+    /// Unified diff, for text files when `content=true` and both sides are small
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub diff: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct DiffSummary {
+    pub added: u64,
+    pub removed: u64,
+    pub modified: u64,
+    /// Total size of regular files on each side (within `path`)
+    pub bytes_before: u64,
+    pub bytes_after: u64,
+}
+
+/// Tree diff from checkpoint `from` to checkpoint `to`, sorted by path.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CheckpointDiff {
+    pub from: String,
+    pub to: String,
+    pub summary: DiffSummary,
+    pub changes: Vec<FileChange>,
+}
+
+/// Body of `POST /sessions/:id/files/diff`: compares `to` with another
+/// path, `from`, or with its own copy in `checkpoint`. Set one of the two.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct FilesDiffRequest {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub from: Option<String>,
+    /// ID of a checkpoint of the session
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub checkpoint: Option<String>,
+    /// A directory or file
+    pub to: String,
+    /// Include unified diffs of small text files
+    #[serde(default)]
+    pub content: bool,
+}
+
+/// Tree diff from `from` (or the checkpoint) to `to`, sorted by path. Paths
+/// are where each entry is, or would be, under `to`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FilesDiff {
+    pub summary: DiffSummary,
+    pub changes: Vec<FileChange>,
+}
+
+/// `GET /sessions/:id/changes` query.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct SessionChangesQuery {
+    /// Only report paths under this directory
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub path: Option<String>,
+    /// Include the SHA-256 of each added or modified file
+    #[serde(default)]
+    pub hash: bool,
+}
+
+/// A path that differs from when its session was created.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChangedFile {
+    pub path: String,
+    pub change: ChangeKind,
+    /// "file", "dir" or "symlink" (what was there, if removed)
+    pub kind: String,
+    /// Current size of a regular file
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub size: Option<u64>,
+    /// Unix seconds of the last change to the entry, unless removed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub modified_at: Option<u64>,
+    /// Set when only the permission bits differ
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub mode_only: bool,
+    /// With `hash=true`, for added and modified regular files
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sha256: Option<String>,
+}
+
+/// Files changed since the session was created, sorted by path.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SessionChanges {
+    pub summary: DiffSummary,
+    pub changes: Vec<ChangedFile>,
+}
+
+/// Body of `POST /sessions/:id/files/delete`.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct DeleteFileRequest {
+    pub path: String,
+    /// Required to delete a directory that isn't empty
+    #[serde(default)]
+    pub recursive: bool,
+    /// Skip the trash of a session created with `trash_ttl`
+    #[serde(default)]
+    pub permanent: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DeleteFileResponse {
+    pub path: String,
+    /// ID to restore the entry with, if it went to the trash
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub trash_id: Option<String>,
+}
+
+/// An entry in a session's trash, listed by `GET /sessions/:id/files/trash`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TrashEntry {
+    pub id: String,
+    /// Where it was deleted from
+    pub path: String,
+    /// `file`, `dir` or `symlink`
+    pub kind: String,
+    /// Size of a file; 0 for directories and symlinks
+    pub size: u64,
+    /// Unix timestamp (seconds)
+    pub deleted_at: u64,
+    /// Unix timestamp (seconds) after which it's purged
+    pub expires_at: u64,
+}
+
+/// Body of `POST /sessions/:id/files/restore`.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct RestoreFileRequest {
+    /// `trash_id` of the deletion
+    pub id: String,
+    /// Where to restore it instead of the path it was deleted from
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub to: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RestoreFileResponse {
+    /// Where the entry was restored
+    pub path: String,
+}
+
+// Directory sync
+
+/// A regular file in a sync manifest.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct SyncFile {
+    /// Below the synced directory, e.g. `src/main.rs`
+    pub path: String,
+    pub size: u64,
+    /// Lowercase hex SHA-256
+    pub sha256: String,
+    /// Permission bits, e.g. `0o755`; a pushed file keeps its mode if unset
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mode: Option<u32>,
+    /// Base64 content, for files the other side needs
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub content: Option<String>,
+}
+
+/// Body of `POST /sessions/:id/files/sync` and `POST /sessions/:id/files/sync/pull`.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct SyncRequest {
+    /// Directory in the sandbox to sync
+    pub path: String,
+    /// Every file the client has
+    pub files: Vec<SyncFile>,
+    /// Push only: once nothing more is needed, remove what's under `path`
+    /// and not in `files`
+    #[serde(default)]
+    pub delete: bool,
+}
+
+/// Response of `POST /sessions/:id/files/sync`.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct SyncPushResponse {
+    /// Files that differ and came without content; send them next
+    pub need: Vec<String>,
+    /// Files written from content in the request
+    pub written: Vec<String>,
+    /// What `delete` removed, files and directories
+    pub deleted: Vec<String>,
+}
+
+/// Response of `POST /sessions/:id/files/sync/pull`.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct SyncPullResponse {
+    /// Files the client lacks or has different content for. Content is
+    /// left out past a per-response budget, and always for large files.
+    pub files: Vec<SyncFile>,
+    /// Files the client has that the sandbox doesn't
+    pub removed: Vec<String>,
+    /// Whether some of `files` were left without content for the budget; a
+    /// pull with the updated manifest returns them
+    pub truncated: bool,
+}
+
+// Resumable uploads
+
+/// Body of `POST /sessions/:id/uploads`.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct CreateUploadRequest {
+    /// Where the file goes once finalized
+    pub path: String,
+    /// Total size in bytes
+    pub size: u64,
+    /// Hex SHA-256 the finished file must have, checked on finalize
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sha256: Option<String>,
+}
+
+/// A resumable upload, returned when it's created and by
+/// `GET /sessions/:id/uploads/:upload_id`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UploadStatus {
+    pub id: String,
+    pub path: String,
+    pub size: u64,
+    /// Bytes received so far; the next chunk starts here
+    pub offset: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sha256: Option<String>,
+    /// Unix timestamp (seconds)
+    pub created_at: u64,
+    /// Unix timestamp (seconds) after which an unfinished upload is removed
+    pub expires_at: u64,
+}
+
+/// Response of `POST /sessions/:id/uploads/:upload_id/finalize`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FinalizeUploadResponse {
+    pub path: String,
+    pub size: u64,
+    /// Hex SHA-256 of the file
+    pub sha256: String,
+}
+
+// Schedules
+
+/// Cron expression to check with `POST /schedules/validate`.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ValidateScheduleRequest {
+    /// Five fields (`min hour dom month dow`), or six/seven with seconds and year
+    pub expression: String,
+    /// IANA timezone, e.g. `Europe/Berlin`; overrides the session's `TZ`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timezone: Option<String>,
+    /// Session whose `TZ` env var supplies the timezone
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub session_id: Option<String>,
+    /// Fire times to preview (default 5, at most 100)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub count: Option<usize>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ValidateScheduleResponse {
+    pub valid: bool,
+    /// Why the expression or timezone was rejected
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// Timezone the fire times are expressed in
+    pub timezone: String,
+    /// Upcoming fire times, RFC 3339 with the timezone's offset
+    pub next: Vec<String>,
+}
+
+/// `POST /sessions/:id/schedules`: a command the server runs in the session
+/// each time `expression` fires, for as long as the session lives.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ScheduleDefinition {
+    /// Cron expression, as for `POST /schedules/validate`
+    pub expression: String,
+    /// IANA timezone; defaults to the session's `TZ`, else UTC
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timezone: Option<String>,
+    pub command: Vec<String>,
+    /// On top of the session env
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub env: HashMap<String, String>,
+    /// Defaults to the session's cwd at the time of each run
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cwd: Option<String>,
+    /// CPU time limit of each run in milliseconds, as `time` for `/run`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub time: Option<u64>,
+}
+
+/// How one run of a schedule went.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ScheduledRun {
+    /// RFC 3339, UTC
+    pub started_at: String,
+    pub duration_ms: u64,
+    pub exit_code: Option<i32>,
+    pub signal: Option<i32>,
+    /// Why the command couldn't run, e.g. the session was paused
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub trace_id: Option<String>,
+}
+
+/// A schedule of a session and its last runs.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ScheduleInfo {
+    pub schedule_id: String,
+    /// The definition, with `timezone` filled in
+    #[serde(flatten)]
+    pub schedule: ScheduleDefinition,
+    /// Next fire time, RFC 3339 with the timezone's offset; none if the
+    /// expression never fires again
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub next_run: Option<String>,
+    /// Whether a run is going on; fire times that come meanwhile are skipped
+    pub running: bool,
+    /// The last runs, oldest first
+    pub runs: Vec<ScheduledRun>,
+}
+
+// Egress
+
+/// Domains a session may reach through the egress proxy. Entries are domain
+/// names, or `*.example.com` for any subdomain of `example.com`.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct EgressPolicy {
+    /// Only these may be reached; empty allows every domain not denied
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub allow: Vec<String>,
+    /// Never reached, even if allowed
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub deny: Vec<String>,
+}
+
+/// `GET /sessions/:id/egress`: the session's rules and where it tried to go.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EgressReport {
+    pub policy: EgressPolicy,
+    /// Recent connections through the proxy, oldest first
+    pub attempts: Vec<EgressAttempt>,
+}
+
+/// A connection a session asked the egress proxy for.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EgressAttempt {
+    /// Unix milliseconds
+    pub at_ms: u64,
+    pub host: String,
+    pub port: u16,
+    /// Full URL of a plain `http://` request; HTTPS tunnels only show host
+    /// and port
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub url: Option<String>,
+    pub allowed: bool,
+    /// Why it was refused
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+}
+
+// SSH
+
+/// Lifetime of a key minted with `POST /sessions/:id/ssh-keys`.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct CreateSshKeyRequest {
+    /// Lifetime in seconds (default: until the session ends)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ttl: Option<u64>,
+}
+
+/// A freshly minted SSH key. The private key is only ever returned here.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CreateSshKeyResponse {
+    pub key_id: String,
+    /// Ed25519 key in OpenSSH format, for `ssh -i`
+    pub private_key: String,
+    /// `ssh-ed25519 AAAA...`
+    pub public_key: String,
+    /// `SHA256:...`
+    pub fingerprint: String,
+    /// User to log in as: the session ID
+    pub username: String,
+    /// Port the SSH server listens on
+    pub port: u16,
+    pub expires_in_secs: Option<u64>,
+}
+
+/// A session's SSH key, as listed by `GET /sessions/:id/ssh-keys`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SshKey {
+    pub key_id: String,
+    pub public_key: String,
+    pub fingerprint: String,
+    pub expires_in_secs: Option<u64>,
+}
+
+// Disk usage
+
+/// `GET /sessions/:id/usage` query.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct DiskUsageQuery {
+    /// Directories listed (default 10, max 100)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub top: Option<usize>,
+    /// Deepest directories considered, `/` being 0 (default 3, max 16)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub depth: Option<usize>,
+    /// Walk the files again instead of using a walk up to a minute old
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub refresh: bool,
+}
+
+/// What a session's files take on disk. Only the session's own files count:
+/// those it created or changed, not its template's or the host's.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DiskUsage {
+    /// Used space on the session's tmpfs, read at request time
+    pub disk_bytes: u64,
+    /// Size of that tmpfs
+    pub disk_limit_bytes: u64,
+    /// Files, directories and links found by the walk
+    pub files: u64,
+    /// Space they take, counting hard-linked files once
+    pub file_bytes: u64,
+    /// Largest directories down to `depth`, biggest first, with everything
+    /// beneath them counted
+    pub largest_dirs: Vec<DirUsage>,
+    /// Whether the walk stopped early at its entry limit
+    pub truncated: bool,
+    /// Age of the walk behind `files`, `file_bytes` and `largest_dirs`
+    pub walked_secs_ago: u64,
+    pub walk_ms: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DirUsage {
+    /// Path inside the sandbox
+    pub path: String,
+    pub bytes: u64,
+    pub files: u64,
+}
+
+// Resource stats
+
+/// Resource use of a session's processes, sampled from `/proc`.
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SessionStats {
+    /// CPU time used since the session started
+    pub cpu_secs: f64,
+    /// CPU use over the last sample interval; 100 is one full core
+    pub cpu_percent: f64,
+    /// Summed RSS of the session's processes
+    pub rss_bytes: u64,
+    /// Highest `rss_bytes` seen
+    pub peak_rss_bytes: u64,
+    pub processes: u32,
+    /// Bytes read and written through syscalls since the session started,
+    /// whether from files, pipes or sockets
+    pub read_bytes: u64,
+    pub write_bytes: u64,
+}
+
+/// `GET /sessions/:id/stats`: current figures and how they got there.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SessionStatsHistory {
+    pub stats: SessionStats,
+    /// Time between samples
+    pub interval_ms: u64,
+    /// Recent samples, oldest first
+    pub samples: Vec<StatsSample>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StatsSample {
+    /// Unix milliseconds
+    pub at_ms: u64,
+    #[serde(flatten)]
+    pub stats: SessionStats,
+}
+
+// Session timeline
+
+/// `GET /sessions/:id/events` (and `/stream`) query.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct SessionEventsQuery {
+    /// Only events with a larger `seq`, e.g. the last one a client saw
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub after: Option<u64>,
+}
+
+/// Something that happened to a session.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SessionEvent {
+    /// Increases by one per event of the session
+    pub seq: u64,
+    /// Unix milliseconds
+    pub at_ms: u64,
+    #[serde(flatten)]
+    pub kind: SessionEventKind,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum SessionEventKind {
+    Created {
+        template: Option<String>,
+    },
+    /// A foreground run began; `command` has secrets masked
+    RunStarted {
+        command: Vec<String>,
+    },
+    RunFinished {
+        command: Vec<String>,
+        exit_code: Option<i32>,
+        signal: Option<i32>,
+        duration_ms: u64,
+    },
+    BackgroundStarted {
+        pid: u32,
+        port: u16,
+        command: Vec<String>,
+    },
+    /// A background process exited with status 0
+    ProcessExited {
+        pid: u32,
+        port: u16,
+    },
+    /// A background process exited with another status or was killed
+    ProcessCrashed {
+        pid: u32,
+        port: u16,
+        exit_code: Option<i32>,
+        signal: Option<i32>,
+    },
+    /// A port was attached to the session (for previews)
+    PortRegistered {
+        port: u16,
+        /// Found listening by the port scan rather than registered by
+        /// `/background`
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        detected: bool,
+    },
+    PortReleased {
+        port: u16,
+    },
+    /// The session will expire soon unless it is used or kept alive
+    TtlWarning {
+        expires_in_secs: u64,
+    },
+    Paused,
+    Resumed,
+    /// Background processes were dumped to disk
+    Hibernated {
+        pids: Vec<u32>,
+    },
+    /// Dumped background processes were restored
+    Woken {
+        pids: Vec<u32>,
+    },
+    /// The last event: the session was reaped after its TTL
+    Expired,
+    /// The last event: the session was deleted
+    Deleted,
+    /// The last event: the session was removed to make room for a new one
+    Evicted,
+}
+
+// Jobs
+
+/// `GET /jobs/:id` query: where each stream's output resumes, e.g. the
+/// offsets of the last response.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct JobQuery {
+    #[serde(default)]
+    pub stdout_offset: u64,
+    #[serde(default)]
+    pub stderr_offset: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum JobStatus {
+    Running,
+    /// Exited 0
+    Succeeded,
+    /// Exited non-zero, was killed by a signal, or couldn't be run
+    Failed,
+    /// Killed by `DELETE /jobs/:id`
+    Cancelled,
+}
+
+/// A command started by `POST /sessions/:id/jobs`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Job {
+    pub job_id: String,
+    pub session_id: String,
+    /// The command, secrets masked
+    pub command: Vec<String>,
+    pub status: JobStatus,
+    /// Output from the requested offset on. While the job runs, a trailing
+    /// partial character or partial secret is held back until it's whole.
+    pub stdout: String,
+    pub stderr: String,
+    /// Offsets to ask for next
+    pub stdout_offset: u64,
+    pub stderr_offset: u64,
+    /// Bytes the command wrote to stdout so far, including any not kept
+    pub stdout_bytes: u64,
+    pub stderr_bytes: u64,
+    /// Whether stdout was cut at `max_output_bytes`
+    pub stdout_truncated: bool,
+    pub stderr_truncated: bool,
+    pub exit_code: Option<i32>,
+    pub signal: Option<i32>,
+    /// For `commit_on_success` jobs, whether staged file changes were committed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub committed: Option<bool>,
+    /// Why a failed job couldn't be run
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// Trace the job belongs to, exported to the command as `OC_TRACE_ID`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub trace_id: Option<String>,
+    /// Time since the job started, or how long it ran once it's done
+    pub duration_ms: u64,
+}
+
+impl Job {
+    pub fn is_done(&self) -> bool {
+        self.status != JobStatus::Running
+    }
+}
+
+// Admin overview
+
+/// `GET /admin/overview` query.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct OverviewQuery {
+    /// Sessions listed per top-consumer ranking (default 5, max 50)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub top: Option<usize>,
+    /// How long CPU usage is sampled over, in ms (default 200, max 2000)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sample_ms: Option<u64>,
+}
+
+/// Host-wide resource usage at a glance, from `GET /admin/overview`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Overview {
+    /// Unix seconds
+    pub generated_at: u64,
+    pub host: HostOverview,
+    /// Sums over every session
+    pub totals: SessionUsageTotals,
+    /// Sessions by ID
+    pub sessions: Vec<SessionUsage>,
+    pub top: TopConsumers,
+    pub ports: PortStats,
+    pub pool: PoolStats,
+    pub queues: QueueDepths,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HostOverview {
+    /// Online CPUs
+    pub cpus: usize,
+    /// 1, 5 and 15 minute load averages
+    pub load_average: [f64; 3],
+    pub mem_total_bytes: u64,
+    pub mem_available_bytes: u64,
+    /// Capacity session reservations are checked against
+    pub capacity: Resources,
+    /// Reservations of live sessions
+    pub reserved: Resources,
+    pub overcommit_ratio: f64,
+}
+
+/// What one session's processes and files use right now.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SessionUsage {
+    pub id: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub slug: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub org_id: Option<String>,
+    pub status: SessionStatus,
+    /// CPU use over the sample window; 100 is one full core
+    pub cpu_percent: f64,
+    /// CPU time consumed by the session's live processes
+    pub cpu_secs: f64,
+    /// Resident memory of the session's processes
+    pub mem_bytes: u64,
+    /// Used space on the session's tmpfs
+    pub disk_bytes: u64,
+    pub processes: usize,
+    pub ports: Vec<u16>,
+    pub idle_secs: u64,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct SessionUsageTotals {
+    pub sessions: usize,
+    /// Sessions per status
+    pub by_status: HashMap<String, usize>,
+    pub cpu_percent: f64,
+    pub mem_bytes: u64,
+    pub disk_bytes: u64,
+    pub processes: usize,
+}
+
+/// Heaviest sessions by each resource, heaviest first.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct TopConsumers {
+    pub cpu: Vec<SessionUsage>,
+    pub memory: Vec<SessionUsage>,
+    pub disk: Vec<SessionUsage>,
+}
+
+/// State of the background-process port range.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PortStats {
+    pub range_start: u16,
+    pub range_end: u16,
+    /// Ports held by sessions
+    pub in_use: usize,
+    /// Released ports waiting to be reused
+    pub free: usize,
+    /// Ports never handed out yet
+    pub untouched: usize,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct QueueDepths {
+    /// Session creates in progress
+    pub session_creates: usize,
+    /// Lifecycle notifications (e.g. webhooks) queued or being delivered
+    pub lifecycle_deliveries: usize,
+}
+
+// Operator API
+
+/// Filters for `GET /admin/sessions`.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct AdminSessionsQuery {
+    /// Only this org's sessions
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub org: Option<String>,
+}
+
+/// A session of any org, from `GET /admin/sessions`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AdminSessionInfo {
+    pub org_id: Option<String>,
+    /// Name of the API key that created it
+    pub key_name: Option<String>,
+    #[serde(flatten)]
+    pub info: SessionInfo,
+}
+
+/// An org's quotas and what it uses of them, from `GET /admin/orgs`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OrgUsage {
+    pub org_id: String,
+    pub sessions: usize,
+    /// Tmpfs usage of its live sessions
+    pub disk_bytes: u64,
+    /// CPU time of its runs since the server started
+    pub cpu_secs: f64,
+    pub max_sessions: Option<usize>,
+    pub max_disk_mb: Option<u64>,
+    pub max_cpu_secs: Option<u64>,
+}
+
+/// Result of `POST /admin/reload`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReloadResponse {
+    pub keys: usize,
+    /// Orgs with quotas configured
+    pub orgs: usize,
+}
+
+/// Result of `POST /admin/drain`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DrainResponse {
+    pub draining: bool,
+    /// Sessions still live; they run until deleted or expired
+    pub sessions: usize,
+}
+
+// Server events
+
+/// Filters for `GET /events/stream`.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ServerEventsQuery {
+    /// Comma-separated event types or groups, e.g. `session,run.finished`
+    /// (default all)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub types: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub session_id: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub org: Option<String>,
+}
+
+/// One message of `GET /events/stream`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ServerEvent {
+    /// Unix milliseconds
+    pub at_ms: u64,
+    /// `None` for stateless `/run`
+    pub session_id: Option<String>,
+    pub org_id: Option<String>,
+    #[serde(flatten)]
+    pub kind: ServerEventKind,
+}
+
+/// What happened, tagged `type` as `{group}.{event}`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum ServerEventKind {
+    #[serde(rename = "session.created")]
+    SessionCreated {
+        slug: Option<String>,
+        labels: HashMap<String, String>,
+    },
+    #[serde(rename = "session.expired")]
+    SessionExpired,
+    #[serde(rename = "session.deleted")]
+    SessionDeleted,
+    #[serde(rename = "session.evicted")]
+    SessionEvicted,
+    #[serde(rename = "run.started")]
+    RunStarted {
+        /// With session secrets masked
+        command: Vec<String>,
+        cwd: String,
+        background: bool,
+    },
+    #[serde(rename = "run.finished")]
+    RunFinished {
+        exit_code: Option<i32>,
+        signal: Option<i32>,
+        duration_ms: u64,
+        cpu_ms: u64,
+    },
+    #[serde(rename = "background.exited")]
+    BackgroundExited {
+        pid: u32,
+        port: u16,
+        exit_code: Option<i32>,
+        signal: Option<i32>,
+    },
+    /// A request through the preview proxy, once its response started
+    #[serde(rename = "proxy.request")]
+    ProxyRequest {
+        /// `None` for static sites
+        port: Option<u16>,
+        method: String,
+        /// Without the query
+        path: String,
+        status: u16,
+        /// Until the response headers
+        duration_ms: u64,
+        websocket: bool,
+    },
+}
+
+impl ServerEventKind {
+    pub const TYPES: &'static [&'static str] = &[
+        "session.created",
+        "session.expired",
+        "session.deleted",
+        "session.evicted",
+        "run.started",
+        "run.finished",
+        "background.exited",
+        "proxy.request",
+    ];
+
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            ServerEventKind::SessionCreated { .. } => "session.created",
+            ServerEventKind::SessionExpired => "session.expired",
+            ServerEventKind::SessionDeleted => "session.deleted",
+            ServerEventKind::SessionEvicted => "session.evicted",
+            ServerEventKind::RunStarted { .. } => "run.started",
+            ServerEventKind::RunFinished { .. } => "run.finished",
+            ServerEventKind::BackgroundExited { .. } => "background.exited",
+            ServerEventKind::ProxyRequest { .. } => "proxy.request",
+        }
+    }
+}
+
+// Fault injection
+
+/// Faults injected by servers built with the `chaos` feature, set with
+/// `PUT /admin/faults`. Probabilities are between 0 and 1; all default to 0.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct FaultConfig {
+    /// Chance that an API request is delayed before it is handled
+    pub delay_probability: f64,
+    /// Longest injected delay; each delay is uniform in `0..=delay_ms_max`
+    pub delay_ms_max: u64,
+    /// Chance, every `kill_interval_secs`, that one running background
+    /// process is killed with SIGKILL
+    pub kill_background_probability: f64,
+    pub kill_interval_secs: u64,
+    /// Chance that a file or template write fails with 500
+    pub storage_failure_probability: f64,
+}
+
+/// Faults injected since the server started.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct FaultCounts {
+    pub delays: u64,
+    pub background_kills: u64,
+    pub storage_failures: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FaultsResponse {
+    pub config: FaultConfig,
+    pub injected: FaultCounts,
+}
@@ -0,0 +1,570 @@
+//! opencomputer - command-line client for a running OpenSandbox server.
+//!
+//! Usage:
+//!   opencomputer session create [--template node20] [--image IMAGE] [-e KEY=VALUE] [-l KEY=VALUE]
+//!   opencomputer session list [-l KEY=VALUE]
+//!   opencomputer session rm <id>...
+//!   opencomputer run [-s <id>] [-d --port 3000] -- <command> [args]
+//!   opencomputer cp ./app <id>:/home/app       # upload a file or directory
+//!   opencomputer cp <id>:/home/out.txt .       # download
+//!   opencomputer logs <id> [-f]
+//!   opencomputer port <id>
+//!   opencomputer ssh <id> [-- <ssh args>]     # needs the server's SSH port enabled
+//!
+//! The server is taken from `--url` or `OPENCOMPUTER_URL` (default
+//! http://localhost:8080), the key from `--api-key` or `OPENCOMPUTER_API_KEY`.
+
+use clap::{Parser, Subcommand};
+use futures_util::StreamExt;
+use opencomputer_client::{
+    BackgroundRunRequest, Client, CreateSessionRequest, CreateSshKeyRequest, DiskUsageQuery, EgressPolicy, ListSessionsQuery, RunRequest,
+};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::exit;
+use std::time::Duration;
+
+const DEFAULT_URL: &str = "http://localhost:8080";
+
+/// Files sent per bulk write when uploading a directory.
+const UPLOAD_BATCH: usize = 64;
+
+/// Lifetime of the key minted for `opencomputer ssh`, which revokes it once
+/// ssh exits anyway.
+const SSH_KEY_TTL_SECS: u64 = 600;
+
+#[derive(Parser, Debug)]
+#[command(name = "opencomputer")]
+#[command(about = "Command-line client for the OpenSandbox HTTP API")]
+struct Args {
+    /// Server URL (default $OPENCOMPUTER_URL or http://localhost:8080)
+    #[arg(long, global = true)]
+    url: Option<String>,
+
+    /// API key (default $OPENCOMPUTER_API_KEY)
+    #[arg(long, global = true)]
+    api_key: Option<String>,
+
+    #[command(subcommand)]
+    command: Commands,
+}
+
+#[derive(Subcommand, Debug)]
+enum Commands {
+    /// Create, list and remove sessions
+    Session {
+        #[command(subcommand)]
+        command: SessionCommands,
+    },
+    /// Run a command in a session, or in a throwaway sandbox without --session
+    Run {
+        #[arg(short, long)]
+        session: Option<String>,
+
+        /// Start in the background (needs --session) and print the preview URL
+        #[arg(short, long)]
+        detach: bool,
+
+        /// Port a detached process listens on (0 = let the server pick)
+        #[arg(long, requires = "detach")]
+        port: Option<u16>,
+
+        /// Environment variable, repeatable
+        #[arg(short, long = "env", value_name = "KEY=VALUE", value_parser = parse_key_value)]
+        env: Vec<(String, String)>,
+
+        #[arg(long)]
+        cwd: Option<String>,
+
+        /// CPU time limit in milliseconds
+        #[arg(long)]
+        time: Option<u64>,
+
+        /// Memory limit in KB
+        #[arg(long)]
+        mem: Option<u64>,
+
+        /// Command and arguments to run
+        #[arg(last = true, required = true)]
+        cmd_args: Vec<String>,
+    },
+    /// Copy files between the local machine and a session (`<id>:<path>`)
+    Cp { src: String, dst: String },
+    /// Print the session's background process log
+    Logs {
+        id: String,
+
+        /// Keep printing new output until the background processes exit
+        #[arg(short, long)]
+        follow: bool,
+    },
+    /// Show the session's ports and preview URL
+    Port { id: String },
+    /// Log in to a session with the system ssh and a key minted for the login
+    Ssh {
+        id: String,
+
+        /// Passed on to ssh, e.g. a command to run instead of a shell
+        #[arg(last = true)]
+        ssh_args: Vec<String>,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum SessionCommands {
+    /// Create a session and print its ID
+    Create {
+        #[arg(long)]
+        template: Option<String>,
+
+        /// OCI image to build the sandbox on, e.g. ghcr.io/org/devimage:1.2
+        #[arg(long)]
+        image: Option<String>,
+
+        /// Custom preview subdomain
+        #[arg(long)]
+        slug: Option<String>,
+
+        /// Idle TTL in seconds
+        #[arg(long)]
+        ttl: Option<u64>,
+
+        /// Environment variable, repeatable
+        #[arg(short, long = "env", value_name = "KEY=VALUE", value_parser = parse_key_value)]
+        env: Vec<(String, String)>,
+
+        /// Label, repeatable
+        #[arg(short, long = "label", value_name = "KEY=VALUE", value_parser = parse_key_value)]
+        label: Vec<(String, String)>,
+
+        /// Only reach this domain (`*.` for subdomains) through the egress proxy, repeatable
+        #[arg(long = "allow-domain", value_name = "DOMAIN")]
+        allow_domain: Vec<String>,
+
+        /// Never reach this domain through the egress proxy, repeatable
+        #[arg(long = "deny-domain", value_name = "DOMAIN")]
+        deny_domain: Vec<String>,
+    },
+    /// List sessions
+    #[command(alias = "ls")]
+    List {
+        /// Only sessions with this label (`KEY=VALUE` or `KEY`), repeatable
+        #[arg(short, long = "label")]
+        label: Vec<String>,
+    },
+    /// Delete sessions
+    Rm {
+        #[arg(required = true)]
+        ids: Vec<String>,
+    },
+    /// Show what a session's files take on disk
+    Du {
+        id: String,
+
+        /// Directories listed
+        #[arg(long)]
+        top: Option<usize>,
+
+        /// Deepest directories listed, `/` being 0
+        #[arg(long)]
+        depth: Option<usize>,
+
+        /// Walk the files again instead of using a recent walk
+        #[arg(long)]
+        refresh: bool,
+    },
+}
+
+#[tokio::main]
+async fn main() {
+    let args = Args::parse();
+
+    let url = args
+        .url
+        .or_else(|| std::env::var("OPENCOMPUTER_URL").ok())
+        .unwrap_or_else(|| DEFAULT_URL.to_string());
+    let mut client = Client::new(url.clone());
+    if let Some(key) = args.api_key.or_else(|| std::env::var("OPENCOMPUTER_API_KEY").ok()) {
+        client = client.with_api_key(key);
+    }
+
+    let result = match args.command {
+        Commands::Session { command } => session(&client, command).await,
+        Commands::Run { session, detach, port, env, cwd, time, mem, cmd_args } => {
+            let env = env.into_iter().collect();
+            if detach {
+                let Some(id) = session else {
+                    eprintln!("Error: --detach needs --session");
+                    exit(2);
+                };
+                let req = BackgroundRunRequest { command: cmd_args, port, env, cwd, ..Default::default() };
+                run_background(&client, &id, &req).await
+            } else {
+                let req = RunRequest {
+                    command: cmd_args,
+                    time,
+                    mem,
+                    env,
+                    cwd,
+                    ..Default::default()
+                };
+                run(&client, session.as_deref(), &req).await
+            }
+        }
+        Commands::Cp { src, dst } => copy(&client, &src, &dst).await,
+        Commands::Logs { id, follow } => logs(&client, &id, follow).await,
+        Commands::Port { id } => ports(&client, &id).await,
+        Commands::Ssh { id, ssh_args } => ssh(&client, &url, &id, &ssh_args).await,
+    };
+
+    if let Err(e) = result {
+        eprintln!("Error: {}", e);
+        exit(1);
+    }
+}
+
+fn parse_key_value(s: &str) -> Result<(String, String), String> {
+    match s.split_once('=') {
+        Some((k, v)) if !k.is_empty() => Ok((k.to_string(), v.to_string())),
+        _ => Err(format!("expected KEY=VALUE, got {:?}", s)),
+    }
+}
+
+async fn session(client: &Client, command: SessionCommands) -> Result<(), String> {
+    match command {
+        SessionCommands::Create {
+            template,
+            image,
+            slug,
+            ttl,
+            env,
+            label,
+            allow_domain,
+            deny_domain,
+        } => {
+            let egress = (!allow_domain.is_empty() || !deny_domain.is_empty()).then_some(EgressPolicy {
+                allow: allow_domain,
+                deny: deny_domain,
+            });
+            let req = CreateSessionRequest {
+                env: env.into_iter().collect(),
+                slug,
+                ttl,
+                template,
+                image,
+                labels: label.into_iter().collect(),
+                egress,
+                ..Default::default()
+            };
+            let resp = client.create_session(&req).await.map_err(|e| e.to_string())?;
+            // Only the ID goes to stdout so `$(opencomputer session create)` works
+            println!("{}", resp.session_id);
+            if let Some(url) = resp.preview_url {
+                eprintln!("Preview URL: {}", url);
+            }
+            Ok(())
+        }
+        SessionCommands::List { label } => {
+            let mut query = ListSessionsQuery { labels: label, ..Default::default() };
+            let mut sessions = Vec::new();
+            loop {
+                let page = client.list_sessions(&query).await.map_err(|e| e.to_string())?;
+                sessions.extend(page.sessions);
+                match page.next_cursor {
+                    Some(cursor) => query.cursor = Some(cursor),
+                    None => break,
+                }
+            }
+            println!("{:<36}  {:<11}  {:>7}  {:<12}  PREVIEW", "ID", "STATUS", "AGE", "TEMPLATE");
+            for s in sessions {
+                println!(
+                    "{:<36}  {:<11}  {:>7}  {:<12}  {}",
+                    s.id,
+                    s.status,
+                    format_age(s.age_secs),
+                    s.template.as_deref().unwrap_or("-"),
+                    s.preview_url.as_deref().unwrap_or("-"),
+                );
+            }
+            Ok(())
+        }
+        SessionCommands::Rm { ids } => {
+            let mut failed = false;
+            for id in ids {
+                match client.delete_session(&id).await {
+                    Ok(()) => println!("{}", id),
+                    Err(e) => {
+                        eprintln!("Error: {}: {}", id, e);
+                        failed = true;
+                    }
+                }
+            }
+            if failed {
+                exit(1);
+            }
+            Ok(())
+        }
+        SessionCommands::Du { id, top, depth, refresh } => {
+            let query = DiskUsageQuery { top, depth, refresh };
+            let usage = client.disk_usage(&id, &query).await.map_err(|e| e.to_string())?;
+            println!(
+                "{} of {} used, {} in {} files (walked {} ago{})",
+                format_bytes(usage.disk_bytes),
+                format_bytes(usage.disk_limit_bytes),
+                format_bytes(usage.file_bytes),
+                usage.files,
+                format_age(usage.walked_secs_ago),
+                if usage.truncated { ", incomplete" } else { "" },
+            );
+            for dir in usage.largest_dirs {
+                println!("{:>9}  {:>9}  {}", format_bytes(dir.bytes), dir.files, dir.path);
+            }
+            Ok(())
+        }
+    }
+}
+
+fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "K", "M", "G", "T"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    match unit {
+        0 => format!("{}B", bytes),
+        _ => format!("{:.1}{}", value, UNITS[unit]),
+    }
+}
+
+fn format_age(secs: u64) -> String {
+    match secs {
+        s if s < 60 => format!("{}s", s),
+        s if s < 3600 => format!("{}m", s / 60),
+        s if s < 86400 => format!("{}h", s / 3600),
+        s => format!("{}d", s / 86400),
+    }
+}
+
+/// Run to completion, relay output, and exit with the command's status.
+async fn run(client: &Client, session: Option<&str>, req: &RunRequest) -> Result<(), String> {
+    let result = match session {
+        Some(id) => client.run(id, req).await,
+        None => client.run_oneshot(req).await,
+    }
+    .map_err(|e| e.to_string())?;
+    print!("{}", result.stdout);
+    eprint!("{}", result.stderr);
+    let _ = std::io::stdout().flush();
+    let code = match (result.exit_code, result.signal) {
+        (Some(code), _) => code,
+        (None, Some(signal)) => 128 + signal,
+        (None, None) => 1,
+    };
+    exit(code);
+}
+
+async fn run_background(client: &Client, id: &str, req: &BackgroundRunRequest) -> Result<(), String> {
+    let resp = client.run_background(id, req).await.map_err(|e| e.to_string())?;
+    println!("pid {} listening on port {}", resp.pid, resp.port);
+    if let Some(url) = resp.preview_url {
+        println!("Preview URL: {}", url);
+    }
+    Ok(())
+}
+
+/// A `cp` operand: `<session>:<path>` or a local path.
+enum Endpoint<'a> {
+    Remote { session: &'a str, path: &'a str },
+    Local(&'a str),
+}
+
+fn parse_endpoint(arg: &str) -> Endpoint<'_> {
+    match arg.split_once(':') {
+        Some((session, path)) if !session.is_empty() && !session.contains('/') => {
+            Endpoint::Remote { session, path }
+        }
+        _ => Endpoint::Local(arg),
+    }
+}
+
+async fn copy(client: &Client, src: &str, dst: &str) -> Result<(), String> {
+    match (parse_endpoint(src), parse_endpoint(dst)) {
+        (Endpoint::Local(local), Endpoint::Remote { session, path }) => {
+            upload(client, session, Path::new(local), path).await
+        }
+        (Endpoint::Remote { session, path }, Endpoint::Local(local)) => {
+            download(client, session, path, local).await
+        }
+        _ => Err("exactly one of SRC and DST must be <session>:<path>".to_string()),
+    }
+}
+
+async fn upload(client: &Client, session: &str, local: &Path, remote: &str) -> Result<(), String> {
+    let meta = std::fs::metadata(local).map_err(|e| format!("{}: {}", local.display(), e))?;
+    if !meta.is_dir() {
+        let remote = if remote.ends_with('/') {
+            let name = local.file_name().unwrap_or_default().to_string_lossy();
+            format!("{}{}", remote, name)
+        } else {
+            remote.to_string()
+        };
+        let content = std::fs::read(local).map_err(|e| format!("{}: {}", local.display(), e))?;
+        return client
+            .write_file(session, &remote, &content)
+            .await
+            .map_err(|e| e.to_string());
+    }
+
+    let mut files = Vec::new();
+    collect_files(local, &mut files).map_err(|e| format!("{}: {}", local.display(), e))?;
+    let base = remote.trim_end_matches('/');
+    for batch in files.chunks(UPLOAD_BATCH) {
+        let mut contents = Vec::with_capacity(batch.len());
+        for path in batch {
+            let content = std::fs::read(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+            let rel = path.strip_prefix(local).unwrap_or(path);
+            contents.push((format!("{}/{}", base, rel.display()), content));
+        }
+        let refs: Vec<(&str, &[u8])> = contents.iter().map(|(p, c)| (p.as_str(), c.as_slice())).collect();
+        let resp = client.write_files(session, &refs).await.map_err(|e| e.to_string())?;
+        if let Some(err) = resp.errors.first() {
+            return Err(format!("{}: {}", err.path, err.error));
+        }
+    }
+    Ok(())
+}
+
+fn collect_files(dir: &Path, files: &mut Vec<PathBuf>) -> std::io::Result<()> {
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            collect_files(&path, files)?;
+        } else {
+            files.push(path);
+        }
+    }
+    Ok(())
+}
+
+/// Download a file, or a directory recursively. `-` writes a file to stdout.
+async fn download(client: &Client, session: &str, remote: &str, local: &str) -> Result<(), String> {
+    let content = match client.read_file(session, remote).await {
+        Ok(content) => content,
+        Err(read_err) => {
+            // Not readable as a file: maybe a directory
+            let Ok(entries) = client.list_files(session, remote).await else {
+                return Err(read_err.to_string());
+            };
+            if local == "-" {
+                return Err(format!("{} is a directory", remote));
+            }
+            let name = Path::new(remote).file_name().unwrap_or_default();
+            let target = local_target(Path::new(local), name);
+            return Box::pin(download_dir(client, session, entries, &target)).await;
+        }
+    };
+    if local == "-" {
+        return std::io::stdout().write_all(&content).map_err(|e| e.to_string());
+    }
+    let name = Path::new(remote).file_name().unwrap_or_default();
+    let target = local_target(Path::new(local), name);
+    std::fs::write(&target, content).map_err(|e| format!("{}: {}", target.display(), e))
+}
+
+/// Like `cp`: copying into an existing directory keeps the source name.
+fn local_target(local: &Path, name: &std::ffi::OsStr) -> PathBuf {
+    if local.is_dir() && !name.is_empty() {
+        local.join(name)
+    } else {
+        local.to_path_buf()
+    }
+}
+
+async fn download_dir(
+    client: &Client,
+    session: &str,
+    entries: Vec<opencomputer_client::FileEntry>,
+    target: &Path,
+) -> Result<(), String> {
+    std::fs::create_dir_all(target).map_err(|e| format!("{}: {}", target.display(), e))?;
+    for entry in entries {
+        let dest = target.join(&entry.name);
+        if entry.is_directory {
+            let children = client
+                .list_files(session, &entry.path)
+                .await
+                .map_err(|e| format!("{}: {}", entry.path, e))?;
+            Box::pin(download_dir(client, session, children, &dest)).await?;
+        } else {
+            let content = client
+                .read_file(session, &entry.path)
+                .await
+                .map_err(|e| format!("{}: {}", entry.path, e))?;
+            std::fs::write(&dest, content).map_err(|e| format!("{}: {}", dest.display(), e))?;
+        }
+    }
+    Ok(())
+}
+
+async fn logs(client: &Client, id: &str, follow: bool) -> Result<(), String> {
+    if !follow {
+        let status = client.background_status(id, 0, None).await.map_err(|e| e.to_string())?;
+        print!("{}", status.log);
+        return Ok(());
+    }
+    let mut stream = Box::pin(client.follow_background_log(id, Duration::from_secs(1)));
+    while let Some(chunk) = stream.next().await {
+        print!("{}", chunk.map_err(|e| e.to_string())?);
+        let _ = std::io::stdout().flush();
+    }
+    Ok(())
+}
+
+/// One line per port; the preview URL belongs to the first port, which is
+/// the one the preview proxy serves.
+async fn ports(client: &Client, id: &str) -> Result<(), String> {
+    let info = client.get_session(id).await.map_err(|e| e.to_string())?;
+    let mut preview = info.preview_url;
+    for port in info.ports {
+        println!("{}\t{}", port, preview.take().as_deref().unwrap_or("-"));
+    }
+    Ok(())
+}
+
+async fn ssh(client: &Client, url: &str, id: &str, ssh_args: &[String]) -> Result<(), String> {
+    let req = CreateSshKeyRequest { ttl: Some(SSH_KEY_TTL_SECS) };
+    let key = client.create_ssh_key(id, &req).await.map_err(|e| e.to_string())?;
+    let path = std::env::temp_dir().join(format!("opencomputer-ssh-{}", key.key_id));
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    options
+        .open(&path)
+        .and_then(|mut file| file.write_all(key.private_key.as_bytes()))
+        .map_err(|e| format!("write {}: {}", path.display(), e))?;
+    let status = std::process::Command::new("ssh")
+        .arg("-i")
+        .arg(&path)
+        .args(["-o", "IdentitiesOnly=yes", "-p", &key.port.to_string()])
+        .arg(format!("{}@{}", key.username, url_host(url)))
+        .args(ssh_args)
+        .status();
+    let _ = std::fs::remove_file(&path);
+    let _ = client.delete_ssh_key(id, &key.key_id).await;
+    let status = status.map_err(|e| format!("run ssh: {}", e))?;
+    exit(status.code().unwrap_or(1));
+}
+
+/// Host part of the server URL, where its SSH server listens too.
+fn url_host(url: &str) -> &str {
+    let rest = url.split_once("://").map_or(url, |(_, rest)| rest);
+    let authority = rest.split('/').next().unwrap_or(rest);
+    let authority = authority.rsplit_once('@').map_or(authority, |(_, host)| host);
+    match authority.strip_prefix('[') {
+        Some(v6) => v6.split(']').next().unwrap_or(v6),
+        None => authority.split(':').next().unwrap_or(authority),
+    }
+}
@@ -0,0 +1,953 @@
+//! Server configuration for `opensandbox serve`.
+//!
+//! Settings come from a TOML file (`--config` or `CONFIG_FILE`), then
+//! environment variables, then command-line flags, each overriding the last.
+//! Every section and field is optional:
+//!
+//! ```toml
+//! [server]
+//! listen = "0.0.0.0:8080"
+//! grpc_listen = "0.0.0.0:50051"
+//! idempotency_window_secs = 3600   # replay retries with the same Idempotency-Key; 0 = off
+//! body_limit_mb = 2                # largest request body for JSON API calls
+//! files_body_limit_mb = 64         # for file writes
+//! upload_body_limit_mb = 1024      # for template, replay and /run-preview tarballs
+//! preview_body_limit_mb = 100      # for requests to previews (default unlimited)
+//!
+//! [sandbox]
+//! backend = "chroot"       # how sessions are isolated; "userns" runs without root
+//!
+//! [preview]
+//! domain = "preview.example.com"
+//! region = "fra"
+//! cookie_secret = "..."
+//!
+//! [sessions]
+//! ttl_secs = 300
+//! max_ttl_secs = 86400
+//! max_sessions = 200
+//! when_full = "evict"      # or "reject" (503) once max_sessions are live
+//! max_runs = 64            # runs in progress server-wide
+//! max_session_runs = 4     # and per session
+//! run_queue_secs = 30      # wait this long for a slot before 429
+//! background_log_max_mb = 10   # rotate a background process's log at this size
+//! background_log_segments = 5  # and keep this many gzipped segments of it
+//! warm_pool_size = 4
+//! cleanup_policy_file = "/etc/opensandbox/cleanup.json"
+//!
+//! [resources]
+//! overcommit_ratio = 1.5   # reservations may add up to 1.5x host capacity
+//! mem_mb = 65536           # override detected capacity (cpu, mem_mb, disk_mb)
+//!
+//! [cgroups]   # share CPU and bound processes per session; off unless root is set
+//! root = "/sys/fs/cgroup/opensandbox"
+//! cpu_weight = 100         # sessions' default share (1-10000)
+//! cpu_max = 2.0            # and cap, in CPUs (unset = none)
+//! pids_max = 1024          # processes and threads per session (0 = none)
+//! max_cpu_weight = 400     # most a session may ask for
+//! max_cpu_max = 4.0
+//! max_pids_max = 4096
+//!
+//! [auth]
+//! api_keys_file = "/etc/opensandbox/keys.json"   # or inline:
+//! # [[auth.keys]]
+//! # key = "osb_..."
+//! # name = "ci"
+//!
+//! [auth.oidc]   # also accept JWTs from an identity provider
+//! issuer = "https://login.example.com/"
+//! audience = "opensandbox"
+//!
+//! [env]
+//! forbidden = ["LD_PRELOAD", "LD_AUDIT", "AWS_*"]
+//! allowed = ["AWS_REGION"]
+//! action = "reject"
+//!
+//! [storage]
+//! templates_dir = "/var/lib/opensandbox/templates"
+//! template_source_dirs = ["/srv/templates"]   # POST /templates may copy from these
+//! base_layer = "/var/lib/opensandbox/base"   # read-only, under every sandbox
+//! package_cache_dir = "/var/cache/opensandbox/packages"
+//! build_cache_dir = "/var/cache/opensandbox/build"
+//! build_cache_max_mb = 20480   # evicted down to this, least recently used first
+//! state_db = "/var/lib/opensandbox/sessions.db"   # keep sessions across restarts
+//! state_key_file = "/etc/opensandbox/state.key"    # encrypt it (or state_key_command)
+//!
+//! [webhooks]
+//! urls = ["https://hooks.example.com/sandbox"]
+//! secret = "..."
+//!
+//! [tls]
+//! cert = "/etc/opensandbox/tls/fullchain.pem"   # reloaded when it changes
+//! key = "/etc/opensandbox/tls/key.pem"
+//! # or issue one via ACME DNS-01 (covers *.{preview domain} automatically):
+//! # [tls.acme]
+//! # dns_hook = "/usr/local/bin/acme-dns"
+//! # email = "ops@example.com"
+//! # domains = ["api.example.com"]
+//!
+//! [admin]   # operator API; not served unless given a listener or a token
+//! listen = "127.0.0.1:9090"
+//! token_file = "/etc/opensandbox/admin.token"   # required instead of API keys
+//!
+//! [ssh]
+//! listen = "0.0.0.0:2222"   # off unless set
+//! host_key = "/var/lib/opensandbox/ssh_host_ed25519_key"   # generated if missing
+//!
+//! [images]
+//! dir = "/var/lib/opensandbox/images"
+//! insecure_registries = ["registry.internal:5000"]   # plain HTTP, as localhost always is
+//! [images.credentials."ghcr.io"]   # others are pulled anonymously
+//! username = "ci-bot"
+//! password = "ghp_..."
+//! ```
+
+use opencomputer_core::quota::OrgQuota;
+use opencomputer_core::run_limits::RunLimits;
+use opencomputer_core::body_limit::BodyLimits;
+use opencomputer_core::cgroups::{Cgroups, SessionLimits};
+use opencomputer_core::tls::CertStore;
+use opencomputer_core::images::{self, ImageStore, RegistryCredentials};
+use opencomputer_core::store_key::StoreKey;
+use opencomputer_core::{
+    acme, auth, backend, background_log, cache, cleanup_policy, env_policy, idempotency, oidc, reservation, sandbox,
+    ssh, state, tls, webhooks,
+};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Environment variable naming the config file when `--config` is not given.
+pub const CONFIG_FILE_ENV: &str = "CONFIG_FILE";
+
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub server: ServerConfig,
+    pub sandbox: SandboxConfig,
+    pub preview: PreviewConfig,
+    pub sessions: SessionsConfig,
+    pub resources: ResourcesConfig,
+    pub cgroups: CgroupsConfig,
+    pub auth: AuthConfig,
+    pub env: EnvConfig,
+    pub storage: StorageConfig,
+    pub webhooks: WebhooksConfig,
+    pub tls: TlsConfig,
+    pub ssh: SshConfig,
+    pub admin: AdminConfig,
+    pub images: ImagesConfig,
+}
+
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    pub listen: SocketAddr,
+    pub grpc_listen: SocketAddr,
+    /// How long responses to requests with an `Idempotency-Key` are kept
+    pub idempotency_window_secs: u64,
+    /// Largest request bodies, in MiB: JSON API calls,
+    pub body_limit_mb: u64,
+    /// file writes,
+    pub files_body_limit_mb: u64,
+    /// tarball uploads,
+    pub upload_body_limit_mb: u64,
+    /// and requests proxied to previews (unset = unlimited)
+    pub preview_body_limit_mb: Option<u64>,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            listen: SocketAddr::from(([0, 0, 0, 0], 8080)),
+            grpc_listen: SocketAddr::from(([0, 0, 0, 0], 50051)),
+            idempotency_window_secs: idempotency::DEFAULT_WINDOW.as_secs(),
+            body_limit_mb: (BodyLimits::default().control / (1024 * 1024)) as u64,
+            files_body_limit_mb: (BodyLimits::default().files / (1024 * 1024)) as u64,
+            upload_body_limit_mb: (BodyLimits::default().uploads / (1024 * 1024)) as u64,
+            preview_body_limit_mb: None,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SandboxConfig {
+    /// Isolation backend sessions run under; see `backend::BACKENDS`
+    pub backend: String,
+}
+
+impl Default for SandboxConfig {
+    fn default() -> Self {
+        Self {
+            backend: backend::DEFAULT_BACKEND.to_string(),
+        }
+    }
+}
+
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PreviewConfig {
+    /// Sessions get preview URLs like https://{session-id}.{domain}
+    pub domain: Option<String>,
+    /// Label inserted before the domain so DNS can route to this instance
+    pub region: Option<String>,
+    /// Secret for signing preview auth cookies (random per process if unset)
+    pub cookie_secret: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SessionsConfig {
+    /// Default idle TTL
+    pub ttl_secs: u64,
+    /// Longest idle TTL a session may request
+    pub max_ttl_secs: u64,
+    /// Most live sessions across all API keys (unset = unlimited)
+    pub max_sessions: Option<usize>,
+    /// What a create does once `max_sessions` are live
+    pub when_full: state::FullPolicy,
+    /// Most runs in progress across the server (unset = unlimited)
+    pub max_runs: Option<usize>,
+    /// Most runs in progress in one session (unset = unlimited)
+    pub max_session_runs: Option<usize>,
+    /// How long a run over either limit waits before it's refused
+    pub run_queue_secs: u64,
+    /// Size a background process's log is rotated at
+    pub background_log_max_mb: u64,
+    /// Rotated segments kept per background log
+    pub background_log_segments: usize,
+    /// Sandboxes kept pre-created for instant session creation
+    pub warm_pool_size: usize,
+    /// JSON file of label-based retention rules
+    pub cleanup_policy_file: Option<PathBuf>,
+}
+
+impl Default for SessionsConfig {
+    fn default() -> Self {
+        Self {
+            ttl_secs: state::SESSION_TTL_SECS,
+            max_ttl_secs: state::MAX_SESSION_TTL_SECS,
+            max_sessions: None,
+            when_full: state::FullPolicy::default(),
+            max_runs: None,
+            max_session_runs: None,
+            run_queue_secs: 0,
+            background_log_max_mb: background_log::LogRotation::default().max_bytes / (1024 * 1024),
+            background_log_segments: background_log::LogRotation::default().segments,
+            warm_pool_size: 0,
+            cleanup_policy_file: None,
+        }
+    }
+}
+
+/// Host capacity that session reservations are checked against.
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ResourcesConfig {
+    /// Reservations may add up to this multiple of host capacity
+    pub overcommit_ratio: f64,
+    /// CPU cores (detected if unset)
+    pub cpu: Option<f64>,
+    /// Memory in MB (detected if unset)
+    pub mem_mb: Option<u64>,
+    /// Sandbox filesystem size in MB (detected if unset)
+    pub disk_mb: Option<u64>,
+}
+
+impl Default for ResourcesConfig {
+    fn default() -> Self {
+        Self {
+            overcommit_ratio: reservation::DEFAULT_OVERCOMMIT_RATIO,
+            cpu: None,
+            mem_mb: None,
+            disk_mb: None,
+        }
+    }
+}
+
+/// Per-session cgroups, so sessions share CPU by weight rather than by how
+/// many processes they run, and a fork bomb can't use up the host's PIDs.
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CgroupsConfig {
+    /// cgroup v2 directory the sessions' groups go in (unset = off)
+    pub root: Option<PathBuf>,
+    /// `cpu.weight` of sessions that don't ask for one (default 100)
+    pub cpu_weight: Option<u32>,
+    /// CPUs a session may use at most unless it asks otherwise (unset = no cap)
+    pub cpu_max: Option<f64>,
+    /// `pids.max` of sessions that don't ask for one (default 1024, 0 = none)
+    pub pids_max: Option<u64>,
+    /// Highest `cpu_weight` a session may ask for
+    pub max_cpu_weight: Option<u32>,
+    /// Highest `cpu_max` a session may ask for; sessions can't go uncapped
+    pub max_cpu_max: Option<f64>,
+    /// Highest `pids_max` a session may ask for; sessions can't go unlimited
+    pub max_pids_max: Option<u64>,
+}
+
+/// API keys, from a JSON file or inline, and an OIDC issuer whose JWTs are
+/// accepted too. With none of them the API is open.
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuthConfig {
+    pub api_keys_file: Option<PathBuf>,
+    pub keys: Vec<auth::ApiKey>,
+    pub orgs: HashMap<String, OrgQuota>,
+    pub oidc: Option<oidc::OidcConfig>,
+}
+
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct EnvConfig {
+    /// Names/patterns sandboxes may not receive (default LD_PRELOAD, LD_AUDIT)
+    pub forbidden: Option<Vec<String>>,
+    /// Exceptions to `forbidden`
+    pub allowed: Option<Vec<String>>,
+    /// "reject" (400) or "strip"
+    pub action: String,
+}
+
+impl Default for EnvConfig {
+    fn default() -> Self {
+        Self {
+            forbidden: None,
+            allowed: None,
+            action: "reject".to_string(),
+        }
+    }
+}
+
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct StorageConfig {
+    /// Directory holding registered session templates
+    pub templates_dir: Option<PathBuf>,
+    /// Server directories `POST /templates` may copy from (unset = none)
+    pub template_source_dirs: Option<Vec<PathBuf>>,
+    /// Read-only directory layered under every sandbox root
+    pub base_layer: Option<PathBuf>,
+    /// Host-managed pip/npm cache for `POST /sessions/:id/packages`
+    pub package_cache_dir: Option<PathBuf>,
+    /// Lockfile-keyed `node_modules`, venvs and `target` dirs sessions share
+    pub build_cache_dir: Option<PathBuf>,
+    /// Size the build cache is evicted down to (default 10240)
+    pub build_cache_max_mb: Option<u64>,
+    /// SQLite database sessions are saved to (unset = lost on restart)
+    pub state_db: Option<PathBuf>,
+    /// 32-byte key, hex or base64, that `state_db` records are encrypted with
+    pub state_key_file: Option<PathBuf>,
+    /// Command printing that key instead, e.g. a KMS decrypt call
+    pub state_key_command: Option<String>,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct WebhooksConfig {
+    /// URLs that receive signed lifecycle events
+    pub urls: Vec<String>,
+    /// Shared secret for signing payloads (required with `urls`)
+    pub secret: Option<String>,
+}
+
+/// TLS for the HTTP and gRPC listeners: a certificate on disk or one
+/// issued via ACME. With neither both speak plaintext.
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TlsConfig {
+    /// PEM certificate chain, leaf first
+    pub cert: Option<PathBuf>,
+    /// PEM private key for `cert`
+    pub key: Option<PathBuf>,
+    pub acme: AcmeConfig,
+}
+
+/// ACME DNS-01 issuance, enabled by setting `dns_hook`.
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AcmeConfig {
+    /// Command called as `<hook> present|cleanup <record> <value>`
+    pub dns_hook: Option<PathBuf>,
+    /// Contact address for expiry notices
+    pub email: Option<String>,
+    /// Names to certify besides the preview wildcard
+    pub domains: Vec<String>,
+    /// ACME directory URL (default Let's Encrypt production)
+    pub directory: String,
+    /// Where the account key and certificate are kept
+    pub cache_dir: PathBuf,
+    /// Wait after publishing TXT records before validation
+    pub propagation_secs: u64,
+}
+
+impl Default for AcmeConfig {
+    fn default() -> Self {
+        Self {
+            dns_hook: None,
+            email: None,
+            domains: Vec::new(),
+            directory: acme::LETS_ENCRYPT_DIRECTORY.to_string(),
+            cache_dir: PathBuf::from("/var/lib/opensandbox/acme"),
+            propagation_secs: 60,
+        }
+    }
+}
+
+/// The embedded SSH server, for logging in to sessions with keys minted
+/// through the API.
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SshConfig {
+    /// Where to listen (unset = no SSH)
+    pub listen: Option<SocketAddr>,
+    /// The server's host key, generated here if missing
+    pub host_key: PathBuf,
+}
+
+impl Default for SshConfig {
+    fn default() -> Self {
+        Self {
+            listen: None,
+            host_key: PathBuf::from(ssh::DEFAULT_HOST_KEY_PATH),
+        }
+    }
+}
+
+/// The operator API (`/admin/...`), on a listener of its own so it can be
+/// kept off the network tenants reach, or with the API behind a token.
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AdminConfig {
+    /// Where to listen (unset = on the API listener with `token_file`, else
+    /// not at all)
+    pub listen: Option<SocketAddr>,
+    /// Token the admin routes require instead of API keys
+    pub token_file: Option<PathBuf>,
+}
+
+/// OCI images sessions can be created from.
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ImagesConfig {
+    /// Where pulled images are unpacked and cached
+    pub dir: PathBuf,
+    /// Registries (`host[:port]`) reached over plain HTTP
+    pub insecure_registries: Vec<String>,
+    /// Logins by registry (`docker.io` for Docker Hub)
+    pub credentials: HashMap<String, RegistryLogin>,
+}
+
+impl Default for ImagesConfig {
+    fn default() -> Self {
+        Self {
+            dir: PathBuf::from(images::DEFAULT_IMAGES_DIR),
+            insecure_registries: Vec::new(),
+            credentials: HashMap::new(),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RegistryLogin {
+    pub username: String,
+    pub password: String,
+}
+
+impl Config {
+    /// Read and parse a TOML config file.
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let data = std::fs::read_to_string(path)
+            .map_err(|e| format!("read config {}: {}", path.display(), e))?;
+        toml::from_str(&data).map_err(|e| format!("parse config {}: {}", path.display(), e))
+    }
+
+    /// Override fields from environment variables. Returns one message per
+    /// variable that is set but does not parse.
+    pub fn apply_env(&mut self) -> Vec<String> {
+        let mut errors = Vec::new();
+        if let Some(addr) = parse_var("LISTEN_ADDR", &mut errors) {
+            self.server.listen = addr;
+        }
+        if let Some(addr) = parse_var("GRPC_LISTEN_ADDR", &mut errors) {
+            self.server.grpc_listen = addr;
+        }
+        if let Some(secs) = parse_var("IDEMPOTENCY_WINDOW_SECS", &mut errors) {
+            self.server.idempotency_window_secs = secs;
+        }
+        if let Some(mb) = parse_var("BODY_LIMIT_MB", &mut errors) {
+            self.server.body_limit_mb = mb;
+        }
+        if let Some(mb) = parse_var("FILES_BODY_LIMIT_MB", &mut errors) {
+            self.server.files_body_limit_mb = mb;
+        }
+        if let Some(mb) = parse_var("UPLOAD_BODY_LIMIT_MB", &mut errors) {
+            self.server.upload_body_limit_mb = mb;
+        }
+        if let Some(mb) = parse_var("PREVIEW_BODY_LIMIT_MB", &mut errors) {
+            self.server.preview_body_limit_mb = Some(mb);
+        }
+        if let Some(addr) = parse_var("SSH_LISTEN_ADDR", &mut errors) {
+            self.ssh.listen = Some(addr);
+        }
+        if let Some(addr) = parse_var("ADMIN_LISTEN_ADDR", &mut errors) {
+            self.admin.listen = Some(addr);
+        }
+        if let Some(ttl) = parse_var("SESSION_TTL", &mut errors) {
+            self.sessions.ttl_secs = ttl;
+        }
+        if let Some(ttl) = parse_var("MAX_SESSION_TTL", &mut errors) {
+            self.sessions.max_ttl_secs = ttl;
+        }
+        if let Some(max) = parse_var("MAX_SESSIONS", &mut errors) {
+            self.sessions.max_sessions = Some(max);
+        }
+        if let Some(policy) = parse_var("SESSIONS_WHEN_FULL", &mut errors) {
+            self.sessions.when_full = policy;
+        }
+        if let Some(max) = parse_var("MAX_RUNS", &mut errors) {
+            self.sessions.max_runs = Some(max);
+        }
+        if let Some(max) = parse_var("MAX_SESSION_RUNS", &mut errors) {
+            self.sessions.max_session_runs = Some(max);
+        }
+        if let Some(secs) = parse_var("RUN_QUEUE_SECS", &mut errors) {
+            self.sessions.run_queue_secs = secs;
+        }
+        if let Some(mb) = parse_var("BACKGROUND_LOG_MAX_MB", &mut errors) {
+            self.sessions.background_log_max_mb = mb;
+        }
+        if let Some(segments) = parse_var("BACKGROUND_LOG_SEGMENTS", &mut errors) {
+            self.sessions.background_log_segments = segments;
+        }
+        if let Some(size) = parse_var("WARM_POOL_SIZE", &mut errors) {
+            self.sessions.warm_pool_size = size;
+        }
+        if let Some(ratio) = parse_var("OVERCOMMIT_RATIO", &mut errors) {
+            self.resources.overcommit_ratio = ratio;
+        }
+        let text = |name: &str| std::env::var(name).ok();
+        if let Some(name) = text("SANDBOX_BACKEND") {
+            self.sandbox.backend = name;
+        }
+        if let Some(dir) = text("CGROUP_ROOT") {
+            self.cgroups.root = Some(dir.into());
+        }
+        if let Some(domain) = text("PREVIEW_DOMAIN") {
+            self.preview.domain = Some(domain);
+        }
+        if let Some(region) = text("PREVIEW_REGION") {
+            self.preview.region = Some(region);
+        }
+        if let Some(secret) = text("PREVIEW_COOKIE_SECRET") {
+            self.preview.cookie_secret = Some(secret);
+        }
+        if let Some(path) = text("CLEANUP_POLICY_FILE") {
+            self.sessions.cleanup_policy_file = Some(path.into());
+        }
+        if let Some(path) = text("API_KEYS_FILE") {
+            self.auth.api_keys_file = Some(path.into());
+        }
+        if let Some(list) = text("FORBIDDEN_ENV") {
+            self.env.forbidden = Some(split_list(&list));
+        }
+        if let Some(list) = text("ALLOWED_ENV") {
+            self.env.allowed = Some(split_list(&list));
+        }
+        if let Some(action) = text("FORBIDDEN_ENV_ACTION") {
+            self.env.action = action;
+        }
+        if let Some(dir) = text("TEMPLATES_DIR") {
+            self.storage.templates_dir = Some(dir.into());
+        }
+        if let Some(list) = text("TEMPLATE_SOURCE_DIRS") {
+            self.storage.template_source_dirs = Some(split_list(&list).into_iter().map(PathBuf::from).collect());
+        }
+        if let Some(dir) = text("BASE_LAYER") {
+            self.storage.base_layer = Some(dir.into());
+        }
+        if let Some(dir) = text("PACKAGE_CACHE_DIR") {
+            self.storage.package_cache_dir = Some(dir.into());
+        }
+        if let Some(dir) = text("BUILD_CACHE_DIR") {
+            self.storage.build_cache_dir = Some(dir.into());
+        }
+        if let Some(mb) = parse_var("BUILD_CACHE_MAX_MB", &mut errors) {
+            self.storage.build_cache_max_mb = Some(mb);
+        }
+        if let Some(path) = text("STATE_DB") {
+            self.storage.state_db = Some(path.into());
+        }
+        if let Some(path) = text("STATE_KEY_FILE") {
+            self.storage.state_key_file = Some(path.into());
+        }
+        if let Some(command) = text("STATE_KEY_COMMAND") {
+            self.storage.state_key_command = Some(command);
+        }
+        if let Some(list) = text("WEBHOOK_URLS") {
+            self.webhooks.urls = split_list(&list);
+        }
+        if let Some(secret) = text("WEBHOOK_SECRET") {
+            self.webhooks.secret = Some(secret);
+        }
+        if let Some(path) = text("TLS_CERT") {
+            self.tls.cert = Some(path.into());
+        }
+        if let Some(path) = text("TLS_KEY") {
+            self.tls.key = Some(path.into());
+        }
+        if let Some(path) = text("ACME_DNS_HOOK") {
+            self.tls.acme.dns_hook = Some(path.into());
+        }
+        if let Some(email) = text("ACME_EMAIL") {
+            self.tls.acme.email = Some(email);
+        }
+        if let Some(list) = text("ACME_DOMAINS") {
+            self.tls.acme.domains = split_list(&list);
+        }
+        if let Some(url) = text("ACME_DIRECTORY") {
+            self.tls.acme.directory = url;
+        }
+        if let Some(path) = text("ADMIN_TOKEN_FILE") {
+            self.admin.token_file = Some(path.into());
+        }
+        if let Some(path) = text("SSH_HOST_KEY") {
+            self.ssh.host_key = path.into();
+        }
+        if let Some(dir) = text("IMAGES_DIR") {
+            self.images.dir = dir.into();
+        }
+        errors
+    }
+
+    /// Validate every field and build the server state. On failure returns
+    /// one message per bad field rather than stopping at the first.
+    pub fn build_state(&self) -> Result<state::AppState, Vec<String>> {
+        let mut errors = Vec::new();
+        let mut state = state::AppState::with_preview_domain(self.preview.domain.clone());
+
+        if let Some(ref region) = self.preview.region {
+            if let Err(e) = state.set_preview_region(region) {
+                errors.push(format!("preview.region: {}", e));
+            }
+        }
+        if let Some(ref secret) = self.preview.cookie_secret {
+            state.set_preview_cookie_secret(secret);
+        }
+        state.set_idempotency_window(Duration::from_secs(self.server.idempotency_window_secs));
+        let server = &self.server;
+        let mb = [server.body_limit_mb, server.files_body_limit_mb, server.upload_body_limit_mb];
+        if mb.contains(&0) || server.preview_body_limit_mb == Some(0) {
+            errors.push("server.*body_limit_mb: must be at least 1".to_string());
+        } else {
+            let bytes = |mb: u64| (mb * 1024 * 1024) as usize;
+            state.set_body_limits(BodyLimits {
+                control: bytes(server.body_limit_mb),
+                files: bytes(server.files_body_limit_mb),
+                uploads: bytes(server.upload_body_limit_mb),
+                preview: server.preview_body_limit_mb.map(bytes),
+            });
+        }
+        match backend::by_name(&self.sandbox.backend) {
+            Ok(backend) => state.set_sandbox_backend(backend),
+            Err(e) => errors.push(format!("sandbox.backend: {}", e)),
+        }
+
+        let sessions = &self.sessions;
+        if sessions.ttl_secs == 0 || sessions.ttl_secs > sessions.max_ttl_secs {
+            errors.push(format!(
+                "sessions.ttl_secs: must be between 1 and sessions.max_ttl_secs ({})",
+                sessions.max_ttl_secs
+            ));
+        } else {
+            state.set_session_ttl(
+                Duration::from_secs(sessions.ttl_secs),
+                Duration::from_secs(sessions.max_ttl_secs),
+            );
+        }
+        if let Some(max) = sessions.max_sessions {
+            state.set_max_sessions(max);
+        }
+        state.set_full_policy(sessions.when_full);
+        if sessions.max_runs == Some(0) {
+            errors.push("sessions.max_runs: must be at least 1".to_string());
+        } else if sessions.max_session_runs == Some(0) {
+            errors.push("sessions.max_session_runs: must be at least 1".to_string());
+        } else {
+            state.set_run_limits(RunLimits::new(
+                sessions.max_runs,
+                sessions.max_session_runs,
+                Duration::from_secs(sessions.run_queue_secs),
+            ));
+        }
+        if sessions.background_log_max_mb == 0 {
+            errors.push("sessions.background_log_max_mb: must be at least 1".to_string());
+        } else if sessions.background_log_segments == 0 {
+            errors.push("sessions.background_log_segments: must be at least 1".to_string());
+        } else {
+            state.set_background_log_rotation(background_log::LogRotation {
+                max_bytes: sessions.background_log_max_mb * 1024 * 1024,
+                segments: sessions.background_log_segments,
+            });
+        }
+        if let Some(ref path) = sessions.cleanup_policy_file {
+            match cleanup_policy::CleanupPolicy::load(path) {
+                Ok(policy) => state.set_cleanup_policy(policy),
+                Err(e) => errors.push(format!("sessions.cleanup_policy_file: {}", e)),
+            }
+        }
+
+        let resources = &self.resources;
+        if !resources.overcommit_ratio.is_finite() || resources.overcommit_ratio <= 0.0 {
+            errors.push("resources.overcommit_ratio: must be a positive number".to_string());
+        } else if resources.cpu.is_some_and(|cpu| !cpu.is_finite() || cpu < 0.0) {
+            errors.push("resources.cpu: must be a non-negative number".to_string());
+        } else {
+            let mut host = reservation::detect_host();
+            host.cpu = resources.cpu.unwrap_or(host.cpu);
+            host.mem_mb = resources.mem_mb.unwrap_or(host.mem_mb);
+            host.disk_mb = resources.disk_mb.unwrap_or(host.disk_mb);
+            state.set_reservation_policy(reservation::ReservationPolicy {
+                host,
+                overcommit_ratio: resources.overcommit_ratio,
+            });
+        }
+
+        let cgroups = &self.cgroups;
+        if let Some(ref root) = cgroups.root {
+            let defaults = SessionLimits {
+                cpu_weight: cgroups.cpu_weight,
+                cpu_max: cgroups.cpu_max,
+                pids_max: cgroups.pids_max,
+            };
+            let max = SessionLimits {
+                cpu_weight: cgroups.max_cpu_weight,
+                cpu_max: cgroups.max_cpu_max,
+                pids_max: cgroups.max_pids_max,
+            };
+            match Cgroups::new(root, defaults).and_then(|c| c.with_max(max)) {
+                Ok(cgroups) => state.set_cgroups(cgroups),
+                Err(e) => errors.push(format!("cgroups: {}", e)),
+            }
+        }
+
+        let keys = match (&self.auth.api_keys_file, self.auth.keys.is_empty()) {
+            (Some(_), false) => Err("auth: set either api_keys_file or keys, not both".to_string()),
+            (Some(path), true) => auth::ApiKeys::load(path).map(Some).map_err(|e| format!("auth.api_keys_file: {}", e)),
+            (None, false) => auth::ApiKeys::from_keys(self.auth.keys.clone(), self.auth.orgs.clone())
+                .map(Some)
+                .map_err(|e| format!("auth.keys: {}", e)),
+            // Org quotas still apply to JWTs naming an org
+            (None, true) if self.auth.oidc.is_some() => auth::ApiKeys::from_keys(Vec::new(), self.auth.orgs.clone())
+                .map(Some)
+                .map_err(|e| format!("auth.orgs: {}", e)),
+            (None, true) => Ok(None),
+        };
+        let verifier = match self.auth.oidc.clone().map(oidc::Oidc::new).transpose() {
+            Ok(verifier) => verifier,
+            Err(e) => {
+                errors.push(format!("auth.oidc: {}", e));
+                None
+            }
+        };
+        match keys {
+            Ok(Some(keys)) => match verifier {
+                Some(verifier) => state.set_api_keys(keys.with_oidc(verifier)),
+                None => state.set_api_keys(keys),
+            },
+            Ok(None) => {}
+            Err(e) => errors.push(e),
+        }
+        if let Some(ref path) = self.auth.api_keys_file {
+            let path = path.clone();
+            state.set_key_source(move || auth::ApiKeys::load(&path));
+        }
+
+        let mut env_policy = env_policy::EnvPolicy::default();
+        match self.env.action.parse() {
+            Ok(action) => env_policy.action = action,
+            Err(e) => errors.push(format!("env.action: {}", e)),
+        }
+        if let Some(ref deny) = self.env.forbidden {
+            env_policy.deny = deny.clone();
+        }
+        if let Some(ref allow) = self.env.allowed {
+            env_policy.allow = allow.clone();
+        }
+        state.set_env_policy(env_policy);
+
+        if let Some(ref dir) = self.storage.templates_dir {
+            state.set_templates_dir(dir);
+        }
+        if let Some(ref dirs) = self.storage.template_source_dirs {
+            state.set_template_source_dirs(dirs.clone());
+        }
+        if let Some(ref dir) = self.storage.package_cache_dir {
+            state.set_package_cache_dir(dir);
+        }
+        if self.storage.build_cache_dir.is_some() || self.storage.build_cache_max_mb.is_some() {
+            let dir = self.storage.build_cache_dir.as_deref();
+            state.set_build_cache(
+                dir.unwrap_or(Path::new(cache::DEFAULT_BUILD_CACHE_DIR)),
+                self.storage.build_cache_max_mb.unwrap_or(cache::DEFAULT_MAX_MB),
+            );
+        }
+        if let Some(ref dir) = self.storage.base_layer {
+            if dir.is_dir() {
+                sandbox::set_base_layer(dir.clone());
+            } else {
+                errors.push(format!("storage.base_layer: {} is not a directory", dir.display()));
+            }
+        }
+
+        if !self.webhooks.urls.is_empty() {
+            match self.webhooks.secret {
+                None => errors.push("webhooks.secret: required when webhooks.urls is set".to_string()),
+                Some(ref secret) => match webhooks::WebhookDispatcher::new(self.webhooks.urls.clone(), secret) {
+                    Ok(dispatcher) => state.add_lifecycle_hook(Arc::new(dispatcher)),
+                    Err(e) => errors.push(format!("webhooks.urls: {}", e)),
+                },
+            }
+        }
+
+        if let Some(addr) = self.ssh.listen {
+            state.set_ssh_port(addr.port());
+        }
+
+        let mut images = ImageStore::new(&self.images.dir)
+            .with_insecure_registries(self.images.insecure_registries.clone());
+        for (registry, login) in &self.images.credentials {
+            let credentials = RegistryCredentials {
+                username: login.username.clone(),
+                password: login.password.clone(),
+            };
+            images = images.with_credentials(registry, credentials);
+        }
+        state.set_image_store(images);
+
+        let storage = &self.storage;
+        let state_key = match (&storage.state_key_file, &storage.state_key_command) {
+            (Some(_), Some(_)) => Err("storage: set either state_key_file or state_key_command, not both".to_string()),
+            (Some(path), None) => StoreKey::load(path).map(Some).map_err(|e| format!("storage.state_key_file: {}", e)),
+            (None, Some(command)) => {
+                StoreKey::from_command(command).map(Some).map_err(|e| format!("storage.state_key_command: {}", e))
+            }
+            (None, None) => Ok(None),
+        };
+        let state_key = match state_key {
+            Ok(Some(_)) if storage.state_db.is_none() => {
+                errors.push("storage: a state key needs state_db".to_string());
+                None
+            }
+            Ok(key) => key,
+            Err(e) => {
+                errors.push(e);
+                None
+            }
+        };
+
+        if !errors.is_empty() {
+            return Err(errors);
+        }
+        // Adopt leftover sandboxes before the warm pool creates new ones
+        if let Some(ref path) = self.storage.state_db {
+            if let Err(e) = state.enable_persistence(path, state_key) {
+                return Err(vec![format!("storage.state_db: {}", e)]);
+            }
+        }
+        state.enable_warm_pool(sessions.warm_pool_size);
+        Ok(state)
+    }
+
+    /// The token the admin routes require, from `admin.token_file`.
+    pub fn admin_token(&self) -> Result<Option<String>, String> {
+        let Some(ref path) = self.admin.token_file else {
+            return Ok(None);
+        };
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| format!("admin.token_file: read {}: {}", path.display(), e))?;
+        let token = text.trim();
+        if token.len() < MIN_ADMIN_TOKEN_LEN {
+            return Err(format!(
+                "admin.token_file: token must be at least {} characters",
+                MIN_ADMIN_TOKEN_LEN
+            ));
+        }
+        Ok(Some(token.to_string()))
+    }
+
+    /// Load the TLS certificate, first issuing one if ACME is configured and
+    /// none is cached. Starts the reload or renewal task. `None` means plaintext.
+    pub async fn build_tls(&self) -> Result<Option<Arc<CertStore>>, String> {
+        let tls = &self.tls;
+        match (&tls.cert, &tls.key, &tls.acme.dns_hook) {
+            (None, None, None) => Ok(None),
+            (Some(cert), Some(key), None) => {
+                let store = Arc::new(CertStore::load(cert, key).map_err(|e| format!("tls.cert: {}", e))?);
+                tls::spawn_file_reload(store.clone(), cert.clone(), key.clone());
+                Ok(Some(store))
+            }
+            (Some(_), None, None) | (None, Some(_), None) => Err("tls: set both cert and key".to_string()),
+            (Some(_), _, Some(_)) | (_, Some(_), Some(_)) => {
+                Err("tls: set either cert/key or acme.dns_hook, not both".to_string())
+            }
+            (None, None, Some(hook)) => {
+                let mut domains = tls.acme.domains.clone();
+                if let Some(ref domain) = self.preview.domain {
+                    let wildcard = match self.preview.region {
+                        Some(ref region) => format!("*.{}.{}", region, domain),
+                        None => format!("*.{}", domain),
+                    };
+                    if !domains.contains(&wildcard) {
+                        domains.push(wildcard);
+                    }
+                }
+                if domains.is_empty() {
+                    return Err("tls.acme.domains: required when preview.domain is unset".to_string());
+                }
+                let config = acme::AcmeConfig {
+                    directory: tls.acme.directory.clone(),
+                    email: tls.acme.email.clone(),
+                    domains,
+                    dns_hook: hook.clone(),
+                    cache_dir: tls.acme.cache_dir.clone(),
+                    propagation_delay: Duration::from_secs(tls.acme.propagation_secs),
+                };
+                let store = Arc::new(acme::load_or_issue(&config).await.map_err(|e| format!("tls.acme: {}", e))?);
+                acme::spawn_renewal(config, store.clone());
+                Ok(Some(store))
+            }
+        }
+    }
+}
+
+/// Shortest admin token accepted.
+const MIN_ADMIN_TOKEN_LEN: usize = 16;
+
+/// Split a comma-separated list, dropping blanks.
+pub fn split_list(list: &str) -> Vec<String> {
+    list.split(',')
+        .map(|s| s.trim().to_string())
+        .filter(|s| !s.is_empty())
+        .collect()
+}
+
+fn parse_var<T: FromStr>(name: &str, errors: &mut Vec<String>) -> Option<T>
+where
+    T::Err: Display,
+{
+    let value = std::env::var(name).ok()?;
+    match value.trim().parse() {
+        Ok(v) => Some(v),
+        Err(e) => {
+            errors.push(format!("{}: invalid value {:?}: {}", name, value, e));
+            None
+        }
+    }
+}
@@ -1,21 +1,19 @@
 //! OpenSandbox - Linux sandbox with HTTP API and gRPC support.
 //!
 //! Usage:
-//!   opensandbox serve [--port 8080] [--grpc-port 50051]   # Start HTTP + gRPC servers
+//!   opensandbox serve [--config config.toml] [--port 8080] # Start HTTP + gRPC servers
 //!   opensandbox --run -- <command> [args]                  # CLI mode (original)
 
 #[cfg(not(target_os = "linux"))]
 compile_error!("This program only works on Linux.");
 
 #[cfg(target_os = "linux")]
-mod grpc_server;
-#[cfg(target_os = "linux")]
-mod http_server;
+mod config;
+
 #[cfg(target_os = "linux")]
-mod sandbox;
+use opencomputer_core::state::FullPolicy;
 #[cfg(target_os = "linux")]
-mod state;
-
+use opencomputer_core::{grpc_server, http_server, sandbox, ssh};
 #[cfg(target_os = "linux")]
 use clap::{Parser, Subcommand};
 #[cfg(target_os = "linux")]
@@ -49,6 +47,10 @@ struct Args {
     #[arg(long, default_value = "64")]
     nofile: u64,
 
+    /// Maximum number of processes of the sandbox's user (0 = no limit)
+    #[arg(long, default_value = "64")]
+    pids: u64,
+
     /// Command and arguments to run
     #[arg(last = true)]
     cmd_args: Vec<String>,
@@ -57,20 +59,189 @@ struct Args {
 #[cfg(target_os = "linux")]
 #[derive(Subcommand, Debug)]
 enum Commands {
-    /// Start the HTTP and gRPC servers
+    /// Start the HTTP and gRPC servers.
+    ///
+    /// Settings come from the config file, then environment variables, then
+    /// these flags; see `config.rs` for the file format.
     Serve {
-        /// HTTP port to listen on
-        #[arg(long, default_value = "8080")]
-        port: u16,
+        /// TOML config file (also CONFIG_FILE)
+        #[arg(long)]
+        config: Option<String>,
+
+        /// HTTP port to listen on (default 8080)
+        #[arg(long)]
+        port: Option<u16>,
 
-        /// gRPC port to listen on
-        #[arg(long, default_value = "50051")]
-        grpc_port: u16,
+        /// gRPC port to listen on (default 50051)
+        #[arg(long)]
+        grpc_port: Option<u16>,
+
+        /// Seconds responses to requests with an Idempotency-Key are kept
+        /// for retries; 0 ignores the header (default 3600)
+        #[arg(long)]
+        idempotency_window_secs: Option<u64>,
+
+        /// Port for the SSH server that logs in to sessions (default off)
+        #[arg(long)]
+        ssh_port: Option<u16>,
+
+        /// Port for the operator API on 127.0.0.1 (default off)
+        #[arg(long)]
+        admin_port: Option<u16>,
+
+        /// SSH host key, generated if missing
+        /// (default /var/lib/opensandbox/ssh_host_ed25519_key)
+        #[arg(long)]
+        ssh_host_key: Option<String>,
+
+        /// How sessions are isolated (default chroot, the only one so far)
+        #[arg(long)]
+        sandbox_backend: Option<String>,
 
         /// Preview domain for sandbox web servers (e.g., "preview.opensandbox.fly.dev")
         /// When set, sessions will get preview URLs like https://{session-id}.preview.opensandbox.fly.dev
         #[arg(long)]
         preview_domain: Option<String>,
+
+        /// Region or instance label for preview URLs, which become
+        /// https://{session-id}.{region}.{preview-domain} so DNS can route
+        /// straight to this instance
+        #[arg(long)]
+        preview_region: Option<String>,
+
+        /// Secret for signing preview auth cookies (random per process if unset).
+        /// Set it when running several instances or to keep cookies valid across restarts.
+        #[arg(long)]
+        preview_cookie_secret: Option<String>,
+
+        /// JSON file of API keys (and their default session env). When set,
+        /// every API request must present one of these keys.
+        #[arg(long)]
+        api_keys_file: Option<String>,
+
+        /// JSON file of label-based retention rules (e.g. longer TTLs for
+        /// `keep=long`, short ones for `ci=true`)
+        #[arg(long)]
+        cleanup_policy_file: Option<String>,
+
+        /// Comma-separated env var names/patterns sandboxes may not receive
+        /// (e.g. "LD_PRELOAD,AWS_*"). Defaults to LD_PRELOAD,LD_AUDIT.
+        #[arg(long, value_delimiter = ',')]
+        forbidden_env: Option<Vec<String>>,
+
+        /// Comma-separated exceptions to --forbidden-env (e.g. "AWS_REGION")
+        #[arg(long, value_delimiter = ',')]
+        allowed_env: Option<Vec<String>>,
+
+        /// What to do with forbidden env vars: "reject" (400, default) or "strip"
+        #[arg(long)]
+        forbidden_env_action: Option<String>,
+
+        /// Default idle TTL for sessions in seconds (default 300)
+        #[arg(long)]
+        session_ttl: Option<u64>,
+
+        /// Maximum idle TTL a session may request in seconds (default 86400)
+        #[arg(long)]
+        max_session_ttl: Option<u64>,
+
+        /// Number of sandboxes to keep pre-created for instant session creation
+        #[arg(long)]
+        warm_pool_size: Option<usize>,
+
+        /// Most live sessions across all API keys (default unlimited)
+        #[arg(long)]
+        max_sessions: Option<usize>,
+
+        /// What a create does once --max-sessions are live: "reject" (503) or
+        /// "evict" the session idle the longest
+        #[arg(long)]
+        when_full: Option<FullPolicy>,
+
+        /// Most runs in progress across the server (default unlimited)
+        #[arg(long)]
+        max_runs: Option<usize>,
+
+        /// Most runs in progress in one session (default unlimited)
+        #[arg(long)]
+        max_session_runs: Option<usize>,
+
+        /// Seconds a run over --max-runs or --max-session-runs waits for a
+        /// slot before it's refused with 429 (default 0)
+        #[arg(long)]
+        run_queue_secs: Option<u64>,
+
+        /// Size a background process's log is rotated at (default 10)
+        #[arg(long)]
+        background_log_max_mb: Option<u64>,
+
+        /// Rotated, gzipped segments kept per background log (default 5)
+        #[arg(long)]
+        background_log_segments: Option<usize>,
+
+        /// Session resource reservations may add up to this multiple of
+        /// host capacity (default 1.0)
+        #[arg(long)]
+        overcommit_ratio: Option<f64>,
+
+        /// Directory holding registered session templates
+        /// (default /var/lib/opensandbox/templates)
+        #[arg(long)]
+        templates_dir: Option<String>,
+
+        /// Read-only directory (e.g. a shared toolchain) layered under
+        /// every sandbox root
+        #[arg(long)]
+        base_layer: Option<String>,
+
+        /// Host-managed cache that package installs are served from
+        /// (default /var/cache/opensandbox/packages)
+        #[arg(long)]
+        package_cache_dir: Option<String>,
+
+        /// Where lockfile-keyed build outputs are cached
+        /// (default /var/cache/opensandbox/build)
+        #[arg(long)]
+        build_cache_dir: Option<String>,
+
+        /// Size the build cache is evicted down to (default 10240)
+        #[arg(long)]
+        build_cache_max_mb: Option<u64>,
+
+        /// Where OCI images pulled for sessions are cached
+        /// (default /var/lib/opensandbox/images)
+        #[arg(long)]
+        images_dir: Option<String>,
+
+        /// SQLite database that sessions are saved to so they survive
+        /// restarts; leftover sandboxes are re-adopted or cleaned up at startup
+        #[arg(long)]
+        state_db: Option<String>,
+
+        /// Comma-separated URLs that receive signed lifecycle events
+        #[arg(long, value_delimiter = ',')]
+        webhook_url: Option<Vec<String>>,
+
+        /// Shared secret for signing webhook payloads (required with --webhook-url)
+        #[arg(long)]
+        webhook_secret: Option<String>,
+
+        /// PEM certificate chain; serves HTTPS and gRPC over TLS (needs --tls-key)
+        #[arg(long)]
+        tls_cert: Option<String>,
+
+        /// PEM private key for --tls-cert
+        #[arg(long)]
+        tls_key: Option<String>,
+
+        /// Obtain the certificate via ACME DNS-01, publishing challenge TXT
+        /// records with this command (`<hook> present|cleanup <record> <value>`)
+        #[arg(long)]
+        acme_dns_hook: Option<String>,
+
+        /// Contact email for the ACME account
+        #[arg(long)]
+        acme_email: Option<String>,
     },
 }
 
@@ -83,37 +254,252 @@ async fn main() {
 
     let args = Args::parse();
 
-    // Must be root
-    if !nix::unistd::geteuid().is_root() {
+    // Must be root, unless serving rootless sessions (checked once the config is read)
+    if !nix::unistd::geteuid().is_root() && !matches!(args.command, Some(Commands::Serve { .. })) {
         eprintln!("Error: Must run as root (need CAP_SYS_ADMIN for namespaces)");
         exit(1);
     }
 
     match args.command {
-        Some(Commands::Serve { port, grpc_port, preview_domain }) => {
-            // CLI flag takes priority, then fall back to PREVIEW_DOMAIN env var
-            let preview_domain = preview_domain.or_else(|| std::env::var("PREVIEW_DOMAIN").ok());
+        Some(Commands::Serve {
+            config: config_path,
+            port,
+            grpc_port,
+            idempotency_window_secs,
+            ssh_port,
+            admin_port,
+            ssh_host_key,
+            sandbox_backend,
+            preview_domain,
+            preview_region,
+            preview_cookie_secret,
+            api_keys_file,
+            cleanup_policy_file,
+            forbidden_env,
+            allowed_env,
+            forbidden_env_action,
+            session_ttl,
+            max_session_ttl,
+            warm_pool_size,
+            max_sessions,
+            when_full,
+            max_runs,
+            max_session_runs,
+            run_queue_secs,
+            background_log_max_mb,
+            background_log_segments,
+            overcommit_ratio,
+            templates_dir,
+            base_layer,
+            package_cache_dir,
+            build_cache_dir,
+            build_cache_max_mb,
+            images_dir,
+            state_db,
+            webhook_url,
+            webhook_secret,
+            tls_cert,
+            tls_key,
+            acme_dns_hook,
+            acme_email,
+        }) => {
+            let config_path = config_path.or_else(|| std::env::var(config::CONFIG_FILE_ENV).ok());
+            let mut config = match config_path {
+                Some(path) => match config::Config::load(std::path::Path::new(&path)) {
+                    Ok(config) => config,
+                    Err(e) => {
+                        eprintln!("Error: {}", e);
+                        exit(1);
+                    }
+                },
+                None => config::Config::default(),
+            };
+            let mut errors = config.apply_env();
+
+            // Flags take priority over the environment and the config file
+            if let Some(port) = port {
+                config.server.listen.set_port(port);
+            }
+            if let Some(port) = grpc_port {
+                config.server.grpc_listen.set_port(port);
+            }
+            if let Some(secs) = idempotency_window_secs {
+                config.server.idempotency_window_secs = secs;
+            }
+            if let Some(port) = ssh_port {
+                let default = std::net::SocketAddr::from(([0, 0, 0, 0], port));
+                config.ssh.listen.get_or_insert(default).set_port(port);
+            }
+            if let Some(port) = admin_port {
+                let default = std::net::SocketAddr::from(([127, 0, 0, 1], port));
+                config.admin.listen.get_or_insert(default).set_port(port);
+            }
+            if let Some(path) = ssh_host_key {
+                config.ssh.host_key = path.into();
+            }
+            if let Some(name) = sandbox_backend {
+                config.sandbox.backend = name;
+            }
+            let preview = &mut config.preview;
+            preview.domain = preview_domain.or(preview.domain.take());
+            preview.region = preview_region.or(preview.region.take());
+            preview.cookie_secret = preview_cookie_secret.or(preview.cookie_secret.take());
+            let sessions = &mut config.sessions;
+            sessions.ttl_secs = session_ttl.unwrap_or(sessions.ttl_secs);
+            sessions.max_ttl_secs = max_session_ttl.unwrap_or(sessions.max_ttl_secs);
+            sessions.warm_pool_size = warm_pool_size.unwrap_or(sessions.warm_pool_size);
+            sessions.max_sessions = max_sessions.or(sessions.max_sessions);
+            sessions.when_full = when_full.unwrap_or(sessions.when_full);
+            sessions.max_runs = max_runs.or(sessions.max_runs);
+            sessions.max_session_runs = max_session_runs.or(sessions.max_session_runs);
+            sessions.run_queue_secs = run_queue_secs.unwrap_or(sessions.run_queue_secs);
+            sessions.background_log_max_mb = background_log_max_mb.unwrap_or(sessions.background_log_max_mb);
+            sessions.background_log_segments = background_log_segments.unwrap_or(sessions.background_log_segments);
+            if let Some(ratio) = overcommit_ratio {
+                config.resources.overcommit_ratio = ratio;
+            }
+            if let Some(path) = cleanup_policy_file {
+                sessions.cleanup_policy_file = Some(path.into());
+            }
+            if let Some(path) = api_keys_file {
+                config.auth.api_keys_file = Some(path.into());
+            }
+            let trimmed = |list: Vec<String>| config::split_list(&list.join(","));
+            if let Some(deny) = forbidden_env {
+                config.env.forbidden = Some(trimmed(deny));
+            }
+            if let Some(allow) = allowed_env {
+                config.env.allowed = Some(trimmed(allow));
+            }
+            if let Some(action) = forbidden_env_action {
+                config.env.action = action;
+            }
+            if let Some(dir) = templates_dir {
+                config.storage.templates_dir = Some(dir.into());
+            }
+            if let Some(dir) = base_layer {
+                config.storage.base_layer = Some(dir.into());
+            }
+            if let Some(dir) = package_cache_dir {
+                config.storage.package_cache_dir = Some(dir.into());
+            }
+            if let Some(dir) = build_cache_dir {
+                config.storage.build_cache_dir = Some(dir.into());
+            }
+            if let Some(mb) = build_cache_max_mb {
+                config.storage.build_cache_max_mb = Some(mb);
+            }
+            if let Some(dir) = images_dir {
+                config.images.dir = dir.into();
+            }
+            if let Some(path) = state_db {
+                config.storage.state_db = Some(path.into());
+            }
+            if let Some(urls) = webhook_url {
+                config.webhooks.urls = trimmed(urls);
+            }
+            config.webhooks.secret = webhook_secret.or(config.webhooks.secret.take());
+            let tls = &mut config.tls;
+            if let Some(path) = tls_cert {
+                tls.cert = Some(path.into());
+            }
+            if let Some(path) = tls_key {
+                tls.key = Some(path.into());
+            }
+            if let Some(path) = acme_dns_hook {
+                tls.acme.dns_hook = Some(path.into());
+            }
+            tls.acme.email = acme_email.or(tls.acme.email.take());
+            if config.sandbox.backend != "userns" && !nix::unistd::geteuid().is_root() {
+                eprintln!("Error: Must run as root (need CAP_SYS_ADMIN for namespaces), or use --sandbox-backend userns");
+                exit(1);
+            }
 
-            // Create shared state with optional preview domain
-            let state = state::AppState::with_preview_domain(preview_domain);
+            let state = match config.build_state() {
+                Ok(state) if errors.is_empty() => state,
+                built => {
+                    errors.extend(built.err().into_iter().flatten());
+                    eprintln!("Error: invalid configuration:");
+                    for e in errors {
+                        eprintln!("  {}", e);
+                    }
+                    exit(1);
+                }
+            };
+            let certs = match config.build_tls().await {
+                Ok(certs) => certs,
+                Err(e) => {
+                    eprintln!("Error: {}", e);
+                    exit(1);
+                }
+            };
+            let ssh_server = match config.ssh.listen {
+                Some(addr) => match ssh::load_or_create_host_key(&config.ssh.host_key) {
+                    Ok(key) => Some((addr, key)),
+                    Err(e) => {
+                        eprintln!("Error: ssh.host_key: {}", e);
+                        exit(1);
+                    }
+                },
+                None => None,
+            };
+            let admin_token = match config.admin_token() {
+                Ok(token) => token,
+                Err(e) => {
+                    eprintln!("Error: {}", e);
+                    exit(1);
+                }
+            };
+            let (http_addr, grpc_addr) = (config.server.listen, config.server.grpc_listen);
+            let admin_addr = config.admin.listen;
 
-            // Spawn HTTP server
+            // Spawn HTTP server. The admin routes go on their own listener if
+            // they have one, else with the API only behind the admin token
             let http_state = state.clone();
+            let http_certs = certs.clone();
+            let http_options = http_server::RouterOptions::new()
+                .admin_routes(admin_addr.is_none() && admin_token.is_some())
+                .admin_token(admin_token.clone());
             let http_handle = tokio::spawn(async move {
-                http_server::run_server(port, http_state).await;
+                http_server::run_server_with(http_addr, http_state, http_certs, http_options).await
+            });
+
+            // Spawn admin server, if enabled
+            let admin_state = state.clone();
+            let admin_handle = tokio::spawn(async move {
+                match admin_addr {
+                    Some(addr) => http_server::run_admin_server(addr, admin_state, admin_token).await,
+                    None => std::future::pending().await,
+                }
             });
 
             // Spawn gRPC server
             let grpc_state = state.clone();
             let grpc_handle = tokio::spawn(async move {
-                grpc_server::run_server(grpc_port, grpc_state).await;
+                match certs {
+                    Some(certs) => grpc_server::run_server_tls(grpc_addr, grpc_state, certs).await,
+                    None => grpc_server::run_server(grpc_addr, grpc_state).await,
+                }
             });
 
-            // Wait for both servers (they run forever)
+            // Spawn SSH server, if enabled
+            let ssh_state = state.clone();
+            let ssh_handle = tokio::spawn(async move {
+                match ssh_server {
+                    Some((addr, key)) => ssh::run_server(addr, ssh_state, key).await,
+                    None => std::future::pending().await,
+                }
+            });
+
+            // Wait for any server to exit or a shutdown signal
             tokio::select! {
                 _ = http_handle => eprintln!("HTTP server exited"),
                 _ = grpc_handle => eprintln!("gRPC server exited"),
+                _ = ssh_handle => eprintln!("SSH server exited"),
+                _ = admin_handle => eprintln!("Admin server exited"),
+                _ = shutdown_signal() => eprintln!("Shutting down"),
             }
+            state.warm_pool.drain().await;
         }
         None if args.run => {
             // Legacy CLI mode
@@ -127,8 +513,15 @@ async fn main() {
                 mem_kb: args.mem,
                 fsize_kb: args.fsize,
                 nofile: args.nofile,
+                nproc: args.pids,
                 env: HashMap::new(),
                 cwd: "/".to_string(),
+                commit_on_success: false,
+                determinism: None,
+                stdin: None,
+                max_output_bytes: sandbox::MAX_OUTPUT_BYTES,
+                kill_grace_ms: sandbox::DEFAULT_KILL_GRACE_MS,
+                secrets: Vec::new(),
             };
             match sandbox::run_oneshot(&config) {
                 Ok(result) => {
@@ -149,6 +542,21 @@ async fn main() {
     }
 }
 
+/// Resolve on SIGINT or SIGTERM.
+#[cfg(target_os = "linux")]
+async fn shutdown_signal() {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut sigterm = match signal(SignalKind::terminate()) {
+        Ok(s) => s,
+        Err(_) => return std::future::pending().await,
+    };
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => {}
+        _ = sigterm.recv() => {}
+    }
+}
+
 #[cfg(not(target_os = "linux"))]
 fn main() {
     eprintln!("This program only works on Linux.");